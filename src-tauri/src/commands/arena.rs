@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 多模型对比（竞技场）模块
+ *
+ * 功能说明:
+ * - 同一轮提问同时发给 2~4 个 provider/模型，各自独立流式输出
+ * - 开始前发出 arena-started 事件，列出每个模型对应的 message_id；之后所有
+ *   stream-chunk / stream-usage 事件都带着这个 message_id，前端据此分栏并排展示
+ * - 某个模型失败只影响它自己那一栏（收到带 error 的 done 块），不打断其他模型
+ *
+ * 所有模型共用会话的取消令牌，点"停止"会同时停下全部模型。对比模式下不做
+ * 故障转移：换了模型的回答就不再是用户想比较的那一个了。
+ */
+
+use crate::commands::llm::{
+    emit_citations, register_stream, resolve_fallback_api_key, retrieve_kb_context, run_stream, unregister_stream_on_drop,
+    FallbackProvider, LLMError, SendMessageRequest, StreamChunk,
+};
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use uuid::Uuid;
+
+/// 一次对比最少/最多的模型数
+const ARENA_MIN_MODELS: usize = 2;
+const ARENA_MAX_MODELS: usize = 4;
+
+/// 多模型对比请求：公共部分（消息、MCP、Skill、采样参数等）沿用单模型请求，
+/// 请求里的 provider/model 被 targets 逐个替换
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArenaRequest {
+    #[serde(flatten)]
+    pub request: SendMessageRequest,
+    /// 参与对比的模型（2~4 个）
+    pub targets: Vec<FallbackProvider>,
+}
+
+/// 参与对比的一栏
+#[derive(Clone, Serialize)]
+pub struct ArenaSlot {
+    /// 在 targets 里的序号
+    pub slot: usize,
+    /// 这一栏的流式事件使用的消息 ID
+    pub message_id: String,
+    pub provider: String,
+    pub model: String,
+}
+
+/// 对比开始事件：所有模型的流式输出开始之前发出一次
+#[derive(Clone, Serialize)]
+pub struct ArenaStartedEvent {
+    pub session_id: String,
+    pub slots: Vec<ArenaSlot>,
+}
+
+/// 用 target 替换公共请求里的 provider/模型，得到这一栏的请求
+fn target_request(base: &SendMessageRequest, target: &FallbackProvider) -> SendMessageRequest {
+    SendMessageRequest {
+        provider: target.provider.clone(),
+        model: target.model.clone(),
+        base_url: target.base_url.clone(),
+        api_key: target.api_key.clone(),
+        api_key_profile: None,
+        custom_auth: target.custom_auth.clone(),
+        key_pool: None,
+        reply_message_id: None,
+        reply_timestamp: None,
+        fallbacks: vec![],
+        ..base.clone()
+    }
+}
+
+/// 同一轮提问同时发给多个模型，流式输出按 message_id 区分
+#[tauri::command]
+pub async fn stream_message_multi(
+    request: ArenaRequest,
+    state: tauri::State<'_, DbState>,
+    app_handle: AppHandle,
+) -> Result<(), LLMError> {
+    let ArenaRequest { request: mut base, targets } = request;
+    if !(ARENA_MIN_MODELS..=ARENA_MAX_MODELS).contains(&targets.len()) {
+        return Err(LLMError::ApiError(format!(
+            "多模型对比需要 {}~{} 个模型，收到 {} 个",
+            ARENA_MIN_MODELS,
+            ARENA_MAX_MODELS,
+            targets.len()
+        )));
+    }
+    log::info!(
+        "[LLM] stream_message_multi: session={} models={}",
+        base.session_id,
+        targets.iter().map(|t| format!("{}/{}", t.provider, t.model)).collect::<Vec<_>>().join(", ")
+    );
+
+    // 知识库只检索一次，所有模型拿到同样的参考内容才有可比性
+    let mut messages = std::mem::take(&mut base.messages);
+    let rag_chunks = retrieve_kb_context(&base, &mut messages, &app_handle).await;
+    base.messages = messages;
+    base.rag_done = true;
+
+    let cancel_token = register_stream(&base.session_id).await;
+    let _cleanup = unregister_stream_on_drop(base.session_id.clone());
+
+    let slots: Vec<ArenaSlot> = targets
+        .iter()
+        .enumerate()
+        .map(|(slot, t)| ArenaSlot {
+            slot,
+            message_id: Uuid::new_v4().to_string(),
+            provider: t.provider.clone(),
+            model: t.model.clone(),
+        })
+        .collect();
+    let _ = app_handle.emit("arena-started", ArenaStartedEvent {
+        session_id: base.session_id.clone(),
+        slots: slots.clone(),
+    });
+    for slot in &slots {
+        emit_citations(&app_handle, &base.session_id, &slot.message_id, &rag_chunks);
+    }
+
+    let runs = targets.iter().zip(&slots).map(|(target, slot)| {
+        let mut request = target_request(&base, target);
+        let state = state.clone();
+        let app_handle = app_handle.clone();
+        let cancel_token = cancel_token.clone();
+        let message_id = slot.message_id.clone();
+        let api_config_id = target.api_config_id.clone();
+        async move {
+            let result = match resolve_fallback_api_key(&request, api_config_id.as_deref()) {
+                Ok(key) => {
+                    request.api_key = key;
+                    run_stream(request.clone(), message_id.clone(), cancel_token, state, app_handle.clone()).await
+                }
+                Err(e) => Err(e),
+            };
+            // 出错的那一栏单独收尾，其他模型照常输出
+            if let Err(e) = result {
+                log::warn!("[LLM] arena model {}/{} failed: {}", request.provider, request.model, e);
+                let _ = app_handle.emit(
+                    "stream-chunk",
+                    StreamChunk::failed(&request.session_id, &message_id, e.to_string()),
+                );
+            }
+        }
+    });
+    futures::future::join_all(runs).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arena_request_flattens_common_fields_and_targets_replace_provider() {
+        let request: ArenaRequest = serde_json::from_value(serde_json::json!({
+            "sessionId": "s1",
+            "messages": [],
+            "provider": "openai",
+            "model": "gpt-4o",
+            "baseUrl": "",
+            "enableMcp": false,
+            "maxTokens": 512,
+            "fallbacks": [{ "provider": "deepseek", "model": "deepseek-chat" }],
+            "targets": [
+                { "provider": "anthropic", "model": "claude-sonnet-4-5", "apiConfigId": "cfg-a" },
+                { "provider": "google", "model": "gemini-2.5-flash" }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(request.targets.len(), 2);
+
+        let second = target_request(&request.request, &request.targets[1]);
+        assert_eq!((second.provider.as_str(), second.model.as_str()), ("google", "gemini-2.5-flash"));
+        assert_eq!(second.max_tokens, Some(512));
+        assert!(second.fallbacks.is_empty());
+    }
+}
@@ -0,0 +1,336 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 数据库备份和恢复
+ *
+ * 功能说明:
+ * - backup_database 用 SQLite 在线备份 API 给 app.db 做一份一致的快照（应用照常读写也不影响），
+ *   连同 Skill 资源文件打包成带时间戳的 zip，默认放在应用数据目录的 backups 下
+ * - restore_database 校验备份完整后，用备份 API 把快照写回正在使用的数据库；
+ *   恢复前先自动备份一次当前数据，恢复错了还能退回去
+ * - 会话、消息、知识库（文档、分块、向量）、协作团队、定时任务都在 app.db 里，一份快照全部覆盖
+ *
+ * ANN 索引文件可以从向量重建，不进备份；恢复后删掉旧索引，应用重启后按需重新构建。
+ * 开启了整库加密时，备份里的快照用同一个密钥加密，只能在同一密码下恢复。
+ * 恢复完成后前端需要重启应用，让各模块的迁移和内存状态基于新数据重新初始化。
+ * API 密钥存在系统钥匙串里，不在备份范围内。
+ */
+
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// 备份格式标识和版本，格式有不兼容的变化时递增
+const BACKUP_FORMAT: &str = "baiyu-backup";
+const BACKUP_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const DATABASE_FILE: &str = "app.db";
+/// Skill 资源文件在备份里的目录前缀
+const SKILLS_PREFIX: &str = "skills/";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    created_at: i64,
+    app_version: String,
+}
+
+/// 一份备份文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupInfo {
+    /// 备份文件路径
+    pub path: String,
+    /// 文件大小 (字节)
+    pub size: u64,
+    /// 备份时间 (毫秒)
+    pub created_at: i64,
+}
+
+fn backup_error(e: impl std::fmt::Display) -> String {
+    format!("备份失败: {}", e)
+}
+
+fn restore_error(e: impl std::fmt::Display) -> String {
+    format!("恢复失败: {}", e)
+}
+
+/// 打开备份快照；开启了整库加密时快照和 app.db 用同一个密钥
+fn open_snapshot(path: &Path, flags: rusqlite::OpenFlags) -> rusqlite::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open_with_flags(path, flags)?;
+    crate::db_encryption::apply_key(&conn)?;
+    Ok(conn)
+}
+
+/// 用在线备份 API 把 source 整库复制到 target
+fn copy_database(source: &rusqlite::Connection, target: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    rusqlite::backup::Backup::new(source, target)?.run_to_completion(256, Duration::from_millis(10), None)
+}
+
+/// 应用数据目录，备份、同步、保留策略和整库加密共用
+pub(crate) fn app_data_dir(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    app_handle.path().app_data_dir().map_err(|e| format!("获取应用数据目录失败: {}", e))
+}
+
+/// 带时间戳的备份文件名，例如 baiyu-backup-20260101-093000.zip
+fn backup_filename(now: chrono::DateTime<chrono::Local>, suffix: &str) -> String {
+    format!("{}-{}{}.zip", BACKUP_FORMAT, now.format("%Y%m%d-%H%M%S"), suffix)
+}
+
+/// 目录下的全部文件（相对路径用 / 分隔），目录不存在时为空
+fn collect_files(dir: &Path) -> Vec<(PathBuf, String)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&current) else { continue };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if let Ok(relative) = path.strip_prefix(dir) {
+                let name = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+                files.push((path, name));
+            }
+        }
+    }
+    files
+}
+
+/// 给数据库做快照，和 Skill 资源文件一起写进 dir 下的新备份文件
+fn write_backup(db_path: &Path, skills_dir: &Path, dir: &Path, suffix: &str) -> Result<BackupInfo, String> {
+    std::fs::create_dir_all(dir).map_err(backup_error)?;
+    let now = chrono::Local::now();
+    let path = dir.join(backup_filename(now, suffix));
+
+    // 在线备份：其他连接在写入时备份会自动重来，得到的总是某一时刻的完整数据库
+    let snapshot = dir.join(format!(".snapshot-{}.db", uuid::Uuid::new_v4()));
+    let _cleanup = scopeguard::guard(snapshot.clone(), |path| {
+        let _ = std::fs::remove_file(path);
+    });
+    let source = crate::db::open_connection(db_path).map_err(backup_error)?;
+    let mut target = open_snapshot(&snapshot, rusqlite::OpenFlags::default()).map_err(backup_error)?;
+    copy_database(&source, &mut target).map_err(backup_error)?;
+    drop((source, target));
+
+    let file = std::fs::File::create(&path).map_err(backup_error)?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(true);
+
+    let manifest = Manifest {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        created_at: now.timestamp_millis(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    zip.start_file(MANIFEST_FILE, options).map_err(backup_error)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(backup_error)?;
+
+    zip.start_file(DATABASE_FILE, options).map_err(backup_error)?;
+    std::io::copy(&mut std::fs::File::open(&snapshot).map_err(backup_error)?, &mut zip).map_err(backup_error)?;
+
+    for (file_path, name) in collect_files(skills_dir) {
+        zip.start_file(format!("{}{}", SKILLS_PREFIX, name), options).map_err(backup_error)?;
+        let bytes = std::fs::read(&file_path).map_err(backup_error)?;
+        zip.write_all(&bytes).map_err(backup_error)?;
+    }
+    zip.finish().map_err(backup_error)?;
+
+    let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    log::info!("Database backed up to {} ({} bytes)", path.display(), size);
+    Ok(BackupInfo {
+        path: path.to_string_lossy().to_string(),
+        size,
+        created_at: manifest.created_at,
+    })
+}
+
+/// 解开备份文件：数据库快照写到 snapshot，返回 Skill 资源文件（相对路径 -> 内容）
+fn extract_backup(file_path: &Path, snapshot: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>, String> {
+    let file = std::fs::File::open(file_path).map_err(restore_error)?;
+    let mut zip = zip::ZipArchive::new(file).map_err(|_| "不是有效的备份文件".to_string())?;
+
+    let manifest: Manifest = {
+        let entry = zip.by_name(MANIFEST_FILE).map_err(|_| "不是有效的备份文件".to_string())?;
+        serde_json::from_reader(entry).map_err(|_| "不是有效的备份文件".to_string())?
+    };
+    if manifest.format != BACKUP_FORMAT {
+        return Err("不是有效的备份文件".to_string());
+    }
+    if manifest.version > BACKUP_VERSION {
+        return Err("备份文件来自更新版本的应用，请先升级应用".to_string());
+    }
+
+    {
+        let mut entry = zip.by_name(DATABASE_FILE).map_err(|_| "备份文件里没有数据库".to_string())?;
+        let mut out = std::fs::File::create(snapshot).map_err(restore_error)?;
+        std::io::copy(&mut entry, &mut out).map_err(restore_error)?;
+    }
+    verify_snapshot(snapshot)?;
+
+    let mut skills = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(restore_error)?;
+        // enclosed_name 过滤掉 ../ 之类跳出目录的路径
+        let Some(relative) = entry
+            .enclosed_name()
+            .and_then(|p| p.strip_prefix(SKILLS_PREFIX.trim_end_matches('/')).ok())
+            .map(Path::to_path_buf)
+        else {
+            continue;
+        };
+        if entry.is_dir() || relative.as_os_str().is_empty() {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes).map_err(restore_error)?;
+        skills.push((relative, bytes));
+    }
+    Ok(skills)
+}
+
+/// 恢复前确认快照是完整的应用数据库
+fn verify_snapshot(snapshot: &Path) -> Result<(), String> {
+    let conn = open_snapshot(snapshot, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|_| "备份里的数据库无法打开".to_string())?;
+    let check: String = conn
+        .query_row("PRAGMA quick_check", [], |row| row.get(0))
+        .map_err(|_| "备份里的数据库无法读取：文件已损坏，或者是用别的密码加密的".to_string())?;
+    if check != "ok" {
+        return Err(format!("备份里的数据库已损坏: {}", check));
+    }
+    let has_sessions: bool = conn
+        .query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'sessions'", [], |row| row.get(0))
+        .map_err(restore_error)?;
+    if !has_sessions {
+        return Err("备份里的数据库不是本应用的数据".to_string());
+    }
+    Ok(())
+}
+
+/// 备份数据库；不指定目录时放在应用数据目录的 backups 下
+#[tauri::command]
+pub async fn backup_database(
+    app_handle: AppHandle,
+    state: tauri::State<'_, DbState>,
+    dir_path: Option<String>,
+) -> Result<BackupInfo, String> {
+    let data_dir = app_data_dir(&app_handle)?;
+    let db_path = PathBuf::from(state.0.lock().await.path.clone());
+    let dir = dir_path.map(PathBuf::from).unwrap_or_else(|| data_dir.join("backups"));
+    tokio::task::spawn_blocking(move || write_backup(&db_path, &data_dir.join("skills"), &dir, ""))
+        .await
+        .map_err(backup_error)?
+}
+
+/// 从备份文件恢复全部数据；返回恢复前自动做的那份备份，恢复完成后需要重启应用
+#[tauri::command]
+pub async fn restore_database(
+    app_handle: AppHandle,
+    state: tauri::State<'_, DbState>,
+    file_path: String,
+) -> Result<BackupInfo, String> {
+    let data_dir = app_data_dir(&app_handle)?;
+    let backups_dir = data_dir.join("backups");
+    std::fs::create_dir_all(&backups_dir).map_err(restore_error)?;
+    let snapshot = backups_dir.join(format!(".restore-{}.db", uuid::Uuid::new_v4()));
+    let _cleanup = scopeguard::guard(snapshot.clone(), |path| {
+        let _ = std::fs::remove_file(path);
+    });
+
+    let skills = {
+        let snapshot = snapshot.clone();
+        tokio::task::spawn_blocking(move || extract_backup(Path::new(&file_path), &snapshot))
+            .await
+            .map_err(restore_error)??
+    };
+
+    let skills_dir = data_dir.join("skills");
+    let safety = {
+        let mut db = state.0.clone().lock_owned().await;
+        let (skills_dir, snapshot) = (skills_dir.clone(), snapshot.clone());
+        tokio::task::spawn_blocking(move || {
+            let safety = write_backup(Path::new(&db.path), &skills_dir, &backups_dir, "-before-restore")?;
+
+            // 写回正在使用的数据库：其他连接持有锁时备份 API 会等待重试
+            let source = open_snapshot(&snapshot, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(restore_error)?;
+            copy_database(&source, &mut db.conn).map_err(restore_error)?;
+            Ok::<_, String>(safety)
+        })
+        .await
+        .map_err(restore_error)??
+    };
+
+    let _ = std::fs::remove_dir_all(&skills_dir);
+    for (relative, bytes) in skills {
+        let path = skills_dir.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(restore_error)?;
+        }
+        std::fs::write(&path, bytes).map_err(restore_error)?;
+    }
+
+    // 旧的 ANN 索引和恢复后的向量对不上，删掉后按需重建
+    for (path, name) in collect_files(&data_dir.join("vector_store")) {
+        if name.ends_with(".hnsw") {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    log::info!("Database restored from backup, previous data saved to {}", safety.path);
+    Ok(safety)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_round_trips_database_and_skill_files() {
+        let root = std::env::temp_dir().join(format!("baiyu-backup-test-{}", uuid::Uuid::new_v4()));
+        let _cleanup = scopeguard::guard(root.clone(), |dir| {
+            let _ = std::fs::remove_dir_all(dir);
+        });
+        let db_path = root.join("app.db");
+        let skills_dir = root.join("skills");
+        std::fs::create_dir_all(skills_dir.join("s1/resources")).unwrap();
+        std::fs::write(skills_dir.join("s1/resources/notes.md"), "hello").unwrap();
+        let conn = crate::db::open_connection(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sessions (id TEXT PRIMARY KEY, title TEXT);
+             INSERT INTO sessions VALUES ('s1', '备份测试');",
+        )
+        .unwrap();
+
+        let info = write_backup(&db_path, &skills_dir, &root.join("backups"), "").unwrap();
+        assert!(info.size > 0);
+        let name = Path::new(&info.path).file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("baiyu-backup-") && name.ends_with(".zip"));
+
+        let snapshot = root.join("restored.db");
+        let skills = extract_backup(Path::new(&info.path), &snapshot).unwrap();
+        assert_eq!(skills, [(PathBuf::from("s1/resources/notes.md"), b"hello".to_vec())]);
+        let restored = rusqlite::Connection::open(&snapshot).unwrap();
+        let title: String = restored.query_row("SELECT title FROM sessions", [], |row| row.get(0)).unwrap();
+        assert_eq!(title, "备份测试");
+    }
+
+    #[test]
+    fn restore_rejects_files_that_are_not_backups() {
+        let root = std::env::temp_dir().join(format!("baiyu-backup-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let _cleanup = scopeguard::guard(root.clone(), |dir| {
+            let _ = std::fs::remove_dir_all(dir);
+        });
+        let not_zip = root.join("notes.txt");
+        std::fs::write(&not_zip, "plain text").unwrap();
+        assert!(extract_backup(&not_zip, &root.join("out.db")).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 消息收藏
+ *
+ * 功能说明:
+ * - 给单条消息加星标收藏，可以附一句备注，再次收藏时更新所在收藏夹和备注
+ * - 收藏夹 (collection) 的增删改查：收藏可以放进一个命名的收藏夹，也可以不放（未分组）
+ * - list_bookmarks 按收藏时间倒序列出收藏，可以只看某个收藏夹或未分组的收藏，
+ *   带上所在会话的标题和消息开头部分，方便直接跳回原会话
+ *
+ * 消息彻底删除后收藏随外键一起删除；消息或会话在回收站里时收藏保留，但不出现在列表里。
+ * 删除收藏夹不会取消收藏，里面的收藏变回未分组。
+ */
+
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// 一个收藏夹
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkCollection {
+    pub id: String,
+    pub name: String,
+    /// 收藏夹里的收藏数（不含在回收站里的消息）
+    #[serde(default)]
+    pub bookmark_count: i64,
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+/// 一条收藏的消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmark {
+    pub message_id: String,
+    pub session_id: String,
+    pub session_title: String,
+    pub role: String,
+    /// 消息开头部分的预览
+    pub preview: String,
+    /// 所在收藏夹，未分组时为 None
+    pub collection_id: Option<String>,
+    pub note: Option<String>,
+    /// 消息本身的时间 (毫秒)
+    pub timestamp: i64,
+    /// 收藏的时间 (毫秒)
+    pub created_at: i64,
+}
+
+/// list_bookmarks 的筛选条件
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "kind", content = "id")]
+pub enum BookmarkFilter {
+    /// 全部收藏
+    #[default]
+    All,
+    /// 没有放进收藏夹的收藏
+    Ungrouped,
+    /// 指定收藏夹里的收藏
+    Collection(String),
+}
+
+/**
+ * 收藏消息；已经收藏过时更新所在收藏夹和备注
+ *
+ * @param collection_id: 放进哪个收藏夹，不传则未分组
+ * @param note: 备注，不传则没有备注
+ */
+#[tauri::command]
+pub async fn bookmark_message(
+    state: tauri::State<'_, DbState>,
+    message_id: String,
+    collection_id: Option<String>,
+    note: Option<String>,
+) -> Result<(), String> {
+    let note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    state
+        .run(move |db| {
+            db.bookmark_message(&message_id, collection_id.as_deref(), note.as_deref())
+                .map_err(|e| friendly_err("收藏消息失败，请确认消息已保存", e))
+        })
+        .await
+}
+
+/// 取消收藏
+#[tauri::command]
+pub async fn remove_bookmark(state: tauri::State<'_, DbState>, message_id: String) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.remove_bookmark(&message_id)
+                .map_err(|e| friendly_err("取消收藏失败，请重试", e))
+        })
+        .await
+}
+
+/// 按收藏时间倒序列出收藏的消息
+#[tauri::command]
+pub async fn list_bookmarks(
+    state: tauri::State<'_, DbState>,
+    filter: Option<BookmarkFilter>,
+) -> Result<Vec<Bookmark>, String> {
+    state
+        .run(move |db| {
+            db.list_bookmarks(&filter.unwrap_or_default())
+                .map_err(|e| friendly_err("读取收藏失败，请重试", e))
+        })
+        .await
+}
+
+/// 新建或重命名收藏夹（id 为空时视为新建）
+#[tauri::command]
+pub async fn save_bookmark_collection(
+    state: tauri::State<'_, DbState>,
+    collection: BookmarkCollection,
+) -> Result<BookmarkCollection, String> {
+    let mut collection = collection;
+    collection.name = collection.name.trim().to_string();
+    if collection.name.is_empty() {
+        return Err("收藏夹名称不能为空".to_string());
+    }
+
+    if collection.id.is_empty() {
+        collection.id = Uuid::new_v4().to_string();
+        collection.created_at = chrono::Utc::now().timestamp_millis();
+    }
+    collection.updated_at = chrono::Utc::now().timestamp_millis();
+
+    state
+        .run(move |db| {
+            db.save_bookmark_collection(&collection)
+                .map_err(|e| friendly_err("保存收藏夹失败，请重试", e))?;
+            Ok(collection)
+        })
+        .await
+}
+
+/// 获取所有收藏夹（按名称排序）
+#[tauri::command]
+pub async fn list_bookmark_collections(state: tauri::State<'_, DbState>) -> Result<Vec<BookmarkCollection>, String> {
+    state
+        .run(|db| {
+            db.get_bookmark_collections()
+                .map_err(|e| friendly_err("读取收藏夹失败，请重试", e))
+        })
+        .await
+}
+
+/// 删除收藏夹，里面的收藏变回未分组
+#[tauri::command]
+pub async fn delete_bookmark_collection(
+    state: tauri::State<'_, DbState>,
+    collection_id: String,
+) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.delete_bookmark_collection(&collection_id)
+                .map_err(|e| friendly_err("删除收藏夹失败，请重试", e))
+        })
+        .await
+}
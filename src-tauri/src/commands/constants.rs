@@ -8,6 +8,15 @@ pub const LLM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 // 流式请求不能设总超时（长回复会被中途掐断，reqwest 报 "error decoding
 // response body"），只限制两次收到数据之间的最大间隔。
 pub const LLM_STREAM_READ_TIMEOUT: Duration = Duration::from_secs(180);
+// 流式回复的空闲看门狗：开始输出后超过这么久没收到任何字节，就认为流卡死了。
+// 比读间隔超时短，是因为有的网关断流时既不关连接也不发数据，等满读间隔超时
+// 用户早就以为程序死了。可按请求覆盖（思考很久才出字的模型可以调大）。
+pub const LLM_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+// 开启自动续写时，单条回复最多因卡死重新发起几次请求。
+pub const LLM_STREAM_MAX_RESUMES: u32 = 2;
+// 流式回复生成期间把已输出的正文写进 messages 表（partial 状态）的间隔，
+// 应用中途被关掉时重启最多丢这么久的内容。
+pub const PARTIAL_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(3);
 
 // 流式下载（Ollama 模型拉取、安装包下载）同理不能设总超时——下载耗时
 // 由文件大小和网速决定，没有安全的上限；只限读间隔，断流才算失败。
@@ -18,10 +27,28 @@ pub const MCP_HTTP_TIMEOUT: Duration = Duration::from_secs(60);
 // 工具真正执行（tools/call）可能是搜索、抓网页、长推理，30 秒不够用；
 // tools/list 仍用上面的短超时。
 pub const MCP_TOOL_CALL_TIMEOUT: Duration = Duration::from_secs(300);
+// 抓取网页（fetch_url / 内置工具）单跳请求的总超时，包括读完响应体
+pub const WEB_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub const EMBEDDING_BATCH_DELAY_MS: u64 = 100;
+// 单批 embedding 请求遇到限流/过载/网络错误时的重试次数，以及指数退避的
+// 起始间隔和上限
+pub const EMBEDDING_MAX_RETRIES: u32 = 3;
+pub const EMBEDDING_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+pub const EMBEDDING_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+// 知识库爬取整站：默认跟随的链接层数和抓取的网页数，以及用户能设置的上限
+pub const CRAWL_DEFAULT_MAX_DEPTH: usize = 2;
+pub const CRAWL_MAX_DEPTH_LIMIT: usize = 5;
+pub const CRAWL_DEFAULT_MAX_PAGES: usize = 20;
+pub const CRAWL_MAX_PAGES_LIMIT: usize = 200;
+// 浏览文档分块时每页默认返回的分块数，以及一页最多返回的分块数
+pub const CHUNK_PAGE_DEFAULT_LIMIT: usize = 50;
+pub const CHUNK_PAGE_MAX_LIMIT: usize = 500;
 
 // 服务商返回限流/过载类错误（429/529/"overloaded" 等）时的默认自动重试
 // 次数和间隔；用户可在设置页覆盖，未配置时用这两个值兜底。
 pub const DEFAULT_LLM_RETRY_COUNT: u32 = 3;
 pub const DEFAULT_LLM_RETRY_INTERVAL_SECS: u32 = 2;
+// 服务商 Retry-After 要求的等待超过这个时长（多半是小时/天级别的配额重置）
+// 就不排队干等了，直接把限流原因报给用户。
+pub const LLM_MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
@@ -0,0 +1,262 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 上下文窗口管理模块
+ *
+ * 功能说明:
+ * - 按模型名维护一张上下文窗口大小表（token 数）
+ * - 发送前粗略估算消息列表的 token 数，超出窗口时从最早的对话轮次开始整轮裁掉
+ * - 只在窗口已知（表里有，或者 API 配置里填了）时裁剪；本地模型、自定义网关这类
+ *   查不到的模型不裁，超限时由服务商报错，不会悄悄丢掉历史
+ * - 发生裁剪时由调用方发出 context-truncated 事件，前端提示"较早的消息未发送"
+ *
+ * token 数沿用知识库分块的字符数估算口径，不追求精确，所以预算里留了余量；
+ * 真正超限时服务商仍会报错，这里只是让长会话不至于每次都撞上限。
+ */
+
+use crate::commands::llm::ChatMessage;
+use crate::knowledge_base::document::estimate_tokens;
+use serde::Serialize;
+
+/// 模型上下文窗口表
+/// 格式: (模型名前缀, 上下文窗口 token 数)，按最长前缀匹配
+const CONTEXT_WINDOWS: &[(&str, u32)] = &[
+    ("gpt-5", 400_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4.5", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+    ("claude", 200_000),
+    ("gemini-1.5-pro", 2_000_000),
+    ("gemini", 1_048_576),
+    ("mistral-large", 128_000),
+    ("mistral-medium", 128_000),
+    ("mistral-small", 32_000),
+    ("codestral", 256_000),
+    ("deepseek", 128_000),
+    ("kimi-k2", 128_000),
+    ("moonshot-v1-8k", 8_192),
+    ("moonshot-v1-32k", 32_768),
+    ("moonshot-v1-128k", 131_072),
+    ("glm-4.5", 128_000),
+    ("glm-4", 128_000),
+    ("qwen-max", 32_768),
+    ("qwen-max-latest", 131_072),
+    ("qwen-plus", 131_072),
+    ("qwen-turbo", 1_000_000),
+    ("qwen-long", 10_000_000),
+    ("qwen2.5", 131_072),
+    ("qwen2.5-turbo", 1_000_000),
+    ("qwen3", 131_072),
+    ("qwen3-max", 262_144),
+    ("qwen3-coder", 262_144),
+    ("qwq", 131_072),
+    ("doubao", 128_000),
+    ("minimax", 1_000_000),
+    ("yi-lightning", 16_384),
+    ("llama3.1", 131_072),
+    ("llama-3.1", 131_072),
+    ("meta-llama-3.1", 131_072),
+    ("llama3.2", 131_072),
+    ("llama-3.2", 131_072),
+    ("llama3.3", 131_072),
+    ("llama-3.3", 131_072),
+    ("llama3", 8_192),
+    ("llama-3", 8_192),
+    ("meta-llama-3", 8_192),
+];
+
+/// 请求没有指定 max_tokens 时给回复预留的 token 数
+const DEFAULT_OUTPUT_RESERVE: u32 = 4_096;
+
+/// 每张图片/每段视频按这么多 token 计入（各家计法不同，取个中间值）
+const ATTACHMENT_TOKEN_ESTIMATE: usize = 1_000;
+
+/// 上下文裁剪事件：发送前裁掉了较早的消息时发出一次
+#[derive(Clone, Serialize)]
+pub struct ContextTruncatedEvent {
+    pub session_id: String,
+    pub message_id: String,
+    /// 被裁掉的消息条数
+    pub dropped_messages: usize,
+    /// 裁剪前的估算 token 数
+    pub estimated_tokens: usize,
+    /// 裁剪后的估算 token 数
+    pub kept_tokens: usize,
+    /// 按哪个上下文窗口计算的
+    pub context_window: u32,
+}
+
+/// 一次裁剪的结果
+#[derive(Debug, PartialEq)]
+pub(crate) struct Truncation {
+    pub dropped: usize,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+}
+
+/// 查找模型的上下文窗口大小，表里没有的模型返回 None
+pub fn context_window(model: &str) -> Option<u32> {
+    let model = model.to_lowercase();
+    // 带命名空间的模型名（"deepseek-ai/DeepSeek-V3"）只看最后一段
+    let model = model.rsplit('/').next().unwrap_or(&model);
+    CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, window)| *window)
+}
+
+/// 可用于输入消息的 token 预算：窗口留 10% 余量给估算误差和工具定义，
+/// 再扣掉回复要用的 max_tokens。max_tokens 配得比窗口还大时至少保留 1/4 窗口。
+pub fn input_budget(window: u32, max_tokens: Option<u32>) -> usize {
+    let usable = window as usize * 9 / 10;
+    let reserve = max_tokens.unwrap_or(DEFAULT_OUTPUT_RESERVE) as usize;
+    usable.saturating_sub(reserve).max(window as usize / 4)
+}
+
+/// 估算单条消息的 token 数（含图片/视频附件）
+pub(crate) fn message_tokens(message: &ChatMessage) -> usize {
+    estimate_tokens(&message.content).max(0) as usize
+        + (message.images.len() + message.videos.len()) * ATTACHMENT_TOKEN_ESTIMATE
+}
+
+/// 把消息列表裁到预算以内，没有裁剪时返回 None
+///
+/// 按对话轮次整轮丢弃：一轮是一条 user 消息和它后面的 assistant/tool 消息，
+/// 不会留下找不到调用的工具结果。开头的 system 消息和最后一轮（本轮的提问）始终保留；
+/// 其余按从旧到新的顺序丢弃。开头不是 user 的残缺轮次也一并丢掉——
+/// Anthropic/Gemini 要求对话以 user 开头。
+pub(crate) fn fit_to_context(messages: &mut Vec<ChatMessage>, budget: usize) -> Option<Truncation> {
+    let tokens_before: usize = messages.iter().map(message_tokens).sum();
+    if tokens_before <= budget {
+        return None;
+    }
+
+    let system_count = messages.iter().take_while(|m| m.role == "system").count();
+    let mut total = tokens_before;
+    let mut dropped = 0;
+    loop {
+        let rest = &messages[system_count..];
+        // 第一轮到下一条 user 消息为止；后面没有 user 消息说明只剩最后一轮
+        let Some(turn_len) = rest.iter().skip(1).position(|m| m.role == "user").map(|i| i + 1) else {
+            break;
+        };
+        let partial = rest[0].role != "user";
+        if total <= budget && !partial {
+            break;
+        }
+        for message in messages.drain(system_count..system_count + turn_len) {
+            total -= message_tokens(&message);
+            dropped += 1;
+        }
+    }
+
+    (dropped > 0).then_some(Truncation {
+        dropped,
+        tokens_before,
+        tokens_after: total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            id: String::new(),
+            role: role.to_string(),
+            content: content.to_string(),
+            timestamp: 0,
+            error: None,
+            images: vec![],
+            videos: vec![],
+            seed: None,
+            generation: None,
+        }
+    }
+
+    #[test]
+    fn longest_prefix_picks_context_window() {
+        assert_eq!(context_window("gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_window("gpt-4-0613"), Some(8_192));
+        assert_eq!(context_window("deepseek-ai/DeepSeek-V3"), Some(128_000));
+        assert_eq!(context_window("llama3.1:8b"), Some(131_072));
+        assert_eq!(context_window("meta-llama/Llama-3.3-70B-Instruct"), Some(131_072));
+        assert_eq!(context_window("meta-llama/Meta-Llama-3.1-8B-Instruct"), Some(131_072));
+        assert_eq!(context_window("llama3:8b"), Some(8_192));
+        assert_eq!(context_window("Qwen/Qwen3-235B-A22B"), Some(131_072));
+        assert_eq!(context_window("qwen3-max-preview"), Some(262_144));
+        assert_eq!(context_window("qwen2.5:7b"), Some(131_072));
+        // 查不到的模型不给窗口，调用方不裁剪
+        assert_eq!(context_window("my-local-model"), None);
+        assert_eq!(context_window("qwen-custom-finetune"), None);
+        assert_eq!(context_window("llama4:scout"), None);
+    }
+
+    #[test]
+    fn drops_oldest_turns_but_keeps_system_and_latest_question() {
+        let long = "字".repeat(300); // ≈100 token
+        let mut messages = vec![
+            msg("system", "你是助手"),
+            msg("user", &long),
+            msg("assistant", &long),
+            msg("user", &long),
+            msg("assistant", &long),
+            msg("user", "最新的问题"),
+        ];
+        let truncation = fit_to_context(&mut messages, 250).unwrap();
+        assert_eq!(truncation.dropped, 2);
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert!(truncation.tokens_after <= 250);
+
+        assert!(fit_to_context(&mut messages, 10_000).is_none());
+    }
+
+    #[test]
+    fn drops_whole_turns_so_tool_results_keep_their_calls() {
+        let long = "字".repeat(300); // ≈100 token
+        let mut messages = vec![
+            msg("system", "你是助手"),
+            msg("user", &long),
+            msg("assistant", &long),
+            msg("tool", &long),
+            msg("assistant", &long),
+            msg("user", &long),
+            msg("assistant", &long),
+            msg("tool", &long),
+            msg("user", "最新的问题"),
+        ];
+        // 丢掉第一轮的一部分就够了，但整轮四条一起丢
+        let truncation = fit_to_context(&mut messages, 650).unwrap();
+        assert_eq!(truncation.dropped, 4);
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "tool", "user"]);
+
+        // 预算再小也保留最后一轮
+        fit_to_context(&mut messages, 10).unwrap();
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user"]);
+
+        // 开头残缺的轮次（前面没有 user）整段丢掉
+        let mut messages = vec![msg("assistant", "旧回答"), msg("tool", "旧结果"), msg("user", "问题")];
+        let truncation = fit_to_context(&mut messages, 0).unwrap();
+        assert_eq!(truncation.dropped, 2);
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn budget_reserves_output_tokens() {
+        assert_eq!(input_budget(100_000, Some(10_000)), 80_000);
+        assert_eq!(input_budget(8_192, Some(8_000)), 2_048);
+    }
+}
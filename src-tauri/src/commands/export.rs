@@ -0,0 +1,489 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 会话导出
+ *
+ * 功能说明:
+ * - export_session 把单个会话导出成 Markdown、JSON 或独立的 HTML 文件，写到用户选择的路径
+ * - export_all_sessions 把全部会话各导出成一个文件，放进用户选择的目录
+ * - Markdown 原样保留消息里的代码块，流式中断留下的未闭合代码块会补上结尾
+ * - HTML 自带样式，不依赖网络和应用本身，可以直接用浏览器打开或分享
+ * - 记录了生成信息的回复附上生成它的模型、结束原因、耗时和 token 数
+ *
+ * 导出只包含未删除的消息（回收站里的不导出）。JSON 的字段和以前前端导出的格式一致。
+ */
+
+use crate::commands::llm::{ChatMessage, GenerationInfo, SessionFilter, SessionOverview};
+use crate::commands::local_model::friendly_err;
+use crate::db::{Database, DbState, MAX_MESSAGE_PAGE_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// 导出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    /// 导出文件的扩展名
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+/// HTML 导出的内嵌样式
+const HTML_STYLE: &str = "body{max-width:860px;margin:40px auto;padding:0 20px;font-family:-apple-system,'Segoe UI','PingFang SC','Microsoft YaHei',sans-serif;line-height:1.7;color:#1f2328;background:#fff}\
+h1{font-size:24px;margin-bottom:8px}.meta{color:#656d76;font-size:13px;margin-bottom:32px}\
+.message{border-top:1px solid #d0d7de;padding:16px 0}.author{font-weight:600;font-size:14px}.time{color:#656d76;font-size:12px;margin-left:8px}\
+.text{white-space:pre-wrap;word-break:break-word;margin:8px 0}.error{color:#cf222e}.generation{color:#656d76;font-size:12px}\
+code{font-family:ui-monospace,SFMono-Regular,Menlo,Consolas,monospace;font-size:13px;background:#f6f8fa;padding:2px 4px;border-radius:4px}\
+pre{background:#f6f8fa;padding:12px 16px;border-radius:6px;overflow-x:auto}pre code{padding:0;background:none}\
+.lang{color:#656d76;font-size:12px;margin-bottom:4px}";
+
+fn role_label(role: &str) -> &str {
+    match role {
+        "user" => "你",
+        "assistant" => "AI 助手",
+        "system" => "系统",
+        other => other,
+    }
+}
+
+fn format_timestamp(ts: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(ts)
+        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+/// 一行回复的生成信息：模型 · 结束原因 · 耗时 · token 数，没有任何一项时返回 None
+fn generation_summary(generation: &GenerationInfo) -> Option<String> {
+    let model = match (&generation.provider, &generation.model) {
+        (Some(provider), Some(model)) => Some(format!("{} / {}", provider, model)),
+        (provider, model) => provider.clone().or_else(|| model.clone()),
+    };
+    let tokens = match (generation.input_tokens, generation.output_tokens) {
+        (None, None) => None,
+        (input, output) => {
+            let count = |n: Option<i64>| n.map(|n| n.to_string()).unwrap_or_else(|| "-".to_string());
+            let estimated = if generation.tokens_estimated { "（估算）" } else { "" };
+            Some(format!("输入 {} / 输出 {} tokens{}", count(input), count(output), estimated))
+        }
+    };
+    let parts: Vec<String> = [
+        model,
+        generation.finish_reason.as_ref().map(|r| format!("结束原因 {}", r)),
+        generation.latency_ms.map(|ms| format!("耗时 {:.1}s", ms as f64 / 1000.0)),
+        tokens,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!parts.is_empty()).then(|| parts.join(" · "))
+}
+
+/// 行首是 ``` 或 ~~~ 的代码块分隔行，返回分隔符后面的语言标记
+fn fence_info(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    ["```", "~~~"]
+        .iter()
+        .find(|fence| trimmed.starts_with(**fence))
+        .map(|fence| trimmed.trim_start_matches(fence.chars().next().unwrap()).trim())
+}
+
+/// 消息里未闭合的代码块（流式输出中断时常见）补上结尾，避免吞掉后面的消息
+fn close_open_fence(content: &str) -> String {
+    let open = content.lines().filter(|line| fence_info(line).is_some()).count() % 2 == 1;
+    if open {
+        format!("{}\n```", content.trim_end())
+    } else {
+        content.trim_end().to_string()
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// 普通文本段落：转义后把 `行内代码` 换成 <code>，换行和缩进靠 pre-wrap 保留
+fn render_text_html(text: &str, out: &mut String) {
+    if text.trim().is_empty() {
+        return;
+    }
+    out.push_str("<div class=\"text\">");
+    for (i, part) in text.trim_matches('\n').split('`').enumerate() {
+        if i % 2 == 1 {
+            out.push_str("<code>");
+            out.push_str(&escape_html(part));
+            out.push_str("</code>");
+        } else {
+            out.push_str(&escape_html(part));
+        }
+    }
+    out.push_str("</div>\n");
+}
+
+/// 把一条消息的正文渲染成 HTML：代码块放进 <pre><code>，其余按纯文本输出
+fn render_content_html(content: &str, out: &mut String) {
+    let mut text = String::new();
+    let mut code: Option<(String, String)> = None;
+    for line in content.lines() {
+        match (&mut code, fence_info(line)) {
+            (None, Some(lang)) => {
+                render_text_html(&text, out);
+                text.clear();
+                code = Some((lang.to_string(), String::new()));
+            }
+            (Some(_), Some(_)) => {
+                let (lang, body) = code.take().unwrap();
+                render_code_html(&lang, &body, out);
+            }
+            (Some((_, body)), None) => {
+                body.push_str(line);
+                body.push('\n');
+            }
+            (None, None) => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    if let Some((lang, body)) = code {
+        render_code_html(&lang, &body, out);
+    }
+    render_text_html(&text, out);
+}
+
+fn render_code_html(lang: &str, body: &str, out: &mut String) {
+    if !lang.is_empty() {
+        out.push_str(&format!("<div class=\"lang\">{}</div>", escape_html(lang)));
+    }
+    out.push_str(&format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>\n",
+        escape_html(lang),
+        escape_html(body.trim_end_matches('\n'))
+    ));
+}
+
+/// 导出为 Markdown
+pub fn render_markdown(session: &SessionOverview, messages: &[ChatMessage]) -> String {
+    let mut out = format!(
+        "# {}\n\n- 模型：{} / {}\n- 创建时间：{}\n- 更新时间：{}\n",
+        session.title,
+        session.provider,
+        session.model,
+        format_timestamp(session.created_at),
+        format_timestamp(session.updated_at)
+    );
+    for m in messages {
+        out.push_str(&format!("\n---\n\n### {} · {}\n\n", role_label(&m.role), format_timestamp(m.timestamp)));
+        if !m.content.trim().is_empty() {
+            out.push_str(&close_open_fence(&m.content));
+            out.push('\n');
+        }
+        if let Some(error) = &m.error {
+            out.push_str(&format!("\n> 出错：{}\n", error));
+        }
+        if let Some(summary) = m.generation.as_ref().and_then(generation_summary) {
+            out.push_str(&format!("\n> {}\n", summary));
+        }
+    }
+    out
+}
+
+/// 导出为 JSON（和以前前端导出的字段一致）
+pub fn render_json(session: &SessionOverview, messages: &[ChatMessage]) -> String {
+    let payload = serde_json::json!({
+        "title": session.title,
+        "provider": session.provider,
+        "model": session.model,
+        "createdAt": session.created_at,
+        "updatedAt": session.updated_at,
+        "messages": messages
+            .iter()
+            .map(|m| serde_json::json!({
+                "role": m.role,
+                "content": m.content,
+                "timestamp": m.timestamp,
+                "error": m.error,
+                "generation": m.generation.as_ref().map(|g| serde_json::json!({
+                    "provider": g.provider,
+                    "model": g.model,
+                    "finishReason": g.finish_reason,
+                    "latencyMs": g.latency_ms,
+                    "inputTokens": g.input_tokens,
+                    "outputTokens": g.output_tokens,
+                    "tokensEstimated": g.tokens_estimated,
+                })),
+            }))
+            .collect::<Vec<_>>(),
+    });
+    serde_json::to_string_pretty(&payload).unwrap_or_default()
+}
+
+/// 导出为独立的 HTML 页面
+pub fn render_html(session: &SessionOverview, messages: &[ChatMessage]) -> String {
+    let title = escape_html(&session.title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"zh-CN\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{title}</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n\
+         <div class=\"meta\">{} / {} · 创建于 {} · 更新于 {}</div>\n",
+        escape_html(&session.provider),
+        escape_html(&session.model),
+        format_timestamp(session.created_at),
+        format_timestamp(session.updated_at)
+    );
+    for m in messages {
+        out.push_str(&format!(
+            "<div class=\"message\">\n<div><span class=\"author\">{}</span><span class=\"time\">{}</span></div>\n",
+            escape_html(role_label(&m.role)),
+            format_timestamp(m.timestamp)
+        ));
+        render_content_html(&m.content, &mut out);
+        if let Some(error) = &m.error {
+            out.push_str(&format!("<div class=\"text error\">出错：{}</div>\n", escape_html(error)));
+        }
+        if let Some(summary) = m.generation.as_ref().and_then(generation_summary) {
+            out.push_str(&format!("<div class=\"generation\">{}</div>\n", escape_html(&summary)));
+        }
+        out.push_str("</div>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn render(format: ExportFormat, session: &SessionOverview, messages: &[ChatMessage]) -> String {
+    match format {
+        ExportFormat::Markdown => render_markdown(session, messages),
+        ExportFormat::Json => render_json(session, messages),
+        ExportFormat::Html => render_html(session, messages),
+    }
+}
+
+/// 建议的导出文件名：标题去掉文件名里不能出现的字符，加上最后更新日期
+fn export_filename(session: &SessionOverview, format: ExportFormat) -> String {
+    let title: String = session
+        .title
+        .chars()
+        .map(|c| if "\\/:*?\"<>|".contains(c) || c.is_control() { '_' } else { c })
+        .collect();
+    let title: String = title.trim().chars().take(40).collect();
+    let title = if title.is_empty() { "对话".to_string() } else { title };
+    let date = chrono::DateTime::from_timestamp_millis(session.updated_at)
+        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    format!("{}_{}.{}", title, date, format.extension())
+}
+
+/// 批量导出时同名文件加上序号，避免互相覆盖
+fn unique_filename(name: String, used: &mut HashSet<String>) -> String {
+    if used.insert(name.to_lowercase()) {
+        return name;
+    }
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, ""));
+    let mut n = 2;
+    loop {
+        let candidate = format!("{}_{}.{}", stem, n, ext);
+        if used.insert(candidate.to_lowercase()) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// 读取会话的全部未删除消息
+fn load_all_messages(db: &Database, session_id: &str) -> Result<Vec<ChatMessage>, Box<dyn std::error::Error>> {
+    let mut messages = Vec::new();
+    loop {
+        let page = db.get_messages_page(session_id, Some(messages.len()), MAX_MESSAGE_PAGE_SIZE)?;
+        let done = page.messages.is_empty() || page.offset + page.messages.len() >= page.total;
+        messages.extend(page.messages);
+        if done {
+            return Ok(messages);
+        }
+    }
+}
+
+/// 导出单个会话到指定路径
+#[tauri::command]
+pub async fn export_session(
+    state: tauri::State<'_, DbState>,
+    session_id: String,
+    format: ExportFormat,
+    file_path: String,
+) -> Result<(), String> {
+    let id = session_id.clone();
+    let (session, messages) = state
+        .run(move |db| {
+            let session = db
+                .get_sessions(&SessionFilter::default())
+                .map_err(|e| friendly_err("读取会话失败，请重试", e))?
+                .into_iter()
+                .find(|s| s.id == id)
+                .ok_or_else(|| "会话不存在或已删除".to_string())?;
+            let messages = load_all_messages(db, &id).map_err(|e| friendly_err("读取消息失败，请重试", e))?;
+            Ok::<_, String>((session, messages))
+        })
+        .await?;
+
+    std::fs::write(&file_path, render(format, &session, &messages)).map_err(|e| format!("导出失败: {}", e))?;
+    log::info!("Exported session {} to {}", session_id, file_path);
+    Ok(())
+}
+
+/// 把全部会话导出到指定目录，每个会话一个文件；返回导出的会话数
+#[tauri::command]
+pub async fn export_all_sessions(
+    state: tauri::State<'_, DbState>,
+    format: ExportFormat,
+    dir_path: String,
+) -> Result<usize, String> {
+    let exports = state
+        .run(|db| {
+            let sessions = db
+                .get_sessions(&SessionFilter::default())
+                .map_err(|e| friendly_err("读取会话列表失败，请重试", e))?;
+            let mut exports = Vec::with_capacity(sessions.len());
+            for session in sessions {
+                let messages =
+                    load_all_messages(db, &session.id).map_err(|e| friendly_err("读取消息失败，请重试", e))?;
+                exports.push((session, messages));
+            }
+            Ok::<_, String>(exports)
+        })
+        .await?;
+
+    let dir = Path::new(&dir_path);
+    let mut used = HashSet::new();
+    for (session, messages) in &exports {
+        let filename = unique_filename(export_filename(session, format), &mut used);
+        std::fs::write(dir.join(&filename), render(format, session, messages))
+            .map_err(|e| format!("导出 {} 失败: {}", filename, e))?;
+    }
+    log::info!("Exported {} sessions to {}", exports.len(), dir_path);
+    Ok(exports.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(title: &str) -> SessionOverview {
+        SessionOverview {
+            id: "s1".to_string(),
+            title: title.to_string(),
+            created_at: 0,
+            updated_at: 0,
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            api_config_id: "cfg".to_string(),
+            persona_id: None,
+            message_count: 2,
+            last_message_preview: None,
+            last_message_at: None,
+            pinned: false,
+            archived: false,
+            tags: Vec::new(),
+        }
+    }
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            id: format!("{}-id", role),
+            role: role.to_string(),
+            content: content.to_string(),
+            timestamp: 0,
+            error: None,
+            images: vec![],
+            videos: vec![],
+            seed: None,
+            generation: None,
+        }
+    }
+
+    #[test]
+    fn markdown_keeps_code_fences_and_closes_unterminated_ones() {
+        let messages = [
+            message("user", "写个函数"),
+            message("assistant", "```rust\nfn main() {}\n```"),
+            message("assistant", "被中断的回复\n```python\nprint(1)"),
+        ];
+        let md = render_markdown(&session("测试"), &messages);
+        assert!(md.starts_with("# 测试\n"));
+        assert!(md.contains("### AI 助手"));
+        assert!(md.contains("```rust\nfn main() {}\n```\n"));
+        assert!(md.contains("print(1)\n```\n"));
+        assert_eq!(md.matches("```").count() % 2, 0);
+    }
+
+    #[test]
+    fn html_escapes_text_and_renders_code_blocks() {
+        let messages = [message("assistant", "用 `<div>` 包起来：\n```html\n<b>&</b>\n```\n完")];
+        let html = render_html(&session("<script>"), &messages);
+        assert!(html.contains("<title>&lt;script&gt;</title>"));
+        assert!(html.contains("用 <code>&lt;div&gt;</code> 包起来："));
+        assert!(html.contains("<pre><code class=\"language-html\">&lt;b&gt;&amp;&lt;/b&gt;</code></pre>"));
+        assert!(html.contains("<div class=\"text\">完</div>"));
+    }
+
+    #[test]
+    fn replies_carry_their_generation_info() {
+        let mut reply = message("assistant", "好的");
+        reply.generation = Some(GenerationInfo {
+            provider: Some("anthropic".to_string()),
+            model: Some("claude-sonnet".to_string()),
+            finish_reason: Some("length".to_string()),
+            latency_ms: Some(3250),
+            input_tokens: Some(120),
+            output_tokens: Some(450),
+            tokens_estimated: true,
+        });
+        let messages = [message("user", "写首诗"), reply];
+
+        let md = render_markdown(&session("测试"), &messages);
+        assert!(md.contains("> anthropic / claude-sonnet · 结束原因 length · 耗时 3.2s · 输入 120 / 输出 450 tokens（估算）\n"));
+        assert_eq!(md.matches("结束原因").count(), 1);
+
+        let json: serde_json::Value = serde_json::from_str(&render_json(&session("测试"), &messages)).unwrap();
+        assert!(json["messages"][0]["generation"].is_null());
+        assert_eq!(json["messages"][1]["generation"]["latencyMs"], 3250);
+        assert_eq!(json["messages"][1]["generation"]["finishReason"], "length");
+
+        let partial = GenerationInfo { output_tokens: Some(8), ..Default::default() };
+        assert_eq!(generation_summary(&partial).as_deref(), Some("输入 - / 输出 8 tokens"));
+        assert_eq!(generation_summary(&GenerationInfo::default()), None);
+    }
+
+    #[test]
+    fn bulk_export_filenames_are_sanitized_and_unique() {
+        let mut used = HashSet::new();
+        let name = export_filename(&session("a/b: c?"), ExportFormat::Markdown);
+        assert!(name.starts_with("a_b_ c__") && name.ends_with(".md"));
+        assert_eq!(unique_filename(name.clone(), &mut used), name);
+        let second = unique_filename(name.clone(), &mut used);
+        assert_eq!(second, name.replace(".md", "_2.md"));
+        assert_eq!(export_filename(&session("  "), ExportFormat::Json).split('_').next(), Some("对话"));
+    }
+}
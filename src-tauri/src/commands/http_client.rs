@@ -0,0 +1,77 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 共享 HTTP 客户端模块
+ *
+ * 功能说明:
+ * - LLM、嵌入、重排序、MCP HTTP 请求共用惰性创建的 reqwest 客户端，复用连接池，
+ *   不再每次请求都新建客户端、重新做 TCP/TLS 握手
+ * - TLS 最低版本、User-Agent、连接保活、超时和代理统一在这里配置
+ *
+ * 超时策略不同的请求（非流式 / 流式 / 其它）各用一种客户端；代理按请求目标
+ * 决定（见 proxy 模块），不同的代理决定各缓存一份客户端。代理设置修改后新
+ * 请求自然落到新的缓存项上，不需要手动失效。
+ */
+
+use crate::commands::constants::{LLM_CONNECT_TIMEOUT, LLM_REQUEST_TIMEOUT, LLM_STREAM_READ_TIMEOUT};
+use crate::commands::proxy::{apply_proxy, resolve_proxy, ProxyChoice};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 所有共享客户端统一带上的 User-Agent
+const USER_AGENT: &str = concat!("BaiyuAISpace/", env!("CARGO_PKG_VERSION"));
+
+/// 空闲连接在池里保留的时长
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// TCP keepalive 间隔，防止长时间思考的流式连接被中间设备掐掉
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// 客户端种类：决定超时策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ClientKind {
+    /// 非流式 LLM 请求：生成完成前服务器不发任何字节，只能设足够长的总超时
+    Request,
+    /// 流式 LLM 请求：不能设总超时（长回复会被中途掐断），只限读间隔
+    Streaming,
+    /// 其它请求（嵌入、重排序、MCP）：只限连接超时，总超时由调用方按请求设置
+    General,
+}
+
+static CLIENTS: Lazy<Mutex<HashMap<(ClientKind, ProxyChoice), reqwest::Client>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn build(kind: ClientKind, proxy: &ProxyChoice) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .min_tls_version(reqwest::tls::Version::TLS_1_2)
+        .connect_timeout(LLM_CONNECT_TIMEOUT)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE);
+    builder = match kind {
+        ClientKind::Request => builder.timeout(LLM_REQUEST_TIMEOUT),
+        ClientKind::Streaming => builder.read_timeout(LLM_STREAM_READ_TIMEOUT),
+        ClientKind::General => builder,
+    };
+    apply_proxy(builder, proxy).build()
+}
+
+/// 获取共享客户端（`reqwest::Client` 内部是 Arc，克隆开销可以忽略）
+///
+/// @param kind: 客户端种类
+/// @param scope: 代理作用域（provider 标识符或 "mcp"），None 表示只用全局代理
+/// @param url: 请求目标地址，回环地址一律直连
+pub(crate) fn shared_client(kind: ClientKind, scope: Option<&str>, url: &str) -> reqwest::Result<reqwest::Client> {
+    let key = (kind, resolve_proxy(scope, url));
+    let mut clients = CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    let client = build(kind, &key.1)?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
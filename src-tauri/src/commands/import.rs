@@ -0,0 +1,309 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 从 ChatGPT / Claude 导入聊天记录
+ *
+ * 功能说明:
+ * - import_conversations 读取 ChatGPT 或 Claude 官方"导出数据"里的 conversations.json
+ *   （也可以直接选整个导出 zip），自动识别格式，每个对话写成一个会话
+ * - ChatGPT 的对话是一棵树（编辑、重新生成会产生分支），只导入 current_node 所在的那条分支；
+ *   隐藏消息、system/tool 消息和图片等非文本内容跳过
+ * - Claude 导出里 human/assistant 两种发言按顺序导入
+ * - 会话 ID 用原对话的 ID，重复导入同一份文件时已导入的对话直接跳过
+ *
+ * 导入的会话不绑定 API 配置（api_config_id 为空），继续对话时使用当前选中的配置。
+ */
+
+use crate::commands::llm::{ChatMessage, ChatSession};
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Read;
+
+/// 导入结果
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    /// 新导入的会话数
+    pub imported: usize,
+    /// 之前已经导入过、这次跳过的会话数
+    pub duplicates: usize,
+    /// 没有可导入消息、跳过的对话数
+    pub empty: usize,
+}
+
+/// 毫秒时间戳；导出文件里 ChatGPT 用浮点秒，Claude 用 RFC 3339 字符串
+fn timestamp_ms(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_f64().map(|secs| (secs * 1000.0) as i64),
+        Value::String(s) => chrono::DateTime::parse_from_rfc3339(s).ok().map(|t| t.timestamp_millis()),
+        _ => None,
+    }
+}
+
+fn non_empty_str(value: &Value) -> Option<&str> {
+    value.as_str().map(str::trim).filter(|s| !s.is_empty())
+}
+
+fn message(id: String, role: &str, content: String, timestamp: i64) -> ChatMessage {
+    ChatMessage {
+        id,
+        role: role.to_string(),
+        content,
+        timestamp,
+        error: None,
+        images: vec![],
+        videos: vec![],
+        seed: None,
+        generation: None,
+    }
+}
+
+/// ChatGPT 消息的文本内容：text / multimodal_text 取文字部分，code 包成代码块，其余类型跳过
+fn openai_content(content: &Value) -> Option<String> {
+    let text = match content["content_type"].as_str()? {
+        "text" | "multimodal_text" => content["parts"]
+            .as_array()?
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        "code" => {
+            let language = content["language"].as_str().filter(|l| *l != "unknown").unwrap_or("");
+            format!("```{}\n{}\n```", language, content["text"].as_str()?)
+        }
+        _ => return None,
+    };
+    Some(text).filter(|t| !t.trim().is_empty())
+}
+
+/// 解析 ChatGPT 导出的一个对话：从 current_node 沿 parent 往上走，得到当前显示的那条分支
+fn parse_openai(conv: &Value) -> Option<ChatSession> {
+    let id = non_empty_str(&conv["conversation_id"]).or_else(|| non_empty_str(&conv["id"]))?;
+    let mapping = conv["mapping"].as_object()?;
+    let created_at = timestamp_ms(&conv["create_time"]).unwrap_or(0);
+
+    let mut path = Vec::new();
+    let mut node_id = non_empty_str(&conv["current_node"]);
+    while let Some(node) = node_id.and_then(|id| mapping.get(id)) {
+        path.push(node);
+        node_id = non_empty_str(&node["parent"]);
+        if path.len() > mapping.len() {
+            break; // 防御损坏文件里的环
+        }
+    }
+    path.reverse();
+
+    let mut model = non_empty_str(&conv["default_model_slug"]).map(str::to_string);
+    let mut last_ts = created_at;
+    let mut messages = Vec::new();
+    for node in path {
+        let msg = &node["message"];
+        let role = msg["author"]["role"].as_str().unwrap_or("");
+        if !matches!(role, "user" | "assistant") || msg["metadata"]["is_visually_hidden_from_conversation"] == true {
+            continue;
+        }
+        let Some(content) = openai_content(&msg["content"]) else { continue };
+        if role == "assistant" {
+            if let Some(slug) = non_empty_str(&msg["metadata"]["model_slug"]) {
+                model = Some(slug.to_string());
+            }
+        }
+        last_ts = timestamp_ms(&msg["create_time"]).unwrap_or(last_ts).max(last_ts);
+        let msg_id = non_empty_str(&msg["id"]).or_else(|| non_empty_str(&node["id"]));
+        let msg_id = msg_id.map(str::to_string).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        messages.push(message(msg_id, role, content, last_ts));
+    }
+
+    Some(ChatSession {
+        id: id.to_string(),
+        title: non_empty_str(&conv["title"]).unwrap_or("ChatGPT 对话").to_string(),
+        messages,
+        created_at,
+        updated_at: timestamp_ms(&conv["update_time"]).unwrap_or(last_ts),
+        provider: "openai".to_string(),
+        model: model.unwrap_or_else(|| "chatgpt".to_string()),
+        api_config_id: String::new(),
+        persona_id: None,
+    })
+}
+
+/// Claude 消息的文本：新版导出放在 content 数组里，旧版只有 text 字段
+fn anthropic_content(msg: &Value) -> Option<String> {
+    let parts: Vec<&str> = msg["content"]
+        .as_array()
+        .map(|blocks| {
+            blocks
+                .iter()
+                .filter(|b| b["type"] == "text")
+                .filter_map(|b| b["text"].as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    let text = if parts.is_empty() { msg["text"].as_str()?.to_string() } else { parts.join("\n\n") };
+    Some(text).filter(|t| !t.trim().is_empty())
+}
+
+/// 解析 Claude 导出的一个对话
+fn parse_anthropic(conv: &Value) -> Option<ChatSession> {
+    let id = non_empty_str(&conv["uuid"])?;
+    let created_at = timestamp_ms(&conv["created_at"]).unwrap_or(0);
+
+    let mut last_ts = created_at;
+    let mut messages = Vec::new();
+    for msg in conv["chat_messages"].as_array()? {
+        let role = match msg["sender"].as_str() {
+            Some("human") => "user",
+            Some("assistant") => "assistant",
+            _ => continue,
+        };
+        let Some(content) = anthropic_content(msg) else { continue };
+        last_ts = timestamp_ms(&msg["created_at"]).unwrap_or(last_ts).max(last_ts);
+        let msg_id = non_empty_str(&msg["uuid"]).map(str::to_string).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        messages.push(message(msg_id, role, content, last_ts));
+    }
+
+    Some(ChatSession {
+        id: id.to_string(),
+        title: non_empty_str(&conv["name"]).unwrap_or("Claude 对话").to_string(),
+        messages,
+        created_at,
+        updated_at: timestamp_ms(&conv["updated_at"]).unwrap_or(last_ts),
+        provider: "anthropic".to_string(),
+        model: non_empty_str(&conv["model"]).unwrap_or("claude").to_string(),
+        api_config_id: String::new(),
+        persona_id: None,
+    })
+}
+
+/// 解析 conversations.json：按第一个对话的字段判断是 ChatGPT 还是 Claude 的导出
+pub fn parse_conversations(json: &str) -> Result<Vec<ChatSession>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("文件不是有效的 JSON: {}", e))?;
+    let conversations = value.as_array().ok_or("不是 ChatGPT 或 Claude 导出的 conversations.json")?;
+    let parse: fn(&Value) -> Option<ChatSession> = match conversations.first() {
+        None => return Ok(vec![]),
+        Some(first) if first.get("mapping").is_some() => parse_openai,
+        Some(first) if first.get("chat_messages").is_some() => parse_anthropic,
+        Some(_) => return Err("不是 ChatGPT 或 Claude 导出的 conversations.json".to_string()),
+    };
+    Ok(conversations.iter().filter_map(parse).collect())
+}
+
+/// 读取导出文件：zip 包里取 conversations.json，否则按 JSON 文件读取
+fn read_export_file(file_path: &str) -> Result<String, String> {
+    let bytes = std::fs::read(file_path).map_err(|e| format!("读取文件失败: {}", e))?;
+    if !bytes.starts_with(b"PK") {
+        return String::from_utf8(bytes).map_err(|_| "文件不是 UTF-8 编码的 JSON".to_string());
+    }
+    let mut archive =
+        zip::ZipArchive::new(std::io::Cursor::new(bytes)).map_err(|e| format!("无法读取 zip 文件: {}", e))?;
+    let name = archive
+        .file_names()
+        .find(|name| name.rsplit('/').next() == Some("conversations.json"))
+        .map(str::to_string)
+        .ok_or("zip 里没有 conversations.json")?;
+    let mut json = String::new();
+    archive
+        .by_name(&name)
+        .map_err(|e| format!("无法读取 zip 文件: {}", e))?
+        .read_to_string(&mut json)
+        .map_err(|e| format!("无法读取 conversations.json: {}", e))?;
+    Ok(json)
+}
+
+/// 导入 ChatGPT / Claude 的导出文件（conversations.json 或整个导出 zip）
+#[tauri::command]
+pub async fn import_conversations(
+    state: tauri::State<'_, DbState>,
+    file_path: String,
+) -> Result<ImportReport, String> {
+    let sessions = tokio::task::spawn_blocking(move || read_export_file(&file_path).and_then(|json| parse_conversations(&json)))
+        .await
+        .map_err(|e| e.to_string())??;
+
+    state
+        .run(move |db| {
+            let mut report = ImportReport::default();
+            for session in sessions {
+                if session.messages.is_empty() {
+                    report.empty += 1;
+                } else if db.import_session(&session).map_err(|e| friendly_err("导入会话失败，请重试", e))? {
+                    report.imported += 1;
+                } else {
+                    report.duplicates += 1;
+                }
+            }
+            log::info!(
+                "Imported {} conversations ({} duplicates, {} empty)",
+                report.imported,
+                report.duplicates,
+                report.empty
+            );
+            Ok(report)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chatgpt_export_follows_the_current_branch() {
+        // 用户编辑过第一条提问：a1 -> (u1 旧分支, u2 当前分支)
+        let json = r#"[{
+            "id": "conv-1", "title": "旅行计划", "create_time": 1700000000.5, "update_time": 1700000100.0,
+            "current_node": "a2",
+            "mapping": {
+                "root": {"id": "root", "message": null, "parent": null, "children": ["sys"]},
+                "sys": {"id": "sys", "parent": "root", "message": {"id": "sys", "author": {"role": "system"},
+                    "content": {"content_type": "text", "parts": [""]}, "metadata": {"is_visually_hidden_from_conversation": true}}},
+                "u1": {"id": "u1", "parent": "sys", "message": {"id": "u1", "author": {"role": "user"}, "create_time": 1700000001,
+                    "content": {"content_type": "text", "parts": ["旧的提问"]}, "metadata": {}}},
+                "u2": {"id": "u2", "parent": "sys", "message": {"id": "u2", "author": {"role": "user"}, "create_time": 1700000002,
+                    "content": {"content_type": "multimodal_text", "parts": [{"asset_pointer": "file-1"}, "去京都玩三天"]}, "metadata": {}}},
+                "a2": {"id": "a2", "parent": "u2", "message": {"id": "a2", "author": {"role": "assistant"}, "create_time": 1700000003,
+                    "content": {"content_type": "text", "parts": ["第一天去伏见稻荷"]}, "metadata": {"model_slug": "gpt-4o"}}}
+            }
+        }]"#;
+        let sessions = parse_conversations(json).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let s = &sessions[0];
+        assert_eq!((s.id.as_str(), s.title.as_str(), s.provider.as_str(), s.model.as_str()), ("conv-1", "旅行计划", "openai", "gpt-4o"));
+        assert_eq!(s.created_at, 1_700_000_000_500);
+        let turns: Vec<_> = s.messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(turns, [("user", "去京都玩三天"), ("assistant", "第一天去伏见稻荷")]);
+        assert_eq!(s.messages[1].timestamp, 1_700_000_003_000);
+    }
+
+    #[test]
+    fn claude_export_maps_senders_and_content_blocks() {
+        let json = r#"[
+            {"uuid": "c-1", "name": "", "created_at": "2024-05-01T08:00:00.000000+00:00", "updated_at": "2024-05-01T09:00:00Z",
+             "chat_messages": [
+                {"uuid": "m1", "sender": "human", "text": "你好", "content": [], "created_at": "2024-05-01T08:00:01Z"},
+                {"uuid": "m2", "sender": "assistant", "text": "",
+                 "content": [{"type": "text", "text": "你好！"}, {"type": "tool_use", "name": "x"}, {"type": "text", "text": "有什么可以帮你？"}],
+                 "created_at": "2024-05-01T08:00:02Z"}
+             ]},
+            {"uuid": "c-2", "name": "空对话", "created_at": "2024-05-02T08:00:00Z", "chat_messages": []}
+        ]"#;
+        let sessions = parse_conversations(json).unwrap();
+        assert_eq!(sessions.len(), 2);
+        let s = &sessions[0];
+        assert_eq!((s.title.as_str(), s.provider.as_str()), ("Claude 对话", "anthropic"));
+        assert_eq!(s.messages[0].role, "user");
+        assert_eq!(s.messages[1].content, "你好！\n\n有什么可以帮你？");
+        assert_eq!(s.updated_at, 1_714_554_000_000);
+        assert!(sessions[1].messages.is_empty());
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        assert!(parse_conversations(r#"[{"foo": 1}]"#).is_err());
+        assert!(parse_conversations(r#"{"conversations": []}"#).is_err());
+        assert!(parse_conversations("[]").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 多密钥轮换模块
+ *
+ * 功能说明:
+ * - 一个 API 配置除主密钥外还可以在密钥链里存一组备用密钥（见 secure_storage）
+ * - 轮询（round_robin）：每次请求从上一次的下一个密钥开始，把额度摊到所有密钥上
+ * - 故障转移（failover）：总是先用主密钥，被限流（429）时才换下一个
+ * - 两种策略下，当前密钥 429 且还有没用过的密钥时都会立刻换下一个重发，
+ *   不在被限流的密钥上排队重试
+ *
+ * 启用密钥池时限流状态按"provider + 密钥尾号"分别记录，一个密钥被限流不会
+ * 让同一 provider 的其它密钥也跟着排队。轮询游标只在内存里，重启后从头开始。
+ */
+
+use crate::secure_storage::get_api_key_pool;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// 密钥选择策略
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRotation {
+    /// 依次轮流使用
+    #[default]
+    RoundRobin,
+    /// 先用主密钥，429 时再换
+    Failover,
+}
+
+/// 每个密钥池下一次轮询的起点
+static CURSORS: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 按策略排出本次请求尝试密钥的顺序
+fn order_keys(pool_id: &str, mut keys: Vec<String>, rotation: KeyRotation) -> Vec<String> {
+    if rotation == KeyRotation::RoundRobin && keys.len() > 1 {
+        let mut cursors = CURSORS.lock().unwrap_or_else(|e| e.into_inner());
+        let cursor = cursors.entry(pool_id.to_string()).or_insert(0);
+        let start = *cursor % keys.len();
+        *cursor = start + 1;
+        keys.rotate_left(start);
+    }
+    keys
+}
+
+/// 一次请求可用的密钥：取出一个用一个
+#[derive(Debug, Default)]
+pub(crate) struct KeyPool {
+    /// 是否配置了备用密钥（决定限流状态是否按密钥分开记录）
+    active: bool,
+    keys: VecDeque<String>,
+}
+
+impl KeyPool {
+    /// 主密钥加上密钥链里的备用密钥，按策略排好顺序；没有备用密钥时只含主密钥
+    pub fn load(pool_id: Option<&str>, primary: String, rotation: KeyRotation) -> Self {
+        let spare = match pool_id {
+            Some(id) => get_api_key_pool(id.to_string()).unwrap_or_else(|e| {
+                log::warn!("[LLM] failed to read API key pool {}: {}", id, e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let mut keys = vec![primary];
+        for key in spare {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        if keys.len() == 1 {
+            return Self { active: false, keys: keys.into() };
+        }
+        let keys = order_keys(pool_id.unwrap_or_default(), keys, rotation);
+        Self { active: true, keys: keys.into() }
+    }
+
+    /// 取出下一个要尝试的密钥
+    pub fn next_key(&mut self) -> Option<String> {
+        self.keys.pop_front()
+    }
+
+    /// 当前密钥之后是否还有没试过的密钥
+    pub fn has_spare(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// 放弃剩下的密钥（故障转移到别的 provider 后不再适用）
+    pub fn clear(&mut self) {
+        self.active = false;
+        self.keys.clear();
+    }
+
+    /// 某个密钥的限流状态记录在哪个键下；没启用密钥池时沿用 provider 级别
+    pub fn throttle_scope(&self, provider: &str, key: &str) -> Option<String> {
+        if !self.active {
+            return None;
+        }
+        let tail: String = key.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+        Some(format!("{} (…{})", provider, tail))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<String> {
+        vec!["k1".into(), "k2".into(), "k3".into()]
+    }
+
+    #[test]
+    fn round_robin_advances_start_key_and_failover_keeps_primary_first() {
+        let starts: Vec<String> = (0..4)
+            .map(|_| order_keys("test-rr", keys(), KeyRotation::RoundRobin).remove(0))
+            .collect();
+        assert_eq!(starts, ["k1", "k2", "k3", "k1"]);
+        assert_eq!(order_keys("test-rr", keys(), KeyRotation::RoundRobin), ["k2", "k3", "k1"]);
+
+        for _ in 0..2 {
+            assert_eq!(order_keys("test-fo", keys(), KeyRotation::Failover), ["k1", "k2", "k3"]);
+        }
+    }
+
+    #[test]
+    fn throttle_scope_only_splits_by_key_when_pool_is_active() {
+        let mut pool = KeyPool { active: true, keys: keys().into() };
+        assert_eq!(pool.throttle_scope("openai", "sk-abcdef"), Some("openai (…cdef)".to_string()));
+        assert_eq!(pool.next_key().as_deref(), Some("k1"));
+        assert!(pool.has_spare());
+        pool.clear();
+        assert!(!pool.has_spare());
+        assert_eq!(pool.throttle_scope("openai", "sk-abcdef"), None);
+    }
+}
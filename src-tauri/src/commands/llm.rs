@@ -13,22 +13,37 @@
  */
 
 use crate::commands::constants::{
-    DEFAULT_LLM_RETRY_COUNT, DEFAULT_LLM_RETRY_INTERVAL_SECS, LLM_CONNECT_TIMEOUT,
-    LLM_REQUEST_TIMEOUT, LLM_STREAM_READ_TIMEOUT,
+    DEFAULT_LLM_RETRY_COUNT, DEFAULT_LLM_RETRY_INTERVAL_SECS, LLM_STREAM_IDLE_TIMEOUT, LLM_STREAM_MAX_RESUMES,
+    PARTIAL_CHECKPOINT_INTERVAL,
 };
+use crate::commands::context_window::{context_window, fit_to_context, input_budget, ContextTruncatedEvent};
 use crate::commands::mcp::{get_all_mcp_tools, call_mcp_tool, MCPTool};
+use crate::commands::http_client::{shared_client, ClientKind};
+use crate::commands::key_rotation::{KeyPool, KeyRotation};
+use crate::commands::llm_debug::{self, DebugId};
+use crate::commands::rate_limit::{mark_throttled, parse_retry_after, throttle_remaining, wait_for_provider, ThrottleContext};
+use crate::commands::pricing::{current_month_start, estimate_cost, MessageUsage, UsageCostEvent};
 use crate::commands::skills::{read_skill_resource_text, Skill};
+use crate::commands::sse::SseDecoder;
+use crate::commands::stream_queue::{acquire_stream_slot, QueueContext};
+use crate::commands::summarizer::{apply_summary, pick_messages_to_summarize, spawn_summarizer, SummaryJob};
 use crate::db::DbState;
-use keyring::Entry as KeyringEntry;
+use crate::knowledge_base::commands::KbState;
+use crate::knowledge_base::compression::compress_chunks;
+use crate::knowledge_base::document::estimate_tokens;
+use crate::knowledge_base::rag::{citations_from, context_template_for, inject_context, last_user_query, retrieve_for_chat, Citation, RagSettings};
+use crate::knowledge_base::retrieval::ContextOptions;
+use crate::knowledge_base::scratch::has_session_files;
+use crate::knowledge_base::types::{QueryLlm, RetrievalMode, RetrievedChunk};
 use futures::StreamExt;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use thiserror::Error;
-use tauri::{AppHandle, Emitter};
+use tauri::{AppHandle, Emitter, Manager};
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
@@ -36,13 +51,66 @@ use uuid::Uuid;
 // ============ 类型定义 ============
 
 /// 图片附件 (base64 编码, 不含 data URL 前缀)
+///
+/// 也可以只给本地文件路径（截图问答时前端拿到的往往只是路径）：发送前由
+/// `resolve_image_paths` 读出文件填进 data/media_type，各 provider 的请求体
+/// 构造只认 base64 数据。
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ImageAttachment {
     /// 原始 base64 数据 (不含 "data:...;base64," 前缀)
+    #[serde(default)]
     pub data: String,
     /// MIME 类型, 如 "image/jpeg"
+    #[serde(default)]
     pub media_type: String,
+    /// 本地图片路径 (data 为空时使用)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// 单张图片附件的大小上限。各家 vision 接口对单图都有限制（Anthropic 5MB、
+/// OpenAI 20MB），超出的直接拒掉比发出去换一个 400 更好排查。
+const MAX_IMAGE_ATTACHMENT_BYTES: u64 = 20 * 1024 * 1024;
+
+/// 按扩展名推断图片 MIME 类型
+fn image_media_type_from_path(path: &str) -> Option<&'static str> {
+    let ext = std::path::Path::new(path).extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// 把只带路径的图片附件读成 base64。读失败/格式不支持直接报错，而不是悄悄
+/// 丢掉图片——否则模型会对着一段没有图的文字回答"我看不到图片"。
+async fn resolve_image_paths(messages: &mut [ChatMessage]) -> Result<(), LLMError> {
+    use base64::Engine;
+    for img in messages.iter_mut().flat_map(|m| m.images.iter_mut()) {
+        if !img.data.is_empty() {
+            continue;
+        }
+        let Some(path) = img.path.clone() else { continue };
+        let media_type = image_media_type_from_path(&path)
+            .ok_or_else(|| LLMError::ApiError(format!("Unsupported image format: {}", path)))?;
+        let meta = tokio::fs::metadata(&path)
+            .await
+            .map_err(|e| LLMError::ApiError(format!("Failed to read image {}: {}", path, e)))?;
+        if meta.len() > MAX_IMAGE_ATTACHMENT_BYTES {
+            return Err(LLMError::ApiError(format!("Image too large: {}", path)));
+        }
+        let bytes = tokio::fs::read(&path)
+            .await
+            .map_err(|e| LLMError::ApiError(format!("Failed to read image {}: {}", path, e)))?;
+        img.data = base64::engine::general_purpose::STANDARD.encode(bytes);
+        if img.media_type.is_empty() {
+            img.media_type = media_type.to_string();
+        }
+    }
+    Ok(())
 }
 
 /// 视频附件 (base64 编码, 不含 data URL 前缀, 仅 Gemini provider 支持)
@@ -72,6 +140,32 @@ pub struct ChatMessage {
     /// 视频附件 (仅 Gemini provider 有效, 其他 provider 忽略)
     #[serde(default)]
     pub videos: Vec<VideoAttachment>,
+    /// 生成这条回复时使用的随机种子（仅 assistant 消息），用同一个 seed
+    /// 和参数重新请求可以复现回复
+    #[serde(default)]
+    pub seed: Option<i64>,
+    /// 这条回复是怎么生成的（仅 assistant 消息），随消息入库，导出和统计时使用
+    #[serde(default)]
+    pub generation: Option<GenerationInfo>,
+}
+
+/// 一条回复的生成信息：实际应答的服务商和模型、结束原因、耗时和 token 用量
+///
+/// 前端在回复结束时从 stream-chunk / stream-usage / stream-metrics 事件里收集，
+/// 和消息一起保存；缺少的项为空（比如旧版本保存的消息、生成中途出错的回复）。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationInfo {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    /// 生成停止的原因：stop / length / content_filter / tool_calls
+    pub finish_reason: Option<String>,
+    /// 从发请求到整条回复结束的总耗时（毫秒），含工具调用
+    pub latency_ms: Option<i64>,
+    pub input_tokens: Option<i64>,
+    pub output_tokens: Option<i64>,
+    /// token 数是否为估算值（服务商没有在流里报告用量）
+    #[serde(default)]
+    pub tokens_estimated: bool,
 }
 
 /// 聊天会话结构
@@ -93,10 +187,108 @@ pub struct ChatSession {
     pub model: String,
     /// API 配置 ID
     pub api_config_id: String,
+    /// 绑定的角色预设 ID（只由 set_session_persona 修改，save_session 不覆盖）
+    #[serde(default)]
+    pub persona_id: Option<String>,
+}
+
+/// 会话列表项：只带元信息和最后一条消息的预览，不含消息正文。
+/// 消息按需通过 get_messages_cmd 分页读取，避免启动时把所有会话的消息都读进内存
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionOverview {
+    /// 会话 ID
+    pub id: String,
+    /// 会话标题
+    pub title: String,
+    /// 创建时间戳
+    pub created_at: i64,
+    /// 最后更新时间戳
+    pub updated_at: i64,
+    /// LLM 提供商
+    pub provider: String,
+    /// 模型名称
+    pub model: String,
+    /// API 配置 ID
+    pub api_config_id: String,
+    /// 绑定的角色预设 ID
+    #[serde(default)]
+    pub persona_id: Option<String>,
+    /// 消息条数
+    pub message_count: i64,
+    /// 最后一条消息的开头部分（截断后的纯文本）
+    pub last_message_preview: Option<String>,
+    /// 最后一条消息的时间戳 (毫秒)
+    pub last_message_at: Option<i64>,
+    /// 是否置顶（只由 set_session_pinned_cmd 修改，列表里排在最前）
+    #[serde(default)]
+    pub pinned: bool,
+    /// 是否已归档（手动归档或清理策略自动归档，不在历史记录主列表里显示）
+    #[serde(default)]
+    pub archived: bool,
+    /// 会话标签（只由 set_session_tags_cmd 修改），按名称排序
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// get_sessions_cmd 的筛选和排序条件，全部在 SQL 里完成；不填的条件不筛选
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionFilter {
+    /// 只要这个服务商的会话
+    pub provider: Option<String>,
+    /// 只要这个模型的会话
+    pub model: Option<String>,
+    /// 最后更新时间不早于这个时间点 (毫秒)
+    pub updated_from: Option<i64>,
+    /// 最后更新时间早于这个时间点 (毫秒)
+    pub updated_to: Option<i64>,
+    /// true 只要有出错回复的会话，false 只要没有的
+    pub has_errors: Option<bool>,
+    /// 只要带这个标签的会话
+    pub tag: Option<String>,
+    /// true 只要已归档的会话，false 只要没归档的
+    pub archived: Option<bool>,
+    pub sort: SessionSort,
+}
+
+/// 会话列表的排序方式；无论哪种，置顶的会话都排在最前
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionSort {
+    /// 最近更新的在前
+    #[default]
+    UpdatedDesc,
+    UpdatedAsc,
+    /// 最近创建的在前
+    CreatedDesc,
+    CreatedAsc,
+    /// 按标题
+    Title,
+    /// 消息多的在前
+    MessageCount,
+}
+
+/// 一个用过的会话标签
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionTagCount {
+    pub tag: String,
+    /// 带这个标签的会话数（不含回收站里的会话）
+    pub sessions: i64,
+}
+
+/// 一页消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessagePage {
+    /// 本页消息，按时间升序
+    pub messages: Vec<ChatMessage>,
+    /// 本页第一条消息在整个会话中的位置（从最早的消息算起，从 0 开始）
+    pub offset: usize,
+    /// 会话的消息总条数
+    pub total: usize,
 }
 
 /// 发送消息请求结构
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SendMessageRequest {
     /// 会话 ID
@@ -117,6 +309,22 @@ pub struct SendMessageRequest {
     pub api_key: String,
     /// API 基础 URL
     pub base_url: String,
+    /// 附加请求头 / query 参数鉴权（自建网关用）
+    #[serde(default)]
+    pub custom_auth: CustomAuth,
+    /// Gemini 安全过滤阈值（按类别），其它 provider 忽略
+    #[serde(default)]
+    pub safety_settings: Vec<SafetySetting>,
+    /// 备用密钥池在密钥链里的标识（前端传 API 配置 ID），None 表示只用 api_key
+    #[serde(default)]
+    pub key_pool: Option<String>,
+    /// 有备用密钥时的选择策略
+    #[serde(default)]
+    pub key_rotation: KeyRotation,
+    /// 指定使用该提供商的哪个密钥档案（见 secure_storage，如 work / personal）；
+    /// 不传时用该提供商选的默认档案；档案里有密钥时优先于 api_key
+    #[serde(default)]
+    pub api_key_profile: Option<String>,
     /// 是否启用 MCP
     pub enable_mcp: bool,
     /// 手动激活的 Skill ID 列表
@@ -128,15 +336,140 @@ pub struct SendMessageRequest {
     /// 是否启用思考模式 (Extended Thinking)
     #[serde(default)]
     pub enable_thinking: bool,
-    /// 最大输出 token 数（None 时使用默认值: 普通模式 4096, 思考模式 16000）
+    /// 最大输出 token 数（None 时大多数 provider 不传；Anthropic 按模型取默认上限）
     #[serde(default)]
     pub max_tokens: Option<u32>,
+    /// 思考 token 预算（None 时用 DEFAULT_THINKING_BUDGET）。只对使用 budget 的
+    /// 写法生效：Claude 3.7/4.5 的 budget_tokens、Gemini 2.5 的 thinkingBudget、
+    /// SiliconFlow 的 thinking_budget；adaptive thinking 没有预算可调
+    #[serde(default)]
+    pub thinking_budget: Option<u32>,
     /// 遇到限流/过载类错误时的自动重试次数（None 时用 DEFAULT_LLM_RETRY_COUNT）
     #[serde(default)]
     pub retry_count: Option<u32>,
     /// 每次重试之间的等待秒数（None 时用 DEFAULT_LLM_RETRY_INTERVAL_SECS）
     #[serde(default)]
     pub retry_interval_secs: Option<u32>,
+    /// 停止序列：模型生成到其中任意一个时停止（空列表表示不设置）
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// 频率惩罚 (-2.0 ~ 2.0)，Anthropic 不支持，会被忽略
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    /// 存在惩罚 (-2.0 ~ 2.0)，Anthropic 不支持，会被忽略
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// 故障转移链：主 provider 在开始输出前失败时，按顺序改用这些 provider
+    #[serde(default)]
+    pub fallbacks: Vec<FallbackProvider>,
+    /// 空闲看门狗秒数：流开始后超过这么久没有新数据就判定卡死
+    /// （None 时用 LLM_STREAM_IDLE_TIMEOUT，0 表示关闭看门狗）
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u32>,
+    /// 流卡死时是否自动带上已输出的部分内容重新请求、让模型接着写
+    #[serde(default)]
+    pub resume_on_stall: bool,
+    /// 上下文窗口 token 数（None 时按模型名查表，表里也没有时不裁剪），超出时自动裁掉较早的对话轮次
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    /// 是否启用滚动摘要：历史占到上下文预算一半以上时在后台把较早的几轮压缩成摘要，
+    /// 之后用摘要代替原文发送。摘要请求按同一模型计费，默认关闭
+    #[serde(default)]
+    pub summarize_history: bool,
+    /// 是否返回每个输出 token 的对数概率（OpenAI 兼容接口和 Gemini 支持，
+    /// Anthropic 没有这个能力，忽略）
+    #[serde(default)]
+    pub logprobs: bool,
+    /// 每个位置额外返回的候选 token 数（0~20），logprobs 关闭时忽略
+    #[serde(default)]
+    pub top_logprobs: Option<u32>,
+    /// 随机种子：相同 seed + 相同参数时服务商尽量给出相同的回复
+    /// （OpenAI 兼容接口和 Gemini 支持，Anthropic 忽略）
+    #[serde(default)]
+    pub seed: Option<i64>,
+    /// 前端占位 assistant 消息的 ID 和时间戳；设置后生成过程中定期把已输出
+    /// 的正文写进数据库（partial 状态），应用中途关闭时重启还能找回
+    #[serde(default)]
+    pub reply_message_id: Option<String>,
+    #[serde(default)]
+    pub reply_timestamp: Option<i64>,
+    /// 本轮要检索的知识库；非空时开始流式输出前先检索，把结果拼进最后一条用户消息
+    #[serde(default)]
+    pub kb_ids: Vec<String>,
+    /// 知识库检索参数（top_k、检索模式、reranker 等），不填用默认值
+    #[serde(default)]
+    pub rag_settings: RagSettings,
+    /// 检索用的问题；最后一条用户消息里拼了附件文档等内容时由前端传用户原话，
+    /// 不填就用最后一条用户消息
+    #[serde(default)]
+    pub rag_query: Option<String>,
+    /// 知识库已经检索过（多模型对比时由 arena 统一检索一次），run_stream 不再检索
+    #[serde(skip)]
+    pub rag_done: bool,
+}
+
+/// 故障转移链中的一个候选 provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FallbackProvider {
+    /// LLM 提供商
+    pub provider: String,
+    /// 模型名称
+    pub model: String,
+    /// API 基础 URL
+    #[serde(default)]
+    pub base_url: String,
+    /// API 密钥；为空时按 api_config_id（没有则按 provider）去 keyring 查
+    #[serde(default)]
+    pub api_key: String,
+    /// 对应的 API 配置 ID
+    #[serde(default)]
+    pub api_config_id: Option<String>,
+    /// 该 provider 的附加请求头 / query 参数鉴权
+    #[serde(default)]
+    pub custom_auth: CustomAuth,
+}
+
+/// 自建网关需要的附加请求头和鉴权方式（主要给 custom provider 用）
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomAuth {
+    /// 附加请求头（如 `api-key`、`X-Org`），同名时覆盖默认头；
+    /// 值里的 `{apiKey}` 会替换成本次请求的密钥
+    #[serde(default)]
+    pub headers: Vec<HeaderPair>,
+    /// 把密钥放进这个 query 参数（如 `key`、`api-key`）而不是 Authorization 头
+    #[serde(default)]
+    pub query_param: Option<String>,
+}
+
+/// 一个附加请求头
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderPair {
+    pub name: String,
+    pub value: String,
+}
+
+/// 实际应答的 provider（流开始前发出一次）。发生过故障转移时，
+/// failed_attempts 按顺序记录前面每个 provider 失败的原因。
+#[derive(Clone, Serialize)]
+pub struct StreamProviderEvent {
+    pub session_id: String,
+    pub message_id: String,
+    pub provider: String,
+    pub model: String,
+    pub failed_attempts: Vec<String>,
+}
+
+/// 流式回复卡死事件：看门狗判定超时后发出一次，resuming 表示是否正在自动续写
+#[derive(Clone, Serialize)]
+pub struct StreamStalledEvent {
+    pub session_id: String,
+    pub message_id: String,
+    pub idle_secs: u64,
+    pub resuming: bool,
+    /// 第几次自动续写（不续写时为 0）
+    pub attempt: u32,
 }
 
 /// 工具调用状态事件结构（前端据此展示"正在调用工具/工具调用结果"）
@@ -166,14 +499,129 @@ pub struct StreamChunk {
     pub session_id: String,
     /// 消息 ID
     pub message_id: String,
-    /// 增量内容
+    /// 正文增量（思考过程增量时为空串）
     pub content: String,
-    /// 是否为思考过程增量。思考型模型（DeepSeek R1 系、Ollama 上的 qwen3.5
-    /// 等）会把思考内容放在 reasoning_content/reasoning 字段流式返回，前端
-    /// 据此把这部分归到"思考过程"折叠区，而不是混进正文。
+    /// 思考过程增量。思考型模型（DeepSeek R1 系、o 系列兼容网关、Qwen 思考
+    /// 模式等）会把思考内容放在 reasoning_content/reasoning 字段，或者干脆用
+    /// `<think>` 标签夹在正文里流式返回；两种来源都统一归到这个字段，前端据此
+    /// 渲染可折叠的"思考过程"区，而不是混进正文。
+    pub reasoning: Option<String>,
+    /// 是否为思考过程增量，等价于 `reasoning.is_some()`，保留给只认这个标志
+    /// 的旧前端
     pub is_thinking: bool,
     /// 是否完成
     pub done: bool,
+    /// 流异常中止的原因（只出现在 done 块上），已输出的部分内容照常保留
+    pub error: Option<String>,
+    /// 生成停止的原因（只出现在 done 块上）：stop / length / content_filter /
+    /// tool_calls；服务商没有给出或经过了工具调用续写时为 None
+    pub finish_reason: Option<String>,
+}
+
+impl StreamChunk {
+    /// 正文增量
+    fn text(session_id: &str, message_id: &str, content: String) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            message_id: message_id.to_string(),
+            content,
+            reasoning: None,
+            is_thinking: false,
+            done: false,
+            error: None,
+            finish_reason: None,
+        }
+    }
+
+    /// 思考过程增量
+    fn reasoning(session_id: &str, message_id: &str, reasoning: String) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            message_id: message_id.to_string(),
+            content: String::new(),
+            reasoning: Some(reasoning),
+            is_thinking: true,
+            done: false,
+            error: None,
+            finish_reason: None,
+        }
+    }
+
+    /// 终止数据块
+    fn done(session_id: &str, message_id: &str) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            message_id: message_id.to_string(),
+            content: String::new(),
+            reasoning: None,
+            is_thinking: false,
+            done: true,
+            error: None,
+            finish_reason: None,
+        }
+    }
+
+    /// 带错误原因的终止数据块
+    pub(crate) fn failed(session_id: &str, message_id: &str, error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::done(session_id, message_id)
+        }
+    }
+}
+
+/// 从正文流里拆出 `<think>...</think>` 段落。Ollama/LM Studio 上的 R1 蒸馏
+/// 模型、部分 Qwen 思考模型不走 reasoning_content 字段，而是把思考过程用标签
+/// 夹在 content 里；标签本身可能被切在两个 SSE chunk 之间，所以疑似标签前缀
+/// 的尾巴要先扣下来，等下一段到了再判断。
+#[derive(Debug, Default)]
+struct ThinkTagSplitter {
+    in_think: bool,
+    pending: String,
+}
+
+impl ThinkTagSplitter {
+    const OPEN: &'static str = "<think>";
+    const CLOSE: &'static str = "</think>";
+
+    /// 喂入一段正文增量，返回 (是否思考内容, 文本) 片段列表
+    fn feed(&mut self, text: &str) -> Vec<(bool, String)> {
+        self.pending.push_str(text);
+        let mut out: Vec<(bool, String)> = Vec::new();
+        loop {
+            let tag = if self.in_think { Self::CLOSE } else { Self::OPEN };
+            if let Some(pos) = self.pending.find(tag) {
+                let before: String = self.pending[..pos].to_string();
+                self.pending = self.pending[pos + tag.len()..].to_string();
+                Self::push_segment(&mut out, self.in_think, before);
+                self.in_think = !self.in_think;
+                continue;
+            }
+            // 末尾可能是半个标签：找最长的、同时也是 tag 前缀的后缀，扣下不发
+            let keep = (1..tag.len())
+                .rev()
+                .find(|&n| self.pending.ends_with(&tag[..n]))
+                .unwrap_or(0);
+            let emit_len = self.pending.len() - keep;
+            let emit: String = self.pending[..emit_len].to_string();
+            self.pending = self.pending[emit_len..].to_string();
+            Self::push_segment(&mut out, self.in_think, emit);
+            return out;
+        }
+    }
+
+    /// 流结束时把扣下的尾巴原样吐出
+    fn finish(&mut self) -> Vec<(bool, String)> {
+        let mut out = Vec::new();
+        Self::push_segment(&mut out, self.in_think, std::mem::take(&mut self.pending));
+        out
+    }
+
+    fn push_segment(out: &mut Vec<(bool, String)>, thinking: bool, text: String) {
+        if !text.is_empty() {
+            out.push((thinking, text));
+        }
+    }
 }
 
 // 每个正在进行的流对应一个取消令牌，以 session_id 为键，
@@ -197,6 +645,9 @@ pub enum LLMError {
     /// 缺少 API 密钥
     #[error("Missing API key")]
     MissingApiKey,
+    /// 服务商限流（429）且重试已用完
+    #[error("API error: {0}")]
+    RateLimited(String),
     /// 流式响应错误
     #[error("Stream error: {0}")]
     StreamError(String),
@@ -245,7 +696,7 @@ const PROVIDER_CONFIGS: &[(&str, &str, &str)] = &[
     ("openclaw", "", "bearer"),
 ];
 
-fn build_url(provider: &str, base_url: &str, model: &str, streaming: bool) -> String {
+pub(crate) fn build_url(provider: &str, base_url: &str, model: &str, streaming: bool) -> String {
     match provider {
         "google" => {
             // Google 是通过路径来区分端点的，不像其他 provider 那样靠请求体里的
@@ -290,6 +741,48 @@ fn build_url(provider: &str, base_url: &str, model: &str, streaming: bool) -> St
     }
 }
 
+/// Anthropic 各模型的最大输出 token 数，按最长前缀匹配。Messages API 的
+/// max_tokens 是必填字段，而且超过模型上限会直接 400——Claude 3.x 只有
+/// 4096/8192，不能一律用同一个默认值。
+const ANTHROPIC_MAX_OUTPUT: &[(&str, u32)] = &[
+    ("claude-3-haiku", 4_096),
+    ("claude-3-sonnet", 4_096),
+    ("claude-3-opus", 4_096),
+    ("claude-3-5-haiku", 8_192),
+    ("claude-3-5-sonnet", 8_192),
+    ("claude-3-7-sonnet", 64_000),
+    ("claude-sonnet-4", 64_000),
+    ("claude-haiku-4", 64_000),
+    ("claude-opus-4", 32_000),
+    ("claude-opus-4-5", 64_000),
+];
+
+/// 用户没有填 max_tokens 时 Anthropic 请求用的默认值（再受模型上限约束）
+const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 32_000;
+
+/// legacy thinking 的默认 budget_tokens；max_tokens 必须比它大
+const DEFAULT_THINKING_BUDGET: u32 = 8_000;
+
+/// Anthropic 要求的最小 thinking budget
+const MIN_THINKING_BUDGET: u32 = 1_024;
+
+/// 计算 Anthropic 请求的 max_tokens：用户填了就用用户的值，没填按模型取
+/// 默认上限；legacy thinking（enabled + budget_tokens）要求上限超过 budget，
+/// 所以搭配它时不低于 9000。
+fn anthropic_max_tokens(model: &str, max_tokens: Option<u32>, legacy_thinking: bool) -> u32 {
+    let model_max = ANTHROPIC_MAX_OUTPUT
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, max)| *max)
+        .unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS);
+    match max_tokens {
+        Some(v) if legacy_thinking => v.max(DEFAULT_THINKING_BUDGET + 1_000),
+        Some(v) => v,
+        None => ANTHROPIC_DEFAULT_MAX_TOKENS.min(model_max),
+    }
+}
+
 fn build_stream_request_body(provider: &str, model: &str, messages: &[ChatMessage], tools: &[MCPTool], enable_thinking: bool, max_tokens: Option<u32>) -> serde_json::Value {
     // 一次流式请求如果在收到任何 token 之前就被停止了（见 `cancel_stream`），
     // 会留下一条内容为空、也没有附件的 assistant 消息。把这种消息原样传回去
@@ -343,22 +836,12 @@ fn build_stream_request_body(provider: &str, model: &str, messages: &[ChatMessag
                 .collect();
 
             // max_tokens 对 Anthropic 的 Messages API 是必填字段（不像这里其他
-            // provider 那样可以直接省略），所以哪怕用户没填，也得给一个具体数值。
-            // 32000 足够覆盖一段较长的回答，又不会超出模型输出 token 的上限被拒绝
-            // （注意：这个上限比 200K 的*上下文*窗口小得多——如果天真地直接拿
-            // 上下文大小当默认值，这里会直接 400）。
-            //
-            // 开启 thinking 时，这个上限必须超过它自己的 budget；具体到旧版
-            // budget_tokens 格式，上限必须超过 8000，所以只要用户值搭配了
-            // legacy thinking，就不能低于 9000。
+            // provider 那样可以直接省略），所以哪怕用户没填，也得给一个具体数值，
+            // 见 anthropic_max_tokens。注意这个上限比 200K 的*上下文*窗口小得多
+            // ——如果天真地直接拿上下文大小当默认值，这里会直接 400。
             let is_legacy_thinking = enable_thinking
                 && (model.contains("claude-3") || model.contains("4-5") || model.contains("4.5"));
-            let default_max: u32 = 32000;
-            let max_tokens_val = match max_tokens {
-                Some(v) if is_legacy_thinking => v.max(9000),
-                Some(v) => v,
-                None => default_max,
-            };
+            let max_tokens_val = anthropic_max_tokens(model, max_tokens, is_legacy_thinking);
             let mut body = serde_json::json!({
                 "model": model,
                 "messages": msgs,
@@ -374,7 +857,7 @@ fn build_stream_request_body(provider: &str, model: &str, messages: &[ChatMessag
                     || model.contains("4-5")
                     || model.contains("4.5");
                 if is_legacy_thinking {
-                    body["thinking"] = serde_json::json!({"type": "enabled", "budget_tokens": 8000});
+                    body["thinking"] = serde_json::json!({"type": "enabled", "budget_tokens": DEFAULT_THINKING_BUDGET});
                 } else {
                     body["thinking"] = serde_json::json!({"type": "adaptive"});
                 }
@@ -578,6 +1061,181 @@ pub async fn build_skill_context(skills: &[Skill], app_handle: &AppHandle) -> St
     parts.join("\n\n---\n\n")
 }
 
+/// 把一段额外的 system prompt 合并进消息历史：已有 system 消息时拼接到它的
+/// 前面（`prepend`）或后面，没有时插入一条新的 system 消息。空文本直接忽略。
+pub(crate) fn merge_system_prompt(messages: &mut Vec<ChatMessage>, text: &str, prepend: bool) {
+    if text.trim().is_empty() {
+        return;
+    }
+    if let Some(first) = messages.first_mut().filter(|m| m.role == "system") {
+        first.content = if prepend {
+            format!("{}\n\n{}", text, first.content)
+        } else {
+            format!("{}\n\n{}", first.content, text)
+        };
+    } else {
+        messages.insert(0, ChatMessage {
+            id: Uuid::new_v4().to_string(),
+            role: "system".to_string(),
+            content: text.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            error: None,
+            images: vec![],
+            videos: vec![],
+            seed: None,
+            generation: None,
+        });
+    }
+}
+
+/// 流卡死后续写用的消息列表：原对话 + 已输出的半截回复 + 一条让模型接着写
+/// 的指令。没有可见输出时（还没开始出字就卡住）直接用原对话重发。
+/// 不用 assistant 预填充，是因为只有少数服务商支持以 assistant 消息结尾的请求。
+fn build_resume_messages(messages: &[ChatMessage], partial_output: &str) -> Vec<ChatMessage> {
+    let mut resumed = messages.to_vec();
+    if partial_output.trim().is_empty() {
+        return resumed;
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    for (role, content) in [
+        ("assistant", partial_output.to_string()),
+        ("user", "你上一条回复在中途被截断了。请从截断处直接接着写，不要重复已经写出的内容，也不要添加任何说明。".to_string()),
+    ] {
+        resumed.push(ChatMessage {
+            id: Uuid::new_v4().to_string(),
+            role: role.to_string(),
+            content,
+            timestamp: now,
+            error: None,
+            images: vec![],
+            videos: vec![],
+            seed: None,
+            generation: None,
+        });
+    }
+    resumed
+}
+
+/// 空闲看门狗的计时：None 表示关闭，永不触发
+async fn idle_deadline(timeout: Option<Duration>) {
+    match timeout {
+        Some(d) => tokio::time::sleep(d).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 可选的采样参数。全部为空时请求体保持原样，由服务商使用默认值。
+#[derive(Debug, Clone, Default)]
+struct SamplingParams {
+    temperature: Option<f32>,
+    stop: Vec<String>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    logprobs: bool,
+    top_logprobs: Option<u32>,
+    seed: Option<i64>,
+    /// Gemini 的安全过滤阈值（严格说不是采样参数，但同样按 provider 写进请求体，
+    /// 工具调用后的续写请求也要带上）
+    safety_settings: Vec<SafetySetting>,
+}
+
+/// Gemini 某一类内容的安全过滤阈值
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SafetySetting {
+    /// 如 HARM_CATEGORY_HARASSMENT、HARM_CATEGORY_DANGEROUS_CONTENT
+    pub category: String,
+    /// 如 BLOCK_NONE、BLOCK_ONLY_HIGH、BLOCK_MEDIUM_AND_ABOVE、OFF
+    pub threshold: String,
+}
+
+/// OpenAI 的 top_logprobs 上限是 20，Gemini 是 20（部分模型更少），取两者都接受的值
+const MAX_TOP_LOGPROBS: u32 = 20;
+
+/// OpenAI 最多接受 4 个停止序列、Gemini 最多 5 个，超出会直接 400，截到
+/// 两者都能接受的数量。
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// 按 provider 的字段名和位置写入采样参数：
+/// - Anthropic：temperature + stop_sequences，没有频率/存在惩罚、logprobs 和 seed，直接忽略
+/// - Gemini：全部放进 generationConfig（stopSequences/frequencyPenalty/presencePenalty/
+///   responseLogprobs/logprobs/seed），安全阈值放在顶层 safetySettings
+/// - OpenAI 兼容：顶层的 temperature/stop/frequency_penalty/presence_penalty/
+///   logprobs/top_logprobs/seed
+fn apply_sampling_params(body: &mut serde_json::Value, provider: &str, params: &SamplingParams) {
+    let stop: Vec<&String> = params
+        .stop
+        .iter()
+        .filter(|s| !s.is_empty())
+        .take(MAX_STOP_SEQUENCES)
+        .collect();
+
+    match provider {
+        "anthropic" => {
+            if let Some(t) = params.temperature {
+                body["temperature"] = serde_json::json!(t);
+            }
+            if !stop.is_empty() {
+                body["stop_sequences"] = serde_json::json!(stop);
+            }
+        }
+        "google" => {
+            let config = &mut body["generationConfig"];
+            if let Some(t) = params.temperature {
+                config["temperature"] = serde_json::json!(t);
+            }
+            if !stop.is_empty() {
+                config["stopSequences"] = serde_json::json!(stop);
+            }
+            if let Some(v) = params.frequency_penalty {
+                config["frequencyPenalty"] = serde_json::json!(v);
+            }
+            if let Some(v) = params.presence_penalty {
+                config["presencePenalty"] = serde_json::json!(v);
+            }
+            if let Some(seed) = params.seed {
+                config["seed"] = serde_json::json!(seed);
+            }
+            if params.logprobs {
+                config["responseLogprobs"] = serde_json::json!(true);
+                if let Some(n) = params.top_logprobs {
+                    config["logprobs"] = serde_json::json!(n.min(MAX_TOP_LOGPROBS));
+                }
+            }
+            let safety: Vec<&SafetySetting> = params
+                .safety_settings
+                .iter()
+                .filter(|s| !s.category.is_empty() && !s.threshold.is_empty())
+                .collect();
+            if !safety.is_empty() {
+                body["safetySettings"] = serde_json::json!(safety);
+            }
+        }
+        _ => {
+            if let Some(t) = params.temperature {
+                body["temperature"] = serde_json::json!(t);
+            }
+            if !stop.is_empty() {
+                body["stop"] = serde_json::json!(stop);
+            }
+            if let Some(v) = params.frequency_penalty {
+                body["frequency_penalty"] = serde_json::json!(v);
+            }
+            if let Some(v) = params.presence_penalty {
+                body["presence_penalty"] = serde_json::json!(v);
+            }
+            if let Some(seed) = params.seed {
+                body["seed"] = serde_json::json!(seed);
+            }
+            if params.logprobs {
+                body["logprobs"] = serde_json::json!(true);
+                if let Some(n) = params.top_logprobs {
+                    body["top_logprobs"] = serde_json::json!(n.min(MAX_TOP_LOGPROBS));
+                }
+            }
+        }
+    }
+}
+
 /// 为模型可以自主调用的每个 skill 追加一条合成的工具定义。这个工具只携带
 /// name + description——调用它实际返回的是该 skill 的 instructions 作为结果
 /// （见 `finalize_turn` 里对 `skill__` 的处理），它本身从不对外发起任何调用。
@@ -666,46 +1324,24 @@ fn append_skill_tools(body: &mut serde_json::Value, provider: &str, autonomous_s
     }
 }
 
-/// 目标是否回环地址 (localhost/127.0.0.1/::1) —— 本地部署的模型服务
-/// (Ollama/LM Studio 等经由 "local"/"custom"/"openclaw" provider 走到这里)
-/// 走这条路径时应绕开系统代理，否则用户为访问境外服务商而开启的全局代理
-/// 会把本该直连本机的请求也绕出去一圈，白白拖慢 TTFT。
-fn is_loopback_url(url: &str) -> bool {
-    reqwest::Url::parse(url)
-        .ok()
-        .and_then(|u| u.host_str().map(|h| h.to_string()))
-        .map(|host| host == "localhost" || host == "127.0.0.1" || host == "::1" || host.starts_with("127."))
-        .unwrap_or(false)
-}
-
-fn create_http_client(url: &str) -> reqwest::Result<reqwest::Client> {
-    let mut builder = reqwest::Client::builder()
-        .timeout(LLM_REQUEST_TIMEOUT)
-        .connect_timeout(LLM_CONNECT_TIMEOUT);
-    if is_loopback_url(url) {
-        builder = builder.no_proxy();
-    }
-    builder.build()
+/// 非流式请求的客户端：总超时兜底，并按 provider 套用代理设置
+/// （回环地址一律直连，见 `proxy::is_loopback_url`）。取自共享连接池。
+pub(crate) fn create_http_client(provider: &str, url: &str) -> reqwest::Result<reqwest::Client> {
+    shared_client(ClientKind::Request, Some(provider), url)
 }
 
 /// 流式请求专用：`timeout()` 是含读完整个响应体的总时长，SSE 长回复会被
 /// 中途掐断（表现为 "Stream error: error decoding response body"），
 /// 因此这里只设读间隔超时，流只要还在吐数据就不会被断开。
-fn create_streaming_http_client(url: &str) -> reqwest::Result<reqwest::Client> {
-    let mut builder = reqwest::Client::builder()
-        .read_timeout(LLM_STREAM_READ_TIMEOUT)
-        .connect_timeout(LLM_CONNECT_TIMEOUT);
-    if is_loopback_url(url) {
-        builder = builder.no_proxy();
-    }
-    builder.build()
+pub(crate) fn create_streaming_http_client(provider: &str, url: &str) -> reqwest::Result<reqwest::Client> {
+    shared_client(ClientKind::Streaming, Some(provider), url)
 }
 
 /// 判断服务商返回的非 2xx 响应是不是"稍后重试大概率会成功"的临时性错误：
 /// 429/503/529（Anthropic 的过载码）本身就是明确信号；有些 provider 把过载/
 /// 限流塞进 200 以外的状态码但错误文本里带关键字（比如题述的
 /// `engine_overloaded_error`），一并按文本兜底识别。
-fn is_retryable_status(status: reqwest::StatusCode, body: &str) -> bool {
+pub(crate) fn is_retryable_status(status: reqwest::StatusCode, body: &str) -> bool {
     if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.as_u16() == 529 || status.is_server_error() {
         return true;
     }
@@ -713,7 +1349,7 @@ fn is_retryable_status(status: reqwest::StatusCode, body: &str) -> bool {
     lower.contains("overloaded") || lower.contains("rate_limit") || lower.contains("rate limit")
 }
 
-fn is_retryable_reqwest_error(e: &reqwest::Error) -> bool {
+pub(crate) fn is_retryable_reqwest_error(e: &reqwest::Error) -> bool {
     e.is_timeout() || e.is_connect()
 }
 
@@ -723,28 +1359,55 @@ fn is_retryable_reqwest_error(e: &reqwest::Error) -> bool {
 /// 内容之后的重试，否则会在前端产生重复/错乱的部分回复。
 async fn send_with_retry(
     request_builder: &reqwest::RequestBuilder,
+    provider: &str,
     retry_count: u32,
     retry_interval_secs: u32,
-    cancel_token: Option<&CancellationToken>,
+    throttle: Option<&ThrottleContext<'_>>,
 ) -> Result<reqwest::Response, LLMError> {
+    let cancel_token = throttle.and_then(|t| t.cancel_token);
+    let spare_keys = throttle.is_some_and(|t| t.spare_keys);
+    // 多密钥轮换时限流状态按密钥记录，否则按 provider
+    let provider = throttle.and_then(|t| t.key_scope.as_deref()).unwrap_or(provider);
+    let retry_interval = Duration::from_secs(retry_interval_secs as u64);
     let mut attempt = 0u32;
     loop {
+        // 同一 provider 刚被限流过（可能是别的会话触发的），先排队等限流解除
+        wait_for_provider(provider, throttle).await?;
+
         let builder = request_builder.try_clone().ok_or_else(|| {
             LLMError::ApiError("internal error: request body not clonable for retry".to_string())
         })?;
+        let mut wait = retry_interval;
         match builder.send().await {
             Ok(response) => {
                 if response.status().is_success() {
                     return Ok(response);
                 }
                 let status = response.status();
+                let retry_after = parse_retry_after(response.headers());
                 let error_text = response.text().await.unwrap_or_else(|_| "unknown".to_string());
+                // 服务商明确给了等待时间，或者直接 429：记到 provider 级别的限流状态里，
+                // 同一 provider 的其它请求也跟着排队，而不是各自撞一次 429
+                if status == reqwest::StatusCode::TOO_MANY_REQUESTS || retry_after.is_some() {
+                    wait = retry_after.unwrap_or_default().max(retry_interval);
+                    mark_throttled(provider, wait, &format!("{} {}", status, error_text));
+                }
+                let rate_limited = status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                if rate_limited && spare_keys {
+                    return Err(LLMError::RateLimited(format!("服务商限流（429）：{}", error_text)));
+                }
                 if attempt >= retry_count || !is_retryable_status(status, &error_text) {
+                    if rate_limited {
+                        return Err(LLMError::RateLimited(format!(
+                            "服务商限流（429），已重试 {} 次仍未成功，请稍后再试：{}",
+                            attempt, error_text
+                        )));
+                    }
                     return Err(LLMError::ApiError(error_text));
                 }
                 log::warn!(
-                    "LLM 请求被服务商拒绝，判定为可重试错误（状态码 {}，第 {}/{} 次重试）：{}",
-                    status, attempt + 1, retry_count, error_text
+                    "LLM 请求被服务商拒绝，判定为可重试错误（状态码 {}，第 {}/{} 次重试，{:.1} 秒后）：{}",
+                    status, attempt + 1, retry_count, wait.as_secs_f64(), error_text
                 );
             }
             Err(e) => {
@@ -758,22 +1421,26 @@ async fn send_with_retry(
             }
         }
         attempt += 1;
-        let wait = tokio::time::sleep(Duration::from_secs(retry_interval_secs as u64));
+        // 被限流时由下一轮开头的 wait_for_provider 负责等待（并通知前端）
+        if throttle_remaining(provider).is_some() {
+            continue;
+        }
+        let sleep = tokio::time::sleep(wait);
         match cancel_token {
             Some(token) => {
                 tokio::select! {
-                    _ = wait => {}
+                    _ = sleep => {}
                     _ = token.cancelled() => {
                         return Err(LLMError::StreamError("请求已取消".to_string()));
                     }
                 }
             }
-            None => wait.await,
+            None => sleep.await,
         }
     }
 }
 
-fn build_headers(provider: &str, api_key: &str) -> reqwest::header::HeaderMap {
+pub(crate) fn build_headers(provider: &str, api_key: &str, custom: Option<&CustomAuth>) -> reqwest::header::HeaderMap {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         reqwest::header::CONTENT_TYPE,
@@ -799,6 +1466,8 @@ fn build_headers(provider: &str, api_key: &str) -> reqwest::header::HeaderMap {
             // 本地模型（如 Ollama）不需要鉴权
             // 不用加 Authorization 头
         }
+        // 密钥走 query 参数时不再重复放进 Authorization 头
+        _ if custom.is_some_and(|c| c.query_param.as_deref().is_some_and(|p| !p.is_empty())) => {}
         _ => {
             headers.insert(
                 reqwest::header::AUTHORIZATION,
@@ -807,9 +1476,36 @@ fn build_headers(provider: &str, api_key: &str) -> reqwest::header::HeaderMap {
         }
     }
 
+    for pair in custom.map(|c| c.headers.as_slice()).unwrap_or_default() {
+        let value = pair.value.replace("{apiKey}", api_key);
+        match (
+            reqwest::header::HeaderName::from_bytes(pair.name.trim().as_bytes()),
+            reqwest::header::HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => log::warn!("[LLM] skipping invalid custom header '{}'", pair.name),
+        }
+    }
+
     headers
 }
 
+/// 配置了 query 参数鉴权时把密钥追加到 URL 上
+fn apply_query_auth(url: &str, api_key: &str, custom: &CustomAuth) -> String {
+    match custom.query_param.as_deref().map(str::trim).filter(|p| !p.is_empty()) {
+        Some(param) => match reqwest::Url::parse(url) {
+            Ok(mut parsed) => {
+                parsed.query_pairs_mut().append_pair(param, api_key);
+                parsed.to_string()
+            }
+            Err(_) => url.to_string(),
+        },
+        None => url.to_string(),
+    }
+}
+
 // 遮蔽密钥，只显示末尾 N 个字符
 fn mask_secret(s: &str, show_last: usize) -> String {
     if s.len() <= show_last {
@@ -829,14 +1525,8 @@ fn mask_auth_header_value(value: &str) -> String {
     }
 }
 
-// 解析一行 SSE，提取出内容或者工具调用
-fn parse_sse_line(provider: &str, line: &str) -> Option<StreamContent> {
-    if !line.starts_with("data: ") {
-        return None;
-    }
-
-    let data = &line[6..];
-    
+// 解析一个 SSE 事件的 data，提取出内容或者工具调用
+fn parse_sse_data(provider: &str, data: &str) -> Option<StreamContent> {
     if data == "[DONE]" {
         return Some(StreamContent::Done);
     }
@@ -1013,6 +1703,376 @@ enum StreamContent {
     Done,
 }
 
+/// 流式回复里除正文增量之外的收尾信息：结束原因和服务商报告的用量。
+/// 各家放的位置不同——OpenAI 在最后一个 choice 的 finish_reason 和
+/// （开了 include_usage 时）choices 为空的末尾 usage 块；Anthropic 在
+/// message_start/message_delta 事件；Gemini 每个块都带 finishReason/usageMetadata。
+#[derive(Debug, Default, PartialEq)]
+struct StreamMeta {
+    finish_reason: Option<String>,
+    input_tokens: Option<i64>,
+    output_tokens: Option<i64>,
+}
+
+/// 本轮已经流出的内容和收尾信息
+#[derive(Debug, Default)]
+struct StreamedOutput {
+    /// 正文+思考内容，服务商没报告用量时用来估算输出 token 数
+    text: String,
+    /// 只含正文，卡死续写时作为上下文交回给模型
+    visible: String,
+    /// 统一后的结束原因：stop / length / content_filter / tool_calls
+    finish_reason: Option<String>,
+    /// 服务商报告的输入 token 数
+    input_tokens: Option<i64>,
+    /// 服务商报告的输出 token 数
+    output_tokens: Option<i64>,
+    /// 开始发请求的时间（排队等并发名额的时间不算）
+    started_at: Option<Instant>,
+    /// 收到第一个输出增量（正文、思考或工具调用参数）的时间
+    first_token_at: Option<Instant>,
+}
+
+/// 单条回复的性能指标，紧挨着最后一个 `done: true` 数据块发出（stream-metrics）
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StreamMetricsEvent {
+    pub session_id: String,
+    pub message_id: String,
+    pub provider: String,
+    pub model: String,
+    /// 首个 token 延迟（毫秒）；一个字都没收到时为空
+    pub ttft_ms: Option<u64>,
+    /// 流式输出阶段的生成速度（token/秒），不含工具调用和续写
+    pub tokens_per_sec: Option<f64>,
+    /// 从发请求到整条回复结束的总耗时（毫秒），含工具调用
+    pub total_ms: u64,
+}
+
+impl StreamedOutput {
+    fn mark_first_token(&mut self) {
+        if self.first_token_at.is_none() {
+            self.first_token_at = Some(Instant::now());
+        }
+    }
+
+    /// 首 token 延迟和流式阶段的生成速度；`stream_end` 是流读完的时间，
+    /// `output_tokens` 是这段流输出的 token 数
+    fn speed(&self, stream_end: Instant, output_tokens: i64) -> (Option<u64>, Option<f64>) {
+        let ttft = self
+            .started_at
+            .zip(self.first_token_at)
+            .map(|(start, first)| first.saturating_duration_since(start).as_millis() as u64);
+        let generating = self.first_token_at.map(|first| stream_end.saturating_duration_since(first).as_secs_f64());
+        let tokens_per_sec = generating
+            .filter(|secs| *secs > 0.0 && output_tokens > 0)
+            .map(|secs| ((output_tokens as f64 / secs) * 10.0).round() / 10.0);
+        (ttft, tokens_per_sec)
+    }
+
+    fn merge_meta(&mut self, meta: StreamMeta) {
+        if meta.finish_reason.is_some() {
+            self.finish_reason = meta.finish_reason;
+        }
+        // Anthropic 的 message_delta 只带 output_tokens，不能把 message_start 里的输入数盖掉
+        self.input_tokens = meta.input_tokens.or(self.input_tokens);
+        self.output_tokens = meta.output_tokens.or(self.output_tokens);
+    }
+}
+
+/// 流式回复的落盘检查点：隔一段时间把已输出的正文写进 messages 表
+/// （partial 状态），前端收到 done 后整条保存时再清掉 partial。
+/// 工具调用之后续写的内容不落检查点，只由前端最后保存。
+struct ReplyCheckpoint {
+    message_id: String,
+    timestamp: i64,
+    seed: Option<i64>,
+    last_saved: Instant,
+    saved_len: usize,
+}
+
+impl ReplyCheckpoint {
+    /// 前端没有给出占位消息 ID（比如多模型对比）时不做检查点
+    fn new(request: &SendMessageRequest) -> Option<Self> {
+        Some(Self {
+            message_id: request.reply_message_id.clone()?,
+            timestamp: request.reply_timestamp.unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
+            seed: request.seed,
+            last_saved: Instant::now(),
+            saved_len: 0,
+        })
+    }
+
+    /// 距上次保存够久、并且正文有变化
+    fn due(&self, visible: &str, now: Instant) -> bool {
+        visible.len() != self.saved_len && now.saturating_duration_since(self.last_saved) >= PARTIAL_CHECKPOINT_INTERVAL
+    }
+
+    async fn save_if_due(&mut self, state: &DbState, session_id: &str, visible: &str) {
+        let now = Instant::now();
+        if !self.due(visible, now) {
+            return;
+        }
+        let (session_id, message_id, content) = (session_id.to_string(), self.message_id.clone(), visible.to_string());
+        let (timestamp, seed) = (self.timestamp, self.seed);
+        state
+            .run(move |db| {
+                if let Err(e) = db.checkpoint_partial_message(&session_id, &message_id, &content, timestamp, seed) {
+                    log::warn!("[LLM] failed to checkpoint partial reply {}: {}", message_id, e);
+                }
+            })
+            .await;
+        self.last_saved = now;
+        self.saved_len = visible.len();
+    }
+}
+
+/// 把各家的结束原因统一成 OpenAI 的叫法，前端只需要认一套
+fn normalize_finish_reason(raw: &str) -> String {
+    match raw {
+        "end_turn" | "stop_sequence" | "STOP" | "pause_turn" => "stop",
+        "max_tokens" | "MAX_TOKENS" | "model_length" => "length",
+        "tool_use" | "function_call" => "tool_calls",
+        "refusal" | "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" | "IMAGE_SAFETY" => "content_filter",
+        other => return other.to_lowercase(),
+    }
+    .to_string()
+}
+
+/// 从一个 SSE 事件里取出结束原因和用量（没有就返回 None）
+fn parse_stream_meta(provider: &str, data: &str) -> Option<StreamMeta> {
+    // 绝大多数事件只是正文增量，先做字符串预判，免得每个事件都再解析一遍 JSON
+    const MARKERS: &[&str] = &["finish_reason", "usage", "stop_reason", "finishReason", "usageMetadata"];
+    if !MARKERS.iter().any(|m| data.contains(m)) {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_str(data).ok()?;
+    let tokens = |v: &serde_json::Value, key: &str| v.get(key).and_then(|t| t.as_i64());
+
+    let meta = match provider {
+        "anthropic" => match json.get("type").and_then(|t| t.as_str()) {
+            Some("message_start") => {
+                let usage = json.get("message").and_then(|m| m.get("usage"));
+                StreamMeta {
+                    finish_reason: None,
+                    // 命中缓存的部分单独计数，加回来才是完整的输入量
+                    input_tokens: usage.and_then(|u| tokens(u, "input_tokens")).map(|n| {
+                        n + usage.and_then(|u| tokens(u, "cache_read_input_tokens")).unwrap_or(0)
+                            + usage.and_then(|u| tokens(u, "cache_creation_input_tokens")).unwrap_or(0)
+                    }),
+                    output_tokens: None,
+                }
+            }
+            Some("message_delta") => StreamMeta {
+                finish_reason: json
+                    .get("delta")
+                    .and_then(|d| d.get("stop_reason"))
+                    .and_then(|r| r.as_str())
+                    .map(normalize_finish_reason),
+                input_tokens: None,
+                output_tokens: json.get("usage").and_then(|u| tokens(u, "output_tokens")),
+            },
+            _ => return None,
+        },
+        "google" => {
+            let usage = json.get("usageMetadata");
+            StreamMeta {
+                finish_reason: json
+                    .get("candidates")
+                    .and_then(|c| c.get(0))
+                    .and_then(|c| c.get("finishReason"))
+                    .and_then(|r| r.as_str())
+                    .map(normalize_finish_reason),
+                input_tokens: usage.and_then(|u| tokens(u, "promptTokenCount")),
+                output_tokens: usage.and_then(|u| {
+                    tokens(u, "candidatesTokenCount").map(|n| n + tokens(u, "thoughtsTokenCount").unwrap_or(0))
+                }),
+            }
+        }
+        _ => {
+            let usage = json.get("usage").filter(|u| !u.is_null());
+            StreamMeta {
+                finish_reason: json
+                    .get("choices")
+                    .and_then(|c| c.get(0))
+                    .and_then(|c| c.get("finish_reason"))
+                    .and_then(|r| r.as_str())
+                    .map(normalize_finish_reason),
+                input_tokens: usage.and_then(|u| tokens(u, "prompt_tokens")),
+                output_tokens: usage.and_then(|u| tokens(u, "completion_tokens")),
+            }
+        }
+    };
+    (meta != StreamMeta::default()).then_some(meta)
+}
+
+/// 一个输出 token 的对数概率
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    /// 这个位置概率最高的几个候选（含实际输出的 token），按概率从高到低
+    pub top: Vec<TopLogprob>,
+}
+
+/// 某个位置的一个候选 token
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+}
+
+/// 请求了 logprobs 时随正文增量发出的事件，前端用来做置信度着色
+#[derive(Clone, Serialize)]
+pub struct StreamLogprobsEvent {
+    pub session_id: String,
+    pub message_id: String,
+    pub tokens: Vec<TokenLogprob>,
+}
+
+/// 从一个 SSE 事件里取出本次增量各 token 的对数概率
+///
+/// OpenAI 兼容接口放在 `choices[0].logprobs.content`；Gemini 放在
+/// `candidates[0].logprobsResult`，实际输出和候选分成 chosenCandidates /
+/// topCandidates 两个按位置对齐的数组。
+fn parse_stream_logprobs(provider: &str, data: &str) -> Option<Vec<TokenLogprob>> {
+    if !data.contains("logprob") && !data.contains("logProbability") {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_str(data).ok()?;
+    let entry = |v: &serde_json::Value, prob_key: &str| -> Option<TopLogprob> {
+        Some(TopLogprob {
+            token: v.get("token")?.as_str()?.to_string(),
+            logprob: v.get(prob_key)?.as_f64()?,
+        })
+    };
+
+    let tokens: Vec<TokenLogprob> = if provider == "google" {
+        let result = json.get("candidates")?.get(0)?.get("logprobsResult")?;
+        let top = result.get("topCandidates").and_then(|t| t.as_array());
+        result
+            .get("chosenCandidates")?
+            .as_array()?
+            .iter()
+            .enumerate()
+            .filter_map(|(i, chosen)| {
+                let chosen = entry(chosen, "logProbability")?;
+                let alternatives = top
+                    .and_then(|t| t.get(i))
+                    .and_then(|t| t.get("candidates"))
+                    .and_then(|c| c.as_array())
+                    .map(|c| c.iter().filter_map(|v| entry(v, "logProbability")).collect())
+                    .unwrap_or_default();
+                Some(TokenLogprob { token: chosen.token, logprob: chosen.logprob, top: alternatives })
+            })
+            .collect()
+    } else {
+        json.get("choices")?
+            .get(0)?
+            .get("logprobs")?
+            .get("content")?
+            .as_array()?
+            .iter()
+            .filter_map(|v| {
+                let chosen = entry(v, "logprob")?;
+                let alternatives = v
+                    .get("top_logprobs")
+                    .and_then(|t| t.as_array())
+                    .map(|t| t.iter().filter_map(|c| entry(c, "logprob")).collect())
+                    .unwrap_or_default();
+                Some(TokenLogprob { token: chosen.token, logprob: chosen.logprob, top: alternatives })
+            })
+            .collect()
+    };
+    (!tokens.is_empty()).then_some(tokens)
+}
+
+/// Gemini 因安全策略拦截时发出的事件：提示词被拦（promptFeedback.blockReason）
+/// 或者回复中途被拦（finishReason 为 SAFETY 等），附带触发拦截的类别
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StreamBlockedEvent {
+    pub session_id: String,
+    pub message_id: String,
+    /// "prompt" = 提示词被拦，"response" = 回复被拦
+    pub stage: String,
+    /// 服务商给出的原因，如 SAFETY、PROHIBITED_CONTENT、BLOCKLIST
+    pub reason: String,
+    /// 被判定为拦截的安全类别，如 HARM_CATEGORY_HARASSMENT
+    pub categories: Vec<String>,
+}
+
+/// 从 Gemini 的 SSE 事件里识别安全拦截，返回 (stage, reason, categories)
+fn parse_gemini_block(data: &str) -> Option<(&'static str, String, Vec<String>)> {
+    if !data.contains("blockReason") && !data.contains("finishReason") {
+        return None;
+    }
+    let json: serde_json::Value = serde_json::from_str(data).ok()?;
+    let blocked_categories = |ratings: Option<&serde_json::Value>| -> Vec<String> {
+        ratings
+            .and_then(|r| r.as_array())
+            .map(|r| {
+                r.iter()
+                    .filter(|r| r.get("blocked").and_then(|b| b.as_bool()).unwrap_or(false))
+                    .filter_map(|r| r.get("category").and_then(|c| c.as_str()).map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    if let Some(feedback) = json.get("promptFeedback") {
+        if let Some(reason) = feedback.get("blockReason").and_then(|r| r.as_str()) {
+            return Some(("prompt", reason.to_string(), blocked_categories(feedback.get("safetyRatings"))));
+        }
+    }
+    let candidate = json.get("candidates")?.get(0)?;
+    let reason = candidate.get("finishReason")?.as_str()?;
+    (normalize_finish_reason(reason) == "content_filter")
+        .then(|| ("response", reason.to_string(), blocked_categories(candidate.get("safetyRatings"))))
+}
+
+/// 把用户设置的思考预算写进已经带 thinking 配置的请求体；请求体里没有
+/// 对应字段（没开思考、或者是 adaptive thinking）时什么都不做
+fn apply_thinking_budget(body: &mut serde_json::Value, provider: &str, budget: Option<u32>) {
+    let Some(budget) = budget else {
+        return;
+    };
+    match provider {
+        "anthropic" => {
+            if body["thinking"]["type"] != "enabled" {
+                return;
+            }
+            let budget = budget.max(MIN_THINKING_BUDGET);
+            body["thinking"]["budget_tokens"] = serde_json::json!(budget);
+            // max_tokens 必须大于 budget_tokens，否则 400
+            let max_tokens = body["max_tokens"].as_u64().unwrap_or(0);
+            if max_tokens <= budget as u64 {
+                body["max_tokens"] = serde_json::json!(budget + 1_000);
+            }
+        }
+        "google" => {
+            let config = &mut body["generationConfig"]["thinkingConfig"];
+            if config.get("thinkingBudget").is_some() {
+                config["thinkingBudget"] = serde_json::json!(budget);
+            }
+        }
+        _ => {
+            if body.get("thinking_budget").is_some() {
+                body["thinking_budget"] = serde_json::json!(budget);
+            }
+        }
+    }
+}
+
+/// 支持 `stream_options.include_usage` 的 OpenAI 兼容 provider。其余的（Azure
+/// 旧 api-version、Mistral 等）会把未知字段当成错误拒绝，只能不带。
+const INCLUDE_USAGE_PROVIDERS: &[&str] = &["openai", "deepseek", "aliyun", "siliconflow", "moonshot", "doubao", "zhipu"];
+
+/// 让流式回复在末尾附带一个 usage 块
+fn apply_stream_options(body: &mut serde_json::Value, provider: &str) {
+    if INCLUDE_USAGE_PROVIDERS.contains(&provider) {
+        body["stream_options"] = serde_json::json!({ "include_usage": true });
+    }
+}
+
 /// 流式工具调用的一个片段，以 `index` 为键。`id`/`name` 只出现在某个 index
 /// 的第一个片段里；`arguments_fragment` 必须把同一个 index 下的所有片段
 /// 依次拼接起来。
@@ -1058,28 +2118,120 @@ pub async fn stream_message(
         request.session_id, request.provider, request.model,
         request.messages.len(), request.enable_mcp
     );
-    
-    let api_key = get_api_key(&request)?;
-    let message_id = Uuid::new_v4().to_string();
-    let session_id = request.session_id.clone();
 
-    // 创建一个取消令牌并注册，这样 `cancel_stream` 就能通知这个正在进行
-    // 的请求提前停止。
+    // 沿用前端占位回复的 ID，用量记录、工具调用状态才能和 save_message 存下的消息对上
+    let message_id = request.reply_message_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+    let cancel_token = register_stream(&request.session_id).await;
+    let _cleanup = unregister_stream_on_drop(request.session_id.clone());
+    run_stream(request, message_id, cancel_token, state, app_handle).await
+}
+
+/// 创建一个取消令牌并注册，这样 `cancel_stream` 就能通知这个会话正在进行
+/// 的请求提前停止。
+pub(crate) async fn register_stream(session_id: &str) -> CancellationToken {
     let cancel_token = CancellationToken::new();
-    {
-        let mut streams = ACTIVE_STREAMS.lock().await;
-        streams.insert(session_id.clone(), cancel_token.clone());
-    }
+    let mut streams = ACTIVE_STREAMS.lock().await;
+    streams.insert(session_id.to_string(), cancel_token.clone());
+    cancel_token
+}
 
-    // 无论函数从哪条路径返回，都要把这个令牌注销掉——用 spawn 是因为 Drop
-    // 里没法直接执行异步的加锁操作。
-    let _cleanup = scopeguard::guard(session_id.clone(), |sid| {
+/// 无论调用方从哪条路径返回，都要把令牌注销掉——用 spawn 是因为 Drop
+/// 里没法直接执行异步的加锁操作。
+pub(crate) fn unregister_stream_on_drop(session_id: String) -> scopeguard::ScopeGuard<String, impl FnOnce(String)> {
+    scopeguard::guard(session_id, |sid| {
         tauri::async_runtime::spawn(async move {
             let mut streams = ACTIVE_STREAMS.lock().await;
             streams.remove(&sid);
         });
+    })
+}
+
+/// 回复用到知识库内容时发出的引用事件，在流式输出开始之前发出，
+/// 前端按 message_id 挂到对应的回复上渲染脚注
+#[derive(Debug, Clone, Serialize)]
+pub struct CitationsEvent {
+    pub session_id: String,
+    pub message_id: String,
+    pub citations: Vec<Citation>,
+}
+
+/// 把检索命中的片段作为引用发给前端；没有命中时不发
+pub(crate) fn emit_citations(app_handle: &AppHandle, session_id: &str, message_id: &str, chunks: &[RetrievedChunk]) {
+    if chunks.is_empty() {
+        return;
+    }
+    let _ = app_handle.emit("citations", CitationsEvent {
+        session_id: session_id.to_string(),
+        message_id: message_id.to_string(),
+        citations: citations_from(chunks),
     });
-    
+}
+
+/// 按请求里的 kb_ids、会话绑定的知识库和会话的临时文件检索，把结果拼进 `messages`
+/// 的最后一条用户消息，返回命中的片段；没有可检索的内容或检索不到时返回空列表
+pub(crate) async fn retrieve_kb_context(
+    request: &SendMessageRequest,
+    messages: &mut [ChatMessage],
+    app_handle: &AppHandle,
+) -> Vec<RetrievedChunk> {
+    if request.rag_done {
+        return Vec::new();
+    }
+    let mut kb_ids = request.kb_ids.clone();
+    if let Some(db_state) = app_handle.try_state::<DbState>() {
+        let session_id = request.session_id.clone();
+        match db_state.run(move |db| db.get_session_kb_ids(&session_id).map_err(|e| e.to_string())).await {
+            Ok(bound) => kb_ids.extend(bound.into_iter().filter(|id| !request.kb_ids.contains(id))),
+            Err(e) => log::warn!("[RAG] failed to load knowledge bases bound to {}: {}", request.session_id, e),
+        }
+    }
+    if kb_ids.is_empty() && !has_session_files(&request.session_id).await {
+        return Vec::new();
+    }
+    let query = request.rag_query.clone().filter(|q| !q.trim().is_empty()).or_else(|| last_user_query(messages));
+    let (Some(kb_state), Some(query)) = (app_handle.try_state::<KbState>(), query) else {
+        return Vec::new();
+    };
+    let mut settings = request.rag_settings.clone();
+    if matches!(settings.retrieval_mode, RetrievalMode::Hyde) || settings.compress_context {
+        settings.query_llm = Some(QueryLlm {
+            provider: request.provider.clone(),
+            model: request.model.clone(),
+            base_url: request.base_url.clone(),
+            api_key: get_api_key(request).unwrap_or_default(),
+        });
+    }
+    let mut chunks = retrieve_for_chat(&kb_state, &kb_ids, &request.session_id, &query, &settings).await;
+    if let (true, Some(llm), false) = (settings.compress_context, &settings.query_llm, chunks.is_empty()) {
+        chunks = compress_chunks(llm, &query, chunks).await;
+    }
+    log::info!("[RAG] retrieved {} chunks from {} knowledge bases", chunks.len(), kb_ids.len());
+    let (template, language) = context_template_for(&kb_state, &kb_ids).await;
+    let options = ContextOptions {
+        template: template.as_deref(),
+        language,
+        token_budget: settings
+            .context_token_budget
+            .or_else(|| request.context_window.map(|window| input_budget(window, request.max_tokens) / 2)),
+        model: &request.model,
+    };
+    inject_context(messages, chunks, &options)
+}
+
+/// 执行一次完整的流式回复：构造上下文、发请求（含故障转移）、把增量以
+/// `message_id` 发给前端，直到结束、取消或出错。多模型对比时每个模型各跑一份，
+/// 共用同一个取消令牌。
+pub(crate) async fn run_stream(
+    mut request: SendMessageRequest,
+    message_id: String,
+    cancel_token: CancellationToken,
+    state: tauri::State<'_, DbState>,
+    app_handle: AppHandle,
+) -> Result<(), LLMError> {
+    let mut key_pool = KeyPool::load(request.key_pool.as_deref(), get_api_key(&request)?, request.key_rotation);
+    let api_key = key_pool.next_key().unwrap_or_default();
+    let session_id = request.session_id.clone();
+
     // 提前把所有已启用 MCP 服务器的工具都取出来——不管 `enable_mcp` 是什么值
     // 都需要，因为哪怕全局 MCP 开关是关的，手动激活的 Skill 仍然可能带上它
     // 自己绑定的服务器的工具进入对话。
@@ -1093,13 +2245,14 @@ pub async fn stream_message(
 
     // 加载 skill 列表，并拆分成"本轮手动激活的"和"已启用但交给模型自己
     // 判断要不要调用的"两组。
-    let all_skills = {
-        let db = state.0.lock().await;
-        db.get_skills().unwrap_or_else(|e| {
-            log::warn!("Failed to load skills: {}", e);
-            vec![]
+    let all_skills = state
+        .run(|db| {
+            db.get_skills().unwrap_or_else(|e| {
+                log::warn!("Failed to load skills: {}", e);
+                vec![]
+            })
         })
-    };
+        .await;
     let active_skills: Vec<Skill> = all_skills
         .iter()
         .filter(|s| s.enabled && request.active_skill_ids.contains(&s.id))
@@ -1115,42 +2268,421 @@ pub async fn stream_message(
         vec![]
     };
 
-    // 本轮实际暴露出去的工具：全局 MCP 集合（如果启用了）加上手动激活的
-    // skill 各自绑定的工具，去重后的结果。
-    let mut mcp_tools: Vec<MCPTool> = if request.enable_mcp { all_mcp_tools.clone() } else { vec![] };
-    for skill in &active_skills {
-        for tool in &all_mcp_tools {
-            if skill.bound_mcp_server_ids.contains(&tool.server_id)
-                && !mcp_tools.iter().any(|t| t.server_id == tool.server_id && t.name == tool.name)
-            {
-                mcp_tools.push(tool.clone());
-            }
-        }
-    }
+    // 本轮实际暴露出去的工具：全局 MCP 集合（如果启用了）加上手动激活的
+    // skill 各自绑定的工具，去重后的结果。
+    let mut mcp_tools: Vec<MCPTool> = if request.enable_mcp { all_mcp_tools.clone() } else { vec![] };
+    for skill in &active_skills {
+        for tool in &all_mcp_tools {
+            if skill.bound_mcp_server_ids.contains(&tool.server_id)
+                && !mcp_tools.iter().any(|t| t.server_id == tool.server_id && t.name == tool.name)
+            {
+                mcp_tools.push(tool.clone());
+            }
+        }
+    }
+
+    // 会话绑定的角色预设：system prompt 放在最前面，默认参数只补请求里没填的项
+    let persona_session_id = request.session_id.clone();
+    let persona = state
+        .run(move |db| {
+            db.get_session_persona(&persona_session_id).unwrap_or_else(|e| {
+                log::warn!("Failed to load session persona: {}", e);
+                None
+            })
+        })
+        .await;
+    if let Some(p) = &persona {
+        if request.max_tokens.is_none() {
+            request.max_tokens = p.max_tokens;
+        }
+    }
+
+    let mut effective_messages = request.messages.clone();
+    resolve_image_paths(&mut effective_messages).await?;
+    if let Some(p) = &persona {
+        merge_system_prompt(&mut effective_messages, &p.system_prompt, true);
+    }
+
+    // 把手动激活的 skill 的 instructions（加上可读资源文件的内容）作为一段
+    // system prompt 注入进去，是和已有的 system 消息合并，而不是替换掉它。
+    if !active_skills.is_empty() {
+        let skill_context = build_skill_context(&active_skills, &app_handle).await;
+        merge_system_prompt(&mut effective_messages, &skill_context, false);
+    }
+
+    // 知识库检索增强：检索结果拼进发给模型的那份用户消息，命中的片段记在这条回复上
+    let rag_chunks = retrieve_kb_context(&request, &mut effective_messages, &app_handle).await;
+    emit_citations(&app_handle, &session_id, &message_id, &rag_chunks);
+    if let (Some(reply_id), false) = (&request.reply_message_id, rag_chunks.is_empty()) {
+        let saved = match serde_json::to_string(&rag_chunks) {
+            Ok(json) => {
+                let (session_id, reply_id) = (session_id.clone(), reply_id.clone());
+                state
+                    .run(move |db| db.save_message_sources(&session_id, &reply_id, &json).map_err(|e| e.to_string()))
+                    .await
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = saved {
+            log::warn!("[RAG] failed to save sources for {}: {}", reply_id, e);
+        }
+    }
+
+    // 滚动摘要（开启了才做）：已经被置顶摘要覆盖的早期消息换成摘要正文；剩下的
+    // 历史如果又占到预算一半以上，等本轮请求发出去之后在后台把更早的几轮再压缩进去
+    let summary_session_id = request.session_id.clone();
+    let session_summary = if request.summarize_history {
+        state
+            .run(move |db| {
+                db.get_session_summary(&summary_session_id).unwrap_or_else(|e| {
+                    log::warn!("Failed to load session summary: {}", e);
+                    None
+                })
+            })
+            .await
+    } else {
+        None
+    };
+    if let Some(summary) = &session_summary {
+        apply_summary(&mut effective_messages, summary);
+    }
+
+    // 上下文窗口：估算超出时从最早的对话轮次开始裁，而不是等服务商报
+    // "context length exceeded"；裁了就告诉前端哪些消息没发出去。
+    // 窗口未知（没配置、表里也查不到）时不裁也不摘要，超限交给服务商报错
+    let window = request.context_window.or_else(|| context_window(&request.model));
+    let budget = window.map(|window| input_budget(window, request.max_tokens));
+    let to_summarize = budget
+        .filter(|_| request.summarize_history)
+        .and_then(|budget| pick_messages_to_summarize(&effective_messages, budget));
+    if let (Some(window), Some(budget)) = (window, budget) {
+        if let Some(t) = fit_to_context(&mut effective_messages, budget) {
+            log::warn!(
+                "[LLM] context window {} exceeded for session {}: dropped {} messages (~{} -> ~{} tokens)",
+                window, session_id, t.dropped, t.tokens_before, t.tokens_after
+            );
+            let _ = app_handle.emit("context-truncated", ContextTruncatedEvent {
+                session_id: request.session_id.clone(),
+                message_id: message_id.clone(),
+                dropped_messages: t.dropped,
+                estimated_tokens: t.tokens_before,
+                kept_tokens: t.tokens_after,
+                context_window: window,
+            });
+        }
+    }
+
+    let sampling = SamplingParams {
+        temperature: persona.as_ref().and_then(|p| p.temperature),
+        stop: request.stop.clone(),
+        frequency_penalty: request.frequency_penalty,
+        presence_penalty: request.presence_penalty,
+        logprobs: request.logprobs,
+        top_logprobs: request.top_logprobs,
+        seed: request.seed,
+        safety_settings: request.safety_settings.clone(),
+    };
+
+    // 限流排队时向前端报告、并响应取消
+    let mut throttle = ThrottleContext {
+        app_handle: &app_handle,
+        session_id: &session_id,
+        cancel_token: Some(&cancel_token),
+        key_scope: key_pool.throttle_scope(&request.provider, &api_key),
+        spare_keys: key_pool.has_spare(),
+    };
+
+    // 同一 provider 同时进行的回复数有上限，超出时在这里排队；名额一直占到
+    // 本次回复结束（函数返回时 drop）
+    let _stream_slot = acquire_stream_slot(&request.provider, &QueueContext {
+        app_handle: &app_handle,
+        session_id: &session_id,
+        message_id: &message_id,
+        cancel_token: &cancel_token,
+    })
+    .await?;
+
+    let request_started = Instant::now();
+
+    // 故障转移：主 provider 在开始流式输出之前就失败（重试耗尽、鉴权失败、
+    // 连接超时），依次换到 fallbacks 里的下一个。一旦拿到了响应就不再切换——
+    // 流到一半换 provider 会让前端收到两段拼不上的回复。切换时把 request 的
+    // provider/model/base_url/api_key 原地改掉，后面的工具调用续写、用量记录
+    // 自然就都跟着实际应答的 provider 走。
+    let mut fallbacks = std::mem::take(&mut request.fallbacks).into_iter();
+    let mut failures: Vec<String> = Vec::new();
+    let mut api_key = api_key;
+    let response = loop {
+        match open_stream(&request, &api_key, &effective_messages, &mcp_tools, &autonomous_skills, &sampling, &throttle).await {
+            Ok(r) => break r,
+            Err(e) => {
+                // 当前密钥被限流，先换同一配置的下一个密钥，用完了再考虑故障转移
+                if matches!(e, LLMError::RateLimited(_)) && !cancel_token.is_cancelled() {
+                    if let Some(key) = key_pool.next_key() {
+                        log::warn!("[LLM] API key for {} rate limited, rotating to next key", request.provider);
+                        throttle.key_scope = key_pool.throttle_scope(&request.provider, &key);
+                        throttle.spare_keys = key_pool.has_spare();
+                        api_key = key;
+                        continue;
+                    }
+                }
+                if cancel_token.is_cancelled() {
+                    return Err(e);
+                }
+                let failed = format!("{}/{}", request.provider, request.model);
+                failures.push(format!("{}: {}", failed, e));
+                let Some(key) = switch_to_next_fallback(&mut request, &mut fallbacks, &mut failures, resolve_fallback_api_key) else {
+                    return Err(e);
+                };
+                key_pool.clear();
+                throttle.key_scope = None;
+                throttle.spare_keys = false;
+                log::warn!(
+                    "[LLM] provider {} failed before streaming, falling back to {} ({}): {}",
+                    failed, request.provider, request.model, e
+                );
+                api_key = key;
+            }
+        }
+    };
+    // 续写请求直接读 request.api_key，这里统一换成实际生效的密钥（可能来自 keyring）
+    request.api_key = api_key;
+
+    if let Some(messages) = to_summarize {
+        spawn_summarizer(app_handle.clone(), SummaryJob {
+            session_id: request.session_id.clone(),
+            provider: request.provider.clone(),
+            model: request.model.clone(),
+            base_url: request.base_url.clone(),
+            api_key: request.api_key.clone(),
+            custom_auth: request.custom_auth.clone(),
+            previous: session_summary,
+            messages,
+        });
+    }
+
+    let _ = app_handle.emit("stream-provider", StreamProviderEvent {
+        session_id: request.session_id.clone(),
+        message_id: message_id.clone(),
+        provider: request.provider.clone(),
+        model: request.model.clone(),
+        failed_attempts: failures,
+    });
+
+    let mut debug_id = response.extensions().get::<DebugId>().copied();
+    let mut stream = response.bytes_stream();
+    let mut sse = SseDecoder::default();
+    let mut tool_call_acc: std::collections::BTreeMap<u32, PartialToolCall> = std::collections::BTreeMap::new();
+    let mut streamed = StreamedOutput { started_at: Some(request_started), ..Default::default() };
+    let mut think_splitter = ThinkTagSplitter::default();
+    let idle_timeout = match request.idle_timeout_secs {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs as u64)),
+        None => Some(LLM_STREAM_IDLE_TIMEOUT),
+    };
+    let mut resumes = 0u32;
+    let mut checkpoint = ReplyCheckpoint::new(&request);
+
+    // 主循环
+    loop {
+        tokio::select! {
+            // 检查取消信号
+            _ = cancel_token.cancelled() => {
+                log::info!("Stream cancelled for session: {}", session_id);
+                // 取消前已经生成的部分服务商照样计费
+                let input_tokens = estimate_messages_tokens(&effective_messages);
+                record_usage(&app_handle, &state, &request, &message_id, input_tokens, estimate_tokens(&streamed.text) as i64, true).await;
+                let _ = app_handle.emit("stream-chunk", StreamChunk::done(&request.session_id, &message_id));
+                return Ok(());
+            }
+            // 空闲看门狗：每收到一个数据块，select 重新开始一轮，计时也就跟着重置
+            _ = idle_deadline(idle_timeout) => {
+                let idle_secs = idle_timeout.map(|d| d.as_secs()).unwrap_or_default();
+                log::warn!(
+                    "[LLM] stream idle for {}s, session={} provider={} model={}",
+                    idle_secs, session_id, request.provider, request.model
+                );
+                // 已经在累积工具调用时不续写：半截的工具参数没法让模型接着补
+                let can_resume = request.resume_on_stall && resumes < LLM_STREAM_MAX_RESUMES && tool_call_acc.is_empty();
+                if can_resume {
+                    resumes += 1;
+                    let _ = app_handle.emit("stream-stalled", StreamStalledEvent {
+                        session_id: request.session_id.clone(),
+                        message_id: message_id.clone(),
+                        idle_secs,
+                        resuming: true,
+                        attempt: resumes,
+                    });
+                    let resume_messages = build_resume_messages(&effective_messages, &streamed.visible);
+                    match open_stream(&request, &request.api_key, &resume_messages, &mcp_tools, &autonomous_skills, &sampling, &throttle).await {
+                        Ok(r) => {
+                            log::info!("[LLM] resumed stalled stream (attempt {}) for session {}", resumes, session_id);
+                            debug_id = r.extensions().get::<DebugId>().copied();
+                            stream = r.bytes_stream();
+                            // 卡住时没收完的半个事件丢掉，它的内容没有发给前端，续写会补上
+                            sse = SseDecoder::default();
+                            continue;
+                        }
+                        Err(e) => log::warn!("[LLM] failed to resume stalled stream: {}", e),
+                    }
+                } else {
+                    let _ = app_handle.emit("stream-stalled", StreamStalledEvent {
+                        session_id: request.session_id.clone(),
+                        message_id: message_id.clone(),
+                        idle_secs,
+                        resuming: false,
+                        attempt: 0,
+                    });
+                }
+                for (thinking, segment) in think_splitter.finish() {
+                    emit_delta(&app_handle, &request.session_id, &message_id, thinking, segment);
+                }
+                let input_tokens = estimate_messages_tokens(&effective_messages);
+                record_usage(&app_handle, &state, &request, &message_id, input_tokens, estimate_tokens(&streamed.text) as i64, true).await;
+                let _ = app_handle.emit("stream-chunk", StreamChunk::failed(
+                    &request.session_id,
+                    &message_id,
+                    format!("服务商超过 {} 秒没有返回新内容，回复已中止", idle_secs),
+                ));
+                return Ok(());
+            }
+            // 从流里读取下一个数据块
+            chunk = stream.next() => {
+                let (events, stream_ended) = match chunk {
+                    Some(Ok(chunk)) => (sse.feed(&chunk), false),
+                    Some(Err(e)) => {
+                        return Err(LLMError::StreamError(e.to_string()));
+                    }
+                    // 流结束：最后一个没以空行收尾的事件也要取出来
+                    None => (sse.finish(), true),
+                };
+
+                for event in events {
+                    if let Some(id) = debug_id {
+                        llm_debug::record_sse(id, event.event.as_deref(), &event.data);
+                    }
+                    if let Some(meta) = parse_stream_meta(&request.provider, &event.data) {
+                        streamed.merge_meta(meta);
+                    }
+                    if request.provider == "google" {
+                        if let Some((stage, reason, categories)) = parse_gemini_block(&event.data) {
+                            log::warn!("[LLM] Gemini blocked the {} ({}): {:?}", stage, reason, categories);
+                            let _ = app_handle.emit("stream-blocked", StreamBlockedEvent {
+                                session_id: request.session_id.clone(),
+                                message_id: message_id.clone(),
+                                stage: stage.to_string(),
+                                reason,
+                                categories,
+                            });
+                        }
+                    }
+                    if request.logprobs {
+                        if let Some(tokens) = parse_stream_logprobs(&request.provider, &event.data) {
+                            let _ = app_handle.emit("stream-logprobs", StreamLogprobsEvent {
+                                session_id: request.session_id.clone(),
+                                message_id: message_id.clone(),
+                                tokens,
+                            });
+                        }
+                    }
+                    if let Some(content) = parse_sse_data(&request.provider, &event.data) {
+                        if !matches!(content, StreamContent::Done) {
+                            streamed.mark_first_token();
+                        }
+                        match content {
+                            StreamContent::Text(text) => {
+                                streamed.text.push_str(&text);
+                                for (thinking, segment) in think_splitter.feed(&text) {
+                                    if !thinking {
+                                        streamed.visible.push_str(&segment);
+                                    }
+                                    emit_delta(&app_handle, &request.session_id, &message_id, thinking, segment);
+                                }
+                            }
+                            StreamContent::Thinking(text) => {
+                                streamed.text.push_str(&text);
+                                emit_delta(&app_handle, &request.session_id, &message_id, true, text);
+                            }
+                            StreamContent::ToolCallDeltas(deltas) => {
+                                for delta in deltas {
+                                    let entry = tool_call_acc.entry(delta.index).or_default();
+                                    if let Some(id) = delta.id {
+                                        entry.id = Some(id);
+                                    }
+                                    if let Some(name) = delta.name {
+                                        entry.name = Some(name);
+                                    }
+                                    if let Some(fragment) = delta.arguments_fragment {
+                                        entry.arguments.push_str(&fragment);
+                                    }
+                                }
+                            }
+                            StreamContent::Done => {
+                                for (thinking, segment) in think_splitter.finish() {
+                                    emit_delta(&app_handle, &request.session_id, &message_id, thinking, segment);
+                                }
+                                return finalize_turn(
+                                    &app_handle,
+                                    state.clone(),
+                                    &request,
+                                    &message_id,
+                                    &effective_messages,
+                                    &mcp_tools,
+                                    &all_skills,
+                                    std::mem::take(&mut tool_call_acc),
+                                    request.max_tokens,
+                                    &sampling,
+                                    &streamed,
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                }
+
+                if let Some(checkpoint) = checkpoint.as_mut() {
+                    checkpoint.save_if_due(&state, &request.session_id, &streamed.visible).await;
+                }
 
-    // 把手动激活的 skill 的 instructions（加上可读资源文件的内容）作为一段
-    // system prompt 注入进去，是和已有的 system 消息合并，而不是替换掉它。
-    let mut effective_messages = request.messages.clone();
-    if !active_skills.is_empty() {
-        let skill_context = build_skill_context(&active_skills, &app_handle).await;
-        if !skill_context.is_empty() {
-            if !effective_messages.is_empty() && effective_messages[0].role == "system" {
-                effective_messages[0].content =
-                    format!("{}\n\n{}", effective_messages[0].content, skill_context);
-            } else {
-                effective_messages.insert(0, ChatMessage {
-                    id: Uuid::new_v4().to_string(),
-                    role: "system".to_string(),
-                    content: skill_context,
-                    timestamp: chrono::Utc::now().timestamp_millis(),
-                    error: None,
-                    images: vec![],
-                    videos: vec![],
-                });
+                if stream_ended {
+                    // 流结束了，但没有收到明确的"本轮结束"信号
+                    // （Google 从来不发这个信号）——按照收到明确的
+                    // `StreamContent::Done` 时同样的方式，把目前累积到的
+                    // 工具调用做收尾处理。
+                    for (thinking, segment) in think_splitter.finish() {
+                        emit_delta(&app_handle, &request.session_id, &message_id, thinking, segment);
+                    }
+                    return finalize_turn(
+                        &app_handle,
+                        state.clone(),
+                        &request,
+                        &message_id,
+                        &effective_messages,
+                        &mcp_tools,
+                        &all_skills,
+                        std::mem::take(&mut tool_call_acc),
+                        request.max_tokens,
+                        &sampling,
+                        &streamed,
+                    )
+                    .await;
+                }
             }
         }
     }
+}
 
+/// 按 request 当前的 provider 构造并发出流式请求，返回已经确认成功的响应。
+/// 故障转移时对每个候选 provider 各调用一次。
+async fn open_stream(
+    request: &SendMessageRequest,
+    api_key: &str,
+    effective_messages: &[ChatMessage],
+    mcp_tools: &[MCPTool],
+    autonomous_skills: &[Skill],
+    sampling: &SamplingParams,
+    throttle: &ThrottleContext<'_>,
+) -> Result<reqwest::Response, LLMError> {
     let url = build_url(&request.provider, &request.base_url, &request.model, true);
     // 记录 provider/base/model 便于调试（不要记录 API key）
     log::debug!(
@@ -1170,12 +2702,16 @@ pub async fn stream_message(
         return Err(LLMError::ApiError("Invalid target URL".to_string()));
     }
 
-    let client = create_streaming_http_client(&url)?;
-    let mut body = build_stream_request_body(&request.provider, &request.model, &effective_messages, &mcp_tools, request.enable_thinking, request.max_tokens);
-    append_skill_tools(&mut body, &request.provider, &autonomous_skills);
-    let headers = build_headers(&request.provider, &api_key);
+    let client = create_streaming_http_client(&request.provider, &url)?;
+    let mut body = build_stream_request_body(&request.provider, &request.model, effective_messages, mcp_tools, request.enable_thinking, request.max_tokens);
+    append_skill_tools(&mut body, &request.provider, autonomous_skills);
+    apply_sampling_params(&mut body, &request.provider, sampling);
+    apply_stream_options(&mut body, &request.provider);
+    apply_thinking_budget(&mut body, &request.provider, request.thinking_budget);
+    let headers = build_headers(&request.provider, api_key, Some(&request.custom_auth));
 
     log::debug!("Constructed URL for provider {}: {}", request.provider, url);
+    let url = apply_query_auth(&url, api_key, &request.custom_auth);
 
     let masked_auth = if let Some(h) = headers.get(reqwest::header::AUTHORIZATION) {
         match h.to_str() {
@@ -1196,124 +2732,21 @@ pub async fn stream_message(
     let retry_count = request.retry_count.unwrap_or(DEFAULT_LLM_RETRY_COUNT);
     let retry_interval_secs = request.retry_interval_secs.unwrap_or(DEFAULT_LLM_RETRY_INTERVAL_SECS);
     let request_builder = client.post(&url).headers(headers.clone()).json(&body);
-    let response = match send_with_retry(&request_builder, retry_count, retry_interval_secs, Some(&cancel_token)).await {
-        Ok(r) => r,
+    let debug_id = llm_debug::record_request(&request.provider, &request.model, &url, &headers, &body);
+    match send_with_retry(&request_builder, &request.provider, retry_count, retry_interval_secs, Some(throttle)).await {
+        Ok(mut r) => {
+            if let Some(id) = debug_id {
+                llm_debug::record_result(id, Some(r.status().as_u16()), None);
+                r.extensions_mut().insert(id);
+            }
+            Ok(r)
+        }
         Err(e) => {
             log::error!("LLM request failed for url '{}': {:?}", url, e);
-            return Err(e);
-        }
-    };
-
-    let mut stream = response.bytes_stream();
-    let mut buffer = String::new();
-    let mut tool_call_acc: std::collections::BTreeMap<u32, PartialToolCall> = std::collections::BTreeMap::new();
-
-    // 主循环
-    loop {
-        tokio::select! {
-            // 检查取消信号
-            _ = cancel_token.cancelled() => {
-                log::info!("Stream cancelled for session: {}", session_id);
-                let _ = app_handle.emit("stream-chunk", StreamChunk {
-                    session_id: request.session_id.clone(),
-                    message_id: message_id.clone(),
-                    content: String::new(),
-                    is_thinking: false,
-                    done: true,
-                });
-                return Ok(());
-            }
-            // 从流里读取下一个数据块
-            chunk = stream.next() => {
-                match chunk {
-                    Some(Ok(chunk)) => {
-                        let text = String::from_utf8_lossy(&chunk);
-                        buffer.push_str(&text);
-
-                        // 处理已经完整的行
-                        while let Some(pos) = buffer.find('\n') {
-                            let line = buffer[..pos].trim().to_string();
-                            buffer = buffer[pos + 1..].to_string();
-
-                            if line.is_empty() {
-                                continue;
-                            }
-
-                            if let Some(content) = parse_sse_line(&request.provider, &line) {
-                                match content {
-                                    StreamContent::Text(text) => {
-                                        let _ = app_handle.emit("stream-chunk", StreamChunk {
-                                            session_id: request.session_id.clone(),
-                                            message_id: message_id.clone(),
-                                            content: text,
-                                            is_thinking: false,
-                                            done: false,
-                                        });
-                                    }
-                                    StreamContent::Thinking(text) => {
-                                        let _ = app_handle.emit("stream-chunk", StreamChunk {
-                                            session_id: request.session_id.clone(),
-                                            message_id: message_id.clone(),
-                                            content: text,
-                                            is_thinking: true,
-                                            done: false,
-                                        });
-                                    }
-                                    StreamContent::ToolCallDeltas(deltas) => {
-                                        for delta in deltas {
-                                            let entry = tool_call_acc.entry(delta.index).or_default();
-                                            if let Some(id) = delta.id {
-                                                entry.id = Some(id);
-                                            }
-                                            if let Some(name) = delta.name {
-                                                entry.name = Some(name);
-                                            }
-                                            if let Some(fragment) = delta.arguments_fragment {
-                                                entry.arguments.push_str(&fragment);
-                                            }
-                                        }
-                                    }
-                                    StreamContent::Done => {
-                                        return finalize_turn(
-                                            &app_handle,
-                                            state.clone(),
-                                            &request,
-                                            &message_id,
-                                            &effective_messages,
-                                            &mcp_tools,
-                                            &all_skills,
-                                            std::mem::take(&mut tool_call_acc),
-                                            request.max_tokens,
-                                        )
-                                        .await;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Some(Err(e)) => {
-                        return Err(LLMError::StreamError(e.to_string()));
-                    }
-                    None => {
-                        // 流结束了，但没有收到明确的"本轮结束"信号
-                        // （Google 从来不发这个信号）——按照收到明确的
-                        // `StreamContent::Done` 时同样的方式，把目前累积到的
-                        // 工具调用做收尾处理。
-                        return finalize_turn(
-                            &app_handle,
-                            state.clone(),
-                            &request,
-                            &message_id,
-                            &effective_messages,
-                            &mcp_tools,
-                            &all_skills,
-                            std::mem::take(&mut tool_call_acc),
-                            request.max_tokens,
-                        )
-                        .await;
-                    }
-                }
+            if let Some(id) = debug_id {
+                llm_debug::record_result(id, None, Some(e.to_string()));
             }
+            Err(e)
         }
     }
 }
@@ -1408,7 +2841,18 @@ async fn finalize_turn(
     all_skills: &[Skill],
     tool_call_acc: std::collections::BTreeMap<u32, PartialToolCall>,
     max_tokens: Option<u32>,
+    sampling: &SamplingParams,
+    streamed: &StreamedOutput,
 ) -> Result<(), LLMError> {
+    // 用量：首个流式请求优先用服务商报告的数字；续写请求重发的完整历史和
+    // 工具结果只能估算
+    let history_tokens = estimate_messages_tokens(effective_messages);
+    let mut estimated = streamed.input_tokens.is_none() || streamed.output_tokens.is_none();
+    let mut input_tokens = streamed.input_tokens.unwrap_or(history_tokens);
+    let mut output_tokens = streamed.output_tokens.unwrap_or_else(|| estimate_tokens(&streamed.text) as i64);
+    let (ttft_ms, tokens_per_sec) = streamed.speed(Instant::now(), output_tokens);
+    let mut finish_reason = streamed.finish_reason.clone();
+
     let tool_calls: Vec<ToolCall> = tool_call_acc
         .into_values()
         .filter_map(|p| {
@@ -1428,12 +2872,23 @@ async fn finalize_turn(
         // 请求都把模型自己的工具重新带上，直到它返回纯文本，或者达到轮次
         // 上限为止——没有这个上限的话，一个行为异常的模型可能会无限循环下去。
         const MAX_TOOL_ROUNDS: usize = 5;
+        estimated = true;
+        // 续写请求是非流式的，拿不到它的结束原因
+        finish_reason = None;
         let mut rounds: Vec<(Vec<ToolCall>, Vec<serde_json::Value>)> = Vec::new();
         let mut current_calls = tool_calls;
 
         for round in 0..MAX_TOOL_ROUNDS {
             let tool_results = execute_tool_calls(app_handle, state.clone(), &request.session_id, message_id, &current_calls, mcp_tools, all_skills).await;
             rounds.push((current_calls, tool_results));
+            input_tokens += history_tokens
+                + rounds
+                    .iter()
+                    .flat_map(|(calls, results)| {
+                        calls.iter().map(|c| estimate_tokens(&c.function.arguments) as i64)
+                            .chain(results.iter().map(|r| estimate_tokens(&r.to_string()) as i64))
+                    })
+                    .sum::<i64>();
 
             match continue_after_tool_calls(
                 &request.provider,
@@ -1445,28 +2900,25 @@ async fn finalize_turn(
                 mcp_tools,
                 all_skills,
                 max_tokens,
+                sampling,
                 request.retry_count.unwrap_or(DEFAULT_LLM_RETRY_COUNT),
                 request.retry_interval_secs.unwrap_or(DEFAULT_LLM_RETRY_INTERVAL_SECS),
+                &request.custom_auth,
             )
             .await
             {
                 Ok(ContinuationResult::Text { text, thinking }) => {
+                    output_tokens += estimate_tokens(&text) as i64
+                        + thinking.as_deref().map(|t| estimate_tokens(t) as i64).unwrap_or(0);
                     if let Some(th) = thinking.filter(|t| !t.is_empty()) {
-                        let _ = app_handle.emit("stream-chunk", StreamChunk {
-                            session_id: request.session_id.clone(),
-                            message_id: message_id.to_string(),
-                            content: th,
-                            is_thinking: true,
-                            done: false,
-                        });
+                        emit_delta(app_handle, &request.session_id, message_id, true, th);
+                    }
+                    let mut splitter = ThinkTagSplitter::default();
+                    let mut segments = splitter.feed(&text);
+                    segments.extend(splitter.finish());
+                    for (thinking, segment) in segments {
+                        emit_delta(app_handle, &request.session_id, message_id, thinking, segment);
                     }
-                    let _ = app_handle.emit("stream-chunk", StreamChunk {
-                        session_id: request.session_id.clone(),
-                        message_id: message_id.to_string(),
-                        content: text,
-                        is_thinking: false,
-                        done: false,
-                    });
                     break;
                 }
                 Ok(ContinuationResult::ToolCalls(next_calls)) => {
@@ -1486,16 +2938,88 @@ async fn finalize_turn(
     }
 
     log::info!("[LLM] stream_message 完成: session={}", request.session_id);
-    let _ = app_handle.emit("stream-chunk", StreamChunk {
+    record_usage(app_handle, &state, request, message_id, input_tokens, output_tokens, estimated).await;
+    let _ = app_handle.emit("stream-metrics", StreamMetricsEvent {
         session_id: request.session_id.clone(),
         message_id: message_id.to_string(),
-        content: String::new(),
-        is_thinking: false,
-        done: true,
+        provider: request.provider.clone(),
+        model: request.model.clone(),
+        ttft_ms,
+        tokens_per_sec,
+        total_ms: streamed.started_at.map(|t| t.elapsed().as_millis() as u64).unwrap_or_default(),
+    });
+    let _ = app_handle.emit("stream-chunk", StreamChunk {
+        finish_reason,
+        ..StreamChunk::done(&request.session_id, message_id)
     });
     Ok(())
 }
 
+/// 发出一个正文或思考过程增量
+fn emit_delta(app_handle: &AppHandle, session_id: &str, message_id: &str, thinking: bool, text: String) {
+    let chunk = if thinking {
+        StreamChunk::reasoning(session_id, message_id, text)
+    } else {
+        StreamChunk::text(session_id, message_id, text)
+    };
+    let _ = app_handle.emit("stream-chunk", chunk);
+}
+
+/// 粗略估算一组消息的输入 token 数（与知识库分块同一口径）。服务商没有
+/// 在流里报告 usage 时，费用只能按这个估算值计算。
+fn estimate_messages_tokens(messages: &[ChatMessage]) -> i64 {
+    messages.iter().map(|m| estimate_tokens(&m.content) as i64).sum()
+}
+
+/// 记录本条回复的用量和估算费用，并发出 `stream-usage` 事件（紧挨着最后一个
+/// `done: true` 数据块）。写库失败只记日志——费用统计不能影响回复本身。
+async fn record_usage(
+    app_handle: &AppHandle,
+    state: &tauri::State<'_, DbState>,
+    request: &SendMessageRequest,
+    message_id: &str,
+    input_tokens: i64,
+    output_tokens: i64,
+    estimated: bool,
+) {
+    let cost_usd = estimate_cost(&request.provider, &request.model, input_tokens, output_tokens);
+    let usage = MessageUsage {
+        message_id: message_id.to_string(),
+        session_id: request.session_id.clone(),
+        provider: request.provider.clone(),
+        model: request.model.clone(),
+        input_tokens,
+        output_tokens,
+        cost_usd,
+        created_at: chrono::Utc::now().timestamp_millis(),
+    };
+
+    let (session_cost_usd, monthly_cost_usd) = state
+        .run(move |db| {
+            if let Err(e) = db.record_message_usage(&usage) {
+                log::warn!("Failed to record message usage: {}", e);
+            }
+            let session_cost = db
+                .get_session_usage(&usage.session_id)
+                .map(|(_, _, cost, _)| cost)
+                .unwrap_or(0.0);
+            let monthly_cost = db.get_usage_cost_since(current_month_start().0).unwrap_or(0.0);
+            (session_cost, monthly_cost)
+        })
+        .await;
+
+    let _ = app_handle.emit("stream-usage", UsageCostEvent {
+        session_id: request.session_id.clone(),
+        message_id: message_id.to_string(),
+        input_tokens,
+        output_tokens,
+        cost_usd,
+        session_cost_usd,
+        monthly_cost_usd,
+        estimated,
+    });
+}
+
 /// 一次工具调用续写请求可能得到的两种结果：模型已经完成，给出了文本回复
 /// （思考型模型可能附带甚至只有思考内容——qwen3.5 经 Ollama 续写时会把回答
 /// 埋进 reasoning、content 留空，这时思考内容是仅有的可展示产出）；或者它
@@ -1522,11 +3046,13 @@ async fn continue_after_tool_calls(
     mcp_tools: &[MCPTool],
     autonomous_skills: &[Skill],
     max_tokens: Option<u32>,
+    sampling: &SamplingParams,
     retry_count: u32,
     retry_interval_secs: u32,
+    custom_auth: &CustomAuth,
 ) -> Result<ContinuationResult, LLMError> {
     let url = build_url(provider, base_url, model, false);
-    let client = create_http_client(&url)?;
+    let client = create_http_client(provider, &url)?;
 
     // 和 `build_stream_request_body` 一样的"空消息"防护：一条因为流在收到
     // 任何 token 之前就被取消而变成空内容的消息，同样不能在这里被当作历史
@@ -1542,16 +3068,9 @@ async fn continue_after_tool_calls(
     let mut body = match provider {
         "anthropic" => {
             let system_msg = original_messages.iter().find(|m| m.role == "system").map(|m| m.content.clone());
-            let mut msgs: Vec<serde_json::Value> = original_messages
-                .iter()
-                .filter(|m| m.role != "system")
-                .map(|m| {
-                    serde_json::json!({
-                        "role": if m.role == "assistant" { "assistant" } else { "user" },
-                        "content": m.content
-                    })
-                })
-                .collect();
+            // 用 build_native_messages 而不是只取 content：用户发的截图必须跟着
+            // 进续写请求，否则模型调完工具回来就"看不到"图了。
+            let mut msgs = build_native_messages(provider, original_messages);
 
             // Anthropic 要求 tool_use/tool_result 块必须每轮恰好打包成一条
             // assistant 消息和一条 user 消息（它强制要求 user/assistant 严格
@@ -1588,7 +3107,7 @@ async fn continue_after_tool_calls(
             // 和 build_stream_request_body 一样的原因：Anthropic 强制要求这个
             // 字段，所以用户没填的话就退回到一个足够宽裕的默认值，而不是一个
             // 会截断长回复的数字。
-            let max_tokens_val = max_tokens.unwrap_or(32000);
+            let max_tokens_val = anthropic_max_tokens(model, max_tokens, false);
             let mut b = serde_json::json!({
                 "model": model,
                 "messages": msgs,
@@ -1612,16 +3131,7 @@ async fn continue_after_tool_calls(
         }
         "google" => {
             let system_msg = original_messages.iter().find(|m| m.role == "system").map(|m| m.content.clone());
-            let mut contents: Vec<serde_json::Value> = original_messages
-                .iter()
-                .filter(|m| m.role != "system")
-                .map(|m| {
-                    serde_json::json!({
-                        "role": if m.role == "assistant" { "model" } else { "user" },
-                        "parts": [{ "text": m.content }]
-                    })
-                })
-                .collect();
+            let mut contents = build_native_messages(provider, original_messages);
 
             for (tool_calls, tool_results) in rounds {
                 let call_parts: Vec<_> = tool_calls
@@ -1679,10 +3189,7 @@ async fn continue_after_tool_calls(
             b
         }
         _ => {
-            let mut msgs: Vec<serde_json::Value> = original_messages
-                .iter()
-                .map(|m| serde_json::json!({ "role": m.role, "content": m.content }))
-                .collect();
+            let mut msgs = build_native_messages(provider, original_messages);
 
             for (tool_calls, tool_results) in rounds {
                 let tool_calls_json: Vec<_> = tool_calls
@@ -1739,10 +3246,12 @@ async fn continue_after_tool_calls(
         }
     };
     append_skill_tools(&mut body, provider, autonomous_skills);
+    apply_sampling_params(&mut body, provider, sampling);
 
-    let headers = build_headers(provider, api_key);
+    let headers = build_headers(provider, api_key, Some(custom_auth));
 
     log::debug!("Constructed URL for provider {} (tool-call continuation): {}", provider, url);
+    let url = apply_query_auth(&url, api_key, custom_auth);
 
     let masked_auth = if let Some(h) = headers.get(reqwest::header::AUTHORIZATION) {
         match h.to_str() {
@@ -1760,7 +3269,7 @@ async fn continue_after_tool_calls(
     log::debug!("Tool-call continuation auth header (masked): {}", masked_auth);
 
     let request_builder = client.post(&url).headers(headers).json(&body);
-    let response = match send_with_retry(&request_builder, retry_count, retry_interval_secs, None).await {
+    let response = match send_with_retry(&request_builder, provider, retry_count, retry_interval_secs, None).await {
         Ok(r) => r,
         Err(e) => {
             log::error!("LLM request failed (tool-call continuation) for url '{}': {:?}", url, e);
@@ -1931,7 +3440,7 @@ pub fn build_native_messages(provider: &str, messages: &[ChatMessage]) -> Vec<se
             .filter(|m| m.role != "system")
             .map(|m| {
                 let role = if m.role == "assistant" { "model" } else { "user" };
-                if role == "user" && !m.images.is_empty() {
+                if role == "user" && (!m.images.is_empty() || !m.videos.is_empty()) {
                     let mut parts: Vec<serde_json::Value> = vec![];
                     if !m.content.is_empty() {
                         parts.push(serde_json::json!({"text": m.content}));
@@ -1941,6 +3450,11 @@ pub fn build_native_messages(provider: &str, messages: &[ChatMessage]) -> Vec<se
                             "inline_data": {"mime_type": img.media_type, "data": img.data}
                         }));
                     }
+                    for vid in &m.videos {
+                        parts.push(serde_json::json!({
+                            "inline_data": {"mime_type": vid.media_type, "data": vid.data}
+                        }));
+                    }
                     serde_json::json!({ "role": "user", "parts": parts })
                 } else {
                     serde_json::json!({ "role": role, "parts": [{ "text": m.content }] })
@@ -1958,9 +3472,15 @@ pub fn build_native_messages(provider: &str, messages: &[ChatMessage]) -> Vec<se
                     }
                     for img in &m.images {
                         let data_uri = format!("data:{};base64,{}", img.media_type, img.data);
+                        // Mistral 的 image_url 是裸字符串，不是 {"url": ...} 对象
+                        let image_url = if provider == "mistral" {
+                            serde_json::json!(data_uri)
+                        } else {
+                            serde_json::json!({"url": data_uri})
+                        };
                         content.push(serde_json::json!({
                             "type": "image_url",
-                            "image_url": {"url": data_uri}
+                            "image_url": image_url
                         }));
                     }
                     serde_json::json!({ "role": m.role, "content": content })
@@ -2075,14 +3595,54 @@ pub async fn run_turn(
     max_tokens: Option<u32>,
     enable_thinking: bool,
 ) -> Result<TurnOutcome, LLMError> {
+    run_turn_with_usage(
+        provider,
+        model,
+        api_key,
+        base_url,
+        &CustomAuth::default(),
+        system_prompt,
+        native_messages,
+        tools,
+        max_tokens,
+        enable_thinking,
+    )
+    .await
+    .map(|(outcome, _)| outcome)
+}
+
+/// 一次非流式请求里服务商报告的 token 用量，没有报告的项为 None
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TurnUsage {
+    pub input_tokens: Option<i64>,
+    pub output_tokens: Option<i64>,
+}
+
+/// 带附加鉴权（请求头 / query 参数）的 `run_turn`，同时返回服务商报告的用量——
+/// 后台摘要这类用户看不到、但同样计费的调用靠它记进用量统计。
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_turn_with_usage(
+    provider: &str,
+    model: &str,
+    api_key: &str,
+    base_url: &str,
+    custom_auth: &CustomAuth,
+    system_prompt: Option<&str>,
+    native_messages: &[serde_json::Value],
+    tools: &[MCPTool],
+    max_tokens: Option<u32>,
+    enable_thinking: bool,
+) -> Result<(TurnOutcome, TurnUsage), LLMError> {
     let url = build_url(provider, base_url, model, false);
-    let client = create_http_client(&url)?;
+    let client = create_http_client(provider, &url)?;
     let body = build_run_turn_body(provider, model, system_prompt, native_messages, tools, max_tokens, enable_thinking);
 
-    let headers = build_headers(provider, api_key);
+    let headers = build_headers(provider, api_key, Some(custom_auth));
+    let url = apply_query_auth(&url, api_key, custom_auth);
     let request_builder = client.post(&url).headers(headers).json(&body);
     let response = send_with_retry(
         &request_builder,
+        provider,
         DEFAULT_LLM_RETRY_COUNT,
         DEFAULT_LLM_RETRY_INTERVAL_SECS,
         None,
@@ -2090,7 +3650,34 @@ pub async fn run_turn(
     .await?;
 
     let json: serde_json::Value = response.json().await.map_err(LLMError::RequestError)?;
+    Ok((turn_outcome(provider, &json)?, turn_usage(provider, &json)))
+}
+
+/// 非流式响应里的 token 用量，字段名和各家流式响应里的相同
+fn turn_usage(provider: &str, json: &serde_json::Value) -> TurnUsage {
+    let tokens = |usage: Option<&serde_json::Value>, key: &str| usage.and_then(|u| u.get(key)).and_then(|t| t.as_i64());
+    match provider {
+        "anthropic" => {
+            let usage = json.get("usage");
+            TurnUsage { input_tokens: tokens(usage, "input_tokens"), output_tokens: tokens(usage, "output_tokens") }
+        }
+        "google" => {
+            let usage = json.get("usageMetadata");
+            TurnUsage {
+                input_tokens: tokens(usage, "promptTokenCount"),
+                output_tokens: tokens(usage, "candidatesTokenCount")
+                    .map(|n| n + tokens(usage, "thoughtsTokenCount").unwrap_or(0)),
+            }
+        }
+        _ => {
+            let usage = json.get("usage");
+            TurnUsage { input_tokens: tokens(usage, "prompt_tokens"), output_tokens: tokens(usage, "completion_tokens") }
+        }
+    }
+}
 
+/// 从非流式响应里取出模型的文本回复或想要执行的工具调用
+fn turn_outcome(provider: &str, json: &serde_json::Value) -> Result<TurnOutcome, LLMError> {
     match provider {
         "anthropic" => {
             let blocks = json.get("content").and_then(|c| c.as_array()).cloned().unwrap_or_default();
@@ -2210,11 +3797,7 @@ fn build_run_turn_body(
             // 的 budget。
             let is_legacy_thinking =
                 enable_thinking && (model.contains("claude-3") || model.contains("4-5") || model.contains("4.5"));
-            let max_tokens_val = match max_tokens {
-                Some(v) if is_legacy_thinking => v.max(9000),
-                Some(v) => v,
-                None => 32000,
-            };
+            let max_tokens_val = anthropic_max_tokens(model, max_tokens, is_legacy_thinking);
 
             // Prompt caching：把倒数第二条消息的最后一个 content block 标记为
             // 缓存断点，策略和流式路径一样——断点及之前的内容都会在服务端被
@@ -2262,7 +3845,7 @@ fn build_run_turn_body(
             });
             if enable_thinking {
                 if is_legacy_thinking {
-                    b["thinking"] = serde_json::json!({"type": "enabled", "budget_tokens": 8000});
+                    b["thinking"] = serde_json::json!({"type": "enabled", "budget_tokens": DEFAULT_THINKING_BUDGET});
                 } else {
                     b["thinking"] = serde_json::json!({"type": "adaptive"});
                 }
@@ -2340,32 +3923,148 @@ fn build_run_turn_body(
     }
 }
 
+/// 环境变量兜底时按顺序尝试的变量名：`{PROVIDER}_API_KEY`，少数 provider
+/// 再补上官方 SDK 惯用的名字
+fn api_key_env_vars(provider: &str) -> Vec<String> {
+    let mut names = vec![format!("{}_API_KEY", provider.to_uppercase().replace('-', "_"))];
+    let aliases: &[&str] = match provider {
+        "google" => &["GEMINI_API_KEY"],
+        "azure" => &["AZURE_OPENAI_API_KEY"],
+        "aliyun" => &["DASHSCOPE_API_KEY"],
+        "doubao" => &["ARK_API_KEY"],
+        _ => &[],
+    };
+    names.extend(aliases.iter().map(|s| s.to_string()));
+    names
+}
+
+/// 读取 provider 某个密钥档案里的密钥，档案为空或读取失败时返回 None
+fn profile_api_key(provider: &str, profile: &str) -> Option<String> {
+    let label = format!("{}:{}", provider, profile);
+    match crate::secure_storage::get_api_key(label.clone()) {
+        Ok(Some(key)) if !key.is_empty() => {
+            log::info!("[LLM] api_key resolved from key profile {}", label);
+            Some(key)
+        }
+        Ok(_) => {
+            log::warn!("[LLM] key profile {} has no key, falling back", label);
+            None
+        }
+        Err(e) => {
+            log::warn!("[LLM] keyring lookup failed for {}: {}", label, e);
+            None
+        }
+    }
+}
+
+/// 密钥的优先顺序：请求指定的档案 → 该 provider 选的默认档案 → 配置自己的 api_key。
+/// 档案没有密钥时顺延到下一项；默认档案只在需要时才读
+fn pick_api_key(
+    requested_profile: Option<&str>,
+    default_profile: impl FnOnce() -> Option<String>,
+    inline_key: &str,
+    profile_key: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    requested_profile
+        .and_then(&profile_key)
+        .or_else(|| default_profile().and_then(|profile| profile_key(&profile)))
+        .or_else(|| (!inline_key.is_empty()).then(|| inline_key.to_string()))
+}
+
 fn get_api_key(request: &SendMessageRequest) -> Result<String, LLMError> {
+    resolve_api_key(request, &request.api_key)
+}
+
+/// get_api_key 的实现，inline_key 是当作配置自己密钥的那个值
+fn resolve_api_key(request: &SendMessageRequest, inline_key: &str) -> Result<String, LLMError> {
     // 本地模型不需要 API key
     if request.provider == "local" {
         return Ok(String::new());
     }
-    if !request.api_key.is_empty() {
-        return Ok(request.api_key.clone());
-    }
-    // 没有传 api_key —— 退回到以 provider 为键的系统 keyring 查找。
-    // 前端调用 save_api_key(provider, key) 时，keyring 里的标签就是
-    // "api_keys_{provider}"。这样一来，只要密钥已经存在 keyring 里，
-    // 调用方就可以逐步不再在 IPC 请求里嵌入明文密钥。
-    if !request.provider.is_empty() {
-        let label = format!("api_keys_{}", request.provider);
-        if let Ok(entry) = KeyringEntry::new("BaiyuAISpace", &label) {
-            if let Ok(key) = entry.get_password() {
-                if !key.is_empty() {
-                    log::info!("[LLM] api_key resolved from keyring ({})", label);
-                    return Ok(key);
-                }
+    let provider = &request.provider;
+    let requested = request.api_key_profile.as_deref().filter(|p| !p.trim().is_empty());
+    let default_profile = || {
+        if provider.is_empty() {
+            return None;
+        }
+        match crate::secure_storage::list_api_key_profiles(provider.clone()) {
+            Ok(index) => index.default,
+            Err(e) => {
+                log::warn!("[LLM] failed to read key profiles for {}: {}", provider, e);
+                None
             }
         }
+    };
+    if let Some(key) = pick_api_key(requested, default_profile, inline_key, |profile| profile_api_key(provider, profile)) {
+        return Ok(key);
+    }
+    if request.provider.is_empty() {
+        return Err(LLMError::MissingApiKey);
+    }
+    // 没有传 api_key —— 先查系统 keyring（save_api_key(provider, key) 存下的
+    // 条目，选了默认档案时是默认档案的密钥），这样只要密钥已经存在 keyring 里，
+    // 调用方就可以逐步不再在 IPC 请求里嵌入明文密钥；keyring 里也没有时再退回环境变量。
+    match crate::secure_storage::get_api_key(request.provider.clone()) {
+        Ok(Some(key)) if !key.is_empty() => {
+            log::info!("[LLM] api_key resolved from keyring ({})", request.provider);
+            return Ok(key);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("[LLM] keyring lookup failed for {}: {}", request.provider, e),
+    }
+    for name in api_key_env_vars(&request.provider) {
+        if let Some(key) = std::env::var(&name).ok().filter(|k| !k.trim().is_empty()) {
+            log::info!("[LLM] api_key resolved from environment variable {}", name);
+            return Ok(key.trim().to_string());
+        }
     }
     Err(LLMError::MissingApiKey)
 }
 
+/// 故障转移候选的密钥：和 get_api_key 同样的顺序；请求里没带 api_key 时按 API 配置 ID
+/// 查 keyring（前端按配置 ID 存密钥），查到的当作配置自己的密钥。
+pub(crate) fn resolve_fallback_api_key(request: &SendMessageRequest, api_config_id: Option<&str>) -> Result<String, LLMError> {
+    let stored = if request.provider != "local" && request.api_key.is_empty() {
+        api_config_id
+            .and_then(|id| crate::secure_storage::get_api_key(id.to_string()).ok().flatten())
+            .filter(|k| !k.is_empty())
+    } else {
+        None
+    };
+    resolve_api_key(request, stored.as_deref().unwrap_or(&request.api_key))
+}
+
+/// 把 request 切换到 fallbacks 里下一个能拿到密钥的候选，返回它的密钥。
+/// 拿不到密钥的候选记进 failures 后直接跳过，request 不动——先改了 provider/base_url
+/// 再发现没有密钥的话，手里还是上一个 provider 的密钥，会被发到新的 base_url 上。
+fn switch_to_next_fallback(
+    request: &mut SendMessageRequest,
+    fallbacks: &mut impl Iterator<Item = FallbackProvider>,
+    failures: &mut Vec<String>,
+    resolve_key: impl Fn(&SendMessageRequest, Option<&str>) -> Result<String, LLMError>,
+) -> Option<String> {
+    for next in fallbacks {
+        let mut candidate = request.clone();
+        candidate.provider = next.provider;
+        candidate.model = next.model;
+        candidate.base_url = next.base_url;
+        candidate.api_key = next.api_key;
+        candidate.api_key_profile = None;
+        candidate.custom_auth = next.custom_auth;
+        match resolve_key(&candidate, next.api_config_id.as_deref()) {
+            Ok(key) => {
+                *request = candidate;
+                return Some(key);
+            }
+            Err(e) => {
+                log::warn!("[LLM] skipping fallback {} ({}): {}", candidate.provider, candidate.model, e);
+                failures.push(format!("{}/{}: {}", candidate.provider, candidate.model, e));
+            }
+        }
+    }
+    None
+}
+
 /// 取消某个会话正在进行的流
 #[tauri::command]
 pub async fn cancel_stream(session_id: String) -> Result<(), String> {
@@ -2404,6 +4103,8 @@ mod provider_tool_calling_tests {
         let messages = vec![ChatMessage {
             id: "1".into(), role: "user".into(), content: "hi".into(),
             timestamp: 0, error: None, images: vec![], videos: vec![],
+            seed: None,
+            generation: None,
         }];
         let body = build_stream_request_body("anthropic", "claude-3-5-sonnet", &messages, &[sample_tool()], false, None);
         let tools = body["tools"].as_array().expect("tools should be an array");
@@ -2417,6 +4118,8 @@ mod provider_tool_calling_tests {
         ChatMessage {
             id: content.into(), role: role.into(), content: content.into(),
             timestamp: 0, error: None, images: vec![], videos: vec![],
+            seed: None,
+            generation: None,
         }
     }
 
@@ -2461,6 +4164,8 @@ mod provider_tool_calling_tests {
         let messages = vec![ChatMessage {
             id: "1".into(), role: "user".into(), content: "hi".into(),
             timestamp: 0, error: None, images: vec![], videos: vec![],
+            seed: None,
+            generation: None,
         }];
         let body = build_stream_request_body("google", "gemini-1.5-pro", &messages, &[sample_tool()], false, None);
         let tools = body["tools"].as_array().expect("tools should be an array");
@@ -2472,9 +4177,9 @@ mod provider_tool_calling_tests {
 
     #[test]
     fn anthropic_tool_use_block_then_input_json_delta_accumulates_into_tool_call_deltas() {
-        let start = parse_sse_line(
+        let start = parse_sse_data(
             "anthropic",
-            r#"data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_01","name":"get_weather","input":{}}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_01","name":"get_weather","input":{}}}"#,
         ).expect("should parse content_block_start");
         match start {
             StreamContent::ToolCallDeltas(deltas) => {
@@ -2486,9 +4191,9 @@ mod provider_tool_calling_tests {
             other => panic!("expected ToolCallDeltas, got {:?}", other),
         }
 
-        let delta = parse_sse_line(
+        let delta = parse_sse_data(
             "anthropic",
-            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\": \"SF\"}"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\": \"SF\"}"}}"#,
         ).expect("should parse content_block_delta");
         match delta {
             StreamContent::ToolCallDeltas(deltas) => {
@@ -2498,24 +4203,24 @@ mod provider_tool_calling_tests {
             other => panic!("expected ToolCallDeltas, got {:?}", other),
         }
 
-        let stop = parse_sse_line("anthropic", r#"data: {"type":"message_stop"}"#);
+        let stop = parse_sse_data("anthropic", r#"{"type":"message_stop"}"#);
         assert!(matches!(stop, Some(StreamContent::Done)));
     }
 
     #[test]
     fn anthropic_text_delta_still_parses_as_text() {
-        let text = parse_sse_line(
+        let text = parse_sse_data(
             "anthropic",
-            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
         );
         assert!(matches!(text, Some(StreamContent::Text(ref s)) if s == "Hello"));
     }
 
     #[test]
     fn google_function_call_part_parses_as_tool_call_delta_with_full_args() {
-        let parsed = parse_sse_line(
+        let parsed = parse_sse_data(
             "google",
-            r#"data: {"candidates":[{"content":{"parts":[{"functionCall":{"name":"get_weather","args":{"city":"SF"}}}]}}]}"#,
+            r#"{"candidates":[{"content":{"parts":[{"functionCall":{"name":"get_weather","args":{"city":"SF"}}}]}}]}"#,
         ).expect("should parse functionCall chunk");
         match parsed {
             StreamContent::ToolCallDeltas(deltas) => {
@@ -2531,9 +4236,9 @@ mod provider_tool_calling_tests {
 
     #[test]
     fn google_text_part_still_parses_as_text() {
-        let parsed = parse_sse_line(
+        let parsed = parse_sse_data(
             "google",
-            r#"data: {"candidates":[{"content":{"parts":[{"text":"Hello"}]}}]}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hello"}]}}]}"#,
         );
         assert!(matches!(parsed, Some(StreamContent::Text(ref s)) if s == "Hello"));
     }
@@ -2543,6 +4248,8 @@ mod provider_tool_calling_tests {
         let messages = vec![ChatMessage {
             id: "1".into(), role: "user".into(), content: "hi".into(),
             timestamp: 0, error: None, images: vec![], videos: vec![],
+            seed: None,
+            generation: None,
         }];
         let body = build_stream_request_body("openai", "gpt-4o", &messages, &[sample_tool()], false, None);
         let tools = body["tools"].as_array().expect("tools should be an array");
@@ -2555,9 +4262,9 @@ mod provider_tool_calling_tests {
         // Ollama 在携带 tool_calls 的 chunk 里同时带 `"content": ""`（OpenAI
         // 官方是 null）。曾经的解析顺序先命中 content 分支，空串直接 return，
         // 同一 chunk 的 tool_calls 被整个吞掉。
-        let parsed = parse_sse_line(
+        let parsed = parse_sse_data(
             "local",
-            r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":"","tool_calls":[{"id":"call_1","index":0,"type":"function","function":{"name":"get_weather","arguments":"{\"city\":\"SF\"}"}}]},"finish_reason":null}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":"","tool_calls":[{"id":"call_1","index":0,"type":"function","function":{"name":"get_weather","arguments":"{\"city\":\"SF\"}"}}]},"finish_reason":null}]}"#,
         ).expect("tool_calls must be parsed even when content is an empty string");
         match parsed {
             StreamContent::ToolCallDeltas(deltas) => {
@@ -2573,31 +4280,46 @@ mod provider_tool_calling_tests {
     fn openai_reasoning_deltas_parse_as_thinking() {
         // Ollama 用 `reasoning`，DeepSeek/SiliconFlow 系用 `reasoning_content`；
         // 两种拼写都必须归到 Thinking，而不是被当作空 content 丢弃。
-        let ollama = parse_sse_line(
+        let ollama = parse_sse_data(
             "local",
-            r#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":"","reasoning":"想一想"},"finish_reason":null}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":"","reasoning":"想一想"},"finish_reason":null}]}"#,
         );
         assert!(matches!(ollama, Some(StreamContent::Thinking(ref s)) if s == "想一想"));
 
-        let deepseek = parse_sse_line(
+        let deepseek = parse_sse_data(
             "deepseek",
-            r#"data: {"choices":[{"index":0,"delta":{"content":null,"reasoning_content":"hmm"},"finish_reason":null}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":null,"reasoning_content":"hmm"},"finish_reason":null}]}"#,
         );
         assert!(matches!(deepseek, Some(StreamContent::Thinking(ref s)) if s == "hmm"));
 
         // 普通正文 chunk 不受影响
-        let text = parse_sse_line(
+        let text = parse_sse_data(
             "local",
-            r#"data: {"choices":[{"index":0,"delta":{"content":"你好"},"finish_reason":null}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":"你好"},"finish_reason":null}]}"#,
         );
         assert!(matches!(text, Some(StreamContent::Text(ref s)) if s == "你好"));
     }
 
+    #[test]
+    fn think_tags_split_across_chunks_are_routed_to_reasoning() {
+        let mut splitter = ThinkTagSplitter::default();
+        let mut segments = Vec::new();
+        for piece in ["<thi", "nk>先想", "一想</th", "ink>", "答案是 42 <b>"] {
+            segments.extend(splitter.feed(piece));
+        }
+        segments.extend(splitter.finish());
+
+        let reasoning: String = segments.iter().filter(|(t, _)| *t).map(|(_, s)| s.as_str()).collect();
+        let content: String = segments.iter().filter(|(t, _)| !*t).map(|(_, s)| s.as_str()).collect();
+        assert_eq!(reasoning, "先想一想");
+        assert_eq!(content, "答案是 42 <b>");
+    }
+
     #[test]
     fn anthropic_thinking_delta_parses_as_thinking() {
-        let parsed = parse_sse_line(
+        let parsed = parse_sse_data(
             "anthropic",
-            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Let me think"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Let me think"}}"#,
         );
         assert!(matches!(parsed, Some(StreamContent::Thinking(ref s)) if s == "Let me think"));
     }
@@ -2607,6 +4329,8 @@ mod provider_tool_calling_tests {
         let messages = vec![ChatMessage {
             id: "1".into(), role: "user".into(), content: "hi".into(),
             timestamp: 0, error: None, images: vec![], videos: vec![],
+            seed: None,
+            generation: None,
         }];
 
         // 本地服务 + 思考关闭：显式关思考（LM Studio 上 qwen3.5 这类默认思考
@@ -2635,8 +4359,10 @@ mod provider_tool_calling_tests {
         ChatMessage {
             id: "1".into(), role: "user".into(), content: "what is this".into(),
             timestamp: 0, error: None,
-            images: vec![ImageAttachment { data: "AAAA".into(), media_type: "image/png".into() }],
+            images: vec![ImageAttachment { data: "AAAA".into(), media_type: "image/png".into(), path: None }],
             videos: vec![],
+            seed: None,
+            generation: None,
         }
     }
 
@@ -2666,6 +4392,247 @@ mod provider_tool_calling_tests {
         assert_eq!(image_url, "data:image/png;base64,AAAA");
     }
 
+    #[test]
+    fn native_messages_keep_images_for_tool_call_continuations() {
+        // 续写请求改用 build_native_messages 之后，截图不能在工具调用轮次里丢掉
+        let messages = vec![image_message()];
+        let anthropic = build_native_messages("anthropic", &messages);
+        assert_eq!(anthropic[0]["content"][0]["type"], "image");
+        let google = build_native_messages("google", &messages);
+        assert_eq!(google[0]["parts"][1]["inline_data"]["mime_type"], "image/png");
+        let mistral = build_native_messages("mistral", &messages);
+        assert_eq!(mistral[0]["content"][1]["image_url"], "data:image/png;base64,AAAA");
+    }
+
+    #[tokio::test]
+    async fn image_paths_are_read_into_base64_before_sending() {
+        let path = std::env::temp_dir().join(format!("baiyu_img_{}.png", Uuid::new_v4()));
+        std::fs::write(&path, [0x89u8, b'P', b'N', b'G']).unwrap();
+        let mut messages = vec![ChatMessage {
+            images: vec![ImageAttachment {
+                data: String::new(),
+                media_type: String::new(),
+                path: Some(path.to_string_lossy().to_string()),
+            }],
+            ..msg("user", "这张图里是什么？")
+        }];
+        resolve_image_paths(&mut messages).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(messages[0].images[0].data, "iVBORw==");
+        assert_eq!(messages[0].images[0].media_type, "image/png");
+    }
+
+    #[test]
+    fn persona_prompt_goes_before_existing_system_and_skills_after() {
+        let mut messages = vec![msg("system", "全局提示"), msg("user", "hi")];
+        merge_system_prompt(&mut messages, "你是一名翻译", true);
+        merge_system_prompt(&mut messages, "技能说明", false);
+        assert_eq!(messages[0].content, "你是一名翻译\n\n全局提示\n\n技能说明");
+
+        let mut bare = vec![msg("user", "hi")];
+        merge_system_prompt(&mut bare, "你是一名翻译", true);
+        merge_system_prompt(&mut bare, "  ", false);
+        assert_eq!(bare.len(), 2);
+        assert_eq!(bare[0].role, "system");
+    }
+
+    #[test]
+    fn resume_messages_append_partial_reply_and_continue_instruction() {
+        let messages = vec![msg("user", "写一首诗")];
+        let resumed = build_resume_messages(&messages, "床前明月光，");
+        let roles: Vec<&str> = resumed.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        assert_eq!(resumed[1].content, "床前明月光，");
+
+        // 还没出字就卡住：原样重发
+        assert_eq!(build_resume_messages(&messages, "  ").len(), 1);
+    }
+
+    #[test]
+    fn sampling_params_use_each_providers_field_names() {
+        let params = SamplingParams {
+            temperature: Some(0.5),
+            stop: vec!["END".into(), String::new()],
+            frequency_penalty: Some(0.2),
+            presence_penalty: Some(0.1),
+            logprobs: true,
+            top_logprobs: Some(50),
+            seed: Some(42),
+            safety_settings: vec![SafetySetting {
+                category: "HARM_CATEGORY_HARASSMENT".into(),
+                threshold: "BLOCK_ONLY_HIGH".into(),
+            }],
+        };
+
+        let mut google = build_stream_request_body("google", "gemini-2.5-flash", &[msg("user", "hi")], &[], false, None);
+        apply_sampling_params(&mut google, "google", &params);
+        assert_eq!(google["generationConfig"]["stopSequences"], serde_json::json!(["END"]));
+        assert!(google["generationConfig"]["presencePenalty"].is_number());
+        assert!(google.get("temperature").is_none());
+        assert_eq!(google["generationConfig"]["logprobs"], 20);
+        assert_eq!(google["generationConfig"]["seed"], 42);
+        assert_eq!(google["safetySettings"][0]["threshold"], "BLOCK_ONLY_HIGH");
+
+        let mut anthropic = build_stream_request_body("anthropic", "claude-sonnet-4-5", &[msg("user", "hi")], &[], false, None);
+        apply_sampling_params(&mut anthropic, "anthropic", &params);
+        assert_eq!(anthropic["stop_sequences"], serde_json::json!(["END"]));
+        assert!(anthropic.get("frequency_penalty").is_none());
+        assert!(anthropic.get("stop").is_none());
+        assert!(anthropic.get("logprobs").is_none());
+        assert!(anthropic.get("seed").is_none());
+        assert!(anthropic.get("safetySettings").is_none());
+
+        let mut openai = build_stream_request_body("openai", "gpt-4o", &[msg("user", "hi")], &[], false, None);
+        apply_sampling_params(&mut openai, "openai", &params);
+        assert_eq!(openai["stop"], serde_json::json!(["END"]));
+        assert!(openai["frequency_penalty"].is_number());
+        assert!(openai["temperature"].is_number());
+        assert_eq!(openai["logprobs"], true);
+        assert_eq!(openai["top_logprobs"], 20);
+        assert_eq!(openai["seed"], 42);
+    }
+
+    #[test]
+    fn stream_speed_measures_ttft_and_generation_rate_from_first_token() {
+        let start = Instant::now();
+        let streamed = StreamedOutput {
+            started_at: Some(start),
+            first_token_at: Some(start + Duration::from_millis(800)),
+            ..Default::default()
+        };
+        let (ttft, tps) = streamed.speed(start + Duration::from_millis(2800), 100);
+        assert_eq!(ttft, Some(800));
+        assert_eq!(tps, Some(50.0));
+
+        let silent = StreamedOutput { started_at: Some(start), ..Default::default() };
+        assert_eq!(silent.speed(start + Duration::from_secs(1), 0), (None, None));
+    }
+
+    #[test]
+    fn reply_checkpoint_needs_a_message_id_and_only_saves_changed_text_after_interval() {
+        let mut request: SendMessageRequest = serde_json::from_value(serde_json::json!({
+            "sessionId": "s1",
+            "messages": [],
+            "provider": "openai",
+            "model": "gpt-4o",
+            "baseUrl": "",
+            "enableMcp": false
+        }))
+        .unwrap();
+        assert!(ReplyCheckpoint::new(&request).is_none());
+
+        request.reply_message_id = Some("m1".to_string());
+        request.reply_timestamp = Some(1234);
+        let mut checkpoint = ReplyCheckpoint::new(&request).unwrap();
+        assert_eq!(checkpoint.timestamp, 1234);
+
+        let start = checkpoint.last_saved;
+        assert!(!checkpoint.due("hello", start + Duration::from_secs(1)));
+        assert!(checkpoint.due("hello", start + PARTIAL_CHECKPOINT_INTERVAL));
+        checkpoint.saved_len = "hello".len();
+        assert!(!checkpoint.due("hello", start + PARTIAL_CHECKPOINT_INTERVAL * 2));
+    }
+
+    #[test]
+    fn fallback_without_a_resolvable_key_is_skipped_and_never_gets_the_previous_key() {
+        let mut request: SendMessageRequest = serde_json::from_value(serde_json::json!({
+            "sessionId": "s1",
+            "messages": [],
+            "provider": "openai",
+            "model": "gpt-4o",
+            "baseUrl": "https://api.openai.com/v1",
+            "apiKey": "sk-primary",
+            "enableMcp": false
+        }))
+        .unwrap();
+        let fallback = |provider: &str, base_url: &str, api_key: &str| FallbackProvider {
+            provider: provider.to_string(),
+            model: format!("{}-model", provider),
+            base_url: base_url.to_string(),
+            api_key: api_key.to_string(),
+            api_config_id: None,
+            custom_auth: CustomAuth::default(),
+        };
+        let mut fallbacks = vec![
+            fallback("deepseek", "https://api.deepseek.com", ""),
+            fallback("anthropic", "https://api.anthropic.com", "sk-anthropic"),
+        ]
+        .into_iter();
+        let resolve = |r: &SendMessageRequest, _: Option<&str>| {
+            if r.api_key.is_empty() { Err(LLMError::MissingApiKey) } else { Ok(r.api_key.clone()) }
+        };
+        let mut failures = Vec::new();
+
+        let key = switch_to_next_fallback(&mut request, &mut fallbacks, &mut failures, resolve);
+        assert_eq!(key.as_deref(), Some("sk-anthropic"));
+        assert_eq!(request.provider, "anthropic");
+        assert_eq!(request.base_url, "https://api.anthropic.com");
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("deepseek/deepseek-model: "));
+
+        // 剩下的候选都拿不到密钥：request 保持原样，不会带着旧密钥换到新地址
+        let mut fallbacks = vec![fallback("deepseek", "https://api.deepseek.com", "")].into_iter();
+        assert!(switch_to_next_fallback(&mut request, &mut fallbacks, &mut failures, resolve).is_none());
+        assert_eq!(request.provider, "anthropic");
+        assert_eq!(request.base_url, "https://api.anthropic.com");
+        assert_eq!(failures.len(), 2);
+    }
+
+    #[test]
+    fn turn_usage_reads_each_providers_non_streaming_usage_fields() {
+        let anthropic = serde_json::json!({"content": [], "usage": {"input_tokens": 120, "output_tokens": 40}});
+        assert_eq!(turn_usage("anthropic", &anthropic), TurnUsage { input_tokens: Some(120), output_tokens: Some(40) });
+
+        let google = serde_json::json!({"usageMetadata": {"promptTokenCount": 90, "candidatesTokenCount": 30, "thoughtsTokenCount": 5}});
+        assert_eq!(turn_usage("google", &google), TurnUsage { input_tokens: Some(90), output_tokens: Some(35) });
+
+        let openai = serde_json::json!({"choices": [], "usage": {"prompt_tokens": 10, "completion_tokens": 2}});
+        assert_eq!(turn_usage("openai", &openai), TurnUsage { input_tokens: Some(10), output_tokens: Some(2) });
+        assert_eq!(turn_usage("openai", &serde_json::json!({"choices": []})), TurnUsage::default());
+    }
+
+    #[test]
+    fn gemini_block_reasons_are_reported_with_blocked_categories() {
+        let prompt = parse_gemini_block(r#"{"promptFeedback":{"blockReason":"SAFETY","safetyRatings":[{"category":"HARM_CATEGORY_HARASSMENT","probability":"HIGH","blocked":true},{"category":"HARM_CATEGORY_HATE_SPEECH","probability":"NEGLIGIBLE"}]}}"#).unwrap();
+        assert_eq!(prompt, ("prompt", "SAFETY".to_string(), vec!["HARM_CATEGORY_HARASSMENT".to_string()]));
+
+        let response = parse_gemini_block(r#"{"candidates":[{"finishReason":"PROHIBITED_CONTENT"}]}"#).unwrap();
+        assert_eq!((response.0, response.1.as_str()), ("response", "PROHIBITED_CONTENT"));
+        assert!(parse_gemini_block(r#"{"candidates":[{"finishReason":"STOP"}]}"#).is_none());
+    }
+
+    #[test]
+    fn stream_meta_reads_finish_reason_and_usage_per_provider() {
+        let mut out = StreamedOutput::default();
+        out.merge_meta(parse_stream_meta("anthropic", r#"{"type":"message_start","message":{"usage":{"input_tokens":12,"cache_read_input_tokens":30,"output_tokens":1}}}"#).unwrap());
+        out.merge_meta(parse_stream_meta("anthropic", r#"{"type":"message_delta","delta":{"stop_reason":"max_tokens"},"usage":{"output_tokens":256}}"#).unwrap());
+        assert_eq!((out.input_tokens, out.output_tokens), (Some(42), Some(256)));
+        assert_eq!(out.finish_reason.as_deref(), Some("length"));
+
+        let openai_finish = parse_stream_meta("openai", r#"{"choices":[{"delta":{},"finish_reason":"content_filter"}]}"#).unwrap();
+        assert_eq!(openai_finish.finish_reason.as_deref(), Some("content_filter"));
+        let openai_usage = parse_stream_meta("openai", r#"{"choices":[],"usage":{"prompt_tokens":9,"completion_tokens":3}}"#).unwrap();
+        assert_eq!((openai_usage.input_tokens, openai_usage.output_tokens), (Some(9), Some(3)));
+        assert!(parse_stream_meta("openai", r#"{"choices":[{"delta":{"content":"hi"},"finish_reason":null}],"usage":null}"#).is_none());
+
+        let google = parse_stream_meta("google", r#"{"candidates":[{"finishReason":"SAFETY"}],"usageMetadata":{"promptTokenCount":5,"candidatesTokenCount":7}}"#).unwrap();
+        assert_eq!(google.finish_reason.as_deref(), Some("content_filter"));
+        assert_eq!(google.output_tokens, Some(7));
+    }
+
+    #[test]
+    fn stream_logprobs_read_openai_and_gemini_shapes() {
+        let openai = parse_stream_logprobs("openai", r#"{"choices":[{"delta":{"content":"Hi"},"logprobs":{"content":[{"token":"Hi","logprob":-0.1,"top_logprobs":[{"token":"Hi","logprob":-0.1},{"token":"Hello","logprob":-2.4}]}]}}]}"#).unwrap();
+        assert_eq!(openai[0].token, "Hi");
+        assert_eq!(openai[0].top[1].token, "Hello");
+
+        let google = parse_stream_logprobs("google", r#"{"candidates":[{"content":{"parts":[{"text":"你好"}]},"logprobsResult":{"chosenCandidates":[{"token":"你好","logProbability":-0.3}],"topCandidates":[{"candidates":[{"token":"你好","logProbability":-0.3},{"token":"您好","logProbability":-1.5}]}]}}]}"#).unwrap();
+        assert_eq!(google[0].logprob, -0.3);
+        assert_eq!(google[0].top.len(), 2);
+
+        assert!(parse_stream_logprobs("openai", r#"{"choices":[{"delta":{"content":"x"},"logprobs":null}]}"#).is_none());
+    }
+
     fn sample_call() -> PendingToolCall {
         PendingToolCall {
             id: "call_1".to_string(),
@@ -2721,9 +4688,9 @@ mod provider_tool_calling_tests {
     #[test]
     fn build_native_messages_matches_provider_shapes() {
         let messages = vec![
-            ChatMessage { id: "0".into(), role: "system".into(), content: "be nice".into(), timestamp: 0, error: None, images: vec![], videos: vec![] },
-            ChatMessage { id: "1".into(), role: "user".into(), content: "hi".into(), timestamp: 0, error: None, images: vec![], videos: vec![] },
-            ChatMessage { id: "2".into(), role: "assistant".into(), content: "hello".into(), timestamp: 0, error: None, images: vec![], videos: vec![] },
+            ChatMessage { id: "0".into(), role: "system".into(), content: "be nice".into(), timestamp: 0, error: None, images: vec![], videos: vec![], seed: None, generation: None },
+            ChatMessage { id: "1".into(), role: "user".into(), content: "hi".into(), timestamp: 0, error: None, images: vec![], videos: vec![], seed: None, generation: None },
+            ChatMessage { id: "2".into(), role: "assistant".into(), content: "hello".into(), timestamp: 0, error: None, images: vec![], videos: vec![], seed: None, generation: None },
         ];
 
         let anthropic = build_native_messages("anthropic", &messages);
@@ -2868,7 +4835,7 @@ mod provider_tool_calling_tests {
 
         let outcome = continue_after_tool_calls(
             "custom", "test-model", "test-key", &base_url,
-            &original_messages, &rounds, &[], &[], None, 0, 0,
+            &original_messages, &rounds, &[], &[], None, &SamplingParams::default(), 0, 0, &CustomAuth::default(),
         ).await.expect("continuation call should succeed");
 
         match outcome {
@@ -2928,7 +4895,7 @@ mod provider_tool_calling_tests {
         };
         let mut rounds = vec![(vec![call_1], vec![result_1])];
 
-        let outcome = continue_after_tool_calls("custom", "test-model", "test-key", &base_url, &original_messages, &rounds, &[], &[], None, 0, 0)
+        let outcome = continue_after_tool_calls("custom", "test-model", "test-key", &base_url, &original_messages, &rounds, &[], &[], None, &SamplingParams::default(), 0, 0, &CustomAuth::default())
             .await
             .expect("round 1 continuation");
         let next_calls = match outcome {
@@ -2938,7 +4905,7 @@ mod provider_tool_calling_tests {
         assert_eq!(next_calls[0].id, "call_2");
 
         rounds.push((next_calls, vec![result_2]));
-        let outcome_2 = continue_after_tool_calls("custom", "test-model", "test-key", &base_url, &original_messages, &rounds, &[], &[], None, 0, 0)
+        let outcome_2 = continue_after_tool_calls("custom", "test-model", "test-key", &base_url, &original_messages, &rounds, &[], &[], None, &SamplingParams::default(), 0, 0, &CustomAuth::default())
             .await
             .expect("round 2 continuation");
         match outcome_2 {
@@ -2962,8 +4929,11 @@ mod provider_tool_calling_tests {
     fn run_turn_body_max_tokens_defaults_match_streaming_path_per_provider() {
         let msgs = vec![native_msg("user", "hi")];
         // Anthropic 强制要求这个字段——未设置时退回到一个宽裕的默认值。
+        // 默认值受模型自身的输出上限约束（Claude 3.5 只有 8192）。
         let anthropic = build_run_turn_body("anthropic", "claude-3-5-sonnet", None, &msgs, &[], None, false);
-        assert_eq!(anthropic["max_tokens"], 32000);
+        assert_eq!(anthropic["max_tokens"], 8192);
+        let anthropic_new = build_run_turn_body("anthropic", "claude-sonnet-4-5", None, &msgs, &[], None, false);
+        assert_eq!(anthropic_new["max_tokens"], 32000);
         let anthropic_set = build_run_turn_body("anthropic", "claude-3-5-sonnet", None, &msgs, &[], Some(1000), false);
         assert_eq!(anthropic_set["max_tokens"], 1000);
 
@@ -2996,6 +4966,77 @@ mod provider_tool_calling_tests {
         assert_eq!(adaptive["thinking"]["type"], "adaptive");
     }
 
+    #[test]
+    fn thinking_budget_overrides_budget_fields_and_lifts_anthropic_max_tokens() {
+        let msgs = vec![msg("user", "hi")];
+        let mut legacy = build_stream_request_body("anthropic", "claude-3-7-sonnet-latest", &msgs, &[], true, Some(10_000));
+        apply_thinking_budget(&mut legacy, "anthropic", Some(16_000));
+        assert_eq!(legacy["thinking"]["budget_tokens"], 16_000);
+        assert_eq!(legacy["max_tokens"], 17_000);
+
+        let mut adaptive = build_stream_request_body("anthropic", "claude-opus-4-6", &msgs, &[], true, None);
+        apply_thinking_budget(&mut adaptive, "anthropic", Some(16_000));
+        assert!(adaptive["thinking"].get("budget_tokens").is_none());
+
+        let mut gemini = build_stream_request_body("google", "gemini-2.5-pro", &msgs, &[], true, None);
+        apply_thinking_budget(&mut gemini, "google", Some(2_048));
+        assert_eq!(gemini["generationConfig"]["thinkingConfig"]["thinkingBudget"], 2_048);
+
+        let mut no_thinking = build_stream_request_body("siliconflow", "qwen3", &msgs, &[], false, None);
+        apply_thinking_budget(&mut no_thinking, "siliconflow", Some(2_048));
+        assert!(no_thinking.get("thinking_budget").is_none());
+    }
+
+    #[test]
+    fn custom_auth_adds_headers_and_moves_key_to_query_param() {
+        let custom: CustomAuth = serde_json::from_value(serde_json::json!({
+            "headers": [
+                { "name": "api-key", "value": "{apiKey}" },
+                { "name": "X-Org", "value": "org-1" },
+                { "name": "bad header", "value": "x" }
+            ]
+        }))
+        .unwrap();
+        let headers = build_headers("custom", "sk-1", Some(&custom));
+        assert_eq!(headers["api-key"], "sk-1");
+        assert_eq!(headers["x-org"], "org-1");
+        assert_eq!(headers[reqwest::header::AUTHORIZATION], "Bearer sk-1");
+
+        let query = CustomAuth { query_param: Some("key".into()), ..custom };
+        assert!(build_headers("custom", "sk-1", Some(&query)).get(reqwest::header::AUTHORIZATION).is_none());
+        assert_eq!(
+            apply_query_auth("https://gw.example.com/v1/chat/completions?alt=sse", "sk 1", &query),
+            "https://gw.example.com/v1/chat/completions?alt=sse&key=sk+1"
+        );
+        assert_eq!(apply_query_auth("https://a.b/v1", "k", &CustomAuth::default()), "https://a.b/v1");
+    }
+
+    #[test]
+    fn api_keys_come_from_the_requested_profile_then_the_default_profile_then_the_config() {
+        let profiles = |profile: &str| match profile {
+            "work" => Some("sk-work".to_string()),
+            "home" => Some("sk-home".to_string()),
+            _ => None,
+        };
+        let default = || Some("home".to_string());
+        assert_eq!(pick_api_key(Some("work"), default, "sk-inline", profiles).as_deref(), Some("sk-work"));
+        assert_eq!(pick_api_key(None, default, "sk-inline", profiles).as_deref(), Some("sk-home"));
+        // 档案里没有密钥时顺延
+        assert_eq!(pick_api_key(Some("empty"), default, "sk-inline", profiles).as_deref(), Some("sk-home"));
+        assert_eq!(pick_api_key(Some("empty"), || None, "sk-inline", profiles).as_deref(), Some("sk-inline"));
+        assert_eq!(pick_api_key(None, || None, "", profiles), None);
+        // 指定的档案有密钥时不去读默认档案
+        let unread = || -> Option<String> { panic!("default profile should not be read") };
+        assert_eq!(pick_api_key(Some("work"), unread, "", profiles).as_deref(), Some("sk-work"));
+    }
+
+    #[test]
+    fn api_key_env_vars_use_provider_name_and_sdk_aliases() {
+        assert_eq!(api_key_env_vars("openai"), ["OPENAI_API_KEY"]);
+        assert_eq!(api_key_env_vars("google"), ["GOOGLE_API_KEY", "GEMINI_API_KEY"]);
+        assert_eq!(api_key_env_vars("aliyun"), ["ALIYUN_API_KEY", "DASHSCOPE_API_KEY"]);
+    }
+
     #[test]
     fn run_turn_body_thinking_only_applied_to_siliconflow_among_openai_compatible() {
         let msgs = vec![native_msg("user", "hi")];
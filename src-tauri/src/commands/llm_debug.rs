@@ -0,0 +1,220 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * LLM 请求调试日志模块
+ *
+ * 功能说明:
+ * - 可选的调试模式：开启后记录最近若干次流式请求的完整请求体、请求头、
+ *   HTTP 状态和服务商返回的原始 SSE 事件，放在内存环形缓冲区里
+ * - 请求头和 URL 里的密钥（Authorization、x-api-key、key=... 等）一律打码后才记录
+ * - 前端通过 get_llm_debug_log 取出记录，用来排查 provider 对接问题
+ *
+ * 调试模式默认关闭，开关由前端持久化、启动时通过 set_llm_debug_mode 同步；
+ * 关闭时清空已有记录。记录只在内存里，不写盘。
+ */
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// 环形缓冲区保留的请求数
+const DEBUG_LOG_CAPACITY: usize = 20;
+/// 单次请求最多记录的 SSE 事件数，超出的丢弃并标记 truncated
+const MAX_SSE_EVENTS_PER_REQUEST: usize = 2000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static RECORDS: Lazy<Mutex<VecDeque<LlmDebugRecord>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// 调试记录的编号；放进 reqwest 响应的 extensions 里，读流时据此追加 SSE 事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DebugId(u64);
+
+/// 一次请求的调试记录
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LlmDebugRecord {
+    pub id: u64,
+    /// 发出请求的时间（Unix 毫秒）
+    pub timestamp: i64,
+    pub provider: String,
+    pub model: String,
+    /// 请求地址（query 里的密钥已打码）
+    pub url: String,
+    /// 请求头（密钥类请求头已打码）
+    pub headers: BTreeMap<String, String>,
+    /// 完整请求体
+    pub body: serde_json::Value,
+    /// HTTP 状态码（请求没拿到响应时为空）
+    pub status: Option<u16>,
+    /// 请求失败时的错误信息
+    pub error: Option<String>,
+    /// 原始 SSE 事件，按 `event:` / `data:` 行还原
+    pub sse_events: Vec<String>,
+    /// SSE 事件超过上限被截断
+    pub truncated: bool,
+}
+
+/// 调试模式是否开启
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// 名字看起来像密钥的请求头 / query 参数
+fn is_secret_name(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    ["auth", "key", "token", "secret", "signature", "cookie"]
+        .iter()
+        .any(|s| lower.contains(s))
+}
+
+/// 打码：只保留末 4 位，便于区分用的是哪个密钥
+fn redact(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 8 {
+        return "***".to_string();
+    }
+    format!("***{}", chars[chars.len() - 4..].iter().collect::<String>())
+}
+
+fn redact_url(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return url.to_string();
+    };
+    if parsed.query().is_none() {
+        return url.to_string();
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(k, v)| {
+            let v = if is_secret_name(&k) { redact(&v) } else { v.into_owned() };
+            (k.into_owned(), v)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+fn redact_headers(headers: &reqwest::header::HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = value.to_str().unwrap_or("<non-utf8>");
+            let value = if is_secret_name(name.as_str()) { redact(value) } else { value.to_string() };
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+fn with_record(id: DebugId, f: impl FnOnce(&mut LlmDebugRecord)) {
+    let mut records = RECORDS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(record) = records.iter_mut().find(|r| r.id == id.0) {
+        f(record);
+    }
+}
+
+/// 记录一次即将发出的请求；调试模式关闭时什么都不做，返回 None
+pub(crate) fn record_request(
+    provider: &str,
+    model: &str,
+    url: &str,
+    headers: &reqwest::header::HeaderMap,
+    body: &serde_json::Value,
+) -> Option<DebugId> {
+    if !is_enabled() {
+        return None;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let record = LlmDebugRecord {
+        id,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        provider: provider.to_string(),
+        model: model.to_string(),
+        url: redact_url(url),
+        headers: redact_headers(headers),
+        body: body.clone(),
+        status: None,
+        error: None,
+        sse_events: Vec::new(),
+        truncated: false,
+    };
+    let mut records = RECORDS.lock().unwrap_or_else(|e| e.into_inner());
+    while records.len() >= DEBUG_LOG_CAPACITY {
+        records.pop_front();
+    }
+    records.push_back(record);
+    Some(DebugId(id))
+}
+
+/// 记录请求结果：拿到响应时的状态码，或者发送失败的错误
+pub(crate) fn record_result(id: DebugId, status: Option<u16>, error: Option<String>) {
+    with_record(id, |r| {
+        r.status = status;
+        r.error = error;
+    });
+}
+
+/// 追加一个收到的 SSE 事件
+pub(crate) fn record_sse(id: DebugId, event: Option<&str>, data: &str) {
+    with_record(id, |r| {
+        if r.sse_events.len() >= MAX_SSE_EVENTS_PER_REQUEST {
+            r.truncated = true;
+            return;
+        }
+        let data = data.lines().map(|l| format!("data: {}", l)).collect::<Vec<_>>().join("\n");
+        r.sse_events.push(match event {
+            Some(name) => format!("event: {}\n{}", name, data),
+            None => data,
+        });
+    });
+}
+
+/// 开关调试模式（应用启动时同步一次，之后每次修改再调用）；关闭时清空记录
+#[tauri::command]
+pub fn set_llm_debug_mode(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        RECORDS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+    log::info!("LLM debug mode {}", if enabled { "enabled" } else { "disabled" });
+}
+
+/// 获取最近的请求调试记录（从旧到新）
+#[tauri::command]
+pub fn get_llm_debug_log() -> Vec<LlmDebugRecord> {
+    RECORDS.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+}
+
+/// 清空调试记录
+#[tauri::command]
+pub fn clear_llm_debug_log() {
+    RECORDS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn secrets_in_headers_and_query_are_redacted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer sk-1234567890abcd"));
+        headers.insert("x-goog-api-key", HeaderValue::from_static("short"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let redacted = redact_headers(&headers);
+        assert_eq!(redacted["authorization"], "***abcd");
+        assert_eq!(redacted["x-goog-api-key"], "***");
+        assert_eq!(redacted["content-type"], "application/json");
+
+        assert_eq!(
+            redact_url("https://gw.example.com/v1/chat?alt=sse&key=AIzaSyABCDEFGH"),
+            "https://gw.example.com/v1/chat?alt=sse&key=***EFGH"
+        );
+        assert_eq!(redact_url("https://api.openai.com/v1/chat/completions"), "https://api.openai.com/v1/chat/completions");
+    }
+}
@@ -18,6 +18,9 @@
  */
 
 use crate::commands::constants::{MCP_HTTP_TIMEOUT, MCP_STDIO_TIMEOUT, MCP_TOOL_CALL_TIMEOUT};
+use crate::commands::http_client::{shared_client, ClientKind};
+use crate::commands::proxy::MCP_PROXY_SCOPE;
+use crate::commands::web_fetch::fetch_page;
 use crate::db::DbState;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
@@ -44,6 +47,16 @@ static MCP_TOOLS_CACHE: Lazy<Mutex<HashMap<String, (Vec<MCPTool>, Instant)>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 const MCP_TOOLS_CACHE_TTL: Duration = Duration::from_secs(300);
 
+/// MCP HTTP/SSE 传输和内置联网工具共用的客户端：取自共享连接池，套用 "mcp"
+/// 作用域的代理设置。代理设置已在 set_proxy_settings 里校验过，构建失败只可能
+/// 是 TLS 后端初始化出错，这时退回不带代理的默认客户端，保持原有行为。
+fn mcp_http_client(url: &str) -> reqwest::Client {
+    shared_client(ClientKind::General, Some(MCP_PROXY_SCOPE), url).unwrap_or_else(|e| {
+        log::warn!("Failed to build proxied MCP HTTP client, using default: {}", e);
+        reqwest::Client::new()
+    })
+}
+
 /// MCP 错误类型
 #[derive(Error, Debug)]
 pub enum MCPError {
@@ -225,10 +238,11 @@ pub async fn create_mcp_server(
     config.updated_at = chrono::Utc::now().timestamp_millis();
 
     // 保存到数据库
-    let db = state.0.lock().await;
-    db.save_mcp_server(&config)
+    let saved = config.clone();
+    state
+        .run(move |db| db.save_mcp_server(&saved).map_err(|e| e.to_string()))
+        .await
         .map_err(|e| { log::error!("保存 MCP 服务器配置失败（详情：{}）", e); MCPError::CommunicationError("保存 MCP 服务器配置失败，请重试".to_string()) })?;
-    drop(db);
 
     // 服务器的 command/args/url 刚刚可能发生了变化 -- 清掉对应的工具列表缓存，
     // 让下一次查询重新发现，而不是继续返回过期数据。
@@ -248,13 +262,17 @@ pub async fn create_mcp_server(
     Ok(config)
 }
 
+/// 从数据库读取全部 MCP 服务器配置
+async fn load_mcp_servers(state: &DbState) -> Result<Vec<MCPServer>, MCPError> {
+    state
+        .run(|db| db.get_mcp_servers().map_err(|e| MCPError::CommunicationError(e.to_string())))
+        .await
+}
+
 /// 获取 MCP 服务器列表
 #[tauri::command]
 pub async fn list_mcp_servers(state: tauri::State<'_, DbState>) -> Result<Vec<MCPServer>, MCPError> {
-    let db = state.0.lock().await;
-    let servers = db
-        .get_mcp_servers()
-        .map_err(|e| MCPError::CommunicationError(e.to_string()))?;
+    let servers = load_mcp_servers(&state).await?;
     log::info!("Retrieved {} MCP servers", servers.len());
     Ok(servers)
 }
@@ -265,10 +283,10 @@ pub async fn delete_mcp_server(
     state: tauri::State<'_, DbState>,
     server_id: String
 ) -> Result<(), MCPError> {
-    let db = state.0.lock().await;
-    db.delete_mcp_server(&server_id)
-        .map_err(|e| MCPError::CommunicationError(e.to_string()))?;
-    drop(db);
+    let id = server_id.clone();
+    state
+        .run(move |db| db.delete_mcp_server(&id).map_err(|e| MCPError::CommunicationError(e.to_string())))
+        .await?;
     MCP_TOOLS_CACHE.lock().await.remove(&server_id);
     log::info!("MCP server deleted: {}", server_id);
     Ok(())
@@ -540,7 +558,7 @@ async fn call_mcp_tools_http(server: &MCPServer) -> Result<Vec<MCPTool>, MCPErro
         id: Uuid::new_v4().to_string(),
     };
 
-    let client = reqwest::Client::new();
+    let client = mcp_http_client(url);
     let mut req_builder = client.post(url).json(&request);
     if let Some(api_key) = &server.api_key {
         req_builder = req_builder.header("Authorization", format!("Bearer {}", api_key));
@@ -585,10 +603,7 @@ pub async fn get_mcp_tools(
     log::info!("Fetching tools from MCP server: {}", server_id);
 
     // 从数据库加载服务器配置
-    let db = state.0.lock().await;
-    let servers = db
-        .get_mcp_servers()
-        .map_err(|e| MCPError::CommunicationError(e.to_string()))?;
+    let servers = load_mcp_servers(&state).await?;
     let server = servers.into_iter().find(|s| s.id == server_id)
         .ok_or_else(|| MCPError::ServerNotFound(server_id.clone()))?;
 
@@ -605,14 +620,8 @@ pub async fn get_mcp_tools(
 pub async fn get_all_mcp_tools(state: tauri::State<'_, DbState>) -> Result<Vec<MCPTool>, MCPError> {
     log::info!("Fetching all available MCP tools");
 
-    // 用作用域限定，确保下面并发拉取之前先释放数据库锁。
-    let enabled_servers: Vec<_> = {
-        let db = state.0.lock().await;
-        let servers = db
-            .get_mcp_servers()
-            .map_err(|e| MCPError::CommunicationError(e.to_string()))?;
-        servers.into_iter().filter(|s| s.enabled).collect()
-    };
+    // 先读出配置、释放数据库锁，再并发拉取。
+    let enabled_servers: Vec<_> = load_mcp_servers(&state).await?.into_iter().filter(|s| s.enabled).collect();
 
     // 并发拉取所有服务器，而不是一个个串行处理 -- 串行循环意味着 N 个服务器
     // 各自的最坏延迟会依次叠加，服务器一多耗时就会迅速累积。
@@ -677,11 +686,7 @@ pub async fn call_mcp_tool(
     }
 
     // 从数据库加载服务器配置
-    let servers = {
-        let db = state.0.lock().await;
-        db.get_mcp_servers()
-            .map_err(|e| MCPError::CommunicationError(e.to_string()))?
-    };
+    let servers = load_mcp_servers(&state).await?;
 
     let target_server = if let Some(server_id) = server_id {
         servers
@@ -814,7 +819,7 @@ fn builtin_tool_defs() -> Vec<MCPTool> {
             server_id: "builtin".to_string(),
             server_name: "内置工具".to_string(),
             name: "builtin__fetch_url".to_string(),
-            description: "抓取指定网页并提取正文文本。内置能力，无需安装任何依赖。".to_string(),
+            description: "抓取指定网页并提取正文文本（只能访问公网地址，正文过长会截断）。内置能力，无需安装任何依赖。".to_string(),
             input_schema: serde_json::json!({
                 "type": "object",
                 "properties": {
@@ -826,8 +831,7 @@ fn builtin_tool_defs() -> Vec<MCPTool> {
     ]
 }
 
-const BUILTIN_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
-const BUILTIN_FETCH_TEXT_LIMIT: usize = 15_000;
+pub(crate) const BUILTIN_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
 
 /// 直接执行某个内置工具 -- 不起子进程，不走外部 MCP 传输协议，
 /// 就是进程内一次普通的 HTTP 调用。
@@ -852,7 +856,7 @@ async fn builtin_web_search(input: serde_json::Value) -> Result<serde_json::Valu
 
     let url = format!("https://html.duckduckgo.com/html/?q={}", urlencoding::encode(query));
 
-    let client = reqwest::Client::new();
+    let client = mcp_http_client(&url);
     let response = tokio::time::timeout(
         MCP_HTTP_TIMEOUT,
         client.get(&url).header("User-Agent", BUILTIN_USER_AGENT).send(),
@@ -912,55 +916,13 @@ async fn builtin_fetch_url(input: serde_json::Value) -> Result<serde_json::Value
         .and_then(|v| v.as_str())
         .ok_or_else(|| MCPError::InvalidConfig("fetch_url requires a 'url' string".to_string()))?;
 
-    let client = reqwest::Client::new();
-    let response = tokio::time::timeout(
-        MCP_HTTP_TIMEOUT,
-        client.get(url).header("User-Agent", BUILTIN_USER_AGENT).send(),
-    )
-    .await
-    .map_err(|_| MCPError::CommunicationError("网页抓取超时".to_string()))?
-    .map_err(|e| MCPError::CommunicationError(format!("网页抓取失败: {}", e)))?;
-
-    if !response.status().is_success() {
-        return Err(MCPError::CommunicationError(format!("网页抓取失败: HTTP {}", response.status())));
-    }
-
-    let html = response
-        .text()
-        .await
-        .map_err(|e| MCPError::CommunicationError(format!("读取网页内容失败: {}", e)))?;
-
-    let document = scraper::Html::parse_document(&html);
-    // 只从块级内容标签里取文本，而不是整个文档 -- 真正的正文内容几乎总是在
-    // 这些标签里，而导航栏/脚本/样式/页脚这些界面元素不会用它们，这样就不用
-    // 再显式地把它们过滤掉。
-    let content_selector = scraper::Selector::parse(
-        "h1, h2, h3, h4, h5, h6, p, li, td, th, blockquote, pre, article, dd, dt"
-    ).unwrap();
-
-    let mut text = String::new();
-    for el in document.select(&content_selector) {
-        let line: String = el.text().collect::<String>().trim().to_string();
-        if !line.is_empty() {
-            text.push_str(&line);
-            text.push('\n');
-        }
-    }
-
-    if text.trim().is_empty() {
-        // 兜底方案：如果页面没有用语义化的块级标签，就整个 body 一起取。
-        if let Some(body) = document.select(&scraper::Selector::parse("body").unwrap()).next() {
-            text = body.text().collect::<Vec<_>>().join(" ");
-        }
-    }
-
-    let mut text = text.trim().to_string();
-    let truncated = text.chars().count() > BUILTIN_FETCH_TEXT_LIMIT;
-    if truncated {
-        text = text.chars().take(BUILTIN_FETCH_TEXT_LIMIT).collect();
-    }
-
-    Ok(serde_json::json!({ "url": url, "content": text, "truncated": truncated }))
+    let page = fetch_page(url).await.map_err(MCPError::CommunicationError)?;
+    Ok(serde_json::json!({
+        "url": page.url,
+        "title": page.title,
+        "content": page.content,
+        "truncated": page.truncated,
+    }))
 }
 
 /// 通过 Stdio 调用 MCP 工具（JSON-RPC 通过 stdin/stdout 传输）
@@ -1090,7 +1052,7 @@ async fn call_mcp_tool_http(
     };
 
     // 创建 HTTP 客户端
-    let client = reqwest::Client::new();
+    let client = mcp_http_client(url);
     let mut req_builder = client.post(url);
 
     // 如果提供了 API 密钥，加上认证头
@@ -1205,7 +1167,7 @@ pub async fn test_mcp_connection(
         "sse" | "http" => {
             if let Some(url) = url {
                 // 尝试向服务器发起 HTTP 请求
-                match reqwest::Client::new().get(&url).send().await {
+                match mcp_http_client(&url).get(&url).send().await {
                     Ok(resp) => {
                         log::info!("MCP test connection to '{}' returned status {}", url, resp.status());
                         let status = resp.status();
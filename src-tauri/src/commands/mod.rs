@@ -6,19 +6,65 @@
  * 命令模块
  * 
  * 模块说明:
+ * - arena: 多模型对比 (同一提问并发发给 2~4 个模型)
+ * - backup: 数据库一键备份和恢复 (SQLite 在线备份，带时间戳的 zip)
+ * - bookmarks: 消息收藏和收藏夹
+ * - http_client: 共享的 reqwest 客户端 (连接池、TLS、UA、代理统一配置)
+ * - import: 从 ChatGPT / Claude 的导出文件导入聊天记录
+ * - key_rotation: 同一配置多个 API 密钥的轮换 (轮询 / 429 时切换)
  * - llm: LLM 聊天相关命令 (流式消息、对话管理)
+ * - llm_debug: 可选的请求/响应调试日志 (最近 N 次请求，密钥打码)
  * - mcp: MCP 服务器相关命令 (工具调用、服务器管理)
  * - constants: 超时和延迟常量
+ * - context_window: 模型上下文窗口表和超长对话的自动裁剪
+ * - export: 会话导出 (Markdown / JSON / HTML，单个或全部)
  * - local_model: 本地模型管理命令 (Ollama 集成)
  * - lmstudio: 本地模型管理命令 (LM Studio 集成)
  * - skills: Skill (技能) 管理命令
+ * - personas: 角色预设 (system prompt + 默认参数) 管理命令
+ * - pricing: 模型参考价格表和用量费用估算
+ * - proxy: 全局 / 按 provider 的 HTTP、SOCKS 代理设置
+ * - retention: 旧数据自动清理策略 (会话自动归档、回收站保留天数、备份占用空间上限)
+ * - rate_limit: 按 provider 的限流状态 (Retry-After 排队等待)
+ * - provider_models: 获取各 provider 的可用模型列表
+ * - sse: 流式回复的增量 SSE 解码 (UTF-8 / 事件边界安全)
+ * - stats: 聊天统计 (按服务商 / 模型 / 天汇总会话数、消息数和回复长度)
+ * - stream_queue: 按 provider 限制同时进行的流式回复数 (超出排队并报告位置)
+ * - summarizer: 长会话的滚动摘要 (后台压缩较早的对话轮次)
+ * - sync: 多设备同步 (加密快照放到 WebDAV / S3，按修改时间合并)
+ * - trash: 会话和消息回收站 (删除后可恢复，过期自动清除)
+ * - tts: 语音合成 (OpenAI TTS / edge-tts)
+ * - web_fetch: 网页抓取和正文提取 (限制大小、禁止访问本机和内网)
  */
 
 pub mod app_update;
+pub mod arena;
+pub mod backup;
+pub mod bookmarks;
 pub mod constants;
+pub mod context_window;
 pub mod docker;
+pub mod export;
+pub mod http_client;
+pub mod import;
+pub mod key_rotation;
 pub mod llm;
+pub mod llm_debug;
 pub mod lmstudio;
 pub mod local_model;
 pub mod mcp;
-pub mod skills;
\ No newline at end of file
+pub mod personas;
+pub mod pricing;
+pub mod provider_models;
+pub mod proxy;
+pub mod rate_limit;
+pub mod retention;
+pub mod skills;
+pub mod sse;
+pub mod stats;
+pub mod stream_queue;
+pub mod summarizer;
+pub mod sync;
+pub mod trash;
+pub mod tts;
+pub mod web_fetch;
@@ -0,0 +1,104 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 角色预设 (Persona) 模块
+ *
+ * 功能说明:
+ * - 角色预设的增删改查：名称 + system prompt + 默认参数
+ * - 给会话绑定/解绑角色预设
+ * - 发送消息时由 stream_message 把预设的 system prompt 合并进 system 消息，
+ *   默认参数只在请求本身没指定时生效
+ */
+
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// 一个角色预设
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Persona {
+    pub id: String,
+    pub name: String,
+    /// 作为 system 消息注入对话的提示词
+    pub system_prompt: String,
+    /// 默认最大输出 token 数（请求里没填时使用）
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// 默认采样温度（None 时不发送该字段，由服务商决定）
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+/// 新建或更新角色预设（id 为空时视为新建）
+#[tauri::command]
+pub async fn save_persona(
+    state: tauri::State<'_, DbState>,
+    persona: Persona,
+) -> Result<Persona, String> {
+    if persona.name.trim().is_empty() {
+        return Err("角色名称不能为空".to_string());
+    }
+
+    let mut persona = persona;
+    if persona.id.is_empty() {
+        persona.id = Uuid::new_v4().to_string();
+        persona.created_at = chrono::Utc::now().timestamp_millis();
+    }
+    persona.updated_at = chrono::Utc::now().timestamp_millis();
+
+    state
+        .run(move |db| {
+            db.save_persona(&persona)
+                .map_err(|e| friendly_err("保存角色失败，请重试", e))?;
+            Ok(persona)
+        })
+        .await
+}
+
+/// 获取所有角色预设
+#[tauri::command]
+pub async fn list_personas(state: tauri::State<'_, DbState>) -> Result<Vec<Persona>, String> {
+    state
+        .run(|db| {
+            db.get_personas()
+                .map_err(|e| friendly_err("获取角色列表失败，请重试", e))
+        })
+        .await
+}
+
+/// 删除角色预设（已绑定的会话自动解绑）
+#[tauri::command]
+pub async fn delete_persona(
+    state: tauri::State<'_, DbState>,
+    persona_id: String,
+) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.delete_persona(&persona_id)
+                .map_err(|e| friendly_err("删除角色失败，请重试", e))
+        })
+        .await
+}
+
+/// 给会话绑定角色预设；persona_id 为 None 时解除绑定
+#[tauri::command]
+pub async fn set_session_persona(
+    state: tauri::State<'_, DbState>,
+    session_id: String,
+    persona_id: Option<String>,
+) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.set_session_persona(&session_id, persona_id.as_deref())
+                .map_err(|e| friendly_err("设置会话角色失败，请确认会话已保存", e))
+        })
+        .await
+}
@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 费用估算模块
+ *
+ * 功能说明:
+ * - 按 provider/模型维护一张每百万 token 的参考价格表 (美元)
+ * - 每次流式回复结束后记录本条消息的 token 用量和估算费用
+ * - 提供会话累计费用、当月累计花费的查询命令
+ *
+ * 价格只是参考值：服务商调价、缓存命中折扣、阶梯计价都不在考虑范围内，
+ * 前端展示时应标明"估算"。
+ */
+
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+
+/// 模型参考价格表
+/// 格式: (提供商标识符, 模型名前缀, 输入价格, 输出价格)，价格单位为美元 / 百万 token
+///
+/// 按模型名前缀匹配，取最长的那条——所以 "gpt-4o-mini" 必须能压过 "gpt-4o"。
+/// 国内服务商官方按人民币计价，这里按近似汇率折成美元，便于统一累计。
+const MODEL_PRICING: &[(&str, &str, f64, f64)] = &[
+    ("openai", "gpt-5-nano", 0.05, 0.40),
+    ("openai", "gpt-5-mini", 0.25, 2.00),
+    ("openai", "gpt-5", 1.25, 10.00),
+    ("openai", "gpt-4.1-nano", 0.10, 0.40),
+    ("openai", "gpt-4.1-mini", 0.40, 1.60),
+    ("openai", "gpt-4.1", 2.00, 8.00),
+    ("openai", "gpt-4o-mini", 0.15, 0.60),
+    ("openai", "gpt-4o", 2.50, 10.00),
+    ("openai", "o4-mini", 1.10, 4.40),
+    ("openai", "o3-mini", 1.10, 4.40),
+    ("openai", "o3-pro", 20.00, 80.00),
+    ("openai", "o3", 2.00, 8.00),
+    ("openai", "o1-mini", 1.10, 4.40),
+    ("openai", "o1-pro", 150.00, 600.00),
+    ("openai", "o1", 15.00, 60.00),
+    ("anthropic", "claude-opus-4", 15.00, 75.00),
+    ("anthropic", "claude-sonnet-4", 3.00, 15.00),
+    ("anthropic", "claude-3-7-sonnet", 3.00, 15.00),
+    ("anthropic", "claude-3-5-sonnet", 3.00, 15.00),
+    ("anthropic", "claude-haiku-4", 1.00, 5.00),
+    ("anthropic", "claude-3-5-haiku", 0.80, 4.00),
+    ("anthropic", "claude-3-haiku", 0.25, 1.25),
+    ("google", "gemini-2.5-pro", 1.25, 10.00),
+    ("google", "gemini-2.5-flash-lite", 0.10, 0.40),
+    ("google", "gemini-2.5-flash", 0.30, 2.50),
+    ("google", "gemini-2.0-flash-lite", 0.075, 0.30),
+    ("google", "gemini-2.0-flash", 0.10, 0.40),
+    ("mistral", "mistral-large", 2.00, 6.00),
+    ("mistral", "mistral-medium", 0.40, 2.00),
+    ("mistral", "mistral-small", 0.10, 0.30),
+    ("mistral", "codestral", 0.30, 0.90),
+    ("deepseek", "deepseek-chat", 0.27, 1.10),
+    ("deepseek", "deepseek-reasoner", 0.55, 2.19),
+    ("moonshot", "kimi-k2", 0.55, 2.20),
+    ("moonshot", "moonshot-v1", 1.65, 1.65),
+    ("zhipu", "glm-4.5-air", 0.11, 0.83),
+    ("zhipu", "glm-4.5", 0.28, 1.11),
+    ("zhipu", "glm-4-flash", 0.0, 0.0),
+    ("aliyun", "qwen-max", 0.33, 1.33),
+    ("aliyun", "qwen-plus", 0.11, 0.28),
+    ("aliyun", "qwen-turbo", 0.04, 0.08),
+    ("doubao", "doubao-seed-1-6", 0.11, 1.11),
+    ("minimax", "minimax-m1", 0.55, 2.20),
+    ("yi", "yi-lightning", 0.14, 0.14),
+];
+
+/// 不计费的 provider：本地推理或用户自建网关，费用恒为 0
+const FREE_PROVIDERS: &[&str] = &["local", "openclaw"];
+
+/// 单条消息的用量记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageUsage {
+    /// 消息 ID（即 save_message 存下的回复消息 ID；多模型对比的各栏为后端生成的 ID）
+    pub message_id: String,
+    /// 会话 ID
+    pub session_id: String,
+    /// LLM 提供商
+    pub provider: String,
+    /// 模型名称
+    pub model: String,
+    /// 输入 token 数
+    pub input_tokens: i64,
+    /// 输出 token 数（含思考过程）
+    pub output_tokens: i64,
+    /// 估算费用 (美元)；价格表里查不到该模型时为 None
+    pub cost_usd: Option<f64>,
+    /// 记录时间戳 (毫秒)
+    pub created_at: i64,
+}
+
+/// 随最后一个 `done: true` 数据块一起发出的用量/费用事件
+#[derive(Clone, Serialize)]
+pub struct UsageCostEvent {
+    /// 会话 ID
+    pub session_id: String,
+    /// 消息 ID
+    pub message_id: String,
+    /// 本条回复的输入 token 数
+    pub input_tokens: i64,
+    /// 本条回复的输出 token 数
+    pub output_tokens: i64,
+    /// 本条回复的估算费用；未知模型为 None
+    pub cost_usd: Option<f64>,
+    /// 该会话累计估算费用
+    pub session_cost_usd: f64,
+    /// 本月累计估算花费
+    pub monthly_cost_usd: f64,
+    /// token 数是否为估算值（false 表示来自服务商在流里报告的 usage）
+    pub estimated: bool,
+}
+
+/// 费用汇总（`get_cost_summary` 的返回值）
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CostSummary {
+    /// 会话 ID（未指定会话时为 None）
+    pub session_id: Option<String>,
+    /// 会话累计输入 token 数
+    pub session_input_tokens: i64,
+    /// 会话累计输出 token 数
+    pub session_output_tokens: i64,
+    /// 会话累计估算费用 (美元)
+    pub session_cost_usd: f64,
+    /// 会话中价格未知、未计入费用的消息条数
+    pub unpriced_messages: i64,
+    /// 统计月份，格式 "YYYY-MM"（本地时间）
+    pub month: String,
+    /// 本月累计估算花费 (美元)
+    pub monthly_cost_usd: f64,
+}
+
+/// 查找模型的参考价格，返回 (输入, 输出) 美元 / 百万 token
+///
+/// custom/siliconflow 这类聚合或自定义端点没有自己的价格条目，但用户往往
+/// 直接填官方模型名，这时退而按模型名在整张表里找。
+pub fn lookup_pricing(provider: &str, model: &str) -> Option<(f64, f64)> {
+    if FREE_PROVIDERS.contains(&provider) {
+        return Some((0.0, 0.0));
+    }
+
+    let model = model.to_lowercase();
+    // 带命名空间的模型名（"deepseek-ai/DeepSeek-V3"、"models/gemini-2.5-pro"）只看最后一段
+    let model = model.rsplit('/').next().unwrap_or(&model);
+
+    let best_match = |same_provider_only: bool| {
+        MODEL_PRICING
+            .iter()
+            .filter(|(p, prefix, _, _)| (!same_provider_only || *p == provider) && model.starts_with(prefix))
+            .max_by_key(|(_, prefix, _, _)| prefix.len())
+            .map(|(_, _, input, output)| (*input, *output))
+    };
+
+    best_match(true).or_else(|| best_match(false))
+}
+
+/// 按参考价格估算一次调用的费用 (美元)
+pub fn estimate_cost(provider: &str, model: &str, input_tokens: i64, output_tokens: i64) -> Option<f64> {
+    let (input_price, output_price) = lookup_pricing(provider, model)?;
+    Some((input_tokens as f64 * input_price + output_tokens as f64 * output_price) / 1_000_000.0)
+}
+
+/// 本地时间当月 1 日零点的毫秒时间戳和 "YYYY-MM" 月份标签
+pub fn current_month_start() -> (i64, String) {
+    use chrono::{Datelike, Local, TimeZone};
+    let now = Local::now();
+    let start = Local
+        .with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .earliest()
+        .map(|dt| dt.timestamp_millis())
+        .unwrap_or(0);
+    (start, format!("{:04}-{:02}", now.year(), now.month()))
+}
+
+/// 获取会话累计费用和本月累计花费
+///
+/// @param session_id: 会话 ID；为空时只返回本月累计
+#[tauri::command]
+pub async fn get_cost_summary(
+    session_id: Option<String>,
+    state: tauri::State<'_, DbState>,
+) -> Result<CostSummary, String> {
+    state
+        .run(move |db| {
+            let (month_start, month) = current_month_start();
+            let monthly_cost_usd = db
+                .get_usage_cost_since(month_start)
+                .map_err(|e| friendly_err("读取费用统计失败", e))?;
+
+            let (session_input_tokens, session_output_tokens, session_cost_usd, unpriced_messages) = match &session_id {
+                Some(sid) => db
+                    .get_session_usage(sid)
+                    .map_err(|e| friendly_err("读取会话费用失败", e))?,
+                None => (0, 0, 0.0, 0),
+            };
+
+            Ok(CostSummary {
+                session_id,
+                session_input_tokens,
+                session_output_tokens,
+                session_cost_usd,
+                unpriced_messages,
+                month,
+                monthly_cost_usd,
+            })
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins_within_provider() {
+        assert_eq!(lookup_pricing("openai", "gpt-4o-mini-2024-07-18"), Some((0.15, 0.60)));
+        assert_eq!(lookup_pricing("openai", "gpt-4o-2024-08-06"), Some((2.50, 10.00)));
+    }
+
+    #[test]
+    fn o1_variants_are_not_priced_as_o1() {
+        assert_eq!(lookup_pricing("openai", "o1-mini-2024-09-12"), Some((1.10, 4.40)));
+        assert_eq!(lookup_pricing("openai", "o1-pro"), Some((150.00, 600.00)));
+        assert_eq!(lookup_pricing("openai", "o1-preview"), Some((15.00, 60.00)));
+        assert_eq!(lookup_pricing("openai", "o1-2024-12-17"), Some((15.00, 60.00)));
+        assert_eq!(lookup_pricing("openai", "o3-pro"), Some((20.00, 80.00)));
+    }
+
+    #[test]
+    fn custom_provider_falls_back_to_model_name_across_table() {
+        assert_eq!(lookup_pricing("custom", "claude-sonnet-4-20250514"), Some((3.00, 15.00)));
+        assert_eq!(lookup_pricing("siliconflow", "deepseek-ai/deepseek-chat"), Some((0.27, 1.10)));
+    }
+
+    #[test]
+    fn local_providers_are_free_and_unknown_models_unpriced() {
+        assert_eq!(estimate_cost("local", "qwen3:8b", 1000, 1000), Some(0.0));
+        assert_eq!(estimate_cost("openai", "some-unknown-model", 1000, 1000), None);
+    }
+
+    #[test]
+    fn estimate_cost_is_per_million_tokens() {
+        let cost = estimate_cost("anthropic", "claude-sonnet-4-5", 1_000_000, 100_000).unwrap();
+        assert!((cost - 4.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 模型列表模块
+ *
+ * 功能说明:
+ * - 调用各 provider 的 /models 端点获取可用模型，省去手动输入模型名
+ * - 结果按 (provider, base_url) 缓存一段时间，切换设置页不会反复请求
+ * - 请求失败（无密钥、网络不通、端点不支持）时退回内置的静态列表
+ */
+
+use crate::commands::llm::{build_headers, build_url, create_http_client};
+use crate::commands::local_model::friendly_err;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// 远程模型列表的缓存有效期
+const MODEL_LIST_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// 内置的静态模型列表：远程获取失败时的兜底，也是 azure/minimax 这类
+/// 没有可用 /models 端点的 provider 的唯一来源
+const STATIC_MODELS: &[(&str, &[&str])] = &[
+    ("openai", &["gpt-5", "gpt-5-mini", "gpt-4.1", "gpt-4.1-mini", "gpt-4o", "gpt-4o-mini", "o3", "o4-mini"]),
+    ("anthropic", &["claude-opus-4-1", "claude-sonnet-4-5", "claude-sonnet-4-0", "claude-3-5-haiku-latest"]),
+    ("google", &["gemini-2.5-pro", "gemini-2.5-flash", "gemini-2.5-flash-lite", "gemini-2.0-flash"]),
+    ("mistral", &["mistral-large-latest", "mistral-medium-latest", "mistral-small-latest", "pixtral-large-latest", "codestral-latest"]),
+    ("moonshot", &["kimi-k2-0905-preview", "moonshot-v1-8k", "moonshot-v1-32k", "moonshot-v1-128k"]),
+    ("zhipu", &["glm-4.5", "glm-4.5-air", "glm-4-flash", "glm-4v-plus"]),
+    ("aliyun", &["qwen-max", "qwen-plus", "qwen-turbo", "qwen-vl-max"]),
+    ("baidu", &["ernie-4.5-turbo-128k", "ernie-x1-turbo-32k", "ernie-speed-128k"]),
+    ("doubao", &["doubao-seed-1-6-250615", "doubao-seed-1-6-flash-250615", "doubao-1-5-pro-32k-250115"]),
+    ("deepseek", &["deepseek-chat", "deepseek-reasoner"]),
+    ("siliconflow", &["deepseek-ai/DeepSeek-V3", "deepseek-ai/DeepSeek-R1", "Qwen/Qwen3-235B-A22B", "Qwen/Qwen2.5-72B-Instruct"]),
+    ("minimax", &["MiniMax-M1", "MiniMax-Text-01"]),
+    ("yi", &["yi-lightning", "yi-vision-v2"]),
+];
+
+/// 缓存键为 "provider|base_url"，值为 (获取时间, 模型列表)
+type ModelListCache = HashMap<String, (Instant, Vec<String>)>;
+
+static MODEL_LIST_CACHE: Lazy<Mutex<ModelListCache>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// `list_provider_models` 的返回值
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderModelList {
+    /// 模型 ID 列表
+    pub models: Vec<String>,
+    /// 数据来源："remote" | "cache" | "static"
+    pub source: String,
+    /// 远程获取失败时的原因（此时 models 为静态列表）
+    pub error: Option<String>,
+}
+
+fn static_models(provider: &str) -> Vec<String> {
+    STATIC_MODELS
+        .iter()
+        .find(|(p, _)| *p == provider)
+        .map(|(_, models)| models.iter().map(|m| m.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// 推导 provider 的模型列表端点。OpenAI 兼容的 provider 都是把对话端点的
+/// `/chat/completions` 换成 `/models`；返回 None 表示该 provider 不支持。
+fn models_url(provider: &str, base_url: &str) -> Option<String> {
+    match provider {
+        "anthropic" => Some("https://api.anthropic.com/v1/models".to_string()),
+        "google" => Some("https://generativelanguage.googleapis.com/v1beta/models?pageSize=1000".to_string()),
+        // Azure 的部署名是用户自己起的，列不出来；MiniMax 没有公开的模型列表端点
+        "azure" | "minimax" => None,
+        _ => {
+            let chat_url = build_url(provider, base_url, "", true);
+            chat_url
+                .strip_suffix("/chat/completions")
+                .filter(|base| !base.is_empty())
+                .map(|base| format!("{}/models", base))
+        }
+    }
+}
+
+/// 从 /models 响应里取出模型 ID。OpenAI 兼容格式和 Anthropic 都是
+/// `data[].id`；Google 是 `models[].name`（带 "models/" 前缀），并且要过滤掉
+/// 不支持 generateContent 的嵌入模型等。
+fn parse_model_ids(provider: &str, json: &serde_json::Value) -> Vec<String> {
+    let mut ids: Vec<String> = if provider == "google" {
+        json["models"]
+            .as_array()
+            .map(|models| {
+                models
+                    .iter()
+                    .filter(|m| {
+                        m["supportedGenerationMethods"]
+                            .as_array()
+                            .map(|methods| methods.iter().any(|v| v == "generateContent"))
+                            .unwrap_or(true)
+                    })
+                    .filter_map(|m| m["name"].as_str())
+                    .map(|name| name.trim_start_matches("models/").to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    } else {
+        json["data"]
+            .as_array()
+            .map(|models| models.iter().filter_map(|m| m["id"].as_str()).map(|id| id.to_string()).collect())
+            .unwrap_or_default()
+    };
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+async fn fetch_remote_models(provider: &str, base_url: &str, api_key: &str) -> Result<Vec<String>, String> {
+    let url = models_url(provider, base_url).ok_or_else(|| "该服务商不支持获取模型列表".to_string())?;
+    let client = create_http_client(provider, &url).map_err(|e| friendly_err("创建网络连接失败，请重启应用后重试", e))?;
+    let mut headers = build_headers(provider, api_key, None);
+    headers.insert(reqwest::header::ACCEPT, "application/json".parse().unwrap());
+
+    let response = client
+        .get(&url)
+        .headers(headers)
+        .send()
+        .await
+        .map_err(|e| friendly_err("无法连接服务商获取模型列表，请检查网络和接口地址", e))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(friendly_err("获取模型列表失败，请检查 API 密钥", format!("{} {}", status, body)));
+    }
+    let json: serde_json::Value = response
+        .json()
+        .await
+        .map_err(|e| friendly_err("模型列表格式无法识别", e))?;
+    let ids = parse_model_ids(provider, &json);
+    if ids.is_empty() {
+        return Err("服务商返回的模型列表为空".to_string());
+    }
+    Ok(ids)
+}
+
+/// 获取 provider 的可用模型列表
+///
+/// @param provider: 提供商标识符
+/// @param base_url: 自定义/本地 provider 的接口地址
+/// @param api_key: API 密钥；为空时按 api_config_id 去 keyring 查
+/// @param api_config_id: API 配置 ID
+/// @param force_refresh: 跳过缓存重新请求
+#[tauri::command]
+pub async fn list_provider_models(
+    provider: String,
+    base_url: Option<String>,
+    api_key: Option<String>,
+    api_config_id: Option<String>,
+    force_refresh: Option<bool>,
+) -> Result<ProviderModelList, String> {
+    let base_url = base_url.unwrap_or_default();
+    let cache_key = format!("{}|{}", provider, base_url.trim_end_matches('/'));
+
+    if !force_refresh.unwrap_or(false) {
+        if let Some((fetched_at, models)) = MODEL_LIST_CACHE.lock().await.get(&cache_key) {
+            if fetched_at.elapsed() < MODEL_LIST_CACHE_TTL {
+                return Ok(ProviderModelList { models: models.clone(), source: "cache".to_string(), error: None });
+            }
+        }
+    }
+
+    let api_key = match api_key.filter(|k| !k.is_empty()) {
+        Some(key) => key,
+        None => api_config_id
+            .and_then(|id| crate::secure_storage::get_api_key(id).ok().flatten())
+            .unwrap_or_default(),
+    };
+
+    match fetch_remote_models(&provider, &base_url, &api_key).await {
+        Ok(models) => {
+            log::info!("[Models] fetched {} models for provider {}", models.len(), provider);
+            MODEL_LIST_CACHE
+                .lock()
+                .await
+                .insert(cache_key, (Instant::now(), models.clone()));
+            Ok(ProviderModelList { models, source: "remote".to_string(), error: None })
+        }
+        Err(e) => Ok(ProviderModelList {
+            models: static_models(&provider),
+            source: "static".to_string(),
+            error: Some(e),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_url_swaps_chat_completions_for_models() {
+        assert_eq!(models_url("deepseek", "").as_deref(), Some("https://api.deepseek.com/v1/models"));
+        assert_eq!(models_url("local", "http://localhost:11434/v1/").as_deref(), Some("http://localhost:11434/v1/models"));
+        assert_eq!(models_url("azure", "https://x.openai.azure.com/openai/deployments/"), None);
+    }
+
+    #[test]
+    fn google_model_ids_drop_prefix_and_non_chat_models() {
+        let json = serde_json::json!({ "models": [
+            { "name": "models/gemini-2.5-pro", "supportedGenerationMethods": ["generateContent", "countTokens"] },
+            { "name": "models/text-embedding-004", "supportedGenerationMethods": ["embedContent"] },
+        ]});
+        assert_eq!(parse_model_ids("google", &json), vec!["gemini-2.5-pro".to_string()]);
+    }
+
+    #[test]
+    fn openai_compatible_model_ids_are_sorted_and_deduplicated() {
+        let json = serde_json::json!({ "data": [{ "id": "b" }, { "id": "a" }, { "id": "b" }] });
+        assert_eq!(parse_model_ids("openai", &json), vec!["a".to_string(), "b".to_string()]);
+    }
+}
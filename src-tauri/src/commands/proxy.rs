@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 网络代理模块
+ *
+ * 功能说明:
+ * - 保存前端同步过来的代理设置（全局代理 + 按 provider 单独指定的代理）
+ * - 支持 http://、https://、socks5://、socks5h:// 代理地址
+ * - 决定每个请求走哪个代理，由 http_client 模块套用到共享的 reqwest 客户端上
+ *
+ * 设置跟"最小化到托盘"一样由前端持久化，应用启动时通过 set_proxy_settings
+ * 同步一次；没有同步之前（或未启用时）沿用 reqwest 默认行为，即读取系统的
+ * HTTP_PROXY/HTTPS_PROXY 环境变量。
+ */
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// MCP HTTP/SSE 传输和内置联网工具使用的代理作用域
+pub const MCP_PROXY_SCOPE: &str = "mcp";
+
+/// 代理设置
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxySettings {
+    /// 总开关；关闭时不做任何处理
+    pub enabled: bool,
+    /// 全局代理地址，如 "http://127.0.0.1:7890"、"socks5h://127.0.0.1:1080"
+    #[serde(default)]
+    pub global_proxy: Option<String>,
+    /// 按作用域单独指定的代理：键是 provider 标识符（openai/anthropic/...，
+    /// 嵌入请求用嵌入配置的 provider），或 "mcp"；值为空串表示该作用域直连
+    #[serde(default)]
+    pub provider_proxies: HashMap<String, String>,
+}
+
+static PROXY_SETTINGS: Lazy<RwLock<ProxySettings>> = Lazy::new(|| RwLock::new(ProxySettings::default()));
+
+/// 目标是否回环地址 (localhost/127.0.0.1/::1) —— 本地部署的模型服务
+/// (Ollama/LM Studio 等经由 "local"/"custom"/"openclaw" provider 走到这里)
+/// 走这条路径时应绕开系统代理，否则用户为访问境外服务商而开启的全局代理
+/// 会把本该直连本机的请求也绕出去一圈，白白拖慢 TTFT。
+pub(crate) fn is_loopback_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .map(|host| host == "localhost" || host == "127.0.0.1" || host == "::1" || host.starts_with("127."))
+        .unwrap_or(false)
+}
+
+/// 代理设置对某个作用域的决定；共享客户端按它分别缓存
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum ProxyChoice {
+    /// 不干预，沿用 reqwest 默认（系统代理环境变量）
+    Default,
+    /// 强制直连
+    Direct,
+    /// 走指定代理
+    Proxy(String),
+}
+
+fn choose_proxy(settings: &ProxySettings, scope: Option<&str>, url: &str) -> ProxyChoice {
+    if is_loopback_url(url) {
+        return ProxyChoice::Direct;
+    }
+    if !settings.enabled {
+        return ProxyChoice::Default;
+    }
+    if let Some(proxy) = scope.and_then(|s| settings.provider_proxies.get(s)) {
+        return if proxy.trim().is_empty() {
+            ProxyChoice::Direct
+        } else {
+            ProxyChoice::Proxy(proxy.trim().to_string())
+        };
+    }
+    match settings.global_proxy.as_deref().map(str::trim).filter(|p| !p.is_empty()) {
+        Some(proxy) => ProxyChoice::Proxy(proxy.to_string()),
+        None => ProxyChoice::Default,
+    }
+}
+
+/// 按当前代理设置决定某个请求该怎么走
+///
+/// @param scope: 代理作用域（provider 标识符或 "mcp"），None 表示只用全局代理
+/// @param url: 请求目标地址，回环地址一律直连
+pub(crate) fn resolve_proxy(scope: Option<&str>, url: &str) -> ProxyChoice {
+    let settings = PROXY_SETTINGS.read().unwrap_or_else(|e| e.into_inner());
+    choose_proxy(&settings, scope, url)
+}
+
+/// 把代理决定套到 reqwest 客户端构造器上
+pub(crate) fn apply_proxy(builder: reqwest::ClientBuilder, choice: &ProxyChoice) -> reqwest::ClientBuilder {
+    match choice {
+        ProxyChoice::Default => builder,
+        ProxyChoice::Direct => builder.no_proxy(),
+        ProxyChoice::Proxy(proxy_url) => match reqwest::Proxy::all(proxy_url) {
+            Ok(proxy) => builder.proxy(proxy),
+            Err(e) => {
+                // set_proxy_settings 已经校验过，走到这里说明设置被绕过写坏了；
+                // 退回默认行为而不是让所有请求都失败
+                log::warn!("Invalid proxy url '{}', ignored: {}", proxy_url, e);
+                builder
+            }
+        },
+    }
+}
+
+/// 同步代理设置（应用启动时调用一次，之后每次修改再调用）
+#[tauri::command]
+pub fn set_proxy_settings(settings: ProxySettings) -> Result<(), String> {
+    let all_proxies = settings
+        .global_proxy
+        .iter()
+        .chain(settings.provider_proxies.values())
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty());
+    for proxy in all_proxies {
+        reqwest::Proxy::all(proxy).map_err(|e| {
+            log::warn!("Rejected proxy url '{}': {}", proxy, e);
+            format!("代理地址无效：{}（支持 http://、https://、socks5:// 开头的地址）", proxy)
+        })?;
+    }
+
+    log::info!(
+        "Proxy settings updated: enabled={} global={} scoped={}",
+        settings.enabled,
+        settings.global_proxy.as_deref().unwrap_or("<none>"),
+        settings.provider_proxies.len()
+    );
+    *PROXY_SETTINGS.write().unwrap_or_else(|e| e.into_inner()) = settings;
+    Ok(())
+}
+
+/// 读取当前生效的代理设置
+#[tauri::command]
+pub fn get_proxy_settings() -> ProxySettings {
+    PROXY_SETTINGS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ProxySettings {
+        ProxySettings {
+            enabled: true,
+            global_proxy: Some("http://127.0.0.1:7890".into()),
+            provider_proxies: HashMap::from([
+                ("openai".to_string(), "socks5h://10.0.0.2:1080".to_string()),
+                ("deepseek".to_string(), String::new()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn provider_proxy_overrides_global_and_empty_means_direct() {
+        let s = settings();
+        assert_eq!(
+            choose_proxy(&s, Some("openai"), "https://api.openai.com/v1"),
+            ProxyChoice::Proxy("socks5h://10.0.0.2:1080".into())
+        );
+        assert_eq!(choose_proxy(&s, Some("deepseek"), "https://api.deepseek.com"), ProxyChoice::Direct);
+        assert_eq!(
+            choose_proxy(&s, Some("anthropic"), "https://api.anthropic.com"),
+            ProxyChoice::Proxy("http://127.0.0.1:7890".into())
+        );
+    }
+
+    #[test]
+    fn loopback_is_always_direct_and_disabled_settings_are_ignored() {
+        let mut s = settings();
+        assert_eq!(choose_proxy(&s, Some("openai"), "http://localhost:11434/v1"), ProxyChoice::Direct);
+        s.enabled = false;
+        assert_eq!(choose_proxy(&s, Some("openai"), "https://api.openai.com/v1"), ProxyChoice::Default);
+    }
+}
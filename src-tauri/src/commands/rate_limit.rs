@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 限流状态模块
+ *
+ * 功能说明:
+ * - 解析服务商 429/503 响应里的 Retry-After（秒数或 HTTP 日期）和 retry-after-ms
+ * - 按 provider 记录"在此之前不要再发请求"的时间点，新请求先排队等到限流解除
+ * - 等待期间向前端发出 llm-throttle 事件，前端据此提示"正在等待限流解除"，
+ *   而不是让用户直接看到服务商的原始报错
+ *
+ * 限流状态只在内存里，应用重启即清空。
+ */
+
+use crate::commands::constants::LLM_MAX_RETRY_AFTER;
+use crate::commands::llm::LLMError;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+use tokio_util::sync::CancellationToken;
+
+/// 单个 provider 的限流状态
+struct ProviderThrottle {
+    /// 在这个时间点之前不再发新请求
+    until: Instant,
+    /// 触发限流的原因（状态码 + 服务商错误信息摘要）
+    reason: String,
+    /// 正在排队等待的请求数
+    waiting: usize,
+}
+
+static THROTTLES: Lazy<Mutex<HashMap<String, ProviderThrottle>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 限流等待期间用来通知前端、响应取消的上下文
+pub(crate) struct ThrottleContext<'a> {
+    pub app_handle: &'a AppHandle,
+    pub session_id: &'a str,
+    pub cancel_token: Option<&'a CancellationToken>,
+    /// 启用多密钥轮换时按密钥分开记录限流状态（见 key_rotation），None 表示按 provider
+    pub key_scope: Option<String>,
+    /// 还有备用密钥可换：429 时不在当前密钥上重试，直接交给调用方换密钥
+    pub spare_keys: bool,
+}
+
+/// 限流状态变化事件（开始等待 / 等待结束各发一次）
+#[derive(Clone, Serialize)]
+pub struct ThrottleEvent {
+    pub session_id: String,
+    pub provider: String,
+    /// true = 正在等待限流解除，false = 等待结束、请求已发出
+    pub waiting: bool,
+    /// 预计还要等待的秒数
+    pub retry_after_secs: u64,
+    pub reason: String,
+}
+
+/// 当前限流状态（`get_throttle_state` 的返回值）
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleState {
+    /// LLM 提供商
+    pub provider: String,
+    /// 距离限流解除的剩余秒数
+    pub remaining_secs: u64,
+    /// 触发限流的原因
+    pub reason: String,
+    /// 正在排队等待的请求数
+    pub waiting_requests: usize,
+}
+
+/// 从响应头里读出服务商要求的等待时长
+///
+/// 优先读 OpenAI 的 `retry-after-ms`，其次是标准的 `Retry-After`
+/// （整数秒，或者 "Wed, 21 Oct 2015 07:28:00 GMT" 这样的 HTTP 日期）。
+pub(crate) fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return Some(Duration::from_millis(ms.max(0.0) as u64));
+    }
+    let value = header("retry-after")?;
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(Duration::from_millis((secs.max(0.0) * 1000.0) as u64));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let delta = at.timestamp_millis() - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(delta.max(0) as u64))
+}
+
+/// 记录 provider 被限流，`wait` 之内的新请求都会排队。已有更晚的解除时间时不缩短。
+pub(crate) fn mark_throttled(provider: &str, wait: Duration, reason: &str) {
+    let until = Instant::now() + wait;
+    let mut throttles = THROTTLES.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = throttles.entry(provider.to_string()).or_insert_with(|| ProviderThrottle {
+        until,
+        reason: String::new(),
+        waiting: 0,
+    });
+    entry.until = entry.until.max(until);
+    entry.reason = reason.chars().take(200).collect();
+}
+
+/// provider 距离限流解除的剩余时间；没有被限流时为 None
+pub(crate) fn throttle_remaining(provider: &str) -> Option<Duration> {
+    let throttles = THROTTLES.lock().unwrap_or_else(|e| e.into_inner());
+    throttles
+        .get(provider)
+        .map(|t| t.until.saturating_duration_since(Instant::now()))
+        .filter(|d| !d.is_zero())
+}
+
+fn adjust_waiting(provider: &str, delta: isize) {
+    let mut throttles = THROTTLES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(t) = throttles.get_mut(provider) {
+        t.waiting = t.waiting.saturating_add_signed(delta);
+    }
+}
+
+fn current_reason(provider: &str) -> String {
+    let throttles = THROTTLES.lock().unwrap_or_else(|e| e.into_inner());
+    throttles.get(provider).map(|t| t.reason.clone()).unwrap_or_default()
+}
+
+fn emit_throttle(ctx: Option<&ThrottleContext<'_>>, provider: &str, waiting: bool, remaining: Duration) {
+    if let Some(ctx) = ctx {
+        let _ = ctx.app_handle.emit("llm-throttle", ThrottleEvent {
+            session_id: ctx.session_id.to_string(),
+            provider: provider.to_string(),
+            waiting,
+            retry_after_secs: remaining.as_secs_f64().ceil() as u64,
+            reason: current_reason(provider),
+        });
+    }
+}
+
+/// 在向 provider 发请求之前调用：被限流时排队等到解除
+///
+/// 剩余等待超过 LLM_MAX_RETRY_AFTER（多半是配额按小时/按天重置）时不干等，
+/// 直接返回一条说明原因的错误。
+pub(crate) async fn wait_for_provider(provider: &str, ctx: Option<&ThrottleContext<'_>>) -> Result<(), LLMError> {
+    let Some(remaining) = throttle_remaining(provider) else {
+        return Ok(());
+    };
+    if remaining > LLM_MAX_RETRY_AFTER {
+        return Err(LLMError::ApiError(format!(
+            "{} 当前处于限流状态，约 {} 秒后才能再次请求：{}",
+            provider,
+            remaining.as_secs(),
+            current_reason(provider)
+        )));
+    }
+
+    log::info!("[LLM] provider {} is throttled, waiting {:.1}s", provider, remaining.as_secs_f64());
+    adjust_waiting(provider, 1);
+    let _guard = scopeguard::guard(provider.to_string(), |p| adjust_waiting(&p, -1));
+    emit_throttle(ctx, provider, true, remaining);
+
+    let wait = tokio::time::sleep(remaining);
+    match ctx.and_then(|c| c.cancel_token) {
+        Some(token) => {
+            tokio::select! {
+                _ = wait => {}
+                _ = token.cancelled() => {
+                    return Err(LLMError::StreamError("请求已取消".to_string()));
+                }
+            }
+        }
+        None => wait.await,
+    }
+
+    emit_throttle(ctx, provider, false, Duration::ZERO);
+    Ok(())
+}
+
+/// 获取当前仍在限流中的 provider 列表
+#[tauri::command]
+pub fn get_throttle_state() -> Vec<ThrottleState> {
+    let now = Instant::now();
+    let throttles = THROTTLES.lock().unwrap_or_else(|e| e.into_inner());
+    throttles
+        .iter()
+        .filter(|(_, t)| t.until > now)
+        .map(|(provider, t)| ThrottleState {
+            provider: provider.clone(),
+            remaining_secs: t.until.saturating_duration_since(now).as_secs_f64().ceil() as u64,
+            reason: t.reason.clone(),
+            waiting_requests: t.waiting,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn parses_retry_after_seconds_ms_and_http_date() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("7"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("1500"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_millis(1500)));
+
+        let mut dated = HeaderMap::new();
+        dated.insert("retry-after", HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(parse_retry_after(&dated), Some(Duration::ZERO));
+
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn throttle_only_extends_and_expires() {
+        mark_throttled("test-provider-throttle", Duration::from_secs(30), "429");
+        mark_throttled("test-provider-throttle", Duration::from_secs(1), "429");
+        assert!(throttle_remaining("test-provider-throttle").unwrap() > Duration::from_secs(20));
+        assert!(throttle_remaining("test-provider-unthrottled").is_none());
+    }
+}
@@ -0,0 +1,308 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 旧数据自动清理策略
+ *
+ * 功能说明:
+ * - 超过 N 天没有更新的会话自动归档（置顶的会话除外），归档的会话不在历史记录主列表里显示，
+ *   数据原样保留，随时可以取消归档
+ * - 回收站里的会话、消息和知识库文档保留 M 天后彻底删除（默认 30 天）
+ * - 应用数据目录 backups 下的备份文件总大小超过上限时，从最旧的开始删除，最新的一份总是保留
+ * - 后台维护任务启动后稍等片刻执行一次，之后每 6 小时执行一次；也可以在设置里立即执行
+ * - preview_retention 只演练不改动，返回会被归档、清除和删除的内容，可以预览还没保存的策略
+ *
+ * 策略保存在应用数据目录的 retention_policy.json，启动时在打开数据库之前读取，
+ * 数据库初始化时的回收站清理也按配置的天数执行。
+ * 应用不单独保存附件文件，占用空间上限针对的是本地备份文件。
+ */
+
+use crate::commands::backup::app_data_dir;
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// 清理策略配置文件（应用数据目录下）
+const POLICY_FILE: &str = "retention_policy.json";
+/// 本地备份目录（应用数据目录下，见 commands::backup）
+const BACKUPS_DIR: &str = "backups";
+
+/// 启动后第一次执行前的等待时间，避开启动时的数据库初始化和索引加载
+const INITIAL_DELAY: Duration = Duration::from_secs(120);
+/// 后台维护的执行间隔
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// 同一时间只允许一次清理
+static RETENTION_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+/// 清理策略
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// 超过多少天没有更新的会话自动归档，0 表示不自动归档
+    pub archive_after_days: u32,
+    /// 回收站保留天数（至少 1 天）
+    pub trash_retention_days: u32,
+    /// 本地备份文件占用空间上限 (MB)，0 表示不限制
+    pub max_backup_storage_mb: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            archive_after_days: 0,
+            trash_retention_days: crate::db::TRASH_RETENTION_DAYS as u32,
+            max_backup_storage_mb: 0,
+        }
+    }
+}
+
+/// 被归档（或演练时会被归档）的会话
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedSession {
+    pub id: String,
+    pub title: String,
+    pub updated_at: i64,
+}
+
+/// 一次清理（或演练）的结果
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionReport {
+    /// 是否只是演练，没有真的改动数据
+    pub dry_run: bool,
+    pub archived_sessions: Vec<ArchivedSession>,
+    /// 从回收站彻底删除的会话数、消息数和知识库文档数
+    pub purged_sessions: usize,
+    pub purged_messages: usize,
+    pub purged_documents: usize,
+    /// 删除的备份文件名
+    pub deleted_backups: Vec<String>,
+    /// 删除备份释放的空间 (字节)
+    pub freed_bytes: u64,
+    /// 执行时间 (毫秒)
+    pub ran_at: i64,
+}
+
+/// 备份目录里的一个文件
+#[derive(Debug, Clone)]
+struct BackupFile {
+    name: String,
+    size: u64,
+    modified: i64,
+}
+
+fn read_policy(dir: &Path) -> RetentionPolicy {
+    std::fs::read_to_string(dir.join(POLICY_FILE))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn write_policy(dir: &Path, policy: &RetentionPolicy) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let text = serde_json::to_string_pretty(policy).map_err(|e| e.to_string())?;
+    std::fs::write(dir.join(POLICY_FILE), text).map_err(|e| format!("保存清理策略失败: {}", e))
+}
+
+/// 读取清理策略并应用回收站保留天数；启动时在打开数据库之前调用
+pub fn load(dir: &Path) {
+    let policy = read_policy(dir);
+    crate::db::set_trash_retention_days(policy.trash_retention_days as i64);
+}
+
+/// 列出备份目录里的 zip 备份文件
+fn list_backups(dir: &Path) -> Vec<BackupFile> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let meta = entry.metadata().ok()?;
+            if !meta.is_file() || !name.ends_with(".zip") {
+                return None;
+            }
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or(0);
+            Some(BackupFile { name, size: meta.len(), modified })
+        })
+        .collect()
+}
+
+/// 按从新到旧累计大小，超出上限的备份需要删除；最新的一份总是保留
+fn backups_over_cap(mut backups: Vec<BackupFile>, cap_bytes: u64) -> Vec<BackupFile> {
+    backups.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| b.name.cmp(&a.name)));
+    let mut total = 0u64;
+    let mut over = Vec::new();
+    for (i, backup) in backups.into_iter().enumerate() {
+        total = total.saturating_add(backup.size);
+        if i > 0 && total > cap_bytes {
+            over.push(backup);
+        }
+    }
+    over
+}
+
+/// 按策略执行一次清理；dry_run 时只统计不改动
+async fn apply(app_handle: &AppHandle, policy: &RetentionPolicy, dry_run: bool) -> Result<RetentionReport, String> {
+    let _guard = RETENTION_LOCK.lock().await;
+    let now = chrono::Utc::now().timestamp_millis();
+    let trash_cutoff = now - policy.trash_retention_days.max(1) as i64 * DAY_MS;
+    let archive_cutoff = (policy.archive_after_days > 0).then(|| now - policy.archive_after_days as i64 * DAY_MS);
+
+    let db_state = app_handle.state::<DbState>();
+    let mut report = db_state
+        .run(move |db| {
+            let mut report = RetentionReport { dry_run, ran_at: now, ..Default::default() };
+            if let Some(cutoff) = archive_cutoff {
+                report.archived_sessions = db
+                    .archive_sessions_before(cutoff, !dry_run)
+                    .map_err(|e| friendly_err("归档会话失败", e))?;
+            }
+            let (sessions, messages) = db
+                .count_trash_before(trash_cutoff)
+                .map_err(|e| friendly_err("读取回收站失败", e))?;
+            report.purged_sessions = sessions;
+            report.purged_messages = messages;
+            if !dry_run {
+                db.purge_expired_trash().map_err(|e| friendly_err("清空过期回收站失败", e))?;
+            }
+
+            let conn = crate::db::open_connection(&db.path).map_err(|e| friendly_err("打开数据库失败", e))?;
+            report.purged_documents = if dry_run {
+                crate::knowledge_base::trash::count_expired_documents(&conn, trash_cutoff).map_err(|e| e.to_string())?
+            } else {
+                crate::knowledge_base::trash::purge_expired_documents(&conn).map_err(|e| e.to_string())?
+            };
+            Ok::<_, String>(report)
+        })
+        .await?;
+
+    if policy.max_backup_storage_mb > 0 {
+        let backups_dir = app_data_dir(app_handle)?.join(BACKUPS_DIR);
+        let cap_bytes = policy.max_backup_storage_mb.saturating_mul(1024 * 1024);
+        for backup in backups_over_cap(list_backups(&backups_dir), cap_bytes) {
+            if !dry_run {
+                if let Err(e) = std::fs::remove_file(backups_dir.join(&backup.name)) {
+                    log::warn!("[retention] 删除备份 {} 失败: {}", backup.name, e);
+                    continue;
+                }
+            }
+            report.freed_bytes += backup.size;
+            report.deleted_backups.push(backup.name);
+        }
+    }
+
+    if !dry_run {
+        log::info!(
+            "[retention] 归档 {} 个会话，清除回收站 {} 个会话、{} 条消息、{} 个文档，删除 {} 个备份",
+            report.archived_sessions.len(),
+            report.purged_sessions,
+            report.purged_messages,
+            report.purged_documents,
+            report.deleted_backups.len()
+        );
+    }
+    Ok(report)
+}
+
+/// 后台维护循环：启动后稍等片刻执行一次，之后定期按保存的策略清理
+pub async fn run_maintenance_loop(app_handle: AppHandle) {
+    tokio::time::sleep(INITIAL_DELAY).await;
+    loop {
+        match app_data_dir(&app_handle) {
+            Ok(dir) => {
+                let policy = read_policy(&dir);
+                if let Err(e) = apply(&app_handle, &policy, false).await {
+                    log::error!("[retention] 自动清理失败: {}", e);
+                }
+            }
+            Err(e) => log::error!("[retention] {}", e),
+        }
+        tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+    }
+}
+
+// ============ 命令 ============
+
+/// 读取清理策略
+#[tauri::command]
+pub fn get_retention_policy(app_handle: AppHandle) -> Result<RetentionPolicy, String> {
+    Ok(read_policy(&app_data_dir(&app_handle)?))
+}
+
+/// 保存清理策略，回收站保留天数立即生效
+#[tauri::command]
+pub fn save_retention_policy(app_handle: AppHandle, policy: RetentionPolicy) -> Result<(), String> {
+    if policy.trash_retention_days == 0 {
+        return Err("回收站至少保留 1 天".to_string());
+    }
+    write_policy(&app_data_dir(&app_handle)?, &policy)?;
+    crate::db::set_trash_retention_days(policy.trash_retention_days as i64);
+    log::info!("[retention] Retention policy saved: {:?}", policy);
+    Ok(())
+}
+
+/**
+ * 演练清理：返回按策略会被归档、清除和删除的内容，不改动任何数据
+ *
+ * @param policy: 要预览的策略，不传则用已保存的策略
+ */
+#[tauri::command]
+pub async fn preview_retention(
+    app_handle: AppHandle,
+    policy: Option<RetentionPolicy>,
+) -> Result<RetentionReport, String> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => read_policy(&app_data_dir(&app_handle)?),
+    };
+    apply(&app_handle, &policy, true).await
+}
+
+/// 立即按已保存的策略清理一次
+#[tauri::command]
+pub async fn run_retention_now(app_handle: AppHandle) -> Result<RetentionReport, String> {
+    let policy = read_policy(&app_data_dir(&app_handle)?);
+    apply(&app_handle, &policy, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup(name: &str, size: u64, modified: i64) -> BackupFile {
+        BackupFile { name: name.to_string(), size, modified }
+    }
+
+    #[test]
+    fn oldest_backups_over_the_cap_are_deleted_but_the_newest_is_kept() {
+        let backups = vec![backup("b.zip", 40, 2), backup("a.zip", 40, 1), backup("c.zip", 40, 3)];
+        let names = |over: Vec<BackupFile>| over.into_iter().map(|b| b.name).collect::<Vec<_>>();
+
+        assert_eq!(names(backups_over_cap(backups.clone(), 100)), ["a.zip"]);
+        assert_eq!(names(backups_over_cap(backups.clone(), 120)), Vec::<String>::new());
+        // 上限比最新的一份还小时也保留最新的那份
+        assert_eq!(names(backups_over_cap(backups, 10)), ["b.zip", "a.zip"]);
+    }
+
+    #[test]
+    fn missing_policy_fields_fall_back_to_defaults() {
+        let policy: RetentionPolicy = serde_json::from_str(r#"{"archive_after_days": 90}"#).unwrap();
+        assert_eq!(policy.archive_after_days, 90);
+        assert_eq!(policy.trash_retention_days, crate::db::TRASH_RETENTION_DAYS as u32);
+        assert_eq!(policy.max_backup_storage_mb, 0);
+    }
+}
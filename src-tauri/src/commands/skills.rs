@@ -104,22 +104,28 @@ pub async fn save_skill(
     }
     config.updated_at = chrono::Utc::now().timestamp_millis();
 
-    let db = state.0.lock().await;
-    db.save_skill(&config)
-        .map_err(|e| { log::error!("保存 Skill 失败（详情：{}）", e); SkillError::DatabaseError("保存 Skill 失败，请重试".to_string()) })?;
-
-    log::info!("Skill saved: {} ({})", config.name, config.id);
-    Ok(config)
+    state
+        .run(move |db| {
+            db.save_skill(&config)
+                .map_err(|e| { log::error!("保存 Skill 失败（详情：{}）", e); SkillError::DatabaseError("保存 Skill 失败，请重试".to_string()) })?;
+
+            log::info!("Skill saved: {} ({})", config.name, config.id);
+            Ok(config)
+        })
+        .await
 }
 
 /// 获取所有 Skill
 #[tauri::command]
 pub async fn list_skills(state: tauri::State<'_, DbState>) -> Result<Vec<Skill>, SkillError> {
-    let db = state.0.lock().await;
-    let skills = db
-        .get_skills()
-        .map_err(|e| { log::error!("获取 Skill 列表失败（详情：{}）", e); SkillError::DatabaseError("获取 Skill 列表失败，请重试".to_string()) })?;
-    Ok(skills)
+    state
+        .run(|db| {
+            let skills = db
+                .get_skills()
+                .map_err(|e| { log::error!("获取 Skill 列表失败（详情：{}）", e); SkillError::DatabaseError("获取 Skill 列表失败，请重试".to_string()) })?;
+            Ok(skills)
+        })
+        .await
 }
 
 /// 删除 Skill (同时清理其资源文件目录)
@@ -129,10 +135,11 @@ pub async fn delete_skill(
     skill_id: String,
     app_handle: AppHandle,
 ) -> Result<(), SkillError> {
-    let db = state.0.lock().await;
-    db.delete_skill(&skill_id)
+    let id = skill_id.clone();
+    state
+        .run(move |db| db.delete_skill(&id).map_err(|e| e.to_string()))
+        .await
         .map_err(|e| { log::error!("删除 Skill 失败（详情：{}）", e); SkillError::DatabaseError("删除 Skill 失败，请重试".to_string()) })?;
-    drop(db);
 
     if let Ok(dir) = skill_resources_dir(&app_handle, &skill_id) {
         if let Some(skill_dir) = dir.parent() {
@@ -170,24 +177,27 @@ pub async fn add_skill_resource_file(
         .await
         .map_err(|e| { log::error!("拷贝资源文件失败（详情：{}）", e); SkillError::FileError("添加资源文件失败，请检查磁盘空间和权限".to_string()) })?;
 
-    let db = state.0.lock().await;
-    let mut skills = db
-        .get_skills()
-        .map_err(|e| { log::error!("获取 Skill 列表失败（详情：{}）", e); SkillError::DatabaseError("获取 Skill 列表失败，请重试".to_string()) })?;
-    let skill = skills
-        .iter_mut()
-        .find(|s| s.id == skill_id)
-        .ok_or_else(|| SkillError::NotFound(skill_id.clone()))?;
-
-    if !skill.resource_files.contains(&filename) {
-        skill.resource_files.push(filename);
-    }
-    skill.updated_at = chrono::Utc::now().timestamp_millis();
-
-    db.save_skill(skill)
-        .map_err(|e| { log::error!("保存 Skill 失败（详情：{}）", e); SkillError::DatabaseError("保存 Skill 失败，请重试".to_string()) })?;
-
-    Ok(skill.clone())
+    state
+        .run(move |db| {
+            let mut skills = db
+                .get_skills()
+                .map_err(|e| { log::error!("获取 Skill 列表失败（详情：{}）", e); SkillError::DatabaseError("获取 Skill 列表失败，请重试".to_string()) })?;
+            let skill = skills
+                .iter_mut()
+                .find(|s| s.id == skill_id)
+                .ok_or_else(|| SkillError::NotFound(skill_id.clone()))?;
+
+            if !skill.resource_files.contains(&filename) {
+                skill.resource_files.push(filename);
+            }
+            skill.updated_at = chrono::Utc::now().timestamp_millis();
+
+            db.save_skill(skill)
+                .map_err(|e| { log::error!("保存 Skill 失败（详情：{}）", e); SkillError::DatabaseError("保存 Skill 失败，请重试".to_string()) })?;
+
+            Ok(skill.clone())
+        })
+        .await
 }
 
 /// 从 Skill 移除一个资源文件 (同时删除磁盘上的文件)
@@ -202,22 +212,25 @@ pub async fn remove_skill_resource_file(
     let path = dir.join(&filename);
     let _ = tokio::fs::remove_file(&path).await;
 
-    let db = state.0.lock().await;
-    let mut skills = db
-        .get_skills()
-        .map_err(|e| { log::error!("获取 Skill 列表失败（详情：{}）", e); SkillError::DatabaseError("获取 Skill 列表失败，请重试".to_string()) })?;
-    let skill = skills
-        .iter_mut()
-        .find(|s| s.id == skill_id)
-        .ok_or_else(|| SkillError::NotFound(skill_id.clone()))?;
+    state
+        .run(move |db| {
+            let mut skills = db
+                .get_skills()
+                .map_err(|e| { log::error!("获取 Skill 列表失败（详情：{}）", e); SkillError::DatabaseError("获取 Skill 列表失败，请重试".to_string()) })?;
+            let skill = skills
+                .iter_mut()
+                .find(|s| s.id == skill_id)
+                .ok_or_else(|| SkillError::NotFound(skill_id.clone()))?;
 
-    skill.resource_files.retain(|f| f != &filename);
-    skill.updated_at = chrono::Utc::now().timestamp_millis();
+            skill.resource_files.retain(|f| f != &filename);
+            skill.updated_at = chrono::Utc::now().timestamp_millis();
 
-    db.save_skill(skill)
-        .map_err(|e| { log::error!("保存 Skill 失败（详情：{}）", e); SkillError::DatabaseError("保存 Skill 失败，请重试".to_string()) })?;
+            db.save_skill(skill)
+                .map_err(|e| { log::error!("保存 Skill 失败（详情：{}）", e); SkillError::DatabaseError("保存 Skill 失败，请重试".to_string()) })?;
 
-    Ok(skill.clone())
+            Ok(skill.clone())
+        })
+        .await
 }
 
 /// 读取某个资源文件的文本内容 (用于前端预览)
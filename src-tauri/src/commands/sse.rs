@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * SSE 增量解码模块
+ *
+ * 功能说明:
+ * - 按字节缓冲网络数据块，只在完整的一行到齐后才做 UTF-8 解码——中文等多字节
+ *   字符被切在两个 TCP 包之间时不会变成乱码
+ * - 按 SSE 规范拼装事件：多行 `data:` 用换行连接，空行结束一个事件，
+ *   `event:` 记录事件名，`:` 开头的注释行（心跳）忽略，兼容 `\r\n` 行尾
+ * - 所有 provider 的流式回复共用，解析出的 data 再交给各 provider 的格式解析
+ *
+ * 解码器只负责把字节流切成事件，不关心 data 里是哪家的 JSON 格式。
+ */
+
+/// 一个完整的 SSE 事件
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SseEvent {
+    /// `event:` 字段（Anthropic 会带，OpenAI 兼容接口一般没有）
+    pub event: Option<String>,
+    /// 所有 `data:` 行按换行拼接后的内容
+    pub data: String,
+}
+
+/// 增量 SSE 解码器：喂入原始字节块，吐出已经完整的事件
+#[derive(Debug, Default)]
+pub(crate) struct SseDecoder {
+    /// 还没凑成完整一行的字节（可能停在多字节字符中间）
+    pending: Vec<u8>,
+    /// 当前事件已收到的 data 行
+    data_lines: Vec<String>,
+    /// 当前事件的事件名
+    event: Option<String>,
+    /// 是否已经跳过流开头可能出现的 UTF-8 BOM
+    started: bool,
+}
+
+impl SseDecoder {
+    /// 喂入一个网络数据块，返回其中已经完整的事件
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.pending.extend_from_slice(chunk);
+        if !self.started && self.pending.len() >= 3 {
+            if self.pending.starts_with(&[0xEF, 0xBB, 0xBF]) {
+                self.pending.drain(..3);
+            }
+            self.started = true;
+        }
+
+        let mut events = Vec::new();
+        // '\n' 不会出现在多字节 UTF-8 序列内部，按它切出来的每一行都是完整字符
+        while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+            let line_bytes: Vec<u8> = self.pending.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line_bytes[..pos]);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            self.handle_line(line, &mut events);
+        }
+        events
+    }
+
+    /// 流结束时调用：把最后一个没有以空行收尾的事件也交出去
+    pub fn finish(&mut self) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        if !self.pending.is_empty() {
+            let rest = std::mem::take(&mut self.pending);
+            let line = String::from_utf8_lossy(&rest);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            self.handle_line(line, &mut events);
+        }
+        self.dispatch(&mut events);
+        events
+    }
+
+    fn handle_line(&mut self, line: &str, events: &mut Vec<SseEvent>) {
+        if line.is_empty() {
+            self.dispatch(events);
+            return;
+        }
+        if line.starts_with(':') {
+            return;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => {
+                // 有些 OpenAI 兼容网关事件之间不发空行；上一条 data 已经是完整
+                // JSON（或 [DONE]）时先把它当成一个事件交出去，免得两条拼在一起
+                if self.pending_data_is_complete() {
+                    self.dispatch(events);
+                }
+                self.data_lines.push(value.to_string());
+            }
+            "event" => self.event = Some(value.to_string()),
+            // id / retry 对一次性的补全流没有意义
+            _ => {}
+        }
+    }
+
+    fn pending_data_is_complete(&self) -> bool {
+        match self.data_lines.as_slice() {
+            [] => false,
+            [single] => single == "[DONE]" || serde_json::from_str::<serde_json::Value>(single).is_ok(),
+            lines => serde_json::from_str::<serde_json::Value>(&lines.join("\n")).is_ok(),
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<SseEvent>) {
+        let event = self.event.take();
+        if self.data_lines.is_empty() {
+            return;
+        }
+        events.push(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data_lines).join("\n"),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(events: &[SseEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.data.as_str()).collect()
+    }
+
+    #[test]
+    fn multibyte_character_split_across_chunks_is_not_corrupted() {
+        let raw = "data: {\"content\":\"你好\"}\n\n".as_bytes();
+        // 在 "你" 的三个字节中间切开
+        let split = raw.iter().position(|&b| b == 0xE4).unwrap() + 1;
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.feed(&raw[..split]).is_empty());
+        let events = decoder.feed(&raw[split..]);
+        assert_eq!(data(&events), ["{\"content\":\"你好\"}"]);
+    }
+
+    #[test]
+    fn event_spanning_chunks_with_crlf_comments_and_multiline_data() {
+        let mut decoder = SseDecoder::default();
+        let mut events = decoder.feed(b": ping\r\nevent: message_start\r\ndata: {\"a\":");
+        events.extend(decoder.feed(b"1}\r\n\r\ndata:line1\ndata: line2\n\n"));
+        assert_eq!(events[0].event.as_deref(), Some("message_start"));
+        assert_eq!(data(&events), ["{\"a\":1}", "line1\nline2"]);
+    }
+
+    #[test]
+    fn data_lines_without_blank_separator_and_unterminated_tail() {
+        let mut decoder = SseDecoder::default();
+        let mut events = decoder.feed(b"data: {\"n\":1}\ndata: {\"n\":2}\ndata: [DONE]");
+        events.extend(decoder.finish());
+        assert_eq!(data(&events), ["{\"n\":1}", "{\"n\":2}", "[DONE]"]);
+    }
+}
@@ -0,0 +1,69 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 聊天统计
+ *
+ * 功能说明:
+ * - get_chat_stats 从数据库汇总会话数、消息数和回复的平均长度，
+ *   分别按服务商、按模型、按天（本地日期）统计，供统计页面展示
+ * - 可以只统计某个时间点之后的数据（最近 7 天、30 天等）
+ * - 回复记录了 token 数和耗时的，一并汇总 token 总数和平均耗时
+ *
+ * 回复按生成它的服务商和模型归类；没有记录的（旧版本保存的消息、用户消息）按所属会话的服务商和模型归类。
+ * 回收站里的会话和消息、生成中断留下的半截回复都不计入；
+ * 回复长度只算成功生成的助手消息，单位是字符数。会话数是统计范围内有消息的会话数，
+ * 还没有发过消息的空会话不计入。
+ */
+
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+
+/// 一组会话和消息的统计
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatStatsGroup {
+    /// 分组键：服务商、"服务商/模型" 或 YYYY-MM-DD 日期；总计时为空
+    pub key: String,
+    /// 会话数（按天统计时是当天有消息的会话数）
+    pub sessions: i64,
+    /// 消息数
+    pub messages: i64,
+    /// 助手回复数
+    pub responses: i64,
+    /// 助手回复的平均长度 (字符)，没有回复时为 0
+    pub average_response_length: f64,
+    /// 回复记录的输入、输出 token 数之和
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    /// 记录了耗时的成功回复的平均耗时 (毫秒)，没有时为 0
+    pub average_latency_ms: f64,
+}
+
+/// get_chat_stats 的返回值
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatStats {
+    pub total: ChatStatsGroup,
+    /// 按服务商，消息数多的在前
+    pub by_provider: Vec<ChatStatsGroup>,
+    /// 按 "服务商/模型"，消息数多的在前
+    pub by_model: Vec<ChatStatsGroup>,
+    /// 按天，日期从早到晚
+    pub by_day: Vec<ChatStatsGroup>,
+}
+
+/**
+ * 获取聊天统计
+ *
+ * @param since: 只统计这个时间点 (毫秒) 之后的消息，不传则统计全部
+ */
+#[tauri::command]
+pub async fn get_chat_stats(state: tauri::State<'_, DbState>, since: Option<i64>) -> Result<ChatStats, String> {
+    state
+        .run(move |db| {
+            db.get_chat_stats(since.unwrap_or(0))
+                .map_err(|e| friendly_err("读取统计数据失败，请重试", e))
+        })
+        .await
+}
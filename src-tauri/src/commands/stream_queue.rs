@@ -0,0 +1,176 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 并发流式请求排队模块
+ *
+ * 功能说明:
+ * - 每个 provider 一个信号量，同时进行的流式回复数超过上限时后来的请求排队
+ * - 排队期间向前端发出 stream-queue 事件，告知当前排在第几位；队伍前面的
+ *   请求开始或取消时，后面所有请求的位置一起更新
+ * - 上限由前端设置同步（set_stream_concurrency），0 表示不限制
+ *
+ * 名额在整个回复期间一直占着（包括工具调用后的续写），回复结束、出错或取消时
+ * 释放。故障转移换了 provider 也仍然占着原 provider 的名额——换过去的那一家
+ * 并没有经过排队，这里只防止用户一口气把同一家的额度打满。
+ */
+
+use crate::commands::llm::LLMError;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// 默认每个 provider 同时进行的流式回复数
+const DEFAULT_STREAM_CONCURRENCY: u32 = 3;
+
+static LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_STREAM_CONCURRENCY);
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(1);
+static SLOTS: Lazy<Mutex<HashMap<String, Arc<ProviderSlots>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 一个排队中的请求
+#[derive(Debug, Clone)]
+struct Waiter {
+    ticket: u64,
+    session_id: String,
+    message_id: String,
+}
+
+/// 单个 provider 的名额和等待队列
+struct ProviderSlots {
+    /// 创建时的上限；上限改了就换一份新的，旧的随在途请求结束自然释放
+    limit: u32,
+    semaphore: Arc<Semaphore>,
+    queue: Mutex<Vec<Waiter>>,
+}
+
+/// 排队状态事件：进入队列、位置变化、轮到自己（queued = false）时各发一次
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamQueueEvent {
+    pub session_id: String,
+    pub message_id: String,
+    pub provider: String,
+    /// true = 正在排队，false = 已经轮到、请求开始发出
+    pub queued: bool,
+    /// 在队伍中的位置（从 1 开始），queued = false 时为 0
+    pub position: usize,
+    /// 当前的并发上限
+    pub limit: u32,
+}
+
+/// 排队需要的上下文
+pub(crate) struct QueueContext<'a> {
+    pub app_handle: &'a AppHandle,
+    pub session_id: &'a str,
+    pub message_id: &'a str,
+    pub cancel_token: &'a CancellationToken,
+}
+
+fn slots_for(provider: &str, limit: u32) -> Arc<ProviderSlots> {
+    let mut slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+    let current = slots.get(provider).filter(|s| s.limit == limit).cloned();
+    current.unwrap_or_else(|| {
+        let fresh = Arc::new(ProviderSlots {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            queue: Mutex::new(Vec::new()),
+        });
+        slots.insert(provider.to_string(), fresh.clone());
+        fresh
+    })
+}
+
+/// 把队伍里每个请求的当前位置告诉前端
+fn emit_positions(app_handle: &AppHandle, provider: &str, slots: &ProviderSlots) {
+    let queue = slots.queue.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    for (i, waiter) in queue.iter().enumerate() {
+        let _ = app_handle.emit("stream-queue", StreamQueueEvent {
+            session_id: waiter.session_id.clone(),
+            message_id: waiter.message_id.clone(),
+            provider: provider.to_string(),
+            queued: true,
+            position: i + 1,
+            limit: slots.limit,
+        });
+    }
+}
+
+fn leave_queue(slots: &ProviderSlots, ticket: u64) {
+    slots.queue.lock().unwrap_or_else(|e| e.into_inner()).retain(|w| w.ticket != ticket);
+}
+
+/// 占一个 provider 的流式名额；名额用完时排队等待，期间可被取消
+///
+/// 返回的 permit 在回复结束前要一直持有；不限并发时返回 None。
+pub(crate) async fn acquire_stream_slot(
+    provider: &str,
+    ctx: &QueueContext<'_>,
+) -> Result<Option<OwnedSemaphorePermit>, LLMError> {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return Ok(None);
+    }
+    let slots = slots_for(provider, limit);
+    if let Ok(permit) = slots.semaphore.clone().try_acquire_owned() {
+        return Ok(Some(permit));
+    }
+
+    let ticket = NEXT_TICKET.fetch_add(1, Ordering::Relaxed);
+    slots.queue.lock().unwrap_or_else(|e| e.into_inner()).push(Waiter {
+        ticket,
+        session_id: ctx.session_id.to_string(),
+        message_id: ctx.message_id.to_string(),
+    });
+    log::info!("[LLM] provider {} at concurrency limit {}, queueing {}", provider, limit, ctx.message_id);
+    emit_positions(ctx.app_handle, provider, &slots);
+
+    let result = tokio::select! {
+        permit = slots.semaphore.clone().acquire_owned() => {
+            permit.map_err(|_| LLMError::StreamError("并发队列已关闭".to_string()))
+        }
+        _ = ctx.cancel_token.cancelled() => Err(LLMError::StreamError("请求已取消".to_string())),
+    };
+
+    leave_queue(&slots, ticket);
+    let _ = ctx.app_handle.emit("stream-queue", StreamQueueEvent {
+        session_id: ctx.session_id.to_string(),
+        message_id: ctx.message_id.to_string(),
+        provider: provider.to_string(),
+        queued: false,
+        position: 0,
+        limit,
+    });
+    emit_positions(ctx.app_handle, provider, &slots);
+    result.map(Some)
+}
+
+/// 设置每个 provider 同时进行的流式回复上限（0 = 不限制）
+#[tauri::command]
+pub fn set_stream_concurrency(limit: u32) {
+    LIMIT.store(limit, Ordering::Relaxed);
+    log::info!("Stream concurrency limit per provider set to {}", limit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changing_the_limit_replaces_provider_slots_but_same_limit_reuses_them() {
+        let first = slots_for("test-provider", 2);
+        let again = slots_for("test-provider", 2);
+        assert!(Arc::ptr_eq(&first, &again));
+
+        let _held = first.semaphore.clone().try_acquire_owned().unwrap();
+        assert_eq!(first.semaphore.available_permits(), 1);
+
+        let resized = slots_for("test-provider", 4);
+        assert!(!Arc::ptr_eq(&first, &resized));
+        assert_eq!(resized.semaphore.available_permits(), 4);
+    }
+}
@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 滚动摘要模块
+ *
+ * 功能说明:
+ * - 会话历史占到上下文预算的一半以上时，在后台把最早的若干轮对话压缩成一段摘要
+ * - 摘要按会话存进数据库 (session_summaries)，之后每次发送都"置顶"注入 system
+ *   prompt，被摘要覆盖的原始消息不再发给模型
+ * - 新一轮摘要在旧摘要的基础上增量合并，会话因此可以无限延续
+ *
+ * 只在请求带了 summarize_history 时进行（设置里默认关闭）。摘要请求复用本轮实际
+ * 应答的 provider/模型和鉴权方式，在回复开始流式输出之后异步进行，不阻塞当前回复；
+ * 用量和估算费用记进 message_usage，计入会话和当月费用；失败只记日志，下一轮再试。
+ */
+
+use crate::commands::llm::{build_native_messages, run_turn_with_usage, ChatMessage, CustomAuth, TurnOutcome};
+use crate::commands::local_model::friendly_err;
+use crate::commands::pricing::{estimate_cost, MessageUsage};
+use crate::db::DbState;
+use crate::knowledge_base::document::estimate_tokens;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// 最近的这么多条消息始终保留原文，不进摘要
+const SUMMARY_KEEP_RECENT: usize = 6;
+
+/// 一次摘要请求的回复上限
+const SUMMARY_MAX_TOKENS: u32 = 1_024;
+
+/// 给摘要模型的指令
+const SUMMARY_SYSTEM_PROMPT: &str = "你是对话摘要助手。请把给出的已有摘要和新增对话合并成一份新的摘要：\
+保留用户的目标、偏好、已确定的事实和结论、尚未解决的问题，以及后续回答需要用到的具体数据、代码或名称；\
+省略寒暄和重复内容。直接输出摘要正文，不要添加标题或解释。";
+
+/// 正在生成摘要的会话，避免同一会话并发触发多个摘要任务
+static SUMMARIZING: Lazy<std::sync::Mutex<HashSet<String>>> = Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+
+/// 会话的置顶摘要
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionSummary {
+    /// 会话 ID
+    pub session_id: String,
+    /// 摘要正文
+    pub summary: String,
+    /// 摘要覆盖到的最后一条消息的时间戳 (毫秒)；不晚于它的消息不再原文发送
+    pub covered_until: i64,
+    /// 摘要累计覆盖的消息条数
+    pub covered_messages: i64,
+    /// 最后更新时间戳 (毫秒)
+    pub updated_at: i64,
+}
+
+/// 摘要更新事件
+#[derive(Clone, Serialize)]
+pub struct SessionSummaryEvent {
+    pub session_id: String,
+    pub covered_messages: i64,
+}
+
+/// 一次后台摘要任务需要的全部信息
+pub(crate) struct SummaryJob {
+    pub session_id: String,
+    pub provider: String,
+    pub model: String,
+    pub base_url: String,
+    pub api_key: String,
+    /// 自建网关的附加请求头 / query 参数鉴权
+    pub custom_auth: CustomAuth,
+    /// 已有摘要（增量合并的基础）
+    pub previous: Option<SessionSummary>,
+    /// 本次要压缩进摘要的消息（按时间顺序）
+    pub messages: Vec<ChatMessage>,
+}
+
+/// 把已有摘要应用到待发送的消息列表：去掉已被摘要覆盖的原始消息，
+/// 再把摘要正文并入 system prompt。最后一条（本轮提问）无论如何保留。
+pub(crate) fn apply_summary(messages: &mut Vec<ChatMessage>, summary: &SessionSummary) {
+    let last_index = messages.len().saturating_sub(1);
+    let mut index = 0;
+    messages.retain(|m| {
+        let keep = m.role == "system" || index == last_index || m.timestamp > summary.covered_until;
+        index += 1;
+        keep
+    });
+    crate::commands::llm::merge_system_prompt(
+        messages,
+        &format!("以下是本会话较早部分的摘要（对应的原始消息未再发送）：\n{}", summary.summary),
+        false,
+    );
+}
+
+/// 判断是否该生成新摘要；需要时返回要压缩的那段消息
+///
+/// 非 system 消息的估算 token 数超过预算的一半、且除去保留的最近几条之后
+/// 还有至少两条时才触发。切分点落在 user 消息之前，保证保留的部分从一轮
+/// 完整对话开始。
+pub(crate) fn pick_messages_to_summarize(messages: &[ChatMessage], budget: usize) -> Option<Vec<ChatMessage>> {
+    let history: Vec<&ChatMessage> = messages.iter().filter(|m| m.role != "system").collect();
+    let tokens: usize = history.iter().map(|m| crate::commands::context_window::message_tokens(m)).sum();
+    if tokens <= budget / 2 || history.len() < SUMMARY_KEEP_RECENT + 2 {
+        return None;
+    }
+
+    let mut cut = history.len() - SUMMARY_KEEP_RECENT;
+    while cut > 0 && history[cut].role != "user" {
+        cut -= 1;
+    }
+    (cut >= 2).then(|| history[..cut].iter().map(|m| (*m).clone()).collect())
+}
+
+/// 在后台生成并保存新摘要；同一会话已有摘要任务在跑时直接跳过
+pub(crate) fn spawn_summarizer(app_handle: AppHandle, job: SummaryJob) {
+    {
+        let mut running = SUMMARIZING.lock().unwrap_or_else(|e| e.into_inner());
+        if !running.insert(job.session_id.clone()) {
+            return;
+        }
+    }
+
+    tauri::async_runtime::spawn(async move {
+        let session_id = job.session_id.clone();
+        let _done = scopeguard::guard(session_id.clone(), |sid| {
+            SUMMARIZING.lock().unwrap_or_else(|e| e.into_inner()).remove(&sid);
+        });
+        if let Err(e) = summarize(&app_handle, job).await {
+            log::warn!("[Summary] failed to summarize session {}: {}", session_id, e);
+        }
+    });
+}
+
+async fn summarize(app_handle: &AppHandle, job: SummaryJob) -> Result<(), String> {
+    let Some(last) = job.messages.last() else {
+        return Ok(());
+    };
+    let covered_until = last.timestamp;
+
+    let transcript = job
+        .messages
+        .iter()
+        .map(|m| format!("{}: {}", if m.role == "user" { "用户" } else { "助手" }, m.content))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let prompt = format!(
+        "已有摘要：\n{}\n\n新增对话：\n{}",
+        job.previous.as_ref().map(|s| s.summary.as_str()).unwrap_or("（无）"),
+        transcript
+    );
+    let request = ChatMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        role: "user".to_string(),
+        content: prompt,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        error: None,
+        images: vec![],
+        videos: vec![],
+        seed: None,
+        generation: None,
+    };
+    let input_estimate = estimate_tokens(SUMMARY_SYSTEM_PROMPT) as i64 + estimate_tokens(&request.content) as i64;
+    let native = build_native_messages(&job.provider, &[request]);
+
+    let (outcome, usage) = run_turn_with_usage(
+        &job.provider,
+        &job.model,
+        &job.api_key,
+        &job.base_url,
+        &job.custom_auth,
+        Some(SUMMARY_SYSTEM_PROMPT),
+        &native,
+        &[],
+        Some(SUMMARY_MAX_TOKENS),
+        false,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let TurnOutcome::Text(text) = outcome else {
+        return Err("summarizer returned tool calls".to_string());
+    };
+
+    // 摘要请求同样计费：按会话记一条用量，服务商没报告用量时按估算值记
+    let input_tokens = usage.input_tokens.unwrap_or(input_estimate);
+    let output_tokens = usage.output_tokens.unwrap_or_else(|| estimate_tokens(&text) as i64);
+    let usage = MessageUsage {
+        message_id: format!("summary-{}", uuid::Uuid::new_v4()),
+        session_id: job.session_id.clone(),
+        provider: job.provider.clone(),
+        model: job.model.clone(),
+        input_tokens,
+        output_tokens,
+        cost_usd: estimate_cost(&job.provider, &job.model, input_tokens, output_tokens),
+        created_at: chrono::Utc::now().timestamp_millis(),
+    };
+    app_handle
+        .state::<DbState>()
+        .run(move |db| {
+            if let Err(e) = db.record_message_usage(&usage) {
+                log::warn!("[Summary] failed to record usage: {}", e);
+            }
+        })
+        .await;
+
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("summarizer returned empty text".to_string());
+    }
+
+    let summary = SessionSummary {
+        session_id: job.session_id.clone(),
+        summary: text.to_string(),
+        covered_until,
+        covered_messages: job.previous.as_ref().map(|s| s.covered_messages).unwrap_or(0) + job.messages.len() as i64,
+        updated_at: chrono::Utc::now().timestamp_millis(),
+    };
+    let saved = summary.clone();
+    app_handle
+        .state::<DbState>()
+        .run(move |db| db.save_session_summary(&saved).map_err(|e| e.to_string()))
+        .await?;
+    log::info!(
+        "[Summary] session {} summary updated, covering {} messages",
+        summary.session_id, summary.covered_messages
+    );
+    let _ = app_handle.emit("session-summary-updated", SessionSummaryEvent {
+        session_id: summary.session_id,
+        covered_messages: summary.covered_messages,
+    });
+    Ok(())
+}
+
+/// 获取会话的置顶摘要
+#[tauri::command]
+pub async fn get_session_summary(
+    session_id: String,
+    state: tauri::State<'_, DbState>,
+) -> Result<Option<SessionSummary>, String> {
+    state
+        .run(move |db| {
+            db.get_session_summary(&session_id)
+                .map_err(|e| friendly_err("读取会话摘要失败", e))
+        })
+        .await
+}
+
+/// 删除会话的置顶摘要（之后的请求重新发送全部原始消息，必要时再重新摘要）
+#[tauri::command]
+pub async fn delete_session_summary(
+    session_id: String,
+    state: tauri::State<'_, DbState>,
+) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.delete_session_summary(&session_id)
+                .map_err(|e| friendly_err("删除会话摘要失败", e))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: &str, timestamp: i64) -> ChatMessage {
+        ChatMessage {
+            id: String::new(),
+            role: role.to_string(),
+            content: content.to_string(),
+            timestamp,
+            error: None,
+            images: vec![],
+            videos: vec![],
+            seed: None,
+            generation: None,
+        }
+    }
+
+    #[test]
+    fn summary_replaces_covered_messages_and_joins_system_prompt() {
+        let mut messages = vec![
+            msg("system", "你是助手", 0),
+            msg("user", "早期问题", 1),
+            msg("assistant", "早期回答", 2),
+            msg("user", "新问题", 3),
+        ];
+        let summary = SessionSummary {
+            session_id: "s".into(),
+            summary: "用户在问早期问题".into(),
+            covered_until: 2,
+            covered_messages: 2,
+            updated_at: 0,
+        };
+        apply_summary(&mut messages, &summary);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].content.starts_with("你是助手\n\n以下是本会话较早部分的摘要"));
+        assert_eq!(messages[1].content, "新问题");
+    }
+
+    #[test]
+    fn picks_oldest_turns_and_keeps_recent_ones() {
+        let long = "字".repeat(300);
+        let messages: Vec<ChatMessage> = (0..10)
+            .map(|i| msg(if i % 2 == 0 { "user" } else { "assistant" }, &long, i))
+            .collect();
+        let picked = pick_messages_to_summarize(&messages, 1_000).unwrap();
+        assert_eq!(picked.len(), 4);
+        assert_eq!(picked.last().unwrap().role, "assistant");
+
+        assert!(pick_messages_to_summarize(&messages, 100_000).is_none());
+    }
+}
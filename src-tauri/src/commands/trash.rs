@@ -0,0 +1,112 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 会话和消息回收站
+ *
+ * 功能说明:
+ * - delete_session_cmd / delete_message_cmd 只给会话、消息打上 deleted_at 删除标记，
+ *   移进回收站；列表、分页读取、聊天记录检索都跳过带标记的数据
+ * - 回收站里的会话和消息可以恢复，也可以彻底删除
+ * - 超过保留期限的数据在启动时和后台维护任务里自动彻底删除（默认 TRASH_RETENTION_DAYS 天，
+ *   可以在清理策略里修改，见 retention 模块）
+ *
+ * 知识库文档的回收站见 knowledge_base::trash，两边共用同一个保留期限。
+ */
+
+use crate::commands::local_model::friendly_err;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+
+/// 回收站里的会话
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedSession {
+    pub id: String,
+    pub title: String,
+    pub provider: String,
+    pub model: String,
+    /// 会话里的消息条数（不含单独删除的消息）
+    pub message_count: i64,
+    /// 移进回收站的时间 (毫秒)
+    pub deleted_at: i64,
+}
+
+/// 回收站里单独删除的消息（会话本身还在）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedMessage {
+    pub id: String,
+    pub session_id: String,
+    pub session_title: String,
+    pub role: String,
+    /// 消息开头部分的预览
+    pub preview: String,
+    pub timestamp: i64,
+    /// 移进回收站的时间 (毫秒)
+    pub deleted_at: i64,
+}
+
+/// 回收站内容
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Trash {
+    pub sessions: Vec<TrashedSession>,
+    pub messages: Vec<TrashedMessage>,
+    /// 保留天数，超过后自动彻底删除
+    pub retention_days: i64,
+}
+
+/// 列出回收站里的会话和消息（按删除时间倒序）
+#[tauri::command]
+pub async fn list_trash(state: tauri::State<'_, DbState>) -> Result<Trash, String> {
+    state.run(|db| db.list_trash().map_err(|e| friendly_err("读取回收站失败，请重试", e))).await
+}
+
+/// 从回收站恢复会话
+#[tauri::command]
+pub async fn restore_session(state: tauri::State<'_, DbState>, session_id: String) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.restore_session(&session_id)
+                .map_err(|e| friendly_err("恢复会话失败，请重试", e))
+        })
+        .await
+}
+
+/// 彻底删除回收站里的会话及其消息，无法撤销
+#[tauri::command]
+pub async fn purge_session(state: tauri::State<'_, DbState>, session_id: String) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.purge_session(&session_id)
+                .map_err(|e| friendly_err("彻底删除会话失败，请重试", e))
+        })
+        .await
+}
+
+/// 从回收站恢复单条消息
+#[tauri::command]
+pub async fn restore_message(state: tauri::State<'_, DbState>, message_id: String) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.restore_message(&message_id)
+                .map_err(|e| friendly_err("恢复消息失败，请重试", e))
+        })
+        .await
+}
+
+/// 彻底删除回收站里的单条消息，无法撤销
+#[tauri::command]
+pub async fn purge_message(state: tauri::State<'_, DbState>, message_id: String) -> Result<(), String> {
+    state
+        .run(move |db| {
+            db.purge_message(&message_id)
+                .map_err(|e| friendly_err("彻底删除消息失败，请重试", e))
+        })
+        .await
+}
+
+/// 清空回收站里的会话和消息，返回彻底删除的条数
+#[tauri::command]
+pub async fn empty_trash(state: tauri::State<'_, DbState>) -> Result<usize, String> {
+    state.run(|db| db.empty_trash().map_err(|e| friendly_err("清空回收站失败，请重试", e))).await
+}
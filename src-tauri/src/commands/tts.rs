@@ -0,0 +1,283 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 语音合成 (TTS) 模块
+ *
+ * 功能说明:
+ * - OpenAI 兼容的 /audio/speech 接口（流式读取响应体）
+ * - edge-tts 命令行（微软 Edge 朗读音色，免密钥，需要用户自行 pip install edge-tts）
+ * - 音频按块以 base64 通过 `tts-audio-chunk` 事件推给前端，边收边播
+ * - 支持按 request_id 中途停止朗读
+ */
+
+use crate::commands::llm::create_streaming_http_client;
+use crate::commands::local_model::{friendly_err, hide_console_window};
+use base64::Engine;
+use futures::StreamExt;
+use keyring::Entry as KeyringEntry;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::{AppHandle, Emitter};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_OPENAI_TTS_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_OPENAI_TTS_MODEL: &str = "gpt-4o-mini-tts";
+const DEFAULT_OPENAI_TTS_VOICE: &str = "alloy";
+const DEFAULT_EDGE_TTS_VOICE: &str = "zh-CN-XiaoxiaoNeural";
+
+/// edge-tts 标准输出每次读取的块大小
+const EDGE_TTS_READ_CHUNK: usize = 16 * 1024;
+
+// 正在进行的朗读，以 request_id 为键，`cancel_speech` 据此提前停止
+static ACTIVE_SPEECH: Lazy<Mutex<HashMap<String, CancellationToken>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 语音合成请求
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SynthesizeSpeechRequest {
+    /// 前端生成的请求 ID，音频块事件和取消都用它配对
+    pub request_id: String,
+    /// 合成引擎："openai" | "edge"
+    pub engine: String,
+    /// 要朗读的文本
+    pub text: String,
+    /// 音色（不填用各引擎默认音色）
+    #[serde(default)]
+    pub voice: Option<String>,
+    /// OpenAI 模型名（仅 openai 引擎）
+    #[serde(default)]
+    pub model: Option<String>,
+    /// OpenAI 兼容端点的基础 URL（仅 openai 引擎，不填用官方地址）
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// API 密钥（仅 openai 引擎；为空时按 api_config_id 去 keyring 查）
+    #[serde(default)]
+    pub api_key: String,
+    /// 对应的 API 配置 ID，用于 keyring 兜底查找密钥
+    #[serde(default)]
+    pub api_config_id: Option<String>,
+    /// 语速倍率，1.0 为正常速度
+    #[serde(default)]
+    pub speed: Option<f32>,
+}
+
+/// 下发给前端的音频块事件
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TtsAudioChunk {
+    pub request_id: String,
+    /// base64 编码的音频数据（mp3）；结束事件为空串
+    pub data: String,
+    /// MIME 类型
+    pub media_type: String,
+    /// 是否结束
+    pub done: bool,
+}
+
+/// 合成语音并以音频块事件流式推给前端
+#[tauri::command]
+pub async fn synthesize_speech(request: SynthesizeSpeechRequest, app_handle: AppHandle) -> Result<(), String> {
+    if request.text.trim().is_empty() {
+        return Err("没有可朗读的内容".to_string());
+    }
+
+    let cancel_token = CancellationToken::new();
+    ACTIVE_SPEECH
+        .lock()
+        .await
+        .insert(request.request_id.clone(), cancel_token.clone());
+    let _cleanup = scopeguard::guard(request.request_id.clone(), |rid| {
+        tauri::async_runtime::spawn(async move {
+            ACTIVE_SPEECH.lock().await.remove(&rid);
+        });
+    });
+
+    log::info!(
+        "[TTS] synthesize_speech: request={} engine={} chars={}",
+        request.request_id,
+        request.engine,
+        request.text.chars().count()
+    );
+
+    let result = match request.engine.as_str() {
+        "openai" => synthesize_openai(&request, &app_handle, &cancel_token).await,
+        "edge" => synthesize_edge(&request, &app_handle, &cancel_token).await,
+        other => Err(friendly_err("不支持的语音合成引擎", other)),
+    };
+
+    // 不论成功、失败还是被取消，都补发结束事件，前端据此收尾播放器
+    emit_chunk(&app_handle, &request.request_id, String::new(), true);
+    result
+}
+
+/// 停止某个正在进行的朗读
+#[tauri::command]
+pub async fn cancel_speech(request_id: String) -> Result<(), String> {
+    if let Some(token) = ACTIVE_SPEECH.lock().await.get(&request_id) {
+        token.cancel();
+        log::info!("[TTS] cancel requested: {}", request_id);
+    }
+    Ok(())
+}
+
+fn emit_chunk(app_handle: &AppHandle, request_id: &str, data: String, done: bool) {
+    let _ = app_handle.emit("tts-audio-chunk", TtsAudioChunk {
+        request_id: request_id.to_string(),
+        data,
+        media_type: "audio/mpeg".to_string(),
+        done,
+    });
+}
+
+fn encode_chunk(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+/// 解析 OpenAI TTS 的密钥：请求里带了就用，否则按 API 配置 ID 查 keyring
+fn resolve_tts_api_key(request: &SynthesizeSpeechRequest) -> Option<String> {
+    if !request.api_key.is_empty() {
+        return Some(request.api_key.clone());
+    }
+    let config_id = request.api_config_id.as_deref().unwrap_or("openai");
+    KeyringEntry::new("BaiyuAISpace", &format!("api_keys_{}", config_id))
+        .ok()
+        .and_then(|entry| entry.get_password().ok())
+        .filter(|key| !key.is_empty())
+}
+
+async fn synthesize_openai(
+    request: &SynthesizeSpeechRequest,
+    app_handle: &AppHandle,
+    cancel_token: &CancellationToken,
+) -> Result<(), String> {
+    let api_key = resolve_tts_api_key(request).ok_or_else(|| "请先在设置中填写 OpenAI API 密钥".to_string())?;
+    let base_url = request
+        .base_url
+        .as_deref()
+        .filter(|u| !u.trim().is_empty())
+        .unwrap_or(DEFAULT_OPENAI_TTS_BASE_URL);
+    let url = format!("{}/audio/speech", base_url.trim_end_matches('/'));
+
+    let mut body = serde_json::json!({
+        "model": request.model.as_deref().unwrap_or(DEFAULT_OPENAI_TTS_MODEL),
+        "input": request.text,
+        "voice": request.voice.as_deref().unwrap_or(DEFAULT_OPENAI_TTS_VOICE),
+        "response_format": "mp3",
+    });
+    if let Some(speed) = request.speed {
+        body["speed"] = serde_json::json!(speed.clamp(0.25, 4.0));
+    }
+
+    let client = create_streaming_http_client("openai", &url).map_err(|e| friendly_err("创建网络连接失败，请重启应用后重试", e))?;
+    let response = client
+        .post(&url)
+        .bearer_auth(&api_key)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| friendly_err("无法连接语音合成服务，请检查网络", e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(friendly_err("语音合成失败，请检查密钥、模型和音色设置", format!("{} {}", status, error_text)));
+    }
+
+    let mut stream = response.bytes_stream();
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                log::info!("[TTS] openai speech cancelled: {}", request.request_id);
+                return Ok(());
+            }
+            chunk = stream.next() => {
+                match chunk {
+                    Some(Ok(bytes)) => emit_chunk(app_handle, &request.request_id, encode_chunk(&bytes), false),
+                    Some(Err(e)) => return Err(friendly_err("语音数据接收中断，请重试", e)),
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// edge-tts 的语速参数是百分比字符串，例如 1.25 -> "+25%"
+fn edge_rate(speed: f32) -> String {
+    let percent = ((speed.clamp(0.5, 2.0) - 1.0) * 100.0).round() as i32;
+    format!("{:+}%", percent)
+}
+
+async fn synthesize_edge(
+    request: &SynthesizeSpeechRequest,
+    app_handle: &AppHandle,
+    cancel_token: &CancellationToken,
+) -> Result<(), String> {
+    let mut cmd = tokio::process::Command::new("edge-tts");
+    cmd.arg("--voice")
+        .arg(request.voice.as_deref().unwrap_or(DEFAULT_EDGE_TTS_VOICE))
+        .arg("--text")
+        .arg(&request.text);
+    if let Some(speed) = request.speed {
+        // 以 "--rate=" 形式传参，避免 "-10%" 被当成一个选项
+        cmd.arg(format!("--rate={}", edge_rate(speed)));
+    }
+    // 不指定 --write-media 时 edge-tts 把音频写到标准输出
+    cmd.stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    hide_console_window(&mut cmd);
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| friendly_err("未找到 edge-tts，请先执行 pip install edge-tts 并确认它在 PATH 中", e))?;
+    let mut stdout = child.stdout.take().ok_or_else(|| "无法读取 edge-tts 输出".to_string())?;
+
+    let mut buf = vec![0u8; EDGE_TTS_READ_CHUNK];
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                let _ = child.kill().await;
+                log::info!("[TTS] edge speech cancelled: {}", request.request_id);
+                return Ok(());
+            }
+            read = stdout.read(&mut buf) => {
+                match read {
+                    Ok(0) => break,
+                    Ok(n) => emit_chunk(app_handle, &request.request_id, encode_chunk(&buf[..n]), false),
+                    Err(e) => return Err(friendly_err("读取 edge-tts 输出失败", e)),
+                }
+            }
+        }
+    }
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| friendly_err("edge-tts 运行失败", e))?;
+    if !output.status.success() {
+        return Err(friendly_err(
+            "edge-tts 合成失败，请检查音色名称和网络",
+            String::from_utf8_lossy(&output.stderr),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edge_rate_formats_signed_percent() {
+        assert_eq!(edge_rate(1.0), "+0%");
+        assert_eq!(edge_rate(1.25), "+25%");
+        assert_eq!(edge_rate(0.8), "-20%");
+        assert_eq!(edge_rate(5.0), "+100%");
+    }
+}
@@ -0,0 +1,418 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 网页抓取模块
+ *
+ * 功能说明:
+ * - fetch_url 命令：下载网页、去掉导航栏/脚本/页脚等页面框架，只留正文文本，
+ *   前端拿去拼进提示词；内置工具 builtin__fetch_url 也走这里
+ * - 只允许 http/https，目标地址（包括每一跳重定向）解析出的 IP 必须是公网地址，
+ *   回环、内网、链路本地、保留网段一律拒绝，防止模型或网页把请求引到本机和内网服务
+ * - 响应体最多读 MAX_FETCH_BYTES，正文最多保留 FETCH_TEXT_LIMIT 个字符
+ * - 同一套正文提取也输出 Markdown 版本（extract_readable_markdown），知识库导入 HTML 文件和网页时用
+ *
+ * 校验通过的 IP 会钉进这次请求的客户端（resolve_to_addrs），连接时不再重新解析，
+ * 避免校验和连接之间 DNS 被换掉。走代理时由代理解析域名，这一层只能做到事先校验。
+ */
+
+use crate::commands::constants::WEB_FETCH_TIMEOUT;
+use crate::commands::mcp::BUILTIN_USER_AGENT;
+use crate::commands::proxy::{apply_proxy, resolve_proxy, MCP_PROXY_SCOPE};
+use futures::StreamExt;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// 响应体最多读取的字节数，超出部分丢弃
+const MAX_FETCH_BYTES: usize = 2 * 1024 * 1024;
+/// 提取出的正文最多保留的字符数
+const FETCH_TEXT_LIMIT: usize = 15_000;
+/// 最多跟随的重定向次数
+const MAX_REDIRECTS: usize = 5;
+
+/// 正文所在的块级标签
+const BLOCK_SELECTOR: &str = "h1, h2, h3, h4, h5, h6, p, li, td, th, blockquote, pre, dd, dt";
+/// 页面框架类标签，里面的文字不算正文
+const BOILERPLATE_TAGS: &[&str] = &["script", "style", "noscript", "nav", "aside", "form", "template", "svg"];
+/// 页眉页脚：在 article / main 外面时是页面框架，在里面时是文章自己的标题区和落款
+const PAGE_CHROME_TAGS: &[&str] = &["header", "footer"];
+const BLOCK_TAGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6", "p", "li", "td", "th", "blockquote", "pre", "dd", "dt"];
+
+/// 抓取结果
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchedPage {
+    /// 跟随重定向之后的最终地址
+    pub url: String,
+    pub title: Option<String>,
+    pub content: String,
+    /// 响应体或正文是否因超过上限被截断
+    pub truncated: bool,
+    /// 内容是否是 HTML（content 为提取出的正文）；否则 content 是原始文本
+    #[serde(skip)]
+    pub is_html: bool,
+    /// 页面里的链接（已转成绝对地址、去掉 #锚点），知识库爬取整站时用
+    #[serde(skip)]
+    pub links: Vec<String>,
+}
+
+/// 抓取网页并提取正文，用于拼进提示词
+#[tauri::command]
+pub async fn fetch_url(url: String) -> Result<FetchedPage, String> {
+    fetch_page(&url).await
+}
+
+/// 抓取网页并提取正文（fetch_url 命令和内置工具共用）
+pub(crate) async fn fetch_page(url: &str) -> Result<FetchedPage, String> {
+    fetch(url, false, Some(FETCH_TEXT_LIMIT)).await
+}
+
+/// 抓取网页并把正文提取成 Markdown，不截断正文（知识库导入网页用）
+pub(crate) async fn fetch_page_markdown(url: &str) -> Result<FetchedPage, String> {
+    fetch(url, true, None).await
+}
+
+/// 抓取网页：as_markdown 决定正文输出成 Markdown 还是纯文本，text_limit 是正文最多保留的字符数
+async fn fetch(url: &str, as_markdown: bool, text_limit: Option<usize>) -> Result<FetchedPage, String> {
+    let mut current = reqwest::Url::parse(url.trim()).map_err(|e| format!("网址无效: {}", e))?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let addrs = resolve_public_addrs(&current).await?;
+        let client = pinned_client(&current, &addrs)?;
+        let response = client
+            .get(current.clone())
+            .send()
+            .await
+            .map_err(|e| format!("网页抓取失败: {}", e))?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| format!("网页抓取失败: HTTP {} 没有给出跳转地址", response.status()))?;
+            current = current.join(location).map_err(|e| format!("跳转地址无效: {}", e))?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(format!("网页抓取失败: HTTP {}", response.status()));
+        }
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("text/html")
+            .to_ascii_lowercase();
+        if !is_textual(&content_type) {
+            return Err(format!("不支持的内容类型: {}", content_type));
+        }
+
+        let (body, body_truncated) = read_limited(response).await?;
+        let is_html = content_type.contains("html") || content_type.contains("xml");
+        let (title, mut content) = if is_html {
+            extract_readable(&body, as_markdown)
+        } else {
+            (None, body.trim().to_string())
+        };
+        let links = if is_html { extract_links(&body, &current) } else { Vec::new() };
+        let text_truncated = text_limit.is_some_and(|limit| content.chars().count() > limit);
+        if let Some(limit) = text_limit.filter(|_| text_truncated) {
+            content = content.chars().take(limit).collect();
+        }
+
+        return Ok(FetchedPage {
+            url: current.to_string(),
+            title,
+            content,
+            truncated: body_truncated || text_truncated,
+            is_html,
+            links,
+        });
+    }
+
+    Err(format!("重定向次数超过 {} 次", MAX_REDIRECTS))
+}
+
+/// 解析目标主机，要求全部地址都是公网地址；只要混进一个内网地址就整体拒绝
+async fn resolve_public_addrs(url: &reqwest::Url) -> Result<Vec<SocketAddr>, String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("只支持 http/https 网址，收到: {}", url.scheme()));
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url.host_str().ok_or_else(|| "网址缺少主机名".to_string())?;
+    // IPv6 字面量在 host_str 里带方括号
+    let addrs: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("无法解析域名 {}: {}", host, e))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err("域名没有解析到任何地址".to_string());
+    }
+    if let Some(blocked) = addrs.iter().find(|a| !is_public_ip(a.ip())) {
+        return Err(format!("出于安全考虑，不允许访问本机或内网地址: {}", blocked.ip()));
+    }
+    Ok(addrs)
+}
+
+/// 每一跳单独建客户端：不自动跟随重定向（要逐跳校验），并把校验过的 IP 钉住
+fn pinned_client(url: &reqwest::Url, addrs: &[SocketAddr]) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(BUILTIN_USER_AGENT)
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(WEB_FETCH_TIMEOUT);
+    if let Some(domain) = url.domain() {
+        builder = builder.resolve_to_addrs(domain, addrs);
+    }
+    apply_proxy(builder, &resolve_proxy(Some(MCP_PROXY_SCOPE), url.as_str()))
+        .build()
+        .map_err(|e| format!("创建 HTTP 客户端失败: {}", e))
+}
+
+fn is_textual(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.contains("html")
+        || content_type.contains("xml")
+        || content_type.contains("json")
+}
+
+/// 流式读取响应体，超过 MAX_FETCH_BYTES 就停下并标记截断
+async fn read_limited(response: reqwest::Response) -> Result<(String, bool), String> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut truncated = false;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| format!("读取网页内容失败: {}", e))?;
+        let room = MAX_FETCH_BYTES - bytes.len();
+        if chunk.len() > room {
+            bytes.extend_from_slice(&chunk[..room]);
+            truncated = true;
+            break;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok((String::from_utf8_lossy(&bytes).into_owned(), truncated))
+}
+
+/// 是否公网地址：回环、私有网段、链路本地、CGNAT、组播、文档/测试保留网段等都不算
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (b & 0xc0) == 64)
+                || (a == 198 && (b & 0xfe) == 18))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let seg = v6.segments();
+            // NAT64 前缀 64:ff9b::/96 里嵌的是 IPv4 地址
+            if seg[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = seg[6].to_be_bytes();
+                let [c, d] = seg[7].to_be_bytes();
+                return is_public_ip(IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d)));
+            }
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (seg[0] & 0xfe00) == 0xfc00
+                || (seg[0] & 0xffc0) == 0xfe80
+                || (seg[0] == 0x2001 && seg[1] == 0x0db8))
+        }
+    }
+}
+
+fn has_ancestor_in(el: &scraper::ElementRef, tags: &[&str]) -> bool {
+    el.ancestors()
+        .filter_map(|n| n.value().as_element())
+        .any(|e| tags.contains(&e.name()))
+}
+
+/// 按祖先元素判断是否在页面框架里；<article><header><h1>…</h1></header> 里的标题要保留
+fn in_boilerplate<'a>(ancestors: impl Iterator<Item = &'a scraper::node::Element>) -> bool {
+    let ancestors: Vec<&scraper::node::Element> = ancestors.collect();
+    let in_content = ancestors
+        .iter()
+        .any(|e| matches!(e.name(), "article" | "main") || e.attr("role") == Some("main"));
+    ancestors
+        .iter()
+        .any(|e| BOILERPLATE_TAGS.contains(&e.name()) || (!in_content && PAGE_CHROME_TAGS.contains(&e.name())))
+}
+
+/// 从 HTML 里提取标题和 Markdown 正文：标题保留层级、列表项带 "- "、pre 包成代码块，
+/// 块与块之间空一行。知识库导入 HTML 文件时用，标题层级会记进分块的 heading_path
+pub(crate) fn extract_readable_markdown(html: &str) -> (Option<String>, String) {
+    extract_readable(html, true)
+}
+
+/// 提取页面里所有 http/https 链接，按出现顺序去重
+fn extract_links(html: &str, base: &reqwest::Url) -> Vec<String> {
+    let document = scraper::Html::parse_document(html);
+    let selector = scraper::Selector::parse("a[href]").unwrap();
+    let mut links: Vec<String> = Vec::new();
+    for href in document.select(&selector).filter_map(|a| a.value().attr("href")) {
+        let Ok(mut url) = base.join(href.trim()) else { continue };
+        if !matches!(url.scheme(), "http" | "https") {
+            continue;
+        }
+        url.set_fragment(None);
+        let url = url.to_string();
+        if !links.contains(&url) {
+            links.push(url);
+        }
+    }
+    links
+}
+
+/// 块级标签按 Markdown 语法输出
+fn markdown_block(tag: &str, text: &str) -> String {
+    match tag {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = tag[1..].parse().unwrap_or(1);
+            format!("{} {}", "#".repeat(level), text)
+        }
+        "li" => format!("- {}", text),
+        "blockquote" => format!("> {}", text),
+        "pre" => format!("```\n{}\n```", text),
+        _ => text.to_string(),
+    }
+}
+
+/// 从 HTML 里提取标题和正文：优先取 article / main，去掉页面框架类标签里的文字，
+/// 嵌套的块级标签只取最外层一次，避免同一段文字重复。
+/// as_markdown 为 false 时每个块输出成一行纯文本
+fn extract_readable(html: &str, as_markdown: bool) -> (Option<String>, String) {
+    let document = scraper::Html::parse_document(html);
+    let selector = |s: &str| scraper::Selector::parse(s).unwrap();
+
+    let title = document
+        .select(&selector("title"))
+        .next()
+        .map(|t| t.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|t| !t.is_empty());
+
+    let root = ["article", "main", "[role=main]", "body"]
+        .iter()
+        .find_map(|s| {
+            document
+                .select(&selector(s))
+                .find(|el| !el.text().collect::<String>().trim().is_empty())
+        });
+    let Some(root) = root else {
+        return (title, String::new());
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    for el in root.select(&selector(BLOCK_SELECTOR)) {
+        if in_boilerplate(el.ancestors().filter_map(|n| n.value().as_element())) || has_ancestor_in(&el, BLOCK_TAGS) {
+            continue;
+        }
+        let line = if el.value().name() == "pre" {
+            el.text().collect::<String>().trim().to_string()
+        } else {
+            el.text().collect::<String>().split_whitespace().collect::<Vec<_>>().join(" ")
+        };
+        if !line.is_empty() {
+            lines.push(if as_markdown { markdown_block(el.value().name(), &line) } else { line });
+        }
+    }
+
+    if lines.is_empty() {
+        // 页面没用语义化的块级标签：取根节点下所有不在页面框架里的文字
+        let words: Vec<String> = root
+            .descendants()
+            .filter_map(|node| {
+                let text = node.value().as_text()?;
+                let inside_boilerplate = in_boilerplate(node.ancestors().filter_map(|n| n.value().as_element()));
+                (!inside_boilerplate).then(|| text.split_whitespace().collect::<Vec<_>>().join(" "))
+            })
+            .filter(|t| !t.is_empty())
+            .collect();
+        lines.push(words.join(" "));
+    }
+
+    let separator = if as_markdown { "\n\n" } else { "\n" };
+    (title, lines.join(separator).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_and_reserved_addresses_are_rejected() {
+        for blocked in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "100.64.0.1", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public_ip(blocked.parse().unwrap()), "{} should be blocked", blocked);
+        }
+        for allowed in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(allowed.parse().unwrap()), "{} should be allowed", allowed);
+        }
+    }
+
+    #[test]
+    fn readable_text_skips_boilerplate_and_nested_duplicates() {
+        let html = r#"<html><head><title> 退货政策 </title><style>p{}</style></head><body>
+            <nav><ul><li>首页</li><li>关于</li></ul></nav>
+            <main>
+              <h1>退货政策</h1>
+              <p>收货后   7 天内可以退货。</p>
+              <ul><li><p>保留原包装</p></li></ul>
+              <script>track()</script>
+            </main>
+            <footer><p>版权所有</p></footer>
+        </body></html>"#;
+        let (title, text) = extract_readable(html, false);
+        assert_eq!(title.as_deref(), Some("退货政策"));
+        assert_eq!(text, "退货政策\n收货后 7 天内可以退货。\n保留原包装");
+    }
+
+    #[test]
+    fn readable_markdown_keeps_headings_lists_and_link_text() {
+        let html = r#"<html><body><header><p>示例文档站</p></header><nav><a href="/">首页</a></nav><article>
+            <header><h1>安装指南</h1></header><p>先阅读 <a href="/req">系统要求</a>。</p>
+            <h2>Windows</h2><ul><li>下载安装包</li><li>双击运行</li></ul>
+            <pre>setup.exe /S</pre>
+        </article></body></html>"#;
+        let (_, text) = extract_readable_markdown(html);
+        assert_eq!(
+            text,
+            "# 安装指南\n\n先阅读 系统要求。\n\n## Windows\n\n- 下载安装包\n\n- 双击运行\n\n```\nsetup.exe /S\n```"
+        );
+    }
+
+    #[test]
+    fn links_are_resolved_deduplicated_and_stripped_of_fragments() {
+        let base = reqwest::Url::parse("https://docs.example.com/guide/intro").unwrap();
+        let html = r##"<a href="setup#win">安装</a><a href="/guide/setup">安装</a>
+            <a href="mailto:a@b.c">邮件</a><a href="https://other.org/">外站</a><a href="#top">顶部</a>"##;
+        assert_eq!(
+            extract_links(html, &base),
+            ["https://docs.example.com/guide/setup", "https://other.org/", "https://docs.example.com/guide/intro"]
+        );
+    }
+
+    #[tokio::test]
+    async fn non_http_schemes_and_loopback_urls_are_refused() {
+        let file = reqwest::Url::parse("file:///etc/passwd").unwrap();
+        assert!(resolve_public_addrs(&file).await.is_err());
+        let local = reqwest::Url::parse("http://127.0.0.1:8080/admin").unwrap();
+        assert!(resolve_public_addrs(&local).await.unwrap_err().contains("127.0.0.1"));
+    }
+}
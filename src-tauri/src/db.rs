@@ -14,16 +14,94 @@
  * 
  * 数据库表:
  * - sessions: 聊天会话表
- * - messages: 消息表 (关联 sessions)
+ * - session_tags: 会话标签
+ * - messages: 消息表 (关联 sessions)，回复还记录生成它的服务商、模型、结束原因、耗时和 token 数
  * - mcp_servers: MCP 服务器配置表
+ * - message_usage: 每条回复的 token 用量和估算费用
+ * - personas: 角色预设 (system prompt + 默认参数)
+ * - bookmarks / bookmark_collections: 收藏的消息和收藏夹
+ * - session_kbs: 会话绑定的知识库 (每轮对话自动检索)
+ *
+ * 会话和消息删除时只打上 deleted_at 标记（回收站），读取时都要跳过带标记的数据，
+ * 超过回收站保留期限（见 commands::retention）后彻底删除。会话可以归档（archived_at），
+ * 归档的会话不在历史记录主列表里显示，在里面继续聊天时自动取消归档。
+ *
+ * rusqlite 的操作都是阻塞的，async 命令里通过 DbState::run 放到阻塞线程池执行。
  */
 
-use crate::types::{ChatMessage, ChatSession, MCPServer, MCPServerType, Skill};
+use crate::types::{
+    ArchivedSession, Bookmark, BookmarkCollection, BookmarkFilter, ChatMessage, ChatSession, ChatStats, ChatStatsGroup,
+    GenerationInfo, MCPServer, MCPServerType, MessagePage, MessageUsage, Persona, SessionFilter, SessionOverview,
+    SessionSort, SessionSummary, SessionTagCount, Skill, Trash, TrashedMessage, TrashedSession,
+};
 use keyring::Entry;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use tauri::Manager;
 
 const MCP_KEYRING_SERVICE: &str = "mcp_api_key";
+/// 上次生成到一半应用被关掉的回复，重启后标上的错误信息
+const INTERRUPTED_REPLY_NOTICE: &str = "应用在生成过程中被关闭，以上是已保存的部分回复";
+
+/// 撞上另一个连接持有写锁时最多重试这么久，而不是立刻报 database is locked
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// 会话列表里最后一条消息预览的最大字符数
+const SESSION_PREVIEW_CHARS: usize = 80;
+/// 收藏列表里消息预览的最大字符数，收藏的多是较长的回答，比会话预览长一些
+const BOOKMARK_PREVIEW_CHARS: usize = 200;
+/// get_messages_page 一页的默认条数和上限
+pub const DEFAULT_MESSAGE_PAGE_SIZE: usize = 100;
+pub const MAX_MESSAGE_PAGE_SIZE: usize = 1000;
+/// 回收站里的会话、消息和知识库文档默认保留的天数，过期后彻底删除（可以在清理策略里修改）
+pub const TRASH_RETENTION_DAYS: i64 = 30;
+
+/// 当前的回收站保留天数，启动时由 commands::retention 按清理策略设置
+static TRASH_RETENTION: AtomicI64 = AtomicI64::new(TRASH_RETENTION_DAYS);
+
+/// messages 表里记录回复生成信息的列（见 GenerationInfo），按 generation_from_row 读取的顺序排列
+const GENERATION_COLUMNS: [(&str, &str); 7] = [
+    ("provider", "TEXT"),
+    ("model", "TEXT"),
+    ("finish_reason", "TEXT"),
+    ("latency_ms", "INTEGER"),
+    ("input_tokens", "INTEGER"),
+    ("output_tokens", "INTEGER"),
+    ("tokens_estimated", "INTEGER"),
+];
+
+/// 没有被删除、所在会话也没有被删除的消息（消息表别名须为 m）
+pub(crate) const LIVE_MESSAGE: &str = "m.deleted_at IS NULL AND NOT EXISTS \
+     (SELECT 1 FROM sessions trashed WHERE trashed.id = m.session_id AND trashed.deleted_at IS NOT NULL)";
+
+/// 回收站保留天数
+pub fn trash_retention_days() -> i64 {
+    TRASH_RETENTION.load(Ordering::Relaxed)
+}
+
+/// 修改回收站保留天数（至少 1 天）
+pub fn set_trash_retention_days(days: i64) {
+    TRASH_RETENTION.store(days.max(1), Ordering::Relaxed);
+}
+
+/// 删除时间早于这个时间戳 (毫秒) 的回收站数据已经过期
+pub fn trash_expiry_cutoff() -> i64 {
+    chrono::Utc::now().timestamp_millis() - trash_retention_days() * 24 * 60 * 60 * 1000
+}
+
+/// 打开 app.db 的连接并做好每个连接都需要的设置
+///
+/// 各模块都是按操作新开短生命周期的连接，这些设置必须在每个连接上都做：
+/// - key：开启了整库加密时先设置密钥（见 db_encryption），之后才能读写
+/// - busy_timeout：导入、Agent 循环等后台写入和前台操作同时进行时等锁重试
+/// - journal_mode=WAL：写入期间其他连接照常读；WAL 记在数据库文件里，已经是 WAL 时是空操作
+/// - foreign_keys=ON：外键按连接生效，打开后 ON DELETE CASCADE 才会真的级联
+pub fn open_connection<P: AsRef<std::path::Path>>(path: P) -> rusqlite::Result<rusqlite::Connection> {
+    let conn = rusqlite::Connection::open(path)?;
+    crate::db_encryption::apply_key(&conn)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
+    Ok(conn)
+}
 
 pub struct Database {
     pub path: String,
@@ -45,7 +123,7 @@ impl Database {
         std::fs::create_dir_all(&app_dir).expect("Failed to create app data dir");
         let db_path = app_dir.join("app.db");
         
-        let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+        let conn = open_connection(&db_path).expect("Failed to open database");
         
         Self {
             path: db_path.to_str().unwrap().to_string(),
@@ -64,8 +142,6 @@ impl Database {
      * 同时创建索引以优化查询性能
      */
     pub fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
-
         self.conn.execute(
             r#"
             CREATE TABLE IF NOT EXISTS sessions (
@@ -95,6 +171,50 @@ impl Database {
             log::info!("Database migration: added api_config_id column");
         }
 
+        let has_persona_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('sessions') WHERE name = 'persona_id'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_persona_column {
+            self.conn.execute("ALTER TABLE sessions ADD COLUMN persona_id TEXT", [])?;
+            log::info!("Database migration: added persona_id column");
+        }
+
+        let has_pinned_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('sessions') WHERE name = 'pinned'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_pinned_column {
+            self.conn.execute("ALTER TABLE sessions ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0", [])?;
+            log::info!("Database migration: added pinned column");
+        }
+
+        let has_deleted_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('sessions') WHERE name = 'deleted_at'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_deleted_column {
+            self.conn.execute("ALTER TABLE sessions ADD COLUMN deleted_at INTEGER", [])?;
+            log::info!("Database migration: added sessions.deleted_at column");
+        }
+
+        let has_archived_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('sessions') WHERE name = 'archived_at'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_archived_column {
+            self.conn.execute("ALTER TABLE sessions ADD COLUMN archived_at INTEGER", [])?;
+            log::info!("Database migration: added sessions.archived_at column");
+        }
+
         self.conn.execute(
             r#"
             CREATE TABLE IF NOT EXISTS messages (
@@ -110,6 +230,119 @@ impl Database {
             [],
         )?;
 
+        let has_seed_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('messages') WHERE name = 'seed'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_seed_column {
+            self.conn.execute("ALTER TABLE messages ADD COLUMN seed INTEGER", [])?;
+            log::info!("Database migration: added messages.seed column");
+        }
+
+        let has_partial_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('messages') WHERE name = 'partial'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_partial_column {
+            self.conn.execute("ALTER TABLE messages ADD COLUMN partial INTEGER NOT NULL DEFAULT 0", [])?;
+            log::info!("Database migration: added messages.partial column");
+        }
+
+        let has_message_deleted_column = self.conn.query_row(
+            "SELECT 1 FROM pragma_table_info('messages') WHERE name = 'deleted_at'",
+            [],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+        if !has_message_deleted_column {
+            self.conn.execute("ALTER TABLE messages ADD COLUMN deleted_at INTEGER", [])?;
+            log::info!("Database migration: added messages.deleted_at column");
+        }
+
+        for (column, column_type) in GENERATION_COLUMNS {
+            let exists = self.conn.query_row(
+                "SELECT 1 FROM pragma_table_info('messages') WHERE name = ?1",
+                [column],
+                |_| Ok(true),
+            )
+            .unwrap_or(false);
+            if !exists {
+                self.conn.execute(&format!("ALTER TABLE messages ADD COLUMN {} {}", column, column_type), [])?;
+                log::info!("Database migration: added messages.{} column", column);
+            }
+        }
+
+        // 回复引用的知识库片段（见 knowledge_base::rag），chunks 为 RetrievedChunk 的 JSON 数组；
+        // 回复本身由前端在流结束时才保存，所以这里不对 messages 建外键
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_sources (
+                message_id TEXT PRIMARY KEY,
+                session_id TEXT NOT NULL,
+                chunks TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+            )
+            "#,
+            [],
+        )?;
+
+        // 聊天记录语义检索用的消息向量（见 knowledge_base::history），每条消息
+        // 只保留最近一次生成的向量，embedding_model 记着是哪个模型生成的
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_vectors (
+                message_id TEXT PRIMARY KEY,
+                embedding_model TEXT NOT NULL,
+                vector BLOB NOT NULL,
+                FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+            )
+            "#,
+            [],
+        )?;
+
+        // 会话标签：一个会话可以有多个标签，会话列表可以按标签筛选
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS session_tags (
+                session_id TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (session_id, tag),
+                FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+            )
+            "#,
+            [],
+        )?;
+
+        // 会话绑定的知识库：绑定后该会话每一轮都自动从这些知识库检索（见 knowledge_base::rag）。
+        // 知识库由知识库模块删除，这里没有指向 knowledge_bases 的外键，所以解绑在 delete_knowledge_base 里显式做
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS session_kbs (
+                session_id TEXT NOT NULL,
+                kb_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (session_id, kb_id),
+                FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+            )
+            "#,
+            [],
+        )?;
+
+        // 启动时还是 partial 的回复都是上次生成到一半应用被关掉留下的：
+        // 保留已生成的内容，标上中断原因，之后按普通消息处理
+        let recovered = self.conn.execute(
+            "UPDATE messages SET partial = 0, error = CASE WHEN error IS NULL OR error = '' THEN ?1 ELSE error END WHERE partial = 1",
+            [INTERRUPTED_REPLY_NOTICE],
+        )?;
+        if recovered > 0 {
+            log::info!("Recovered {} interrupted partial replies", recovered);
+        }
+
         self.conn.execute(
             r#"
             CREATE TABLE IF NOT EXISTS mcp_servers (
@@ -148,6 +381,79 @@ impl Database {
             [],
         )?;
 
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS personas (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                system_prompt TEXT NOT NULL DEFAULT '',
+                max_tokens INTEGER,
+                temperature REAL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )
+            "#,
+            [],
+        )?;
+
+        // 收藏：每条消息最多收藏一次，可以放进一个收藏夹。
+        // 消息彻底删除时收藏随之删除；收藏夹删除时收藏保留，变回未分组
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS bookmark_collections (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )
+            "#,
+            [],
+        )?;
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS bookmarks (
+                message_id TEXT PRIMARY KEY,
+                collection_id TEXT,
+                note TEXT,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+                FOREIGN KEY (collection_id) REFERENCES bookmark_collections(id) ON DELETE SET NULL
+            )
+            "#,
+            [],
+        )?;
+
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS session_summaries (
+                session_id TEXT PRIMARY KEY,
+                summary TEXT NOT NULL,
+                covered_until INTEGER NOT NULL,
+                covered_messages INTEGER NOT NULL DEFAULT 0,
+                updated_at INTEGER NOT NULL,
+                FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+            )
+            "#,
+            [],
+        )?;
+
+        // 用量记录不挂外键：会话删除后，当月已经花掉的钱仍应计入月度累计。
+        self.conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_usage (
+                message_id TEXT PRIMARY KEY,
+                session_id TEXT NOT NULL,
+                provider TEXT NOT NULL,
+                model TEXT NOT NULL,
+                input_tokens INTEGER NOT NULL DEFAULT 0,
+                output_tokens INTEGER NOT NULL DEFAULT 0,
+                cost_usd REAL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+            [],
+        )?;
+
         self.conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_sessions_updated_at ON sessions(updated_at DESC)",
             [],
@@ -160,6 +466,11 @@ impl Database {
             "CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp)",
             [],
         )?;
+        // 会话列表取最后一条消息、分页读消息都按 (session_id, timestamp) 查
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_session_timestamp ON messages(session_id, timestamp)",
+            [],
+        )?;
         self.conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_mcp_servers_enabled ON mcp_servers(enabled)",
             [],
@@ -168,6 +479,28 @@ impl Database {
             "CREATE INDEX IF NOT EXISTS idx_skills_enabled ON skills(enabled)",
             [],
         )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_message_usage_session_id ON message_usage(session_id)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_message_usage_created_at ON message_usage(created_at)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_bookmarks_collection_id ON bookmarks(collection_id)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_session_tags_tag ON session_tags(tag)",
+            [],
+        )?;
+
+        match self.purge_expired_trash() {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {} expired sessions/messages from trash", purged),
+            Err(e) => log::warn!("Failed to purge expired trash: {}", e),
+        }
 
         log::info!("Database initialized at: {}", self.path);
         Ok(())
@@ -206,74 +539,427 @@ impl Database {
     }
 
     /**
-     * 删除会话
+     * 导入外部会话（连同消息）；同 ID 的会话已经存在（包括在回收站里）时不做任何事
+     *
+     * @param session: 要导入的会话，messages 是全部消息
+     * @return 是否导入了新会话
+     */
+    pub fn import_session(&self, session: &ChatSession) -> Result<bool, Box<dyn std::error::Error>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let inserted = tx.execute(
+            r#"
+            INSERT OR IGNORE INTO sessions (id, title, provider, model, api_config_id, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#,
+            rusqlite::params![
+                &session.id,
+                &session.title,
+                &session.provider,
+                &session.model,
+                &session.api_config_id,
+                session.created_at,
+                session.updated_at,
+            ],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO messages (id, session_id, role, content, timestamp, error, partial)
+                 VALUES (?1, ?2, ?3, ?4, ?5, '', 0)",
+            )?;
+            for m in &session.messages {
+                stmt.execute(rusqlite::params![&m.id, &session.id, &m.role, &m.content, m.timestamp])?;
+            }
+        }
+        tx.commit()?;
+
+        log::info!("Session imported: {} ({} messages)", session.id, session.messages.len());
+        Ok(true)
+    }
+
+    /**
+     * 复制会话：会话设置、全部消息（不含回收站里的）、引用来源、绑定的知识库、标签和滚动摘要
+     * 都复制到一个新 ID 下，原会话不受影响；用量记录和检索向量不复制
+     *
+     * @param session_id: 要复制的会话 ID
+     * @param title: 新会话标题，None 时用 “原标题 (副本)”
+     * @return 新会话 ID
+     */
+    pub fn duplicate_session(&self, session_id: &str, title: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+        let new_id = uuid::Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().timestamp_millis();
+        let tx = self.conn.unchecked_transaction()?;
+        let copied = tx.execute(
+            r#"
+            INSERT INTO sessions (id, title, provider, model, api_config_id, persona_id, created_at, updated_at)
+            SELECT ?1, COALESCE(?2, title || ' (副本)'), provider, model, api_config_id, persona_id, ?3, ?3
+            FROM sessions WHERE id = ?4 AND deleted_at IS NULL
+            "#,
+            rusqlite::params![&new_id, title, now, session_id],
+        )?;
+        if copied == 0 {
+            return Err(format!("Session not found: {}", session_id).into());
+        }
+
+        let message_ids: Vec<String> = {
+            let mut stmt = tx.prepare(
+                "SELECT id FROM messages WHERE session_id = ?1 AND deleted_at IS NULL ORDER BY timestamp ASC, rowid ASC",
+            )?;
+            let ids = stmt.query_map([session_id], |row| row.get(0))?.collect::<Result<_, _>>()?;
+            ids
+        };
+        {
+            let mut copy_message = tx.prepare(
+                r#"
+                INSERT INTO messages (id, session_id, role, content, timestamp, error, seed, partial,
+                                      provider, model, finish_reason, latency_ms, input_tokens, output_tokens, tokens_estimated)
+                SELECT ?1, ?2, role, content, timestamp, error, seed, partial,
+                       provider, model, finish_reason, latency_ms, input_tokens, output_tokens, tokens_estimated
+                FROM messages WHERE id = ?3
+                "#,
+            )?;
+            let mut copy_sources = tx.prepare(
+                r#"
+                INSERT INTO message_sources (message_id, session_id, chunks, created_at)
+                SELECT ?1, ?2, chunks, created_at FROM message_sources WHERE message_id = ?3
+                "#,
+            )?;
+            for old_id in &message_ids {
+                let new_message_id = uuid::Uuid::new_v4().to_string();
+                copy_message.execute(rusqlite::params![&new_message_id, &new_id, old_id])?;
+                copy_sources.execute(rusqlite::params![&new_message_id, &new_id, old_id])?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO session_kbs (session_id, kb_id, created_at) SELECT ?1, kb_id, ?2 FROM session_kbs WHERE session_id = ?3",
+            rusqlite::params![&new_id, now, session_id],
+        )?;
+        tx.execute(
+            "INSERT INTO session_tags (session_id, tag) SELECT ?1, tag FROM session_tags WHERE session_id = ?2",
+            rusqlite::params![&new_id, session_id],
+        )?;
+        tx.execute(
+            r#"
+            INSERT INTO session_summaries (session_id, summary, covered_until, covered_messages, updated_at)
+            SELECT ?1, summary, covered_until, covered_messages, updated_at FROM session_summaries WHERE session_id = ?2
+            "#,
+            rusqlite::params![&new_id, session_id],
+        )?;
+        tx.commit()?;
+
+        log::info!("Session duplicated: {} -> {} ({} messages)", session_id, new_id, message_ids.len());
+        Ok(new_id)
+    }
+
+    /**
+     * 删除会话：移进回收站，消息原样保留，恢复后一起回来
      * 
      * @param session_id: 要删除的会话 ID
      */
     pub fn delete_session(&self, session_id: &str) -> Result<(), Box<dyn std::error::Error>> {
         self.conn.execute(
-            "DELETE FROM sessions WHERE id = ?1",
+            "UPDATE sessions SET deleted_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+            rusqlite::params![chrono::Utc::now().timestamp_millis(), session_id],
+        )?;
+
+        log::info!("Session moved to trash: {}", session_id);
+        Ok(())
+    }
+
+    /**
+     * 从回收站恢复会话
+     *
+     * @param session_id: 会话 ID
+     */
+    pub fn restore_session(&self, session_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let restored = self.conn.execute(
+            "UPDATE sessions SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL",
             [session_id],
         )?;
+        if restored == 0 {
+            return Err(format!("Session not in trash: {}", session_id).into());
+        }
 
-        log::info!("Session deleted: {}", session_id);
+        log::info!("Session restored: {}", session_id);
         Ok(())
     }
 
     /**
-     * 获取所有会话
-     * 按最后更新时间倒序排列
-     * 
-     * @return 会话列表 (包含消息)
+     * 彻底删除回收站里的会话（消息随外键级联删除）
+     *
+     * @param session_id: 会话 ID
      */
-    pub fn get_sessions(&self) -> Result<Vec<ChatSession>, Box<dyn std::error::Error>> {
+    pub fn purge_session(&self, session_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let purged = self.conn.execute(
+            "DELETE FROM sessions WHERE id = ?1 AND deleted_at IS NOT NULL",
+            [session_id],
+        )?;
+        if purged == 0 {
+            return Err(format!("Session not in trash: {}", session_id).into());
+        }
+        self.conn.execute(
+            "DELETE FROM session_summaries WHERE session_id = ?1",
+            [session_id],
+        )?;
+
+        log::info!("Session purged: {}", session_id);
+        Ok(())
+    }
+
+    /**
+     * 列出回收站里的会话和单独删除的消息（按删除时间倒序）
+     */
+    pub fn list_trash(&self) -> Result<Trash, Box<dyn std::error::Error>> {
         let mut stmt = self.conn.prepare(
             r#"
-            SELECT id, title, provider, model, api_config_id, created_at, updated_at 
-            FROM sessions 
-            ORDER BY updated_at DESC
+            SELECT s.id, s.title, s.provider, s.model,
+                   (SELECT COUNT(*) FROM messages m WHERE m.session_id = s.id AND m.deleted_at IS NULL),
+                   s.deleted_at
+            FROM sessions s
+            WHERE s.deleted_at IS NOT NULL
+            ORDER BY s.deleted_at DESC
             "#,
         )?;
+        let sessions = stmt
+            .query_map([], |row| {
+                Ok(TrashedSession {
+                    id: row.get(0)?,
+                    title: row.get(1)?,
+                    provider: row.get(2)?,
+                    model: row.get(3)?,
+                    message_count: row.get(4)?,
+                    deleted_at: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let rows = stmt.query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, i64>(5)?,
-                row.get::<_, i64>(6)?,
-            ))
-        })?;
+        // 会话整个在回收站里的消息随会话一起列出，这里不再单独列
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT m.id, m.session_id, s.title, m.role, substr(m.content, 1, ?1), m.timestamp, m.deleted_at
+            FROM messages m
+            JOIN sessions s ON s.id = m.session_id
+            WHERE m.deleted_at IS NOT NULL AND s.deleted_at IS NULL
+            ORDER BY m.deleted_at DESC, m.timestamp DESC
+            "#,
+        )?;
+        let messages = stmt
+            .query_map([SESSION_PREVIEW_CHARS as i64], |row| {
+                let preview: String = row.get(4)?;
+                Ok(TrashedMessage {
+                    id: row.get(0)?,
+                    session_id: row.get(1)?,
+                    session_title: row.get(2)?,
+                    role: row.get(3)?,
+                    preview: preview.split_whitespace().collect::<Vec<_>>().join(" "),
+                    timestamp: row.get(5)?,
+                    deleted_at: row.get(6)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let mut sessions = Vec::new();
-        for row in rows {
-            let (id, title, provider, model, api_config_id, created_at, updated_at) = row?;
-            let messages = self.get_messages(&id)?;
-            
-            sessions.push(ChatSession {
-                id,
-                title,
-                provider,
-                model,
-                api_config_id,
-                created_at,
-                updated_at,
-                messages,
-            });
+        Ok(Trash { sessions, messages, retention_days: trash_retention_days() })
+    }
+
+    /**
+     * 清空回收站：彻底删除所有带删除标记的会话和消息
+     *
+     * @return 彻底删除的会话和消息条数
+     */
+    pub fn empty_trash(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        self.purge_trash_before(i64::MAX)
+    }
+
+    /**
+     * 彻底删除回收站里超过保留期限的会话和消息
+     *
+     * @return 彻底删除的会话和消息条数
+     */
+    pub fn purge_expired_trash(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        self.purge_trash_before(trash_expiry_cutoff())
+    }
+
+    fn purge_trash_before(&self, cutoff: i64) -> Result<usize, Box<dyn std::error::Error>> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM message_sources WHERE message_id IN (SELECT id FROM messages WHERE deleted_at < ?1)
+                OR session_id IN (SELECT id FROM sessions WHERE deleted_at < ?1)",
+            [cutoff],
+        )?;
+        tx.execute(
+            "DELETE FROM session_summaries WHERE session_id IN (SELECT id FROM sessions WHERE deleted_at < ?1)",
+            [cutoff],
+        )?;
+        let messages = tx.execute("DELETE FROM messages WHERE deleted_at < ?1", [cutoff])?;
+        let sessions = tx.execute("DELETE FROM sessions WHERE deleted_at < ?1", [cutoff])?;
+        tx.commit()?;
+        Ok(messages + sessions)
+    }
+
+    /**
+     * 统计回收站里删除时间早于 cutoff 的会话和消息（清理策略的预演用）
+     *
+     * @return (会话数, 消息数)
+     */
+    pub fn count_trash_before(&self, cutoff: i64) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        let counts = self.conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM sessions WHERE deleted_at < ?1), (SELECT COUNT(*) FROM messages WHERE deleted_at < ?1)",
+            [cutoff],
+            |row| Ok((row.get::<_, i64>(0)? as usize, row.get::<_, i64>(1)? as usize)),
+        )?;
+        Ok(counts)
+    }
+
+    /**
+     * 归档或取消归档会话：归档的会话不在历史记录的主列表里显示，数据原样保留
+     *
+     * @param session_id: 会话 ID
+     * @param archived: 是否归档
+     */
+    pub fn set_session_archived(&self, session_id: &str, archived: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let archived_at = archived.then(|| chrono::Utc::now().timestamp_millis());
+        let updated = self.conn.execute(
+            "UPDATE sessions SET archived_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+            rusqlite::params![archived_at, session_id],
+        )?;
+        if updated == 0 {
+            return Err(format!("Session not found: {}", session_id).into());
         }
 
-        log::info!("Loaded {} sessions", sessions.len());
-        for s in &sessions {
-            log::debug!("Session {} has {} messages", s.id, s.messages.len());
+        log::info!("Session {}: {}", if archived { "archived" } else { "unarchived" }, session_id);
+        Ok(())
+    }
+
+    /**
+     * 找出最后更新早于 cutoff、还没有归档的会话（置顶的会话不自动归档）
+     *
+     * @param cutoff: 时间戳 (毫秒)
+     * @param archive: 是否真的归档；false 时只返回会被归档的会话
+     */
+    pub fn archive_sessions_before(
+        &self,
+        cutoff: i64,
+        archive: bool,
+    ) -> Result<Vec<ArchivedSession>, Box<dyn std::error::Error>> {
+        let sessions: Vec<ArchivedSession> = {
+            let mut stmt = self.conn.prepare(
+                "SELECT id, title, updated_at FROM sessions
+                 WHERE updated_at < ?1 AND archived_at IS NULL AND deleted_at IS NULL AND pinned = 0
+                 ORDER BY updated_at ASC",
+            )?;
+            let rows = stmt.query_map([cutoff], |row| {
+                Ok(ArchivedSession { id: row.get(0)?, title: row.get(1)?, updated_at: row.get(2)? })
+            })?;
+            rows.collect::<Result<_, _>>()?
+        };
+        if archive && !sessions.is_empty() {
+            let tx = self.conn.unchecked_transaction()?;
+            let now = chrono::Utc::now().timestamp_millis();
+            for session in &sessions {
+                tx.execute("UPDATE sessions SET archived_at = ?1 WHERE id = ?2", rusqlite::params![now, session.id])?;
+            }
+            tx.commit()?;
+            log::info!("Archived {} sessions not updated since {}", sessions.len(), cutoff);
+        }
+        Ok(sessions)
+    }
+
+    /**
+     * 获取会话的列表项
+     * 筛选和排序都在 SQL 里完成，置顶的会话总是排在前面；只带消息条数、标签和最后一条消息的预览，
+     * 消息正文通过 get_messages_page 按需读取
+     *
+     * @param filter: 筛选和排序条件，默认值表示全部会话、按最后更新时间倒序
+     * @return 会话列表项
+     */
+    pub fn get_sessions(&self, filter: &SessionFilter) -> Result<Vec<SessionOverview>, Box<dyn std::error::Error>> {
+        let mut params: Vec<rusqlite::types::Value> = vec![(SESSION_PREVIEW_CHARS as i64).into()];
+        let mut conditions = vec!["s.deleted_at IS NULL".to_string()];
+        let mut bind = |condition: &str, value: rusqlite::types::Value| {
+            params.push(value);
+            conditions.push(condition.replace('?', &format!("?{}", params.len())));
+        };
+        if let Some(provider) = &filter.provider {
+            bind("s.provider = ?", provider.clone().into());
+        }
+        if let Some(model) = &filter.model {
+            bind("s.model = ?", model.clone().into());
+        }
+        if let Some(from) = filter.updated_from {
+            bind("s.updated_at >= ?", from.into());
+        }
+        if let Some(to) = filter.updated_to {
+            bind("s.updated_at < ?", to.into());
+        }
+        if let Some(tag) = &filter.tag {
+            bind("EXISTS (SELECT 1 FROM session_tags t WHERE t.session_id = s.id AND t.tag = ?)", tag.clone().into());
         }
+        if let Some(has_errors) = filter.has_errors {
+            conditions.push(format!(
+                "{}EXISTS (SELECT 1 FROM messages e WHERE e.session_id = s.id AND e.deleted_at IS NULL AND e.error IS NOT NULL AND e.error <> '')",
+                if has_errors { "" } else { "NOT " }
+            ));
+        }
+        if let Some(archived) = filter.archived {
+            conditions.push(format!("s.archived_at IS {}NULL", if archived { "NOT " } else { "" }));
+        }
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT s.id, s.title, s.provider, s.model, s.api_config_id, s.created_at, s.updated_at, s.persona_id,
+                   (SELECT COUNT(*) FROM messages m WHERE m.session_id = s.id AND m.deleted_at IS NULL) AS message_count,
+                   substr(last.content, 1, ?1), last.timestamp, s.pinned, s.archived_at IS NOT NULL,
+                   (SELECT group_concat(t.tag, char(10)) FROM session_tags t WHERE t.session_id = s.id)
+            FROM sessions s
+            LEFT JOIN messages last ON last.rowid = (
+                SELECT m.rowid FROM messages m
+                WHERE m.session_id = s.id AND m.deleted_at IS NULL
+                ORDER BY m.timestamp DESC, m.rowid DESC
+                LIMIT 1
+            )
+            WHERE {}
+            ORDER BY {}
+            "#,
+            conditions.join(" AND "),
+            session_order_by(filter.sort)
+        ))?;
+
+        let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+            let preview: Option<String> = row.get(9)?;
+            let tags: Option<String> = row.get(13)?;
+            let mut tags: Vec<String> = tags.map(|t| t.split('\n').map(str::to_string).collect()).unwrap_or_default();
+            tags.sort();
+            Ok(SessionOverview {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                provider: row.get(2)?,
+                model: row.get(3)?,
+                api_config_id: row.get(4)?,
+                created_at: row.get(5)?,
+                updated_at: row.get(6)?,
+                persona_id: row.get(7)?,
+                message_count: row.get(8)?,
+                // 预览只占一行，换行和连续空白压成一个空格
+                last_message_preview: preview
+                    .map(|p| p.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .filter(|p| !p.is_empty()),
+                last_message_at: row.get(10)?,
+                pinned: row.get(11)?,
+                archived: row.get(12)?,
+                tags,
+            })
+        })?;
+
+        let sessions: Vec<SessionOverview> = rows.collect::<Result<_, _>>()?;
+        log::info!("Loaded {} sessions", sessions.len());
         Ok(sessions)
     }
 
     /**
      * 保存消息到数据库
-     * 同时更新会话的 updated_at 时间戳
+     * 同时更新会话的 updated_at 时间戳；再次保存时没有带生成信息的项保留原来的值
      * 
      * @param session_id: 所属会话 ID
      * @param message: 要保存的消息
@@ -283,72 +969,302 @@ impl Database {
         session_id: &str,
         message: &ChatMessage,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let generation = message.generation.as_ref();
         self.conn.execute(
             r#"
-            INSERT INTO messages (id, session_id, role, content, timestamp, error)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT INTO messages (id, session_id, role, content, timestamp, error, seed, partial,
+                                  provider, model, finish_reason, latency_ms, input_tokens, output_tokens, tokens_estimated)
+            VALUES (?, ?, ?, ?, ?, ?, ?, 0, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT(id) DO UPDATE SET
                 content = excluded.content,
-                error = excluded.error
+                error = excluded.error,
+                seed = excluded.seed,
+                partial = 0,
+                provider = COALESCE(excluded.provider, messages.provider),
+                model = COALESCE(excluded.model, messages.model),
+                finish_reason = COALESCE(excluded.finish_reason, messages.finish_reason),
+                latency_ms = COALESCE(excluded.latency_ms, messages.latency_ms),
+                input_tokens = COALESCE(excluded.input_tokens, messages.input_tokens),
+                output_tokens = COALESCE(excluded.output_tokens, messages.output_tokens),
+                tokens_estimated = COALESCE(excluded.tokens_estimated, messages.tokens_estimated)
             "#,
-            [
+            rusqlite::params![
                 &message.id,
                 session_id,
                 &message.role,
                 &message.content,
                 &message.timestamp.to_string(),
                 &message.error.as_deref().unwrap_or(""),
+                message.seed,
+                generation.and_then(|g| g.provider.as_deref()),
+                generation.and_then(|g| g.model.as_deref()),
+                generation.and_then(|g| g.finish_reason.as_deref()),
+                generation.and_then(|g| g.latency_ms),
+                generation.and_then(|g| g.input_tokens),
+                generation.and_then(|g| g.output_tokens),
+                generation.map(|g| g.tokens_estimated),
             ],
         )?;
 
-        self.conn.execute(
-            "UPDATE sessions SET updated_at = ?1 WHERE id = ?2",
-            [&chrono::Utc::now().timestamp_millis().to_string(), session_id],
+        // 在归档的会话里继续聊天，会话自动回到列表里
+        self.conn.execute(
+            "UPDATE sessions SET updated_at = ?1, archived_at = NULL WHERE id = ?2",
+            [&chrono::Utc::now().timestamp_millis().to_string(), session_id],
+        )?;
+
+        log::info!("[save_message] saved message {} for session {}", message.id, session_id);
+        Ok(())
+    }
+
+    /**
+     * 写入流式回复的检查点
+     * 生成过程中定期把已输出的正文存成 partial 状态的 assistant 消息；前端收到
+     * done 后用 save_message 保存完整内容时清掉 partial。已经保存完成的消息
+     * 不会被迟到的检查点覆盖。
+     *
+     * @param session_id: 所属会话 ID
+     * @param message_id: 前端占位 assistant 消息的 ID
+     * @param content: 到目前为止的正文
+     * @param timestamp: 消息时间戳
+     * @param seed: 本次回复使用的随机种子
+     */
+    pub fn checkpoint_partial_message(
+        &self,
+        session_id: &str,
+        message_id: &str,
+        content: &str,
+        timestamp: i64,
+        seed: Option<i64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            r#"
+            INSERT INTO messages (id, session_id, role, content, timestamp, error, seed, partial)
+            VALUES (?, ?, 'assistant', ?, ?, '', ?, 1)
+            ON CONFLICT(id) DO UPDATE SET content = excluded.content
+            WHERE messages.partial = 1
+            "#,
+            rusqlite::params![message_id, session_id, content, timestamp, seed],
+        )?;
+        Ok(())
+    }
+
+    /**
+     * 保存一条回复引用的知识库片段
+     *
+     * @param session_id: 所属会话 ID
+     * @param message_id: 回复消息 ID
+     * @param chunks_json: RetrievedChunk 列表的 JSON
+     */
+    pub fn save_message_sources(
+        &self,
+        session_id: &str,
+        message_id: &str,
+        chunks_json: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO message_sources (message_id, session_id, chunks, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![message_id, session_id, chunks_json, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /**
+     * 获取会话中各条回复引用的知识库片段
+     *
+     * @param session_id: 会话 ID
+     * @return (消息 ID, 片段 JSON) 列表
+     */
+    pub fn get_message_sources(
+        &self,
+        session_id: &str,
+    ) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare("SELECT message_id, chunks FROM message_sources WHERE session_id = ?1")?;
+        let rows = stmt.query_map([session_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /**
+     * 给会话绑定知识库（已绑定时不做任何事）
+     *
+     * @param session_id: 会话 ID
+     * @param kb_id: 知识库 ID
+     */
+    pub fn attach_session_kb(&self, session_id: &str, kb_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO session_kbs (session_id, kb_id, created_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![session_id, kb_id, chrono::Utc::now().timestamp_millis()],
+        )?;
+
+        log::info!("Knowledge base {} attached to session {}", kb_id, session_id);
+        Ok(())
+    }
+
+    /**
+     * 解除会话和知识库的绑定
+     *
+     * @param session_id: 会话 ID
+     * @param kb_id: 知识库 ID
+     */
+    pub fn detach_session_kb(&self, session_id: &str, kb_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "DELETE FROM session_kbs WHERE session_id = ?1 AND kb_id = ?2",
+            [session_id, kb_id],
+        )?;
+
+        log::info!("Knowledge base {} detached from session {}", kb_id, session_id);
+        Ok(())
+    }
+
+    /**
+     * 获取会话绑定的知识库 ID（按绑定先后排序）
+     *
+     * @param session_id: 会话 ID
+     */
+    pub fn get_session_kb_ids(&self, session_id: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT kb_id FROM session_kbs WHERE session_id = ?1 ORDER BY created_at ASC",
+        )?;
+        let ids = stmt.query_map([session_id], |row| row.get(0))?;
+        Ok(ids.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /**
+     * 删除单条消息：移进回收站，同时更新会话的 updated_at
+     * 用于用户手动删除消息、消息编辑（截断编辑点之后的旧消息）和重新生成（删除待重生成的回复）
+     *
+     * @param message_id: 要删除的消息 ID
+     */
+    pub fn delete_message(&self, message_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let tx = self.conn.unchecked_transaction()?;
+        Self::message_changed(&tx, message_id, now)?;
+        tx.execute(
+            "UPDATE messages SET deleted_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+            rusqlite::params![now, message_id],
+        )?;
+        tx.commit()?;
+
+        log::info!("Message moved to trash: {}", message_id);
+        Ok(())
+    }
+
+    /**
+     * 修改单条消息的内容，同时更新会话的 updated_at
+     *
+     * @param message_id: 消息 ID
+     * @param content: 新的消息内容
+     */
+    pub fn update_message(&self, message_id: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let tx = self.conn.unchecked_transaction()?;
+        let updated = tx.execute(
+            "UPDATE messages SET content = ?1, partial = 0 WHERE id = ?2 AND deleted_at IS NULL",
+            rusqlite::params![content, message_id],
+        )?;
+        if updated == 0 {
+            return Err(format!("Message not found: {}", message_id).into());
+        }
+        Self::message_changed(&tx, message_id, now)?;
+        tx.commit()?;
+
+        log::info!("Message updated: {}", message_id);
+        Ok(())
+    }
+
+    /**
+     * 消息被修改或删除后：更新所在会话的 updated_at；
+     * 置顶摘要已经覆盖了这条消息时摘要作废，下次发送时按现有消息重新生成
+     */
+    fn message_changed(conn: &rusqlite::Connection, message_id: &str, now: i64) -> rusqlite::Result<()> {
+        conn.execute(
+            "UPDATE sessions SET updated_at = ?1 WHERE id = (SELECT session_id FROM messages WHERE id = ?2)",
+            rusqlite::params![now, message_id],
+        )?;
+        conn.execute(
+            "DELETE FROM session_summaries WHERE session_id = (SELECT session_id FROM messages WHERE id = ?1)
+                AND covered_until >= (SELECT timestamp FROM messages WHERE id = ?1)",
+            [message_id],
+        )?;
+        Ok(())
+    }
+
+    /**
+     * 从回收站恢复单条消息（所在会话也在回收站里时，要等会话恢复后才可见）
+     *
+     * @param message_id: 消息 ID
+     */
+    pub fn restore_message(&self, message_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let restored = self.conn.execute(
+            "UPDATE messages SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL",
+            [message_id],
         )?;
+        if restored == 0 {
+            return Err(format!("Message not in trash: {}", message_id).into());
+        }
 
-        log::info!("[save_message] saved message {} for session {}", message.id, session_id);
+        log::info!("Message restored: {}", message_id);
         Ok(())
     }
 
     /**
-     * 删除单条消息
-     * 用于消息编辑（截断编辑点之后的旧消息）和重新生成（删除待重生成的回复）
+     * 彻底删除回收站里的单条消息
      *
-     * @param message_id: 要删除的消息 ID
+     * @param message_id: 消息 ID
      */
-    pub fn delete_message(&self, message_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn purge_message(&self, message_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let purged = self.conn.execute(
+            "DELETE FROM messages WHERE id = ?1 AND deleted_at IS NOT NULL",
+            [message_id],
+        )?;
+        if purged == 0 {
+            return Err(format!("Message not in trash: {}", message_id).into());
+        }
         self.conn.execute(
-            "DELETE FROM messages WHERE id = ?1",
+            "DELETE FROM message_sources WHERE message_id = ?1",
             [message_id],
         )?;
 
-        log::info!("Message deleted: {}", message_id);
+        log::info!("Message purged: {}", message_id);
         Ok(())
     }
 
     /**
-     * 获取指定会话的所有消息
-     * 按时间戳升序排列
+     * 分页获取指定会话的消息
+     * 按时间戳升序排列，offset 从最早的消息算起
      *
      * @param session_id: 会话 ID
-     * @return 消息列表
+     * @param offset: 跳过最早的多少条；为 None 时取最新的一页
+     * @param limit: 每页条数，超过 MAX_MESSAGE_PAGE_SIZE 时按上限取
+     * @return 本页消息、本页起始位置和消息总条数
      */
-    pub fn get_messages(
+    pub fn get_messages_page(
         &self,
         session_id: &str,
-    ) -> Result<Vec<ChatMessage>, Box<dyn std::error::Error>> {
-        log::info!("[get_messages] Querying messages for session_id: {}, db_path: {}", session_id, self.path);
-        
+        offset: Option<usize>,
+        limit: usize,
+    ) -> Result<MessagePage, Box<dyn std::error::Error>> {
+        let limit = limit.clamp(1, MAX_MESSAGE_PAGE_SIZE);
+        let total: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE session_id = ? AND deleted_at IS NULL",
+            [session_id],
+            |row| row.get(0),
+        )?;
+        let total = total as usize;
+        let offset = offset.unwrap_or(total.saturating_sub(limit)).min(total);
+
+        // 同一毫秒写入的消息再按 rowid（写入顺序）排，分页边界才稳定
         let mut stmt = self.conn.prepare(
             r#"
-            SELECT id, role, content, timestamp, error 
-            FROM messages 
-            WHERE session_id = ? 
-            ORDER BY timestamp ASC
+            SELECT id, role, content, timestamp, error, seed,
+                   provider, model, finish_reason, latency_ms, input_tokens, output_tokens, tokens_estimated
+            FROM messages
+            WHERE session_id = ?1 AND deleted_at IS NULL
+            ORDER BY timestamp ASC, rowid ASC
+            LIMIT ?2 OFFSET ?3
             "#,
         )?;
-        
-        let rows = stmt.query_map([session_id], |row| {
+
+        let rows = stmt.query_map(rusqlite::params![session_id, limit as i64, offset as i64], |row| {
             let error: Option<String> = row.get(4)?;
             Ok(ChatMessage {
                 id: row.get(0)?,
@@ -358,12 +1274,20 @@ impl Database {
                 error: if error.as_deref() == Some("") { None } else { error },
                 images: vec![],
                 videos: vec![],
+                seed: row.get(5)?,
+                generation: generation_from_row(row, 6)?,
             })
         })?;
 
-        let messages: Result<Vec<_>, _> = rows.collect();
-        log::info!("get_messages for session {}: {} messages", session_id, messages.as_ref().map(|m| m.len()).unwrap_or(0));
-        Ok(messages?)
+        let messages: Vec<ChatMessage> = rows.collect::<Result<_, _>>()?;
+        log::debug!(
+            "get_messages_page for session {}: {} of {} messages from {}",
+            session_id,
+            messages.len(),
+            total,
+            offset
+        );
+        Ok(MessagePage { messages, offset, total })
     }
 
     /**
@@ -573,7 +1497,515 @@ impl Database {
     }
 
     /**
-     * 清空数据库：删除所有会话、消息、MCP 服务器配置、Skill。
+     * 保存角色预设 (新建或覆盖)
+     *
+     * @param persona: 角色预设
+     */
+    pub fn save_persona(&self, persona: &Persona) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            r#"
+            INSERT OR REPLACE INTO personas
+            (id, name, system_prompt, max_tokens, temperature, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#,
+            rusqlite::params![
+                &persona.id,
+                &persona.name,
+                &persona.system_prompt,
+                &persona.max_tokens,
+                &persona.temperature,
+                &persona.created_at,
+                &persona.updated_at,
+            ],
+        )?;
+
+        log::info!("Persona saved: {}", persona.id);
+        Ok(())
+    }
+
+    /**
+     * 获取所有角色预设
+     */
+    pub fn get_personas(&self) -> Result<Vec<Persona>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT id, name, system_prompt, max_tokens, temperature, created_at, updated_at
+            FROM personas
+            ORDER BY name ASC
+            "#,
+        )?;
+        let personas: Result<Vec<_>, _> = stmt.query_map([], Self::row_to_persona)?.collect();
+        Ok(personas?)
+    }
+
+    /**
+     * 获取会话当前绑定的角色预设
+     *
+     * @param session_id: 会话 ID
+     * @return 未绑定或预设已被删除时返回 None
+     */
+    pub fn get_session_persona(&self, session_id: &str) -> Result<Option<Persona>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT p.id, p.name, p.system_prompt, p.max_tokens, p.temperature, p.created_at, p.updated_at
+            FROM sessions s
+            JOIN personas p ON p.id = s.persona_id
+            WHERE s.id = ?1
+            "#,
+        )?;
+        let mut rows = stmt.query_map([session_id], Self::row_to_persona)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /**
+     * 设置会话的角色预设
+     *
+     * @param session_id: 会话 ID
+     * @param persona_id: 角色预设 ID，None 表示解除绑定
+     */
+    pub fn set_session_persona(&self, session_id: &str, persona_id: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+        let updated = self.conn.execute(
+            "UPDATE sessions SET persona_id = ?1 WHERE id = ?2",
+            rusqlite::params![persona_id, session_id],
+        )?;
+        if updated == 0 {
+            return Err(format!("Session not found: {}", session_id).into());
+        }
+
+        log::info!("Session {} persona set to {:?}", session_id, persona_id);
+        Ok(())
+    }
+
+    /**
+     * 设置会话的标签，整体替换原有标签
+     *
+     * @param session_id: 会话 ID
+     * @param tags: 新的标签（已去掉首尾空白和重复）
+     */
+    pub fn set_session_tags(&self, session_id: &str, tags: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = ?1 AND deleted_at IS NULL)",
+            [session_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(format!("Session not found: {}", session_id).into());
+        }
+        tx.execute("DELETE FROM session_tags WHERE session_id = ?1", [session_id])?;
+        for tag in tags {
+            tx.execute(
+                "INSERT OR IGNORE INTO session_tags (session_id, tag) VALUES (?1, ?2)",
+                rusqlite::params![session_id, tag],
+            )?;
+        }
+        tx.commit()?;
+
+        log::info!("Session {} tags set to {:?}", session_id, tags);
+        Ok(())
+    }
+
+    /**
+     * 获取所有用过的标签及使用它的会话数（不含回收站里的会话），按名称排序
+     */
+    pub fn get_session_tags(&self) -> Result<Vec<SessionTagCount>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT t.tag, COUNT(*)
+            FROM session_tags t
+            JOIN sessions s ON s.id = t.session_id
+            WHERE s.deleted_at IS NULL
+            GROUP BY t.tag
+            ORDER BY t.tag ASC
+            "#,
+        )?;
+        let tags = stmt
+            .query_map([], |row| Ok(SessionTagCount { tag: row.get(0)?, sessions: row.get(1)? }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tags)
+    }
+
+    /**
+     * 置顶或取消置顶会话
+     *
+     * @param session_id: 会话 ID
+     * @param pinned: true 置顶，false 取消置顶
+     */
+    pub fn set_session_pinned(&self, session_id: &str, pinned: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let updated = self.conn.execute(
+            "UPDATE sessions SET pinned = ?1 WHERE id = ?2",
+            rusqlite::params![pinned, session_id],
+        )?;
+        if updated == 0 {
+            return Err(format!("Session not found: {}", session_id).into());
+        }
+
+        log::info!("Session {} pinned set to {}", session_id, pinned);
+        Ok(())
+    }
+
+    /**
+     * 删除角色预设，并解除所有会话对它的绑定
+     */
+    pub fn delete_persona(&self, persona_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "UPDATE sessions SET persona_id = NULL WHERE persona_id = ?1",
+            [persona_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM personas WHERE id = ?1",
+            [persona_id],
+        )?;
+
+        log::info!("Persona deleted: {}", persona_id);
+        Ok(())
+    }
+
+    /**
+     * 收藏消息；已经收藏过时更新所在收藏夹和备注，收藏时间不变
+     *
+     * @param message_id: 消息 ID
+     * @param collection_id: 收藏夹 ID，None 表示未分组
+     * @param note: 备注
+     */
+    pub fn bookmark_message(
+        &self,
+        message_id: &str,
+        collection_id: Option<&str>,
+        note: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            r#"
+            INSERT INTO bookmarks (message_id, collection_id, note, created_at)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(message_id) DO UPDATE SET
+                collection_id = excluded.collection_id,
+                note = excluded.note
+            "#,
+            rusqlite::params![message_id, collection_id, note, chrono::Utc::now().timestamp_millis()],
+        )?;
+
+        log::info!("Message bookmarked: {} (collection {:?})", message_id, collection_id);
+        Ok(())
+    }
+
+    /**
+     * 取消收藏
+     *
+     * @param message_id: 消息 ID
+     */
+    pub fn remove_bookmark(&self, message_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute("DELETE FROM bookmarks WHERE message_id = ?1", [message_id])?;
+
+        log::info!("Bookmark removed: {}", message_id);
+        Ok(())
+    }
+
+    /**
+     * 按收藏时间倒序列出收藏的消息，跳过在回收站里的消息和会话
+     *
+     * @param filter: 全部、未分组或指定收藏夹
+     */
+    pub fn list_bookmarks(&self, filter: &BookmarkFilter) -> Result<Vec<Bookmark>, Box<dyn std::error::Error>> {
+        let (condition, collection_id) = match filter {
+            BookmarkFilter::All => ("1 = 1", None),
+            BookmarkFilter::Ungrouped => ("b.collection_id IS NULL", None),
+            BookmarkFilter::Collection(id) => ("b.collection_id = ?2", Some(id.as_str())),
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT b.message_id, m.session_id, s.title, m.role, substr(m.content, 1, ?1),
+                   b.collection_id, b.note, m.timestamp, b.created_at
+            FROM bookmarks b
+            JOIN messages m ON m.id = b.message_id
+            JOIN sessions s ON s.id = m.session_id
+            WHERE m.deleted_at IS NULL AND s.deleted_at IS NULL AND {}
+            ORDER BY b.created_at DESC
+            "#,
+            condition
+        ))?;
+        let map_row = |row: &rusqlite::Row| {
+            let preview: String = row.get(4)?;
+            Ok(Bookmark {
+                message_id: row.get(0)?,
+                session_id: row.get(1)?,
+                session_title: row.get(2)?,
+                role: row.get(3)?,
+                preview: preview.split_whitespace().collect::<Vec<_>>().join(" "),
+                collection_id: row.get(5)?,
+                note: row.get(6)?,
+                timestamp: row.get(7)?,
+                created_at: row.get(8)?,
+            })
+        };
+        let bookmarks = match collection_id {
+            Some(id) => stmt.query_map(rusqlite::params![BOOKMARK_PREVIEW_CHARS as i64, id], map_row)?,
+            None => stmt.query_map([BOOKMARK_PREVIEW_CHARS as i64], map_row)?,
+        }
+        .collect::<Result<Vec<_>, _>>()?;
+        Ok(bookmarks)
+    }
+
+    /**
+     * 新建或重命名收藏夹
+     *
+     * 不用 INSERT OR REPLACE：替换会先删掉旧行，收藏夹里的收藏会被外键置为未分组
+     *
+     * @param collection: 收藏夹
+     */
+    pub fn save_bookmark_collection(&self, collection: &BookmarkCollection) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            r#"
+            INSERT INTO bookmark_collections (id, name, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(id) DO UPDATE SET
+                name = excluded.name,
+                updated_at = excluded.updated_at
+            "#,
+            rusqlite::params![&collection.id, &collection.name, &collection.created_at, &collection.updated_at],
+        )?;
+
+        log::info!("Bookmark collection saved: {}", collection.id);
+        Ok(())
+    }
+
+    /**
+     * 获取所有收藏夹及其中的收藏数（按名称排序）
+     */
+    pub fn get_bookmark_collections(&self) -> Result<Vec<BookmarkCollection>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT c.id, c.name,
+                   (SELECT COUNT(*) FROM bookmarks b
+                    JOIN messages m ON m.id = b.message_id
+                    JOIN sessions s ON s.id = m.session_id
+                    WHERE b.collection_id = c.id AND m.deleted_at IS NULL AND s.deleted_at IS NULL),
+                   c.created_at, c.updated_at
+            FROM bookmark_collections c
+            ORDER BY c.name ASC
+            "#,
+        )?;
+        let collections = stmt
+            .query_map([], |row| {
+                Ok(BookmarkCollection {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    bookmark_count: row.get(2)?,
+                    created_at: row.get(3)?,
+                    updated_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(collections)
+    }
+
+    /**
+     * 删除收藏夹，里面的收藏随外键变回未分组
+     *
+     * @param collection_id: 收藏夹 ID
+     */
+    pub fn delete_bookmark_collection(&self, collection_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute("DELETE FROM bookmark_collections WHERE id = ?1", [collection_id])?;
+
+        log::info!("Bookmark collection deleted: {}", collection_id);
+        Ok(())
+    }
+
+    /**
+     * 获取会话的置顶摘要
+     *
+     * @param session_id: 会话 ID
+     * @return 还没有生成过摘要时返回 None
+     */
+    pub fn get_session_summary(&self, session_id: &str) -> Result<Option<SessionSummary>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT session_id, summary, covered_until, covered_messages, updated_at
+            FROM session_summaries
+            WHERE session_id = ?1
+            "#,
+        )?;
+        let mut rows = stmt.query_map([session_id], |row| {
+            Ok(SessionSummary {
+                session_id: row.get(0)?,
+                summary: row.get(1)?,
+                covered_until: row.get(2)?,
+                covered_messages: row.get(3)?,
+                updated_at: row.get(4)?,
+            })
+        })?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /**
+     * 保存（覆盖）会话的置顶摘要
+     */
+    pub fn save_session_summary(&self, summary: &SessionSummary) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            r#"
+            INSERT OR REPLACE INTO session_summaries (session_id, summary, covered_until, covered_messages, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+            rusqlite::params![
+                &summary.session_id,
+                &summary.summary,
+                &summary.covered_until,
+                &summary.covered_messages,
+                &summary.updated_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /**
+     * 删除会话的置顶摘要
+     */
+    pub fn delete_session_summary(&self, session_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "DELETE FROM session_summaries WHERE session_id = ?1",
+            [session_id],
+        )?;
+        log::info!("Session summary deleted: {}", session_id);
+        Ok(())
+    }
+
+    fn row_to_persona(row: &rusqlite::Row) -> rusqlite::Result<Persona> {
+        Ok(Persona {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            system_prompt: row.get(2)?,
+            max_tokens: row.get(3)?,
+            temperature: row.get(4)?,
+            created_at: row.get(5)?,
+            updated_at: row.get(6)?,
+        })
+    }
+
+    /**
+     * 记录一条回复的 token 用量和估算费用
+     *
+     * @param usage: 用量记录（同一 message_id 重复写入时覆盖）
+     */
+    pub fn record_message_usage(&self, usage: &MessageUsage) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            r#"
+            INSERT OR REPLACE INTO message_usage
+                (message_id, session_id, provider, model, input_tokens, output_tokens, cost_usd, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#,
+            rusqlite::params![
+                usage.message_id,
+                usage.session_id,
+                usage.provider,
+                usage.model,
+                usage.input_tokens,
+                usage.output_tokens,
+                usage.cost_usd,
+                usage.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /**
+     * 获取会话的累计用量
+     *
+     * @param session_id: 会话 ID
+     * @return (输入 token, 输出 token, 估算费用, 价格未知的消息数)
+     */
+    pub fn get_session_usage(&self, session_id: &str) -> Result<(i64, i64, f64, i64), Box<dyn std::error::Error>> {
+        let usage = self.conn.query_row(
+            r#"
+            SELECT COALESCE(SUM(input_tokens), 0),
+                   COALESCE(SUM(output_tokens), 0),
+                   COALESCE(SUM(cost_usd), 0.0),
+                   COUNT(*) - COUNT(cost_usd)
+            FROM message_usage
+            WHERE session_id = ?1
+            "#,
+            [session_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        Ok(usage)
+    }
+
+    /**
+     * 获取某个时间点之后的累计估算花费
+     *
+     * @param since_ms: 起始时间戳 (毫秒)
+     */
+    pub fn get_usage_cost_since(&self, since_ms: i64) -> Result<f64, Box<dyn std::error::Error>> {
+        let cost = self.conn.query_row(
+            "SELECT COALESCE(SUM(cost_usd), 0.0) FROM message_usage WHERE created_at >= ?1",
+            [since_ms],
+            |row| row.get(0),
+        )?;
+        Ok(cost)
+    }
+
+    /**
+     * 按 key_sql 分组统计 since_ms 之后的消息（见 commands::stats）
+     *
+     * @param key_sql: 分组表达式，可以引用 m (messages) 和 s (sessions)
+     * @param order_sql: 排序表达式
+     */
+    fn chat_stats_groups(&self, key_sql: &str, order_sql: &str, since_ms: i64) -> Result<Vec<ChatStatsGroup>, Box<dyn std::error::Error>> {
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT {key} AS k,
+                   COUNT(DISTINCT m.session_id),
+                   COUNT(*),
+                   COALESCE(SUM(m.role = 'assistant' AND COALESCE(m.error, '') = ''), 0),
+                   COALESCE(AVG(CASE WHEN m.role = 'assistant' AND COALESCE(m.error, '') = '' THEN LENGTH(m.content) END), 0.0),
+                   COALESCE(SUM(m.input_tokens), 0),
+                   COALESCE(SUM(m.output_tokens), 0),
+                   COALESCE(AVG(CASE WHEN m.role = 'assistant' AND COALESCE(m.error, '') = '' THEN m.latency_ms END), 0.0)
+            FROM messages m
+            JOIN sessions s ON s.id = m.session_id
+            WHERE m.deleted_at IS NULL AND s.deleted_at IS NULL AND m.partial = 0 AND m.timestamp >= ?1
+            GROUP BY k
+            ORDER BY {order}
+            "#,
+            key = key_sql,
+            order = order_sql,
+        ))?;
+        let groups = stmt
+            .query_map([since_ms], |row| {
+                Ok(ChatStatsGroup {
+                    key: row.get(0)?,
+                    sessions: row.get(1)?,
+                    messages: row.get(2)?,
+                    responses: row.get(3)?,
+                    average_response_length: row.get(4)?,
+                    input_tokens: row.get(5)?,
+                    output_tokens: row.get(6)?,
+                    average_latency_ms: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(groups)
+    }
+
+    /**
+     * 获取聊天统计：总计、按服务商、按模型、按天
+     *
+     * @param since_ms: 只统计这个时间戳 (毫秒) 之后的消息，0 表示全部
+     */
+    pub fn get_chat_stats(&self, since_ms: i64) -> Result<ChatStats, Box<dyn std::error::Error>> {
+        Ok(ChatStats {
+            total: self.chat_stats_groups("''", "k", since_ms)?.pop().unwrap_or_default(),
+            by_provider: self.chat_stats_groups("COALESCE(m.provider, s.provider)", "COUNT(*) DESC, k", since_ms)?,
+            by_model: self.chat_stats_groups(
+                "COALESCE(m.provider, s.provider) || '/' || COALESCE(m.model, s.model)",
+                "COUNT(*) DESC, k",
+                since_ms,
+            )?,
+            by_day: self.chat_stats_groups("date(m.timestamp / 1000, 'unixepoch', 'localtime')", "k", since_ms)?,
+        })
+    }
+
+    /**
+     * 清空数据库：删除所有会话、消息、MCP 服务器配置、Skill、角色预设、用量记录。
      * 不涉及知识库 / 协作团队 / 定时任务，那些是各自独立的 SQLite 文件。
      */
     pub fn clear_all(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -591,16 +2023,71 @@ impl Database {
             }
         }
 
+        self.conn.execute("DELETE FROM bookmarks", [])?;
+        self.conn.execute("DELETE FROM bookmark_collections", [])?;
+        self.conn.execute("DELETE FROM message_sources", [])?;
+        self.conn.execute("DELETE FROM session_kbs", [])?;
+        self.conn.execute("DELETE FROM session_tags", [])?;
         self.conn.execute("DELETE FROM messages", [])?;
         self.conn.execute("DELETE FROM sessions", [])?;
         self.conn.execute("DELETE FROM mcp_servers", [])?;
         self.conn.execute("DELETE FROM skills", [])?;
+        self.conn.execute("DELETE FROM message_usage", [])?;
+        self.conn.execute("DELETE FROM personas", [])?;
+        self.conn.execute("DELETE FROM session_summaries", [])?;
         self.conn.execute_batch("VACUUM")?;
-        log::info!("Database cleared: all sessions, messages, mcp_servers, skills removed");
+        log::info!("Database cleared: all sessions, messages, bookmarks, mcp_servers, skills, personas, summaries, usage removed");
         Ok(())
     }
 }
 
+/// 会话列表的 ORDER BY 子句；message_count 是 get_sessions 查询里的列别名
+fn session_order_by(sort: SessionSort) -> &'static str {
+    match sort {
+        SessionSort::UpdatedDesc => "s.pinned DESC, s.updated_at DESC",
+        SessionSort::UpdatedAsc => "s.pinned DESC, s.updated_at ASC",
+        SessionSort::CreatedDesc => "s.pinned DESC, s.created_at DESC",
+        SessionSort::CreatedAsc => "s.pinned DESC, s.created_at ASC",
+        SessionSort::Title => "s.pinned DESC, s.title COLLATE NOCASE ASC, s.updated_at DESC",
+        SessionSort::MessageCount => "s.pinned DESC, message_count DESC, s.updated_at DESC",
+    }
+}
+
+/// 从第 start 列开始按 GENERATION_COLUMNS 的顺序读出回复的生成信息，全部为空时返回 None
+pub(crate) fn generation_from_row(row: &rusqlite::Row, start: usize) -> rusqlite::Result<Option<GenerationInfo>> {
+    let estimated: Option<bool> = row.get(start + 6)?;
+    let info = GenerationInfo {
+        provider: row.get(start)?,
+        model: row.get(start + 1)?,
+        finish_reason: row.get(start + 2)?,
+        latency_ms: row.get(start + 3)?,
+        input_tokens: row.get(start + 4)?,
+        output_tokens: row.get(start + 5)?,
+        tokens_estimated: estimated.unwrap_or(false),
+    };
+    Ok((estimated.is_some() || info != GenerationInfo::default()).then_some(info))
+}
+
 /// 数据库状态封装结构
 /// 用于在 Tauri 应用中共享数据库实例
 pub struct DbState(pub Arc<tokio::sync::Mutex<Database>>);
+
+impl DbState {
+    /**
+     * 在阻塞线程池里执行数据库操作
+     *
+     * rusqlite 的调用都是同步阻塞的，大批量写入时直接在 async 命令里执行会卡住异步运行时的工作线程；
+     * 这里先拿到数据库锁，再把操作交给 spawn_blocking 执行。操作里 panic 会原样传回调用方。
+     */
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Database) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.0.clone().lock_owned().await;
+        match tokio::task::spawn_blocking(move || f(&db)).await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
@@ -0,0 +1,452 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * app.db 整库加密模块 (SQLCipher)
+ *
+ * 功能说明:
+ * - 可选开启：用用户设置的密码经 PBKDF2-HMAC-SHA256 派生出 256 位密钥，整个 app.db
+ *   （会话、消息、知识库分块和向量、协作团队等）都用它加密
+ * - 派生出的密钥保存在系统 keyring（BaiyuAISpace / db_key），启动时自动读取解锁；
+ *   keyring 里的密钥丢失时（换机器、重装系统）用密码解锁一次即可
+ * - 盐和迭代次数存在应用数据目录的 db_encryption.json，这个文件存在就表示数据库已加密
+ * - 开启、修改密码、关闭都只记下待生效的配置和密钥，重启应用时、打开任何连接之前
+ *   用 sqlcipher_export 导出一份新数据库替换 app.db（运行中的连接不能换文件；在启动时导出，
+ *   导出之后不会再有写入丢在旧文件里）
+ *
+ * 每个连接用原始密钥（PRAGMA key = "x'...'"）打开，跳过 SQLCipher 自带的密码派生——
+ * 各模块都是按操作新开短生命周期的连接，每次都做几十万次迭代会明显拖慢。
+ * 加密需要用 sqlcipher feature 编译（链接 SQLCipher 代替普通 SQLite）；普通构建里
+ * 相关命令返回"不支持"。
+ */
+
+use crate::commands::backup::app_data_dir;
+use keyring::Entry;
+use once_cell::sync::Lazy;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::RwLock;
+use tauri::AppHandle;
+
+/// keyring 里保存密钥的条目
+const KEYRING_SERVICE: &str = "BaiyuAISpace";
+const KEY_ENTRY: &str = "db_key";
+/// 待重启替换的新数据库对应的密钥
+const PENDING_KEY_ENTRY: &str = "db_key_pending";
+
+const CONFIG_FILE: &str = "db_encryption.json";
+const PENDING_CONFIG_FILE: &str = "db_encryption.pending.json";
+const PENDING_DB_FILE: &str = "app.db.pending";
+
+/// PBKDF2 迭代次数，只在设置和解锁时算一次
+const KDF_ITERATIONS: u32 = 600_000;
+/// 密码最短长度
+const MIN_PASSPHRASE_CHARS: usize = 8;
+
+/// 当前数据库密钥（十六进制），None 表示数据库没加密
+static DB_KEY: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+
+/// db_encryption.json：派生密钥用的参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptionConfig {
+    /// false 只出现在待生效的配置里，表示重启后关闭加密
+    enabled: bool,
+    /// 十六进制的随机盐
+    #[serde(default)]
+    salt: String,
+    #[serde(default)]
+    iterations: u32,
+}
+
+/// 加密状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionStatus {
+    /// 当前构建是否链接了 SQLCipher
+    pub supported: bool,
+    /// 数据库是否已加密
+    pub enabled: bool,
+    /// 已加密时是否已经拿到密钥；false 表示需要输入密码解锁
+    pub unlocked: bool,
+    /// 是否有重启后才生效的变更
+    pub pending: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// 从密码派生 256 位密钥，返回十六进制
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> String {
+    let mut key = [0u8; 32];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(iterations.max(1)).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    hex(&key)
+}
+
+/// 新建一组派生参数和对应的密钥
+fn new_config(passphrase: &str) -> Result<(EncryptionConfig, String), String> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+        return Err(format!("密码至少需要 {} 个字符", MIN_PASSPHRASE_CHARS));
+    }
+    let mut salt = [0u8; 16];
+    SystemRandom::new().fill(&mut salt).map_err(|_| "生成随机盐失败".to_string())?;
+    let config = EncryptionConfig { enabled: true, salt: hex(&salt), iterations: KDF_ITERATIONS };
+    let key = derive_key(passphrase, &salt, KDF_ITERATIONS);
+    Ok((config, key))
+}
+
+/// 用配置里的参数从密码派生密钥
+fn key_from_passphrase(config: &EncryptionConfig, passphrase: &str) -> Result<String, String> {
+    let salt = unhex(&config.salt).ok_or("加密配置已损坏")?;
+    Ok(derive_key(passphrase, &salt, config.iterations))
+}
+
+/// 给新打开的连接设置密钥；必须在访问数据库之前执行
+pub(crate) fn apply_key(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let key = DB_KEY.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match key {
+        Some(key) => conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", key)),
+        None => Ok(()),
+    }
+}
+
+fn set_current_key(key: Option<String>) {
+    *DB_KEY.write().unwrap_or_else(|e| e.into_inner()) = key;
+}
+
+/// 当前构建是否链接了 SQLCipher
+pub fn encryption_supported() -> bool {
+    rusqlite::Connection::open_in_memory()
+        .and_then(|conn| conn.query_row("PRAGMA cipher_version", [], |row| row.get::<_, String>(0)))
+        .is_ok()
+}
+
+fn keyring_entry(name: &str) -> Result<Entry, String> {
+    Entry::new(KEYRING_SERVICE, name).map_err(|e| format!("无法访问系统密钥链: {}", e))
+}
+
+fn keyring_get(name: &str) -> Option<String> {
+    keyring_entry(name).ok()?.get_password().ok()
+}
+
+fn keyring_set(name: &str, key: &str) -> Result<(), String> {
+    keyring_entry(name)?.set_password(key).map_err(|e| format!("无法写入系统密钥链: {}", e))
+}
+
+fn keyring_delete(name: &str) {
+    if let Ok(entry) = keyring_entry(name) {
+        let _ = entry.delete_credential();
+    }
+}
+
+fn read_config(path: &Path) -> Option<EncryptionConfig> {
+    serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()
+}
+
+fn write_config(path: &Path, config: &EncryptionConfig) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(config).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| format!("写入加密配置失败: {}", e))
+}
+
+/// 用给定密钥能不能读出数据库
+fn key_opens(db_path: &Path, key: Option<&str>) -> bool {
+    let Ok(conn) = rusqlite::Connection::open(db_path) else { return false };
+    if let Some(key) = key {
+        if conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", key)).is_err() {
+            return false;
+        }
+    }
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0)).is_ok()
+}
+
+/// 把导出好的新数据库换成 app.db
+fn replace_database(dir: &Path) -> std::io::Result<()> {
+    // 导出时已经包含了 WAL 里的内容，旧的 WAL 不能留给新文件
+    let _ = std::fs::remove_file(dir.join("app.db-wal"));
+    let _ = std::fs::remove_file(dir.join("app.db-shm"));
+    std::fs::rename(dir.join(PENDING_DB_FILE), dir.join("app.db"))
+}
+
+/// 待生效的配置转正：开启加密时换成 db_encryption.json，关闭加密时连同旧配置一起删掉
+fn commit_pending_config(dir: &Path, config: &EncryptionConfig) -> std::io::Result<()> {
+    let pending_config = dir.join(PENDING_CONFIG_FILE);
+    if config.enabled {
+        std::fs::rename(&pending_config, dir.join(CONFIG_FILE))
+    } else {
+        std::fs::remove_file(&pending_config)?;
+        let _ = std::fs::remove_file(dir.join(CONFIG_FILE));
+        Ok(())
+    }
+}
+
+/// 放弃待生效的变更
+fn discard_pending_change(dir: &Path) {
+    let _ = std::fs::remove_file(dir.join(PENDING_DB_FILE));
+    let _ = std::fs::remove_file(dir.join(PENDING_CONFIG_FILE));
+    keyring_delete(PENDING_KEY_ENTRY);
+}
+
+/// 按待生效的配置导出并换上新数据库
+///
+/// current 是能打开 app.db 的密钥（None 为不加密），target 是新数据库的密钥。两者相同说明
+/// 上次启动已经换好了文件、退出前没来得及更新配置，只补上配置。
+fn apply_pending_change(
+    dir: &Path,
+    config: &EncryptionConfig,
+    current: Option<&str>,
+    target: Option<&str>,
+) -> Result<(), String> {
+    if current != target {
+        let conn = rusqlite::Connection::open(dir.join("app.db")).map_err(|e| format!("打开数据库失败: {}", e))?;
+        if let Some(key) = current {
+            conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", key)).map_err(|e| e.to_string())?;
+        }
+        export_database(&conn, dir, target)?;
+        drop(conn);
+        replace_database(dir).map_err(|e| format!("替换数据库文件失败: {}", e))?;
+    }
+    commit_pending_config(dir, config).map_err(|e| format!("更新加密配置失败: {}", e))
+}
+
+/**
+ * 启动时、打开数据库之前调用：先应用待生效的加密变更，再从 keyring 读取密钥
+ * keyring 里没有可用的密钥时数据库保持锁定，前端提示用户输入密码解锁
+ *
+ * @param dir: 应用数据目录
+ */
+pub fn prepare(dir: &Path) {
+    let db_path = dir.join("app.db");
+    match read_config(&dir.join(PENDING_CONFIG_FILE)) {
+        // 没有待生效的变更；导出中途退出留下的半成品直接丢掉
+        None => {
+            let _ = std::fs::remove_file(dir.join(PENDING_DB_FILE));
+        }
+        Some(config) => {
+            let target = if config.enabled { keyring_get(PENDING_KEY_ENTRY) } else { None };
+            // 能打开现在这个 app.db 的密钥：正在用的、待生效的（上次替换到一半），或者没加密
+            let current = [keyring_get(KEY_ENTRY), keyring_get(PENDING_KEY_ENTRY)]
+                .into_iter()
+                .flatten()
+                .map(Some)
+                .chain([None])
+                .find(|key| key_opens(&db_path, key.as_deref()));
+            match (current, target) {
+                (_, None) if config.enabled => {
+                    log::error!("Pending database key is missing from the keyring; discarding the encryption change");
+                    discard_pending_change(dir);
+                }
+                // 加密的数据库还没解锁，变更留到解锁后的下一次启动
+                (None, _) => log::error!("Database key not found; pending encryption change waits for unlock"),
+                (Some(current), target) => match apply_pending_change(dir, &config, current.as_deref(), target.as_deref()) {
+                    Ok(()) => {
+                        log::info!("Applied pending database encryption change");
+                        match &target {
+                            Some(key) => {
+                                if let Err(e) = keyring_set(KEY_ENTRY, key) {
+                                    log::error!("Failed to store database key: {}", e);
+                                }
+                            }
+                            None => keyring_delete(KEY_ENTRY),
+                        }
+                        keyring_delete(PENDING_KEY_ENTRY);
+                    }
+                    Err(e) => {
+                        log::error!("Failed to apply pending database encryption change: {}", e);
+                        discard_pending_change(dir);
+                    }
+                },
+            }
+        }
+    }
+
+    if read_config(&dir.join(CONFIG_FILE)).is_none() {
+        return;
+    }
+    // 替换文件后、写 keyring 之前退出的话，新密钥还留在待生效条目里
+    for entry in [KEY_ENTRY, PENDING_KEY_ENTRY] {
+        if let Some(key) = keyring_get(entry).filter(|key| key_opens(&db_path, Some(key))) {
+            if entry == PENDING_KEY_ENTRY {
+                let _ = keyring_set(KEY_ENTRY, &key);
+                keyring_delete(PENDING_KEY_ENTRY);
+            }
+            set_current_key(Some(key));
+            return;
+        }
+    }
+    log::error!("Database is encrypted but no usable key was found in the keyring; waiting for unlock");
+}
+
+/// 把 conn 打开的数据库导出成用 key 加密（None 为不加密）的 app.db.pending
+fn export_database(conn: &rusqlite::Connection, dir: &Path, key: Option<&str>) -> Result<(), String> {
+    let target = dir.join(PENDING_DB_FILE);
+    let _ = std::fs::remove_file(&target);
+    let attach_key = key.map(|k| format!("x'{}'", k)).unwrap_or_default();
+    let export = || -> rusqlite::Result<()> {
+        conn.execute(
+            "ATTACH DATABASE ?1 AS encryption_export KEY ?2",
+            rusqlite::params![target.to_string_lossy(), attach_key],
+        )?;
+        let result = conn.query_row("SELECT sqlcipher_export('encryption_export')", [], |_| Ok(()));
+        conn.execute_batch("DETACH DATABASE encryption_export")?;
+        result
+    };
+    if let Err(e) = export() {
+        let _ = std::fs::remove_file(&target);
+        return Err(format!("导出加密数据库失败: {}", e));
+    }
+    Ok(())
+}
+
+/// 记下待生效的变更：新密钥（None 为关闭加密）和配置，下次启动时由 prepare 导出替换
+fn stage_change(dir: &Path, key: Option<&str>, config: &EncryptionConfig) -> Result<(), String> {
+    match key {
+        Some(key) => keyring_set(PENDING_KEY_ENTRY, key)?,
+        None => keyring_delete(PENDING_KEY_ENTRY),
+    }
+    write_config(&dir.join(PENDING_CONFIG_FILE), config)
+}
+
+/// 校验当前密码，返回当前配置
+fn verify_passphrase(dir: &Path, passphrase: &str) -> Result<EncryptionConfig, String> {
+    let config = read_config(&dir.join(CONFIG_FILE)).ok_or("数据库没有加密")?;
+    let key = key_from_passphrase(&config, passphrase)?;
+    if !key_opens(&dir.join("app.db"), Some(&key)) {
+        return Err("密码不正确".to_string());
+    }
+    Ok(config)
+}
+
+fn ensure_supported() -> Result<(), String> {
+    if encryption_supported() {
+        Ok(())
+    } else {
+        Err("当前版本没有包含数据库加密功能".to_string())
+    }
+}
+
+/// 获取数据库加密状态
+#[tauri::command]
+pub fn get_database_encryption_status(app_handle: AppHandle) -> Result<EncryptionStatus, String> {
+    let dir = app_data_dir(&app_handle)?;
+    let enabled = dir.join(CONFIG_FILE).exists();
+    Ok(EncryptionStatus {
+        supported: encryption_supported(),
+        enabled,
+        unlocked: !enabled || DB_KEY.read().unwrap_or_else(|e| e.into_inner()).is_some(),
+        pending: dir.join(PENDING_CONFIG_FILE).exists(),
+    })
+}
+
+/// 开启加密：重启应用时导出一份加密的数据库替换生效
+#[tauri::command]
+pub fn enable_database_encryption(app_handle: AppHandle, passphrase: String) -> Result<(), String> {
+    ensure_supported()?;
+    let dir = app_data_dir(&app_handle)?;
+    if dir.join(CONFIG_FILE).exists() {
+        return Err("数据库已经加密".to_string());
+    }
+    let (config, key) = new_config(&passphrase)?;
+    stage_change(&dir, Some(&key), &config)?;
+    log::info!("Database encryption will be enabled after restart");
+    Ok(())
+}
+
+/// 修改密码：重启应用时用新密钥重新导出替换生效
+#[tauri::command]
+pub fn change_database_passphrase(
+    app_handle: AppHandle,
+    current_passphrase: String,
+    new_passphrase: String,
+) -> Result<(), String> {
+    ensure_supported()?;
+    let dir = app_data_dir(&app_handle)?;
+    verify_passphrase(&dir, &current_passphrase)?;
+    let (config, key) = new_config(&new_passphrase)?;
+    stage_change(&dir, Some(&key), &config)?;
+    log::info!("Database passphrase will be changed after restart");
+    Ok(())
+}
+
+/// 关闭加密：重启应用时导出一份不加密的数据库替换生效
+#[tauri::command]
+pub fn disable_database_encryption(app_handle: AppHandle, passphrase: String) -> Result<(), String> {
+    ensure_supported()?;
+    let dir = app_data_dir(&app_handle)?;
+    verify_passphrase(&dir, &passphrase)?;
+    let config = EncryptionConfig { enabled: false, salt: String::new(), iterations: 0 };
+    stage_change(&dir, None, &config)?;
+    log::info!("Database encryption will be disabled after restart");
+    Ok(())
+}
+
+/// keyring 里的密钥丢失时用密码解锁；密钥写回 keyring，重启应用后正常使用
+#[tauri::command]
+pub fn unlock_database(app_handle: AppHandle, passphrase: String) -> Result<(), String> {
+    ensure_supported()?;
+    let dir = app_data_dir(&app_handle)?;
+    let config = verify_passphrase(&dir, &passphrase)?;
+    let key = key_from_passphrase(&config, &passphrase)?;
+    keyring_set(KEY_ENTRY, &key)?;
+    log::info!("Database unlocked with passphrase");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_derived_deterministically_from_passphrase_and_salt() {
+        let config = EncryptionConfig { enabled: true, salt: hex(b"0123456789abcdef"), iterations: 1_000 };
+        let key = key_from_passphrase(&config, "correct horse").unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, key_from_passphrase(&config, "correct horse").unwrap());
+        assert_ne!(key, key_from_passphrase(&config, "correct horsf").unwrap());
+        assert_eq!(unhex(&hex(&[0, 15, 255])), Some(vec![0, 15, 255]));
+        assert!(new_config("short").is_err());
+    }
+
+    #[test]
+    fn pending_change_replaces_app_db_and_commits_config() {
+        let dir = std::env::temp_dir().join(format!("baiyu-encryption-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let _cleanup = scopeguard::guard(dir.clone(), |dir| {
+            let _ = std::fs::remove_dir_all(dir);
+        });
+        std::fs::write(dir.join("app.db"), "old").unwrap();
+        std::fs::write(dir.join("app.db-wal"), "old wal").unwrap();
+
+        let config = EncryptionConfig { enabled: true, salt: "00".into(), iterations: 1 };
+        std::fs::write(dir.join(PENDING_DB_FILE), "encrypted").unwrap();
+        write_config(&dir.join(PENDING_CONFIG_FILE), &config).unwrap();
+        replace_database(&dir).unwrap();
+        commit_pending_config(&dir, &config).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("app.db")).unwrap(), "encrypted");
+        assert!(!dir.join("app.db-wal").exists() && !dir.join(PENDING_DB_FILE).exists());
+        assert!(read_config(&dir.join(CONFIG_FILE)).unwrap().enabled);
+        assert!(!dir.join(PENDING_CONFIG_FILE).exists());
+
+        // 关闭加密，上次启动已经换好了文件（能打开 app.db 的密钥就是目标密钥）：
+        // 不再导出，只删掉配置
+        let disabled = EncryptionConfig { enabled: false, salt: String::new(), iterations: 0 };
+        write_config(&dir.join(PENDING_CONFIG_FILE), &disabled).unwrap();
+        apply_pending_change(&dir, &disabled, None, None).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("app.db")).unwrap(), "encrypted");
+        assert!(!dir.join(CONFIG_FILE).exists() && !dir.join(PENDING_CONFIG_FILE).exists());
+    }
+}
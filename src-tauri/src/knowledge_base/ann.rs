@@ -0,0 +1,781 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 近似最近邻（ANN）索引模块
+ *
+ * 功能说明:
+ * - HnswIndex 是一个 HNSW 图索引：分层的近邻图，检索时从顶层往下贪心逼近，
+ *   只访问很小一部分向量，耗时随向量数大致按对数增长
+ * - 索引里的向量归一化后量化成 int8，只用来在图上导航；最终分数由 VectorStore
+ *   用 SQLite 里的原始向量重新精确计算，量化误差不会影响返回的相似度
+ * - 支持增量插入和删除（删除打墓碑标记，墓碑过多时整体重建）
+ * - 按知识库持久化到向量目录下的 <kb_id>.hnsw 文件，应用重启后直接加载
+ * - vectors 表上的触发器把每次写入/删除记到 vector_log，检索前按序号把新变化同步进内存里的索引，
+ *   所以导入、重新导入、修改分块等各种写入路径都不需要单独维护索引
+ * - 检索只读不写：写回索引文件、清理已落盘的日志都在写路径上做（见 AnnIndexes::checkpoint）
+ *
+ * 小知识库直接精确扫描更快也更准，只有向量数达到 ANN_MIN_VECTORS 才启用索引；
+ * 索引第一次构建在后台线程进行，构建完成之前仍然走精确扫描。
+ * 不用索引的知识库（向量数不够、或者加密）的变更日志在删除向量的写路径上清掉（见 prune_vector_log），
+ * 加密知识库的向量写入触发器根本不记日志。
+ */
+
+use super::db::bytes_to_vector;
+use super::types::KnowledgeBaseError;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// 向量数达到这个值才启用 ANN 索引，更少时精确扫描已经足够快
+pub(crate) const ANN_MIN_VECTORS: i64 = 10_000;
+
+/// vector_log.deleted 取这个值的记录是日志被清空过的标记（chunk_id 为空），
+/// 同步到它的旧索引缺了之前的删除记录，需要整个重建
+const LOG_RESET: i64 = 2;
+
+/// 已经同步进索引、但还没写回文件的日志攒到这么多条，写路径才写回索引文件并清掉这些日志，
+/// 免得每写一批向量都把整个索引文件重写一遍
+const CHECKPOINT_LOG_ROWS: i64 = 2_000;
+
+/// 每个节点在上层保留的邻居数，第 0 层保留两倍
+const M: usize = 16;
+const M0: usize = M * 2;
+/// 构建时每层搜索的候选数，越大图质量越好、构建越慢
+const EF_CONSTRUCTION: usize = 64;
+/// 检索时第 0 层搜索的最少候选数
+const EF_SEARCH: usize = 96;
+/// 层数上限，防止随机层数异常时图退化
+const MAX_LEVEL: usize = 16;
+
+/// 索引文件格式标识，格式有变化时改版本号，旧文件会被丢弃重建
+const FILE_MAGIC: &[u8; 8] = b"BYHNSW01";
+
+/// 量化后的满刻度
+const QUANT_SCALE: f32 = 127.0;
+
+struct Node {
+    chunk_id: String,
+    /// 每层的邻居，links.len() - 1 就是节点所在的最高层
+    links: Vec<Vec<u32>>,
+    deleted: bool,
+}
+
+/// HNSW 近邻图
+pub(crate) struct HnswIndex {
+    dim: usize,
+    /// 已经同步到的 vector_log 序号
+    pub(crate) watermark: i64,
+    nodes: Vec<Node>,
+    /// 量化后的向量，第 i 个节点占 codes[i * dim..(i + 1) * dim]
+    codes: Vec<i8>,
+    /// chunk_id -> 当前有效的节点
+    ids: HashMap<String, u32>,
+    entry: Option<u32>,
+    deleted: usize,
+    rng: u64,
+}
+
+/// 归一化后量化成 int8；零向量量化成全 0
+fn quantize(vector: &[f32]) -> Vec<i8> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return vec![0; vector.len()];
+    }
+    vector
+        .iter()
+        .map(|x| (x / norm * QUANT_SCALE).round().clamp(-QUANT_SCALE, QUANT_SCALE) as i8)
+        .collect()
+}
+
+/// 量化向量的"距离"：点积取负，越小越近。用整数比较，不用处理 NaN
+fn distance(a: &[i8], b: &[i8]) -> i32 {
+    -a.iter().zip(b).map(|(&x, &y)| x as i32 * y as i32).sum::<i32>()
+}
+
+impl HnswIndex {
+    pub(crate) fn new(dim: usize) -> Self {
+        Self {
+            dim,
+            watermark: 0,
+            nodes: Vec::new(),
+            codes: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            deleted: 0,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    pub(crate) fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// 有效（没有被删除）的向量数
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// 墓碑超过有效节点数时，图里一半以上是死节点，检索效率和召回都会下降，应当重建
+    pub(crate) fn needs_rebuild(&self) -> bool {
+        self.deleted > self.ids.len().max(1)
+    }
+
+    fn code(&self, idx: u32) -> &[i8] {
+        let start = idx as usize * self.dim;
+        &self.codes[start..start + self.dim]
+    }
+
+    fn level(&self, idx: u32) -> usize {
+        self.nodes[idx as usize].links.len() - 1
+    }
+
+    /// 按 HNSW 的指数分布随机抽一个层数（xorshift，不依赖外部随机数）
+    fn random_level(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let uniform = ((self.rng >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level = (-uniform.ln() / (M as f64).ln()).floor() as usize;
+        level.min(MAX_LEVEL)
+    }
+
+    /// 在某一层上从 entry 出发做贪心的 best-first 搜索，返回按距离升序的 ef 个候选
+    fn search_layer(&self, query: &[i8], entry: u32, ef: usize, layer: usize) -> Vec<(i32, u32)> {
+        let mut visited: HashSet<u32> = HashSet::from([entry]);
+        let d = distance(query, self.code(entry));
+        let mut candidates = BinaryHeap::from([Reverse((d, entry))]);
+        let mut results = BinaryHeap::from([(d, entry)]);
+
+        while let Some(Reverse((dist, current))) = candidates.pop() {
+            let worst = results.peek().map_or(i32::MAX, |&(d, _)| d);
+            if dist > worst && results.len() >= ef {
+                break;
+            }
+            for &neighbor in &self.nodes[current as usize].links[layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let d = distance(query, self.code(neighbor));
+                let worst = results.peek().map_or(i32::MAX, |&(d, _)| d);
+                if results.len() < ef || d < worst {
+                    candidates.push(Reverse((d, neighbor)));
+                    results.push((d, neighbor));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// HNSW 的邻居选择启发式：候选只有在离 base 比离所有已选邻居都近时才入选，
+    /// 让邻居分布在不同方向上；不够 m 个时再用被跳过的候选补齐
+    fn select_neighbors(&self, candidates: &[(i32, u32)], m: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(m);
+        let mut skipped: Vec<u32> = Vec::new();
+        for &(dist, candidate) in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let diverse = selected
+                .iter()
+                .all(|&s| distance(self.code(candidate), self.code(s)) > dist);
+            if diverse {
+                selected.push(candidate);
+            } else {
+                skipped.push(candidate);
+            }
+        }
+        for candidate in skipped {
+            if selected.len() >= m {
+                break;
+            }
+            selected.push(candidate);
+        }
+        selected
+    }
+
+    /// 插入或替换一个向量；维度和索引不一致时返回 false
+    pub(crate) fn insert(&mut self, chunk_id: &str, vector: &[f32]) -> bool {
+        if self.nodes.is_empty() {
+            self.dim = vector.len();
+        }
+        if vector.len() != self.dim {
+            return false;
+        }
+        self.remove(chunk_id);
+
+        let idx = self.nodes.len() as u32;
+        let code = quantize(vector);
+        let level = self.random_level();
+        self.codes.extend_from_slice(&code);
+        self.nodes.push(Node {
+            chunk_id: chunk_id.to_string(),
+            links: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(chunk_id.to_string(), idx);
+
+        let Some(mut entry) = self.entry else {
+            self.entry = Some(idx);
+            return true;
+        };
+        let top = self.level(entry);
+
+        // 在新节点所在层之上只做贪心下降
+        for layer in (level + 1..=top).rev() {
+            entry = self.search_layer(&code, entry, 1, layer)[0].1;
+        }
+
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(&code, entry, EF_CONSTRUCTION, layer);
+            let neighbors = self.select_neighbors(&candidates, M);
+            let max_links = if layer == 0 { M0 } else { M };
+
+            for &neighbor in &neighbors {
+                let links = &mut self.nodes[neighbor as usize].links[layer];
+                links.push(idx);
+                if links.len() > max_links {
+                    // 邻居的连接超出上限，按同样的启发式裁剪
+                    let base = self.code(neighbor);
+                    let mut scored: Vec<(i32, u32)> = self.nodes[neighbor as usize].links[layer]
+                        .iter()
+                        .map(|&n| (distance(base, self.code(n)), n))
+                        .collect();
+                    scored.sort_unstable();
+                    let pruned = self.select_neighbors(&scored, max_links);
+                    self.nodes[neighbor as usize].links[layer] = pruned;
+                }
+            }
+            self.nodes[idx as usize].links[layer] = neighbors;
+            entry = candidates[0].1;
+        }
+
+        if level > top {
+            self.entry = Some(idx);
+        }
+        true
+    }
+
+    /// 删除一个向量：节点留在图里继续帮助导航，只是不再出现在结果中
+    pub(crate) fn remove(&mut self, chunk_id: &str) {
+        if let Some(idx) = self.ids.remove(chunk_id) {
+            self.nodes[idx as usize].deleted = true;
+            self.deleted += 1;
+        }
+    }
+
+    /// 近似检索和 query 最相似的 k 个向量，返回按相似度降序的 chunk_id
+    pub(crate) fn search(&self, query: &[f32], k: usize) -> Vec<String> {
+        let Some(mut entry) = self.entry else {
+            return Vec::new();
+        };
+        if query.len() != self.dim || k == 0 {
+            return Vec::new();
+        }
+        let code = quantize(query);
+        for layer in (1..=self.level(entry)).rev() {
+            entry = self.search_layer(&code, entry, 1, layer)[0].1;
+        }
+        // 墓碑会占用候选名额，按比例多搜一些
+        let ef = (k + k * self.deleted / self.ids.len().max(1)).max(EF_SEARCH);
+        self.search_layer(&code, entry, ef, 0)
+            .into_iter()
+            .filter(|&(_, idx)| !self.nodes[idx as usize].deleted)
+            .take(k)
+            .map(|(_, idx)| self.nodes[idx as usize].chunk_id.clone())
+            .collect()
+    }
+
+    /// 写入索引文件（先写临时文件再改名，中途崩溃不会留下半个文件）
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("hnsw.tmp");
+        {
+            let mut w = BufWriter::new(std::fs::File::create(&tmp)?);
+            w.write_all(FILE_MAGIC)?;
+            w.write_all(&(self.dim as u32).to_le_bytes())?;
+            w.write_all(&self.watermark.to_le_bytes())?;
+            w.write_all(&self.entry.map_or(u32::MAX, |e| e).to_le_bytes())?;
+            w.write_all(&self.rng.to_le_bytes())?;
+            w.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+            for node in &self.nodes {
+                w.write_all(&(node.chunk_id.len() as u32).to_le_bytes())?;
+                w.write_all(node.chunk_id.as_bytes())?;
+                w.write_all(&[node.deleted as u8, node.links.len() as u8])?;
+                for links in &node.links {
+                    w.write_all(&(links.len() as u32).to_le_bytes())?;
+                    for &n in links {
+                        w.write_all(&n.to_le_bytes())?;
+                    }
+                }
+            }
+            let bytes: Vec<u8> = self.codes.iter().map(|&c| c as u8).collect();
+            w.write_all(&bytes)?;
+            w.flush()?;
+        }
+        std::fs::rename(tmp, path)
+    }
+
+    /// 读取索引文件，格式不对时返回错误（调用方会重建索引）
+    pub(crate) fn load(path: &Path) -> std::io::Result<Self> {
+        fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+            let mut buf = [0u8; 4];
+            r.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        }
+        fn read_u64(r: &mut impl Read) -> std::io::Result<u64> {
+            let mut buf = [0u8; 8];
+            r.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+        let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut r = BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != FILE_MAGIC {
+            return Err(invalid("unknown index format"));
+        }
+        let dim = read_u32(&mut r)? as usize;
+        let watermark = read_u64(&mut r)? as i64;
+        let entry = read_u32(&mut r)?;
+        let rng = read_u64(&mut r)?;
+        let count = read_u32(&mut r)? as usize;
+
+        let mut index = HnswIndex::new(dim);
+        index.watermark = watermark;
+        index.rng = rng;
+        index.entry = (entry != u32::MAX).then_some(entry);
+        for idx in 0..count {
+            let len = read_u32(&mut r)? as usize;
+            let mut id = vec![0u8; len];
+            r.read_exact(&mut id)?;
+            let chunk_id = String::from_utf8(id).map_err(|_| invalid("invalid chunk id"))?;
+            let mut flags = [0u8; 2];
+            r.read_exact(&mut flags)?;
+            let mut links = Vec::with_capacity(flags[1] as usize);
+            for _ in 0..flags[1] {
+                let n = read_u32(&mut r)? as usize;
+                let layer = (0..n).map(|_| read_u32(&mut r)).collect::<std::io::Result<Vec<u32>>>()?;
+                if layer.iter().any(|&l| l as usize >= count) {
+                    return Err(invalid("link out of range"));
+                }
+                links.push(layer);
+            }
+            if links.is_empty() {
+                return Err(invalid("node without layers"));
+            }
+            let deleted = flags[0] != 0;
+            if deleted {
+                index.deleted += 1;
+            } else {
+                index.ids.insert(chunk_id.clone(), idx as u32);
+            }
+            index.nodes.push(Node { chunk_id, links, deleted });
+        }
+        let mut codes = vec![0u8; count * dim];
+        r.read_exact(&mut codes)?;
+        index.codes = codes.into_iter().map(|c| c as i8).collect();
+        if index.entry.is_some_and(|e| e as usize >= count) {
+            return Err(invalid("entry out of range"));
+        }
+        Ok(index)
+    }
+}
+
+/// 从 vectors 表完整构建一个知识库的索引
+fn build_index(conn: &rusqlite::Connection, kb_id: &str) -> Result<HnswIndex, KnowledgeBaseError> {
+    let db_error = |e: rusqlite::Error| KnowledgeBaseError::DatabaseError(e.to_string());
+
+    // 先记下日志序号再读向量：读的过程中新写入的向量之后还会再同步一次，重复插入是幂等的
+    let watermark: i64 = conn
+        .query_row("SELECT COALESCE(MAX(seq), 0) FROM vector_log WHERE kb_id = ?1", [kb_id], |row| row.get(0))
+        .map_err(db_error)?;
+
+    let mut stmt = conn
+        .prepare("SELECT chunk_id, vector FROM vectors WHERE kb_id = ?1 ORDER BY rowid")
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([kb_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))
+        .map_err(db_error)?;
+
+    let mut index: Option<HnswIndex> = None;
+    for row in rows {
+        let (chunk_id, bytes) = row.map_err(db_error)?;
+        let vector = bytes_to_vector(&bytes);
+        let index = index.get_or_insert_with(|| HnswIndex::new(vector.len()));
+        if !index.insert(&chunk_id, &vector) {
+            log::warn!("[KB] Skipping vector {} with mismatched dimension in ANN index", chunk_id);
+        }
+    }
+
+    let mut index = index.unwrap_or_else(|| HnswIndex::new(0));
+    index.watermark = watermark;
+    Ok(index)
+}
+
+/// 把 vector_log 里 watermark 之后的变化同步进索引，返回是否有变化
+///
+/// 遇到维度和索引不一致的向量（换了 embedding 模型）时返回错误，调用方应当重建索引。
+pub(crate) fn sync_index(
+    conn: &rusqlite::Connection,
+    index: &mut HnswIndex,
+    kb_id: &str,
+) -> Result<bool, KnowledgeBaseError> {
+    let db_error = |e: rusqlite::Error| KnowledgeBaseError::DatabaseError(e.to_string());
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.seq, l.chunk_id, l.deleted, v.vector FROM vector_log l
+             LEFT JOIN vectors v ON v.chunk_id = l.chunk_id
+             WHERE l.kb_id = ?1 AND l.seq > ?2 ORDER BY l.seq",
+        )
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map(rusqlite::params![kb_id, index.watermark], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<Vec<u8>>>(3)?,
+            ))
+        })
+        .map_err(db_error)?;
+
+    let mut changed = false;
+    for row in rows {
+        let (seq, chunk_id, deleted, vector) = row.map_err(db_error)?;
+        if deleted == LOG_RESET {
+            return Err(KnowledgeBaseError::RetrievalError(format!("vector log of {} was pruned", kb_id)));
+        }
+        if deleted != 0 {
+            index.remove(&chunk_id);
+        } else if let Some(bytes) = vector {
+            if !index.insert(&chunk_id, &bytes_to_vector(&bytes)) {
+                return Err(KnowledgeBaseError::RetrievalError(format!(
+                    "vector dimension of {} differs from the ANN index",
+                    chunk_id
+                )));
+            }
+        }
+        // 插入日志对应的向量可能已经被后面的操作删掉，那时会有对应的删除日志
+        index.watermark = seq;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// 知识库的向量数在 ANN_MIN_VECTORS 以下、用不到索引时清掉它的变更日志，返回是否清理了
+///
+/// 在删除向量的写路径上调用（彻底删除文档、重新导入），检索只读不写。清空后留一条重置标记，
+/// 知识库以后再长到需要索引时，留下的旧索引同步到它会整个重建。
+pub(crate) fn prune_vector_log(conn: &rusqlite::Connection, kb_id: &str) -> Result<bool, rusqlite::Error> {
+    let vector_count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM vectors WHERE kb_id = ?1", [kb_id], |row| row.get(0))?;
+    if vector_count >= ANN_MIN_VECTORS {
+        return Ok(false);
+    }
+    let pruned = conn.execute(
+        "DELETE FROM vector_log WHERE kb_id = ?1 AND deleted != ?2",
+        rusqlite::params![kb_id, LOG_RESET],
+    )?;
+    if pruned == 0 {
+        return Ok(false);
+    }
+    conn.execute("DELETE FROM vector_log WHERE kb_id = ?1", [kb_id])?;
+    conn.execute(
+        "INSERT INTO vector_log (kb_id, chunk_id, deleted) VALUES (?1, '', ?2)",
+        rusqlite::params![kb_id, LOG_RESET],
+    )?;
+    Ok(true)
+}
+
+/// 已经加载到内存的各知识库索引
+pub(crate) struct AnnIndexes {
+    /// 索引文件所在的目录
+    dir: PathBuf,
+    /// 后台构建索引时打开的数据库
+    main_db_path: PathBuf,
+    ready: Mutex<HashMap<String, Arc<Mutex<HnswIndex>>>>,
+    building: Arc<Mutex<HashSet<String>>>,
+}
+
+impl AnnIndexes {
+    pub(crate) fn new(dir: PathBuf, main_db_path: PathBuf) -> Self {
+        Self {
+            dir,
+            main_db_path,
+            ready: Mutex::new(HashMap::new()),
+            building: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn index_path(&self, kb_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.hnsw", kb_id))
+    }
+
+    /// 取知识库的索引：内存里有就直接用，否则尝试从文件加载；
+    /// 都没有时在后台线程构建并返回 None（本次检索走精确扫描）
+    pub(crate) fn get(&self, kb_id: &str) -> Option<Arc<Mutex<HnswIndex>>> {
+        if let Some(index) = self.loaded(kb_id) {
+            return Some(index);
+        }
+        self.spawn_build(kb_id, self.index_path(kb_id));
+        None
+    }
+
+    /// 内存里的索引，没有时从文件加载；都没有时返回 None，不触发构建
+    fn loaded(&self, kb_id: &str) -> Option<Arc<Mutex<HnswIndex>>> {
+        let mut ready = self.ready.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = ready.get(kb_id) {
+            return Some(index.clone());
+        }
+
+        let path = self.index_path(kb_id);
+        if path.exists() {
+            match HnswIndex::load(&path) {
+                Ok(index) => {
+                    let index = Arc::new(Mutex::new(index));
+                    ready.insert(kb_id.to_string(), index.clone());
+                    return Some(index);
+                }
+                Err(e) => log::warn!("[KB] Discarding unreadable ANN index {}: {}", path.display(), e),
+            }
+        }
+        None
+    }
+
+    /// 在后台线程构建索引并写入文件，完成后放进内存
+    fn spawn_build(&self, kb_id: &str, path: PathBuf) {
+        {
+            let mut building = self.building.lock().unwrap_or_else(|e| e.into_inner());
+            if !building.insert(kb_id.to_string()) {
+                return;
+            }
+        }
+
+        let building = self.building.clone();
+        let main_db_path = self.main_db_path.clone();
+        let kb_id = kb_id.to_string();
+        std::thread::spawn(move || {
+            let started = std::time::Instant::now();
+            let result = crate::db::open_connection(&main_db_path)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+                .and_then(|conn| build_index(&conn, &kb_id));
+            match result {
+                Ok(index) => {
+                    log::info!(
+                        "[KB] Built ANN index for {} with {} vectors in {:?}",
+                        kb_id,
+                        index.len(),
+                        started.elapsed()
+                    );
+                    // 下次检索时从文件加载
+                    if let Err(e) = index.save(&path) {
+                        log::warn!("[KB] Failed to save ANN index {}: {}", path.display(), e);
+                    }
+                }
+                Err(e) => log::warn!("[KB] Failed to build ANN index for {}: {}", kb_id, e),
+            }
+            building.lock().unwrap_or_else(|e| e.into_inner()).remove(&kb_id);
+        });
+    }
+
+    /// 写路径上调用：把索引同步到最新，攒下的日志够多时写回索引文件，再清掉已经落盘的日志
+    ///
+    /// 知识库还没有索引时什么也不做，日志留给以后构建好的索引同步。
+    pub(crate) fn checkpoint(&self, conn: &rusqlite::Connection, kb_id: &str) {
+        let Some(index) = self.loaded(kb_id) else {
+            return;
+        };
+        let mut index = index.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = sync_index(conn, &mut index, kb_id) {
+            // 日志被清空过或者换了 embedding 模型，旧索引作废，下次检索时重建
+            log::warn!("[KB] Dropping stale ANN index for {}: {}", kb_id, e);
+            drop(index);
+            self.remove(kb_id);
+            return;
+        }
+        let backlog: i64 = match conn.query_row(
+            "SELECT COUNT(*) FROM vector_log WHERE kb_id = ?1 AND seq <= ?2",
+            rusqlite::params![kb_id, index.watermark],
+            |row| row.get(0),
+        ) {
+            Ok(backlog) => backlog,
+            Err(e) => {
+                log::warn!("[KB] Failed to read vector log for {}: {}", kb_id, e);
+                return;
+            }
+        };
+        if backlog < CHECKPOINT_LOG_ROWS {
+            return;
+        }
+        if let Err(e) = index.save(&self.index_path(kb_id)) {
+            log::warn!("[KB] Failed to save ANN index for {}: {}", kb_id, e);
+            return;
+        }
+        if let Err(e) = conn.execute(
+            "DELETE FROM vector_log WHERE kb_id = ?1 AND seq <= ?2",
+            rusqlite::params![kb_id, index.watermark],
+        ) {
+            log::warn!("[KB] Failed to prune vector log for {}: {}", kb_id, e);
+        }
+    }
+
+    /// 丢掉知识库的索引（内存和文件），下次需要时重新构建
+    pub(crate) fn remove(&self, kb_id: &str) {
+        self.ready.lock().unwrap_or_else(|e| e.into_inner()).remove(kb_id);
+        let path = self.index_path(kb_id);
+        if path.exists() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::warn!("[KB] Failed to remove ANN index {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 可复现的伪随机向量
+    fn random_vectors(count: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                (0..dim)
+                    .map(|_| {
+                        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                        ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn exact_top_k(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<String> {
+        let mut scored: Vec<(f32, usize)> = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (super::super::db::cosine_similarity(query, v), i))
+            .collect();
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        scored.into_iter().take(k).map(|(_, i)| format!("c{}", i)).collect()
+    }
+
+    #[test]
+    fn hnsw_recall_matches_exact_search() {
+        let vectors = random_vectors(1000, 32, 7);
+        let mut index = HnswIndex::new(32);
+        for (i, v) in vectors.iter().enumerate() {
+            assert!(index.insert(&format!("c{}", i), v));
+        }
+
+        let queries = random_vectors(20, 32, 99);
+        let mut hits = 0;
+        for query in &queries {
+            let expected = exact_top_k(&vectors, query, 10);
+            let found = index.search(query, 10);
+            hits += found.iter().filter(|id| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (queries.len() * 10) as f32;
+        assert!(recall >= 0.9, "recall too low: {}", recall);
+    }
+
+    #[test]
+    fn removed_vectors_are_not_returned_and_index_survives_reload() {
+        let vectors = random_vectors(300, 16, 3);
+        let mut index = HnswIndex::new(16);
+        for (i, v) in vectors.iter().enumerate() {
+            index.insert(&format!("c{}", i), v);
+        }
+        // 查询自身时应当排在第一
+        assert_eq!(index.search(&vectors[42], 1), vec!["c42".to_string()]);
+
+        index.remove("c42");
+        assert!(!index.search(&vectors[42], 10).contains(&"c42".to_string()));
+        assert_eq!(index.len(), 299);
+
+        let path = std::env::temp_dir().join(format!("baiyu-ann-test-{}.hnsw", uuid::Uuid::new_v4()));
+        index.watermark = 17;
+        index.save(&path).unwrap();
+        let loaded = HnswIndex::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.watermark, 17);
+        assert_eq!(loaded.len(), 299);
+        assert_eq!(loaded.search(&vectors[7], 5), index.search(&vectors[7], 5));
+    }
+
+    #[test]
+    fn small_kb_logs_are_pruned_on_write_and_encrypted_kbs_log_nothing() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at, encrypted)
+                VALUES ('kb', 'kb', 'cfg', 'openai', 'm', 0, 0, 0), ('secret', 'secret', 'cfg', 'openai', 'm', 0, 0, 1);
+             INSERT INTO vectors (chunk_id, document_id, kb_id, vector) VALUES
+                ('c1', 'd1', 'kb', x'0000803f'), ('c2', 'd1', 'kb', x'0000803f'), ('s1', 'd2', 'secret', x'0000803f');",
+        )
+        .unwrap();
+        let log_rows = |kb_id: &str| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM vector_log WHERE kb_id = ?1", [kb_id], |row| row.get(0)).unwrap()
+        };
+        assert_eq!((log_rows("kb"), log_rows("secret")), (2, 0));
+
+        let mut index = build_index(&conn, "kb").unwrap();
+        conn.execute("DELETE FROM vectors WHERE chunk_id = 'c1'", []).unwrap();
+        assert!(prune_vector_log(&conn, "kb").unwrap());
+        assert!(!prune_vector_log(&conn, "kb").unwrap());
+        assert_eq!(log_rows("kb"), 1);
+
+        // 留下的旧索引缺了 c1 的删除记录，同步时要求重建
+        assert!(sync_index(&conn, &mut index, "kb").is_err());
+        let mut rebuilt = build_index(&conn, "kb").unwrap();
+        assert_eq!(rebuilt.len(), 1);
+        assert!(!sync_index(&conn, &mut rebuilt, "kb").unwrap());
+    }
+
+    #[test]
+    fn checkpoint_writes_the_index_and_prunes_the_log_once_enough_changes_pile_up() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        let insert = |range: std::ops::Range<i64>| {
+            for i in range {
+                conn.execute(
+                    "INSERT INTO vectors (chunk_id, document_id, kb_id, vector) VALUES (?1, 'd', 'kb', x'0000803f')",
+                    [format!("c{}", i)],
+                )
+                .unwrap();
+            }
+        };
+        let log_rows = || -> i64 { conn.query_row("SELECT COUNT(*) FROM vector_log", [], |row| row.get(0)).unwrap() };
+
+        let dir = std::env::temp_dir().join(format!("baiyu-ann-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ann = AnnIndexes::new(dir.clone(), dir.join("app.db"));
+        insert(0..10);
+        let index = Arc::new(Mutex::new(build_index(&conn, "kb").unwrap()));
+        ann.ready.lock().unwrap().insert("kb".to_string(), index.clone());
+
+        // 积压的日志不多时只同步内存里的索引，不写文件也不动日志
+        insert(10..20);
+        ann.checkpoint(&conn, "kb");
+        assert_eq!(index.lock().unwrap().len(), 20);
+        assert_eq!(log_rows(), 20);
+        assert!(!ann.index_path("kb").exists());
+
+        insert(20..CHECKPOINT_LOG_ROWS);
+        ann.checkpoint(&conn, "kb");
+        assert_eq!(log_rows(), 0);
+        let saved = HnswIndex::load(&ann.index_path("kb")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(saved.len(), CHECKPOINT_LOG_ROWS as usize);
+        assert_eq!(saved.watermark, index.lock().unwrap().watermark);
+    }
+}
@@ -0,0 +1,485 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 知识库导出/导入模块
+ *
+ * 功能说明:
+ * - export_knowledge_base 把一个知识库（配置、文档记录、分块和向量）打包成一个 zip 文件
+ * - import_knowledge_base 从这样的 zip 文件恢复出一个新的知识库，不需要重新生成向量
+ *
+ * 归档里有三个文件：manifest.json 存格式版本、知识库配置和文档列表；
+ * chunks.jsonl 每行一个分块，向量按小端 f32 字节序列做 base64 编码；
+ * parents.jsonl 每行一个父子分块的父块（旧版本的归档没有这个文件）。
+ * 导入时所有 id 都重新生成，可以在同一台机器上重复导入；embedding 的 provider/model
+ * 沿用归档里的，API Key 取导入时选的 Embedding API 配置（需要是同一个模型，否则检索时
+ * 查询向量和存量向量不在同一个空间）。只导出已完成导入的文档。
+ * 多设备同步（commands::sync）也用这个格式传输知识库，导入时保留知识库 id 和时间戳，
+ * 覆盖本机同 id 的知识库。
+ */
+
+use super::commands::{delete_kb_rows, document_from_row, embedding_target, KbState, DOCUMENT_COLUMNS};
+use super::embedding_config::load_embedding_config;
+use super::db::bytes_to_vector;
+use super::fts::segment_for_index;
+use super::quantization::{encode_vector, is_int8};
+use super::types::*;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use tauri::State;
+use uuid::Uuid;
+
+/// 归档格式标识和版本，格式有不兼容的变化时递增
+const ARCHIVE_FORMAT: &str = "baiyu-knowledge-base";
+const ARCHIVE_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CHUNKS_FILE: &str = "chunks.jsonl";
+const PARENTS_FILE: &str = "parents.jsonl";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    exported_at: i64,
+    knowledge_base: KnowledgeBase,
+    documents: Vec<Document>,
+}
+
+/// chunks.jsonl 的一行
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedChunk {
+    id: String,
+    document_id: String,
+    content: String,
+    chunk_index: i32,
+    token_count: i32,
+    #[serde(default)]
+    heading_path: Option<String>,
+    /// base64 编码的向量，没有向量的分块为 None
+    #[serde(default)]
+    vector: Option<String>,
+    /// 父子分块时所属父块的 id（parents.jsonl 里的 id）
+    #[serde(default)]
+    parent_chunk_id: Option<String>,
+    #[serde(default)]
+    page: Option<i32>,
+    #[serde(default)]
+    start_offset: Option<i32>,
+    #[serde(default)]
+    end_offset: Option<i32>,
+}
+
+/// parents.jsonl 的一行
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedParent {
+    id: String,
+    document_id: String,
+    content: String,
+    #[serde(default)]
+    heading_path: Option<String>,
+}
+
+fn archive_error(e: impl std::fmt::Display) -> KnowledgeBaseError {
+    KnowledgeBaseError::DocumentParseError(format!("知识库归档无效: {}", e))
+}
+
+/// 把知识库导出成 zip 归档，返回导出的文档数
+#[tauri::command]
+pub async fn export_knowledge_base(
+    kb_id: String,
+    path: String,
+    kb_state: State<'_, KbState>,
+) -> Result<usize, KnowledgeBaseError> {
+    let db_path = kb_state.db_path.clone();
+    tokio::task::spawn_blocking(move || export_blocking(&db_path, &kb_id, &path))
+        .await
+        .map_err(|e| KnowledgeBaseError::DatabaseError(format!("spawn_blocking failed: {}", e)))?
+}
+
+pub(crate) fn export_blocking(db_path: &str, kb_id: &str, path: &str) -> Result<usize, KnowledgeBaseError> {
+    let conn = crate::db::open_connection(db_path).map_err(db_error)?;
+    let knowledge_base = conn
+        .query_row(
+            &format!("SELECT {} FROM knowledge_bases WHERE id = ?1", KnowledgeBase::COLUMNS),
+            [kb_id],
+            KnowledgeBase::from_row,
+        )
+        .map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                KnowledgeBaseError::NotFound(format!("Knowledge base not found: {}", kb_id))
+            }
+            e => db_error(e),
+        })?;
+    if knowledge_base.encrypted {
+        return Err(KnowledgeBaseError::InvalidConfig("加密的知识库不能导出".to_string()));
+    }
+
+    let documents: Vec<Document> = {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM documents WHERE kb_id = ?1 AND status = 'completed' AND deleted_at IS NULL ORDER BY created_at ASC",
+                DOCUMENT_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let documents = stmt
+            .query_map([kb_id], |row| document_from_row(row, None))
+            .map_err(db_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_error)?;
+        documents
+    };
+
+    let file = std::fs::File::create(path)
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(format!("无法创建文件 {}: {}", path, e)))?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    let manifest = Manifest {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        exported_at: chrono::Utc::now().timestamp_millis(),
+        knowledge_base,
+        documents,
+    };
+    zip.start_file(MANIFEST_FILE, options).map_err(archive_error)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(archive_error)?;
+
+    // 分块逐行写出，不把整个知识库的向量都放进内存
+    zip.start_file(CHUNKS_FILE, options).map_err(archive_error)?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT c.id, c.document_id, c.content, c.chunk_index, c.token_count, c.heading_path, v.vector, c.parent_chunk_id,
+                    c.page, c.start_offset, c.end_offset
+             FROM chunks c
+             JOIN documents d ON c.document_id = d.id
+             LEFT JOIN vectors v ON v.chunk_id = c.id
+             WHERE c.kb_id = ?1 AND d.status = 'completed' AND d.deleted_at IS NULL
+             ORDER BY c.document_id, c.chunk_index",
+        )
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([kb_id], |row| {
+            let vector: Option<Vec<u8>> = row.get(6)?;
+            Ok(ArchivedChunk {
+                id: row.get(0)?,
+                document_id: row.get(1)?,
+                content: row.get(2)?,
+                chunk_index: row.get(3)?,
+                token_count: row.get(4)?,
+                heading_path: row.get(5)?,
+                vector: vector.map(|bytes| base64::engine::general_purpose::STANDARD.encode(bytes)),
+                parent_chunk_id: row.get(7)?,
+                page: row.get(8)?,
+                start_offset: row.get(9)?,
+                end_offset: row.get(10)?,
+            })
+        })
+        .map_err(db_error)?;
+    for row in rows {
+        let chunk = row.map_err(db_error)?;
+        serde_json::to_writer(&mut zip, &chunk).map_err(archive_error)?;
+        zip.write_all(b"\n").map_err(archive_error)?;
+    }
+
+    zip.start_file(PARENTS_FILE, options).map_err(archive_error)?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT p.id, p.document_id, p.content, p.heading_path
+             FROM parent_chunks p
+             JOIN documents d ON p.document_id = d.id
+             WHERE p.kb_id = ?1 AND d.status = 'completed' AND d.deleted_at IS NULL",
+        )
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([kb_id], |row| {
+            Ok(ArchivedParent {
+                id: row.get(0)?,
+                document_id: row.get(1)?,
+                content: row.get(2)?,
+                heading_path: row.get(3)?,
+            })
+        })
+        .map_err(db_error)?;
+    for row in rows {
+        let parent = row.map_err(db_error)?;
+        serde_json::to_writer(&mut zip, &parent).map_err(archive_error)?;
+        zip.write_all(b"\n").map_err(archive_error)?;
+    }
+
+    zip.finish().map_err(archive_error)?;
+    log::info!("[KB] Exported knowledge base {} ({} documents) to {}", kb_id, manifest.documents.len(), path);
+    Ok(manifest.documents.len())
+}
+
+/// 从 zip 归档导入一个新的知识库
+#[tauri::command]
+pub async fn import_knowledge_base(
+    path: String,
+    embedding_api_config_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<KnowledgeBase, KnowledgeBaseError> {
+    let db_path = kb_state.db_path.clone();
+    tokio::task::spawn_blocking(move || import_blocking(&db_path, &path, &embedding_api_config_id, false))
+        .await
+        .map_err(|e| KnowledgeBaseError::DatabaseError(format!("spawn_blocking failed: {}", e)))?
+}
+
+/// 从归档导入知识库
+///
+/// keep_identity 为 false 时生成新的知识库 id，导入的是一份新知识库；为 true 时沿用归档里的
+/// id 和创建/修改时间，本机已有的同 id 知识库（文档、分块、向量）在同一个事务里先删掉再写入，
+/// 会话对它的绑定保持不变。调用方之后需要丢掉这个知识库的 ANN 索引和向量缓存。
+pub(crate) fn import_blocking(
+    db_path: &str,
+    path: &str,
+    embedding_api_config_id: &str,
+    keep_identity: bool,
+) -> Result<KnowledgeBase, KnowledgeBaseError> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(format!("无法打开文件 {}: {}", path, e)))?;
+    let mut zip = zip::ZipArchive::new(file).map_err(archive_error)?;
+
+    let manifest: Manifest = {
+        let entry = zip.by_name(MANIFEST_FILE).map_err(archive_error)?;
+        serde_json::from_reader(entry).map_err(archive_error)?
+    };
+    if manifest.format != ARCHIVE_FORMAT {
+        return Err(archive_error("不是知识库归档文件"));
+    }
+    if manifest.version > ARCHIVE_VERSION {
+        return Err(archive_error(format!("归档版本 {} 比当前支持的版本 {} 新，请先升级应用", manifest.version, ARCHIVE_VERSION)));
+    }
+
+    let mut conn = crate::db::open_connection(db_path).map_err(db_error)?;
+    let tx = conn.transaction().map_err(db_error)?;
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut kb = manifest.knowledge_base;
+    if !keep_identity {
+        kb.id = Uuid::new_v4().to_string();
+    }
+    kb.embedding_api_config_id = embedding_api_config_id.to_string();
+    // 归档里的向量是按原模型生成的，所选配置必须是同一个模型；Base URL 用本机配置的
+    let config = load_embedding_config(&tx, embedding_api_config_id)?;
+    let (provider, model, _) = embedding_target(&kb);
+    if config.provider != provider || config.model != model {
+        return Err(KnowledgeBaseError::InvalidConfig(format!(
+            "归档的向量由 {}/{} 生成，所选配置是 {}/{}，请选择同一模型的配置",
+            provider, model, config.provider, config.model
+        )));
+    }
+    kb.embedding_provider = config.provider;
+    kb.embedding_model = config.model;
+    kb.embedding_base_url = config.base_url;
+    kb.document_count = manifest.documents.len() as i32;
+    if keep_identity {
+        delete_kb_rows(&tx, &kb.id)?;
+        tx.execute("DELETE FROM vectors WHERE kb_id = ?1", [&kb.id]).map_err(db_error)?;
+        tx.execute("DELETE FROM vector_log WHERE kb_id = ?1", [&kb.id]).map_err(db_error)?;
+        tx.execute("DELETE FROM vector_versions WHERE kb_id = ?1", [&kb.id]).map_err(db_error)?;
+        tx.execute("DELETE FROM knowledge_bases WHERE id = ?1", [&kb.id]).map_err(db_error)?;
+    } else {
+        kb.created_at = now;
+        kb.updated_at = now;
+    }
+
+    let separators_json = if kb.separators.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(&kb.separators).map_err(archive_error)?)
+    };
+    let retrieval_defaults_json = kb
+        .retrieval_defaults
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(archive_error)?;
+    tx.execute(
+        r#"
+        INSERT INTO knowledge_bases
+        (id, name, description, embedding_provider, embedding_model, embedding_dim, embedding_api_config_id, embedding_base_url,
+         chunk_size, chunk_overlap, chunk_unit, separators, retrieval_defaults, vector_quantization, parent_chunk_size,
+         created_at, updated_at, document_count)
+        VALUES (?1, ?2, ?3, ?4, ?5, 1536, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+        "#,
+        rusqlite::params![
+            &kb.id,
+            &kb.name,
+            &kb.description,
+            &kb.embedding_provider,
+            &kb.embedding_model,
+            &kb.embedding_api_config_id,
+            &kb.embedding_base_url,
+            kb.chunk_size,
+            kb.chunk_overlap,
+            kb.chunk_unit.as_str(),
+            separators_json,
+            retrieval_defaults_json,
+            kb.vector_quantization.as_str(),
+            kb.parent_chunk_size,
+            kb.created_at,
+            kb.updated_at,
+            kb.document_count,
+        ],
+    )
+    .map_err(db_error)?;
+
+    // 旧 id -> 新 id
+    let doc_ids: HashMap<String, String> = manifest
+        .documents
+        .iter()
+        .map(|d| (d.id.clone(), Uuid::new_v4().to_string()))
+        .collect();
+    for doc in &manifest.documents {
+        let tags = if doc.tags.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&doc.tags).map_err(archive_error)?)
+        };
+        tx.execute(
+            r#"
+            INSERT INTO documents
+            (id, kb_id, filename, file_type, file_size, file_hash, content_preview, chunk_count, status,
+             source, duplicate_of, tags, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'completed', ?9, ?10, ?11, ?12)
+            "#,
+            rusqlite::params![
+                &doc_ids[&doc.id],
+                &kb.id,
+                &doc.filename,
+                &doc.file_type,
+                doc.file_size,
+                &doc.file_hash,
+                &doc.content_preview,
+                doc.chunk_count,
+                &doc.source,
+                doc.duplicate_of.as_ref().and_then(|id| doc_ids.get(id)),
+                tags,
+                doc.created_at,
+            ],
+        )
+        .map_err(db_error)?;
+    }
+
+    // 父块要先导入，分块里引用的是它们的新 id
+    let mut parent_ids: HashMap<String, String> = HashMap::new();
+    if let Ok(entry) = zip.by_name(PARENTS_FILE) {
+        for line in BufReader::new(entry).lines() {
+            let line = line.map_err(archive_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parent: ArchivedParent = serde_json::from_str(&line).map_err(archive_error)?;
+            let Some(doc_id) = doc_ids.get(&parent.document_id) else {
+                continue;
+            };
+            let parent_id = Uuid::new_v4().to_string();
+            tx.execute(
+                "INSERT INTO parent_chunks (id, document_id, kb_id, content, heading_path) VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![&parent_id, doc_id, &kb.id, &parent.content, &parent.heading_path],
+            )
+            .map_err(db_error)?;
+            parent_ids.insert(parent.id, parent_id);
+        }
+    }
+
+    let entry = zip.by_name(CHUNKS_FILE).map_err(archive_error)?;
+    let mut imported_chunks = 0usize;
+    for line in BufReader::new(entry).lines() {
+        let line = line.map_err(archive_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk: ArchivedChunk = serde_json::from_str(&line).map_err(archive_error)?;
+        let Some(doc_id) = doc_ids.get(&chunk.document_id) else {
+            continue;
+        };
+        let chunk_id = Uuid::new_v4().to_string();
+
+        tx.execute(
+            r#"
+            INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, token_count, heading_path, parent_chunk_id,
+                                page, start_offset, end_offset, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+            "#,
+            rusqlite::params![
+                &chunk_id,
+                doc_id,
+                &kb.id,
+                &chunk.content,
+                chunk.chunk_index,
+                chunk.token_count,
+                &chunk.heading_path,
+                chunk.parent_chunk_id.as_ref().and_then(|id| parent_ids.get(id)),
+                chunk.page,
+                chunk.start_offset,
+                chunk.end_offset,
+                now
+            ],
+        )
+        .map_err(db_error)?;
+        tx.execute(
+            "INSERT INTO chunks_fts (rowid, kb_id, content) VALUES (last_insert_rowid(), ?1, ?2)",
+            rusqlite::params![&kb.id, segment_for_index(&chunk.content)],
+        )
+        .map_err(db_error)?;
+
+        if let Some(encoded) = &chunk.vector {
+            let vector = base64::engine::general_purpose::STANDARD.decode(encoded).map_err(archive_error)?;
+            if vector.is_empty() || (vector.len() % 4 != 0 && !is_int8(&vector)) {
+                return Err(archive_error(format!("分块 {} 的向量长度不正确", chunk.id)));
+            }
+            // 归档里的向量按原样导出，可能是任意一种格式，按知识库的量化方式重新编码
+            let vector = encode_vector(&bytes_to_vector(&vector), kb.vector_quantization);
+            tx.execute(
+                "INSERT INTO vectors (chunk_id, document_id, kb_id, vector, code) VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![&chunk_id, doc_id, &kb.id, vector.vector, vector.code],
+            )
+            .map_err(db_error)?;
+        }
+        imported_chunks += 1;
+    }
+
+    tx.commit().map_err(db_error)?;
+    log::info!(
+        "[KB] Imported knowledge base {} from {}: {} documents, {} chunks",
+        kb.name,
+        path,
+        manifest.documents.len(),
+        imported_chunks
+    );
+    Ok(kb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knowledge_base::db::{bytes_to_vector, vector_to_bytes};
+
+    #[test]
+    fn archived_chunk_round_trips_its_vector() {
+        let vector = vec![0.25f32, -1.5, 3.0];
+        let chunk = ArchivedChunk {
+            id: "c1".into(),
+            document_id: "d1".into(),
+            content: "内容".into(),
+            chunk_index: 0,
+            token_count: 2,
+            heading_path: None,
+            vector: Some(base64::engine::general_purpose::STANDARD.encode(vector_to_bytes(&vector))),
+            parent_chunk_id: None,
+            page: Some(3),
+            start_offset: None,
+            end_offset: None,
+        };
+
+        let line = serde_json::to_string(&chunk).unwrap();
+        let parsed: ArchivedChunk = serde_json::from_str(&line).unwrap();
+        let bytes = base64::engine::general_purpose::STANDARD.decode(parsed.vector.unwrap()).unwrap();
+        assert_eq!(bytes_to_vector(&bytes), vector);
+    }
+}
@@ -3,12 +3,28 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::types::*;
-use super::document::{parse_document, calculate_file_hash, split_text, estimate_tokens};
-use super::embedding::generate_embeddings;
-use super::db::{VectorStore, init_sqlite_tables};
-use super::retrieval::Retriever;
-use tauri::State;
+use super::document::{parse_document, parse_document_pages, locate_chunks, calculate_file_hash, calculate_text_hash, split_document, split_parent_child, estimate_tokens, join_chunks, replace_char_range, ChunkLocation, ParsedDocument, SplitOptions, TextChunk};
+use super::embedding::{embed_in_batches, generate_embeddings};
+use super::ann::prune_vector_log;
+use super::db::{VectorStore, init_sqlite_tables, insert_rows, bytes_to_vector, vector_to_bytes};
+use super::fts::segment_for_index;
+use super::quantization::requantize_vectors;
+use super::encryption::{
+    cipher_for, create_kb_key, delete_kb_key, document_cipher, encode_for_kb, kb_cipher, open_text, seal_text, KbCipher,
+};
+use super::reembed::ensure_not_reembedding;
+use super::import_queue::{acquire_slot, ImportPool};
+use super::import_tasks::{recover_interrupted_imports, rollback_interrupted, run_cancellable, CANCELLED_NOTICE};
+use super::embedding_config::{kb_embedding, load_embedding_config, read_api_key, EmbeddingTarget};
+use super::retrieval::{validate_context_template, Retriever};
+use super::summary::spawn_summary;
+use super::trash::{trash_document_rows, NOT_TRASHED};
+use crate::commands::web_fetch::{fetch_page_markdown, FetchedPage};
+use crate::commands::constants::{CHUNK_PAGE_DEFAULT_LIMIT, CHUNK_PAGE_MAX_LIMIT};
+use tauri::{AppHandle, Emitter, Manager, State};
+use std::collections::HashMap;
 use std::sync::Arc;
+use rusqlite::OptionalExtension;
 
 use uuid::Uuid;
 use keyring::Entry;
@@ -19,29 +35,62 @@ pub struct KbState {
 }
 
 /// 初始化知识库相关数据表
+///
+/// 启动时顺带恢复上次退出时没有导入完的文档（见 import_tasks）。
 pub fn init_knowledge_base(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
-    init_sqlite_tables(conn)
+    init_sqlite_tables(conn)?;
+    match recover_interrupted_imports(conn) {
+        Ok(0) => {}
+        Ok(count) => log::info!("[KB] Recovered {} interrupted imports", count),
+        Err(e) => log::warn!("[KB] Failed to recover interrupted imports: {}", e),
+    }
+    Ok(())
 }
 
 /// 根据 embedding 配置 ID 从系统 keyring 中取出对应的 API Key
-/// keyring 条目格式为：emb_{config_id}
-fn get_embedding_api_key(config_id: &str) -> Result<String, KnowledgeBaseError> {
-    let entry = Entry::new(
-        "BaiyuAISpace",
-        &format!("api_keys_emb_{}", config_id),
-    ).map_err(|e| KnowledgeBaseError::InvalidConfig(format!("Failed to access keyring: {}", e)))?;
+/// keyring 条目格式为：emb_{config_id}（和 embedding_configs 表的 key_ref 一致）
+pub(crate) fn get_embedding_api_key(config_id: &str) -> Result<String, KnowledgeBaseError> {
+    read_api_key(&format!("emb_{}", config_id))
+}
 
-    match entry.get_password() {
-        Ok(key) => Ok(key),
-        Err(keyring::Error::NoEntry) => {
-            Err(KnowledgeBaseError::InvalidConfig(
-                format!("Embedding API key not found for config: {}. Please set it in Settings.", config_id)
-            ))
-        }
-        Err(e) => Err(KnowledgeBaseError::InvalidConfig(
-            format!("Failed to retrieve API key: {}", e)
-        )),
-    }
+/// 打开数据库取得知识库生成 embedding 用的模型和 API Key，见 kb_embedding
+pub(super) fn resolve_embedding(db_path: &str, kb: &KnowledgeBase) -> Result<EmbeddingTarget, KnowledgeBaseError> {
+    let conn = crate::db::open_connection(db_path)
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    kb_embedding(&conn, kb)
+}
+
+/// 在阻塞线程池里打开一个数据库连接执行 f
+///
+/// rusqlite 的调用都是阻塞的，写入大批分块和向量时直接在 async 函数里执行会卡住异步运行时的工作线程。
+pub(crate) async fn with_conn<T, F>(db_path: &str, f: F) -> Result<T, KnowledgeBaseError>
+where
+    F: FnOnce(rusqlite::Connection) -> Result<T, KnowledgeBaseError> + Send + 'static,
+    T: Send + 'static,
+{
+    let db_path = db_path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let conn = crate::db::open_connection(&db_path)
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        f(conn)
+    })
+    .await
+    .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+}
+
+/// 同 with_conn，但执行期间持有 DbState 的锁，和其他持锁的写操作排队执行
+pub(super) async fn with_locked_conn<T, F>(db_state: &crate::db::DbState, f: F) -> Result<T, KnowledgeBaseError>
+where
+    F: FnOnce(rusqlite::Connection) -> Result<T, KnowledgeBaseError> + Send + 'static,
+    T: Send + 'static,
+{
+    db_state
+        .run(move |db| {
+            let conn = crate::db::open_connection(&db.path)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            f(conn)
+        })
+        .await
 }
 
 /// 创建新知识库
@@ -52,77 +101,145 @@ pub async fn create_knowledge_base(
 ) -> Result<KnowledgeBase, KnowledgeBaseError> {
     log::info!("[KB] Creating knowledge base: {:?}", request);
 
-    if request.embedding_provider.trim().is_empty() || request.embedding_model.trim().is_empty() {
-        return Err(KnowledgeBaseError::InvalidConfig(
-            "embedding_provider and embedding_model are required".to_string()
-        ));
-    }
-
     // 校验 chunk_overlap 必须小于 chunk_size
     let chunk_size = request.chunk_size.unwrap_or(1000);
     let chunk_overlap = request.chunk_overlap.unwrap_or(200);
+    let chunk_unit = request.chunk_unit.unwrap_or_default();
+    let vector_quantization = request.vector_quantization.unwrap_or_default();
+    // 空分隔符没有意义，直接去掉；剩下的为空就用默认分隔符（存 NULL）
+    let separators: Vec<String> = request.separators.unwrap_or_default().into_iter().filter(|s| !s.is_empty()).collect();
+    let separators_json = if separators.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(&separators).map_err(|e| KnowledgeBaseError::InvalidConfig(e.to_string()))?)
+    };
     if chunk_overlap >= chunk_size {
         return Err(KnowledgeBaseError::InvalidConfig(
             format!("chunk_overlap ({}) must be less than chunk_size ({})", chunk_overlap, chunk_size)
         ));
     }
+    let parent_chunk_size = request.parent_chunk_size.unwrap_or(0).max(0);
+    validate_parent_chunk_size(parent_chunk_size, chunk_size)?;
+    let encrypted = request.encrypted.unwrap_or(false);
+    validate_encryption(encrypted, vector_quantization)?;
+    let context_template = normalize_context_template(request.context_template)?;
+    let context_language = request.context_language.unwrap_or_default();
+
+    with_conn(&kb_state.db_path, move |conn| {
+        // 服务商/模型/Base URL 以配置为准，快照到知识库上，记录向量是由哪个模型生成的
+        let embedding = load_embedding_config(&conn, &request.embedding_api_config_id)?;
+
+        let id = Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().timestamp_millis();
+        // 先把密钥存进 keyring，存不进去就不创建知识库
+        if encrypted {
+            create_kb_key(&id)?;
+        }
 
-    let conn = rusqlite::Connection::open(&kb_state.db_path)
-        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        log::info!(
+            "[KB] Inserting with chunk_size={}, chunk_overlap={}, chunk_unit={}",
+            chunk_size,
+            chunk_overlap,
+            chunk_unit.as_str()
+        );
 
-    let id = Uuid::new_v4().to_string();
-    let now = chrono::Utc::now().timestamp_millis();
+        let result = conn.execute(
+            r#"
+            INSERT INTO knowledge_bases
+            (id, name, description, embedding_provider, embedding_model, embedding_dim, embedding_api_config_id, embedding_base_url, chunk_size, chunk_overlap, chunk_unit, separators, vector_quantization, parent_chunk_size, encrypted, context_template, context_language, created_at, updated_at, document_count)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, 0)
+            "#,
+            rusqlite::params![
+                &id,
+                &request.name,
+                &request.description,
+                &embedding.provider,
+                &embedding.model,
+                1536i32,     // embedding_dim —— 默认 1536
+                &request.embedding_api_config_id,
+                &embedding.base_url,
+                chunk_size,
+                chunk_overlap,
+                chunk_unit.as_str(),
+                separators_json,
+                vector_quantization.as_str(),
+                parent_chunk_size,
+                encrypted,
+                context_template,
+                context_language.as_str(),
+                now,
+                now,
+            ],
+        );
+
+        match result {
+            Ok(rows) => {
+                log::info!("[KB] Successfully created, rows affected: {}", rows);
+            }
+            Err(e) => {
+                log::error!("[KB] Failed to insert: {}", e);
+                if encrypted {
+                    delete_kb_key(&id);
+                }
+                return Err(KnowledgeBaseError::DatabaseError(e.to_string()));
+            }
+        }
 
-    log::info!("[KB] Inserting with chunk_size={}, chunk_overlap={}", chunk_size, chunk_overlap);
-
-    let result = conn.execute(
-        r#"
-        INSERT INTO knowledge_bases
-        (id, name, description, embedding_provider, embedding_model, embedding_dim, embedding_api_config_id, embedding_base_url, chunk_size, chunk_overlap, created_at, updated_at, document_count)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, 0)
-        "#,
-        rusqlite::params![
-            &id,
-            &request.name,
-            &request.description,
-            &request.embedding_provider,
-            &request.embedding_model,
-            1536i32,     // embedding_dim —— 默认 1536
-            &request.embedding_api_config_id,
-            &request.embedding_base_url,
+        log::info!("Created knowledge base: {} ({})", request.name, id);
+
+        Ok(KnowledgeBase {
+            id,
+            name: request.name,
+            description: request.description,
+            embedding_api_config_id: request.embedding_api_config_id,
+            embedding_provider: embedding.provider,
+            embedding_model: embedding.model,
+            embedding_base_url: embedding.base_url,
             chunk_size,
             chunk_overlap,
-            now,
-            now,
-        ],
-    );
+            chunk_unit,
+            separators,
+            retrieval_defaults: None,
+            vector_quantization,
+            parent_chunk_size,
+            encrypted,
+            context_template,
+            context_language,
+            created_at: now,
+            updated_at: now,
+            document_count: 0,
+        })
+    })
+    .await
+}
 
-    match result {
-        Ok(rows) => {
-            log::info!("[KB] Successfully created, rows affected: {}", rows);
+/// 加密知识库不能用 binary 量化：单独存放的符号位没有加密
+fn validate_encryption(encrypted: bool, quantization: VectorQuantization) -> Result<(), KnowledgeBaseError> {
+    if encrypted && quantization == VectorQuantization::Binary {
+        return Err(KnowledgeBaseError::InvalidConfig("加密的知识库不支持 binary 量化，请选择 int8 或不量化".to_string()));
+    }
+    Ok(())
+}
+
+/// 空白模板视为没有自定义（用默认模板），其余的必须包含两个占位符
+fn normalize_context_template(template: Option<String>) -> Result<Option<String>, KnowledgeBaseError> {
+    match template {
+        Some(template) if !template.trim().is_empty() => {
+            validate_context_template(&template)?;
+            Ok(Some(template))
         }
-        Err(e) => {
-            log::error!("[KB] Failed to insert: {}", e);
-            return Err(KnowledgeBaseError::DatabaseError(e.to_string()));
-        }
-    }
-
-    log::info!("Created knowledge base: {} ({})", request.name, id);
-
-    Ok(KnowledgeBase {
-        id,
-        name: request.name,
-        description: request.description,
-        embedding_api_config_id: request.embedding_api_config_id,
-        embedding_provider: request.embedding_provider,
-        embedding_model: request.embedding_model,
-        embedding_base_url: request.embedding_base_url,
-        chunk_size,
-        chunk_overlap,
-        created_at: now,
-        updated_at: now,
-        document_count: 0,
-    })
+        _ => Ok(None),
+    }
+}
+
+/// 父块必须比子块大，0 表示不开启父子分块
+fn validate_parent_chunk_size(parent_chunk_size: i32, chunk_size: i32) -> Result<(), KnowledgeBaseError> {
+    if parent_chunk_size > 0 && parent_chunk_size <= chunk_size {
+        return Err(KnowledgeBaseError::InvalidConfig(
+            format!("parent_chunk_size ({}) must be greater than chunk_size ({})", parent_chunk_size, chunk_size)
+        ));
+    }
+    Ok(())
 }
 
 /// 列出所有知识库
@@ -130,39 +247,203 @@ pub async fn create_knowledge_base(
 pub async fn list_knowledge_bases(
     kb_state: State<'_, KbState>,
 ) -> Result<Vec<KnowledgeBase>, KnowledgeBaseError> {
-    let conn = rusqlite::Connection::open(&kb_state.db_path)
-        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    with_conn(&kb_state.db_path, move |conn| {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM knowledge_bases ORDER BY updated_at DESC",
+            KnowledgeBase::COLUMNS
+        )).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    let mut stmt = conn.prepare(
-        "SELECT id, name, description, embedding_api_config_id,
-         chunk_size, chunk_overlap, created_at, updated_at, document_count,
-         COALESCE(embedding_provider, ''), COALESCE(embedding_model, ''), COALESCE(embedding_base_url, '')
-         FROM knowledge_bases ORDER BY updated_at DESC"
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let rows = stmt.query_map([], KnowledgeBase::from_row).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    let rows = stmt.query_map([], |row| {
-        Ok(KnowledgeBase {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            description: row.get(2)?,
-            embedding_api_config_id: row.get(3)?,
-            chunk_size: row.get(4)?,
-            chunk_overlap: row.get(5)?,
-            created_at: row.get(6)?,
-            updated_at: row.get(7)?,
-            document_count: row.get(8)?,
-            embedding_provider: row.get(9)?,
-            embedding_model: row.get(10)?,
-            embedding_base_url: row.get(11)?,
-        })
-    }).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let mut bases = Vec::new();
+        for row in rows {
+            bases.push(row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?);
+        }
+
+        Ok(bases)
+    })
+    .await
+}
+
+/// 修改知识库设置
+///
+/// 分块参数（chunk_size/chunk_overlap/chunk_unit/separators/parent_chunk_size）只影响之后导入的文档；
+/// request.rechunk 为 true 且分块参数确实变了时，已有文档会在后台逐个按新参数
+/// 重新分块（从原文件或网址重新读取，走 reimport_document 同一条路径，内容没变的分块沿用旧向量）。
+/// 没有记录导入来源或原文件已不存在的文档跳过，保留原来的分块。
+#[tauri::command]
+pub async fn update_knowledge_base(
+    request: UpdateKnowledgeBaseRequest,
+    app_handle: AppHandle,
+    db_state: State<'_, crate::db::DbState>,
+) -> Result<UpdateKnowledgeBaseResult, KnowledgeBaseError> {
+    let (kb, jobs) = with_locked_conn(&db_state, move |mut conn| {
+        let old = load_knowledge_base(&conn, &request.kb_id)?;
+
+        let name = request.name.as_deref().map(str::trim).unwrap_or(&old.name).to_string();
+        if name.is_empty() {
+            return Err(KnowledgeBaseError::InvalidConfig("知识库名称不能为空".to_string()));
+        }
+        let description = request.description.clone().unwrap_or(old.description.clone());
+        let chunk_size = request.chunk_size.unwrap_or(old.chunk_size);
+        let chunk_overlap = request.chunk_overlap.unwrap_or(old.chunk_overlap);
+        let chunk_unit = request.chunk_unit.unwrap_or(old.chunk_unit);
+        let separators: Vec<String> = match request.separators.clone() {
+            Some(separators) => separators.into_iter().filter(|s| !s.is_empty()).collect(),
+            None => old.separators.clone(),
+        };
+        if chunk_size <= 0 || chunk_overlap < 0 || chunk_overlap >= chunk_size {
+            return Err(KnowledgeBaseError::InvalidConfig(
+                format!("chunk_overlap ({}) must be less than chunk_size ({})", chunk_overlap, chunk_size)
+            ));
+        }
+        let parent_chunk_size = request.parent_chunk_size.unwrap_or(old.parent_chunk_size).max(0);
+        validate_parent_chunk_size(parent_chunk_size, chunk_size)?;
+        let context_template = match request.context_template.clone() {
+            Some(template) => normalize_context_template(Some(template))?,
+            None => old.context_template.clone(),
+        };
+        let context_language = request.context_language.unwrap_or(old.context_language);
+        let retrieval_defaults = if request.clear_retrieval_defaults {
+            None
+        } else {
+            request.retrieval_defaults.clone().or(old.retrieval_defaults.clone())
+        };
+
+        let separators_json = if separators.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&separators).map_err(|e| KnowledgeBaseError::InvalidConfig(e.to_string()))?)
+        };
+        let retrieval_defaults_json = retrieval_defaults
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| KnowledgeBaseError::InvalidConfig(e.to_string()))?;
+
+        conn.execute(
+            "UPDATE knowledge_bases SET name = ?1, description = ?2, chunk_size = ?3, chunk_overlap = ?4,
+             chunk_unit = ?5, separators = ?6, retrieval_defaults = ?7, parent_chunk_size = ?8,
+             context_template = ?9, context_language = ?10, updated_at = ?11 WHERE id = ?12",
+            rusqlite::params![
+                &name,
+                &description,
+                chunk_size,
+                chunk_overlap,
+                chunk_unit.as_str(),
+                separators_json,
+                retrieval_defaults_json,
+                parent_chunk_size,
+                context_template,
+                context_language.as_str(),
+                chrono::Utc::now().timestamp_millis(),
+                &request.kb_id,
+            ],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        // 量化方式变了：在一个事务里改设置并重新编码已有向量
+        let vector_quantization = request.vector_quantization.unwrap_or(old.vector_quantization);
+        validate_encryption(old.encrypted, vector_quantization)?;
+        if vector_quantization != old.vector_quantization {
+            let tx = conn.transaction().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            tx.execute(
+                "UPDATE knowledge_bases SET vector_quantization = ?1 WHERE id = ?2",
+                rusqlite::params![vector_quantization.as_str(), &request.kb_id],
+            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let cipher = kb_cipher(&tx, &request.kb_id)?;
+            let count = requantize_vectors(&tx, &request.kb_id, vector_quantization, cipher.as_deref())
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            tx.commit().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            log::info!(
+                "[KB] Requantized {} vectors in {} as {}",
+                count,
+                request.kb_id,
+                vector_quantization.as_str()
+            );
+        }
+
+        let chunking_changed = chunk_size != old.chunk_size
+            || chunk_overlap != old.chunk_overlap
+            || chunk_unit != old.chunk_unit
+            || separators != old.separators
+            || parent_chunk_size != old.parent_chunk_size;
+
+        let mut jobs = Vec::new();
+        if request.rechunk && chunking_changed {
+            let mut stmt = conn.prepare(
+                "SELECT id FROM documents WHERE kb_id = ?1 AND status = 'completed' AND duplicate_of IS NULL ORDER BY created_at ASC"
+            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let doc_ids: Vec<String> = stmt.query_map([&request.kb_id], |row| row.get(0))
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+                .filter_map(|r| r.ok())
+                .collect();
+            for doc_id in doc_ids {
+                match prepare_reimport(&conn, &doc_id) {
+                    Ok(job) => jobs.push(job),
+                    Err(e) => log::warn!("[KB] Skipping rechunk of document {}: {}", doc_id, e),
+                }
+            }
+        }
 
-    let mut bases = Vec::new();
-    for row in rows {
-        bases.push(row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?);
+        Ok((load_knowledge_base(&conn, &request.kb_id)?, jobs))
+    })
+    .await?;
+
+    let rechunk_documents = jobs.len();
+    if !jobs.is_empty() {
+        log::info!("[KB] Rechunking {} documents in {}", rechunk_documents, kb.name);
+        // 同时进行的数量由导入队列限制，不会一下子向 embedding 服务发出大量请求
+        for job in jobs {
+            let app_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                match run_cancellable(&job.task, run_reimport(&app_handle, &job, true)).await {
+                    Some(result) => finish_reimport(&app_handle, &job.task, result).await,
+                    None => finish_cancelled(&app_handle, &job.task).await,
+                }
+            });
+        }
     }
 
-    Ok(bases)
+    Ok(UpdateKnowledgeBaseResult { knowledge_base: kb, rechunk_documents })
+}
+
+/// 删除知识库的文档、分块、全文索引等数据（不含知识库本身、会话绑定和向量）
+pub(crate) fn delete_kb_rows(conn: &rusqlite::Connection, kb_id: &str) -> Result<(), KnowledgeBaseError> {
+    conn.execute(
+        "DELETE FROM document_contents WHERE document_id IN (SELECT id FROM documents WHERE kb_id = ?1)",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM parent_chunks WHERE kb_id = ?1",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM document_summaries WHERE kb_id = ?1",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM reembed_vectors WHERE kb_id = ?1",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM reembed_jobs WHERE kb_id = ?1",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    // 不指望级联删除（早期版本建的表、chunks_fts 都不受外键约束）：全文索引、分块和文档都显式删掉（全文索引要先删，需要用到 rowid）
+    conn.execute(
+        "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE kb_id = ?1)",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM chunks WHERE kb_id = ?1",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM documents WHERE kb_id = ?1",
+        [kb_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    Ok(())
 }
 
 /// 删除知识库
@@ -171,35 +452,49 @@ pub async fn delete_knowledge_base(
     kb_id: String,
     kb_state: State<'_, KbState>,
 ) -> Result<(), KnowledgeBaseError> {
-    let conn = rusqlite::Connection::open(&kb_state.db_path)
-        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    let id = kb_id.clone();
+    with_conn(&kb_state.db_path, move |conn| {
+        let kb_id = &id;
+        // 检查知识库是否存在
+        let exists: bool = conn.query_row(
+            "SELECT COUNT(*) FROM knowledge_bases WHERE id = ?1",
+            [kb_id],
+            |row| row.get(0),
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    // 检查知识库是否存在
-    let exists: bool = conn.query_row(
-        "SELECT COUNT(*) FROM knowledge_bases WHERE id = ?1",
-        [&kb_id],
-        |row| row.get(0),
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if !exists {
+            return Err(KnowledgeBaseError::NotFound(format!("Knowledge base not found: {}", kb_id)));
+        }
 
-    if !exists {
-        return Err(KnowledgeBaseError::NotFound(format!("Knowledge base not found: {}", kb_id)));
-    }
+        delete_kb_rows(&conn, kb_id)?;
+        conn.execute(
+            "DELETE FROM knowledge_bases WHERE id = ?1",
+            [kb_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    // 从 SQLite 中删除（级联删除会自动清掉关联的 documents 和 chunks）
-    conn.execute(
-        "DELETE FROM knowledge_bases WHERE id = ?1",
-        [&kb_id],
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        // 解除所有会话对它的绑定
+        conn.execute(
+            "DELETE FROM session_kbs WHERE kb_id = ?1",
+            [kb_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(())
+    })
+    .await?;
 
     // 删除向量表
     kb_state.vector_store.drop_kb_table(&kb_id).await?;
+    delete_kb_key(&kb_id);
 
     log::info!("Deleted knowledge base: {}", kb_id);
     Ok(())
 }
 
-/// 把文档标记为失败，并清理掉阶段一（Phase 1）里已经写入的 chunks/FTS5 记录，
-/// 避免文档卡在“处理中”状态却留下一堆孤儿数据（对应 import_document 阶段二失败的情况）。
+/// 把导入失败的文档标记为 "error"
+///
+/// 失败发生在生成 embedding 及之后（import_stage 为 embedding/inserting）时，已经写入的
+/// chunks 和向量都保留下来，import_stage 也不清空，之后可以用 resume_import 从第一个还没有
+/// 向量的分块接着导入；更早的阶段失败则清理掉已经写入的 chunks/FTS5/向量记录，
+/// 避免文档卡在“处理中”状态却留下一堆孤儿数据。
 async fn mark_document_failed(
     db_state: &State<'_, crate::db::DbState>,
     doc_id: &str,
@@ -207,336 +502,1554 @@ async fn mark_document_failed(
 ) -> Result<(), KnowledgeBaseError> {
     log::error!("[KB] {}", error_msg);
 
-    let db = db_state.0.lock().await;
-    let conn = rusqlite::Connection::open(&db.path)
+    let (doc_id, error_msg) = (doc_id.to_string(), error_msg.to_string());
+    with_locked_conn(db_state, move |conn| mark_failed_rows(&conn, &doc_id, &error_msg)).await
+}
+
+/// mark_document_failed 的数据库部分
+fn mark_failed_rows(conn: &rusqlite::Connection, doc_id: &str, error_msg: &str) -> Result<(), KnowledgeBaseError> {
+    let stage: Option<String> = conn.query_row(
+        "SELECT import_stage FROM documents WHERE id = ?1",
+        rusqlite::params![doc_id],
+        |row| row.get(0),
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    if stage.as_deref().and_then(ImportStage::parse).is_some_and(|s| s.is_resumable()) {
+        conn.execute(
+            "UPDATE documents SET status = 'error', error_message = ?1 WHERE id = ?2",
+            rusqlite::params![error_msg, doc_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        return Ok(());
+    }
+
+    conn.execute(
+        "UPDATE documents SET status = 'error', error_message = ?1, import_stage = NULL WHERE id = ?2",
+        rusqlite::params![error_msg, doc_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    if let Err(cleanup_err) = conn.execute(
+        "DELETE FROM vectors WHERE document_id = ?1",
+        rusqlite::params![doc_id],
+    ) {
+        log::warn!("[KB] Failed to clean up orphan vectors: {}", cleanup_err);
+    }
+
+    // 必须在删除 chunks 之前先清理 FTS5 条目（需要用到 chunks 里的 rowid）
+    if let Err(cleanup_err) = conn.execute(
+        "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE document_id = ?1)",
+        rusqlite::params![doc_id],
+    ) {
+        log::warn!("[KB] Failed to clean up FTS5 entries: {}", cleanup_err);
+    }
+
+    // 清理孤儿 chunks（在 FTS5 清理之后进行）
+    if let Err(cleanup_err) = conn.execute(
+        "DELETE FROM chunks WHERE document_id = ?1",
+        rusqlite::params![doc_id],
+    ) {
+        log::warn!("[KB] Failed to clean up orphan chunks: {}", cleanup_err);
+    }
+    if let Err(cleanup_err) = conn.execute(
+        "DELETE FROM parent_chunks WHERE document_id = ?1",
+        rusqlite::params![doc_id],
+    ) {
+        log::warn!("[KB] Failed to clean up orphan parent chunks: {}", cleanup_err);
+    }
+
+    Ok(())
+}
+
+/// 读取知识库配置
+pub(super) fn load_knowledge_base(conn: &rusqlite::Connection, kb_id: &str) -> Result<KnowledgeBase, KnowledgeBaseError> {
+    conn.query_row(
+        &format!("SELECT {} FROM knowledge_bases WHERE id = ?1", KnowledgeBase::COLUMNS),
+        [kb_id],
+        KnowledgeBase::from_row,
+    ).map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => KnowledgeBaseError::NotFound(format!("Knowledge base not found: {}", kb_id)),
+        e => KnowledgeBaseError::DatabaseError(e.to_string()),
+    })
+}
+
+/// 向知识库导入文档
+///
+/// 只读取知识库配置、写入一条 processing 状态的文档记录就立即返回 ImportTask，
+/// 解析、分块、embedding、写入向量都在后台任务 run_import 里完成，各阶段通过
+/// `kb-import-progress` 事件上报，文档记录的 import_stage 随之更新。
+/// 失败时文档被标记为 "error" 并上报 failed 阶段（见 mark_document_failed）。
+///
+/// # 对应 #32 的修复：
+/// - API Key 改为通过 embedding_api_config_id 从安全存储（keyring）中读取
+/// - 前端不再传递 api_key 参数
+///
+/// 内容和知识库里已有文档相同时按 duplicate_policy 处理（默认跳过）。
+#[tauri::command]
+pub async fn import_document(
+    kb_id: String,
+    file_path: String,
+    duplicate_policy: Option<DuplicatePolicy>,
+    app_handle: AppHandle,
+) -> Result<ImportTask, KnowledgeBaseError> {
+    let path = std::path::Path::new(&file_path);
+    if !path.is_file() {
+        return Err(KnowledgeBaseError::DocumentParseError(format!("文件不存在: {}", file_path)));
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown")
+        .to_string();
+    let file_type = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("txt")
+        .to_lowercase();
+
+    // 获取文件大小
+    let file_size = match tokio::fs::metadata(&file_path).await {
+        Ok(m) => m.len() as i64,
+        Err(e) => {
+            log::warn!("Failed to read file metadata for {}: {}", file_path, e);
+            0
+        }
+    };
+
+    let source = ImportSource::File(file_path);
+    start_import(kb_id, file_name, file_type, file_size, source, duplicate_policy.unwrap_or_default(), app_handle).await
+}
+
+/// 把网页导入知识库
+///
+/// 和 import_document 走同一条后台管线：抓取网页、提取正文（转成 Markdown，
+/// 按标题层级分块）、embedding。网址同时作为文档的文件名和来源。
+/// 抓取受 fetch_url 同样的限制（只允许公网 http/https 地址，响应体有大小上限）。
+#[tauri::command]
+pub async fn import_url(
+    kb_id: String,
+    url: String,
+    duplicate_policy: Option<DuplicatePolicy>,
+    app_handle: AppHandle,
+) -> Result<ImportTask, KnowledgeBaseError> {
+    let parsed = reqwest::Url::parse(url.trim())
+        .map_err(|e| KnowledgeBaseError::InvalidConfig(format!("网址无效: {}", e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(KnowledgeBaseError::InvalidConfig(format!("只支持 http/https 网址，收到: {}", parsed.scheme())));
+    }
+    let url = parsed.to_string();
+
+    // 文件类型和大小在抓取之后才知道，先按网页记
+    let source = ImportSource::Url(url.clone());
+    start_import(kb_id, url, "html".to_string(), 0, source, duplicate_policy.unwrap_or_default(), app_handle).await
+}
+
+/// 把一段文字（纯文本或 Markdown）作为笔记加入知识库
+///
+/// 没有对应的文件，正文按 Markdown 分块后和其他文档一样生成 embedding；
+/// 标题留空时取正文第一行。适合粘贴片段、会议记录和值得记住的回答。
+#[tauri::command]
+pub async fn add_note(
+    kb_id: String,
+    title: Option<String>,
+    content: String,
+    duplicate_policy: Option<DuplicatePolicy>,
+    app_handle: AppHandle,
+) -> Result<ImportTask, KnowledgeBaseError> {
+    if content.trim().is_empty() {
+        return Err(KnowledgeBaseError::InvalidConfig("笔记内容不能为空".to_string()));
+    }
+    let title = title
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| note_title(&content));
+
+    let file_size = content.len() as i64;
+    let source = ImportSource::Note(content);
+    start_import(kb_id, title, NOTE_FILE_TYPE.to_string(), file_size, source, duplicate_policy.unwrap_or_default(), app_handle).await
+}
+
+/// 笔记按 Markdown 分块
+const NOTE_FILE_TYPE: &str = "md";
+
+/// 笔记标题的最大字符数（取正文第一行时截断）
+const NOTE_TITLE_MAX_CHARS: usize = 40;
+
+/// 没填标题时用正文第一个非空行（去掉 Markdown 标题符号）作标题
+fn note_title(content: &str) -> String {
+    let line = content
+        .lines()
+        .map(|l| l.trim().trim_start_matches('#').trim())
+        .find(|l| !l.is_empty())
+        .unwrap_or("笔记");
+    let mut title: String = line.chars().take(NOTE_TITLE_MAX_CHARS).collect();
+    if line.chars().count() > NOTE_TITLE_MAX_CHARS {
+        title.push('…');
+    }
+    title
+}
+
+/// 导入的来源
+pub(super) enum ImportSource {
+    /// 本地文件路径
+    File(String),
+    /// 网页地址，导入时再抓取
+    Url(String),
+    /// 已经抓取好的网页（爬取整站时用）
+    Page(FetchedPage),
+    /// 直接输入的笔记正文
+    Note(String),
+}
+
+impl ImportSource {
+    /// 记在文档 source 列里的值
+    fn as_str(&self) -> &str {
+        match self {
+            ImportSource::File(path) => path,
+            ImportSource::Url(url) => url,
+            ImportSource::Page(page) => &page.url,
+            // 笔记没有来源，正文保存在 document_contents 里
+            ImportSource::Note(_) => "",
+        }
+    }
+}
+
+/// 写入一条 processing 状态的文档记录，并在后台开始导入
+async fn start_import(
+    kb_id: String,
+    filename: String,
+    file_type: String,
+    file_size: i64,
+    source: ImportSource,
+    policy: DuplicatePolicy,
+    app_handle: AppHandle,
+) -> Result<ImportTask, KnowledgeBaseError> {
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let (kb, task) = create_import_document(kb_id, filename, file_type, file_size, &source, &db_state).await?;
+
+    let job = task.clone();
+    tauri::async_runtime::spawn(async move {
+        match run_cancellable(&job, run_import(&app_handle, &kb, &job, &source, policy)).await {
+            Some(result) => finish_import(&app_handle, &job, result).await,
+            None => finish_cancelled(&app_handle, &job).await,
+        }
+    });
+
+    Ok(task)
+}
+
+/// 写入一条 processing 状态的文档记录，返回知识库配置和对应的导入任务
+pub(super) async fn create_import_document(
+    kb_id: String,
+    filename: String,
+    file_type: String,
+    file_size: i64,
+    source: &ImportSource,
+    db_state: &crate::db::DbState,
+) -> Result<(KnowledgeBase, ImportTask), KnowledgeBaseError> {
+    let source = source.as_str().to_string();
+    let (task_kb_id, task_filename) = (kb_id.clone(), filename.clone());
+    let (kb, doc_id) = with_locked_conn(db_state, move |conn| {
+        let kb = load_knowledge_base(&conn, &kb_id)?;
+        ensure_not_reembedding(&conn, &kb_id)?;
+
+        // 创建文档记录，文件哈希在后台解析时补上
+        let doc_id = Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().timestamp_millis();
+        conn.execute(
+            r#"
+            INSERT INTO documents
+            (id, kb_id, filename, file_type, file_size, file_hash, content_preview,
+             chunk_count, status, import_stage, source, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, '', '', 0, 'processing', ?6, ?7, ?8)
+            "#,
+            rusqlite::params![&doc_id, &kb_id, &filename, &file_type, file_size, ImportStage::Parsing.as_str(), &source, now],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        Ok((kb, doc_id))
+    })
+    .await?;
+
+    let task = ImportTask {
+        task_id: Uuid::new_v4().to_string(),
+        kb_id: task_kb_id,
+        document_id: doc_id,
+        filename: task_filename,
+    };
+
+    Ok((kb, task))
+}
+
+/// 继续导入中途失败的文档
+///
+/// 只有在生成 embedding 及之后失败的文档可以继续（见 mark_document_failed）：
+/// 已经有向量的分块直接跳过，从第一个还没有向量的分块接着做，
+/// 不用重新解析文件、也不会重复请求已经成功的批次。
+#[tauri::command]
+pub async fn resume_import(
+    document_id: String,
+    app_handle: AppHandle,
+    db_state: State<'_, crate::db::DbState>,
+) -> Result<ImportTask, KnowledgeBaseError> {
+    let id = document_id.clone();
+    let (kb, filename) = with_locked_conn(&db_state, move |conn| {
+        let document_id = &id;
+        let (kb_id, filename, status, stage): (String, String, String, Option<String>) = conn.query_row(
+            "SELECT kb_id, filename, status, import_stage FROM documents WHERE id = ?1",
+            [document_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                KnowledgeBaseError::NotFound(format!("Document not found: {}", document_id))
+            }
+            e => KnowledgeBaseError::DatabaseError(e.to_string()),
+        })?;
+
+        let resumable = stage.as_deref().and_then(ImportStage::parse).is_some_and(|s| s.is_resumable());
+        if status != "error" || !resumable {
+            return Err(KnowledgeBaseError::InvalidConfig(format!(
+                "文档 {} 不能继续导入，请删除后重新导入",
+                filename
+            )));
+        }
+
+        let kb = load_knowledge_base(&conn, &kb_id)?;
+        ensure_not_reembedding(&conn, &kb_id)?;
+        conn.execute(
+            "UPDATE documents SET status = 'processing', error_message = NULL WHERE id = ?1",
+            [document_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        Ok((kb, filename))
+    })
+    .await?;
+
+    let task = ImportTask {
+        task_id: Uuid::new_v4().to_string(),
+        kb_id: kb.id.clone(),
+        document_id,
+        filename,
+    };
+
+    let job = task.clone();
+    tauri::async_runtime::spawn(async move {
+        match run_cancellable(&job, embed_and_finish(&app_handle, &kb, &job)).await {
+            Some(result) => finish_import(&app_handle, &job, result).await,
+            None => finish_cancelled(&app_handle, &job).await,
+        }
+    });
+
+    Ok(task)
+}
+
+/// 重新导入文档：从原来的文件路径或网址重新读取，内容有变化时原地替换分块和向量
+///
+/// 文档 id 不变，引用它的地方不受影响。新分块里和旧分块内容完全相同的直接沿用旧向量，
+/// 只为变化的分块请求 embedding；所有新向量拿到之后才在一个事务里替换旧数据，
+/// 中途失败时文档保持原样（只记下错误信息）。
+#[tauri::command]
+pub async fn reimport_document(
+    document_id: String,
+    app_handle: AppHandle,
+    db_state: State<'_, crate::db::DbState>,
+) -> Result<ImportTask, KnowledgeBaseError> {
+    let job = with_locked_conn(&db_state, move |conn| prepare_reimport(&conn, &document_id)).await?;
+
+    let task = job.task.clone();
+    tauri::async_runtime::spawn(async move {
+        match run_cancellable(&job.task, run_reimport(&app_handle, &job, false)).await {
+            Some(result) => finish_reimport(&app_handle, &job.task, result).await,
+            None => finish_cancelled(&app_handle, &job.task).await,
+        }
+    });
+
+    Ok(task)
+}
+
+/// 一个待重新导入的文档
+struct ReimportJob {
+    kb: KnowledgeBase,
+    task: ImportTask,
+    source: ImportSource,
+    old_hash: String,
+}
+
+/// 检查文档能否重新导入，能的话把它标成 processing
+fn prepare_reimport(conn: &rusqlite::Connection, document_id: &str) -> Result<ReimportJob, KnowledgeBaseError> {
+    let (kb_id, filename, status, source, file_hash, duplicate_of): (String, String, String, Option<String>, Option<String>, Option<String>) = conn.query_row(
+        "SELECT kb_id, filename, status, source, file_hash, duplicate_of FROM documents WHERE id = ?1",
+        [document_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)),
+    ).map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => {
+            KnowledgeBaseError::NotFound(format!("Document not found: {}", document_id))
+        }
+        e => KnowledgeBaseError::DatabaseError(e.to_string()),
+    })?;
+
+    if status == "processing" {
+        return Err(KnowledgeBaseError::InvalidConfig(format!("文档 {} 正在导入中", filename)));
+    }
+    if duplicate_of.is_some() {
+        return Err(KnowledgeBaseError::InvalidConfig(format!(
+            "文档 {} 是关联到已有文档的重复记录，请重新导入它关联的文档",
+            filename
+        )));
+    }
+    let source = match source.filter(|s| !s.is_empty()) {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => ImportSource::Url(url),
+        Some(path) if std::path::Path::new(&path).is_file() => ImportSource::File(path),
+        Some(path) => return Err(KnowledgeBaseError::DocumentParseError(format!("原文件不存在: {}", path))),
+        // 没有来源的笔记用保存的正文重新分块
+        None => match stored_content(conn, document_id, kb_cipher(conn, &kb_id)?.as_deref())? {
+            Some(text) => ImportSource::Note(text),
+            None => {
+                return Err(KnowledgeBaseError::InvalidConfig(format!(
+                    "文档 {} 没有记录导入来源，请删除后重新导入",
+                    filename
+                )))
+            }
+        },
+    };
+
+    let kb = load_knowledge_base(conn, &kb_id)?;
+    ensure_not_reembedding(conn, &kb_id)?;
+    // import_stage 记成 reimporting，中断时 rollback_interrupted 据此恢复旧文档而不是删除
+    conn.execute(
+        "UPDATE documents SET status = 'processing', error_message = NULL, import_stage = ?1 WHERE id = ?2",
+        rusqlite::params![ImportStage::Reimporting.as_str(), document_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    Ok(ReimportJob {
+        task: ImportTask {
+            task_id: Uuid::new_v4().to_string(),
+            kb_id: kb.id.clone(),
+            document_id: document_id.to_string(),
+            filename,
+        },
+        kb,
+        source,
+        old_hash: file_hash.unwrap_or_default(),
+    })
+}
+
+/// 重新导入结束时调用：失败时旧的分块和向量都还在，文档恢复为已完成，只记下这次失败的原因
+async fn finish_reimport(app_handle: &AppHandle, task: &ImportTask, result: Result<(), KnowledgeBaseError>) {
+    let Err(e) = result else {
+        return;
+    };
+    let error_msg = e.to_string();
+    log::error!("[KB] Reimport of {} failed: {}", task.filename, error_msg);
+
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let (message, document_id) = (format!("重新导入失败: {}", error_msg), task.document_id.clone());
+    let restored = db_state
+        .run(move |db| {
+            crate::db::open_connection(&db.path).and_then(|conn| {
+                conn.execute(
+                    "UPDATE documents SET status = 'completed', import_stage = NULL, error_message = ?1 WHERE id = ?2",
+                    rusqlite::params![message, &document_id],
+                )
+            })
+        })
+        .await;
+    if let Err(mark_err) = restored {
+        log::warn!("[KB] Failed to restore document {}: {}", task.document_id, mark_err);
+    }
+    emit_import_progress(app_handle, task, ImportStage::Failed, 0, 0, Some(error_msg));
+}
+
+/// 重新导入的后台部分：解析 → 内容没变则直接结束 → 分块 → 只为变化的分块生成 embedding → 事务内替换
+///
+/// force 为 true 时即使内容没变也重新分块（知识库的分块参数改了之后用）。
+async fn run_reimport(app_handle: &AppHandle, job: &ReimportJob, force: bool) -> Result<(), KnowledgeBaseError> {
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let ReimportJob { kb, task, source, old_hash } = job;
+    let doc_id = &task.document_id;
+
+    let parse_slot = acquire_slot(ImportPool::Parse, &task.task_id).await?;
+    emit_import_progress(app_handle, task, ImportStage::Parsing, 0, 0, None);
+    let (file_hash, document, file_type) = load_source(source).await?;
+    let content = document.text.as_str();
+
+    if !force && &file_hash == old_hash {
+        let document_id = doc_id.clone();
+        with_locked_conn(&db_state, move |conn| {
+            conn.execute(
+                "UPDATE documents SET status = 'completed', import_stage = NULL, error_message = NULL WHERE id = ?1",
+                [&document_id],
+            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            Ok(())
+        })
+        .await?;
+
+        emit_import_progress(app_handle, task, ImportStage::Completed, 0, 0, None);
+        log::info!("[KB] Reimport of {} skipped: content unchanged", task.filename);
+        return Ok(());
+    }
+
+    let (chunks, parents) = split_for_kb(content, &file_type, kb);
+    let locations = locate_chunks(&document, &chunks);
+    emit_import_progress(app_handle, task, ImportStage::Chunking, 0, chunks.len(), None);
+
+    // 旧分块的内容 -> 向量，内容没变的分块直接沿用
+    let document_id = doc_id.clone();
+    let cipher = cipher_for(kb)?;
+    let mut old_vectors: HashMap<String, Vec<u8>> = with_locked_conn(&db_state, move |conn| {
+        let mut stmt = conn.prepare(
+            "SELECT c.content, v.vector FROM chunks c JOIN vectors v ON v.chunk_id = c.id WHERE c.document_id = ?1"
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        // 加密知识库的分块内容是密文，解密后再和新分块比较
+        let old_vectors = stmt.query_map([&document_id], |row| Ok((open_text(cipher.as_deref(), row.get(0)?), row.get(1)?)))
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(old_vectors)
+    })
+    .await?;
+
+    let mut vectors: Vec<Option<Vec<u8>>> = chunks.iter().map(|c| old_vectors.remove(&c.content)).collect();
+    let changed: Vec<usize> = (0..chunks.len()).filter(|&i| vectors[i].is_none()).collect();
+    log::info!(
+        "[KB] Reimporting {}: {} of {} chunks changed",
+        task.filename,
+        changed.len(),
+        chunks.len()
+    );
+
+    drop(parse_slot);
+    // 只有需要生成新向量时才排 embedding 的队
+    let _embed_slot = match changed.is_empty() {
+        true => None,
+        false => Some(acquire_slot(ImportPool::Embed, &task.task_id).await?),
+    };
+    emit_import_progress(app_handle, task, ImportStage::Embedding, 0, changed.len(), None);
+    if !changed.is_empty() {
+        let target = resolve_embedding(&app_handle.state::<KbState>().db_path, kb)?;
+
+        // 新向量先攒在内存里，最后和新分块在同一个事务里写入
+        let (total, mut done) = (changed.len(), 0);
+        embed_in_batches(&changed, |&idx| chunks[idx].content.clone(), &target, |batch, embeddings| {
+            for (&idx, embedding) in batch.iter().zip(embeddings) {
+                vectors[idx] = Some(vector_to_bytes(&embedding));
+            }
+            done += batch.len();
+            emit_import_progress(app_handle, task, ImportStage::Embedding, done, total, None);
+            std::future::ready(Ok(true))
+        })
+        .await?;
+    }
+
+    // ===== 在一个事务里用新的分块和向量替换旧数据 =====
+    emit_import_progress(app_handle, task, ImportStage::Inserting, chunks.len(), chunks.len(), None);
+    let file_size = content_size(source, content).await;
+    let chunk_count = chunks.len();
+    let (kb_id, quantization, document_id) = (kb.id.clone(), kb.vector_quantization, doc_id.clone());
+    with_locked_conn(&db_state, move |mut conn| {
+        let (doc_id, content) = (&document_id, document.text.as_str());
+        let tx = conn.transaction().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        tx.execute("DELETE FROM vectors WHERE document_id = ?1", [doc_id])
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        tx.execute(
+            "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE document_id = ?1)",
+            [doc_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        tx.execute("DELETE FROM chunks WHERE document_id = ?1", [doc_id])
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        tx.execute("DELETE FROM parent_chunks WHERE document_id = ?1", [doc_id])
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let parent_ids = insert_parent_chunks(&tx, doc_id, &kb_id, &parents)?;
+
+        let chunk_ids = insert_chunks(&tx, doc_id, &kb_id, &chunks, &locations, &parent_ids)?;
+        let cipher = kb_cipher(&tx, &kb_id)?;
+
+        // 沿用的旧向量可能是切换量化方式之前写入的，统一按当前方式重新编码
+        let encoded: Vec<_> = chunk_ids
+            .iter()
+            .zip(vectors)
+            .filter_map(|(chunk_id, vector)| {
+                vector.map(|v| (chunk_id, encode_for_kb(&bytes_to_vector(&v), quantization, cipher.as_deref())))
+            })
+            .collect();
+        let rows: Vec<Vec<&dyn rusqlite::ToSql>> = encoded
+            .iter()
+            .map(|(chunk_id, encoded)| vec![*chunk_id as &dyn rusqlite::ToSql, &doc_id, &kb_id, &encoded.vector, &encoded.code])
+            .collect();
+        insert_rows(&tx, "INSERT INTO vectors (chunk_id, document_id, kb_id, vector, code)", "(?, ?, ?, ?, ?)", &rows)
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        // 整篇替换会在变更日志里留下一删一增，知识库小到不用索引时清掉
+        prune_vector_log(&tx, &kb_id).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let now = chrono::Utc::now().timestamp_millis();
+
+        tx.execute(
+            "INSERT OR REPLACE INTO document_contents (document_id, content) VALUES (?1, ?2)",
+            rusqlite::params![doc_id, seal_text(cipher.as_deref(), content)],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let preview = seal_text(cipher.as_deref(), &content.chars().take(500).collect::<String>());
+        tx.execute(
+            "UPDATE documents SET file_hash = ?1, content_preview = ?2, file_size = ?3, chunk_count = ?4,
+             status = 'completed', error_message = NULL, import_stage = NULL WHERE id = ?5",
+            rusqlite::params![&file_hash, &preview, file_size, chunk_count as i32, doc_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        tx.execute(
+            "UPDATE knowledge_bases SET updated_at = ?1 WHERE id = ?2",
+            rusqlite::params![now, &kb_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        tx.commit().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(())
+    })
+    .await?;
+    app_handle.state::<KbState>().vector_store.checkpoint_ann(&kb.id).await?;
+
+    emit_import_progress(app_handle, task, ImportStage::Completed, chunk_count, chunk_count, None);
+    log::info!("Reimported document {} with {} chunks", task.filename, chunk_count);
+    spawn_summary(app_handle, kb, doc_id);
+    Ok(())
+}
+
+/// 文档大小：本地文件取文件大小，网页取正文长度
+async fn content_size(source: &ImportSource, content: &str) -> i64 {
+    match source {
+        ImportSource::File(path) => tokio::fs::metadata(path).await.map(|m| m.len() as i64).unwrap_or(0),
+        _ => content.len() as i64,
+    }
+}
+
+/// 后台任务结束时调用：失败则标记文档并上报 failed 阶段
+pub(super) async fn finish_import(app_handle: &AppHandle, task: &ImportTask, result: Result<(), KnowledgeBaseError>) {
+    let Err(e) = result else {
+        return;
+    };
+    let error_msg = e.to_string();
+    let db_state = app_handle.state::<crate::db::DbState>();
+    if let Err(mark_err) = mark_document_failed(&db_state, &task.document_id, &error_msg).await {
+        log::warn!("[KB] Failed to mark document {} as failed: {}", task.document_id, mark_err);
+    }
+    emit_import_progress(app_handle, task, ImportStage::Failed, 0, 0, Some(error_msg));
+}
+
+/// 导入被 cancel_import 取消后调用：按文档所处阶段回滚并上报 cancelled 阶段
+pub(super) async fn finish_cancelled(app_handle: &AppHandle, task: &ImportTask) {
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let document_id = task.document_id.clone();
+    let outcome = with_locked_conn(&db_state, move |conn| {
+        rollback_interrupted(&conn, &document_id, CANCELLED_NOTICE, true)
+    })
+    .await;
+    match outcome {
+        Ok(outcome) => log::info!("[KB] Import of {} cancelled: {:?}", task.filename, outcome),
+        Err(e) => log::warn!("[KB] Failed to roll back cancelled import of {}: {}", task.filename, e),
+    }
+    emit_import_progress(app_handle, task, ImportStage::Cancelled, 0, 0, Some(CANCELLED_NOTICE.to_string()));
+}
+
+/// 上报导入进度
+fn emit_import_progress(
+    app_handle: &AppHandle,
+    task: &ImportTask,
+    stage: ImportStage,
+    current: usize,
+    total: usize,
+    error: Option<String>,
+) {
+    let event = ImportProgressEvent {
+        task_id: task.task_id.clone(),
+        kb_id: task.kb_id.clone(),
+        document_id: task.document_id.clone(),
+        filename: task.filename.clone(),
+        stage,
+        current,
+        total,
+        error,
+    };
+    if let Err(e) = app_handle.emit("kb-import-progress", event) {
+        log::warn!("[KB] Failed to emit import progress: {}", e);
+    }
+}
+
+/// 更新文档记录的 import_stage
+async fn set_import_stage(
+    db_state: &State<'_, crate::db::DbState>,
+    doc_id: &str,
+    stage: ImportStage,
+) -> Result<(), KnowledgeBaseError> {
+    let doc_id = doc_id.to_string();
+    with_locked_conn(db_state, move |conn| {
+        conn.execute(
+            "UPDATE documents SET import_stage = ?1 WHERE id = ?2",
+            rusqlite::params![stage.as_str(), doc_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(())
+    })
+    .await
+}
+
+/// 后台导入：解析 → 查重 → 分块（写入 chunks + FTS5）→ 生成 embedding、写入向量 → 更新文档状态
+///
+/// rusqlite::Connection 不是 Send 的，不能跨越 .await 持有它，因此每个阶段各自打开连接。
+pub(super) async fn run_import(
+    app_handle: &AppHandle,
+    kb: &KnowledgeBase,
+    task: &ImportTask,
+    source: &ImportSource,
+    policy: DuplicatePolicy,
+) -> Result<(), KnowledgeBaseError> {
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let doc_id = &task.document_id;
+
+    // ===== 解析 =====
+    // 解析名额在分块写完后释放，embedding 另外排队
+    let parse_slot = acquire_slot(ImportPool::Parse, &task.task_id).await?;
+    emit_import_progress(app_handle, task, ImportStage::Parsing, 0, 0, None);
+    let (file_hash, document, file_type) = load_source(source).await?;
+    let content = document.text.as_str();
+    let cipher = cipher_for(kb)?;
+    let preview = seal_text(cipher.as_deref(), &content.chars().take(500).collect::<String>());
+
+    // ===== 查重：同一知识库里已有相同哈希的文档时按 policy 处理 =====
+    if let Some(existing) = find_duplicate(&db_state, &kb.id, &file_hash, doc_id).await? {
+        match policy {
+            DuplicatePolicy::Skip => {
+                let document_id = doc_id.clone();
+                with_locked_conn(&db_state, move |conn| {
+                    conn.execute("DELETE FROM documents WHERE id = ?1", [&document_id])
+                        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                    Ok(())
+                })
+                .await?;
+
+                let reason = format!("内容与已有文档 {} 相同，已跳过", existing.filename);
+                emit_import_progress(app_handle, task, ImportStage::Skipped, 0, 0, Some(reason));
+                log::info!("[KB] Skipped duplicate document {} (same as {})", task.filename, existing.id);
+                return Ok(());
+            }
+            DuplicatePolicy::Link => {
+                let (document_id, kb_id, existing_id) = (doc_id.clone(), kb.id.clone(), existing.id.clone());
+                with_locked_conn(&db_state, move |conn| {
+                    conn.execute(
+                        "UPDATE documents SET file_hash = ?1, content_preview = ?2, duplicate_of = ?3, chunk_count = 0,
+                         status = 'completed', error_message = NULL, import_stage = NULL WHERE id = ?4",
+                        rusqlite::params![&file_hash, &preview, &existing_id, &document_id],
+                    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                    conn.execute(
+                        "UPDATE knowledge_bases SET document_count = document_count + 1, updated_at = ?1 WHERE id = ?2",
+                        rusqlite::params![chrono::Utc::now().timestamp_millis(), &kb_id],
+                    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                    Ok(())
+                })
+                .await?;
+
+                emit_import_progress(app_handle, task, ImportStage::Completed, 0, 0, None);
+                log::info!("[KB] Linked duplicate document {} to {}", task.filename, existing.id);
+                return Ok(());
+            }
+            DuplicatePolicy::Replace => {
+                let kb_state = app_handle.state::<KbState>();
+                kb_state.vector_store.delete_document_vectors(&kb.id, &existing.id).await?;
+
+                let (document_id, kb_id, existing_id) = (doc_id.clone(), kb.id.clone(), existing.id.clone());
+                with_locked_conn(&db_state, move |conn| {
+                    delete_document_rows(&conn, &existing_id)?;
+                    // 关联到旧文档的记录改为关联到新文档
+                    conn.execute(
+                        "UPDATE documents SET duplicate_of = ?1 WHERE duplicate_of = ?2",
+                        rusqlite::params![&document_id, &existing_id],
+                    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                    conn.execute(
+                        "UPDATE knowledge_bases SET document_count = MAX(document_count - 1, 0) WHERE id = ?1",
+                        [&kb_id],
+                    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                    Ok(())
+                })
+                .await?;
+                log::info!("[KB] Replacing document {} with {}", existing.id, task.filename);
+            }
+        }
+    }
+
+    // ===== 分块：把 chunk 写入 SQLite 和 FTS5 =====
+    // Markdown 沿标题层级分块，其余格式按段落/句子递归切分
+    let (chunks, parents) = split_for_kb(content, &file_type, kb);
+    let locations = locate_chunks(&document, &chunks);
+    emit_import_progress(app_handle, task, ImportStage::Chunking, 0, chunks.len(), None);
+    let is_file = matches!(source, ImportSource::File(_));
+    let (kb_id, document_id) = (kb.id.clone(), doc_id.clone());
+    with_locked_conn(&db_state, move |mut conn| {
+        let (doc_id, content) = (&document_id, document.text.as_str());
+        // 全文、父块、分块和全文索引在一个事务里写入，中途出错时整体回滚，不留下半截分块
+        let tx = conn.transaction().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        tx.execute(
+            "UPDATE documents SET file_hash = ?1, content_preview = ?2, import_stage = ?3 WHERE id = ?4",
+            rusqlite::params![&file_hash, &preview, ImportStage::Chunking.as_str(), doc_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if !is_file {
+            // 网页的类型和大小抓取之后才知道
+            tx.execute(
+                "UPDATE documents SET file_type = ?1, file_size = ?2 WHERE id = ?3",
+                rusqlite::params![&file_type, content.len() as i64, doc_id],
+            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO document_contents (document_id, content) VALUES (?1, ?2)",
+            rusqlite::params![doc_id, seal_text(cipher.as_deref(), content)],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let parent_ids = insert_parent_chunks(&tx, doc_id, &kb_id, &parents)?;
+        insert_chunks(&tx, doc_id, &kb_id, &chunks, &locations, &parent_ids)?;
+        tx.commit().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(())
+    })
+    .await?;
+    drop(parse_slot);
+
+    embed_and_finish(app_handle, kb, task).await
+}
+
+/// 读取并解析导入来源，返回 (哈希, 正文及分页, 分块时按哪种格式处理)
+async fn load_source(source: &ImportSource) -> Result<(String, ParsedDocument, String), KnowledgeBaseError> {
+    match source {
+        ImportSource::File(file_path) => {
+            let file_type = std::path::Path::new(file_path)
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("txt")
+                .to_string();
+            Ok((calculate_file_hash(file_path).await?, parse_document_pages(file_path).await?, file_type))
+        }
+        ImportSource::Url(url) => {
+            let page = fetch_page_markdown(url)
+                .await
+                .map_err(KnowledgeBaseError::DocumentParseError)?;
+            page_content(&page)
+        }
+        ImportSource::Page(page) => page_content(page),
+        ImportSource::Note(text) => Ok((
+            calculate_text_hash(text),
+            ParsedDocument { text: text.clone(), pages: Vec::new() },
+            NOTE_FILE_TYPE.to_string(),
+        )),
+    }
+}
+
+/// 按知识库的设置分块，返回 (用来检索的块, 父块)；没开启父子分块时父块为空
+fn split_for_kb(content: &str, file_type: &str, kb: &KnowledgeBase) -> (Vec<TextChunk>, Vec<TextChunk>) {
+    let options = SplitOptions::for_kb(kb);
+    if kb.parent_chunk_size > kb.chunk_size {
+        split_parent_child(content, file_type, &options, kb.parent_chunk_size as usize)
+    } else {
+        (split_document(content, file_type, &options), Vec::new())
+    }
+}
+
+/// 写入文档的父块，返回和 parents 下标一一对应的父块 id
+fn insert_parent_chunks(
+    conn: &rusqlite::Connection,
+    doc_id: &str,
+    kb_id: &str,
+    parents: &[TextChunk],
+) -> Result<Vec<String>, KnowledgeBaseError> {
+    let cipher = kb_cipher(conn, kb_id)?;
+    let mut stmt = conn
+        .prepare("INSERT INTO parent_chunks (id, document_id, kb_id, content, heading_path) VALUES (?1, ?2, ?3, ?4, ?5)")
         .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    parents
+        .iter()
+        .map(|parent| {
+            let id = Uuid::new_v4().to_string();
+            let content = seal_text(cipher.as_deref(), &parent.content);
+            stmt.execute(rusqlite::params![&id, doc_id, kb_id, &content, &parent.heading_path])
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            Ok(id)
+        })
+        .collect()
+}
+
+/// 在调用方的事务里用多行 INSERT 写入文档的分块和对应的全文索引，返回按顺序生成的分块 id
+fn insert_chunks(
+    conn: &rusqlite::Connection,
+    doc_id: &str,
+    kb_id: &str,
+    chunks: &[TextChunk],
+    locations: &[ChunkLocation],
+    parent_ids: &[String],
+) -> Result<Vec<String>, KnowledgeBaseError> {
+    let cipher = kb_cipher(conn, kb_id)?;
+    let now = chrono::Utc::now().timestamp_millis();
+    let ids: Vec<String> = chunks.iter().map(|_| Uuid::new_v4().to_string()).collect();
+    let contents: Vec<String> = chunks.iter().map(|c| seal_text(cipher.as_deref(), &c.content)).collect();
+    let indexes: Vec<i32> = (0..chunks.len() as i32).collect();
+    let tokens: Vec<i32> = chunks.iter().map(|c| estimate_tokens(&c.content)).collect();
+    let parents: Vec<Option<&String>> = chunks.iter().map(|c| c.parent.map(|p| &parent_ids[p])).collect();
+    let rows: Vec<Vec<&dyn rusqlite::ToSql>> = chunks
+        .iter()
+        .zip(locations)
+        .enumerate()
+        .map(|(i, (chunk, location))| {
+            vec![
+                &ids[i] as &dyn rusqlite::ToSql, &doc_id, &kb_id, &contents[i], &indexes[i], &tokens[i],
+                &chunk.heading_path, &parents[i],
+                &location.page, &location.start_offset, &location.end_offset, &now,
+            ]
+        })
+        .collect();
+    insert_rows(
+        conn,
+        "INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, token_count, heading_path, parent_chunk_id,
+                             page, start_offset, end_offset, created_at)",
+        "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        &rows,
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    // 加密知识库不建全文索引，索引里的分词就是明文
+    if cipher.is_some() {
+        return Ok(ids);
+    }
+
+    // 写入 FTS5 —— 出错时记日志而不是直接忽略（FTS5 不可用时检索会退回 LIKE）
+    let segmented: Vec<String> = chunks.iter().map(|c| segment_for_index(&c.content)).collect();
+    let fts_rows: Vec<Vec<&dyn rusqlite::ToSql>> = ids
+        .iter()
+        .zip(&segmented)
+        .map(|(id, text)| vec![id as &dyn rusqlite::ToSql, &kb_id, text])
+        .collect();
+    if let Err(e) = insert_rows(
+        conn,
+        "INSERT INTO chunks_fts (rowid, kb_id, content)",
+        "((SELECT rowid FROM chunks WHERE id = ?), ?, ?)",
+        &fts_rows,
+    ) {
+        log::warn!("[KB] FTS5 insert failed for document {}: {}", doc_id, e);
+    }
+    Ok(ids)
+}
+
+/// 和正在导入的文档内容相同的已有文档
+struct ExistingDocument {
+    id: String,
+    filename: String,
+}
+
+/// 在知识库里找哈希相同的已完成文档（本身是关联记录的不算）
+async fn find_duplicate(
+    db_state: &State<'_, crate::db::DbState>,
+    kb_id: &str,
+    file_hash: &str,
+    doc_id: &str,
+) -> Result<Option<ExistingDocument>, KnowledgeBaseError> {
+    let params = (kb_id.to_string(), file_hash.to_string(), doc_id.to_string());
+    with_locked_conn(db_state, move |conn| {
+        conn.query_row(
+            "SELECT id, filename FROM documents
+             WHERE kb_id = ?1 AND file_hash = ?2 AND id != ?3 AND status = 'completed' AND duplicate_of IS NULL
+               AND deleted_at IS NULL
+             ORDER BY created_at ASC LIMIT 1",
+            params,
+            |row| Ok(ExistingDocument { id: row.get(0)?, filename: row.get(1)? }),
+        )
+        .optional()
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+    })
+    .await
+}
+
+/// 删除文档的 FTS5 条目和文档记录（级联删除 chunks），向量需要另外用 vector_store 删除
+pub(super) fn delete_document_rows(conn: &rusqlite::Connection, doc_id: &str) -> Result<(), KnowledgeBaseError> {
+    // 从 FTS5 中删除（必须在删除 chunks 之前进行，因为需要用到 rowid）
+    if let Err(e) = conn.execute(
+        "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE document_id = ?1)",
+        rusqlite::params![doc_id],
+    ) {
+        log::warn!("[KB] FTS5 cleanup failed for document {}: {}", doc_id, e);
+    }
+
+    conn.execute(
+        "DELETE FROM document_contents WHERE document_id = ?1",
+        rusqlite::params![doc_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM parent_chunks WHERE document_id = ?1",
+        rusqlite::params![doc_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    conn.execute(
+        "DELETE FROM document_summaries WHERE document_id = ?1",
+        rusqlite::params![doc_id],
+    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
+    // 从 SQLite 中删除（级联删除会自动清掉 chunks）
     conn.execute(
-        "UPDATE documents SET status = 'error', error_message = ?1 WHERE id = ?2",
-        rusqlite::params![error_msg, doc_id],
+        "DELETE FROM documents WHERE id = ?1",
+        rusqlite::params![doc_id],
     ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
 
-    // 必须在删除 chunks 之前先清理 FTS5 条目（需要用到 chunks 里的 rowid）
-    if let Err(cleanup_err) = conn.execute(
-        "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE document_id = ?1)",
-        rusqlite::params![doc_id],
-    ) {
-        log::warn!("[KB] Failed to clean up FTS5 entries: {}", cleanup_err);
+/// 网页的哈希、正文和按哪种格式分块
+fn page_content(page: &FetchedPage) -> Result<(String, ParsedDocument, String), KnowledgeBaseError> {
+    if page.content.trim().is_empty() {
+        return Err(KnowledgeBaseError::DocumentParseError(format!("网页中没有可用的文本: {}", page.url)));
     }
+    // 网页正文已经是 Markdown，按 html 处理会沿标题层级分块；其他文本类型按纯文本分块
+    let file_type = if page.is_html { "html" } else { "txt" }.to_string();
+    let document = ParsedDocument { text: page.content.clone(), pages: Vec::new() };
+    Ok((calculate_text_hash(&page.content), document, file_type))
+}
 
-    // 清理孤儿 chunks（在 FTS5 清理之后进行）
-    if let Err(cleanup_err) = conn.execute(
-        "DELETE FROM chunks WHERE document_id = ?1",
-        rusqlite::params![doc_id],
-    ) {
-        log::warn!("[KB] Failed to clean up orphan chunks: {}", cleanup_err);
+/// 知识库使用的 embedding (provider, model, base_url)
+///
+/// 使用知识库自身保存的 embedding provider/model/base_url
+/// （这些字段在创建知识库时，根据所选的 Embedding API 配置写入）。
+/// 仅对创建于该字段引入之前的旧知识库，才回退到 OpenAI 默认值。
+pub(super) fn embedding_target(kb: &KnowledgeBase) -> (String, String, String) {
+    if !kb.embedding_provider.is_empty() && !kb.embedding_model.is_empty() {
+        (kb.embedding_provider.clone(), kb.embedding_model.clone(), kb.embedding_base_url.clone())
+    } else {
+        ("openai".to_string(), "text-embedding-3-small".to_string(), String::new())
     }
-
-    Ok(())
 }
 
-/// 向知识库导入文档
+/// 为文档里还没有向量的分块生成 embedding 并写入向量，全部完成后更新文档状态
 ///
 /// # 对应 #33、#34 的修复：
-/// - 阶段一（持有 DB 锁）：读取知识库配置、创建文档记录、解析文件、写入 chunks + FTS
-/// - 阶段二（释放 DB 锁）：通过网络请求生成 embedding（不持锁）
-/// - 阶段三（重新获取 DB 锁）：写入向量、更新文档状态
-/// - 如果阶段二失败，阶段三会把文档标记为 "error" 并清理孤儿 chunks
+/// - 生成 embedding 的网络请求期间不持有 DB 锁
 ///
-/// # 对应 #32 的修复：
-/// - API Key 改为通过 embedding_api_config_id 从安全存储（keyring）中读取
-/// - 前端不再传递 api_key 参数
-#[tauri::command]
-pub async fn import_document(
-    kb_id: String,
-    file_path: String,
-    db_state: State<'_, crate::db::DbState>,
-    kb_state: State<'_, KbState>,
-) -> Result<Document, KnowledgeBaseError> {
-    // ===== 阶段一：数据库操作（持有锁） =====
-    let (doc_id, kb, file_name, file_type, file_size, file_hash, preview, chunks) = {
-        let db = db_state.0.lock().await;
-        let conn = rusqlite::Connection::open(&db.path)
+/// 每一批的向量一拿到就写入，中途失败时已经成功的批次不会丢，resume_import 可以接着做。
+async fn embed_and_finish(
+    app_handle: &AppHandle,
+    kb: &KnowledgeBase,
+    task: &ImportTask,
+) -> Result<(), KnowledgeBaseError> {
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let kb_state = app_handle.state::<KbState>();
+    let doc_id = &task.document_id;
+
+    let _embed_slot = acquire_slot(ImportPool::Embed, &task.task_id).await?;
+    set_import_stage(&db_state, doc_id, ImportStage::Embedding).await?;
+
+    // 查出总分块数和还没有向量的分块（同步，不涉及 await）
+    let document_id = doc_id.clone();
+    let cipher = cipher_for(kb)?;
+    let (pending, total): (Vec<(String, String)>, usize) = with_locked_conn(&db_state, move |conn| {
+        let doc_id = &document_id;
+        let total: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM chunks WHERE document_id = ?1",
+            [doc_id],
+            |row| row.get(0),
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.content FROM chunks c
+             LEFT JOIN vectors v ON v.chunk_id = c.id
+             WHERE c.document_id = ?1 AND v.chunk_id IS NULL
+             ORDER BY c.chunk_index ASC"
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let pending = stmt.query_map([doc_id], |row| Ok((row.get(0)?, open_text(cipher.as_deref(), row.get(1)?))))
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-        // 获取知识库配置
-        let kb: KnowledgeBase = conn.query_row(
-            "SELECT id, name, description, embedding_api_config_id,
-             chunk_size, chunk_overlap, created_at, updated_at, document_count,
-             COALESCE(embedding_provider, ''), COALESCE(embedding_model, ''), COALESCE(embedding_base_url, '')
-             FROM knowledge_bases WHERE id = ?1",
-            [&kb_id],
-            |row| {
-                Ok(KnowledgeBase {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    description: row.get(2)?,
-                    embedding_api_config_id: row.get(3)?,
-                    chunk_size: row.get(4)?,
-                    chunk_overlap: row.get(5)?,
-                    created_at: row.get(6)?,
-                    updated_at: row.get(7)?,
-                    document_count: row.get(8)?,
-                    embedding_provider: row.get(9)?,
-                    embedding_model: row.get(10)?,
-                    embedding_base_url: row.get(11)?,
+        Ok((pending, total as usize))
+    })
+    .await?;
+
+    let mut done = total - pending.len();
+    emit_import_progress(app_handle, task, ImportStage::Embedding, done, total, None);
+
+    if !pending.is_empty() {
+        // 从安全存储中读取 API Key，而不再由前端传入（#32）
+        let target = resolve_embedding(&app_handle.state::<KbState>().db_path, kb)?;
+
+        let vector_store = &kb_state.vector_store;
+        embed_in_batches(&pending, |(_, content): &(String, String)| content.clone(), &target, |batch, embeddings| {
+            // 每批向量直接写库作为断点（异步，不持有 DB 锁）
+            let vectors: Vec<_> = batch.iter()
+                .zip(embeddings)
+                .map(|((chunk_id, content), embedding)| {
+                    (chunk_id.clone(), doc_id.clone(), content.clone(), embedding)
                 })
+                .collect();
+            done += batch.len();
+            let done = done;
+            async move {
+                vector_store.insert_vectors(&kb.id, vectors).await?;
+                emit_import_progress(app_handle, task, ImportStage::Embedding, done, total, None);
+                Ok(true)
             }
-        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        })
+        .await?;
+    }
 
-        // 创建文档记录
-        let doc_id = Uuid::new_v4().to_string();
+    // ===== 更新文档状态（重新获取 DB 锁） =====
+    set_import_stage(&db_state, doc_id, ImportStage::Inserting).await?;
+    emit_import_progress(app_handle, task, ImportStage::Inserting, total, total, None);
+    let (document_id, kb_id) = (doc_id.clone(), kb.id.clone());
+    with_locked_conn(&db_state, move |conn| {
         let now = chrono::Utc::now().timestamp_millis();
-        let file_hash = calculate_file_hash(&file_path).await?;
-        let file_name = std::path::Path::new(&file_path)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("unknown")
-            .to_string();
-        let file_type = std::path::Path::new(&file_path)
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("txt")
-            .to_lowercase();
-
-        // 获取文件大小
-        let file_size = match tokio::fs::metadata(&file_path).await {
-            Ok(m) => m.len() as i64,
-            Err(e) => {
-                log::warn!("Failed to read file metadata for {}: {}", file_path, e);
-                0
-            }
-        };
-
         conn.execute(
-            r#"
-            INSERT INTO documents
-            (id, kb_id, filename, file_type, file_size, file_hash, content_preview,
-             chunk_count, status, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, '', 0, 'processing', ?7)
-            "#,
-            rusqlite::params![&doc_id, &kb_id, &file_name, &file_type, file_size, &file_hash, now],
+            "UPDATE documents SET status = 'completed', chunk_count = ?1, error_message = NULL, import_stage = NULL WHERE id = ?2",
+            rusqlite::params![total as i32, &document_id],
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-        // 解析文档
-        let content = match parse_document(&file_path).await {
-            Ok(c) => c,
-            Err(e) => {
-                conn.execute(
-                    "UPDATE documents SET status = 'error', error_message = ?1 WHERE id = ?2",
-                    rusqlite::params![&e.to_string(), &doc_id],
-                ).map_err(|e2| KnowledgeBaseError::DatabaseError(e2.to_string()))?;
-                return Err(e);
-            }
-        };
-
-        // 存储预览内容
-        let preview: String = content.chars().take(500).collect();
         conn.execute(
-            "UPDATE documents SET content_preview = ?1 WHERE id = ?2",
-            rusqlite::params![&preview, &doc_id],
+            "UPDATE knowledge_bases SET document_count = document_count + 1, updated_at = ?1 WHERE id = ?2",
+            rusqlite::params![now, &kb_id],
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(())
+    })
+    .await?;
 
-        // 切分为多个 chunk
-        let chunks = split_text(&content, kb.chunk_size as usize, kb.chunk_overlap as usize);
+    emit_import_progress(app_handle, task, ImportStage::Completed, total, total, None);
+    log::info!("Imported document {} with {} chunks", task.filename, total);
+    spawn_summary(app_handle, kb, doc_id);
 
-        // 把 chunk 写入 SQLite 和 FTS5
-        let mut all_chunk_ids = Vec::new();
-        for (i, chunk_text) in chunks.iter().enumerate() {
-            let chunk_id = Uuid::new_v4().to_string();
-            let tokens = estimate_tokens(chunk_text);
+    Ok(())
+}
 
-            conn.execute(
-                r#"
-                INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, token_count, created_at)
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-                "#,
-                rusqlite::params![&chunk_id, &doc_id, &kb_id, chunk_text, i as i32, tokens, now],
-            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+/// documents 表里组成 Document 的列，顺序和 document_from_row 一致
+pub(super) const DOCUMENT_COLUMNS: &str = "id, kb_id, filename, file_type, file_size, file_hash, content_preview,
+         chunk_count, status, error_message, created_at, import_stage, source, duplicate_of, tags, deleted_at";
+
+/// cipher 是文档所属知识库的加密器，用来解密内容预览
+pub(super) fn document_from_row(row: &rusqlite::Row, cipher: Option<&KbCipher>) -> rusqlite::Result<Document> {
+    let status_str: String = row.get(8)?;
+    let status = match status_str.as_str() {
+        "completed" => DocumentStatus::Completed,
+        "error" => DocumentStatus::Error,
+        _ => DocumentStatus::Processing,
+    };
 
-            // 写入 FTS5 —— 出错时记日志而不是直接忽略
-            if let Err(e) = conn.execute(
-                "INSERT INTO chunks_fts (rowid, kb_id, content) VALUES (last_insert_rowid(), ?1, ?2)",
-                rusqlite::params![&kb_id, chunk_text],
-            ) {
-                log::warn!("[KB] FTS5 insert failed for chunk {}: {}", chunk_id, e);
-            }
+    Ok(Document {
+        id: row.get(0)?,
+        kb_id: row.get(1)?,
+        filename: row.get(2)?,
+        file_type: row.get(3)?,
+        file_size: row.get(4)?,
+        file_hash: row.get(5)?,
+        content_preview: open_text(cipher, row.get(6)?),
+        chunk_count: row.get(7)?,
+        status,
+        error_message: row.get(9)?,
+        import_stage: row.get::<_, Option<String>>(11)?.as_deref().and_then(ImportStage::parse),
+        source: row.get(12)?,
+        duplicate_of: row.get(13)?,
+        tags: Document::tags_from_column(row.get(14)?),
+        created_at: row.get(10)?,
+        deleted_at: row.get(15)?,
+    })
+}
 
-            all_chunk_ids.push(chunk_id);
+/// 列出知识库中的文档
+#[tauri::command]
+pub async fn list_documents(
+    kb_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<Vec<Document>, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        let cipher = kb_cipher(&conn, &kb_id)?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM documents WHERE kb_id = ?1 AND deleted_at IS NULL ORDER BY created_at DESC",
+            DOCUMENT_COLUMNS
+        )).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let rows = stmt.query_map([&kb_id], |row| document_from_row(row, cipher.as_deref()))
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let mut docs = Vec::new();
+        for row in rows {
+            docs.push(row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?);
         }
 
-        (doc_id, kb, file_name, file_type, file_size, file_hash, preview, chunks)
-    };
-    // ===== 阶段一结束：释放 DB 锁 =====
+        Ok(docs)
+    })
+    .await
+}
 
-    // ===== 阶段二：网络请求（不持有 DB 锁） =====
-    // 从安全存储中读取 API Key，而不再由前端传入（#32）
-    let api_key = match get_embedding_api_key(&kb.embedding_api_config_id) {
-        Ok(key) => key,
-        Err(e) => {
-            let error_msg = format!("Embedding API key lookup failed: {}", e);
-            mark_document_failed(&db_state, &doc_id, &error_msg).await?;
-            return Err(e);
+/// 按分块顺序分页列出文档的分块，用来查看文档实际被切成了什么样
+#[tauri::command]
+pub async fn list_chunks(
+    kb_id: String,
+    doc_id: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    kb_state: State<'_, KbState>,
+) -> Result<ChunkPage, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        let doc_exists: bool = conn.query_row(
+            "SELECT COUNT(*) FROM documents WHERE id = ?1 AND kb_id = ?2",
+            rusqlite::params![&doc_id, &kb_id],
+            |row| row.get(0),
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if !doc_exists {
+            return Err(KnowledgeBaseError::NotFound(
+                format!("Document not found: {} in knowledge base: {}", doc_id, kb_id)
+            ));
         }
-    };
 
-    // 使用知识库自身保存的 embedding provider/model/base_url
-    // （这些字段在创建知识库时，根据所选的 Embedding API 配置写入）。
-    // 仅对创建于该字段引入之前的旧知识库，才回退到 OpenAI 默认值。
-    let (embedding_provider, embedding_model, embedding_base_url) =
-        if !kb.embedding_provider.is_empty() && !kb.embedding_model.is_empty() {
-            (kb.embedding_provider.clone(), kb.embedding_model.clone(), kb.embedding_base_url.clone())
-        } else {
-            ("openai".to_string(), "text-embedding-3-small".to_string(), String::new())
-        };
+        let total: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM chunks WHERE document_id = ?1",
+            [&doc_id],
+            |row| row.get(0),
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        let cipher = kb_cipher(&conn, &kb_id)?;
+        let limit = limit.unwrap_or(CHUNK_PAGE_DEFAULT_LIMIT).clamp(1, CHUNK_PAGE_MAX_LIMIT);
+        let offset = offset.unwrap_or(0);
+        let mut stmt = conn.prepare(
+            "SELECT id, document_id, kb_id, content, chunk_index, COALESCE(token_count, 0), heading_path,
+                    page, start_offset, end_offset, duplicate_of
+             FROM chunks WHERE document_id = ?1
+             ORDER BY chunk_index LIMIT ?2 OFFSET ?3",
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let chunks = stmt
+            .query_map(rusqlite::params![&doc_id, limit as i64, offset as i64], |row| {
+                Ok(Chunk {
+                    id: row.get(0)?,
+                    document_id: row.get(1)?,
+                    kb_id: row.get(2)?,
+                    content: open_text(cipher.as_deref(), row.get(3)?),
+                    chunk_index: row.get(4)?,
+                    token_count: row.get(5)?,
+                    heading_path: row.get(6)?,
+                    page: row.get(7)?,
+                    start_offset: row.get(8)?,
+                    end_offset: row.get(9)?,
+                    duplicate_of: row.get(10)?,
+                })
+            })
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        Ok(ChunkPage { chunks, total: total as usize })
+    })
+    .await
+}
+
+/// 修改单个分块的内容并重新生成它的向量
+///
+/// 先请求 embedding，拿到向量后再在一个事务里更新分块、FTS5 条目和向量，
+/// 请求失败时分块保持原样。保存过全文的文档，全文里对应的那一段也一起替换，
+/// 之后重新分块时不会丢掉这次修改。
+#[tauri::command]
+pub async fn update_chunk(
+    chunk_id: String,
+    content: String,
+    kb_state: State<'_, KbState>,
+) -> Result<Chunk, KnowledgeBaseError> {
+    let content = content.trim().to_string();
+    if content.is_empty() {
+        return Err(KnowledgeBaseError::InvalidConfig("分块内容不能为空".to_string()));
+    }
+
+    let id = chunk_id.clone();
+    let (kb, old) = with_conn(&kb_state.db_path, move |conn| {
+        let chunk_id = &id;
+        let old = conn.query_row(
+            "SELECT id, document_id, kb_id, content, chunk_index, COALESCE(token_count, 0), heading_path,
+                    page, start_offset, end_offset, duplicate_of
+             FROM chunks WHERE id = ?1",
+            [chunk_id],
+            |row| {
+                Ok(Chunk {
+                    id: row.get(0)?,
+                    document_id: row.get(1)?,
+                    kb_id: row.get(2)?,
+                    content: row.get(3)?,
+                    chunk_index: row.get(4)?,
+                    token_count: row.get(5)?,
+                    heading_path: row.get(6)?,
+                    page: row.get(7)?,
+                    start_offset: row.get(8)?,
+                    end_offset: row.get(9)?,
+                    duplicate_of: row.get(10)?,
+                })
+            },
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                KnowledgeBaseError::NotFound(format!("Chunk not found: {}", chunk_id))
+            }
+            e => KnowledgeBaseError::DatabaseError(e.to_string()),
+        })?;
+        let kb = load_knowledge_base(&conn, &old.kb_id)?;
+        let content = open_text(cipher_for(&kb)?.as_deref(), old.content);
+        Ok((kb, Chunk { content, ..old }))
+    })
+    .await?;
+    if old.content == content {
+        return Ok(old);
+    }
 
-    let embeddings_result = generate_embeddings(
-        chunks.clone(),
+    // 生成 embedding 的网络请求期间不打开事务
+    let EmbeddingTarget { provider: embedding_provider, model: embedding_model, base_url: embedding_base_url, api_key } =
+        resolve_embedding(&kb_state.db_path, &kb)?;
+    let embedding = generate_embeddings(
+        vec![content.clone()],
         &embedding_provider,
         &api_key,
         &embedding_model,
         &embedding_base_url,
-    ).await;
+    ).await
+    .map_err(|e| KnowledgeBaseError::EmbeddingError(format!("Embedding generation failed: {}", e)))?
+    .into_iter()
+    .next()
+    .ok_or_else(|| KnowledgeBaseError::EmbeddingError("Embedding count mismatch: 1 chunk, 0 vectors".to_string()))?;
+
+    let token_count = SplitOptions::for_kb(&kb).tokenizer.count(&content) as i32;
+    let (id, text, old_chunk) = (chunk_id.clone(), content.clone(), old.clone());
+    let end_offset = with_conn(&kb_state.db_path, move |mut conn| {
+        let (chunk_id, content, old) = (&id, &text, &old_chunk);
+        let tx = conn.transaction().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let cipher = kb_cipher(&tx, &kb.id)?;
+
+        tx.execute(
+            "UPDATE chunks SET content = ?1, token_count = ?2 WHERE id = ?3",
+            rusqlite::params![seal_text(cipher.as_deref(), content), token_count, chunk_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if cipher.is_none() {
+            if let Err(e) = tx.execute(
+                "UPDATE chunks_fts SET content = ?1 WHERE rowid = (SELECT rowid FROM chunks WHERE id = ?2)",
+                rusqlite::params![segment_for_index(content), chunk_id],
+            ) {
+                log::warn!("[KB] FTS5 update failed for chunk {}: {}", chunk_id, e);
+            }
+        }
+        let encoded = encode_for_kb(&embedding, kb.vector_quantization, cipher.as_deref());
+        tx.execute(
+            "INSERT OR REPLACE INTO vectors (chunk_id, document_id, kb_id, vector, code) VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![chunk_id, &old.document_id, &old.kb_id, encoded.vector, encoded.code],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        // 迁移 embedding 模型时已经按旧内容暂存的新向量作废，迁移会重新生成
+        tx.execute(
+            "DELETE FROM reembed_vectors WHERE chunk_id = ?1",
+            [chunk_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    // 处理 embedding 生成失败的情况：把文档标记为 error 并清理孤儿 chunks
-    let embeddings = match embeddings_result {
-        Ok(emb) => emb,
-        Err(e) => {
-            let error_msg = format!("Embedding generation failed: {}", e);
-            mark_document_failed(&db_state, &doc_id, &error_msg).await?;
-            return Err(KnowledgeBaseError::EmbeddingError(error_msg));
+        // 按分块记录的位置回写全文，不按内容查找（相同的句子可能在文档里出现多次）；
+        // 之后的分块位置随长度变化平移
+        let mut end_offset = old.end_offset;
+        if let (Some(start), Some(end)) = (old.start_offset, old.end_offset) {
+            let full_text = stored_content(&tx, &old.document_id, cipher.as_deref())?;
+            let patched = full_text.and_then(|text| {
+                replace_char_range(&text, usize::try_from(start).ok()?, usize::try_from(end).ok()?, content)
+            });
+            if let Some(patched) = patched {
+                tx.execute(
+                    "UPDATE document_contents SET content = ?1 WHERE document_id = ?2",
+                    rusqlite::params![seal_text(cipher.as_deref(), &patched), &old.document_id],
+                ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                let new_end = start + content.chars().count() as i32;
+                let shift = new_end - end;
+                tx.execute(
+                    "UPDATE chunks SET start_offset = start_offset + ?1
+                     WHERE document_id = ?2 AND id <> ?3 AND start_offset >= ?4",
+                    rusqlite::params![shift, &old.document_id, chunk_id, end],
+                ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                tx.execute(
+                    "UPDATE chunks SET end_offset = end_offset + ?1
+                     WHERE document_id = ?2 AND id <> ?3 AND end_offset >= ?4",
+                    rusqlite::params![shift, &old.document_id, chunk_id, end],
+                ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                tx.execute(
+                    "UPDATE chunks SET end_offset = ?1 WHERE id = ?2",
+                    rusqlite::params![new_end, chunk_id],
+                ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+                end_offset = Some(new_end);
+            }
         }
-    };
+        tx.execute(
+            "UPDATE knowledge_bases SET updated_at = ?1 WHERE id = ?2",
+            rusqlite::params![chrono::Utc::now().timestamp_millis(), &old.kb_id],
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    // ===== 阶段三：在数据库中收尾 =====
-    // rusqlite::Connection 不是 Send 的，不能跨越 .await 持有它。
-    // 因此拆成几个子阶段：同步的 DB 操作 → 异步的向量插入 → 同步的 DB 更新。
+        tx.commit().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(end_offset)
+    })
+    .await?;
+    kb_state.vector_store.checkpoint_ann(&old.kb_id).await?;
 
-    // 阶段 3a：查询 chunk ID 并构建向量（同步，不涉及 await）
-    let (vectors_to_insert, chunk_count_actual): (Vec<_>, usize) = {
-        let db = db_state.0.lock().await;
-        let conn = rusqlite::Connection::open(&db.path)
-            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    log::info!("[KB] Updated chunk {} of document {}", chunk_id, old.document_id);
+    Ok(Chunk { content, token_count, end_offset, ..old })
+}
+
+/// 读取文档全文
+///
+/// 优先返回导入时保存的清洗后全文；更早导入、没有保存全文的文档，按顺序拼接分块，
+/// 并去掉相邻分块之间的重叠部分。关联到其他文档的重复记录返回被关联文档的全文。
+#[tauri::command]
+pub async fn get_document_content(
+    doc_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<String, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        // 重复记录和被关联的文档在同一个知识库里，用同一个加密器
+        let (kb_id, duplicate_of): (String, Option<String>) = conn.query_row(
+            "SELECT kb_id, duplicate_of FROM documents WHERE id = ?1",
+            [&doc_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                KnowledgeBaseError::NotFound(format!("Document not found: {}", doc_id))
+            }
+            e => KnowledgeBaseError::DatabaseError(e.to_string()),
+        })?;
+        let doc_id = duplicate_of.unwrap_or(doc_id);
+        let cipher = kb_cipher(&conn, &kb_id)?;
+
+        if let Some(content) = stored_content(&conn, &doc_id, cipher.as_deref())? {
+            return Ok(content);
+        }
 
-        // 从数据库中查询 chunk ID
         let mut stmt = conn.prepare(
-            "SELECT id FROM chunks WHERE document_id = ?1 ORDER BY chunk_index ASC"
+            "SELECT content FROM chunks WHERE document_id = ?1 ORDER BY chunk_index",
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-        let chunk_ids: Vec<String> = stmt.query_map([&doc_id], |row| row.get(0))
+        let chunks = stmt
+            .query_map([&doc_id], |row| Ok(open_text(cipher.as_deref(), row.get(0)?)))
             .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
-            .filter_map(|r| r.ok())
-            .collect();
-        // stmt 在此处被释放
-
-        let count = chunk_ids.len();
-
-        if chunk_ids.len() != embeddings.len() {
-            log::warn!(
-                "[KB] Chunk ID count ({}) != embedding count ({}), skipping vector insertion",
-                chunk_ids.len(),
-                embeddings.len()
-            );
-            (Vec::new(), count)
-        } else {
-            let vectors: Vec<_> = chunk_ids.iter()
-                .zip(chunks.iter())
-                .zip(embeddings.iter())
-                .map(|((chunk_id, content), embedding)| {
-                    (chunk_id.clone(), doc_id.clone(), content.clone(), embedding.clone())
-                })
-                .collect();
-            (vectors, count)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if chunks.is_empty() {
+            return Err(KnowledgeBaseError::NotFound(format!("Document has no content: {}", doc_id)));
         }
-    }; // db 锁在此处释放
+        Ok(join_chunks(&chunks, "\n\n"))
+    })
+    .await
+}
 
-    // 阶段 3b：插入向量（异步，不持有 DB 锁）
-    if !vectors_to_insert.is_empty() {
-        kb_state.vector_store.insert_vectors(&kb_id, vectors_to_insert).await?;
-    }
+/// 导入时保存的文档全文，更早导入的文档没有；cipher 是文档所属知识库的加密器
+fn stored_content(
+    conn: &rusqlite::Connection,
+    doc_id: &str,
+    cipher: Option<&KbCipher>,
+) -> Result<Option<String>, KnowledgeBaseError> {
+    conn.query_row(
+        "SELECT content FROM document_contents WHERE document_id = ?1",
+        [doc_id],
+        |row| Ok(open_text(cipher, row.get(0)?)),
+    ).optional().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+}
 
-    // 阶段 3c：更新文档状态（重新获取 DB 锁）
-    {
-        let db = db_state.0.lock().await;
-        let conn = rusqlite::Connection::open(&db.path)
-            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+/// 统计知识库的分块数、token 数、向量占用空间和各文件类型的文档分布
+///
+/// 都是聚合查询，不读出分块内容，知识库很大时也不会慢。
+#[tauri::command]
+pub async fn get_kb_stats(
+    kb_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<KbStats, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        load_knowledge_base(&conn, &kb_id)?;
 
-        let now = chrono::Utc::now().timestamp_millis();
-        conn.execute(
-            "UPDATE documents SET status = 'completed', chunk_count = ?1 WHERE id = ?2",
-            rusqlite::params![chunk_count_actual as i32, &doc_id],
+        let (document_count, last_import_at): (i64, Option<i64>) = conn.query_row(
+            "SELECT COUNT(*), MAX(created_at) FROM documents WHERE kb_id = ?1 AND deleted_at IS NULL",
+            [&kb_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-        conn.execute(
-            "UPDATE knowledge_bases SET document_count = document_count + 1, updated_at = ?1 WHERE id = ?2",
-            rusqlite::params![now, &kb_id],
+        let (chunk_count, total_tokens): (i64, i64) = conn.query_row(
+            &format!(
+                "SELECT COUNT(*), COALESCE(SUM(c.token_count), 0) FROM chunks c WHERE c.kb_id = ?1 AND {}",
+                NOT_TRASHED
+            ),
+            [&kb_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-    } // db 锁已释放
 
-    log::info!("Imported document {} with {} chunks", file_name, chunk_count_actual);
+        let vector_bytes: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(LENGTH(vector)), 0) FROM vectors WHERE kb_id = ?1",
+            [&kb_id],
+            |row| row.get(0),
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    Ok(Document {
-        id: doc_id,
-        kb_id,
-        filename: file_name,
-        file_type,
-        file_size,
-        file_hash,
-        content_preview: preview,
-        chunk_count: chunk_count_actual as i32,
-        status: DocumentStatus::Completed,
-        error_message: None,
-        created_at: chrono::Utc::now().timestamp_millis(),
+        let mut stmt = conn.prepare(
+            "SELECT file_type, COUNT(*), COALESCE(SUM(file_size), 0), COALESCE(SUM(chunk_count), 0)
+             FROM documents WHERE kb_id = ?1 AND deleted_at IS NULL
+             GROUP BY file_type ORDER BY COUNT(*) DESC, file_type",
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let file_types = stmt
+            .query_map([&kb_id], |row| {
+                Ok(FileTypeStats {
+                    file_type: row.get(0)?,
+                    document_count: row.get(1)?,
+                    file_size: row.get(2)?,
+                    chunk_count: row.get(3)?,
+                })
+            })
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+        Ok(KbStats {
+            kb_id,
+            document_count,
+            chunk_count,
+            total_tokens,
+            vector_bytes,
+            file_types,
+            last_import_at,
+        })
     })
+    .await
 }
 
-/// 列出知识库中的文档
+/// 整理标签：去掉首尾空白和空标签，去重并保持原来的顺序
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !result.iter().any(|t| t == tag) {
+            result.push(tag.to_string());
+        }
+    }
+    result
+}
+
+/// 修改文档的显示名称和标签
+///
+/// 只改 documents 表里的记录，不影响分块和向量；检索结果里的文件名会随之变化。
 #[tauri::command]
-pub async fn list_documents(
-    kb_id: String,
+pub async fn update_document(
+    request: UpdateDocumentRequest,
     kb_state: State<'_, KbState>,
-) -> Result<Vec<Document>, KnowledgeBaseError> {
-    let conn = rusqlite::Connection::open(&kb_state.db_path)
-        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-    let mut stmt = conn.prepare(
-        "SELECT id, kb_id, filename, file_type, file_size, file_hash, content_preview,
-         chunk_count, status, error_message, created_at
-         FROM documents WHERE kb_id = ?1 ORDER BY created_at DESC"
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-    let rows = stmt.query_map([&kb_id], |row| {
-        let status_str: String = row.get(8)?;
-        let status = match status_str.as_str() {
-            "completed" => DocumentStatus::Completed,
-            "error" => DocumentStatus::Error,
-            _ => DocumentStatus::Processing,
-        };
+) -> Result<Document, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        if let Some(filename) = &request.filename {
+            let filename = filename.trim();
+            if filename.is_empty() {
+                return Err(KnowledgeBaseError::InvalidConfig("文档名称不能为空".to_string()));
+            }
+            conn.execute(
+                "UPDATE documents SET filename = ?1 WHERE id = ?2",
+                rusqlite::params![filename, &request.document_id],
+            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        }
+        if let Some(tags) = request.tags {
+            let tags = serde_json::to_string(&normalize_tags(tags))
+                .map_err(|e| KnowledgeBaseError::InvalidConfig(e.to_string()))?;
+            conn.execute(
+                "UPDATE documents SET tags = ?1 WHERE id = ?2",
+                rusqlite::params![tags, &request.document_id],
+            ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        }
 
-        Ok(Document {
-            id: row.get(0)?,
-            kb_id: row.get(1)?,
-            filename: row.get(2)?,
-            file_type: row.get(3)?,
-            file_size: row.get(4)?,
-            file_hash: row.get(5)?,
-            content_preview: row.get(6)?,
-            chunk_count: row.get(7)?,
-            status,
-            error_message: row.get(9)?,
-            created_at: row.get(10)?,
+        let cipher = document_cipher(&conn, &request.document_id)?;
+        conn.query_row(
+            &format!("SELECT {} FROM documents WHERE id = ?1", DOCUMENT_COLUMNS),
+            [&request.document_id],
+            |row| document_from_row(row, cipher.as_deref()),
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                KnowledgeBaseError::NotFound(format!("Document not found: {}", request.document_id))
+            }
+            e => KnowledgeBaseError::DatabaseError(e.to_string()),
         })
-    }).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-    let mut docs = Vec::new();
-    for row in rows {
-        docs.push(row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?);
-    }
-
-    Ok(docs)
+    })
+    .await
 }
 
-/// 删除文档
+/// 删除文档：移进回收站，可以用 restore_document 恢复（见 trash 模块）
 ///
 /// # 对应 #35 的修复：
 /// - 校验文档存在，且确实属于指定的知识库
@@ -548,47 +2061,33 @@ pub async fn delete_document(
     kb_id: String,
     kb_state: State<'_, KbState>,
 ) -> Result<(), KnowledgeBaseError> {
-    let conn = rusqlite::Connection::open(&kb_state.db_path)
-        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-    // 校验文档存在，且属于指定的知识库
-    let doc_exists: bool = conn.query_row(
-        "SELECT COUNT(*) FROM documents WHERE id = ?1 AND kb_id = ?2",
-        rusqlite::params![&doc_id, &kb_id],
-        |row| row.get(0),
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-    if !doc_exists {
-        return Err(KnowledgeBaseError::NotFound(
-            format!("Document not found: {} in knowledge base: {}", doc_id, kb_id)
-        ));
-    }
-
-    // 删除向量
-    kb_state.vector_store.delete_document_vectors(&kb_id, &doc_id).await?;
-
-    // 从 FTS5 中删除（必须在删除 chunks 之前进行，因为需要用到 rowid）
-    if let Err(e) = conn.execute(
-        "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE document_id = ?1)",
-        rusqlite::params![&doc_id],
-    ) {
-        log::warn!("[KB] FTS5 cleanup failed for document {}: {}", doc_id, e);
-    }
+    let (document_id, id) = (doc_id.clone(), kb_id.clone());
+    with_conn(&kb_state.db_path, move |conn| {
+        let (doc_id, kb_id) = (&document_id, &id);
+
+        // 校验文档存在，且属于指定的知识库
+        let doc_exists: bool = conn.query_row(
+            "SELECT COUNT(*) FROM documents WHERE id = ?1 AND kb_id = ?2 AND deleted_at IS NULL",
+            rusqlite::params![doc_id, kb_id],
+            |row| row.get(0),
+        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-    // 从 SQLite 中删除（级联删除会自动清掉 chunks）
-    conn.execute(
-        "DELETE FROM documents WHERE id = ?1",
-        rusqlite::params![&doc_id],
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if !doc_exists {
+            return Err(KnowledgeBaseError::NotFound(
+                format!("Document not found: {} in knowledge base: {}", doc_id, kb_id)
+            ));
+        }
 
-    // 安全地更新知识库的文档计数（保证永远不会小于 0）
-    let now = chrono::Utc::now().timestamp_millis();
-    conn.execute(
-        "UPDATE knowledge_bases SET document_count = MAX(document_count - 1, 0), updated_at = ?1 WHERE id = ?2",
-        rusqlite::params![now, &kb_id],
-    ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        // 移进回收站：数据原样保留，检索时跳过；关联到这份文档的重复记录一起移入
+        trash_document_rows(&conn, kb_id, doc_id)
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        Ok(())
+    })
+    .await?;
+    // 参与检索的分块变了，向量缓存要重新载入
+    kb_state.vector_store.drop_vector_cache(&kb_id);
 
-    log::info!("Deleted document: {}", doc_id);
+    log::info!("Moved document to trash: {}", doc_id);
     Ok(())
 }
 
@@ -601,28 +2100,22 @@ pub async fn search_knowledge_base(
     request: RetrievalRequest,
     kb_state: State<'_, KbState>,
 ) -> Result<RetrievalResult, KnowledgeBaseError> {
-    // 从知识库中获取 embedding API 配置
-    let (embedding_api_config_id, embedding_provider, embedding_model, embedding_base_url) = {
-        let conn = rusqlite::Connection::open(&kb_state.db_path)
-            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-        let (config_id, provider, model, base_url): (String, String, String, String) = conn.query_row(
-            "SELECT embedding_api_config_id, COALESCE(embedding_provider, ''), COALESCE(embedding_model, ''), COALESCE(embedding_base_url, '') FROM knowledge_bases WHERE id = ?1",
-            [&request.kb_id],
-            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
-        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-
-        // 仅对创建于 embedding_provider/model 字段引入之前的旧知识库，
-        // 才回退到 OpenAI 默认值。
-        if provider.is_empty() || model.is_empty() {
-            (config_id, "openai".to_string(), "text-embedding-3-small".to_string(), String::new())
-        } else {
-            (config_id, provider, model, base_url)
-        }
-    };
+    search_kb(&kb_state, request).await
+}
 
-    // 从安全存储中读取 API Key（#32）
-    let api_key = get_embedding_api_key(&embedding_api_config_id)?;
+/// 检索单个知识库（含可选的 reranker 精排）；聊天时的 RAG 检索也走这里
+pub(crate) async fn search_kb(
+    kb_state: &KbState,
+    request: RetrievalRequest,
+) -> Result<RetrievalResult, KnowledgeBaseError> {
+    // 知识库记录的 embedding 模型，API Key 从它引用的配置在安全存储中读取（#32）
+    let kb_id = request.kb_id.clone();
+    let EmbeddingTarget { provider: embedding_provider, model: embedding_model, base_url: embedding_base_url, api_key } =
+        with_conn(&kb_state.db_path, move |conn| {
+            let kb = load_knowledge_base(&conn, &kb_id)?;
+            kb_embedding(&conn, &kb)
+        })
+        .await?;
 
     let retriever = Retriever::new(kb_state.vector_store.clone(), kb_state.db_path.clone());
     let mut result = retriever.retrieve(request.clone(), &embedding_provider, &embedding_model, &embedding_base_url, &api_key).await?;
@@ -689,3 +2182,76 @@ pub async fn read_document_for_context(
 ) -> Result<String, KnowledgeBaseError> {
     parse_document(&file_path).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joined_chunks_keep_overlapping_text_once() {
+        let chunks = vec![
+            "第一段讲安装步骤，先下载最新的安装包。".to_string(),
+            "先下载最新的安装包。然后运行安装程序。".to_string(),
+            "第二段讲配置。".to_string(),
+        ];
+        assert_eq!(
+            join_chunks(&chunks, "\n\n"),
+            "第一段讲安装步骤，先下载最新的安装包。然后运行安装程序。\n\n第二段讲配置。"
+        );
+        // 只有一两个字相同不算重叠
+        assert_eq!(join_chunks(&["abc".to_string(), "cde".to_string()], "\n\n"), "abc\n\ncde");
+    }
+
+    #[test]
+    fn note_title_defaults_to_first_line() {
+        assert_eq!(note_title("\n## 周会记录\n- 讨论了检索"), "周会记录");
+        assert_eq!(note_title("   "), "笔记");
+        let long = "很".repeat(NOTE_TITLE_MAX_CHARS + 5);
+        assert_eq!(note_title(&long).chars().count(), NOTE_TITLE_MAX_CHARS + 1);
+    }
+
+    #[test]
+    fn chunks_are_inserted_in_batches_with_their_fts_rows() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        init_sqlite_tables(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, created_at, updated_at) VALUES ('kb', 'kb', 'cfg', 0, 0);
+             INSERT INTO documents (id, kb_id, filename, file_type, created_at) VALUES ('d', 'kb', 'a.pdf', 'pdf', 0);
+             INSERT INTO parent_chunks (id, document_id, kb_id, content) VALUES ('p', 'd', 'kb', '父块');",
+        )
+        .unwrap();
+        // 超过一批的行数，最后一批不满
+        let count = super::super::db::INSERT_BATCH_ROWS * 2 + 5;
+        let chunks: Vec<TextChunk> = (0..count)
+            .map(|i| TextChunk {
+                content: format!("第 {} 段 keyword{}", i, i),
+                heading_path: None,
+                parent: (i == 0).then_some(0),
+            })
+            .collect();
+        let locations = vec![ChunkLocation { page: Some(2), ..Default::default() }; count];
+
+        let ids = insert_chunks(&conn, "d", "kb", &chunks, &locations, &["p".to_string()]).unwrap();
+        assert_eq!(ids.len(), count);
+        let (last_index, page, parent): (i32, Option<i32>, Option<String>) = conn
+            .query_row(
+                "SELECT chunk_index, page, (SELECT parent_chunk_id FROM chunks WHERE chunk_index = 0) FROM chunks WHERE id = ?1",
+                [&ids[count - 1]],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((last_index, page, parent.as_deref()), (count as i32 - 1, Some(2), Some("p")));
+
+        let fts_match: Option<String> = conn
+            .query_row(
+                "SELECT c.id FROM chunks_fts f JOIN chunks c ON c.rowid = f.rowid WHERE chunks_fts MATCH 'keyword130'",
+                [],
+                |row| row.get(0),
+            )
+            .ok();
+        // 没有 FTS5 的 SQLite 上跳过这一项检查
+        if let Some(id) = fts_match {
+            assert_eq!(id, ids[130]);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 检索结果上下文压缩模块
+ *
+ * 功能说明:
+ * - 检索到的片段（尤其是扩展了句子窗口之后）往往只有一两句和问题有关，
+ *   整段拼进上下文会挤占其它来源的位置
+ * - 开启 compress_context 后，聊天 RAG 在 build_context 之前让对话模型逐个片段
+ *   原样摘出和问题相关的句子，用摘出的内容代替片段原文
+ * - 模型判断片段和问题完全无关时，这个片段直接丢掉，不再占上下文
+ *
+ * 各片段并发压缩。某个片段压缩失败、或者摘出来的内容不比原文短时保留原文。
+ */
+
+use super::hyde::complete;
+use super::types::{QueryLlm, RetrievedChunk};
+
+/// 片段和问题无关时模型应回复的标记
+const NO_RELEVANT_CONTENT: &str = "无相关内容";
+
+/// 压缩用的系统提示词
+const COMPRESSION_SYSTEM_PROMPT: &str = "你会收到一个问题和一段资料。请从资料中原样摘出能帮助回答问题的句子，\
+按原文顺序输出，不要改写、不要补充、不要解释。资料和问题完全无关时只回复\"无相关内容\"。";
+
+/// 单个片段压缩结果的 token 上限
+const COMPRESSION_MAX_TOKENS: u32 = 1024;
+
+/// 一个片段的压缩结果
+#[derive(Debug, PartialEq)]
+enum Extraction {
+    /// 用摘出的句子代替原文
+    Replace(String),
+    /// 和问题无关，丢掉
+    Drop,
+    /// 保留原文
+    Keep,
+}
+
+/// 解读模型的回复
+fn parse_extraction(original: &str, reply: &str) -> Extraction {
+    let reply = reply.trim();
+    let marker = reply.trim_end_matches(['。', '.', '！', '!']);
+    if marker == NO_RELEVANT_CONTENT {
+        Extraction::Drop
+    } else if reply.is_empty() || reply.chars().count() >= original.chars().count() {
+        Extraction::Keep
+    } else {
+        Extraction::Replace(reply.to_string())
+    }
+}
+
+/// 压缩一个片段
+async fn compress_chunk(llm: &QueryLlm, query: &str, chunk: &RetrievedChunk) -> Extraction {
+    let content = format!("问题：{}\n\n资料：\n{}", query, chunk.chunk.content);
+    match complete(llm, COMPRESSION_SYSTEM_PROMPT, &content, COMPRESSION_MAX_TOKENS).await {
+        Ok(reply) => parse_extraction(&chunk.chunk.content, &reply),
+        Err(e) => {
+            log::warn!("[RAG] compressing chunk {} failed, keeping it as is: {}", chunk.chunk.id, e);
+            Extraction::Keep
+        }
+    }
+}
+
+/// 并发压缩检索结果，保持原来的顺序；和问题无关的片段被去掉
+pub(crate) async fn compress_chunks(
+    llm: &QueryLlm,
+    query: &str,
+    chunks: Vec<RetrievedChunk>,
+) -> Vec<RetrievedChunk> {
+    let extractions =
+        futures::future::join_all(chunks.iter().map(|chunk| compress_chunk(llm, query, chunk))).await;
+
+    let before: usize = chunks.iter().map(|c| c.chunk.content.chars().count()).sum();
+    let compressed: Vec<RetrievedChunk> = chunks
+        .into_iter()
+        .zip(extractions)
+        .filter_map(|(mut chunk, extraction)| match extraction {
+            Extraction::Replace(text) => {
+                chunk.chunk.content = text;
+                Some(chunk)
+            }
+            Extraction::Drop => None,
+            Extraction::Keep => Some(chunk),
+        })
+        .collect();
+    let after: usize = compressed.iter().map(|c| c.chunk.content.chars().count()).sum();
+    log::info!(
+        "[RAG] compressed context from {} to {} characters, {} chunks kept",
+        before, after, compressed.len()
+    );
+    compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_applied_only_when_they_shorten_the_chunk() {
+        let original = "向量知识库支持 PDF、Word 和 Markdown。导入时会自动分块。价格按量计费。";
+        assert_eq!(
+            parse_extraction(original, " 向量知识库支持 PDF、Word 和 Markdown。\n"),
+            Extraction::Replace("向量知识库支持 PDF、Word 和 Markdown。".to_string())
+        );
+        assert_eq!(parse_extraction(original, "无相关内容。"), Extraction::Drop);
+        assert_eq!(parse_extraction(original, "  "), Extraction::Keep);
+        assert_eq!(parse_extraction("短", "比原文还长的回复"), Extraction::Keep);
+    }
+}
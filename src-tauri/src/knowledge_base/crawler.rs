@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 整站爬取模块
+ *
+ * 功能说明:
+ * - crawl_site 从一个网页出发，按广度优先跟随同一主机名下的链接，
+ *   受 max_depth（链接层数）和 max_pages（抓取网页数）限制
+ * - 按网址去重（同一次爬取里不重复抓，知识库里已经导入过的网址不再导入），
+ *   再按正文哈希去重（不同网址、相同内容的网页只导入一份）
+ * - 每个网页作为单独的文档导入，复用 import_url 的分块/embedding 管线；
+ *   网页逐个导入，避免同时向 embedding 服务发出大量请求
+ * - 通过 kb-crawl-progress 事件上报整体进度
+ *
+ * 抓取走 fetch_url 同一套实现，只允许公网 http/https 地址。
+ */
+
+use super::commands::{
+    create_import_document, finish_cancelled, finish_import, load_knowledge_base, run_import, with_locked_conn, ImportSource,
+};
+use super::import_tasks::run_cancellable;
+use super::document::calculate_text_hash;
+use super::types::*;
+use crate::commands::constants::{
+    CRAWL_DEFAULT_MAX_DEPTH, CRAWL_DEFAULT_MAX_PAGES, CRAWL_MAX_DEPTH_LIMIT, CRAWL_MAX_PAGES_LIMIT,
+};
+use crate::commands::web_fetch::{fetch_page_markdown, FetchedPage};
+use std::collections::{HashSet, VecDeque};
+use tauri::{AppHandle, Emitter, Manager, State};
+use uuid::Uuid;
+
+/// 链接指向这些扩展名的文件时不抓取（不是网页）
+const SKIPPED_EXTENSIONS: &[&str] = &[
+    "pdf", "zip", "gz", "tar", "rar", "7z", "exe", "dmg", "msi", "png", "jpg", "jpeg", "gif", "svg",
+    "webp", "ico", "mp3", "mp4", "avi", "mov", "css", "js", "woff", "woff2",
+];
+
+/// 爬取一次的参数和状态
+struct Crawl {
+    task: CrawlTask,
+    host: String,
+    max_depth: usize,
+    max_pages: usize,
+    /// 知识库里已有文档的来源网址
+    known_sources: HashSet<String>,
+    /// 知识库里已有文档和本次已导入网页的正文哈希
+    known_hashes: HashSet<String>,
+}
+
+/// 从一个网页开始爬取同一站点，把每个网页导入知识库
+#[tauri::command]
+pub async fn crawl_site(
+    request: CrawlRequest,
+    app_handle: AppHandle,
+    db_state: State<'_, crate::db::DbState>,
+) -> Result<CrawlTask, KnowledgeBaseError> {
+    let start = reqwest::Url::parse(request.url.trim())
+        .map_err(|e| KnowledgeBaseError::InvalidConfig(format!("网址无效: {}", e)))?;
+    if !matches!(start.scheme(), "http" | "https") {
+        return Err(KnowledgeBaseError::InvalidConfig(format!("只支持 http/https 网址，收到: {}", start.scheme())));
+    }
+    let host = start
+        .host_str()
+        .ok_or_else(|| KnowledgeBaseError::InvalidConfig("网址缺少主机名".to_string()))?
+        .to_string();
+
+    let kb_id = request.kb_id.clone();
+    let (known_sources, known_hashes): (HashSet<String>, HashSet<String>) = with_locked_conn(&db_state, move |conn| {
+        load_knowledge_base(&conn, &kb_id)?;
+
+        let mut stmt = conn
+            .prepare("SELECT COALESCE(source, ''), COALESCE(file_hash, '') FROM documents WHERE kb_id = ?1")
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        let rows: Vec<(String, String)> = stmt
+            .query_map([&kb_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+            .filter_map(|r| r.ok())
+            .collect();
+        let sources = rows.iter().map(|(s, _)| s.clone()).filter(|s| !s.is_empty()).collect();
+        let hashes = rows.into_iter().map(|(_, h)| h).filter(|h| !h.is_empty()).collect();
+        Ok((sources, hashes))
+    })
+    .await?;
+
+    let task = CrawlTask {
+        crawl_id: Uuid::new_v4().to_string(),
+        kb_id: request.kb_id,
+        url: start.to_string(),
+    };
+    let crawl = Crawl {
+        task: task.clone(),
+        host,
+        max_depth: request.max_depth.unwrap_or(CRAWL_DEFAULT_MAX_DEPTH).min(CRAWL_MAX_DEPTH_LIMIT),
+        max_pages: request.max_pages.unwrap_or(CRAWL_DEFAULT_MAX_PAGES).clamp(1, CRAWL_MAX_PAGES_LIMIT),
+        known_sources,
+        known_hashes,
+    };
+
+    tauri::async_runtime::spawn(async move {
+        run_crawl(&app_handle, crawl).await;
+    });
+
+    Ok(task)
+}
+
+/// 链接是否值得跟随：同一主机名、不是图片/压缩包等文件
+fn should_follow(link: &str, host: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(link) else {
+        return false;
+    };
+    if url.host_str() != Some(host) {
+        return false;
+    }
+    let path = url.path().to_ascii_lowercase();
+    !path
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| !ext.contains('/') && SKIPPED_EXTENSIONS.contains(&ext))
+}
+
+async fn run_crawl(app_handle: &AppHandle, mut crawl: Crawl) {
+    let mut queue: VecDeque<(String, usize)> = VecDeque::from([(crawl.task.url.clone(), 0)]);
+    let mut seen: HashSet<String> = HashSet::from([crawl.task.url.clone()]);
+    let mut progress = CrawlProgressEvent {
+        crawl_id: crawl.task.crawl_id.clone(),
+        kb_id: crawl.task.kb_id.clone(),
+        url: crawl.task.url.clone(),
+        visited: 0,
+        queued: 1,
+        imported: 0,
+        skipped: 0,
+        failed: 0,
+        finished: false,
+    };
+
+    while progress.visited < crawl.max_pages {
+        let Some((url, depth)) = queue.pop_front() else { break };
+        progress.visited += 1;
+        progress.url = url.clone();
+
+        match fetch_page_markdown(&url).await {
+            Err(e) => {
+                log::warn!("[KB] Crawl failed to fetch {}: {}", url, e);
+                progress.failed += 1;
+            }
+            Ok(page) => {
+                if depth < crawl.max_depth {
+                    for link in &page.links {
+                        if should_follow(link, &crawl.host) && seen.insert(link.clone()) {
+                            queue.push_back((link.clone(), depth + 1));
+                        }
+                    }
+                }
+                // 跟随重定向之后的地址也算见过
+                seen.insert(page.url.clone());
+
+                let hash = calculate_text_hash(&page.content);
+                if page.content.trim().is_empty()
+                    || crawl.known_sources.contains(&url)
+                    || crawl.known_sources.contains(&page.url)
+                    || !crawl.known_hashes.insert(hash)
+                {
+                    progress.skipped += 1;
+                } else if import_page(app_handle, &crawl.task.kb_id, page).await {
+                    progress.imported += 1;
+                } else {
+                    progress.failed += 1;
+                }
+            }
+        }
+
+        progress.queued = queue.len();
+        emit_crawl_progress(app_handle, &progress);
+    }
+
+    progress.finished = true;
+    progress.queued = queue.len();
+    emit_crawl_progress(app_handle, &progress);
+    log::info!(
+        "[KB] Crawl of {} finished: {} visited, {} imported, {} skipped, {} failed",
+        crawl.task.url,
+        progress.visited,
+        progress.imported,
+        progress.skipped,
+        progress.failed
+    );
+}
+
+/// 把抓取好的网页作为一个文档导入，等导入完成再返回；成功时返回 true
+async fn import_page(app_handle: &AppHandle, kb_id: &str, page: FetchedPage) -> bool {
+    let db_state = app_handle.state::<crate::db::DbState>();
+    let file_type = if page.is_html { "html" } else { "txt" }.to_string();
+    let file_size = page.content.len() as i64;
+    let url = page.url.clone();
+    let source = ImportSource::Page(page);
+
+    let (kb, task) = match create_import_document(kb_id.to_string(), url, file_type, file_size, &source, &db_state).await {
+        Ok(created) => created,
+        Err(e) => {
+            log::warn!("[KB] Crawl failed to create document: {}", e);
+            return false;
+        }
+    };
+    let Some(result) = run_cancellable(&task, run_import(app_handle, &kb, &task, &source, DuplicatePolicy::Skip)).await else {
+        finish_cancelled(app_handle, &task).await;
+        return false;
+    };
+    let ok = result.is_ok();
+    finish_import(app_handle, &task, result).await;
+    ok
+}
+
+fn emit_crawl_progress(app_handle: &AppHandle, progress: &CrawlProgressEvent) {
+    if let Err(e) = app_handle.emit("kb-crawl-progress", progress) {
+        log::warn!("[KB] Failed to emit crawl progress: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_same_host_pages_are_followed() {
+        assert!(should_follow("https://docs.example.com/guide/setup", "docs.example.com"));
+        assert!(should_follow("https://docs.example.com/v1.2/intro", "docs.example.com"));
+        assert!(!should_follow("https://example.com/guide", "docs.example.com"));
+        assert!(!should_follow("https://docs.example.com/files/manual.PDF", "docs.example.com"));
+        assert!(!should_follow("not a url", "docs.example.com"));
+    }
+}
@@ -2,11 +2,25 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::ann::{prune_vector_log, sync_index, AnnIndexes, ANN_MIN_VECTORS};
+use super::dedup::NOT_COLLAPSED;
+use super::encryption::{encode_for_kb, is_sealed_vector, kb_cipher, open_text, open_vector, KbCipher};
+use super::fts::ensure_fts_table;
+use super::quantization::{
+    binary_code, decode_int8, hamming_distance, is_int8, BINARY_RESCORE_FACTOR,
+};
+use super::trash::NOT_TRASHED;
 use super::types::*;
+use super::vector_cache::{vector_version, CachedVector, VectorCache};
+use std::sync::Arc;
 
 /// 基于 SQLite、用余弦相似度做检索的向量存储
 pub struct VectorStore {
     db_path: String,
+    /// 大知识库的 ANN 索引，索引文件放在 db_path 目录下
+    ann: Arc<AnnIndexes>,
+    /// 最近检索过的知识库的向量
+    cache: Arc<VectorCache>,
 }
 
 impl VectorStore {
@@ -15,11 +29,23 @@ impl VectorStore {
         std::fs::create_dir_all(db_path)
             .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
+        let dir = std::path::PathBuf::from(db_path);
+        let main_db_path = dir
+            .parent()
+            .map(|p| p.join("app.db"))
+            .ok_or_else(|| KnowledgeBaseError::DatabaseError("Invalid db path".to_string()))?;
+
         Ok(Self {
             db_path: db_path.to_string(),
+            ann: Arc::new(AnnIndexes::new(dir, main_db_path)),
+            cache: Arc::new(VectorCache::default()),
         })
     }
 
+    pub(crate) fn vector_cache(&self) -> &VectorCache {
+        &self.cache
+    }
+
     /// 为某个知识库创建向量表
     #[allow(dead_code)]
     pub async fn create_kb_table(&self, kb_id: &str, dim: i32) -> Result<(), KnowledgeBaseError> {
@@ -66,6 +92,7 @@ impl VectorStore {
     ) -> Result<(), KnowledgeBaseError> {
         let db_path = self.db_path.clone();
         let kb_id = kb_id.to_string();
+        let ann = self.ann.clone();
 
         tokio::task::spawn_blocking(move || {
             let main_db_path = std::path::Path::new(&db_path)
@@ -73,21 +100,34 @@ impl VectorStore {
                 .map(|p| p.join("app.db"))
                 .ok_or_else(|| KnowledgeBaseError::DatabaseError("Invalid db path".to_string()))?;
 
-            let conn = rusqlite::Connection::open(&main_db_path)
+            let mut conn = crate::db::open_connection(&main_db_path)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let quantization = kb_quantization(&conn, &kb_id)?;
+            let cipher = kb_cipher(&conn, &kb_id)?;
 
+            // 整批在一个事务里多行写入，失败时这一批一条都不留
             let count = vectors.len();
-            for (chunk_id, document_id, _content, vector) in vectors {
-                let vector_bytes = vector_to_bytes(&vector);
-                conn.execute(
-                    r#"
-                    INSERT OR REPLACE INTO vectors (chunk_id, document_id, kb_id, vector)
-                    VALUES (?1, ?2, ?3, ?4)
-                    "#,
-                    rusqlite::params![chunk_id, document_id, kb_id, vector_bytes],
-                )
-                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
-            }
+            let encoded: Vec<_> = vectors
+                .iter()
+                .map(|(_, _, _, vector)| encode_for_kb(vector, quantization, cipher.as_deref()))
+                .collect();
+            let rows: Vec<Vec<&dyn rusqlite::ToSql>> = vectors
+                .iter()
+                .zip(&encoded)
+                .map(|((chunk_id, document_id, _content, _), encoded)| {
+                    vec![chunk_id as &dyn rusqlite::ToSql, document_id, &kb_id, &encoded.vector, &encoded.code]
+                })
+                .collect();
+            let tx = conn.transaction().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            insert_rows(
+                &tx,
+                "INSERT OR REPLACE INTO vectors (chunk_id, document_id, kb_id, vector, code)",
+                "(?, ?, ?, ?, ?)",
+                &rows,
+            )
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            tx.commit().map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            ann.checkpoint(&conn, &kb_id);
 
             log::info!("Inserted {} vectors for knowledge base: {}", count, kb_id);
             Ok(())
@@ -97,11 +137,13 @@ impl VectorStore {
     }
 
     /// 单个知识库的向量数超过这个值时，记一条提示日志说明本次是精确全量扫描。
-    /// 这个阈值绝不会排除任何数据 —— 它只是提示：如果查询延迟在这个规模下已经
-    /// 让用户能感知到，或许值得考虑上 ANN 索引。
+    /// 这个阈值绝不会排除任何数据 —— 大知识库正常走 ANN 索引，到这里说明索引还在
+    /// 构建或者这次回退了，日志用来解释为什么这次查询比较慢。
     const LARGE_KB_SCAN_HINT: u64 = 200_000;
 
     /// 在知识库的全部向量上做精确余弦相似度检索（不会有任何文档被预先排除在候选之外）。
+    /// 向量数达到 ANN_MIN_VECTORS 的知识库先用 ANN 索引取候选再精确重排，见 ann_search；
+    /// 放得进缓存预算的知识库在内存里的向量上计算，见 cached_search。
     ///
     /// 包了一层 `spawn_blocking`，避免阻塞式的 SQLite I/O 卡住异步执行器。内存占用
     /// 通过固定大小的最小堆流式处理每一行，而不是把所有打分结果都物化进一个 Vec，
@@ -111,9 +153,14 @@ impl VectorStore {
         kb_id: &str,
         query_vector: Vec<f32>,
         top_k: i32,
+        tags: &[String],
+        documents: Option<&[String]>,
     ) -> Result<Vec<(String, String, String, f32)>, KnowledgeBaseError> {
         let db_path = self.db_path.clone();
         let kb_id = kb_id.to_string();
+        let tag_filter = document_filter_param(tags, documents);
+        let ann = self.ann.clone();
+        let cache = self.cache.clone();
 
         tokio::task::spawn_blocking(move || {
             // top_k 非正数意味着"不需要任何结果"。
@@ -127,24 +174,57 @@ impl VectorStore {
                 .map(|p| p.join("app.db"))
                 .ok_or_else(|| KnowledgeBaseError::DatabaseError("Invalid db path".to_string()))?;
 
-            let conn = rusqlite::Connection::open(&main_db_path)
+            let conn = crate::db::open_connection(&main_db_path)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
+            // 大知识库先用 ANN 索引找候选；索引还没建好、或者过滤之后候选不够时回退到精确扫描
+            let vector_count: i64 = conn
+                .query_row("SELECT COUNT(*) FROM vectors WHERE kb_id = ?1", [&kb_id], |row| row.get(0))
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            // 加密知识库不建 ANN 索引（索引文件里是明文向量），总是精确扫描
+            let cipher = kb_cipher(&conn, &kb_id)?;
+            if vector_count >= ANN_MIN_VECTORS && cipher.is_none() {
+                match ann_search(&conn, &ann, &kb_id, &query_vector, top_k, &tag_filter) {
+                    Ok(Some(results)) => return Ok(results),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("[KB] ANN search failed for {}, falling back to exact scan: {}", kb_id, e),
+                }
+            }
+            if kb_quantization(&conn, &kb_id)? == VectorQuantization::Binary {
+                return binary_search(&conn, &kb_id, &query_vector, top_k, &tag_filter);
+            }
+
+            // 向量缓存：命中或者放得进预算时在内存里算，否则逐行流式扫描
+            let version = vector_version(&conn, &kb_id)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let cached = match cache.get(&kb_id, version) {
+                Some(vectors) => Some(vectors),
+                None => load_cached_vectors(&conn, &cache, &kb_id, vector_count as usize)?
+                    .map(|vectors| cache.insert(&kb_id, version, vectors)),
+            };
+            if let Some(vectors) = cached {
+                return cached_search(&conn, &kb_id, cipher.as_deref(), &vectors, &query_vector, top_k, &tag_filter);
+            }
+
             let mut stmt = conn
-                .prepare(
+                .prepare(&format!(
                     r#"
                     SELECT v.chunk_id, v.document_id, c.content, v.vector
                     FROM vectors v
                     JOIN chunks c ON v.chunk_id = c.id
-                    WHERE v.kb_id = ?1
+                    JOIN documents d ON v.document_id = d.id
+                    WHERE v.kb_id = ?1 AND {} AND {} AND {}
                     "#,
-                )
+                    document_filter_clause(2),
+                    NOT_COLLAPSED,
+                    NOT_TRASHED
+                ))
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
             // `query_map` 是惰性游标 —— 每次只取到一行，不会把所有行都物化进内存。
             // 我们对每一行算出分数后只在最小堆里保留当前的 top_k，因此峰值内存维持在 O(top_k)。
             let rows = stmt
-                .query_map([&kb_id], |row| {
+                .query_map(rusqlite::params![&kb_id, &tag_filter], |row| {
                     let chunk_id: String = row.get(0)?;
                     let document_id: String = row.get(1)?;
                     let content: String = row.get(2)?;
@@ -158,6 +238,7 @@ impl VectorStore {
             let mut heap: std::collections::BinaryHeap<std::cmp::Reverse<ScoredChunk>> =
                 std::collections::BinaryHeap::with_capacity(top_k + 1);
 
+            let scorer = CosineScorer::new(&query_vector);
             let mut scanned: u64 = 0;
             for row in rows {
                 let (chunk_id, document_id, content, vector_bytes) =
@@ -165,7 +246,7 @@ impl VectorStore {
                 scanned += 1;
 
                 let vector = bytes_to_vector(&vector_bytes);
-                let score = cosine_similarity(&query_vector, &vector);
+                let score = scorer.score(&vector);
                 // `vector`（每行里占用内存最大的分配）在此处被释放。
 
                 push_capped(
@@ -178,14 +259,14 @@ impl VectorStore {
             if scanned > Self::LARGE_KB_SCAN_HINT {
                 log::info!(
                     "[KB] Exact full scan over {} vectors in '{}' (complete, no exclusion). \
-                     Consider an ANN index if query latency becomes noticeable at this scale.",
+                     The ANN index is still building or could not serve this query.",
                     scanned, kb_id
                 );
             }
 
             let results: Vec<(String, String, String, f32)> = drain_sorted_desc(heap)
                 .into_iter()
-                .map(|s| (s.chunk_id, s.document_id, s.content, s.score))
+                .map(|s| (s.chunk_id, s.document_id, open_text(cipher.as_deref(), s.content), s.score))
                 .collect();
 
             log::info!(
@@ -212,6 +293,11 @@ impl VectorStore {
             [kb_id, document_id],
         )
         .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        if prune_vector_log(&conn, kb_id).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))? {
+            self.ann.remove(kb_id);
+        } else {
+            self.ann.checkpoint(&conn, kb_id);
+        }
         log::info!("Deleted vectors for document: {} in {}", document_id, kb_id);
         Ok(())
     }
@@ -221,20 +307,371 @@ impl VectorStore {
         let conn = self.get_conn()?;
         conn.execute("DELETE FROM vectors WHERE kb_id = ?1", [kb_id])
             .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        conn.execute("DELETE FROM vector_log WHERE kb_id = ?1", [kb_id])
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        conn.execute("DELETE FROM vector_versions WHERE kb_id = ?1", [kb_id])
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        self.ann.remove(kb_id);
+        self.cache.remove(kb_id);
         log::info!("Dropped vectors for knowledge base: {}", kb_id);
         Ok(())
     }
 
+    /// 丢掉知识库的向量缓存（向量没变、但参与检索的分块变了时调用），下次检索时重新载入
+    pub(crate) fn drop_vector_cache(&self, kb_id: &str) {
+        self.cache.remove(kb_id);
+    }
+
+    /// 在 VectorStore 之外写了向量（重新导入、修改分块、彻底删除文档）之后调用，
+    /// 把 ANN 索引同步到最新并按需写回文件、清理变更日志，见 AnnIndexes::checkpoint
+    pub(crate) async fn checkpoint_ann(&self, kb_id: &str) -> Result<(), KnowledgeBaseError> {
+        let conn = self.get_conn()?;
+        let (ann, kb_id) = (self.ann.clone(), kb_id.to_string());
+        tokio::task::spawn_blocking(move || ann.checkpoint(&conn, &kb_id))
+            .await
+            .map_err(|e| KnowledgeBaseError::DatabaseError(format!("spawn_blocking failed: {}", e)))
+    }
+
+    /// 丢掉知识库的 ANN 索引和向量缓存。向量整体换成另一个模型之后调用，下次检索时重建
+    pub(crate) fn drop_ann_index(&self, kb_id: &str) {
+        self.ann.remove(kb_id);
+        self.cache.remove(kb_id);
+    }
+
     fn get_conn(&self) -> Result<rusqlite::Connection, KnowledgeBaseError> {
         let main_db_path = std::path::Path::new(&self.db_path)
             .parent()
             .map(|p| p.join("app.db"))
             .ok_or_else(|| KnowledgeBaseError::DatabaseError("Invalid db path".to_string()))?;
-        rusqlite::Connection::open(&main_db_path)
+        crate::db::open_connection(&main_db_path)
             .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
     }
 }
 
+/// 用 ANN 索引检索：先把内存里的索引同步到最新，取 ANN_CANDIDATE_FACTOR 倍的候选，
+/// 再从 SQLite 读出候选的原始向量精确打分（同时应用标签过滤）。
+/// 只读不写：索引文件和变更日志留给写路径处理（见 AnnIndexes::checkpoint）
+///
+/// 返回 None 表示这次不能用索引（还在后台构建，或者过滤后候选不够 top_k），
+/// 由调用方回退到精确扫描。
+fn ann_search(
+    conn: &rusqlite::Connection,
+    ann: &AnnIndexes,
+    kb_id: &str,
+    query_vector: &[f32],
+    top_k: usize,
+    tag_filter: &Option<String>,
+) -> Result<Option<Vec<SearchHit>>, KnowledgeBaseError> {
+    let Some(index) = ann.get(kb_id) else {
+        return Ok(None);
+    };
+
+    let want = top_k * if tag_filter.is_some() { ANN_CANDIDATE_FACTOR * 4 } else { ANN_CANDIDATE_FACTOR };
+    let candidates = {
+        let mut index = index.lock().unwrap_or_else(|e| e.into_inner());
+        match sync_index(conn, &mut index, kb_id) {
+            Ok(_) => {}
+            Err(e) => {
+                // 一般是换了 embedding 模型导致维度变化，整个索引重建
+                log::warn!("[KB] Rebuilding ANN index for {}: {}", kb_id, e);
+                drop(index);
+                ann.remove(kb_id);
+                return Ok(None);
+            }
+        }
+        if index.needs_rebuild() {
+            log::info!("[KB] ANN index for {} has too many deleted entries, rebuilding", kb_id);
+            drop(index);
+            ann.remove(kb_id);
+            return Ok(None);
+        }
+        if index.dim() != query_vector.len() {
+            return Ok(None);
+        }
+        index.search(query_vector, want)
+    };
+
+    // 加密知识库不建 ANN 索引，走到这里的内容都是明文
+    let (results, matched) = rescore(conn, None, &candidates, query_vector, top_k, tag_filter)?;
+
+    // 过滤之后不够 top_k 个，而索引里可能还有更多符合条件的，交给精确扫描
+    if matched < top_k && candidates.len() == want {
+        return Ok(None);
+    }
+
+    log::info!(
+        "Vector search for {} used ANN index: {} candidates, {} matched",
+        kb_id,
+        candidates.len(),
+        matched
+    );
+    Ok(Some(results))
+}
+
+/// binary 量化的知识库：先在符号位上按汉明距离粗筛出 top_k 的 BINARY_RESCORE_FACTOR 倍候选，
+/// 再用 int8 向量精确重排。没有符号位的旧向量（切换量化方式之前写入的）现场计算
+fn binary_search(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    query_vector: &[f32],
+    top_k: usize,
+    tag_filter: &Option<String>,
+) -> Result<Vec<SearchHit>, KnowledgeBaseError> {
+    let query_code = binary_code(query_vector);
+    let keep = top_k * BINARY_RESCORE_FACTOR;
+
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            SELECT v.chunk_id, v.code, CASE WHEN v.code IS NULL THEN v.vector END
+            FROM vectors v
+            JOIN documents d ON v.document_id = d.id
+            WHERE v.kb_id = ?1 AND {}
+            "#,
+            document_filter_clause(2)
+        ))
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    let rows = stmt
+        .query_map(rusqlite::params![kb_id, tag_filter], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<Vec<u8>>>(1)?, row.get::<_, Option<Vec<u8>>>(2)?))
+        })
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    // 汉明距离最小的 keep 个：最大堆，超出时弹出距离最大的
+    let mut heap: std::collections::BinaryHeap<(u32, String)> = std::collections::BinaryHeap::with_capacity(keep + 1);
+    let mut scanned = 0;
+    for row in rows {
+        let (chunk_id, code, vector) = row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        scanned += 1;
+        let code = match (code, vector) {
+            (Some(code), _) => code,
+            (None, Some(vector)) => binary_code(&bytes_to_vector(&vector)),
+            (None, None) => continue,
+        };
+        heap.push((hamming_distance(&query_code, &code), chunk_id));
+        if heap.len() > keep {
+            heap.pop();
+        }
+    }
+
+    let candidates: Vec<String> = heap.into_iter().map(|(_, chunk_id)| chunk_id).collect();
+    // 加密知识库不支持 binary 量化，内容都是明文
+    let (results, _) = rescore(conn, None, &candidates, query_vector, top_k, tag_filter)?;
+    log::info!(
+        "Vector search for {} scanned {} binary codes, rescored {} candidates",
+        kb_id,
+        scanned,
+        candidates.len()
+    );
+    Ok(results)
+}
+
+/// 把知识库的全部向量读进内存；按第一个向量的维度估算放不进缓存预算时返回 None
+fn load_cached_vectors(
+    conn: &rusqlite::Connection,
+    cache: &VectorCache,
+    kb_id: &str,
+    vector_count: usize,
+) -> Result<Option<Vec<CachedVector>>, KnowledgeBaseError> {
+    use rusqlite::OptionalExtension;
+    let first: Option<Vec<u8>> = conn
+        .query_row("SELECT vector FROM vectors WHERE kb_id = ?1 LIMIT 1", [kb_id], |row| row.get(0))
+        .optional()
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    let Some(first) = first else {
+        return Ok(None);
+    };
+    if !cache.fits(vector_count, bytes_to_vector(&first).len()) {
+        return Ok(None);
+    }
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT v.chunk_id, v.document_id, v.vector FROM vectors v JOIN chunks c ON v.chunk_id = c.id
+             WHERE v.kb_id = ?1 AND {} AND {}",
+            NOT_COLLAPSED,
+            NOT_TRASHED
+        ))
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    let vectors = stmt
+        .query_map([kb_id], |row| {
+            Ok(CachedVector {
+                chunk_id: row.get(0)?,
+                document_id: row.get(1)?,
+                vector: bytes_to_vector(&row.get::<_, Vec<u8>>(2)?),
+            })
+        })
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    log::info!("[KB] Loaded {} vectors of {} into cache", vectors.len(), kb_id);
+    Ok(Some(vectors))
+}
+
+/// 在缓存的向量上精确检索：内存里算出前 top_k 个分块，再交给 rescore 读出内容
+fn cached_search(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    cipher: Option<&KbCipher>,
+    vectors: &[CachedVector],
+    query_vector: &[f32],
+    top_k: usize,
+    tag_filter: &Option<String>,
+) -> Result<Vec<SearchHit>, KnowledgeBaseError> {
+    // 有标签过滤时先查出符合条件的文档
+    let allowed: Option<std::collections::HashSet<String>> = match tag_filter {
+        None => None,
+        Some(_) => {
+            let mut stmt = conn
+                .prepare(&format!("SELECT d.id FROM documents d WHERE d.kb_id = ?1 AND {}", document_filter_clause(2)))
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let ids = stmt
+                .query_map(rusqlite::params![kb_id, tag_filter], |row| row.get(0))
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+                .collect::<Result<_, _>>()
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            Some(ids)
+        }
+    };
+
+    // 向量多时分段交给多个线程，每个线程各自维护一个 top_k 堆，最后合并
+    let scorer = CosineScorer::new(query_vector);
+    let threads = if vectors.len() >= PARALLEL_SCAN_MIN_VECTORS {
+        std::thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_SCAN_THREADS)
+    } else {
+        1
+    };
+    let mut heap = std::collections::BinaryHeap::with_capacity(top_k + 1);
+    if threads > 1 {
+        let segment = vectors.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = vectors
+                .chunks(segment)
+                .enumerate()
+                .map(|(i, part)| {
+                    let (scorer, allowed) = (&scorer, allowed.as_ref());
+                    scope.spawn(move || scan_top_k(part, i * segment, scorer, allowed, top_k))
+                })
+                .collect();
+            for handle in handles {
+                // 扫描线程里只有算术运算，不会 panic
+                for item in handle.join().unwrap_or_default() {
+                    push_capped(&mut heap, item.0, top_k);
+                }
+            }
+        });
+    } else {
+        heap = scan_top_k(vectors, 0, &scorer, allowed.as_ref(), top_k);
+    }
+
+    let candidates: Vec<String> = heap.into_iter().map(|r| vectors[r.0.index].chunk_id.clone()).collect();
+    let (results, _) = rescore(conn, cipher, &candidates, query_vector, top_k, tag_filter)?;
+    log::info!(
+        "Vector search for {} scanned {} cached vectors, returned {} results",
+        kb_id,
+        vectors.len(),
+        results.len()
+    );
+    Ok(results)
+}
+
+/// 在一段缓存向量上打分，只保留分数最高的 top_k 个（index 加上 offset 是在整个缓存里的下标）
+fn scan_top_k(
+    vectors: &[CachedVector],
+    offset: usize,
+    scorer: &CosineScorer,
+    allowed: Option<&std::collections::HashSet<String>>,
+    top_k: usize,
+) -> std::collections::BinaryHeap<std::cmp::Reverse<ScoredIndex>> {
+    let mut heap = std::collections::BinaryHeap::with_capacity(top_k + 1);
+    for (i, v) in vectors.iter().enumerate() {
+        if allowed.is_some_and(|docs| !docs.contains(&v.document_id)) {
+            continue;
+        }
+        push_capped(&mut heap, ScoredIndex { score: scorer.score(&v.vector), index: offset + i }, top_k);
+    }
+    heap
+}
+
+/// 读出候选分块的原始向量，精确计算余弦相似度后取前 top_k 个（同时应用标签过滤），
+/// 返回结果和通过过滤的候选数；cipher 是知识库的加密器，用来解密分块内容
+fn rescore(
+    conn: &rusqlite::Connection,
+    cipher: Option<&KbCipher>,
+    candidates: &[String],
+    query_vector: &[f32],
+    top_k: usize,
+    tag_filter: &Option<String>,
+) -> Result<(Vec<SearchHit>, usize), KnowledgeBaseError> {
+    let candidate_ids = serde_json::to_string(candidates)
+        .map_err(|e| KnowledgeBaseError::RetrievalError(e.to_string()))?;
+    let scorer = CosineScorer::new(query_vector);
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            SELECT v.chunk_id, v.document_id, c.content, v.vector
+            FROM vectors v
+            JOIN chunks c ON v.chunk_id = c.id
+            JOIN documents d ON v.document_id = d.id
+            WHERE v.chunk_id IN (SELECT value FROM json_each(?1)) AND {} AND {} AND {}
+            "#,
+            document_filter_clause(2),
+            NOT_COLLAPSED,
+            NOT_TRASHED
+        ))
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    let rows = stmt
+        .query_map(rusqlite::params![&candidate_ids, tag_filter], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, Vec<u8>>(3)?))
+        })
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+
+    let mut heap: std::collections::BinaryHeap<std::cmp::Reverse<ScoredChunk>> =
+        std::collections::BinaryHeap::with_capacity(top_k + 1);
+    let mut matched = 0;
+    for row in rows {
+        let (chunk_id, document_id, content, vector_bytes) =
+            row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+        matched += 1;
+        let score = scorer.score(&bytes_to_vector(&vector_bytes));
+        push_capped(&mut heap, ScoredChunk { score, chunk_id, document_id, content }, top_k);
+    }
+
+    let results = drain_sorted_desc(heap)
+        .into_iter()
+        .map(|s| (s.chunk_id, s.document_id, open_text(cipher, s.content), s.score))
+        .collect();
+    Ok((results, matched))
+}
+
+/// 知识库的向量量化方式，知识库不存在时按不量化处理
+pub(crate) fn kb_quantization(conn: &rusqlite::Connection, kb_id: &str) -> Result<VectorQuantization, KnowledgeBaseError> {
+    use rusqlite::OptionalExtension;
+    let value: Option<String> = conn
+        .query_row(
+            "SELECT COALESCE(vector_quantization, 'none') FROM knowledge_bases WHERE id = ?1",
+            [kb_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    Ok(value.as_deref().map(VectorQuantization::parse).unwrap_or_default())
+}
+
+/// 检索结果：(chunk_id, document_id, content, score)
+type SearchHit = (String, String, String, f32);
+
+/// 点积循环同时累加的路数，8 个 f32 正好是一个 AVX 寄存器
+const SIMD_LANES: usize = 8;
+
+/// 缓存里的向量数达到这个值时分给多个线程打分，更少时开线程不划算
+const PARALLEL_SCAN_MIN_VECTORS: usize = 20_000;
+/// 打分最多用的线程数
+const MAX_SCAN_THREADS: usize = 8;
+
+/// ANN 检索时取 top_k 的多少倍作为候选，再用原始向量精确重排
+const ANN_CANDIDATE_FACTOR: usize = 4;
+
 /// 向量检索过程中，top-k 最小堆里保存的一个打分候选项。
 /// 排序只依据 `score`；NaN 分数（来自格式异常的 embedding）会被视为最小值，
 /// 因此总是最先被淘汰，不会挤占正常结果的位置。
@@ -258,26 +695,53 @@ impl PartialOrd for ScoredChunk {
 }
 impl Ord for ScoredChunk {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        use std::cmp::Ordering;
-        // 把 NaN 严格当作最小值处理，这样 NaN 候选项总会先于任何有效打分的候选项
-        // 被淘汰，绝不会顶替掉一个真实的结果。
-        match self.score.partial_cmp(&other.score) {
-            Some(ord) => ord,
-            None => match (self.score.is_nan(), other.score.is_nan()) {
-                (true, true) => Ordering::Equal,
-                (true, false) => Ordering::Less,
-                (false, true) => Ordering::Greater,
-                (false, false) => Ordering::Equal, // 对有限的 f32 而言这个分支不可能走到
-            },
-        }
+        compare_scores(self.score, other.score)
+    }
+}
+
+/// 比较两个分数。把 NaN 严格当作最小值处理，这样 NaN 候选项总会先于任何有效打分的
+/// 候选项被淘汰，绝不会顶替掉一个真实的结果。
+fn compare_scores(a: f32, b: f32) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    match a.partial_cmp(&b) {
+        Some(ord) => ord,
+        None => match (a.is_nan(), b.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => Ordering::Equal, // 对有限的 f32 而言这个分支不可能走到
+        },
+    }
+}
+
+/// 在缓存向量上检索时的打分候选项，只记下标，不复制字符串
+struct ScoredIndex {
+    score: f32,
+    index: usize,
+}
+
+impl PartialEq for ScoredIndex {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+impl Eq for ScoredIndex {}
+impl PartialOrd for ScoredIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for ScoredIndex {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        compare_scores(self.score, other.score)
     }
 }
 
 /// 把一个候选项推入有界的 top-k 最小堆，一旦堆的大小超过 `top_k` 就淘汰当前最低分的那个。
 /// 使峰值内存维持在 O(top_k)。
-fn push_capped(
-    heap: &mut std::collections::BinaryHeap<std::cmp::Reverse<ScoredChunk>>,
-    item: ScoredChunk,
+fn push_capped<T: Ord>(
+    heap: &mut std::collections::BinaryHeap<std::cmp::Reverse<T>>,
+    item: T,
     top_k: usize,
 ) {
     heap.push(std::cmp::Reverse(item));
@@ -295,16 +759,70 @@ fn drain_sorted_desc(
     scored
 }
 
+/// 按标签和文档范围过滤文档的 SQL 条件，文档表的别名需要是 d
+///
+/// 第 param 个参数传 document_filter_param 的结果：为 NULL 时不过滤；否则指定了标签时
+/// 只保留至少带有其中一个标签的文档，指定了文档范围（摘要预筛选的结果）时只保留其中的文档。
+pub(crate) fn document_filter_clause(param: usize) -> String {
+    format!(
+        "(?{param} IS NULL OR ((json_extract(?{param}, '$.tags') IS NULL \
+         OR EXISTS (SELECT 1 FROM json_each(COALESCE(d.tags, '[]')) t \
+         WHERE t.value IN (SELECT value FROM json_each(?{param}, '$.tags')))) \
+         AND (json_extract(?{param}, '$.documents') IS NULL \
+         OR d.id IN (SELECT value FROM json_each(?{param}, '$.documents')))))"
+    )
+}
+
+/// document_filter_clause 的参数：没有指定标签也没有限定文档时为 None（不过滤）
+pub(crate) fn document_filter_param(tags: &[String], documents: Option<&[String]>) -> Option<String> {
+    if tags.is_empty() && documents.is_none() {
+        return None;
+    }
+    let tags = (!tags.is_empty()).then_some(tags);
+    serde_json::to_string(&serde_json::json!({ "tags": tags, "documents": documents })).ok()
+}
+
+
+/// 多行 INSERT 每条语句最多写入的行数，参数个数保持在 SQLite 旧版本的上限（999）以内
+pub(crate) const INSERT_BATCH_ROWS: usize = 64;
+
+/// 把多行数据拼成 `head VALUES row, row, ...` 分批执行，返回受影响的行数
+///
+/// row 是一行的 VALUES 模板，例如 `(?, ?, ?)`，其中 ? 的个数要和每行的参数个数一致。
+/// 满批的语句相同，走 prepare_cached 只编译一次。需要原子性时由调用方包在事务里。
+pub(crate) fn insert_rows(
+    conn: &rusqlite::Connection,
+    head: &str,
+    row: &str,
+    rows: &[Vec<&dyn rusqlite::ToSql>],
+) -> rusqlite::Result<usize> {
+    let mut affected = 0;
+    for batch in rows.chunks(INSERT_BATCH_ROWS) {
+        let sql = format!("{} VALUES {}", head, vec![row; batch.len()].join(", "));
+        let mut stmt = conn.prepare_cached(&sql)?;
+        affected += stmt.execute(rusqlite::params_from_iter(batch.iter().flatten()))?;
+    }
+    Ok(affected)
+}
+
 /// 把向量（f32 数组）转换为字节序列
-fn vector_to_bytes(vector: &[f32]) -> Vec<u8> {
+pub(crate) fn vector_to_bytes(vector: &[f32]) -> Vec<u8> {
     vector
         .iter()
         .flat_map(|&f| f.to_le_bytes())
         .collect()
 }
 
-/// 把字节序列转换回向量（f32 数组）
-fn bytes_to_vector(bytes: &[u8]) -> Vec<f32> {
+/// 把字节序列转换回向量（f32 数组），加密的向量先解密，int8 量化的向量会先反量化
+///
+/// 加密向量无法解密时返回空向量（维度对不上，相似度按 0 算）。
+pub(crate) fn bytes_to_vector(bytes: &[u8]) -> Vec<f32> {
+    if is_sealed_vector(bytes) {
+        return open_vector(bytes).map(|opened| bytes_to_vector(&opened)).unwrap_or_default();
+    }
+    if is_int8(bytes) {
+        return decode_int8(bytes);
+    }
     bytes
         .chunks_exact(4)
         .map(|chunk| {
@@ -316,20 +834,55 @@ fn bytes_to_vector(bytes: &[u8]) -> Vec<f32> {
 }
 
 /// 计算两个向量之间的余弦相似度
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-    if a.len() != b.len() {
-        return 0.0;
-    }
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    CosineScorer::new(a).score(b)
+}
 
-    let dot_product: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
-    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
-    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+/// 固定问题向量、对大量向量打分：问题向量的模只算一次
+pub(crate) struct CosineScorer<'a> {
+    query: &'a [f32],
+    norm: f32,
+}
 
-    if norm_a == 0.0 || norm_b == 0.0 {
-        return 0.0;
+impl<'a> CosineScorer<'a> {
+    pub(crate) fn new(query: &'a [f32]) -> Self {
+        Self { query, norm: dot_and_norm(query, query).0.sqrt() }
     }
 
-    dot_product / (norm_a * norm_b)
+    /// 和问题向量的余弦相似度，维度不同或者有零向量时为 0
+    pub(crate) fn score(&self, vector: &[f32]) -> f32 {
+        if self.query.len() != vector.len() {
+            return 0.0;
+        }
+        let (dot, norm_sq) = dot_and_norm(self.query, vector);
+        let norm = norm_sq.sqrt();
+        if self.norm == 0.0 || norm == 0.0 {
+            return 0.0;
+        }
+        dot / (self.norm * norm)
+    }
+}
+
+/// 点积和 b 的模的平方，一趟算完。分成 SIMD_LANES 路独立累加，
+/// 打破累加的先后依赖，编译器能把循环向量化成 SIMD 指令
+fn dot_and_norm(a: &[f32], b: &[f32]) -> (f32, f32) {
+    let mut dot = [0.0f32; SIMD_LANES];
+    let mut norm = [0.0f32; SIMD_LANES];
+    let (chunks_a, chunks_b) = (a.chunks_exact(SIMD_LANES), b.chunks_exact(SIMD_LANES));
+    let (rest_a, rest_b) = (chunks_a.remainder(), chunks_b.remainder());
+    for (x, y) in chunks_a.zip(chunks_b) {
+        for lane in 0..SIMD_LANES {
+            dot[lane] += x[lane] * y[lane];
+            norm[lane] += y[lane] * y[lane];
+        }
+    }
+    let mut dot: f32 = dot.iter().sum();
+    let mut norm: f32 = norm.iter().sum();
+    for (x, y) in rest_a.iter().zip(rest_b) {
+        dot += x * y;
+        norm += y * y;
+    }
+    (dot, norm)
 }
 
 /// 元数据用的 SQLite schema
@@ -415,6 +968,48 @@ pub fn init_sqlite_tables(conn: &rusqlite::Connection) -> Result<(), rusqlite::E
         );
     }
 
+    // 若不存在则添加 chunk_unit（分块大小的计量单位，旧知识库按字符数）
+    if !table_info.contains(&"chunk_unit".to_string()) {
+        let _ = conn.execute(
+            "ALTER TABLE knowledge_bases ADD COLUMN chunk_unit TEXT NOT NULL DEFAULT 'chars'",
+            [],
+        );
+    }
+
+    // 若不存在则添加 separators（自定义分块分隔符的 JSON 数组，NULL 表示使用默认分隔符）
+    if !table_info.contains(&"separators".to_string()) {
+        let _ = conn.execute("ALTER TABLE knowledge_bases ADD COLUMN separators TEXT", []);
+    }
+    // 若不存在则添加 retrieval_defaults（知识库自己的默认检索参数，JSON）
+    if !table_info.contains(&"retrieval_defaults".to_string()) {
+        let _ = conn.execute("ALTER TABLE knowledge_bases ADD COLUMN retrieval_defaults TEXT", []);
+    }
+    // 若不存在则添加 vector_quantization（向量的量化存储方式，旧知识库不量化）
+    if !table_info.contains(&"vector_quantization".to_string()) {
+        let _ = conn.execute(
+            "ALTER TABLE knowledge_bases ADD COLUMN vector_quantization TEXT NOT NULL DEFAULT 'none'",
+            [],
+        );
+    }
+    // 若不存在则添加 parent_chunk_size（父子分块的父块大小，0 表示不开启）
+    if !table_info.contains(&"parent_chunk_size".to_string()) {
+        let _ = conn.execute(
+            "ALTER TABLE knowledge_bases ADD COLUMN parent_chunk_size INTEGER NOT NULL DEFAULT 0",
+            [],
+        );
+    }
+    // 若不存在则添加 encrypted（分块内容和向量是否加密存储，只能在创建时选择）
+    if !table_info.contains(&"encrypted".to_string()) {
+        let _ = conn.execute("ALTER TABLE knowledge_bases ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0", []);
+    }
+    // 若不存在则添加 context_template / context_language（检索上下文的提示词模板，NULL 表示用默认模板）
+    if !table_info.contains(&"context_template".to_string()) {
+        let _ = conn.execute("ALTER TABLE knowledge_bases ADD COLUMN context_template TEXT", []);
+    }
+    if !table_info.contains(&"context_language".to_string()) {
+        let _ = conn.execute("ALTER TABLE knowledge_bases ADD COLUMN context_language TEXT NOT NULL DEFAULT 'zh'", []);
+    }
+
     // 文档表
     conn.execute(
         r#"
@@ -435,6 +1030,32 @@ pub fn init_sqlite_tables(conn: &rusqlite::Connection) -> Result<(), rusqlite::E
         [],
     )?;
 
+    // 若不存在则添加 import_stage（后台导入当前所处的阶段）
+    let doc_columns: Vec<String> = conn
+        .prepare("PRAGMA table_info(documents)")?
+        .query_map([], |row| row.get(1))?
+        .filter_map(|r| r.ok())
+        .collect();
+    if !doc_columns.contains(&"import_stage".to_string()) {
+        let _ = conn.execute("ALTER TABLE documents ADD COLUMN import_stage TEXT", []);
+    }
+    // 若不存在则添加 source（导入来源：文件路径或网页地址）
+    if !doc_columns.contains(&"source".to_string()) {
+        let _ = conn.execute("ALTER TABLE documents ADD COLUMN source TEXT", []);
+    }
+    // 若不存在则添加 duplicate_of（按“关联”导入的重复文档指向的已有文档）
+    if !doc_columns.contains(&"duplicate_of".to_string()) {
+        let _ = conn.execute("ALTER TABLE documents ADD COLUMN duplicate_of TEXT", []);
+    }
+    // 若不存在则添加 tags（用户给文档加的标签，JSON 字符串数组）
+    if !doc_columns.contains(&"tags".to_string()) {
+        let _ = conn.execute("ALTER TABLE documents ADD COLUMN tags TEXT", []);
+    }
+    // 若不存在则添加 deleted_at（移进回收站的时间，见 trash 模块）
+    if !doc_columns.contains(&"deleted_at".to_string()) {
+        let _ = conn.execute("ALTER TABLE documents ADD COLUMN deleted_at INTEGER", []);
+    }
+
     // chunks 表 —— 存放供关键词检索使用的实际文本内容
     conn.execute(
         r#"
@@ -451,6 +1072,44 @@ pub fn init_sqlite_tables(conn: &rusqlite::Connection) -> Result<(), rusqlite::E
         [],
     )?;
 
+    // 若不存在则添加 heading_path（Markdown 分块所在的标题路径）
+    let chunk_columns: Vec<String> = conn
+        .prepare("PRAGMA table_info(chunks)")?
+        .query_map([], |row| row.get(1))?
+        .filter_map(|r| r.ok())
+        .collect();
+    if !chunk_columns.contains(&"heading_path".to_string()) {
+        let _ = conn.execute("ALTER TABLE chunks ADD COLUMN heading_path TEXT", []);
+    }
+    // 若不存在则添加 parent_chunk_id（父子分块时所属的父块）
+    if !chunk_columns.contains(&"parent_chunk_id".to_string()) {
+        let _ = conn.execute("ALTER TABLE chunks ADD COLUMN parent_chunk_id TEXT", []);
+    }
+    // 若不存在则添加 duplicate_of（近似重复时指向保留的那一块，检索时跳过，见 dedup 模块）
+    if !chunk_columns.contains(&"duplicate_of".to_string()) {
+        let _ = conn.execute("ALTER TABLE chunks ADD COLUMN duplicate_of TEXT", []);
+    }
+    // 若不存在则添加 page、start_offset、end_offset（分块在原文里的页码和字符偏移，引用时用）
+    for column in ["page", "start_offset", "end_offset"] {
+        if !chunk_columns.iter().any(|c| c == column) {
+            let _ = conn.execute(&format!("ALTER TABLE chunks ADD COLUMN {} INTEGER", column), []);
+        }
+    }
+
+    // 父块表 —— 父子分块时检索结果实际返回的较大段落，不参与向量和关键词检索
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS parent_chunks (
+            id TEXT PRIMARY KEY,
+            document_id TEXT NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+            kb_id TEXT NOT NULL REFERENCES knowledge_bases(id) ON DELETE CASCADE,
+            content TEXT NOT NULL,
+            heading_path TEXT
+        )
+        "#,
+        [],
+    )?;
+
     // vectors 表 —— 存放 embedding 向量
     conn.execute(
         r#"
@@ -465,19 +1124,181 @@ pub fn init_sqlite_tables(conn: &rusqlite::Connection) -> Result<(), rusqlite::E
         [],
     )?;
 
-    // 用于全文检索的 FTS5 虚拟表（可选，取决于 FTS5 是否可用）
-    // 对应 #29、#30 的修复：加入 kb_id 列以实现知识库之间的隔离
-    let _ = conn.execute(
+    // 若不存在则添加 code（binary 量化时向量的符号位，用于粗筛）
+    let vector_columns: Vec<String> = conn
+        .prepare("PRAGMA table_info(vectors)")?
+        .query_map([], |row| row.get(1))?
+        .filter_map(|r| r.ok())
+        .collect();
+    if !vector_columns.contains(&"code".to_string()) {
+        let _ = conn.execute("ALTER TABLE vectors ADD COLUMN code BLOB", []);
+    }
+
+    // 导入时解析、清洗后的文档全文，用来在应用里阅读原文；单独成表，列文档时不会读到大段文本
+    conn.execute(
         r#"
-        CREATE VIRTUAL TABLE IF NOT EXISTS chunks_fts USING fts5(
-            kb_id,
-            content,
-            content_rowid=rowid,
-            tokenize='porter'
+        CREATE TABLE IF NOT EXISTS document_contents (
+            document_id TEXT PRIMARY KEY REFERENCES documents(id) ON DELETE CASCADE,
+            content TEXT NOT NULL
         )
         "#,
         [],
-    );
+    )?;
+
+    // 文档摘要和摘要向量（f32 原样存储），检索时先按摘要挑文档；content_hash 是写摘要时正文的哈希
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS document_summaries (
+            document_id TEXT PRIMARY KEY REFERENCES documents(id) ON DELETE CASCADE,
+            kb_id TEXT NOT NULL,
+            summary TEXT NOT NULL,
+            vector BLOB,
+            content_hash TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_document_summaries_kb ON document_summaries(kb_id)",
+        [],
+    )?;
+
+    // vectors 的变更日志：触发器记下每次写入和删除，ANN 索引按序号增量同步。
+    // 加密知识库不建索引，不记日志；旧版本建的触发器没有这个条件，每次都重新创建
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS vector_log (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            kb_id TEXT NOT NULL,
+            chunk_id TEXT NOT NULL,
+            deleted INTEGER NOT NULL DEFAULT 0
+        )
+        "#,
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_vector_log_kb ON vector_log(kb_id, seq)",
+        [],
+    )?;
+    conn.execute_batch(
+        r#"
+        DROP TRIGGER IF EXISTS vectors_log_insert;
+        DROP TRIGGER IF EXISTS vectors_log_update;
+        DROP TRIGGER IF EXISTS vectors_log_delete;
+        CREATE TRIGGER vectors_log_insert AFTER INSERT ON vectors
+        WHEN NOT EXISTS (SELECT 1 FROM knowledge_bases WHERE id = NEW.kb_id AND encrypted = 1) BEGIN
+            INSERT INTO vector_log (kb_id, chunk_id, deleted) VALUES (NEW.kb_id, NEW.chunk_id, 0);
+        END;
+        CREATE TRIGGER vectors_log_update AFTER UPDATE OF vector ON vectors
+        WHEN NOT EXISTS (SELECT 1 FROM knowledge_bases WHERE id = NEW.kb_id AND encrypted = 1) BEGIN
+            INSERT INTO vector_log (kb_id, chunk_id, deleted) VALUES (NEW.kb_id, NEW.chunk_id, 0);
+        END;
+        CREATE TRIGGER vectors_log_delete AFTER DELETE ON vectors
+        WHEN NOT EXISTS (SELECT 1 FROM knowledge_bases WHERE id = OLD.kb_id AND encrypted = 1) BEGIN
+            INSERT INTO vector_log (kb_id, chunk_id, deleted) VALUES (OLD.kb_id, OLD.chunk_id, 1);
+        END;
+        "#,
+    )?;
+    // 加密知识库不建 ANN 索引，旧版本触发器给它们记下的日志用不到
+    conn.execute(
+        "DELETE FROM vector_log WHERE kb_id IN (SELECT id FROM knowledge_bases WHERE encrypted = 1)",
+        [],
+    )?;
+
+    // 每个知识库的向量版本号：vectors 每次写入/删除都加一，向量缓存据此判断是否过期
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS vector_versions (
+            kb_id TEXT PRIMARY KEY,
+            version INTEGER NOT NULL DEFAULT 0
+        )
+        "#,
+        [],
+    )?;
+    conn.execute_batch(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS vectors_version_insert AFTER INSERT ON vectors BEGIN
+            INSERT INTO vector_versions (kb_id, version) VALUES (NEW.kb_id, 1)
+            ON CONFLICT(kb_id) DO UPDATE SET version = version + 1;
+        END;
+        CREATE TRIGGER IF NOT EXISTS vectors_version_update AFTER UPDATE ON vectors BEGIN
+            INSERT INTO vector_versions (kb_id, version) VALUES (NEW.kb_id, 1)
+            ON CONFLICT(kb_id) DO UPDATE SET version = version + 1;
+        END;
+        CREATE TRIGGER IF NOT EXISTS vectors_version_delete AFTER DELETE ON vectors BEGIN
+            INSERT INTO vector_versions (kb_id, version) VALUES (OLD.kb_id, 1)
+            ON CONFLICT(kb_id) DO UPDATE SET version = version + 1;
+        END;
+        "#,
+    )?;
+
+    // 更换 embedding 模型的迁移任务（每个知识库最多一个）和还没切换进 vectors 的新向量（f32 原样存储）
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS reembed_jobs (
+            kb_id TEXT PRIMARY KEY,
+            embedding_api_config_id TEXT NOT NULL,
+            embedding_provider TEXT NOT NULL,
+            embedding_model TEXT NOT NULL,
+            embedding_base_url TEXT NOT NULL DEFAULT '',
+            error_message TEXT,
+            started_at INTEGER NOT NULL
+        )
+        "#,
+        [],
+    )?;
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS reembed_vectors (
+            chunk_id TEXT PRIMARY KEY,
+            kb_id TEXT NOT NULL,
+            vector BLOB NOT NULL
+        )
+        "#,
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_reembed_vectors_kb ON reembed_vectors(kb_id)",
+        [],
+    )?;
+
+    // Embedding API 配置，知识库的 embedding_api_config_id 指向这里；API Key 本身仍在 keyring，key_ref 是条目名
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS embedding_configs (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL DEFAULT '',
+            provider TEXT NOT NULL,
+            model TEXT NOT NULL,
+            base_url TEXT NOT NULL DEFAULT '',
+            key_ref TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )
+        "#,
+        [],
+    )?;
+    // 迁移：以前配置只存在前端，按知识库上快照的服务商/模型补出被引用的配置（名称留空，前端同步时补上）
+    conn.execute(
+        r#"
+        INSERT OR IGNORE INTO embedding_configs (id, name, provider, model, base_url, key_ref, created_at, updated_at)
+        SELECT embedding_api_config_id, '',
+               CASE WHEN COALESCE(embedding_provider, '') = '' OR COALESCE(embedding_model, '') = '' THEN 'openai' ELSE embedding_provider END,
+               CASE WHEN COALESCE(embedding_provider, '') = '' OR COALESCE(embedding_model, '') = '' THEN 'text-embedding-3-small' ELSE embedding_model END,
+               CASE WHEN COALESCE(embedding_provider, '') = '' OR COALESCE(embedding_model, '') = '' THEN '' ELSE COALESCE(embedding_base_url, '') END,
+               'emb_' || embedding_api_config_id, MIN(created_at), MIN(created_at)
+        FROM knowledge_bases WHERE embedding_api_config_id != ''
+        GROUP BY embedding_api_config_id
+        "#,
+        [],
+    )?;
+
+    // 用于全文检索的 FTS5 虚拟表（可选，取决于 FTS5 是否可用）
+    // 内容写入前做中日韩预分词，见 fts 模块
+    if let Err(e) = ensure_fts_table(conn) {
+        log::warn!("[KB] Full-text index unavailable: {}", e);
+    }
 
     // 索引
     conn.execute(
@@ -496,6 +1317,10 @@ pub fn init_sqlite_tables(conn: &rusqlite::Connection) -> Result<(), rusqlite::E
         "CREATE INDEX IF NOT EXISTS idx_chunk_kb ON chunks(kb_id)",
         [],
     )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_parent_chunk_doc ON parent_chunks(document_id)",
+        [],
+    )?;
     // 关键词检索用的索引
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_chunk_content ON chunks(content)",
@@ -581,4 +1406,103 @@ mod tests {
         let ids = heap_top_k(&scores, 10);
         assert_eq!(ids, vec!["1".to_string(), "2".to_string(), "0".to_string()]);
     }
+
+    #[test]
+    fn lane_split_cosine_matches_plain_formula() {
+        // 长度不是 SIMD_LANES 的倍数，余下的几维也要算进去
+        let a: Vec<f32> = (0..19).map(|i| (i as f32 * 0.37).sin()).collect();
+        let b: Vec<f32> = (0..19).map(|i| (i as f32 * 0.11).cos()).collect();
+        let dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let expected = dot / (norm(&a) * norm(&b));
+
+        assert!((cosine_similarity(&a, &b) - expected).abs() < 1e-5);
+        assert_eq!(cosine_similarity(&a, &b[..18]), 0.0);
+        assert_eq!(cosine_similarity(&a, &[0.0; 19]), 0.0);
+    }
+
+    #[test]
+    fn segmented_scan_keeps_global_offsets() {
+        let vectors: Vec<CachedVector> = (0..10)
+            .map(|i| CachedVector {
+                chunk_id: i.to_string(),
+                document_id: if i % 2 == 0 { "even" } else { "odd" }.to_string(),
+                vector: vec![1.0, i as f32],
+            })
+            .collect();
+        let query = [0.0, 1.0];
+        let scorer = CosineScorer::new(&query);
+        let best = |heap: std::collections::BinaryHeap<std::cmp::Reverse<ScoredIndex>>| {
+            let mut indexes: Vec<usize> = heap.into_iter().map(|r| r.0.index).collect();
+            indexes.sort();
+            indexes
+        };
+
+        assert_eq!(best(scan_top_k(&vectors[5..], 5, &scorer, None, 2)), vec![8, 9]);
+        let allowed = std::collections::HashSet::from(["even".to_string()]);
+        assert_eq!(best(scan_top_k(&vectors, 0, &scorer, Some(&allowed), 2)), vec![6, 8]);
+    }
+
+    #[test]
+    fn legacy_documents_table_gets_import_stage_column() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE documents (
+                id TEXT PRIMARY KEY, kb_id TEXT NOT NULL, filename TEXT NOT NULL, file_type TEXT NOT NULL,
+                file_size INTEGER, file_hash TEXT, content_preview TEXT, chunk_count INTEGER DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'processing', error_message TEXT, created_at INTEGER NOT NULL
+            )",
+            [],
+        ).unwrap();
+
+        init_sqlite_tables(&conn).unwrap();
+        // 迁移可以重复执行
+        init_sqlite_tables(&conn).unwrap();
+
+        let stage: Option<String> = conn
+            .query_row("SELECT import_stage FROM documents LIMIT 1", [], |row| row.get(0))
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })
+            .unwrap();
+        assert_eq!(stage, None);
+    }
+
+    #[test]
+    fn tag_filter_keeps_documents_with_any_requested_tag() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"CREATE TABLE documents (id TEXT, tags TEXT);
+               INSERT INTO documents VALUES ('a', '["合同","2024"]'), ('b', '["手册"]'), ('c', NULL);"#,
+        ).unwrap();
+
+        let matching = |tags: &[&str]| -> Vec<String> {
+            let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+            let sql = format!("SELECT id FROM documents d WHERE {} ORDER BY id", document_filter_clause(1));
+            let mut stmt = conn.prepare(&sql).unwrap();
+            stmt.query_map([document_filter_param(&tags, None)], |row| row.get(0))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+
+        assert_eq!(matching(&[]), vec!["a", "b", "c"]);
+        assert_eq!(matching(&["手册", "2024"]), vec!["a", "b"]);
+        assert_eq!(matching(&["不存在"]), Vec::<String>::new());
+
+        let within = |tags: &[&str], documents: &[&str]| -> Vec<String> {
+            let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+            let documents: Vec<String> = documents.iter().map(|d| d.to_string()).collect();
+            let sql = format!("SELECT id FROM documents d WHERE {} ORDER BY id", document_filter_clause(1));
+            let mut stmt = conn.prepare(&sql).unwrap();
+            stmt.query_map([document_filter_param(&tags, Some(&documents))], |row| row.get(0))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+        assert_eq!(within(&[], &["b", "c"]), vec!["b", "c"]);
+        assert_eq!(within(&["合同"], &["b", "c"]), Vec::<String>::new());
+        assert_eq!(within(&[], &[]), Vec::<String>::new());
+    }
 }
@@ -0,0 +1,276 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 近似重复分块的识别与折叠
+ *
+ * 功能说明:
+ * - 对知识库里的每个分块按字符 3-gram 计算 MinHash 签名，用 LSH 分段找出候选对，
+ *   签名估算的 Jaccard 相似度达到阈值的两块视为近似重复
+ * - 互相重复的分块归成一组，组里最早导入的那一块保留，其余标记 duplicate_of 指向它
+ * - 检索时跳过被标记的分块（见 NOT_COLLAPSED），页眉页脚、版权声明这类在每篇文档里
+ *   重复出现的内容只占一个结果位置
+ * - 被指向的分块删除、或者所在文档进了回收站后，标记自动失效，重复的分块重新参与检索
+ *
+ * 只在用户执行 dedup_chunks 时计算，每次先清掉旧标记再重新标记；clear_chunk_duplicates
+ * 撤销全部标记。标记不影响分块和向量本身，随时可以撤销。
+ */
+
+use super::commands::{with_conn, KbState};
+use super::encryption::{kb_cipher, open_text, KbCipher};
+use super::types::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use tauri::State;
+
+/// MinHash 签名长度
+const SIGNATURE_LEN: usize = 64;
+/// LSH 分段数，每段 SIGNATURE_LEN / LSH_BANDS 个哈希值；相似度约 0.5 以上的分块大概率成为候选
+const LSH_BANDS: usize = 16;
+/// 按字符几元组计算相似度（中文没有空格分词，用字符比用词稳定）
+const SHINGLE_CHARS: usize = 3;
+/// 没有指定阈值时，签名估算的 Jaccard 相似度达到这个值才算重复
+pub const DEFAULT_DUPLICATE_THRESHOLD: f32 = 0.9;
+
+/// 检索时排除被折叠的分块的 SQL 条件，分块表的别名需要是 c。
+/// 保留的那一块被删除、或者所在文档进了回收站时，重复块重新参与检索
+pub(crate) const NOT_COLLAPSED: &str = "(c.duplicate_of IS NULL OR NOT EXISTS (SELECT 1 FROM chunks kept \
+     JOIN documents kept_doc ON kept_doc.id = kept.document_id \
+     WHERE kept.id = c.duplicate_of AND kept_doc.deleted_at IS NULL))";
+
+/// 一次去重的结果
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DedupReport {
+    /// 参与比较的分块数
+    pub scanned: usize,
+    /// 被标记为重复的分块数
+    pub duplicates: usize,
+    /// 含有重复分块的组数
+    pub groups: usize,
+}
+
+/// 比较前的归一化：小写、空白合并成一个空格
+fn normalize(text: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_whitespace() {
+            if chars.last().is_some_and(|last: &char| *last != ' ') {
+                chars.push(' ');
+            }
+        } else {
+            chars.push(c);
+        }
+    }
+    if chars.last() == Some(&' ') {
+        chars.pop();
+    }
+    chars
+}
+
+/// splitmix64：把一个 64 位值打散，用来从一个基础哈希派生出多个独立的哈希函数
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// 文本的 MinHash 签名；空文本返回 None
+fn signature(text: &str) -> Option<[u64; SIGNATURE_LEN]> {
+    let chars = normalize(text);
+    if chars.is_empty() {
+        return None;
+    }
+    let mut sig = [u64::MAX; SIGNATURE_LEN];
+    // 比一个 shingle 还短的文本整体当作一个 shingle
+    for shingle in chars.windows(SHINGLE_CHARS.min(chars.len())) {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        shingle.hash(&mut hasher);
+        let base = hasher.finish();
+        for (i, slot) in sig.iter_mut().enumerate() {
+            *slot = (*slot).min(mix(base ^ (i as u64).wrapping_mul(0x2545_F491_4F6C_DD1D)));
+        }
+    }
+    Some(sig)
+}
+
+/// 两个签名估算的 Jaccard 相似度
+fn similarity(a: &[u64; SIGNATURE_LEN], b: &[u64; SIGNATURE_LEN]) -> f32 {
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f32 / SIGNATURE_LEN as f32
+}
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// 把近似重复的文本分组，返回 (重复项下标, 保留项下标)；保留项是组里下标最小的
+///
+/// 输入按导入先后排好序，下标小的先导入。
+pub(crate) fn find_duplicates(texts: &[String], threshold: f32) -> Vec<(usize, usize)> {
+    let signatures: Vec<Option<[u64; SIGNATURE_LEN]>> = texts.iter().map(|t| signature(t)).collect();
+    let rows = SIGNATURE_LEN / LSH_BANDS;
+    let mut parent: Vec<usize> = (0..texts.len()).collect();
+
+    for band in 0..LSH_BANDS {
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (i, sig) in signatures.iter().enumerate() {
+            if let Some(sig) = sig {
+                buckets.entry(&sig[band * rows..(band + 1) * rows]).or_default().push(i);
+            }
+        }
+        for members in buckets.values().filter(|m| m.len() > 1) {
+            let first = members[0];
+            for &other in &members[1..] {
+                let (Some(a), Some(b)) = (&signatures[first], &signatures[other]) else {
+                    continue;
+                };
+                if similarity(a, b) >= threshold {
+                    let (ra, rb) = (find(&mut parent, first), find(&mut parent, other));
+                    // 根总是组里下标最小的，也就是最早导入的那一块
+                    parent[ra.max(rb)] = ra.min(rb);
+                }
+            }
+        }
+    }
+
+    (0..texts.len())
+        .filter_map(|i| {
+            let root = find(&mut parent, i);
+            (root != i).then_some((i, root))
+        })
+        .collect()
+}
+
+/// 重新标记知识库里的近似重复分块，cipher 是知识库的加密器（用来解密分块内容）
+pub(crate) fn mark_duplicates(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    cipher: Option<&KbCipher>,
+    threshold: f32,
+) -> Result<DedupReport, rusqlite::Error> {
+    let chunks: Vec<(String, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.content FROM chunks c JOIN documents d ON c.document_id = d.id
+             WHERE c.kb_id = ?1 AND d.status = 'completed' AND d.deleted_at IS NULL
+             ORDER BY d.created_at, d.id, c.chunk_index",
+        )?;
+        let rows = stmt.query_map([kb_id], |row| Ok((row.get(0)?, open_text(cipher, row.get(1)?))))?;
+        rows.collect::<Result<_, _>>()?
+    };
+    let (ids, texts): (Vec<String>, Vec<String>) = chunks.into_iter().unzip();
+    let duplicates = find_duplicates(&texts, threshold);
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("UPDATE chunks SET duplicate_of = NULL WHERE kb_id = ?1", [kb_id])?;
+    {
+        let mut update = tx.prepare("UPDATE chunks SET duplicate_of = ?1 WHERE id = ?2")?;
+        for (duplicate, kept) in &duplicates {
+            update.execute([&ids[*kept], &ids[*duplicate]])?;
+        }
+    }
+    tx.commit()?;
+
+    let mut groups: Vec<usize> = duplicates.iter().map(|(_, kept)| *kept).collect();
+    groups.sort_unstable();
+    groups.dedup();
+    Ok(DedupReport { scanned: ids.len(), duplicates: duplicates.len(), groups: groups.len() })
+}
+
+/// 找出知识库里近似重复的分块并折叠（检索时只保留每组最早导入的一块）
+///
+/// threshold 是 0~1 之间的相似度，不传时用 DEFAULT_DUPLICATE_THRESHOLD。
+#[tauri::command]
+pub async fn dedup_chunks(
+    kb_id: String,
+    threshold: Option<f32>,
+    kb_state: State<'_, KbState>,
+) -> Result<DedupReport, KnowledgeBaseError> {
+    let threshold = threshold.unwrap_or(DEFAULT_DUPLICATE_THRESHOLD);
+    if !(0.5..=1.0).contains(&threshold) {
+        return Err(KnowledgeBaseError::InvalidConfig(format!("相似度阈值需要在 0.5 到 1 之间: {}", threshold)));
+    }
+    let id = kb_id.clone();
+    let report = with_conn(&kb_state.db_path, move |conn| {
+        let cipher = kb_cipher(&conn, &id)?;
+        mark_duplicates(&conn, &id, cipher.as_deref(), threshold).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+    })
+    .await?;
+    // 缓存里的向量是按标记前的分块载入的
+    kb_state.vector_store.drop_vector_cache(&kb_id);
+    log::info!("[KB] Dedup of {}: {:?}", kb_id, report);
+    Ok(report)
+}
+
+/// 撤销知识库里全部的重复标记，返回撤销的分块数
+#[tauri::command]
+pub async fn clear_chunk_duplicates(kb_id: String, kb_state: State<'_, KbState>) -> Result<usize, KnowledgeBaseError> {
+    let id = kb_id.clone();
+    let cleared = with_conn(&kb_state.db_path, move |conn| {
+        conn.execute("UPDATE chunks SET duplicate_of = NULL WHERE kb_id = ?1 AND duplicate_of IS NOT NULL", [&id])
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+    })
+    .await?;
+    kb_state.vector_store.drop_vector_cache(&kb_id);
+    Ok(cleared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_identical_boilerplate_collapses_into_the_first_copy() {
+        let footer = "版权所有 © 2024 某某科技有限公司。未经许可不得转载。联系我们：support@example.com";
+        let texts = vec![
+            footer.to_string(),
+            "第一章介绍了系统的整体架构和各个模块之间的关系。".to_string(),
+            format!("{}  ", footer.replace("2024", "2025")),
+            footer.to_uppercase(),
+            "第二章讲部署：先安装依赖，再修改配置文件，最后启动服务。".to_string(),
+            String::new(),
+        ];
+        let duplicates = find_duplicates(&texts, 0.8);
+        assert_eq!(duplicates, [(2, 0), (3, 0)]);
+
+        // 阈值为 1 时只有归一化后完全相同的才算重复
+        assert_eq!(find_duplicates(&texts, 1.0), [(3, 0)]);
+        assert!(similarity(&signature(&texts[1]).unwrap(), &signature(&texts[4]).unwrap()) < 0.5);
+    }
+
+    #[test]
+    fn marked_duplicates_are_skipped_until_the_kept_chunk_is_gone() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at)
+                VALUES ('kb', 'kb', 'cfg', 'openai', 'm', 0, 0);
+             INSERT INTO documents (id, kb_id, filename, file_type, status, created_at)
+                VALUES ('d1', 'kb', 'a.md', 'md', 'completed', 1), ('d2', 'kb', 'b.md', 'md', 'completed', 2);
+             INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at) VALUES
+                ('a0', 'd1', 'kb', '本文档仅供内部使用，请勿外传。', 0, 0),
+                ('a1', 'd1', 'kb', '退款需要在收货后七天内申请。', 1, 0),
+                ('b0', 'd2', 'kb', '本文档仅供内部使用，请勿外传。', 0, 0),
+                ('b1', 'd2', 'kb', '发票可以在订单详情页下载。', 1, 0);",
+        )
+        .unwrap();
+        let visible = |conn: &rusqlite::Connection| -> Vec<String> {
+            let sql = format!("SELECT c.id FROM chunks c WHERE {} ORDER BY c.id", NOT_COLLAPSED);
+            let mut stmt = conn.prepare(&sql).unwrap();
+            let ids = stmt.query_map([], |row| row.get(0)).unwrap();
+            ids.collect::<Result<_, _>>().unwrap()
+        };
+
+        let report = mark_duplicates(&conn, "kb", None, DEFAULT_DUPLICATE_THRESHOLD).unwrap();
+        assert_eq!(report, DedupReport { scanned: 4, duplicates: 1, groups: 1 });
+        assert_eq!(visible(&conn), ["a0", "a1", "b1"]);
+
+        conn.execute("DELETE FROM chunks WHERE id = 'a0'", []).unwrap();
+        assert_eq!(visible(&conn), ["a1", "b0", "b1"]);
+    }
+}
@@ -2,9 +2,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::ocr;
+use super::structured::{parse_structured, StructuredKind};
+use super::tokenizer::Tokenizer;
+use super::transcribe;
 use super::types::*;
 use crate::commands::local_model::hide_console_window;
+use crate::commands::web_fetch::extract_readable_markdown;
 use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
 use std::path::Path;
 
 /// 支持的文档格式枚举
@@ -17,6 +23,10 @@ pub enum DocumentFormat {
     Markdown,
     Html,
     Txt,
+    Image,
+    Epub,
+    Structured,
+    Media,
 }
 
 #[allow(dead_code)]
@@ -30,9 +40,13 @@ impl DocumentFormat {
             "xlsx" | "xls" | "csv" => Some(DocumentFormat::Excel),
             "md" | "markdown" => Some(DocumentFormat::Markdown),
             "html" | "htm" => Some(DocumentFormat::Html),
+            "epub" => Some(DocumentFormat::Epub),
+            "json" | "jsonl" | "ndjson" | "yaml" | "yml" => Some(DocumentFormat::Structured),
             "txt" | "text" | "rs" | "js" | "ts" | "py" | "java" | "c" | "cpp" | "h" | "go" => {
                 Some(DocumentFormat::Txt)
             }
+            "png" | "jpg" | "jpeg" | "bmp" | "tif" | "tiff" | "webp" => Some(DocumentFormat::Image),
+            "mp3" | "wav" | "m4a" | "flac" | "ogg" | "mp4" | "mkv" | "mov" | "webm" => Some(DocumentFormat::Media),
             _ => None,
         }
     }
@@ -46,12 +60,29 @@ impl DocumentFormat {
             DocumentFormat::Markdown => "md",
             DocumentFormat::Html => "html",
             DocumentFormat::Txt => "txt",
+            DocumentFormat::Image => "image",
+            DocumentFormat::Epub => "epub",
+            DocumentFormat::Structured => "json",
+            DocumentFormat::Media => "media",
         }
     }
 }
 
 /// 解析文档内容为纯文本
 pub async fn parse_document(file_path: &str) -> Result<String, KnowledgeBaseError> {
+    Ok(parse_document_pages(file_path).await?.text)
+}
+
+/// 解析结果：正文，以及分页格式（PDF、PPTX）每页在正文里的起点
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedDocument {
+    pub text: String,
+    /// (起始字符偏移, 页码)，按偏移升序；没有分页的格式为空
+    pub pages: Vec<(usize, i32)>,
+}
+
+/// 解析文档，同时记下每页的起点，分块时用来标注页码
+pub async fn parse_document_pages(file_path: &str) -> Result<ParsedDocument, KnowledgeBaseError> {
     let path = Path::new(file_path);
     let ext = path
         .extension()
@@ -68,22 +99,60 @@ pub async fn parse_document(file_path: &str) -> Result<String, KnowledgeBaseErro
         KnowledgeBaseError::DocumentParseError(hint)
     })?;
 
+    let text = parse_text(file_path, format, &ext).await?;
+    Ok(match format {
+        DocumentFormat::Pdf => take_page_markers(&text),
+        DocumentFormat::Pptx => ParsedDocument { pages: slide_starts(&text), text },
+        _ => ParsedDocument { text, pages: Vec::new() },
+    })
+}
+
+/// 按格式解析出正文；PDF 的正文里还带着页码标记行（见 take_page_markers）
+async fn parse_text(file_path: &str, format: DocumentFormat, ext: &str) -> Result<String, KnowledgeBaseError> {
+    let path = Path::new(file_path);
     let content = match format {
         DocumentFormat::Pdf => parse_pdf(file_path).await?,
         DocumentFormat::Word => parse_word(file_path).await?,
-        DocumentFormat::Pptx => parse_pptx(file_path).await?,
+        // 幻灯片整理成了带标题的 Markdown，同样不能走 clean_text
+        DocumentFormat::Pptx => return Ok(clean_markdown(&parse_pptx(file_path).await?)),
+        DocumentFormat::Epub => return Ok(clean_markdown(&parse_epub(file_path).await?)),
+        DocumentFormat::Structured => {
+            let raw = tokio::fs::read_to_string(file_path)
+                .await
+                .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
+            let kind = StructuredKind::from_extension(ext).unwrap_or(StructuredKind::Json);
+            return Ok(clean_markdown(&parse_structured(&raw, kind)?));
+        }
+        // 转写稿按时间窗口分好了标题，时间范围会记进分块的 heading_path
+        DocumentFormat::Media => return Ok(clean_markdown(&transcribe::parse_media(path).await?)),
         DocumentFormat::Excel => parse_excel(file_path).await?,
         DocumentFormat::Html => {
+            // 去掉导航栏、脚本、页脚等页面框架，正文转成 Markdown 保留标题层级和列表
             let raw = tokio::fs::read_to_string(file_path)
                 .await
                 .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
-            strip_html_tags(&raw)
+            let (_, markdown) = extract_readable_markdown(&raw);
+            return Ok(clean_markdown(&markdown));
         }
-        DocumentFormat::Markdown | DocumentFormat::Txt => {
+        DocumentFormat::Markdown => {
+            // Markdown 的空行和缩进有语义（段落、代码块），不能走 clean_text
+            let raw = tokio::fs::read_to_string(file_path)
+                .await
+                .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
+            return Ok(clean_markdown(&raw));
+        }
+        DocumentFormat::Txt => {
             tokio::fs::read_to_string(file_path)
                 .await
                 .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?
         }
+        DocumentFormat::Image => {
+            let text = ocr::ocr_image(path).await?;
+            if text.trim().is_empty() {
+                return Err(KnowledgeBaseError::DocumentParseError("图片中没有识别出文字".to_string()));
+            }
+            text
+        }
     };
 
     Ok(clean_text(&content))
@@ -91,8 +160,39 @@ pub async fn parse_document(file_path: &str) -> Result<String, KnowledgeBaseErro
 
 // ============ PDF ============
 
-/// 尝试通过外部 pdftotext（poppler-utils）提取文本
-async fn try_pdftotext(file_path: &str) -> Result<String, ()> {
+/// PDF 的一页文本
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdfPage {
+    /// 页码，从 1 开始
+    pub number: usize,
+    pub text: String,
+}
+
+/// 按顺序给每页文本编上页码
+fn number_pages(texts: Vec<String>) -> Vec<PdfPage> {
+    texts
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| PdfPage { number: i + 1, text })
+        .collect()
+}
+
+/// pdftotext 在每一页末尾输出一个换页符（\f），按它切回各页；最后一页之后的空串丢掉
+fn split_pdftotext_pages(output: &str) -> Vec<String> {
+    let mut pages: Vec<String> = output.split('\u{c}').map(str::to_string).collect();
+    if pages.len() > 1 && pages.last().is_some_and(|p| p.trim().is_empty()) {
+        pages.pop();
+    }
+    pages
+}
+
+/// 所有页的非空白字符数，用来比较两种提取方式谁丢字更少
+fn non_whitespace_chars(pages: &[String]) -> usize {
+    pages.iter().map(|p| p.chars().filter(|c| !c.is_whitespace()).count()).sum()
+}
+
+/// 尝试通过外部 pdftotext（poppler-utils）逐页提取文本
+async fn try_pdftotext(file_path: &str) -> Result<Vec<String>, ()> {
     let mut cmd = tokio::process::Command::new("pdftotext");
     cmd.args(["-layout", file_path, "-"]);
     hide_console_window(&mut cmd);
@@ -100,7 +200,7 @@ async fn try_pdftotext(file_path: &str) -> Result<String, ()> {
         Ok(result) if result.status.success() => {
             let text = String::from_utf8_lossy(&result.stdout).to_string();
             if !text.trim().is_empty() {
-                Ok(text)
+                Ok(split_pdftotext_pages(&text))
             } else {
                 Err(())
             }
@@ -109,36 +209,83 @@ async fn try_pdftotext(file_path: &str) -> Result<String, ()> {
     }
 }
 
-/// 解析 PDF 文件
+/// 逐页解析 PDF 文件
 /// 外部 pdftotext（版式/表格精度更高）与内置 pdf-extract（纯 Rust）都跑一遍，取字符数明显更多的
 /// 那份。单跑 pdftotext 不够：PATH 上的 `pdftotext.exe` 未必是 poppler——Windows 上常见的是随
 /// Git for Windows 等工具分发的 xpdf 衍生版本，遇到内嵌 CJK 字体会静默丢字（命令正常返回、非空，
 /// 但中文整段消失），不会触发任何错误，也就永远走不到下面的回退分支。
-async fn parse_pdf(file_path: &str) -> Result<String, KnowledgeBaseError> {
+/// 没装 pdftotext 时只用 pdf-extract，不依赖任何外部程序。
+/// 两者都抽不出文字的页（扫描件）再交给 OCR。
+pub async fn parse_pdf_pages(file_path: &str) -> Result<Vec<PdfPage>, KnowledgeBaseError> {
     let path_owned = file_path.to_string();
-    let extract_result = tokio::task::spawn_blocking(move || pdf_extract::extract_text(&path_owned))
+    let extract_result = tokio::task::spawn_blocking(move || pdf_extract::extract_text_by_pages(&path_owned))
         .await
         .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
 
     let pdftotext_result = try_pdftotext(file_path).await.ok();
 
-    match (pdftotext_result, extract_result) {
+    let pages = match (pdftotext_result, extract_result) {
         (Some(pt), Ok(pe)) => {
-            let pt_chars = pt.chars().filter(|c| !c.is_whitespace()).count();
-            let pe_chars = pe.chars().filter(|c| !c.is_whitespace()).count();
             // pdf_extract 抽出的非空白字符明显更多（阈值 1.5x），说明 pdftotext 大概率丢字了
-            if pe_chars > pt_chars * 3 / 2 {
-                Ok(pe)
+            if non_whitespace_chars(&pe) > non_whitespace_chars(&pt) * 3 / 2 {
+                pe
             } else {
-                Ok(pt)
+                pt
+            }
+        }
+        (Some(pt), Err(_)) => pt,
+        (None, Ok(pe)) => pe,
+        (None, Err(e)) => {
+            return Err(KnowledgeBaseError::DocumentParseError(format!(
+                "PDF 解析失败: {e}"
+            )))
+        }
+    };
+
+    let mut pages = number_pages(pages);
+
+    // 抽不出文字的页多半是扫描件，逐页 OCR；OCR 不可用（没装 tesseract 等）时后面的页也不再尝试
+    for page in pages.iter_mut().filter(|page| page.text.trim().is_empty()) {
+        match ocr::ocr_pdf_page(file_path, page.number).await {
+            Ok(text) => page.text = text,
+            Err(e) => {
+                log::warn!("[KB] OCR skipped for {} from page {}: {}", file_path, page.number, e);
+                break;
             }
         }
-        (Some(pt), Err(_)) => Ok(pt),
-        (None, Ok(pe)) => Ok(pe),
-        (None, Err(e)) => Err(KnowledgeBaseError::DocumentParseError(format!(
-            "PDF 解析失败: {e}"
-        ))),
     }
+
+    Ok(pages)
+}
+
+/// 解析 PDF 文件：每页前面单独一行页码标记，各页之间空一行，保证分块时页与页之间是段落边界
+async fn parse_pdf(file_path: &str) -> Result<String, KnowledgeBaseError> {
+    let pages = parse_pdf_pages(file_path).await?;
+    Ok(pages
+        .iter()
+        .filter(|page| !page.text.trim().is_empty())
+        .map(|page| format!("{}{}\n{}", PAGE_MARKER, page.number, page.text.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// 页码标记行的开头。不是空白字符，clean_text 去首尾空白时不会把它去掉
+const PAGE_MARKER: char = '\u{1}';
+
+/// 去掉 clean_text 之后正文里的页码标记行，记下每页的起始字符偏移
+fn take_page_markers(text: &str) -> ParsedDocument {
+    let mut lines = Vec::new();
+    let mut pages = Vec::new();
+    let mut offset = 0;
+    for line in text.lines() {
+        if let Some(number) = line.strip_prefix(PAGE_MARKER).and_then(|n| n.parse().ok()) {
+            pages.push((offset, number));
+            continue;
+        }
+        offset += line.chars().count() + 1;
+        lines.push(line);
+    }
+    ParsedDocument { text: lines.join("\n"), pages }
 }
 
 // ============ Word / DOCX ============
@@ -203,36 +350,39 @@ fn extract_text_from_docx_xml(xml: &str) -> String {
 // ============ PowerPoint / PPTX ============
 
 /// 解析 PowerPoint 文档（.pptx）
+///
+/// 每页输出成一个一级标题"第 N 页：标题"，下面是正文和演讲者备注，
+/// 分块时按 Markdown 处理，页码和标题就记在块的 heading_path 里。
 async fn parse_pptx(file_path: &str) -> Result<String, KnowledgeBaseError> {
     let bytes = tokio::fs::read(file_path)
         .await
         .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
 
     tokio::task::spawn_blocking(move || {
-        use std::io::Read;
         let cursor = std::io::Cursor::new(&bytes);
         let mut archive = zip::ZipArchive::new(cursor).map_err(|_| {
             KnowledgeBaseError::DocumentParseError("无法解析 PPTX 文件".into())
         })?;
 
-        // 收集幻灯片文件名并按页码排序
-        let mut slide_names: Vec<String> = (0..archive.len())
+        // 收集幻灯片文件名并按页码排序（按数字排，字典序会把 slide10 排在 slide2 前面）
+        let mut slides: Vec<(usize, String)> = (0..archive.len())
             .filter_map(|i| archive.by_index(i).ok().map(|f| f.name().to_string()))
-            .filter(|n| n.starts_with("ppt/slides/slide") && n.ends_with(".xml"))
+            .filter_map(|name| {
+                let number = name.strip_prefix("ppt/slides/slide")?.strip_suffix(".xml")?.parse().ok()?;
+                Some((number, name))
+            })
             .collect();
-        slide_names.sort();
+        slides.sort();
 
         let mut result = String::new();
-        for (idx, name) in slide_names.iter().enumerate() {
-            let mut xml = String::new();
-            archive
-                .by_name(name)
-                .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?
-                .read_to_string(&mut xml)
-                .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
-            result.push_str(&format!("--- 第 {} 页 ---\n", idx + 1));
-            result.push_str(&extract_text_from_pptx_xml(&xml));
-            result.push('\n');
+        for (idx, (number, name)) in slides.iter().enumerate() {
+            let slide_xml = read_zip_entry(&mut archive, name)
+                .ok_or_else(|| KnowledgeBaseError::DocumentParseError(format!("无法读取 {}", name)))?;
+            // 备注页通过幻灯片的关系文件关联，没有备注时不存在
+            let notes_xml = read_zip_entry(&mut archive, &format!("ppt/slides/_rels/slide{}.xml.rels", number))
+                .and_then(|rels| find_notes_target(&rels))
+                .and_then(|target| read_zip_entry(&mut archive, &format!("ppt/notesSlides/{}", target)));
+            result.push_str(&format_slide(idx + 1, &slide_xml, notes_xml.as_deref()));
         }
         Ok(result)
     })
@@ -240,6 +390,95 @@ async fn parse_pptx(file_path: &str) -> Result<String, KnowledgeBaseError> {
     .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?
 }
 
+/// 读出 ZIP 里的一个文本文件，不存在或读取失败时返回 None
+fn read_zip_entry<R: std::io::Read + std::io::Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Option<String> {
+    use std::io::Read;
+    let mut content = String::new();
+    archive.by_name(name).ok()?.read_to_string(&mut content).ok()?;
+    Some(content)
+}
+
+/// 从幻灯片的关系文件里找出备注页的文件名（如 notesSlide3.xml）
+fn find_notes_target(rels: &str) -> Option<String> {
+    let start = rels.find("../notesSlides/")? + "../notesSlides/".len();
+    let end = rels[start..].find('"')?;
+    Some(rels[start..start + end].to_string())
+}
+
+/// 切出 XML 里所有的形状（`<p:sp>…</p:sp>`），组合形状里嵌套的也会被切出来
+fn pptx_shapes(xml: &str) -> Vec<&str> {
+    let mut shapes = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find("<p:sp>") {
+        let Some(len) = rest[start..].find("</p:sp>") else { break };
+        let end = start + len + "</p:sp>".len();
+        shapes.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    shapes
+}
+
+/// 形状是否是指定类型的占位符（如 title、body）
+fn is_placeholder(shape: &str, types: &[&str]) -> bool {
+    types.iter().any(|t| shape.contains(&format!("<p:ph type=\"{}\"", t)))
+}
+
+/// 幻灯片正文里每页标题行（"# 第 N 页"）的起始字符偏移和页码
+fn slide_starts(text: &str) -> Vec<(usize, i32)> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    for line in text.lines() {
+        let number = line
+            .strip_prefix("# 第 ")
+            .and_then(|rest| rest.split_once(" 页"))
+            .and_then(|(n, _)| n.parse().ok());
+        if let Some(number) = number {
+            starts.push((offset, number));
+        }
+        offset += line.chars().count() + 1;
+    }
+    starts
+}
+
+/// 把一页幻灯片整理成 Markdown：标题作为一级标题，正文照常，备注放在最后
+fn format_slide(number: usize, slide_xml: &str, notes_xml: Option<&str>) -> String {
+    let title_shape = pptx_shapes(slide_xml)
+        .into_iter()
+        .find(|shape| is_placeholder(shape, &["title", "ctrTitle"]));
+    let title = title_shape
+        .map(|shape| extract_text_from_pptx_xml(shape).split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty());
+    // 正文取标题以外的全部文字，表格等不在 <p:sp> 里的内容也能保留
+    let body = match title_shape {
+        Some(shape) => extract_text_from_pptx_xml(&slide_xml.replacen(shape, "", 1)),
+        None => extract_text_from_pptx_xml(slide_xml),
+    };
+    let notes: String = notes_xml
+        .map(|xml| {
+            pptx_shapes(xml)
+                .into_iter()
+                .filter(|shape| is_placeholder(shape, &["body"]))
+                .map(extract_text_from_pptx_xml)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut result = match title {
+        Some(title) => format!("# 第 {} 页：{}\n\n", number, title),
+        None => format!("# 第 {} 页\n\n", number),
+    };
+    if !body.trim().is_empty() {
+        result.push_str(body.trim());
+        result.push_str("\n\n");
+    }
+    if !notes.trim().is_empty() {
+        result.push_str("备注：\n");
+        result.push_str(notes.trim());
+        result.push_str("\n\n");
+    }
+    result
+}
+
 /// 从 PPTX 幻灯片 XML（DrawingML）中提取纯文本（保留段落换行）
 fn extract_text_from_pptx_xml(xml: &str) -> String {
     const PARA_END: &str = "\x02PARA\x02";
@@ -248,7 +487,7 @@ fn extract_text_from_pptx_xml(xml: &str) -> String {
     for chunk in xml.split("<a:t") {
         if let Some(end) = chunk.find("</a:t>") {
             if let Some(start) = chunk.find('>') {
-                result.push_str(&chunk[start + 1..end]);
+                result.push_str(&decode_entities(&chunk[start + 1..end]));
             }
             if chunk[end..].contains(PARA_END) {
                 result.push('\n');
@@ -258,6 +497,153 @@ fn extract_text_from_pptx_xml(xml: &str) -> String {
     result
 }
 
+// ============ EPUB ============
+
+/// 解析电子书（.epub）
+///
+/// 按 OPF 的 spine 顺序读出每一章的 XHTML，每章输出成一个一级标题加正文，
+/// 分块时按 Markdown 处理，章节标题记在块的 heading_path 里。
+async fn parse_epub(file_path: &str) -> Result<String, KnowledgeBaseError> {
+    let bytes = tokio::fs::read(file_path)
+        .await
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
+
+    tokio::task::spawn_blocking(move || {
+        let cursor = std::io::Cursor::new(&bytes);
+        let mut archive = zip::ZipArchive::new(cursor).map_err(|_| {
+            KnowledgeBaseError::DocumentParseError("无法解析 EPUB 文件（格式损坏或不是有效 ZIP）".into())
+        })?;
+
+        let invalid = || KnowledgeBaseError::DocumentParseError("EPUB 缺少 content.opf，无法确定章节顺序".into());
+        let container = read_zip_entry(&mut archive, "META-INF/container.xml").ok_or_else(invalid)?;
+        let opf_path = find_tags(&container, "rootfile")
+            .into_iter()
+            .find_map(|tag| xml_attr(tag, "full-path"))
+            .ok_or_else(invalid)?;
+        let opf = read_zip_entry(&mut archive, &opf_path).ok_or_else(invalid)?;
+        // 章节路径相对于 OPF 所在目录
+        let base_dir = opf_path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or_default();
+
+        let mut result = String::new();
+        for (idx, href) in epub_spine(&opf).into_iter().enumerate() {
+            let Some(xhtml) = read_zip_entry(&mut archive, &resolve_zip_path(base_dir, &href)) else {
+                log::warn!("[KB] EPUB chapter missing: {}", href);
+                continue;
+            };
+            result.push_str(&format_epub_chapter(idx + 1, &xhtml));
+        }
+        if result.trim().is_empty() {
+            return Err(KnowledgeBaseError::DocumentParseError("EPUB 中没有可用的文本".into()));
+        }
+        Ok(result)
+    })
+    .await
+    .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?
+}
+
+/// 找出 XML 里所有名为 name 的开始标签（`<name …>`，含属性部分）
+fn find_tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}", name);
+    let mut tags = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find('>') else { break };
+        // 排除名字只是前缀相同的标签，如找 item 时碰到 itemref
+        if after.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            tags.push(&rest[start..start + open.len() + end + 1]);
+        }
+        rest = &after[end..];
+    }
+    tags
+}
+
+/// 读取标签里的属性值，单双引号都支持
+fn xml_attr(tag: &str, name: &str) -> Option<String> {
+    for quote in ['"', '\''] {
+        let key = format!(" {}={}", name, quote);
+        if let Some(start) = tag.find(&key) {
+            let value = &tag[start + key.len()..];
+            let end = value.find(quote)?;
+            return Some(decode_entities(&value[..end]));
+        }
+    }
+    None
+}
+
+/// 按 spine 顺序列出各章在 ZIP 里相对于 OPF 的路径
+fn epub_spine(opf: &str) -> Vec<String> {
+    let manifest: std::collections::HashMap<String, String> = find_tags(opf, "item")
+        .into_iter()
+        .filter_map(|tag| Some((xml_attr(tag, "id")?, xml_attr(tag, "href")?)))
+        .collect();
+
+    find_tags(opf, "itemref")
+        .into_iter()
+        .filter_map(|tag| manifest.get(&xml_attr(tag, "idref")?))
+        .map(|href| {
+            let href = href.split('#').next().unwrap_or(href);
+            urlencoding::decode(href).map(|h| h.into_owned()).unwrap_or_else(|_| href.to_string())
+        })
+        .collect()
+}
+
+/// 把 OPF 所在目录和章节的相对路径拼成 ZIP 里的完整路径，
+/// 消掉 `.` 和 `..`（如 OEBPS/Text/../Styles 一类的写法），否则 by_name 找不到条目
+fn resolve_zip_path(base_dir: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in base_dir.split('/').chain(href.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// 章节标题：正文里第一个 h1–h3，没有就用 <title>，都没有就用"第 N 章"。
+/// 标题取自正文里的 h1–h3 时，同时返回该元素在 xhtml 里的字节范围
+fn epub_chapter_title(xhtml: &str, number: usize) -> (String, Option<std::ops::Range<usize>>) {
+    let element = |tag: &str| -> Option<(String, std::ops::Range<usize>)> {
+        let open = find_tags(xhtml, tag).into_iter().next()?;
+        let start = xhtml.find(open)?;
+        let inner = start + open.len();
+        let close = format!("</{}>", tag);
+        let end = inner + xhtml[inner..].find(&close)?;
+        let text = strip_html_tags(&xhtml[inner..end]);
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        (!text.is_empty()).then_some((text, start..end + close.len()))
+    };
+    if let Some((title, range)) = ["h1", "h2", "h3"].into_iter().find_map(element) {
+        return (title, Some(range));
+    }
+    let title = element("title").map(|(title, _)| title).unwrap_or_else(|| format!("第 {} 章", number));
+    (title, None)
+}
+
+/// 把一章整理成 Markdown：标题作为一级标题，下面是正文；没有正文的章节（封面等）跳过
+fn format_epub_chapter(number: usize, xhtml: &str) -> String {
+    let (title, heading) = epub_chapter_title(xhtml, number);
+    // 标题已经单独输出成一级标题，从正文里去掉，免得每章开头重复一遍
+    let xhtml = match heading {
+        Some(range) => format!("{}{}", &xhtml[..range.start], &xhtml[range.end..]),
+        None => xhtml.to_string(),
+    };
+    // <head> 里的 <title> 不属于正文
+    let body = match (xhtml.find("<body"), xhtml.rfind("</body>")) {
+        (Some(start), Some(end)) if start < end => &xhtml[start..end],
+        _ => &xhtml,
+    };
+    let text = clean_text(&strip_html_tags(body));
+    if text.is_empty() {
+        return String::new();
+    }
+    format!("# {}\n\n{}\n\n", title, text)
+}
+
 // ============ Excel / XLSX / XLS / CSV ============
 
 /// 解析 Excel 文件（.xlsx、.xls 用 calamine；.csv 直接读文本）
@@ -346,16 +732,20 @@ fn strip_html_tags(html: &str) -> String {
         }
     }
 
-    // 解码常见 HTML 实体（单次遍历完成，替换顺序经过安排以避免重复解码）
-    result
-        .replace("&amp;", "&")
-        .replace("&lt;", "<")
+    decode_entities(&result)
+}
+
+/// 解码常见的 HTML/XML 实体
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
         .replace("&gt;", ">")
         .replace("&quot;", "\"")
         .replace("&apos;", "'")
         .replace("&#39;", "'")
         .replace("&nbsp;", " ")
         .replace("&#160;", " ")
+        // &amp; 放在最后，避免 "&amp;lt;" 被解码两次变成 "<"
+        .replace("&amp;", "&")
 }
 
 /// 清理并规范化文本
@@ -370,6 +760,31 @@ fn clean_text(text: &str) -> String {
         .join("\n\n")
 }
 
+/// 清理 Markdown 文本：只去掉行尾空白、把连续多个空行合并成一个，保留缩进和段落空行
+fn clean_markdown(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut blank_run = 0;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank_run += 1;
+            if blank_run > 1 {
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    result.trim().to_string()
+}
+
+/// 计算文本内容的哈希（网页等没有文件的来源用）
+pub fn calculate_text_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
 /// 计算文件哈希
 pub async fn calculate_file_hash(file_path: &str) -> Result<String, KnowledgeBaseError> {
     let bytes = tokio::fs::read(file_path)
@@ -380,12 +795,12 @@ pub async fn calculate_file_hash(file_path: &str) -> Result<String, KnowledgeBas
     Ok(format!("{:x}", hash))
 }
 
-/// 分隔符按"粗粒度 → 细粒度"优先级排列。
+/// 默认分隔符，按"粗粒度 → 细粒度"优先级排列。知识库没有自定义分隔符时使用。
 ///
 /// Markdown 标题行（`\n# ` 等）排在最前，使同一标题下的内容优先聚在同一个块里。
 /// 标题分隔符保留在左侧块末尾（含 `\n# ` 字符），对语义影响极小。
-/// 之后依次是段落、句子、逗号、空格，最后由 `hard_split_by_chars` 兜底。
-const SPLIT_SEPARATORS: &[&str] = &[
+/// 之后依次是段落、换行、中英文句末标点、逗号、空格；都切不开时按单个字符（或 token）切。
+pub const DEFAULT_SEPARATORS: &[&str] = &[
     "\n# ",
     "\n## ",
     "\n### ",
@@ -407,18 +822,12 @@ fn char_count(s: &str) -> usize {
     s.chars().count()
 }
 
-/// 返回 `s` 末尾 `n` 个字符对应的切片（按字符数而非字节数截取）
-fn tail_chars(s: &str, n: usize) -> &str {
-    let total = char_count(s);
-    if n == 0 || total == 0 {
-        return "";
+/// 按知识库配置的单位计量文本长度
+fn measure(s: &str, options: &SplitOptions) -> usize {
+    match options.unit {
+        ChunkUnit::Chars => char_count(s),
+        ChunkUnit::Tokens => options.tokenizer.count(s),
     }
-    if n >= total {
-        return s;
-    }
-    let skip = total - n;
-    let byte_idx = s.char_indices().nth(skip).map(|(b, _)| b).unwrap_or(s.len());
-    &s[byte_idx..]
 }
 
 /// 在 `text` 中按 `sep` 切分，并把分隔符保留在前一段末尾
@@ -436,110 +845,639 @@ fn split_keep_separator<'a>(text: &'a str, sep: &str) -> Vec<&'a str> {
     result
 }
 
-/// 递归分割：依次尝试用从粗到细的分隔符切分文本。
-fn recursive_split(text: &str, chunk_size: usize, sep_index: usize) -> Vec<String> {
-    if text.is_empty() {
-        return Vec::new();
-    }
-    if char_count(text) <= chunk_size {
-        return vec![text.to_string()];
-    }
-    if sep_index >= SPLIT_SEPARATORS.len() {
-        return hard_split_by_chars(text, chunk_size);
+/// 最细一级的切分：单个字符，或单个 token 片段（按 token 计量时不会切断字符）
+fn split_atoms<'a>(text: &'a str, options: &SplitOptions) -> Vec<(&'a str, usize)> {
+    match options.unit {
+        ChunkUnit::Chars => text
+            .char_indices()
+            .map(|(i, c)| (&text[i..i + c.len_utf8()], 1))
+            .collect(),
+        ChunkUnit::Tokens => options.tokenizer.pieces(text),
     }
+}
+
+/// 把切好的小片段依次拼成不超过 chunk_size 的块。
+///
+/// 每拼满一块，就从窗口前端丢掉片段，只留下末尾不超过 chunk_overlap 的部分
+/// 作为下一块的开头，所以重叠也算在 chunk_size 之内，并且总是由完整的片段组成。
+/// 单个片段本身超过 chunk_size 时（只会出现在最细一级）单独成块。
+fn merge_splits(splits: Vec<(&str, usize)>, options: &SplitOptions, chunks: &mut Vec<String>) {
+    let mut window: VecDeque<(&str, usize)> = VecDeque::new();
+    let mut total = 0usize;
 
-    let sep = SPLIT_SEPARATORS[sep_index];
-    if !text.contains(sep) {
-        return recursive_split(text, chunk_size, sep_index + 1);
+    for (split, size) in splits {
+        if total + size > options.chunk_size && !window.is_empty() {
+            chunks.push(window.iter().map(|(s, _)| *s).collect());
+            while total > options.chunk_overlap || (total > 0 && total + size > options.chunk_size) {
+                if let Some((_, dropped)) = window.pop_front() {
+                    total -= dropped;
+                }
+            }
+        }
+        window.push_back((split, size));
+        total += size;
+    }
+    if !window.is_empty() {
+        chunks.push(window.iter().map(|(s, _)| *s).collect());
     }
+}
 
-    let parts = split_keep_separator(text, sep);
-    let mut result = Vec::new();
-    let mut current = String::new();
+/// 递归分割：用文本里出现的第一个分隔符切开，能放进 chunk_size 的片段合并成块，
+/// 仍然超长的片段交给更细一级的分隔符继续切；所有分隔符都用完后按字符/token 切。
+fn recursive_split(text: &str, options: &SplitOptions, level: usize, chunks: &mut Vec<String>) {
+    let separators = &options.separators;
+    let Some(level) = (level..separators.len()).find(|&i| text.contains(separators[i].as_str())) else {
+        merge_splits(split_atoms(text, options), options, chunks);
+        return;
+    };
 
-    for part in parts {
-        if char_count(part) > chunk_size {
-            if !current.is_empty() {
-                result.push(std::mem::take(&mut current));
-            }
-            result.extend(recursive_split(part, chunk_size, sep_index + 1));
+    let mut pending = Vec::new();
+    for part in split_keep_separator(text, &separators[level]) {
+        let size = measure(part, options);
+        if size <= options.chunk_size {
+            pending.push((part, size));
             continue;
         }
-        if char_count(&current) + char_count(part) > chunk_size && !current.is_empty() {
-            result.push(std::mem::take(&mut current));
-        }
-        current.push_str(part);
+        merge_splits(std::mem::take(&mut pending), options, chunks);
+        recursive_split(part, options, level + 1, chunks);
     }
-    if !current.is_empty() {
-        result.push(current);
+    merge_splits(pending, options, chunks);
+}
+
+/// 按 options 切分一段文本，去掉每块首尾的空白，丢掉空块
+fn split_into_chunks(text: &str, options: &SplitOptions) -> Vec<String> {
+    if measure(text, options) <= options.chunk_size {
+        return vec![text.to_string()];
     }
+    let mut chunks = Vec::new();
+    recursive_split(text, options, 0, &mut chunks);
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.trim().to_string())
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
 
-    result
+/// 分块参数：大小和重叠都按 unit 计量，separators 从粗到细排列；
+/// 按 token 计量时用 tokenizer 分词
+#[derive(Debug, Clone)]
+pub struct SplitOptions {
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub unit: ChunkUnit,
+    pub separators: Vec<String>,
+    pub tokenizer: Tokenizer,
 }
 
-/// 硬字符切分（最后一道兜底）
-fn hard_split_by_chars(text: &str, chunk_size: usize) -> Vec<String> {
-    let chars: Vec<(usize, char)> = text.char_indices().collect();
-    if chars.is_empty() {
-        return Vec::new();
+impl SplitOptions {
+    /// 按字符数计量、使用默认分隔符
+    pub fn chars(chunk_size: usize, chunk_overlap: usize) -> Self {
+        Self::new(chunk_size, chunk_overlap, ChunkUnit::Chars, &[])
     }
 
-    let mut result = Vec::new();
-    let mut start = 0usize;
+    /// 知识库自身的分块配置，token 按知识库 Embedding 模型的词表计数
+    pub fn for_kb(kb: &KnowledgeBase) -> Self {
+        Self {
+            tokenizer: Tokenizer::for_model(&kb.embedding_model),
+            ..Self::new(kb.chunk_size.max(1) as usize, kb.chunk_overlap.max(0) as usize, kb.chunk_unit, &kb.separators)
+        }
+    }
 
-    while start < chars.len() {
-        let end = (start + chunk_size).min(chars.len());
-        let start_byte = chars[start].0;
-        let end_byte = chars.get(end).map(|&(b, _)| b).unwrap_or(text.len());
-        result.push(text[start_byte..end_byte].to_string());
-        start = end;
+    /// 规整参数：chunk_size 至少为 1，重叠必须小于 chunk_size，
+    /// 去掉空分隔符（空串会在切分时死循环），没有可用的分隔符时回落到默认值
+    fn new(chunk_size: usize, chunk_overlap: usize, unit: ChunkUnit, separators: &[String]) -> Self {
+        let chunk_size = chunk_size.max(1);
+        let mut separators: Vec<String> = separators.iter().filter(|s| !s.is_empty()).cloned().collect();
+        if separators.is_empty() {
+            separators = DEFAULT_SEPARATORS.iter().map(|s| s.to_string()).collect();
+        }
+        Self {
+            chunk_size,
+            chunk_overlap: chunk_overlap.min(chunk_size - 1),
+            unit,
+            separators,
+            tokenizer: Tokenizer::Estimate,
+        }
     }
+}
 
-    result
+/// 文本分块（按字符数计量，使用默认分隔符）
+pub fn split_text(text: &str, chunk_size: usize, chunk_overlap: usize) -> Vec<String> {
+    split_plain(text, &SplitOptions::chars(chunk_size, chunk_overlap))
 }
 
-/// 在切好的块之间补上重叠
-fn apply_overlap(chunks: Vec<String>, chunk_overlap: usize) -> Vec<String> {
-    if chunk_overlap == 0 || chunks.len() <= 1 {
-        return chunks;
-    }
+/// 拼接分块时认定为重叠的最少字符数，避免把碰巧相同的一两个字当成重叠去掉
+const MIN_CHUNK_OVERLAP_CHARS: usize = 8;
 
-    let mut result = Vec::with_capacity(chunks.len());
-    for (i, chunk) in chunks.iter().enumerate() {
-        if i == 0 {
-            result.push(chunk.clone());
-        } else {
-            let tail = tail_chars(&chunks[i - 1], chunk_overlap);
-            result.push(format!("{}{}", tail, chunk));
+/// 按顺序拼接相邻分块：后一块开头和前一块结尾重叠的部分（chunk_overlap 带来的）只保留一份，
+/// 不重叠的块之间用 separator 分隔
+pub(crate) fn join_chunks(chunks: &[String], separator: &str) -> String {
+    let mut result = String::new();
+    let mut prev: &str = "";
+    for chunk in chunks {
+        // 从最长的候选前缀往短找，找到的第一个就是实际的重叠
+        let overlap = chunk
+            .char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .take_while(|&end| end <= prev.len())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .find(|&end| prev.ends_with(&chunk[..end]) && chunk[..end].chars().count() >= MIN_CHUNK_OVERLAP_CHARS)
+            .unwrap_or(0);
+        if overlap == 0 && !result.is_empty() {
+            result.push_str(separator);
         }
+        result.push_str(&chunk[overlap..]);
+        prev = chunk;
     }
     result
 }
 
-/// 文本分块
-pub fn split_text(text: &str, chunk_size: usize, chunk_overlap: usize) -> Vec<String> {
+/// 文本分块：按分隔符递归切分，块的大小按 options.unit 计量
+fn split_plain(text: &str, options: &SplitOptions) -> Vec<String> {
     let trimmed = text.trim();
     if trimmed.is_empty() {
         return Vec::new();
     }
-    let chunk_size = chunk_size.max(1);
 
-    let chunks = recursive_split(trimmed, chunk_size, 0);
-    let result = apply_overlap(chunks, chunk_overlap);
+    let result = split_into_chunks(trimmed, options);
 
     log::debug!(
-        "split_text: {} 字符 -> {} 块 (chunk_size={}, chunk_overlap={})",
+        "split_text: {} 字符 -> {} 块 (chunk_size={}, chunk_overlap={}, unit={})",
         char_count(trimmed),
         result.len(),
-        chunk_size,
-        chunk_overlap
+        options.chunk_size,
+        options.chunk_overlap,
+        options.unit.as_str()
     );
 
     result
 }
 
+// ============ Markdown 分块 ============
+
+/// 带元数据的分块结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub content: String,
+    /// Markdown 文档里这个块所在的标题路径，如 "安装 > Windows"；其他格式为 None
+    pub heading_path: Option<String>,
+    /// 父子分块时所属父块的下标（见 split_parent_child），其余情况为 None
+    pub parent: Option<usize>,
+}
+
+/// 按文档类型分块：Markdown（以及解析成 Markdown 的 HTML、PPTX、EPUB、结构化数据、音视频转写稿）
+/// 沿标题层级切分，其余格式按段落/句子递归切分
+pub fn split_document(text: &str, file_type: &str, options: &SplitOptions) -> Vec<TextChunk> {
+    match DocumentFormat::from_extension(file_type) {
+        Some(
+            DocumentFormat::Markdown
+            | DocumentFormat::Html
+            | DocumentFormat::Pptx
+            | DocumentFormat::Epub
+            | DocumentFormat::Structured
+            | DocumentFormat::Media,
+        ) => {
+            split_markdown(text, options)
+        }
+        _ => split_plain(text, options)
+            .into_iter()
+            .map(|content| TextChunk { content, heading_path: None, parent: None })
+            .collect(),
+    }
+}
+
+/// 父子分块：先按 parent_size 切出较大的父块（按文档类型切分，父块之间不重叠），
+/// 再把每个父块按 options 切成子块。返回 (子块, 父块)，子块的 parent 指向父块下标，
+/// 标题路径沿用父块的
+///
+/// 子块只用来匹配，检索结果会换成父块，所以子块一律按段落/句子切分，不再区分文档类型。
+pub fn split_parent_child(
+    text: &str,
+    file_type: &str,
+    options: &SplitOptions,
+    parent_size: usize,
+) -> (Vec<TextChunk>, Vec<TextChunk>) {
+    let parent_options = SplitOptions { chunk_size: parent_size.max(1), chunk_overlap: 0, ..options.clone() };
+    let parents = split_document(text, file_type, &parent_options);
+    let children = parents
+        .iter()
+        .enumerate()
+        .flat_map(|(i, parent)| {
+            split_plain(&parent.content, options).into_iter().map(move |content| TextChunk {
+                content,
+                heading_path: parent.heading_path.clone(),
+                parent: Some(i),
+            })
+        })
+        .collect();
+    (children, parents)
+}
+
+/// 分块在解析出的正文里的位置
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChunkLocation {
+    /// 块开头所在的页码；没有分页的格式为 None
+    pub page: Option<i32>,
+    /// 起始字符偏移
+    pub start_offset: Option<i32>,
+    /// 结束字符偏移（不含）
+    pub end_offset: Option<i32>,
+}
+
+/// 按顺序在正文里找出每个分块的位置，返回和 chunks 一一对应的结果
+///
+/// 带重叠的块从上一块开头之后开始找。Markdown 分块会把段落之间的空行规整掉，
+/// 整块找不到时按块的第一行定位、按块长估算结束位置；第一行也找不到时位置为空。
+pub fn locate_chunks(doc: &ParsedDocument, chunks: &[TextChunk]) -> Vec<ChunkLocation> {
+    let text = doc.text.as_str();
+    let total_chars = char_count(text);
+    let (mut byte_cursor, mut char_cursor) = (0usize, 0usize);
+
+    chunks
+        .iter()
+        .map(|chunk| {
+            let rest = &text[byte_cursor..];
+            let head = chunk.content.lines().next().unwrap_or_default();
+            let found = rest
+                .find(chunk.content.as_str())
+                .or_else(|| (!head.is_empty()).then(|| rest.find(head)).flatten());
+            let Some(pos) = found else {
+                return ChunkLocation::default();
+            };
+
+            let start_byte = byte_cursor + pos;
+            let start = char_cursor + char_count(&rest[..pos]);
+            let end = (start + char_count(&chunk.content)).min(total_chars);
+            // 下一块至少从这一块的第二个字符开始找
+            let step = text[start_byte..].chars().next().map_or(0, char::len_utf8);
+            byte_cursor = start_byte + step;
+            char_cursor = start + usize::from(step > 0);
+
+            let page = doc.pages.iter().take_while(|(offset, _)| *offset <= start).last().map(|(_, n)| *n);
+            ChunkLocation { page, start_offset: Some(start as i32), end_offset: Some(end as i32) }
+        })
+        .collect()
+}
+
+/// 把正文里 [start, end) 字符区间替换成 replacement，用于按分块位置回写编辑后的内容；
+/// 区间越界或首尾颠倒时返回 None
+pub fn replace_char_range(text: &str, start: usize, end: usize, replacement: &str) -> Option<String> {
+    if start > end {
+        return None;
+    }
+    let byte_at = |n: usize| text.char_indices().map(|(i, _)| i).chain(std::iter::once(text.len())).nth(n);
+    let (start_byte, end_byte) = (byte_at(start)?, byte_at(end)?);
+    Some(format!("{}{}{}", &text[..start_byte], replacement, &text[end_byte..]))
+}
+
+/// Markdown 的一个块：代码块和表格是不可拆分的整体，普通段落在超长时可以再切
+enum MarkdownBlock {
+    Atomic(String),
+    Text(String),
+}
+
+/// 一个标题下的内容（不含子标题下的内容）
+struct MarkdownSection {
+    heading_path: Option<String>,
+    blocks: Vec<MarkdownBlock>,
+}
+
+/// 解析标题行，返回 (级别, 标题文字)；最多 3 个空格的缩进，# 后必须是空格或行尾
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let rest = &line[indent..];
+    let level = rest.chars().take_while(|&c| c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let title = &rest[level..];
+    if !title.is_empty() && !title.starts_with(' ') {
+        return None;
+    }
+    Some((level, title.trim().trim_end_matches('#').trim()))
+}
+
+/// 代码块围栏行（``` 或 ~~~），返回围栏字符和长度
+fn parse_fence(line: &str) -> Option<(char, usize)> {
+    let trimmed = line.trim_start();
+    let marker = trimmed.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let len = trimmed.chars().take_while(|&c| c == marker).count();
+    (len >= 3).then_some((marker, len))
+}
+
+/// 把 Markdown 切成按标题划分的小节，小节内再分成段落/代码块/表格
+fn parse_markdown_sections(text: &str) -> Vec<MarkdownSection> {
+    let mut sections = vec![MarkdownSection { heading_path: None, blocks: Vec::new() }];
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut lines = text.lines().peekable();
+
+    fn flush(paragraph: &mut Vec<&str>, section: &mut MarkdownSection) {
+        if !paragraph.is_empty() {
+            section.blocks.push(MarkdownBlock::Text(paragraph.join("\n")));
+            paragraph.clear();
+        }
+    }
+
+    while let Some(line) = lines.next() {
+        let section = sections.last_mut().expect("sections is never empty");
+
+        if let Some((marker, len)) = parse_fence(line) {
+            flush(&mut paragraph, section);
+            // 一直读到同样字符、不短于开头的围栏为止；没有闭合就到文末
+            let mut block = vec![line];
+            for inner in lines.by_ref() {
+                block.push(inner);
+                let closes = parse_fence(inner).is_some_and(|(m, l)| m == marker && l >= len)
+                    && inner.trim_start().trim_start_matches(marker).trim().is_empty();
+                if closes {
+                    break;
+                }
+            }
+            section.blocks.push(MarkdownBlock::Atomic(block.join("\n")));
+            continue;
+        }
+
+        if line.trim_start().starts_with('|') {
+            flush(&mut paragraph, section);
+            let mut block = vec![line];
+            while let Some(next) = lines.peek() {
+                if !next.trim_start().starts_with('|') {
+                    break;
+                }
+                block.push(next);
+                lines.next();
+            }
+            section.blocks.push(MarkdownBlock::Atomic(block.join("\n")));
+            continue;
+        }
+
+        if let Some((level, title)) = parse_heading(line) {
+            flush(&mut paragraph, section);
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, title.to_string()));
+            let path = headings.iter().map(|(_, t)| t.as_str()).collect::<Vec<_>>().join(" > ");
+            sections.push(MarkdownSection {
+                heading_path: Some(path),
+                blocks: vec![MarkdownBlock::Text(line.trim().to_string())],
+            });
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            flush(&mut paragraph, section);
+        } else {
+            paragraph.push(line);
+        }
+    }
+    if let Some(section) = sections.last_mut() {
+        flush(&mut paragraph, section);
+    }
+
+    // 只有一行标题、没有正文的小节不单独成块，它的标题已经在子小节的路径里了
+    sections
+        .into_iter()
+        .filter(|s| {
+            let body = if s.heading_path.is_some() { &s.blocks[1..] } else { &s.blocks[..] };
+            !body.is_empty()
+        })
+        .collect()
+}
+
+/// Markdown 分块
+///
+/// 沿标题层级切分，每个块只包含同一个标题下的内容，标题路径记在 heading_path 里；
+/// 代码块和表格永远不会被切开（超过 chunk_size 时单独成块）。
+/// 只有超长段落才按分隔符再次切分，切出来的几段之间带 chunk_overlap 的重叠。
+pub fn split_markdown(text: &str, options: &SplitOptions) -> Vec<TextChunk> {
+    let chunk_size = options.chunk_size.max(1);
+    let mut result = Vec::new();
+
+    for section in parse_markdown_sections(text) {
+        let mut current = String::new();
+        let emit = |content: String, result: &mut Vec<TextChunk>| {
+            result.push(TextChunk { content, heading_path: section.heading_path.clone(), parent: None });
+        };
+
+        for block in section.blocks {
+            let (content, atomic) = match block {
+                MarkdownBlock::Atomic(content) => (content, true),
+                MarkdownBlock::Text(content) => (content, false),
+            };
+            let needed = if current.is_empty() {
+                measure(&content, options)
+            } else {
+                measure(&current, options) + measure("\n\n", options) + measure(&content, options)
+            };
+            if needed <= chunk_size {
+                if !current.is_empty() {
+                    current.push_str("\n\n");
+                }
+                current.push_str(&content);
+                continue;
+            }
+
+            if !current.is_empty() {
+                emit(std::mem::take(&mut current), &mut result);
+            }
+            if atomic || measure(&content, options) <= chunk_size {
+                if measure(&content, options) > chunk_size {
+                    emit(content, &mut result);
+                } else {
+                    current = content;
+                }
+            } else {
+                for piece in split_into_chunks(&content, options) {
+                    emit(piece, &mut result);
+                }
+            }
+        }
+        if !current.is_empty() {
+            emit(current, &mut result);
+        }
+    }
+
+    result
+}
+
 /// 估算 token 数量（粗略近似）
 pub fn estimate_tokens(text: &str) -> i32 {
     let char_count = text.chars().count();
     (char_count / 3) as i32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tokenizer;
+
+    #[test]
+    fn pdftotext_output_is_split_into_numbered_pages() {
+        let pages = number_pages(split_pdftotext_pages("第一页\n\u{c}\u{c}第三页\n\u{c}"));
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0], PdfPage { number: 1, text: "第一页\n".to_string() });
+        assert!(pages[1].text.is_empty());
+        assert_eq!(pages[2].number, 3);
+    }
+
+    #[test]
+    fn chunks_are_located_by_page_and_character_offset() {
+        let raw = format!("{m}1\n  第一页的内容。\n\n{m}3\n第三页。\n还是第三页。", m = PAGE_MARKER);
+        let doc = take_page_markers(&clean_text(&raw));
+        assert_eq!(doc.text, "第一页的内容。\n第三页。\n还是第三页。");
+        assert_eq!(doc.pages, [(0, 1), (8, 3)]);
+
+        let chunks = split_document(&doc.text, "pdf", &SplitOptions::chars(8, 0));
+        let located: Vec<_> = locate_chunks(&doc, &chunks)
+            .into_iter()
+            .map(|l| (l.page, l.start_offset, l.end_offset))
+            .collect();
+        assert_eq!(located, [(Some(1), Some(0), Some(7)), (Some(3), Some(8), Some(12)), (Some(3), Some(13), Some(19))]);
+
+        let slides = "# 第 1 页：封面\n\n标题\n\n# 第 2 页\n\n正文";
+        assert_eq!(slide_starts(slides), [(0, 1), (16, 2)]);
+    }
+
+    #[test]
+    fn char_ranges_are_replaced_in_place() {
+        let text = "重复的句子。重复的句子。";
+        assert_eq!(replace_char_range(text, 6, 12, "改过的句子。").as_deref(), Some("重复的句子。改过的句子。"));
+        assert_eq!(replace_char_range(text, 12, 12, "尾巴").as_deref(), Some("重复的句子。重复的句子。尾巴"));
+        assert_eq!(replace_char_range(text, 6, 13, "越界"), None);
+        assert_eq!(replace_char_range(text, 7, 6, "颠倒"), None);
+    }
+
+    #[test]
+    fn slides_become_headed_sections_with_notes() {
+        let slide = r#"<p:spTree><p:sp><p:nvSpPr><p:nvPr><p:ph type="title"/></p:nvPr></p:nvSpPr>
+            <p:txBody><a:p><a:r><a:t>季度总结</a:t></a:r></a:p></p:txBody></p:sp>
+            <p:sp><p:txBody><a:p><a:r><a:t>收入 &amp; 利润</a:t></a:r></a:p><a:p><a:r><a:t>同比增长</a:t></a:r></a:p></p:txBody></p:sp></p:spTree>"#;
+        let notes = r#"<p:sp><p:nvSpPr><p:nvPr><p:ph type="sldNum"/></p:nvPr></p:nvSpPr><p:txBody><a:p><a:r><a:t>3</a:t></a:r></a:p></p:txBody></p:sp>
+            <p:sp><p:nvSpPr><p:nvPr><p:ph type="body" idx="1"/></p:nvPr></p:nvSpPr><p:txBody><a:p><a:r><a:t>先讲利润</a:t></a:r></a:p></p:txBody></p:sp>"#;
+
+        let text = format_slide(3, slide, Some(notes));
+        assert_eq!(text, "# 第 3 页：季度总结\n\n收入 & 利润\n同比增长\n\n备注：\n先讲利润\n\n");
+
+        let chunks = split_document(&clean_markdown(&text), "pptx", &SplitOptions::chars(1000, 0));
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].heading_path.as_deref(), Some("第 3 页：季度总结"));
+        assert_eq!(find_notes_target(r#"<Relationship Id="rId2" Target="../notesSlides/notesSlide7.xml"/>"#).as_deref(), Some("notesSlide7.xml"));
+    }
+
+    #[test]
+    fn epub_chapters_follow_the_spine_and_keep_their_titles() {
+        let opf = r#"<manifest>
+            <item id="c2" href="Text/ch%202.xhtml" media-type="application/xhtml+xml"/>
+            <item id='c1' href='Text/ch1.xhtml' media-type='application/xhtml+xml'/>
+            </manifest><spine><itemref idref="c1"/><itemref idref="c2"/></spine>"#;
+        assert_eq!(epub_spine(opf), ["Text/ch1.xhtml", "Text/ch 2.xhtml"]);
+        assert_eq!(resolve_zip_path("OEBPS", "Text/ch1.xhtml"), "OEBPS/Text/ch1.xhtml");
+        assert_eq!(resolve_zip_path("OEBPS/Content", "../Text/./ch1.xhtml"), "OEBPS/Text/ch1.xhtml");
+        assert_eq!(resolve_zip_path("", "../ch1.xhtml"), "ch1.xhtml");
+
+        // 标题只出现在一级标题里，不在正文开头重复
+        let chapter = "<html><head><title>书名</title></head><body><h2 class=\"t\">第一章 起源</h2><p>很久以前。</p></body></html>";
+        assert_eq!(format_epub_chapter(1, chapter), "# 第一章 起源\n\n很久以前。\n\n");
+        let untitled = "<html><head><title>书名</title></head><body><p>很久以前。</p></body></html>";
+        assert_eq!(format_epub_chapter(3, untitled), "# 书名\n\n很久以前。\n\n");
+        assert_eq!(format_epub_chapter(2, "<html><body><img src=\"cover.jpg\"/></body></html>"), "");
+    }
+
+    #[test]
+    fn markdown_chunks_follow_headings_and_keep_the_path() {
+        let text = "# 指南\n\n简介。\n\n## 安装\n\n### Windows\n\n下载安装包。\n\n## 配置\n\n编辑配置文件。";
+        let chunks = split_markdown(text, &SplitOptions::chars(1000, 0));
+
+        let paths: Vec<Option<&str>> = chunks.iter().map(|c| c.heading_path.as_deref()).collect();
+        assert_eq!(paths, [Some("指南"), Some("指南 > 安装 > Windows"), Some("指南 > 配置")]);
+        assert_eq!(chunks[1].content, "### Windows\n\n下载安装包。");
+    }
+
+    #[test]
+    fn markdown_code_fences_and_tables_are_never_split() {
+        let code = format!("```rust\n{}\n\n# 不是标题\n```", "let x = 1;\n".repeat(20));
+        let table = "| a | b |\n|---|---|\n| 1 | 2 |\n| 3 | 4 |";
+        let text = format!("# 示例\n\n{}\n\n{}\n\n结尾段落。", code, table);
+        let chunks = split_markdown(&text, &SplitOptions::chars(40, 10));
+
+        assert!(chunks.iter().any(|c| c.content == code));
+        assert!(chunks.iter().any(|c| c.content.contains(table)));
+        assert!(chunks.iter().all(|c| c.heading_path.as_deref() == Some("示例")));
+    }
+
+    #[test]
+    fn non_markdown_documents_use_the_plain_splitter() {
+        let chunks = split_document("# 标题\n正文", "txt", &SplitOptions::chars(1000, 0));
+        assert_eq!(chunks, vec![TextChunk { content: "# 标题\n正文".to_string(), heading_path: None, parent: None }]);
+    }
+
+    #[test]
+    fn child_chunks_point_at_the_parent_section_they_came_from() {
+        let text = "# 安装\n\n第一步下载。第二步解压。第三步运行。\n\n# 配置\n\n打开设置。填写密钥。";
+        let (children, parents) = split_parent_child(text, "md", &SplitOptions::chars(8, 0), 1000);
+
+        assert_eq!(parents.len(), 2);
+        assert!(parents.iter().all(|p| p.parent.is_none()));
+        assert!(children.len() > parents.len());
+        for child in &children {
+            let parent = &parents[child.parent.expect("every child has a parent")];
+            assert!(parent.content.contains(&child.content));
+            assert_eq!(child.heading_path, parent.heading_path);
+        }
+    }
+
+    #[test]
+    fn chunks_including_overlap_never_exceed_chunk_size() {
+        let text = "第一句话比较短。第二句话稍微长一点点。第三句。".repeat(20);
+        let chunks = split_text(&text, 50, 15);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 50));
+        // 重叠由整句组成：第一块的最后两句（15 个字符）带到第二块开头
+        assert!(chunks[0].ends_with("第二句话稍微长一点点。第三句。"));
+        assert!(chunks[1].starts_with("第二句话稍微长一点点。第三句。第一句话比较短。"));
+        // 没有任何分隔符时按字符切
+        let letters = "a".repeat(120);
+        assert_eq!(split_text(&letters, 50, 0), vec!["a".repeat(50), "a".repeat(50), "a".repeat(20)]);
+    }
+
+    #[test]
+    fn custom_separators_replace_the_defaults() {
+        let text = "甲段内容|乙段内容|丙段内容。丁段内容";
+        let kb_separators = vec!["|".to_string(), String::new()];
+        let chunks = split_plain(text, &SplitOptions::new(10, 0, ChunkUnit::Chars, &kb_separators));
+        assert_eq!(chunks, ["甲段内容|乙段内容|", "丙段内容。丁段内容"]);
+
+        // 默认分隔符里没有 "|"，只能先在句号处切开，再按字符切
+        assert_eq!(split_text(text, 10, 0), ["甲段内容|乙段内容|", "丙段内容。", "丁段内容"]);
+    }
+
+    #[test]
+    fn token_sized_chunks_stay_within_the_limit() {
+        let text = "向量检索的召回质量取决于分块。".repeat(30) + &" retrieval quality depends on chunking.".repeat(30);
+        let options = SplitOptions::new(64, 8, ChunkUnit::Tokens, &[]);
+        let chunks = split_plain(&text, &options);
+
+        assert!(chunks.len() > 1);
+        // 每块最多是 chunk_size 加上从前一块带过来的重叠
+        assert!(chunks.iter().all(|c| tokenizer::count_tokens(c) <= 64 + 8 + 1));
+        // 英文约 4 个字母一个 token，同样的数值按字符计量会切出多得多的块
+        assert!(split_text(&text, 64, 8).len() > chunks.len());
+    }
+
+    #[test]
+    fn token_sized_chunks_use_the_embedding_models_vocabulary() {
+        let text = "向量检索的召回质量取决于分块。".repeat(30) + &" retrieval quality depends on chunking.".repeat(30);
+        let tokenizer = Tokenizer::for_model("text-embedding-3-small");
+        let options = SplitOptions { tokenizer, ..SplitOptions::new(64, 8, ChunkUnit::Tokens, &[]) };
+        let chunks = split_plain(&text, &options);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| tokenizer.count(c) <= 64 + 8 + 1));
+    }
+}
@@ -7,16 +7,25 @@
  * 
  * 功能说明:
  * - 调用外部 API 生成文本向量
- * - 支持多种 Embedding 提供商 (OpenAI, 智谱, SiliconFlow)
+ * - 支持多种 Embedding 提供商 (OpenAI 兼容接口、智谱、SiliconFlow、Jina、Voyage、Cohere)
  * - 批量处理支持
+ * - 单批请求遇到限流/过载/网络错误时指数退避重试，并遵守服务商的 Retry-After
+ *   （和聊天请求共用 rate_limit 里按 provider 记录的限流状态）
  * 
  * Embedding 向量用于:
  * - 文档相似度计算
  * - 语义检索
  */
 
+use super::embedding_config::EmbeddingTarget;
 use super::types::*;
+use crate::commands::constants::{EMBEDDING_MAX_RETRIES, EMBEDDING_RETRY_BASE_DELAY, EMBEDDING_RETRY_MAX_DELAY};
+use crate::commands::http_client::{shared_client, ClientKind};
+use crate::commands::llm::{is_retryable_reqwest_error, is_retryable_status};
+use crate::commands::rate_limit::{mark_throttled, parse_retry_after, wait_for_provider};
+use serde::Serialize;
 use serde_json::json;
+use std::time::Duration;
 
 /// 获取 Embedding 模型配置
 /// 
@@ -35,23 +44,156 @@ fn get_embedding_config(provider: &str) -> (&'static str, i32) {
     }
 }
 
+/// 内置的 Embedding 模型及其向量维度：(提供商, 模型, 维度)
+///
+/// 设置页的模型候选（get_available_embedding_models）和按模型推断维度都用这张表；
+/// 表里没有的模型照样可以用，只是需要用户自己填写模型名称。
+const EMBEDDING_MODELS: &[(&str, &str, i32)] = &[
+    ("openai", "text-embedding-3-small", 1536),
+    ("openai", "text-embedding-3-large", 3072),
+    ("openai", "text-embedding-ada-002", 1536),
+    ("zhipu", "embedding-2", 1024),
+    ("zhipu", "embedding-3", 2048),
+    ("siliconflow", "BAAI/bge-large-zh-v1.5", 1024),
+    ("siliconflow", "BAAI/bge-m3", 1024),
+    ("jina", "jina-embeddings-v3", 1024),
+    ("jina", "jina-embeddings-v2-base-zh", 768),
+    ("jina", "jina-embeddings-v2-base-en", 768),
+    ("voyage", "voyage-3", 1024),
+    ("voyage", "voyage-3-large", 1024),
+    ("voyage", "voyage-3-lite", 512),
+    ("voyage", "voyage-multilingual-2", 1024),
+    ("cohere", "embed-multilingual-v3.0", 1024),
+    ("cohere", "embed-english-v3.0", 1024),
+    ("cohere", "embed-multilingual-light-v3.0", 384),
+];
+
+/// 可选的 Embedding 模型
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingModelInfo {
+    pub provider: String,
+    pub model: String,
+    pub dimension: i32,
+}
+
+/// 列出内置的 Embedding 模型（供设置页做模型候选）
+#[tauri::command]
+pub fn get_available_embedding_models() -> Vec<EmbeddingModelInfo> {
+    EMBEDDING_MODELS
+        .iter()
+        .map(|(provider, model, dimension)| EmbeddingModelInfo {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            dimension: *dimension,
+        })
+        .collect()
+}
+
+/// 被编码文本的用途：Voyage/Cohere/Jina v3 对入库文档和检索问题使用不同的编码方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InputType {
+    Document,
+    Query,
+}
+
+/// base_url 留空时各提供商的默认地址
+fn default_embedding_base_url(provider: &str) -> &'static str {
+    match provider {
+        "jina" => "https://api.jina.ai/v1",
+        "voyage" => "https://api.voyageai.com/v1",
+        "cohere" => "https://api.cohere.com/v2",
+        _ => "https://api.openai.com/v1",
+    }
+}
+
 /// 获取 Embedding API 端点 URL
 ///
 /// 直接基于用户配置的 base_url 拼接 `/embeddings`（与 llm.rs::build_url 对
 /// custom/local 提供商的处理方式一致），而不是依赖一份只覆盖 3 个服务商的
 /// 硬编码表 —— 这样能支持设置里任意一个 OpenAI 兼容的 Embedding API 配置，
-/// 而不仅仅是 openai/zhipu/siliconflow
-fn get_embedding_url(base_url: &str) -> String {
+/// 而不仅仅是 openai/zhipu/siliconflow。Cohere 的接口路径是 `/embed`。
+fn get_embedding_url(provider: &str, base_url: &str) -> String {
     let trimmed = base_url.trim_end_matches('/');
-    if trimmed.is_empty() {
-        return "https://api.openai.com/v1/embeddings".to_string();
+    let base = if trimmed.is_empty() { default_embedding_base_url(provider) } else { trimmed };
+    match provider {
+        "cohere" => format!("{}/embed", base),
+        _ => format!("{}/embeddings", base),
+    }
+}
+
+/// 按提供商构建请求体
+fn build_embedding_body(provider: &str, model: &str, texts: &[String], input_type: InputType) -> serde_json::Value {
+    match provider {
+        "zhipu" => {
+            json!({
+                "model": model,
+                "input": texts,
+            })
+        }
+        "jina" => {
+            let mut body = json!({
+                "model": model,
+                "input": texts,
+            });
+            // task 参数只有 v3 及之后的模型支持，v2 模型带上会被拒绝
+            if model.starts_with("jina-embeddings-v") && !model.starts_with("jina-embeddings-v2") {
+                body["task"] = json!(match input_type {
+                    InputType::Document => "retrieval.passage",
+                    InputType::Query => "retrieval.query",
+                });
+            }
+            body
+        }
+        "voyage" => {
+            json!({
+                "model": model,
+                "input": texts,
+                "input_type": match input_type {
+                    InputType::Document => "document",
+                    InputType::Query => "query",
+                },
+            })
+        }
+        "cohere" => {
+            json!({
+                "model": model,
+                "texts": texts,
+                "input_type": match input_type {
+                    InputType::Document => "search_document",
+                    InputType::Query => "search_query",
+                },
+                "embedding_types": ["float"],
+            })
+        }
+        _ => {
+            json!({
+                "model": model,
+                "input": texts,
+                "encoding_format": "float",
+            })
+        }
     }
-    format!("{}/embeddings", trimmed)
 }
 
 /// 批量处理的大小限制
 const EMBEDDING_BATCH_SIZE: usize = 100;
 
+/// 单批 embedding 请求的失败：retryable 表示限流/过载/网络类的临时错误，值得退避后重试
+struct BatchFailure {
+    error: KnowledgeBaseError,
+    retryable: bool,
+}
+
+impl BatchFailure {
+    fn fatal(message: String) -> Self {
+        Self { error: KnowledgeBaseError::EmbeddingError(message), retryable: false }
+    }
+
+    fn retryable(message: String) -> Self {
+        Self { error: KnowledgeBaseError::EmbeddingError(message), retryable: true }
+    }
+}
+
 /// 生成文本批次嵌入向量
 /// 
 /// # 参数
@@ -68,6 +210,54 @@ pub async fn generate_embeddings(
     api_key: &str,
     model: &str,
     base_url: &str,
+) -> Result<Vec<Vec<f32>>, KnowledgeBaseError> {
+    embed_texts(texts, InputType::Document, provider, api_key, model, base_url).await
+}
+
+/// 分批生成向量并逐批落盘
+///
+/// 每批向量生成后立即交给 persist 保存作为断点，中途失败时已保存的批次不用重来。
+/// 批与批之间等待 EMBEDDING_BATCH_DELAY_MS，最后一批之后不再等待。
+/// persist 返回 false 表示任务已被取消，剩下的批次不再处理，整体也返回 false。
+pub(crate) async fn embed_in_batches<'a, T, Fut>(
+    items: &'a [T],
+    text_of: impl Fn(&T) -> String,
+    target: &EmbeddingTarget,
+    mut persist: impl FnMut(&'a [T], Vec<Vec<f32>>) -> Fut,
+) -> Result<bool, KnowledgeBaseError>
+where
+    Fut: std::future::Future<Output = Result<bool, KnowledgeBaseError>>,
+{
+    for (i, batch) in items.chunks(EMBEDDING_BATCH_SIZE).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_millis(crate::commands::constants::EMBEDDING_BATCH_DELAY_MS)).await;
+        }
+
+        let texts: Vec<String> = batch.iter().map(&text_of).collect();
+        let embeddings = generate_embeddings(texts, &target.provider, &target.api_key, &target.model, &target.base_url)
+            .await
+            .map_err(|e| KnowledgeBaseError::EmbeddingError(format!("Embedding generation failed: {}", e)))?;
+        if embeddings.len() != batch.len() {
+            return Err(KnowledgeBaseError::EmbeddingError(format!(
+                "Embedding count mismatch: {} chunks, {} vectors",
+                batch.len(),
+                embeddings.len()
+            )));
+        }
+        if !persist(batch, embeddings).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+async fn embed_texts(
+    texts: Vec<String>,
+    input_type: InputType,
+    provider: &str,
+    api_key: &str,
+    model: &str,
+    base_url: &str,
 ) -> Result<Vec<Vec<f32>>, KnowledgeBaseError> {
     if texts.is_empty() {
         return Ok(Vec::new());
@@ -75,56 +265,89 @@ pub async fn generate_embeddings(
 
     let mut all_embeddings = Vec::new();
 
-    for chunk in texts.chunks(EMBEDDING_BATCH_SIZE) {
-        let batch_embeddings = generate_embeddings_batch(
-            chunk.to_vec(),
+    for (i, chunk) in texts.chunks(EMBEDDING_BATCH_SIZE).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(
+                crate::commands::constants::EMBEDDING_BATCH_DELAY_MS,
+            )).await;
+        }
+
+        let batch_embeddings = generate_embeddings_batch_with_retry(
+            chunk,
+            input_type,
             provider,
             api_key,
             model,
             base_url,
         ).await?;
         all_embeddings.extend(batch_embeddings);
-
-        if texts.len() > EMBEDDING_BATCH_SIZE {
-            tokio::time::sleep(std::time::Duration::from_millis(
-                crate::commands::constants::EMBEDDING_BATCH_DELAY_MS,
-            )).await;
-        }
     }
 
     Ok(all_embeddings)
 }
 
-async fn generate_embeddings_batch(
-    texts: Vec<String>,
+/// 第 attempt 次重试（从 0 开始）前的退避时长：基础间隔逐次翻倍，不超过上限
+fn retry_delay(attempt: u32) -> Duration {
+    EMBEDDING_RETRY_BASE_DELAY
+        .saturating_mul(1u32 << attempt.min(16))
+        .min(EMBEDDING_RETRY_MAX_DELAY)
+}
+
+/// 发送一批 embedding 请求，临时性错误最多重试 EMBEDDING_MAX_RETRIES 次
+///
+/// 每次发送前先等 provider 的限流解除（429 带 Retry-After 时由
+/// generate_embeddings_batch 记下），其余临时错误按 retry_delay 退避。
+async fn generate_embeddings_batch_with_retry(
+    texts: &[String],
+    input_type: InputType,
     provider: &str,
     api_key: &str,
     model: &str,
     base_url: &str,
 ) -> Result<Vec<Vec<f32>>, KnowledgeBaseError> {
+    let mut attempt = 0;
+    loop {
+        wait_for_provider(provider, None)
+            .await
+            .map_err(|e| KnowledgeBaseError::EmbeddingError(e.to_string()))?;
+
+        match generate_embeddings_batch(texts, input_type, provider, api_key, model, base_url).await {
+            Ok(embeddings) => return Ok(embeddings),
+            Err(failure) if failure.retryable && attempt < EMBEDDING_MAX_RETRIES => {
+                let delay = retry_delay(attempt);
+                attempt += 1;
+                log::warn!(
+                    "[KB] Embedding batch failed, retrying ({}/{}) in {:.1}s: {}",
+                    attempt,
+                    EMBEDDING_MAX_RETRIES,
+                    delay.as_secs_f64(),
+                    failure.error
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(failure) => return Err(failure.error),
+        }
+    }
+}
+
+async fn generate_embeddings_batch(
+    texts: &[String],
+    input_type: InputType,
+    provider: &str,
+    api_key: &str,
+    model: &str,
+    base_url: &str,
+) -> Result<Vec<Vec<f32>>, BatchFailure> {
     if texts.is_empty() {
         return Ok(Vec::new());
     }
 
-    let url = get_embedding_url(base_url);
-    let client = reqwest::Client::new();
+    let url = get_embedding_url(provider, base_url);
+    let client = shared_client(ClientKind::General, Some(provider), &url)
+        .map_err(|e| BatchFailure::fatal(format!("Failed to build HTTP client: {}", e)))?;
     
     // 构建请求体
-    let body = match provider {
-        "zhipu" => {
-            json!({
-                "model": model,
-                "input": texts,
-            })
-        }
-        _ => {
-            json!({
-                "model": model,
-                "input": texts,
-                "encoding_format": "float",
-            })
-        }
-    };
+    let body = build_embedding_body(provider, model, texts, input_type);
     
     // 构建请求头
     let mut headers = reqwest::header::HeaderMap::new();
@@ -135,7 +358,7 @@ async fn generate_embeddings_batch(
     
     let auth_value = format!("Bearer {}", api_key.trim())
         .parse()
-        .map_err(|e| KnowledgeBaseError::EmbeddingError(format!("Invalid API key: {}", e)))?;
+        .map_err(|e| BatchFailure::fatal(format!("Invalid API key: {}", e)))?;
     headers.insert(reqwest::header::AUTHORIZATION, auth_value);
     
     log::info!("Sending embedding request to {} for {} texts", provider, texts.len());
@@ -146,12 +369,28 @@ async fn generate_embeddings_batch(
         .json(&body)
         .send()
         .await
-        .map_err(|e| KnowledgeBaseError::EmbeddingError(format!("Request failed: {}", e)))?;
+        .map_err(|e| {
+            let message = format!("Request failed: {}", e);
+            if is_retryable_reqwest_error(&e) {
+                BatchFailure::retryable(message)
+            } else {
+                BatchFailure::fatal(message)
+            }
+        })?;
     
     if !response.status().is_success() {
         let status = response.status();
+        let retry_after = parse_retry_after(response.headers());
         let error_text = response.text().await
-            .map_err(|e| KnowledgeBaseError::EmbeddingError(format!("Failed to read error: {}", e)))?;
+            .map_err(|e| BatchFailure::retryable(format!("Failed to read error: {}", e)))?;
+
+        if is_retryable_status(status, &error_text) {
+            // 服务商明确给出了等待时长时记到限流状态里，下一次发送（包括聊天请求）会先排队等待
+            if let Some(wait) = retry_after {
+                mark_throttled(provider, wait, &format!("{} {}", status, error_text));
+            }
+            return Err(BatchFailure::retryable(format!("API error ({}): {}", status, error_text)));
+        }
 
         // 4xx 很常见的两个原因是 API Key/模型名写错，或者单个分块超出了该
         // Embedding 模型的输入长度上限（比如 BAAI/bge-large-zh-v1.5 实测约
@@ -168,15 +407,16 @@ async fn generate_embeddings_batch(
             String::new()
         };
 
-        return Err(KnowledgeBaseError::EmbeddingError(format!(
+        return Err(BatchFailure::fatal(format!(
             "API error ({}): {}{}", status, error_text, hint
         )));
     }
     
     let json: serde_json::Value = response.json().await
-        .map_err(|e| KnowledgeBaseError::EmbeddingError(format!("Failed to parse response: {}", e)))?;
+        .map_err(|e| BatchFailure::retryable(format!("Failed to parse response: {}", e)))?;
     
-    let embeddings = parse_embedding_response(&json)?;
+    let embeddings = parse_embedding_response(provider, &json)
+        .map_err(|error| BatchFailure { error, retryable: false })?;
     
     log::info!("Generated {} embeddings", embeddings.len());
     Ok(embeddings)
@@ -197,7 +437,21 @@ fn parse_embedding_array(data: &[serde_json::Value]) -> Result<Vec<Vec<f32>>, Kn
     Ok(embeddings)
 }
 
-fn parse_embedding_response(json: &serde_json::Value) -> Result<Vec<Vec<f32>>, KnowledgeBaseError> {
+fn parse_embedding_response(provider: &str, json: &serde_json::Value) -> Result<Vec<Vec<f32>>, KnowledgeBaseError> {
+    // Cohere v2：{"embeddings": {"float": [[...], ...]}}
+    if provider == "cohere" {
+        let rows = json.pointer("/embeddings/float")
+            .and_then(|d| d.as_array())
+            .ok_or_else(|| KnowledgeBaseError::EmbeddingError("Invalid response format".to_string()))?;
+        return Ok(rows.iter()
+            .map(|row| {
+                row.as_array()
+                    .map(|values| values.iter().filter_map(|v| v.as_f64().map(|f| f as f32)).collect())
+                    .unwrap_or_default()
+            })
+            .collect());
+    }
+
     let data = json.get("data")
         .and_then(|d| d.as_array())
         .ok_or_else(|| KnowledgeBaseError::EmbeddingError("Invalid response format".to_string()))?;
@@ -205,7 +459,7 @@ fn parse_embedding_response(json: &serde_json::Value) -> Result<Vec<Vec<f32>>, K
     parse_embedding_array(data)
 }
 
-/// 生成单条文本的 embedding
+/// 生成单条检索问题的 embedding
 pub async fn generate_single_embedding(
     text: &str,
     provider: &str,
@@ -213,7 +467,7 @@ pub async fn generate_single_embedding(
     model: &str,
     base_url: &str,
 ) -> Result<Vec<f32>, KnowledgeBaseError> {
-    let embeddings = generate_embeddings(vec![text.to_string()], provider, api_key, model, base_url).await?;
+    let embeddings = embed_texts(vec![text.to_string()], InputType::Query, provider, api_key, model, base_url).await?;
     embeddings.into_iter().next()
         .ok_or_else(|| KnowledgeBaseError::EmbeddingError("No embedding generated".to_string()))
 }
@@ -221,12 +475,48 @@ pub async fn generate_single_embedding(
 /// 获取指定模型的 embedding 向量维度
 #[allow(dead_code)]
 pub fn get_embedding_dimension(provider: &str, model: &str) -> i32 {
-    match (provider, model) {
-        ("openai", "text-embedding-3-small") => 1536,
-        ("openai", "text-embedding-3-large") => 3072,
-        ("openai", "text-embedding-ada-002") => 1536,
-        ("zhipu", _) => 1024,
-        ("siliconflow", _) => 1024,
+    if let Some((_, _, dimension)) = EMBEDDING_MODELS.iter().find(|(p, m, _)| *p == provider && *m == model) {
+        return *dimension;
+    }
+    match provider {
+        "zhipu" | "siliconflow" | "jina" | "voyage" | "cohere" => 1024,
         _ => 1536,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_and_is_capped() {
+        assert_eq!(retry_delay(0), EMBEDDING_RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(1), EMBEDDING_RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(2), EMBEDDING_RETRY_BASE_DELAY * 4);
+        assert_eq!(retry_delay(40), EMBEDDING_RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn hosted_providers_use_their_own_endpoints_and_formats() {
+        assert_eq!(get_embedding_url("cohere", ""), "https://api.cohere.com/v2/embed");
+        assert_eq!(get_embedding_url("voyage", ""), "https://api.voyageai.com/v1/embeddings");
+        assert_eq!(get_embedding_url("jina", "https://proxy.example/v1/"), "https://proxy.example/v1/embeddings");
+        assert_eq!(get_embedding_url("custom", ""), "https://api.openai.com/v1/embeddings");
+
+        let texts = vec!["你好".to_string()];
+        let cohere = build_embedding_body("cohere", "embed-multilingual-v3.0", &texts, InputType::Query);
+        assert_eq!(cohere["texts"][0], "你好");
+        assert_eq!(cohere["input_type"], "search_query");
+        let voyage = build_embedding_body("voyage", "voyage-3", &texts, InputType::Document);
+        assert_eq!(voyage["input_type"], "document");
+        assert!(voyage.get("encoding_format").is_none());
+        let jina_v2 = build_embedding_body("jina", "jina-embeddings-v2-base-zh", &texts, InputType::Query);
+        assert!(jina_v2.get("task").is_none());
+        let jina_v3 = build_embedding_body("jina", "jina-embeddings-v3", &texts, InputType::Query);
+        assert_eq!(jina_v3["task"], "retrieval.query");
+
+        let response = json!({"embeddings": {"float": [[0.5, 1.0], [0.25, 0.0]]}});
+        assert_eq!(parse_embedding_response("cohere", &response).unwrap(), vec![vec![0.5, 1.0], vec![0.25, 0.0]]);
+        assert_eq!(get_embedding_dimension("voyage", "voyage-3-lite"), 512);
+    }
+}
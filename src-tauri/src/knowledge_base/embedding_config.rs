@@ -0,0 +1,264 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * Embedding API 配置模块
+ *
+ * 功能说明:
+ * - embedding_configs 表保存每个配置的服务商、模型、Base URL 和 API Key 在 keyring 里的条目名，
+ *   知识库只通过 embedding_api_config_id 引用配置
+ * - 创建知识库、导入知识库归档、更换模型时从配置里取服务商/模型，不再由前端另外传一份
+ * - 导入文档、检索时用 kb_embedding 统一取得模型和 API Key
+ * - 被知识库引用的配置不能删除，也不能改服务商/模型/Base URL（知识库的向量是按原模型生成的，
+ *   要换模型请用 reembed_knowledge_base 迁移）；名称可以随时改
+ *
+ * 知识库表上仍然保留 embedding_provider/model/base_url 三列，记录向量实际是由哪个模型生成的。
+ */
+
+use super::commands::{embedding_target, with_conn, KbState};
+use super::types::*;
+use keyring::Entry;
+use rusqlite::OptionalExtension;
+use tauri::State;
+
+const CONFIG_COLUMNS: &str = "id, name, provider, model, base_url, key_ref, created_at, updated_at,
+     (SELECT COUNT(*) FROM knowledge_bases kb WHERE kb.embedding_api_config_id = embedding_configs.id)";
+
+fn config_from_row(row: &rusqlite::Row) -> rusqlite::Result<EmbeddingConfig> {
+    Ok(EmbeddingConfig {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        provider: row.get(2)?,
+        model: row.get(3)?,
+        base_url: row.get(4)?,
+        key_ref: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+        kb_count: row.get(8)?,
+    })
+}
+
+/// 按 id 读取配置，不存在时返回 InvalidConfig（多半是配置已经在设置里删掉了）
+pub(crate) fn load_embedding_config(
+    conn: &rusqlite::Connection,
+    config_id: &str,
+) -> Result<EmbeddingConfig, KnowledgeBaseError> {
+    conn.query_row(
+        &format!("SELECT {} FROM embedding_configs WHERE id = ?1", CONFIG_COLUMNS),
+        [config_id],
+        config_from_row,
+    )
+    .optional()
+    .map_err(db_error)?
+    .ok_or_else(|| {
+        KnowledgeBaseError::InvalidConfig(format!("Embedding API 配置不存在: {}，请在设置里重新选择", config_id))
+    })
+}
+
+/// 找一个指定服务商和模型的配置（同步过来的知识库在本机还没有配置可用时）
+pub(crate) fn find_embedding_config(
+    conn: &rusqlite::Connection,
+    provider: &str,
+    model: &str,
+) -> Result<Option<String>, KnowledgeBaseError> {
+    conn.query_row(
+        "SELECT id FROM embedding_configs WHERE provider = ?1 AND model = ?2 ORDER BY created_at LIMIT 1",
+        [provider, model],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(db_error)
+}
+
+/// 从系统 keyring 读取 API Key；key_ref 是 secure_storage 保存时用的条目名
+pub(crate) fn read_api_key(key_ref: &str) -> Result<String, KnowledgeBaseError> {
+    let entry = Entry::new("BaiyuAISpace", &format!("api_keys_{}", key_ref))
+        .map_err(|e| KnowledgeBaseError::InvalidConfig(format!("Failed to access keyring: {}", e)))?;
+
+    match entry.get_password() {
+        Ok(key) => Ok(key),
+        Err(keyring::Error::NoEntry) => Err(KnowledgeBaseError::InvalidConfig(format!(
+            "Embedding API key not found for {}. Please set it in Settings.",
+            key_ref
+        ))),
+        Err(e) => Err(KnowledgeBaseError::InvalidConfig(format!("Failed to retrieve API key: {}", e))),
+    }
+}
+
+/// 为知识库生成 embedding 所需的一切
+pub(crate) struct EmbeddingTarget {
+    pub provider: String,
+    pub model: String,
+    pub base_url: String,
+    pub api_key: String,
+}
+
+/// 知识库生成 embedding 时使用的模型和 API Key
+///
+/// 模型取知识库上记录的（和已有向量一致），API Key 取它引用的配置的。
+pub(crate) fn kb_embedding(
+    conn: &rusqlite::Connection,
+    kb: &KnowledgeBase,
+) -> Result<EmbeddingTarget, KnowledgeBaseError> {
+    let config = load_embedding_config(conn, &kb.embedding_api_config_id)?;
+    let (provider, model, base_url) = embedding_target(kb);
+    Ok(EmbeddingTarget { provider, model, base_url, api_key: read_api_key(&config.key_ref)? })
+}
+
+fn save_config(
+    conn: &rusqlite::Connection,
+    request: SaveEmbeddingConfigRequest,
+) -> Result<EmbeddingConfig, KnowledgeBaseError> {
+    if request.id.trim().is_empty() || request.provider.trim().is_empty() || request.model.trim().is_empty() {
+        return Err(KnowledgeBaseError::InvalidConfig("id, provider and model are required".to_string()));
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    match load_embedding_config(conn, &request.id) {
+        Ok(existing) => {
+            let model_changed = existing.provider != request.provider
+                || existing.model != request.model
+                || existing.base_url != request.base_url;
+            if model_changed && existing.kb_count > 0 {
+                return Err(KnowledgeBaseError::InvalidConfig(format!(
+                    "有 {} 个知识库正在使用这个配置，不能修改服务商、模型或 Base URL；请新建配置后在知识库里更换模型",
+                    existing.kb_count
+                )));
+            }
+            conn.execute(
+                "UPDATE embedding_configs SET name = ?1, provider = ?2, model = ?3, base_url = ?4, updated_at = ?5 WHERE id = ?6",
+                rusqlite::params![&request.name, &request.provider, &request.model, &request.base_url, now, &request.id],
+            )
+            .map_err(db_error)?;
+        }
+        Err(KnowledgeBaseError::InvalidConfig(_)) => {
+            conn.execute(
+                "INSERT INTO embedding_configs (id, name, provider, model, base_url, key_ref, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+                rusqlite::params![
+                    &request.id,
+                    &request.name,
+                    &request.provider,
+                    &request.model,
+                    &request.base_url,
+                    format!("emb_{}", request.id),
+                    now
+                ],
+            )
+            .map_err(db_error)?;
+        }
+        Err(e) => return Err(e),
+    }
+    load_embedding_config(conn, &request.id)
+}
+
+fn delete_config(conn: &rusqlite::Connection, config_id: &str) -> Result<(), KnowledgeBaseError> {
+    let kb_names: Vec<String> = conn
+        .prepare("SELECT name FROM knowledge_bases WHERE embedding_api_config_id = ?1 ORDER BY name")
+        .and_then(|mut stmt| stmt.query_map([config_id], |row| row.get(0))?.collect())
+        .map_err(db_error)?;
+    if !kb_names.is_empty() {
+        return Err(KnowledgeBaseError::InvalidConfig(format!(
+            "知识库 {} 正在使用这个配置，请先为它们更换模型或删除知识库",
+            kb_names.join("、")
+        )));
+    }
+    conn.execute("DELETE FROM embedding_configs WHERE id = ?1", [config_id]).map_err(db_error)?;
+    Ok(())
+}
+
+/// 列出全部 Embedding API 配置（带引用它的知识库数）
+#[tauri::command]
+pub async fn list_embedding_configs(kb_state: State<'_, KbState>) -> Result<Vec<EmbeddingConfig>, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, |conn| {
+        let mut stmt = conn
+            .prepare(&format!("SELECT {} FROM embedding_configs ORDER BY created_at", CONFIG_COLUMNS))
+            .map_err(db_error)?;
+        let configs = stmt
+            .query_map([], config_from_row)
+            .map_err(db_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_error)?;
+        Ok(configs)
+    })
+    .await
+}
+
+/// 新建或修改 Embedding API 配置
+#[tauri::command]
+pub async fn save_embedding_config(
+    request: SaveEmbeddingConfigRequest,
+    kb_state: State<'_, KbState>,
+) -> Result<EmbeddingConfig, KnowledgeBaseError> {
+    let config = with_conn(&kb_state.db_path, move |conn| save_config(&conn, request)).await?;
+    log::info!("[KB] Saved embedding config {} ({}/{})", config.id, config.provider, config.model);
+    Ok(config)
+}
+
+/// 删除没有被知识库引用的 Embedding API 配置；keyring 里的 API Key 由前端删除
+#[tauri::command]
+pub async fn delete_embedding_config(config_id: String, kb_state: State<'_, KbState>) -> Result<(), KnowledgeBaseError> {
+    let id = config_id.clone();
+    with_conn(&kb_state.db_path, move |conn| delete_config(&conn, &id)).await?;
+    log::info!("[KB] Deleted embedding config {}", config_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(model: &str) -> SaveEmbeddingConfigRequest {
+        SaveEmbeddingConfigRequest {
+            id: "cfg".to_string(),
+            name: "OpenAI".to_string(),
+            provider: "openai".to_string(),
+            model: model.to_string(),
+            base_url: String::new(),
+        }
+    }
+
+    #[test]
+    fn configs_in_use_keep_their_model_and_cannot_be_deleted() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+
+        let saved = save_config(&conn, request("text-embedding-3-small")).unwrap();
+        assert_eq!((saved.key_ref.as_str(), saved.kb_count), ("emb_cfg", 0));
+        conn.execute(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at)
+             VALUES ('kb', '手册', 'cfg', 'openai', 'text-embedding-3-small', 0, 0)",
+            [],
+        )
+        .unwrap();
+
+        assert!(save_config(&conn, request("text-embedding-3-large")).is_err());
+        assert!(delete_config(&conn, "cfg").is_err());
+        let renamed = save_config(&conn, SaveEmbeddingConfigRequest { name: "主力".to_string(), ..request("text-embedding-3-small") }).unwrap();
+        assert_eq!((renamed.name.as_str(), renamed.kb_count), ("主力", 1));
+
+        conn.execute("DELETE FROM knowledge_bases", []).unwrap();
+        delete_config(&conn, "cfg").unwrap();
+        assert!(load_embedding_config(&conn, "cfg").is_err());
+    }
+
+    #[test]
+    fn configs_referenced_by_existing_kbs_are_migrated() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, embedding_base_url, created_at, updated_at)
+             VALUES ('kb', 'kb', 'old', 'voyage', 'voyage-3', 'https://api.voyageai.com', 5, 5)",
+            [],
+        )
+        .unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+
+        let config = load_embedding_config(&conn, "old").unwrap();
+        assert_eq!(
+            (config.provider.as_str(), config.model.as_str(), config.base_url.as_str(), config.key_ref.as_str()),
+            ("voyage", "voyage-3", "https://api.voyageai.com", "emb_old")
+        );
+    }
+}
@@ -0,0 +1,267 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 知识库加密存储模块
+ *
+ * 功能说明:
+ * - 创建知识库时可以选择加密：分块内容、父块、文档全文和向量用 AES-256-GCM 加密后写入，
+ *   密钥随机生成，保存在系统 keyring（BaiyuAISpace / kb_key_{知识库 id}）
+ * - 密文自带知识库 id，读取时按 id 取密钥透明解密：向量由 db::bytes_to_vector 识别，
+ *   文本由 open_text 用所属知识库的加密器解密，没加密的知识库原样返回
+ * - 密钥读出来之后缓存在内存里，检索时不用每次访问 keyring
+ *
+ * 文件名、标签、标题路径等元数据不加密。加密知识库不建全文索引和 ANN 索引
+ * （两者都会把内容或向量以明文形式落盘），检索只走向量相似度；也不支持 binary 量化
+ * （符号位会泄露向量的大致方向）。
+ */
+
+use super::quantization::{encode_vector, EncodedVector};
+use super::types::{KnowledgeBase, KnowledgeBaseError, VectorQuantization};
+use base64::Engine;
+use keyring::Entry;
+use once_cell::sync::Lazy;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// 加密文本的前缀，后面是 “知识库 id:base64(nonce + 密文)”
+const SEALED_TEXT_PREFIX: &str = "enc1:";
+/// 加密向量的开头 4 个字节，和 int8 编码一样按 f32 解读是 NaN，不会和普通向量混淆
+const SEALED_VECTOR_MAGIC: [u8; 4] = [0x7E, 0xFF, 0xFF, 0xFF];
+/// 密文无法解密（密钥丢失或数据损坏）时代替内容显示的文字
+const UNREADABLE_TEXT: &str = "[内容无法解密：知识库密钥丢失或数据已损坏]";
+
+/// 已经从 keyring 读出的密钥：知识库 id → 加密器
+static CIPHERS: Lazy<Mutex<HashMap<String, Arc<KbCipher>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn crypto_error(message: &str) -> KnowledgeBaseError {
+    KnowledgeBaseError::InvalidConfig(message.to_string())
+}
+
+fn key_entry(kb_id: &str) -> Result<Entry, KnowledgeBaseError> {
+    Entry::new("BaiyuAISpace", &format!("kb_key_{}", kb_id))
+        .map_err(|e| KnowledgeBaseError::InvalidConfig(format!("Failed to access keyring: {}", e)))
+}
+
+/// 一个知识库的加密器，附加数据（AAD）是知识库 id，密文不能被挪到别的知识库里解密
+pub(crate) struct KbCipher {
+    kb_id: String,
+    key: LessSafeKey,
+}
+
+impl KbCipher {
+    fn new(kb_id: &str, key: &[u8]) -> Result<Self, KnowledgeBaseError> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| crypto_error("知识库密钥格式不正确"))?;
+        Ok(KbCipher { kb_id: kb_id.to_string(), key: LessSafeKey::new(key) })
+    }
+
+    /// 加密，返回 nonce + 密文 + 校验标签
+    fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).expect("system random generator failed");
+        let mut in_out = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(self.kb_id.as_bytes()), &mut in_out)
+            .expect("AES-GCM seal failed");
+        let mut sealed = nonce.to_vec();
+        sealed.append(&mut in_out);
+        sealed
+    }
+
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).ok()?;
+        let mut in_out = sealed[NONCE_LEN..].to_vec();
+        let plain = self.key.open_in_place(nonce, Aad::from(self.kb_id.as_bytes()), &mut in_out).ok()?;
+        Some(plain.to_vec())
+    }
+
+    /// 加密要写入 TEXT 列的内容
+    pub fn seal_text(&self, text: &str) -> String {
+        let sealed = base64::engine::general_purpose::STANDARD.encode(self.seal(text.as_bytes()));
+        format!("{}{}:{}", SEALED_TEXT_PREFIX, self.kb_id, sealed)
+    }
+
+    /// 加密要写入 vectors.vector 列的向量编码
+    pub fn seal_vector(&self, encoded: &[u8]) -> Vec<u8> {
+        let mut bytes = SEALED_VECTOR_MAGIC.to_vec();
+        bytes.push(self.kb_id.len() as u8);
+        bytes.extend_from_slice(self.kb_id.as_bytes());
+        bytes.extend(self.seal(encoded));
+        bytes
+    }
+}
+
+/// 记住一个知识库的密钥
+fn remember(cipher: KbCipher) -> Arc<KbCipher> {
+    let cipher = Arc::new(cipher);
+    CIPHERS.lock().unwrap_or_else(|e| e.into_inner()).insert(cipher.kb_id.clone(), cipher.clone());
+    cipher
+}
+
+/// 按知识库 id 取密钥，先查内存缓存，没有再读 keyring
+fn cipher_by_id(kb_id: &str) -> Result<Arc<KbCipher>, KnowledgeBaseError> {
+    if let Some(cipher) = CIPHERS.lock().unwrap_or_else(|e| e.into_inner()).get(kb_id) {
+        return Ok(cipher.clone());
+    }
+    let encoded = key_entry(kb_id)?.get_password().map_err(|e| match e {
+        keyring::Error::NoEntry => crypto_error("知识库的加密密钥不在系统钥匙串里，无法解密"),
+        e => KnowledgeBaseError::InvalidConfig(format!("Failed to retrieve knowledge base key: {}", e)),
+    })?;
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| crypto_error("知识库密钥格式不正确"))?;
+    Ok(remember(KbCipher::new(kb_id, &key)?))
+}
+
+/// 为新建的加密知识库生成密钥并保存到 keyring
+pub(crate) fn create_kb_key(kb_id: &str) -> Result<(), KnowledgeBaseError> {
+    let mut key = [0u8; 32];
+    SystemRandom::new().fill(&mut key).map_err(|_| crypto_error("无法生成随机密钥"))?;
+    key_entry(kb_id)?
+        .set_password(&base64::engine::general_purpose::STANDARD.encode(key))
+        .map_err(|e| KnowledgeBaseError::InvalidConfig(format!("Failed to store knowledge base key: {}", e)))?;
+    remember(KbCipher::new(kb_id, &key)?);
+    Ok(())
+}
+
+/// 删除知识库的密钥（知识库删除之后调用）
+pub(crate) fn delete_kb_key(kb_id: &str) {
+    CIPHERS.lock().unwrap_or_else(|e| e.into_inner()).remove(kb_id);
+    if let Ok(entry) = key_entry(kb_id) {
+        match entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(e) => log::warn!("[KB] Failed to delete key of knowledge base {}: {}", kb_id, e),
+        }
+    }
+}
+
+/// 知识库开启了加密时返回它的加密器，写入内容和向量之前用它加密
+pub(crate) fn kb_cipher(conn: &rusqlite::Connection, kb_id: &str) -> Result<Option<Arc<KbCipher>>, KnowledgeBaseError> {
+    use rusqlite::OptionalExtension;
+    let encrypted: Option<bool> = conn
+        .query_row("SELECT COALESCE(encrypted, 0) FROM knowledge_bases WHERE id = ?1", [kb_id], |row| row.get(0))
+        .optional()
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    match encrypted {
+        Some(true) => cipher_by_id(kb_id).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// 同 kb_cipher，按文档所属的知识库判断（文档不存在时按没加密处理）
+pub(crate) fn document_cipher(conn: &rusqlite::Connection, document_id: &str) -> Result<Option<Arc<KbCipher>>, KnowledgeBaseError> {
+    use rusqlite::OptionalExtension;
+    let owner: Option<(String, bool)> = conn
+        .query_row(
+            "SELECT k.id, COALESCE(k.encrypted, 0) FROM documents d JOIN knowledge_bases k ON k.id = d.kb_id WHERE d.id = ?1",
+            [document_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+    match owner {
+        Some((kb_id, true)) => cipher_by_id(&kb_id).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// 同 kb_cipher，用已经读出的知识库设置判断是否加密
+pub(crate) fn cipher_for(kb: &KnowledgeBase) -> Result<Option<Arc<KbCipher>>, KnowledgeBaseError> {
+    match kb.encrypted {
+        true => cipher_by_id(&kb.id).map(Some),
+        false => Ok(None),
+    }
+}
+
+/// 有加密器时加密文本，否则原样返回
+pub(crate) fn seal_text(cipher: Option<&KbCipher>, text: &str) -> String {
+    match cipher {
+        Some(cipher) => cipher.seal_text(text),
+        None => text.to_string(),
+    }
+}
+
+/// 有加密器时加密向量编码，否则原样返回
+pub(crate) fn seal_vector(cipher: Option<&KbCipher>, encoded: Vec<u8>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal_vector(&encoded),
+        None => encoded,
+    }
+}
+
+/// 按量化方式编码要写入 vectors 表的向量，知识库加密时再加密（加密后不保存符号位）
+pub(crate) fn encode_for_kb(vector: &[f32], quantization: VectorQuantization, cipher: Option<&KbCipher>) -> EncodedVector {
+    let encoded = encode_vector(vector, quantization);
+    match cipher {
+        Some(cipher) => EncodedVector { vector: cipher.seal_vector(&encoded.vector), code: None },
+        None => encoded,
+    }
+}
+
+/// 解密从 TEXT 列读出的内容。cipher 是内容所属知识库的加密器（kb_cipher / cipher_for），
+/// 知识库没加密时为 None，内容原样返回，不会把恰好以 "enc1:" 开头的明文当成密文；
+/// 无法解密时返回提示文字
+pub(crate) fn open_text(cipher: Option<&KbCipher>, stored: String) -> String {
+    let Some(cipher) = cipher else {
+        return stored;
+    };
+    let Some(rest) = stored.strip_prefix(SEALED_TEXT_PREFIX) else {
+        return stored;
+    };
+    let opened = rest.split_once(':').filter(|(kb_id, _)| *kb_id == cipher.kb_id).and_then(|(_, sealed)| {
+        let sealed = base64::engine::general_purpose::STANDARD.decode(sealed).ok()?;
+        String::from_utf8(cipher.open(&sealed)?).ok()
+    });
+    opened.unwrap_or_else(|| UNREADABLE_TEXT.to_string())
+}
+
+/// 向量编码是不是加密过的
+pub(crate) fn is_sealed_vector(bytes: &[u8]) -> bool {
+    bytes.len() > 5 && bytes[..4] == SEALED_VECTOR_MAGIC
+}
+
+/// 解密加密过的向量编码，返回里面的 f32/int8 编码；无法解密时返回 None
+pub(crate) fn open_vector(bytes: &[u8]) -> Option<Vec<u8>> {
+    let id_len = bytes[4] as usize;
+    let kb_id = std::str::from_utf8(bytes.get(5..5 + id_len)?).ok()?;
+    let cipher = cipher_by_id(kb_id).map_err(|e| log::warn!("[KB] {}", e)).ok()?;
+    cipher.open(&bytes[5 + id_len..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knowledge_base::db::{bytes_to_vector, vector_to_bytes};
+
+    #[test]
+    fn sealed_content_and_vectors_open_transparently() {
+        let cipher = remember(KbCipher::new("kb-secret", &[7u8; 32]).unwrap());
+
+        let sealed = cipher.seal_text("合同第 3 条：保密期限五年");
+        assert!(sealed.starts_with(SEALED_TEXT_PREFIX) && !sealed.contains("合同"));
+        assert_eq!(open_text(Some(&*cipher), sealed.clone()), "合同第 3 条：保密期限五年");
+        assert_eq!(open_text(Some(&*cipher), "明文".to_string()), "明文");
+        // 没加密的知识库里碰巧以前缀开头的内容原样返回
+        assert_eq!(open_text(None, "enc1:kb-secret:普通笔记".to_string()), "enc1:kb-secret:普通笔记");
+
+        let vector = vec![0.25f32, -0.5, 1.0];
+        let sealed_vector = cipher.seal_vector(&vector_to_bytes(&vector));
+        assert!(is_sealed_vector(&sealed_vector));
+        assert_eq!(bytes_to_vector(&sealed_vector), vector);
+
+        // 换了知识库 id（AAD 不同）或者密文被改动都无法解密
+        let other = remember(KbCipher::new("kb-other", &[7u8; 32]).unwrap());
+        let moved = sealed.replacen("kb-secret", &other.kb_id, 1);
+        assert_eq!(open_text(Some(&*other), moved), UNREADABLE_TEXT);
+        assert_eq!(open_text(Some(&*other), sealed), UNREADABLE_TEXT);
+        let mut tampered = sealed_vector.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_vector(&tampered).is_none());
+    }
+}
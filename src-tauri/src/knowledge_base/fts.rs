@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 全文检索分词模块
+ *
+ * 功能说明:
+ * - FTS5 自带的分词器按空白和标点切词，一整段中文会被当成一个词，中文关键词检索基本查不到
+ * - 写入 chunks_fts 之前用 segment_for_index 预分词：中日韩文字按相邻两字切成二元词，
+ *   每段末尾再补一个单字；其余文字原样保留，仍交给 porter 分词器处理
+ * - 检索时用 build_match_query 按同样的规则切分问题：连续的中日韩文字组成一个短语，
+ *   二元词必须相邻出现，相当于子串匹配；单个汉字用前缀匹配
+ * - ensure_fts_table 在建表时检查分词方式，旧版本（没有预分词）的索引会整体重建
+ * - match_ranges / build_snippet 在原文里找出问题的各个词，给检索结果生成带高亮位置的摘录
+ *
+ * 预分词只影响 chunks_fts 里的内容，chunks 表里保存的仍是原文。FTS5 的 snippet()/highlight()
+ * 返回的是预分词之后的文字，所以摘录在原文上按同样的切分规则重新匹配。
+ */
+
+use super::types::MatchSnippet;
+
+/// 建表时的分词器配置。和旧版本的 'porter' 效果相同，写法不同，用来识别索引是否已经预分词
+const FTS_TOKENIZE: &str = "porter unicode61";
+
+/// 是否是中日韩文字（汉字、假名、谚文）
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'     // 平假名、片假名
+        | '\u{3400}'..='\u{4DBF}'   // 汉字扩展 A
+        | '\u{4E00}'..='\u{9FFF}'   // 基本汉字
+        | '\u{AC00}'..='\u{D7AF}'   // 谚文音节
+        | '\u{F900}'..='\u{FAFF}'   // 兼容汉字
+        | '\u{20000}'..='\u{2EBEF}' // 汉字扩展 B-F
+    )
+}
+
+/// 把文本切成连续的中日韩文字段和其它文字段，返回 (是否中日韩, 片段)
+fn split_runs(text: &str) -> Vec<(bool, &str)> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut current: Option<bool> = None;
+    for (i, c) in text.char_indices() {
+        let cjk = is_cjk(c);
+        if current.is_some_and(|run| run != cjk) {
+            runs.push((!cjk, &text[start..i]));
+            start = i;
+        }
+        current = Some(cjk);
+    }
+    if let Some(cjk) = current {
+        runs.push((cjk, &text[start..]));
+    }
+    runs
+}
+
+/// 一段中日韩文字的二元词
+fn bigrams(run: &str) -> Vec<String> {
+    let chars: Vec<char> = run.chars().collect();
+    chars.windows(2).map(|w| w.iter().collect()).collect()
+}
+
+/// 写入全文索引之前的预分词：中日韩文字段切成二元词，末尾补一个单字（单字查询靠它命中）
+pub(crate) fn segment_for_index(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() * 2);
+    for (cjk, run) in split_runs(text) {
+        if !cjk {
+            result.push_str(run);
+            continue;
+        }
+        let mut tokens = bigrams(run);
+        if let Some(last) = run.chars().last() {
+            tokens.push(last.to_string());
+        }
+        result.push(' ');
+        result.push_str(&tokens.join(" "));
+        result.push(' ');
+    }
+    result
+}
+
+/// 把用户的问题转成 FTS5 的 MATCH 表达式：每个片段都必须出现（AND）
+///
+/// 连续的中日韩文字组成二元词短语；单个汉字用前缀匹配；其它文字整体加引号，
+/// 转义双引号，避免 FTS5 的特殊字符和运算符（" * ( ) : ^ AND OR NOT NEAR 等）生效。
+pub(crate) fn build_match_query(query: &str) -> String {
+    let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+    query
+        .split_whitespace()
+        .flat_map(split_runs)
+        .map(|(cjk, run)| match (cjk, bigrams(run)) {
+            (false, _) => quote(run),
+            (true, grams) if grams.is_empty() => format!("{} *", quote(run)),
+            (true, grams) => quote(&grams.join(" ")),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 摘录时命中处前后各保留的字符数
+const SNIPPET_CONTEXT_CHARS: usize = 60;
+/// 摘录最多包含的字符数（不含省略号），后面的命中超出时不再往后扩展
+const SNIPPET_MAX_CHARS: usize = 240;
+
+/// 问题里的各个词在 text 里出现的位置（字符偏移，[start, end)），按位置排序，重叠或相连的合并
+///
+/// 和 build_match_query 的切分一致：连续的中日韩文字整段匹配（索引里按相邻二元词组成短语，
+/// 命中时原文里就是连续的这一段）；其余的词不区分大小写按子串匹配，词干变化只能按前缀命中。
+pub(crate) fn match_ranges(query: &str, text: &str) -> Vec<[usize; 2]> {
+    let fold = |s: &str| -> Vec<char> { s.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect() };
+    let haystack = fold(text);
+    let terms: Vec<Vec<char>> = query
+        .split_whitespace()
+        .flat_map(split_runs)
+        .map(|(_, run)| fold(run.trim_matches(|c: char| !c.is_alphanumeric())))
+        .filter(|term| !term.is_empty())
+        .collect();
+
+    let mut ranges: Vec<[usize; 2]> = Vec::new();
+    for term in &terms {
+        let mut start = 0;
+        while start + term.len() <= haystack.len() {
+            if haystack[start..start + term.len()] == term[..] {
+                ranges.push([start, start + term.len()]);
+                start += term.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+    ranges.sort_unstable();
+
+    let mut merged: Vec<[usize; 2]> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range[0] <= last[1] => last[1] = last[1].max(range[1]),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// 以第一个命中处为中心截取一段摘录，尽量多包含后面的命中；没有命中时返回 None
+pub(crate) fn build_snippet(text: &str, ranges: &[[usize; 2]]) -> Option<MatchSnippet> {
+    let first = ranges.first()?;
+    let chars: Vec<char> = text.chars().collect();
+    let start = first[0].saturating_sub(SNIPPET_CONTEXT_CHARS);
+    let last_end = ranges
+        .iter()
+        .take_while(|r| r[1] + SNIPPET_CONTEXT_CHARS - start <= SNIPPET_MAX_CHARS)
+        .last()
+        .map_or(first[1], |r| r[1]);
+    let end = (last_end + SNIPPET_CONTEXT_CHARS).min(chars.len()).min(start + SNIPPET_MAX_CHARS).max(first[1]);
+
+    let prefix = if start > 0 { "…" } else { "" };
+    let suffix = if end < chars.len() { "…" } else { "" };
+    let shift = prefix.chars().count();
+    let highlights = ranges
+        .iter()
+        .filter(|r| r[0] >= start && r[1] <= end)
+        .map(|r| [r[0] - start + shift, r[1] - start + shift])
+        .collect();
+
+    Some(MatchSnippet {
+        text: format!("{}{}{}", prefix, chars[start..end].iter().collect::<String>(), suffix),
+        highlights,
+        offset: start,
+    })
+}
+
+/// 创建 chunks_fts；已有的表不是预分词版本时删掉重建，并用 chunks 表重新填充
+pub(crate) fn ensure_fts_table(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    use rusqlite::OptionalExtension;
+    let existing: Option<String> = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'chunks_fts'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let up_to_date = existing.as_deref().is_some_and(|sql| sql.contains(FTS_TOKENIZE));
+    if up_to_date {
+        return Ok(());
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DROP TABLE IF EXISTS chunks_fts", [])?;
+    // 对应 #29、#30 的修复：加入 kb_id 列以实现知识库之间的隔离
+    tx.execute(
+        &format!(
+            "CREATE VIRTUAL TABLE chunks_fts USING fts5(kb_id, content, content_rowid=rowid, tokenize='{}')",
+            FTS_TOKENIZE
+        ),
+        [],
+    )?;
+    let rows: Vec<(i64, String, String)> = {
+        // 加密知识库的分块不进全文索引（索引里是明文）
+        let mut stmt = tx.prepare(
+            "SELECT c.rowid, c.kb_id, c.content FROM chunks c
+             WHERE NOT EXISTS (SELECT 1 FROM knowledge_bases k WHERE k.id = c.kb_id AND k.encrypted = 1)",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+    {
+        let mut insert = tx.prepare("INSERT INTO chunks_fts (rowid, kb_id, content) VALUES (?1, ?2, ?3)")?;
+        for (rowid, kb_id, content) in &rows {
+            insert.execute(rusqlite::params![rowid, kb_id, segment_for_index(content)])?;
+        }
+    }
+    tx.commit()?;
+    if !rows.is_empty() {
+        log::info!("[KB] Rebuilt full-text index with CJK segmentation for {} chunks", rows.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cjk_runs_become_bigrams_and_queries_become_phrases() {
+        assert_eq!(segment_for_index("知识库RAG检索"), " 知识 识库 库 RAG 检索 索 ");
+        assert_eq!(segment_for_index("plain text"), "plain text");
+
+        assert_eq!(build_match_query("知识库 rust"), "\"知识 识库\" \"rust\"");
+        assert_eq!(build_match_query("库"), "\"库\" *");
+        assert_eq!(build_match_query("RAG检索"), "\"RAG\" \"检索\"");
+        assert_eq!(build_match_query("say \"hi\""), "\"say\" \"\"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn snippets_highlight_query_terms_in_the_original_text() {
+        let text = "向量知识库支持混合检索。Rust 实现的检索很快，RUST 也支持 SIMD。";
+        let ranges = match_ranges("知识库 rust, 检索", text);
+        assert_eq!(ranges, vec![[2, 5], [9, 11], [12, 16], [20, 22], [25, 29]]);
+
+        let snippet = build_snippet(text, &ranges).unwrap();
+        assert_eq!(snippet.text, text);
+        assert_eq!(snippet.offset, 0);
+        assert_eq!(snippet.highlights, ranges);
+
+        let long = format!("{}关键词{}", "前".repeat(100), "后".repeat(100));
+        let snippet = build_snippet(&long, &match_ranges("关键词", &long)).unwrap();
+        assert_eq!(snippet.offset, 40);
+        assert_eq!(snippet.highlights, vec![[61, 64]]);
+        assert!(snippet.text.starts_with('…') && snippet.text.ends_with('…'));
+        assert_eq!(build_snippet("无关内容", &match_ranges("rust", "无关内容")), None);
+    }
+
+    #[test]
+    fn segmented_index_matches_chinese_substrings() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE knowledge_bases (id TEXT, encrypted INTEGER);
+             CREATE TABLE chunks (kb_id TEXT, content TEXT);
+             INSERT INTO knowledge_bases VALUES ('kb', 0), ('secret', 1);
+             INSERT INTO chunks VALUES ('kb', '向量知识库支持混合检索'), ('kb', 'Rust is fast'),
+                                       ('kb', 'enc1: 是明文笔记里的普通字符串'), ('secret', 'enc1:secret:AAAA');",
+        )
+        .unwrap();
+        ensure_fts_table(&conn).unwrap();
+
+        let count = |query: &str| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM chunks_fts WHERE chunks_fts MATCH ?1",
+                [build_match_query(query)],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count("知识库"), 1);
+        assert_eq!(count("混合 检索"), 1);
+        assert_eq!(count("库"), 1);
+        assert_eq!(count("检库"), 0);
+        assert_eq!(count("rust"), 1);
+        // 不按内容前缀判断是否加密：没加密的知识库里以 enc1: 开头的分块照样进索引
+        assert_eq!(count("明文笔记"), 1);
+        assert_eq!(count("AAAA"), 0);
+    }
+}
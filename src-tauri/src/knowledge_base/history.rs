@@ -0,0 +1,413 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 聊天记录检索模块
+ *
+ * 功能说明:
+ * - 把 messages 表里的用户/助手消息做 embedding，存进 message_vectors 表
+ *   （复用知识库的 embedding 管线和向量编码）
+ * - search_chat_history 跨所有会话检索历史消息，支持向量 / 关键词 / 混合三种
+ *   模式，混合模式和知识库检索一样用 RRF 合并两路结果
+ *
+ * 向量按需增量生成：每次语义检索前先把还没有向量、或者向量出自别的 embedding
+ * 模型的消息补齐，所以第一次检索会比较慢。还在生成中（partial）的回复不参与。
+ * 回收站里的消息（或所在会话在回收站里）不参与检索，彻底删除时向量随外键级联删除。
+ */
+
+use super::commands::{get_embedding_api_key, with_conn, KbState};
+use super::db::{bytes_to_vector, cosine_similarity, vector_to_bytes};
+use super::embedding::{generate_embeddings, generate_single_embedding};
+use super::types::*;
+use crate::db::LIVE_MESSAGE;
+use std::collections::HashMap;
+use tauri::State;
+
+/// 单条消息参与 embedding 的最大字符数，超长的只取开头
+const MESSAGE_EMBED_MAX_CHARS: usize = 2000;
+/// 检索结果里消息预览的字符数
+const HIT_PREVIEW_CHARS: usize = 200;
+/// RRF 常数，与知识库混合检索一致
+const RRF_K: f32 = 60.0;
+
+/// 向量记录上标注的模型：同名模型换了服务商也算不同的向量空间
+fn embedding_key(provider: &str, model: &str) -> String {
+    format!("{}/{}", provider, model)
+}
+
+/// 还没有当前模型向量的消息：(message_id, 参与 embedding 的文本)
+fn pending_messages(conn: &rusqlite::Connection, model_key: &str) -> Result<Vec<(String, String)>, KnowledgeBaseError> {
+    let mut stmt = conn
+        .prepare(&format!(
+            r#"
+            SELECT m.id, m.content
+            FROM messages m
+            LEFT JOIN message_vectors v ON v.message_id = m.id AND v.embedding_model = ?1
+            WHERE v.message_id IS NULL
+              AND m.role IN ('user', 'assistant')
+              AND m.partial = 0
+              AND TRIM(m.content) != ''
+              AND {}
+            ORDER BY m.timestamp ASC
+            "#,
+            LIVE_MESSAGE
+        ))
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([model_key], |row| {
+            let id: String = row.get(0)?;
+            let content: String = row.get(1)?;
+            Ok((id, content.chars().take(MESSAGE_EMBED_MAX_CHARS).collect()))
+        })
+        .map_err(db_error)?;
+    rows.collect::<Result<Vec<_>, _>>().map_err(db_error)
+}
+
+fn store_message_vectors(
+    conn: &rusqlite::Connection,
+    model_key: &str,
+    vectors: &[(String, Vec<f32>)],
+) -> Result<(), KnowledgeBaseError> {
+    for (message_id, vector) in vectors {
+        conn.execute(
+            "INSERT OR REPLACE INTO message_vectors (message_id, embedding_model, vector) VALUES (?1, ?2, ?3)",
+            rusqlite::params![message_id, model_key, vector_to_bytes(vector)],
+        )
+        .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+/// 把还没有向量的消息补齐，返回新生成的向量数
+async fn index_pending_messages(
+    db_path: &str,
+    request: &HistorySearchRequest,
+    api_key: &str,
+) -> Result<usize, KnowledgeBaseError> {
+    let model_key = embedding_key(&request.embedding_provider, &request.embedding_model);
+    let key = model_key.clone();
+    let pending = with_conn(db_path, move |conn| pending_messages(&conn, &key)).await?;
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    log::info!("[History] Embedding {} messages with {}", pending.len(), model_key);
+
+    let (ids, texts): (Vec<String>, Vec<String>) = pending.into_iter().unzip();
+    let embeddings = generate_embeddings(
+        texts,
+        &request.embedding_provider,
+        api_key,
+        &request.embedding_model,
+        &request.embedding_base_url,
+    )
+    .await?;
+    if embeddings.len() != ids.len() {
+        return Err(KnowledgeBaseError::EmbeddingError(format!(
+            "Expected {} embeddings, got {}",
+            ids.len(),
+            embeddings.len()
+        )));
+    }
+
+    let vectors: Vec<(String, Vec<f32>)> = ids.into_iter().zip(embeddings).collect();
+    let count = vectors.len();
+    with_conn(db_path, move |conn| store_message_vectors(&conn, &model_key, &vectors)).await?;
+    Ok(count)
+}
+
+/// 向量相似度最高的消息：(message_id, 余弦分数)，按分数降序
+fn vector_hits(
+    conn: &rusqlite::Connection,
+    model_key: &str,
+    query_vector: &[f32],
+    limit: usize,
+) -> Result<Vec<(String, f32)>, KnowledgeBaseError> {
+    let mut stmt = conn
+        .prepare("SELECT message_id, vector FROM message_vectors WHERE embedding_model = ?1")
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([model_key], |row| {
+            let id: String = row.get(0)?;
+            let bytes: Vec<u8> = row.get(1)?;
+            Ok((id, bytes))
+        })
+        .map_err(db_error)?;
+
+    let mut scored = Vec::new();
+    for row in rows {
+        let (id, bytes) = row.map_err(db_error)?;
+        let score = cosine_similarity(query_vector, &bytes_to_vector(&bytes));
+        if !score.is_nan() {
+            scored.push((id, score));
+        }
+    }
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    scored.truncate(limit);
+    Ok(scored)
+}
+
+/// 包含全部关键词的消息 ID，新消息在前
+fn keyword_hits(conn: &rusqlite::Connection, query: &str, limit: usize) -> Result<Vec<String>, KnowledgeBaseError> {
+    let patterns: Vec<String> = query
+        .split_whitespace()
+        .map(|term| {
+            let escaped = term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            format!("%{}%", escaped)
+        })
+        .collect();
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let conditions = vec!["m.content LIKE ? ESCAPE '\\'"; patterns.len()].join(" AND ");
+    let sql = format!(
+        "SELECT m.id FROM messages m WHERE m.role IN ('user', 'assistant') AND {} AND {} ORDER BY m.timestamp DESC LIMIT {}",
+        conditions, LIVE_MESSAGE, limit
+    );
+    let mut stmt = conn.prepare(&sql).map_err(db_error)?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(patterns), |row| row.get(0))
+        .map_err(db_error)?;
+    rows.collect::<Result<Vec<String>, _>>().map_err(db_error)
+}
+
+/// 一条命中的分数（还没有取消息内容）
+#[derive(Debug, Clone, PartialEq)]
+struct ScoredHit {
+    message_id: String,
+    score: f32,
+    vector_score: Option<f32>,
+    keyword_score: Option<f32>,
+}
+
+/// 按检索模式把两路结果合成最终排名；混合模式用 RRF
+fn rank_hits(
+    mode: &RetrievalMode,
+    vector: Vec<(String, f32)>,
+    keyword: Vec<String>,
+    top_k: usize,
+) -> Vec<ScoredHit> {
+    let mut hits: Vec<ScoredHit> = match mode {
+        RetrievalMode::Vector => vector
+            .into_iter()
+            .map(|(message_id, score)| ScoredHit { message_id, score, vector_score: Some(score), keyword_score: None })
+            .collect(),
+        RetrievalMode::Keyword => keyword
+            .into_iter()
+            .map(|message_id| ScoredHit { message_id, score: 1.0, vector_score: None, keyword_score: Some(1.0) })
+            .collect(),
+        RetrievalMode::Hybrid | RetrievalMode::Hyde => {
+            let mut merged: HashMap<String, ScoredHit> = HashMap::new();
+            for (rank, (message_id, score)) in vector.into_iter().enumerate() {
+                merged.insert(message_id.clone(), ScoredHit {
+                    message_id,
+                    score: 1.0 / (RRF_K + rank as f32),
+                    vector_score: Some(score),
+                    keyword_score: None,
+                });
+            }
+            for (rank, message_id) in keyword.into_iter().enumerate() {
+                let rrf = 1.0 / (RRF_K + rank as f32);
+                let hit = merged.entry(message_id.clone()).or_insert(ScoredHit {
+                    message_id,
+                    score: 0.0,
+                    vector_score: None,
+                    keyword_score: None,
+                });
+                hit.score += rrf;
+                hit.keyword_score = Some(1.0);
+            }
+            let mut hits: Vec<ScoredHit> = merged.into_values().collect();
+            hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+            hits
+        }
+    };
+    hits.truncate(top_k);
+    hits
+}
+
+/// 给排好序的命中补上会话标题、角色、时间和内容预览
+fn load_hits(conn: &rusqlite::Connection, scored: Vec<ScoredHit>) -> Result<Vec<HistorySearchHit>, KnowledgeBaseError> {
+    if scored.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders = vec!["?"; scored.len()].join(",");
+    let sql = format!(
+        r#"
+        SELECT m.id, m.session_id, COALESCE(s.title, ''), m.role, m.content, m.timestamp
+        FROM messages m
+        LEFT JOIN sessions s ON s.id = m.session_id
+        WHERE m.id IN ({}) AND {}
+        "#,
+        placeholders, LIVE_MESSAGE
+    );
+    let mut stmt = conn.prepare(&sql).map_err(db_error)?;
+    let mut rows: HashMap<String, (String, String, String, String, i64)> = stmt
+        .query_map(rusqlite::params_from_iter(scored.iter().map(|h| h.message_id.as_str())), |row| {
+            Ok((row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+        })
+        .map_err(db_error)?
+        .collect::<Result<_, _>>()
+        .map_err(db_error)?;
+
+    Ok(scored
+        .into_iter()
+        .filter_map(|hit| {
+            let (session_id, session_title, role, content, timestamp) = rows.remove(&hit.message_id)?;
+            Some(HistorySearchHit {
+                message_id: hit.message_id,
+                session_id,
+                session_title,
+                role,
+                preview: content.chars().take(HIT_PREVIEW_CHARS).collect(),
+                timestamp,
+                score: hit.score,
+                vector_score: hit.vector_score,
+                keyword_score: hit.keyword_score,
+            })
+        })
+        .collect())
+}
+
+/// 跨所有会话检索聊天记录
+#[tauri::command]
+pub async fn search_chat_history(
+    request: HistorySearchRequest,
+    kb_state: State<'_, KbState>,
+) -> Result<Vec<HistorySearchHit>, KnowledgeBaseError> {
+    if request.query.trim().is_empty() || request.top_k <= 0 {
+        return Ok(Vec::new());
+    }
+    let top_k = request.top_k as usize;
+    // 聊天记录检索没有配置对话模型，HyDE 按普通混合检索处理
+    let mode = match request.retrieval_mode {
+        RetrievalMode::Hyde => RetrievalMode::Hybrid,
+        ref mode => mode.clone(),
+    };
+    let needs_vector = !matches!(mode, RetrievalMode::Keyword);
+    // 混合模式两路各多取一些，给 RRF 合并留余地
+    let candidates = if matches!(mode, RetrievalMode::Hybrid) { top_k * 2 } else { top_k };
+
+    let mut vector = Vec::new();
+    if needs_vector {
+        if request.embedding_provider.trim().is_empty() || request.embedding_model.trim().is_empty() {
+            return Err(KnowledgeBaseError::InvalidConfig(
+                "Semantic history search requires an embedding API config".to_string(),
+            ));
+        }
+        let api_key = get_embedding_api_key(&request.embedding_api_config_id)?;
+        let indexed = index_pending_messages(&kb_state.db_path, &request, &api_key).await?;
+        if indexed > 0 {
+            log::info!("[History] Indexed {} new messages", indexed);
+        }
+        let query_vector = generate_single_embedding(
+            &request.query,
+            &request.embedding_provider,
+            &api_key,
+            &request.embedding_model,
+            &request.embedding_base_url,
+        )
+        .await?;
+        let model_key = embedding_key(&request.embedding_provider, &request.embedding_model);
+        vector = with_conn(&kb_state.db_path, move |conn| vector_hits(&conn, &model_key, &query_vector, candidates)).await?;
+        if matches!(mode, RetrievalMode::Vector) {
+            vector.retain(|(_, score)| *score >= request.similarity_threshold);
+        }
+    }
+
+    let query = request.query.clone();
+    let ranked_mode = mode.clone();
+    let hits = with_conn(&kb_state.db_path, move |conn| {
+        let keyword = if matches!(ranked_mode, RetrievalMode::Vector) {
+            Vec::new()
+        } else {
+            keyword_hits(&conn, &query, candidates)?
+        };
+        load_hits(&conn, rank_hits(&ranked_mode, vector, keyword, top_k))
+    })
+    .await?;
+    log::info!("[History] search '{}' ({:?}) returned {} hits", request.query, mode, hits.len());
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE sessions (id TEXT PRIMARY KEY, title TEXT NOT NULL);
+            CREATE TABLE messages (
+                id TEXT PRIMARY KEY, session_id TEXT NOT NULL, role TEXT NOT NULL,
+                content TEXT NOT NULL, timestamp INTEGER NOT NULL, partial INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE message_vectors (message_id TEXT PRIMARY KEY, embedding_model TEXT NOT NULL, vector BLOB NOT NULL);
+            INSERT INTO sessions VALUES ('s1', 'Rust 学习');
+            INSERT INTO messages VALUES ('m1', 's1', 'user', '怎么写 100% 安全的 unsafe 代码', 1, 0);
+            INSERT INTO messages VALUES ('m2', 's1', 'assistant', '先读 Rustonomicon 的 unsafe 章节', 2, 0);
+            INSERT INTO messages VALUES ('m3', 's1', 'system', 'unsafe system prompt', 3, 0);
+            INSERT INTO messages VALUES ('m4', 's1', 'assistant', '还在生成的 unsafe 回复', 4, 1);
+            ALTER TABLE sessions ADD COLUMN deleted_at INTEGER;
+            ALTER TABLE messages ADD COLUMN deleted_at INTEGER;
+            "#,
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn pending_messages_skip_system_partial_and_already_embedded_rows() {
+        let conn = test_db();
+        let pending: Vec<String> = pending_messages(&conn, "openai/small").unwrap().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(pending, ["m1", "m2"]);
+
+        store_message_vectors(&conn, "openai/small", &[("m1".to_string(), vec![1.0, 0.0])]).unwrap();
+        let pending: Vec<String> = pending_messages(&conn, "openai/small").unwrap().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(pending, ["m2"]);
+        // 换了模型，旧向量不算数
+        assert_eq!(pending_messages(&conn, "zhipu/embedding-2").unwrap().len(), 2);
+
+        store_message_vectors(&conn, "openai/small", &[("m2".to_string(), vec![0.0, 1.0])]).unwrap();
+        let hits = vector_hits(&conn, "openai/small", &[0.1, 1.0], 5).unwrap();
+        assert_eq!(hits[0].0, "m2");
+    }
+
+    #[test]
+    fn keyword_hits_require_every_term_and_escape_like_wildcards() {
+        let conn = test_db();
+        assert_eq!(keyword_hits(&conn, "unsafe", 10).unwrap(), ["m4", "m2", "m1"]);
+        assert_eq!(keyword_hits(&conn, "unsafe 100%", 10).unwrap(), ["m1"]);
+        assert!(keyword_hits(&conn, "unsafe 0%代", 10).unwrap().is_empty());
+
+        let hits = load_hits(&conn, rank_hits(&RetrievalMode::Keyword, vec![], vec!["m2".into()], 5)).unwrap();
+        assert_eq!(hits[0].session_title, "Rust 学习");
+        assert_eq!(hits[0].role, "assistant");
+    }
+
+    #[test]
+    fn trashed_messages_and_sessions_are_not_searched() {
+        let conn = test_db();
+        conn.execute("UPDATE messages SET deleted_at = 1 WHERE id = 'm1'", []).unwrap();
+        assert_eq!(keyword_hits(&conn, "unsafe", 10).unwrap(), ["m4", "m2"]);
+        assert_eq!(pending_messages(&conn, "openai/small").unwrap().len(), 1);
+
+        conn.execute("UPDATE sessions SET deleted_at = 1 WHERE id = 's1'", []).unwrap();
+        assert!(keyword_hits(&conn, "unsafe", 10).unwrap().is_empty());
+        let hits = load_hits(&conn, rank_hits(&RetrievalMode::Keyword, vec![], vec!["m2".into()], 5)).unwrap();
+        assert!(hits.is_empty());
+    }
+
+    #[test]
+    fn hybrid_rank_boosts_messages_found_by_both_searches() {
+        let vector = vec![("a".to_string(), 0.9), ("b".to_string(), 0.8)];
+        let keyword = vec!["c".to_string(), "b".to_string()];
+        let hits = rank_hits(&RetrievalMode::Hybrid, vector, keyword, 2);
+        assert_eq!(hits[0].message_id, "b");
+        assert_eq!(hits[0].vector_score, Some(0.8));
+        assert_eq!(hits[0].keyword_score, Some(1.0));
+        assert_eq!(hits.len(), 2);
+    }
+}
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * HyDE 检索模块（Hypothetical Document Embeddings）
+ *
+ * 功能说明:
+ * - 口语化、省略主语的问题（"那它支持哪些格式？"）和文档原文的向量离得比较远，
+ *   直接拿问题做向量检索召回率低
+ * - 检索前先让用户配置的对话模型针对问题写一段简短的假设回答，用"原问题 + 假设回答"
+ *   生成查询向量；假设回答的措辞更接近文档，内容对不对并不重要
+ * - 关键词检索仍用原问题，两边结果按混合检索的 RRF 规则融合
+ *
+ * 模型没配置、调用失败或返回空内容时记一条日志，退回用原问题检索，不影响检索本身。
+ */
+
+use super::types::QueryLlm;
+use crate::commands::llm::{build_native_messages, run_turn, ChatMessage, TurnOutcome};
+
+/// 写假设回答时的系统提示词
+const HYDE_SYSTEM_PROMPT: &str = "请直接写一段可能出现在资料中的文字来回答用户的问题，\
+不确定的细节也按常见情况写出来。不要提问、不要解释，不超过 200 字。";
+
+/// 假设回答的 token 上限：只用来生成向量，不需要长
+const HYDE_MAX_TOKENS: u32 = 400;
+
+/// 生成向量检索用的问题：原问题后面接上假设回答；拿不到假设回答时返回原问题
+pub(crate) async fn expand_query(llm: Option<&QueryLlm>, query: &str) -> String {
+    let Some(llm) = llm else {
+        log::warn!("[KB] HyDE retrieval requested without a chat model, using the original query");
+        return query.to_string();
+    };
+    match hypothetical_answer(llm, query).await {
+        Ok(answer) => combine(query, &answer),
+        Err(e) => {
+            log::warn!("[KB] HyDE answer generation failed, using the original query: {}", e);
+            query.to_string()
+        }
+    }
+}
+
+/// 拼接原问题和假设回答，假设回答为空时只保留原问题
+fn combine(query: &str, answer: &str) -> String {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        query.to_string()
+    } else {
+        format!("{}\n\n{}", query, answer)
+    }
+}
+
+/// 让 LLM 针对问题写一段假设回答
+async fn hypothetical_answer(llm: &QueryLlm, query: &str) -> Result<String, String> {
+    complete(llm, HYDE_SYSTEM_PROMPT, query, HYDE_MAX_TOKENS).await
+}
+
+/// 用检索辅助模型做一次不带工具的单轮问答，返回回复文本（上下文压缩也用它）
+pub(crate) async fn complete(
+    llm: &QueryLlm,
+    system_prompt: &str,
+    content: &str,
+    max_tokens: u32,
+) -> Result<String, String> {
+    let api_key = resolve_api_key(llm);
+    let message = ChatMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        role: "user".to_string(),
+        content: content.to_string(),
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        error: None,
+        images: vec![],
+        videos: vec![],
+        seed: None,
+        generation: None,
+    };
+    let native = build_native_messages(&llm.provider, &[message]);
+
+    let outcome = run_turn(
+        &llm.provider,
+        &llm.model,
+        &api_key,
+        &llm.base_url,
+        Some(system_prompt),
+        &native,
+        &[],
+        Some(max_tokens),
+        false,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    match outcome {
+        TurnOutcome::Text(text) => Ok(text),
+        TurnOutcome::ToolCalls(_) => Err("model returned tool calls".to_string()),
+    }
+}
+
+/// 请求里没带密钥时按 provider 查系统 keyring（本地模型不需要密钥）
+fn resolve_api_key(llm: &QueryLlm) -> String {
+    if !llm.api_key.is_empty() || llm.provider == "local" {
+        return llm.api_key.clone();
+    }
+    match crate::secure_storage::get_api_key(llm.provider.clone()) {
+        Ok(Some(key)) => key,
+        Ok(None) => String::new(),
+        Err(e) => {
+            log::warn!("[KB] keyring lookup failed for {}: {}", llm.provider, e);
+            String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn missing_model_or_empty_answer_keeps_original_query() {
+        assert_eq!(expand_query(None, "它支持哪些格式？").await, "它支持哪些格式？");
+        assert_eq!(combine("问题", "  \n"), "问题");
+        assert_eq!(combine("问题", " 支持 PDF 和 Word。\n"), "问题\n\n支持 PDF 和 Word。");
+    }
+}
@@ -0,0 +1,217 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 文档导入处理队列
+ *
+ * 功能说明:
+ * - 解析（CPU 密集）和生成 embedding（等网络）各用一个信号量限制同时进行的任务数，
+ *   一次拖进几十个文件时多出来的任务按提交顺序排队
+ * - 上限由前端设置同步（set_import_concurrency），至少为 1
+ * - get_import_queue 返回所有还没结束的导入任务、它们所处的状态和排队位置
+ *
+ * 名额只在对应阶段占用：解析和分块写完就释放解析名额，再去排 embedding 的队，
+ * 一个文档在等网络时不会挡住后面文件的解析。上限改了就换一份新的信号量，
+ * 旧的随已经在运行的任务结束自然释放（和流式回复排队一样）。
+ */
+
+use super::types::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// 默认同时解析的文档数
+const DEFAULT_PARSE_WORKERS: u32 = 2;
+/// 默认同时生成 embedding 的文档数
+const DEFAULT_EMBED_WORKERS: u32 = 2;
+/// 并发上限的最大值
+const MAX_IMPORT_WORKERS: u32 = 16;
+
+static PARSE_LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_PARSE_WORKERS);
+static EMBED_LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_EMBED_WORKERS);
+static POOLS: Lazy<Mutex<HashMap<ImportPool, Arc<PoolSlots>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// 还没结束的导入任务，按提交顺序排列
+static ENTRIES: Lazy<Mutex<Vec<Entry>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// 导入的两个阶段各自的名额池
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum ImportPool {
+    Parse,
+    Embed,
+}
+
+impl ImportPool {
+    fn limit(self) -> u32 {
+        match self {
+            ImportPool::Parse => PARSE_LIMIT.load(Ordering::Relaxed),
+            ImportPool::Embed => EMBED_LIMIT.load(Ordering::Relaxed),
+        }
+    }
+
+    /// (等待名额时的状态, 拿到名额后的状态)
+    fn states(self) -> (ImportQueueState, ImportQueueState) {
+        match self {
+            ImportPool::Parse => (ImportQueueState::WaitingParse, ImportQueueState::Parsing),
+            ImportPool::Embed => (ImportQueueState::WaitingEmbed, ImportQueueState::Embedding),
+        }
+    }
+}
+
+/// 一个池的名额
+struct PoolSlots {
+    /// 创建时的上限；上限改了就换一份新的
+    limit: u32,
+    semaphore: Arc<Semaphore>,
+}
+
+/// 队列里的一个任务
+struct Entry {
+    task: ImportTask,
+    state: ImportQueueState,
+}
+
+fn slots_for(pool: ImportPool) -> Arc<PoolSlots> {
+    let limit = pool.limit();
+    let mut pools = POOLS.lock().unwrap_or_else(|e| e.into_inner());
+    let current = pools.get(&pool).filter(|s| s.limit == limit).cloned();
+    current.unwrap_or_else(|| {
+        let fresh = Arc::new(PoolSlots { limit, semaphore: Arc::new(Semaphore::new(limit as usize)) });
+        pools.insert(pool, fresh.clone());
+        fresh
+    })
+}
+
+fn set_state(task_id: &str, state: ImportQueueState) {
+    let mut entries = ENTRIES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(entry) = entries.iter_mut().find(|e| e.task.task_id == task_id) {
+        entry.state = state;
+    }
+}
+
+/// 把任务加入队列（还没开始排任何名额）
+pub(super) fn enqueue(task: &ImportTask) {
+    ENTRIES.lock().unwrap_or_else(|e| e.into_inner()).push(Entry {
+        task: task.clone(),
+        state: ImportQueueState::Pending,
+    });
+}
+
+/// 任务结束（完成、失败或取消）时移出队列
+pub(super) fn dequeue(task_id: &str) {
+    ENTRIES.lock().unwrap_or_else(|e| e.into_inner()).retain(|e| e.task.task_id != task_id);
+}
+
+/// 占一个阶段的名额，名额用完时排队等待；返回的 permit 在这一阶段结束前要一直持有
+pub(super) async fn acquire_slot(pool: ImportPool, task_id: &str) -> Result<OwnedSemaphorePermit, KnowledgeBaseError> {
+    let (waiting, running) = pool.states();
+    set_state(task_id, waiting);
+    let permit = slots_for(pool)
+        .semaphore
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|_| KnowledgeBaseError::InvalidConfig("导入队列已关闭".to_string()))?;
+    set_state(task_id, running);
+    Ok(permit)
+}
+
+/// 当前队列的快照，等待中的任务按提交顺序编号
+fn snapshot() -> ImportQueueStatus {
+    let entries = ENTRIES.lock().unwrap_or_else(|e| e.into_inner());
+    let (mut waiting_parse, mut waiting_embed) = (0, 0);
+    let tasks = entries
+        .iter()
+        .map(|entry| {
+            let position = match entry.state {
+                ImportQueueState::WaitingParse => {
+                    waiting_parse += 1;
+                    waiting_parse
+                }
+                ImportQueueState::WaitingEmbed => {
+                    waiting_embed += 1;
+                    waiting_embed
+                }
+                _ => 0,
+            };
+            QueuedImport {
+                task_id: entry.task.task_id.clone(),
+                kb_id: entry.task.kb_id.clone(),
+                document_id: entry.task.document_id.clone(),
+                filename: entry.task.filename.clone(),
+                state: entry.state,
+                position,
+            }
+        })
+        .collect();
+
+    ImportQueueStatus {
+        parse_workers: PARSE_LIMIT.load(Ordering::Relaxed),
+        embed_workers: EMBED_LIMIT.load(Ordering::Relaxed),
+        tasks,
+    }
+}
+
+/// 设置同时解析和同时生成 embedding 的文档数（1 到 16）
+#[tauri::command]
+pub fn set_import_concurrency(parse_workers: u32, embed_workers: u32) {
+    let parse_workers = parse_workers.clamp(1, MAX_IMPORT_WORKERS);
+    let embed_workers = embed_workers.clamp(1, MAX_IMPORT_WORKERS);
+    PARSE_LIMIT.store(parse_workers, Ordering::Relaxed);
+    EMBED_LIMIT.store(embed_workers, Ordering::Relaxed);
+    log::info!("[KB] Import concurrency set to {} parse / {} embed workers", parse_workers, embed_workers);
+}
+
+/// 查询导入队列：并发上限和所有还没结束的导入任务
+#[tauri::command]
+pub fn get_import_queue() -> ImportQueueStatus {
+    snapshot()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(id: &str) -> ImportTask {
+        ImportTask {
+            task_id: id.to_string(),
+            kb_id: "kb".to_string(),
+            document_id: format!("doc-{}", id),
+            filename: format!("{}.pdf", id),
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_tasks_are_numbered_per_pool_in_submission_order() {
+        let ids = ["queue-a", "queue-b", "queue-c", "queue-d"];
+        for id in ids {
+            enqueue(&task(id));
+        }
+        let _running = acquire_slot(ImportPool::Embed, "queue-a").await.unwrap();
+        set_state("queue-b", ImportQueueState::WaitingParse);
+        set_state("queue-d", ImportQueueState::WaitingParse);
+
+        let ours: Vec<(ImportQueueState, usize)> = snapshot()
+            .tasks
+            .into_iter()
+            .filter(|t| ids.contains(&t.task_id.as_str()))
+            .map(|t| (t.state, t.position))
+            .collect();
+        assert_eq!(
+            ours,
+            vec![
+                (ImportQueueState::Embedding, 0),
+                (ImportQueueState::WaitingParse, 1),
+                (ImportQueueState::Pending, 0),
+                (ImportQueueState::WaitingParse, 2),
+            ]
+        );
+
+        for id in ids {
+            dequeue(id);
+        }
+        assert!(snapshot().tasks.iter().all(|t| !ids.contains(&t.task_id.as_str())));
+    }
+}
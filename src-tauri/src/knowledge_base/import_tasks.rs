@@ -0,0 +1,238 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 导入任务的取消与中断恢复
+ *
+ * 功能说明:
+ * - 记录正在运行的后台导入任务，cancel_import 按任务 id 取消
+ * - 被取消的导入按它走到的阶段回滚：重新导入恢复成原来的已完成文档，
+ *   已经开始生成向量的保留进度（之后可以 resume_import），更早的阶段直接删除文档记录
+ * - 应用启动时把上次退出前还在 processing 的文档按同样的规则恢复，
+ *   不会留下永远“处理中”的文档
+ *
+ * 文档记录上的 import_stage 就是导入的检查点：分块在一个事务里写入，向量按批写入，
+ * 中断后根据 import_stage 和已写入的数据就能判断该回滚还是继续。
+ */
+
+use super::import_queue;
+use super::types::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio_util::sync::CancellationToken;
+
+/// 正在运行的导入任务：task_id → 取消令牌
+static RUNNING_IMPORTS: Lazy<std::sync::Mutex<HashMap<String, CancellationToken>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// 启动时发现上次没有导入完的文档时写入的错误信息
+const INTERRUPTED_NOTICE: &str = "导入被中断（应用在导入过程中退出）";
+
+/// 被 cancel_import 取消时写入的错误信息
+pub(super) const CANCELLED_NOTICE: &str = "导入已取消";
+
+/// 运行一个可取消的导入任务；被 cancel_import 取消时返回 None
+///
+/// 任务运行期间留在导入队列里（见 import_queue）。取消发生在两个 await 之间，
+/// 已经提交的事务不会回滚，调用方需要用 rollback_interrupted 按文档当前的阶段收尾。
+pub(super) async fn run_cancellable<F>(task: &ImportTask, work: F) -> Option<Result<(), KnowledgeBaseError>>
+where
+    F: Future<Output = Result<(), KnowledgeBaseError>>,
+{
+    let cancel = CancellationToken::new();
+    RUNNING_IMPORTS.lock().unwrap().insert(task.task_id.clone(), cancel.clone());
+    import_queue::enqueue(task);
+    let _running = scopeguard::guard(task.task_id.clone(), |id| {
+        RUNNING_IMPORTS.lock().unwrap().remove(&id);
+        import_queue::dequeue(&id);
+    });
+
+    tokio::select! {
+        result = work => Some(result),
+        _ = cancel.cancelled() => None,
+    }
+}
+
+/// 中断的导入被处理成了什么样
+#[derive(Debug, PartialEq)]
+pub(super) enum Rollback {
+    /// 重新导入被中断，旧的分块和向量都还在，文档恢复为已完成
+    Restored,
+    /// 已经开始生成向量，保留进度，可以继续导入
+    Resumable,
+    /// 文档记录已删除
+    Discarded,
+    /// 清理了写入一半的数据，文档标记为失败
+    Failed,
+}
+
+/// 按文档走到的阶段收尾一个中断的导入；文档已经不是 processing（导入恰好结束）时返回 None
+///
+/// discard 为 true 时还没开始生成向量的新文档直接删除（用户主动取消），
+/// 否则留下一条失败记录，方便用户知道有文件没有导入成功。
+pub(super) fn rollback_interrupted(
+    conn: &rusqlite::Connection,
+    doc_id: &str,
+    message: &str,
+    discard: bool,
+) -> Result<Option<Rollback>, KnowledgeBaseError> {
+    use rusqlite::OptionalExtension;
+
+    let row: Option<(String, Option<String>)> = conn
+        .query_row(
+            "SELECT status, import_stage FROM documents WHERE id = ?1",
+            [doc_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+    let Some((status, stage)) = row else {
+        return Ok(None);
+    };
+    if status != "processing" {
+        return Ok(None);
+    }
+    let stage = stage.as_deref().and_then(ImportStage::parse);
+
+    // 重新导入的新分块要到最后一个事务才替换旧数据，中断时旧内容完好；
+    // 旧文档可能一个分块都没有（比如没有文字的 PDF），不能按 chunk_count 判断
+    if stage == Some(ImportStage::Reimporting) {
+        conn.execute(
+            "UPDATE documents SET status = 'completed', import_stage = NULL, error_message = ?1 WHERE id = ?2",
+            rusqlite::params![format!("重新导入失败: {}", message), doc_id],
+        )
+        .map_err(db_error)?;
+        return Ok(Some(Rollback::Restored));
+    }
+
+    if stage.is_some_and(|s| s.is_resumable()) {
+        conn.execute(
+            "UPDATE documents SET status = 'error', error_message = ?1 WHERE id = ?2",
+            rusqlite::params![message, doc_id],
+        )
+        .map_err(db_error)?;
+        return Ok(Some(Rollback::Resumable));
+    }
+
+    conn.execute("DELETE FROM vectors WHERE document_id = ?1", [doc_id]).map_err(db_error)?;
+    if discard {
+        super::commands::delete_document_rows(conn, doc_id)?;
+        return Ok(Some(Rollback::Discarded));
+    }
+
+    conn.execute(
+        "DELETE FROM chunks_fts WHERE rowid IN (SELECT rowid FROM chunks WHERE document_id = ?1)",
+        [doc_id],
+    )
+    .map_err(db_error)?;
+    conn.execute("DELETE FROM chunks WHERE document_id = ?1", [doc_id]).map_err(db_error)?;
+    conn.execute("DELETE FROM parent_chunks WHERE document_id = ?1", [doc_id]).map_err(db_error)?;
+    conn.execute(
+        "UPDATE documents SET status = 'error', import_stage = NULL, error_message = ?1 WHERE id = ?2",
+        rusqlite::params![message, doc_id],
+    )
+    .map_err(db_error)?;
+    Ok(Some(Rollback::Failed))
+}
+
+/// 启动时恢复上次退出时还在导入的文档，返回处理的文档数
+///
+/// 只能在还没有任何导入任务运行时调用（应用启动阶段）。
+pub(crate) fn recover_interrupted_imports(conn: &rusqlite::Connection) -> Result<usize, KnowledgeBaseError> {
+    let doc_ids: Vec<String> = conn
+        .prepare("SELECT id FROM documents WHERE status = 'processing'")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+        .map_err(db_error)?;
+
+    for doc_id in &doc_ids {
+        let outcome = rollback_interrupted(conn, doc_id, INTERRUPTED_NOTICE, false)?;
+        log::info!("[KB] Recovered interrupted import of {}: {:?}", doc_id, outcome);
+    }
+    Ok(doc_ids.len())
+}
+
+/// 取消正在运行的导入任务；任务会在下一个 await 点停下并按阶段回滚
+#[tauri::command]
+pub async fn cancel_import(task_id: String) -> Result<(), KnowledgeBaseError> {
+    let cancel = RUNNING_IMPORTS.lock().unwrap().get(&task_id).cloned();
+    match cancel {
+        Some(cancel) => {
+            log::info!("[KB] Cancelling import task {}", task_id);
+            cancel.cancel();
+            Ok(())
+        }
+        None => Err(KnowledgeBaseError::NotFound(format!("导入任务不存在或已经结束: {}", task_id))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert_document(conn: &rusqlite::Connection, id: &str, stage: &str, chunk_count: i64) {
+        conn.execute(
+            "INSERT INTO documents (id, kb_id, filename, file_type, file_size, status, import_stage, chunk_count, created_at)
+             VALUES (?1, 'kb', ?1, 'pdf', 10, 'processing', ?2, ?3, 0)",
+            rusqlite::params![id, stage, chunk_count],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at)
+             VALUES (?1 || '-c', ?1, 'kb', 'text', 0, 0)",
+            [id],
+        )
+        .unwrap();
+    }
+
+    fn document(conn: &rusqlite::Connection, id: &str) -> Option<(String, Option<String>, i64)> {
+        use rusqlite::OptionalExtension;
+        conn.query_row(
+            "SELECT status, import_stage, (SELECT COUNT(*) FROM chunks WHERE document_id = ?1) FROM documents WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .unwrap()
+    }
+
+    #[test]
+    fn interrupted_imports_are_rolled_back_by_stage() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at)
+             VALUES ('kb', 'kb', 'cfg', 'openai', 'm', 0, 0)",
+            [],
+        )
+        .unwrap();
+        insert_document(&conn, "chunked", "chunking", 0);
+        insert_document(&conn, "embedding", "embedding", 0);
+        insert_document(&conn, "reimport", "reimporting", 3);
+        insert_document(&conn, "cancelled", "chunking", 0);
+        insert_document(&conn, "empty-reimport", "reimporting", 0);
+
+        assert_eq!(recover_interrupted_imports(&conn).unwrap(), 5);
+        assert_eq!(document(&conn, "chunked"), Some(("error".to_string(), None, 0)));
+        assert_eq!(document(&conn, "embedding"), Some(("error".to_string(), Some("embedding".to_string()), 1)));
+        assert_eq!(document(&conn, "reimport"), Some(("completed".to_string(), None, 1)));
+
+        conn.execute("UPDATE documents SET status = 'processing', import_stage = 'chunking' WHERE id = 'cancelled'", [])
+            .unwrap();
+        conn.execute("INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at) VALUES ('x', 'cancelled', 'kb', 't', 1, 0)", [])
+            .unwrap();
+        assert_eq!(rollback_interrupted(&conn, "cancelled", CANCELLED_NOTICE, true).unwrap(), Some(Rollback::Discarded));
+        assert_eq!(document(&conn, "cancelled"), None);
+        assert_eq!(rollback_interrupted(&conn, "reimport", CANCELLED_NOTICE, true).unwrap(), None);
+
+        // 取消没有分块的已完成文档的重新导入，文档原样保留
+        conn.execute("UPDATE documents SET status = 'processing', import_stage = 'reimporting' WHERE id = 'empty-reimport'", [])
+            .unwrap();
+        assert_eq!(
+            rollback_interrupted(&conn, "empty-reimport", CANCELLED_NOTICE, true).unwrap(),
+            Some(Rollback::Restored)
+        );
+        assert_eq!(document(&conn, "empty-reimport"), Some(("completed".to_string(), None, 1)));
+    }
+}
@@ -6,18 +6,66 @@
  * 知识库模块
  * 
  * 模块说明:
+ * - ann: 大知识库的近似最近邻（HNSW）索引
+ * - archive: 知识库导出/导入（zip 归档，含向量）
  * - commands: 知识库相关 Tauri 命令
+ * - compression: 检索结果的上下文压缩（只保留和问题相关的句子）
+ * - crawler: 按深度和网页数限制爬取整站导入
  * - db: 向量数据库操作
+ * - dedup: 近似重复分块的识别与折叠（MinHash）
  * - document: 文档处理
  * - embedding: 文本嵌入
+ * - embedding_config: Embedding API 配置（知识库通过 id 引用）
+ * - encryption: 知识库加密存储（密钥保存在系统 keyring）
+ * - fts: 全文检索的中日韩预分词
+ * - history: 聊天记录检索
+ * - hyde: HyDE 检索（先让 LLM 写假设回答再做向量检索）
+ * - import_queue: 文档导入处理队列（解析和 embedding 分别限制并发）
+ * - import_tasks: 导入任务的取消和中断恢复
+ * - ocr: 图片和扫描版 PDF 的文字识别
+ * - quantization: 向量量化存储（int8/binary）
+ * - rag: 聊天时的知识库检索增强
+ * - reembed: 更换知识库的 embedding 模型（后台重新生成向量后整体切换）
+ * - repair: 清理没有归属的分块、向量和全文索引
  * - retrieval: 相似度检索
+ * - scratch: 临时文件问答（会话内的内存索引）
+ * - structured: JSON/JSONL/YAML 按记录展平
+ * - summary: 文档摘要索引（先按摘要挑文档再检索分块）
+ * - tokenizer: token 计数（按 token 分块时使用，OpenAI 模型用 BPE 词表精确计数）
+ * - trash: 文档回收站（删除后可恢复，过期自动清除）
+ * - transcribe: 音视频转写（带时间戳）
  * - types: 类型定义
+ * - vector_cache: 最近检索过的知识库向量的内存缓存（LRU）
  */
 
+pub mod ann;
+pub mod archive;
 pub mod commands;
+pub mod compression;
+pub mod crawler;
 pub mod db;
+pub mod dedup;
 pub mod document;
 pub mod embedding;
+pub mod embedding_config;
+pub mod encryption;
+pub mod fts;
+pub mod history;
+pub mod hyde;
+pub mod import_queue;
+pub mod import_tasks;
+pub mod ocr;
+pub mod quantization;
+pub mod rag;
+pub mod reembed;
+pub mod repair;
 pub mod reranker;
 pub mod retrieval;
+pub mod scratch;
+pub mod structured;
+pub mod summary;
+pub mod tokenizer;
+pub mod trash;
+pub mod transcribe;
 pub mod types;
+pub mod vector_cache;
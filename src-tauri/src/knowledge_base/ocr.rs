@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * OCR 模块
+ *
+ * 功能说明:
+ * - ocr_image 调用外部 tesseract 识别图片（png/jpg 等）里的文字
+ * - ocr_pdf_page 先用 pdftoppm（poppler-utils）把 PDF 的某一页渲染成图片，再交给 tesseract
+ * - 扫描版 PDF 里抽不出文字的页会自动走这里（见 document::parse_pdf_pages）
+ *
+ * 和 pdftotext 一样走外部程序，不把 OCR 引擎编进应用；没装 tesseract 时图片导入会
+ * 报错提示安装，PDF 则只是跳过 OCR，保留能抽出来的文字。
+ */
+
+use super::types::KnowledgeBaseError;
+use crate::commands::local_model::hide_console_window;
+use std::path::Path;
+use uuid::Uuid;
+
+/// 识别语言：简体中文 + 英文（需要 tesseract 装了 chi_sim 语言包）
+const OCR_LANGUAGES: &str = "chi_sim+eng";
+
+/// 渲染 PDF 页面的分辨率，300 DPI 是 tesseract 推荐的输入精度
+const PDF_RENDER_DPI: &str = "300";
+
+/// 外部程序启动失败时的提示：区分"没装"和其他错误
+fn spawn_error(program: &str, install_hint: &str, e: std::io::Error) -> KnowledgeBaseError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        KnowledgeBaseError::DocumentParseError(format!("未找到 {}，{}", program, install_hint))
+    } else {
+        KnowledgeBaseError::DocumentParseError(format!("{} 启动失败: {}", program, e))
+    }
+}
+
+/// 识别图片里的文字
+pub async fn ocr_image(image_path: &Path) -> Result<String, KnowledgeBaseError> {
+    let mut cmd = tokio::process::Command::new("tesseract");
+    // 输出文件名写 stdout 时 tesseract 把结果打印到标准输出
+    cmd.arg(image_path).args(["stdout", "-l", OCR_LANGUAGES]);
+    hide_console_window(&mut cmd);
+
+    let output = cmd
+        .output()
+        .await
+        .map_err(|e| spawn_error("tesseract", "图片和扫描版 PDF 需要安装 tesseract（含 chi_sim 语言包）并确认它在 PATH 中", e))?;
+    if !output.status.success() {
+        return Err(KnowledgeBaseError::DocumentParseError(format!(
+            "OCR 失败: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// 把 PDF 的第 page 页（从 1 开始）渲染成图片后识别
+pub async fn ocr_pdf_page(file_path: &str, page: usize) -> Result<String, KnowledgeBaseError> {
+    let work_dir = std::env::temp_dir().join(format!("baiyu-ocr-{}", Uuid::new_v4()));
+    tokio::fs::create_dir_all(&work_dir)
+        .await
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
+    let _cleanup = scopeguard::guard(work_dir.clone(), |dir| {
+        let _ = std::fs::remove_dir_all(dir);
+    });
+
+    let prefix = work_dir.join("page");
+    let page = page.to_string();
+    let mut cmd = tokio::process::Command::new("pdftoppm");
+    // -singlefile 让输出文件名固定为 <prefix>.png，不带页码后缀
+    cmd.args(["-f", &page, "-l", &page, "-r", PDF_RENDER_DPI, "-png", "-singlefile", file_path])
+        .arg(&prefix);
+    hide_console_window(&mut cmd);
+
+    let output = cmd
+        .output()
+        .await
+        .map_err(|e| spawn_error("pdftoppm", "扫描版 PDF 的 OCR 需要安装 poppler-utils", e))?;
+    if !output.status.success() {
+        return Err(KnowledgeBaseError::DocumentParseError(format!(
+            "PDF 第 {} 页渲染失败: {}",
+            page,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    ocr_image(&prefix.with_extension("png")).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_program_gets_an_install_hint() {
+        let missing = spawn_error("tesseract", "请先安装", std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(missing.to_string(), KnowledgeBaseError::DocumentParseError("未找到 tesseract，请先安装".into()).to_string());
+
+        let denied = spawn_error("tesseract", "请先安装", std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(denied.to_string().contains("tesseract 启动失败"));
+    }
+}
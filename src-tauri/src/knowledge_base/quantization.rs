@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 向量量化模块
+ *
+ * 功能说明:
+ * - int8：每个向量存一个缩放系数和每维一个字节，占用约为 f32 的 1/4；
+ *   检索时用 f32 的问题向量和反量化后的向量算余弦，精度损失很小
+ * - binary：在 int8 的基础上再为每个向量存一份符号位（每维 1 bit），检索时先用汉明距离
+ *   在符号位上粗筛出 top_k 的若干倍候选，再用 int8 向量按 f32 精确重排
+ * - encode_vector 按知识库的量化方式编码要写入 vectors 表的向量；
+ *   db::bytes_to_vector 能识别 int8 格式，读向量的地方不需要关心存储格式
+ *
+ * int8 编码以一个 f32 NaN 的位模式开头，正常的 f32 向量不会以 NaN 开头，
+ * 所以同一张表里新旧两种格式可以并存（切换量化方式前导入的向量仍然可读）。
+ */
+
+use super::types::VectorQuantization;
+
+/// int8 编码的开头 4 个字节，按 f32 解读是 NaN
+const INT8_MAGIC: [u8; 4] = [0x7F, 0xFF, 0xFF, 0xFF];
+
+/// binary 量化时，粗筛出的候选数是 top_k 的多少倍
+pub(crate) const BINARY_RESCORE_FACTOR: usize = 10;
+
+/// 编码后要写入 vectors 表的内容：vector 列和 code 列（只有 binary 量化才有 code）
+pub(crate) struct EncodedVector {
+    pub vector: Vec<u8>,
+    pub code: Option<Vec<u8>>,
+}
+
+/// 按量化方式编码向量
+pub(crate) fn encode_vector(vector: &[f32], quantization: VectorQuantization) -> EncodedVector {
+    match quantization {
+        VectorQuantization::None => EncodedVector { vector: super::db::vector_to_bytes(vector), code: None },
+        VectorQuantization::Int8 => EncodedVector { vector: encode_int8(vector), code: None },
+        VectorQuantization::Binary => EncodedVector {
+            vector: encode_int8(vector),
+            code: Some(binary_code(vector)),
+        },
+    }
+}
+
+/// int8 编码：魔数 + 缩放系数（f32）+ 每维一个 i8
+pub(crate) fn encode_int8(vector: &[f32]) -> Vec<u8> {
+    let max_abs = vector.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+    let scale = if max_abs > 0.0 && max_abs.is_finite() { max_abs / 127.0 } else { 1.0 };
+
+    let mut bytes = Vec::with_capacity(8 + vector.len());
+    bytes.extend_from_slice(&INT8_MAGIC);
+    bytes.extend_from_slice(&scale.to_le_bytes());
+    bytes.extend(vector.iter().map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8 as u8));
+    bytes
+}
+
+/// 字节序列是不是 int8 编码
+pub(crate) fn is_int8(bytes: &[u8]) -> bool {
+    bytes.len() >= 8 && bytes[..4] == INT8_MAGIC
+}
+
+/// 反量化 int8 编码
+pub(crate) fn decode_int8(bytes: &[u8]) -> Vec<f32> {
+    let scale = f32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    bytes[8..].iter().map(|&b| b as i8 as f32 * scale).collect()
+}
+
+/// 符号位编码：第 i 维大于 0 时第 i 位为 1
+pub(crate) fn binary_code(vector: &[f32]) -> Vec<u8> {
+    let mut code = vec![0u8; vector.len().div_ceil(8)];
+    for (i, &x) in vector.iter().enumerate() {
+        if x > 0.0 {
+            code[i / 8] |= 1 << (i % 8);
+        }
+    }
+    code
+}
+
+/// 两个符号位编码之间的汉明距离，长度不同时按最大距离算
+pub(crate) fn hamming_distance(a: &[u8], b: &[u8]) -> u32 {
+    if a.len() != b.len() {
+        return u32::MAX;
+    }
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+/// 按新的量化方式重新编码知识库里的所有向量（在调用方的事务里执行），返回处理的向量数
+///
+/// 从 int8 改回 none 时只是换回 f32 格式，量化损失的精度找不回来。加密知识库重新编码后再加密。
+pub(crate) fn requantize_vectors(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    quantization: VectorQuantization,
+    cipher: Option<&super::encryption::KbCipher>,
+) -> Result<usize, rusqlite::Error> {
+    let rows: Vec<(String, Vec<u8>)> = {
+        let mut stmt = conn.prepare("SELECT chunk_id, vector FROM vectors WHERE kb_id = ?1")?;
+        let rows = stmt.query_map([kb_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let mut update = conn.prepare("UPDATE vectors SET vector = ?1, code = ?2 WHERE chunk_id = ?3")?;
+    for (chunk_id, bytes) in &rows {
+        let encoded = super::encryption::encode_for_kb(&super::db::bytes_to_vector(bytes), quantization, cipher);
+        update.execute(rusqlite::params![encoded.vector, encoded.code, chunk_id])?;
+    }
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knowledge_base::db::{bytes_to_vector, cosine_similarity, vector_to_bytes};
+
+    #[test]
+    fn int8_round_trip_keeps_cosine_similarity() {
+        let a: Vec<f32> = (0..64).map(|i| ((i * 37 % 17) as f32 - 8.0) / 10.0).collect();
+        let b: Vec<f32> = (0..64).map(|i| ((i * 11 % 13) as f32 - 6.0) / 7.0).collect();
+
+        let encoded = encode_vector(&a, VectorQuantization::Int8);
+        assert!(encoded.code.is_none());
+        assert_eq!(encoded.vector.len(), 8 + 64);
+        assert!(is_int8(&encoded.vector));
+        assert!(!is_int8(&vector_to_bytes(&a)));
+
+        let decoded = bytes_to_vector(&encoded.vector);
+        assert!((cosine_similarity(&decoded, &b) - cosine_similarity(&a, &b)).abs() < 0.01);
+    }
+
+    #[test]
+    fn binary_codes_measure_sign_disagreement() {
+        let a = [0.5, -0.2, 0.1, -0.9, 0.3, 0.0, 0.7, -0.1, 0.4];
+        let b = [0.4, 0.2, 0.1, -0.9, -0.3, 0.0, 0.7, -0.1, 0.4];
+        let code_a = binary_code(&a);
+        assert_eq!(code_a.len(), 2);
+        assert_eq!(hamming_distance(&code_a, &binary_code(&b)), 2);
+        assert_eq!(hamming_distance(&code_a, &code_a), 0);
+
+        let encoded = encode_vector(&a, VectorQuantization::Binary);
+        assert_eq!(encoded.code, Some(code_a));
+        assert!(is_int8(&encoded.vector));
+    }
+}
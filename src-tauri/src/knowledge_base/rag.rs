@@ -0,0 +1,372 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 聊天检索增强（RAG）模块
+ *
+ * 功能说明:
+ * - 发消息时带上 kb_ids，后端在开始流式输出之前用最后一条用户消息去这些
+ *   知识库检索（和知识库页的检索走同一条路径，包括可选的 reranker）
+ * - 检索结果用 build_context 拼进发给模型的那份用户消息，聊天记录里保存的
+ *   仍是用户的原话；提示词模板用第一个知识库的设置（见 KnowledgeBase::context_template）
+ * - 命中的片段记进 message_sources 表，挂在这条回复上，重新打开会话时还能看到
+ * - 命中的片段整理成引用列表（Citation），编号和拼进上下文的"[文档 N]"一致，
+ *   前端据此渲染脚注
+ * - 会话可以绑定知识库（session_kbs 表），之后该会话每一轮都自动检索绑定的
+ *   知识库，和请求里带的 kb_ids 合在一起，前端不用每次都传
+ * - 会话里用 chat_with_file 加进来的临时文件也一起检索（见 scratch 模块）
+ * - 可选的上下文压缩：拼上下文之前只保留片段里和问题相关的句子（见 compression 模块）
+ * - 上下文有 token 预算（默认按对话模型的上下文窗口估算），超出时丢掉分数最低的片段，
+ *   引用列表只包含实际拼进去的片段
+ *
+ * 检索失败不会中断回复：记一条日志，按没有检索结果继续。多个知识库的结果
+ * 合在一起按分数排序，总数不超过 top_k。
+ */
+
+use super::commands::{load_knowledge_base, search_kb, with_conn, KbState};
+use super::retrieval::{build_context, ContextOptions};
+use super::scratch::retrieve_session_files;
+use super::types::*;
+use crate::commands::llm::ChatMessage;
+use crate::db::DbState;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+fn default_top_k() -> i32 {
+    5
+}
+
+/// 聊天时的检索参数（和知识库页的检索设置对应，query / kb_id 由后端填）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RagSettings {
+    #[serde(default = "default_top_k")]
+    pub top_k: i32,
+    #[serde(default)]
+    pub retrieval_mode: RetrievalMode,
+    #[serde(default)]
+    pub similarity_threshold: f32,
+    #[serde(default)]
+    pub window_size: i32,
+    #[serde(default)]
+    pub reranker_config_id: Option<String>,
+    #[serde(default)]
+    pub reranker_base_url: Option<String>,
+    #[serde(default)]
+    pub reranker_model: Option<String>,
+    #[serde(default)]
+    pub rerank_top_n: Option<i32>,
+    /// 只检索带有其中任一标签的文档
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// 拼上下文之前让模型只摘出片段里和问题相关的句子
+    #[serde(default)]
+    pub compress_context: bool,
+    /// 先按文档摘要挑出这么多篇文档再检索分块，0 表示不预筛选
+    #[serde(default)]
+    pub summary_top_n: i32,
+    /// 拼进对话的检索上下文最多占多少 token，不填时按对话模型的上下文窗口估算
+    #[serde(default)]
+    pub context_token_budget: Option<usize>,
+    /// HyDE 模式写假设回答、上下文压缩用的模型，由后端按本轮对话的模型填
+    #[serde(skip)]
+    pub query_llm: Option<QueryLlm>,
+}
+
+impl Default for RagSettings {
+    fn default() -> Self {
+        Self {
+            top_k: default_top_k(),
+            retrieval_mode: RetrievalMode::default(),
+            similarity_threshold: 0.0,
+            window_size: 0,
+            reranker_config_id: None,
+            reranker_base_url: None,
+            reranker_model: None,
+            rerank_top_n: None,
+            tags: Vec::new(),
+            compress_context: false,
+            summary_top_n: 0,
+            context_token_budget: None,
+            query_llm: None,
+        }
+    }
+}
+
+impl RagSettings {
+    fn request_for(&self, kb_id: &str, query: &str) -> RetrievalRequest {
+        RetrievalRequest {
+            kb_id: kb_id.to_string(),
+            query: query.to_string(),
+            top_k: self.top_k,
+            retrieval_mode: self.retrieval_mode.clone(),
+            similarity_threshold: self.similarity_threshold,
+            window_size: self.window_size,
+            reranker_config_id: self.reranker_config_id.clone(),
+            reranker_base_url: self.reranker_base_url.clone(),
+            reranker_model: self.reranker_model.clone(),
+            rerank_top_n: self.rerank_top_n,
+            tags: self.tags.clone(),
+            query_llm: self.query_llm.clone(),
+            expanded_query: None,
+            summary_top_n: self.summary_top_n,
+            document_ids: None,
+        }
+    }
+}
+
+/// 回复引用的一条知识库片段，`index` 从 1 开始，对应上下文里的"[文档 N]"
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Citation {
+    pub index: usize,
+    pub chunk_id: String,
+    pub document_id: String,
+    pub document_filename: String,
+    /// 片段在文档里的序号
+    pub chunk_index: i32,
+    pub score: f32,
+    pub content: String,
+    /// 片段所在页码（PDF、PPTX）
+    #[serde(default)]
+    pub page: Option<i32>,
+    /// 片段在文档正文里的起止字符偏移，用来跳转到原文位置
+    #[serde(default)]
+    pub start_offset: Option<i32>,
+    #[serde(default)]
+    pub end_offset: Option<i32>,
+    /// 问题里的词命中处的摘录，说明片段为什么被检索到
+    #[serde(default)]
+    pub snippet: Option<MatchSnippet>,
+}
+
+/// 按拼进上下文的顺序给片段编号
+pub(crate) fn citations_from(chunks: &[RetrievedChunk]) -> Vec<Citation> {
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, c)| Citation {
+            index: i + 1,
+            chunk_id: c.chunk.id.clone(),
+            document_id: c.chunk.document_id.clone(),
+            document_filename: c.document_filename.clone(),
+            chunk_index: c.chunk.chunk_index,
+            score: c.score,
+            content: c.chunk.content.clone(),
+            page: c.chunk.page,
+            start_offset: c.chunk.start_offset,
+            end_offset: c.chunk.end_offset,
+            snippet: c.snippet.clone(),
+        })
+        .collect()
+}
+
+/// 本轮的检索问题：最后一条用户消息
+pub(crate) fn last_user_query(messages: &[ChatMessage]) -> Option<String> {
+    messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.clone())
+        .filter(|q| !q.trim().is_empty())
+}
+
+/// 在给定的知识库和会话的临时文件里检索，合并后按分数取前 top_k 个
+pub(crate) async fn retrieve_for_chat(
+    kb_state: &KbState,
+    kb_ids: &[String],
+    session_id: &str,
+    query: &str,
+    settings: &RagSettings,
+) -> Vec<RetrievedChunk> {
+    // HyDE：假设回答只生成一次，所有知识库共用
+    let expanded_query = match settings.retrieval_mode {
+        RetrievalMode::Hyde if !kb_ids.is_empty() => {
+            Some(super::hyde::expand_query(settings.query_llm.as_ref(), query).await)
+        }
+        _ => None,
+    };
+    let mut chunks = Vec::new();
+    for kb_id in kb_ids {
+        let mut request = settings.request_for(kb_id, query);
+        request.expanded_query = expanded_query.clone();
+        match search_kb(kb_state, request).await {
+            Ok(result) => chunks.extend(result.chunks),
+            Err(e) => log::warn!("[RAG] retrieval from knowledge base {} failed: {}", kb_id, e),
+        }
+    }
+    chunks.extend(retrieve_session_files(session_id, query, settings.top_k).await);
+    merge_ranked(chunks, settings.top_k)
+}
+
+fn merge_ranked(mut chunks: Vec<RetrievedChunk>, top_k: i32) -> Vec<RetrievedChunk> {
+    chunks.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    chunks.truncate(top_k.max(0) as usize);
+    chunks
+}
+
+/// 拼上下文用的模板和语言：多个知识库一起检索时用第一个知识库的设置，
+/// 只有会话临时文件或读不到知识库时用中文默认模板
+pub(crate) async fn context_template_for(kb_state: &KbState, kb_ids: &[String]) -> (Option<String>, ContextLanguage) {
+    let kb = match kb_ids.first().cloned() {
+        Some(kb_id) => with_conn(&kb_state.db_path, move |conn| load_knowledge_base(&conn, &kb_id)).await.ok(),
+        None => None,
+    };
+    match kb {
+        Some(kb) => (kb.context_template, kb.context_language),
+        None => (None, ContextLanguage::default()),
+    }
+}
+
+/// 把检索到的片段拼进最后一条用户消息（只改发给模型的那份拷贝），返回实际拼进去的片段
+///
+/// 超出 token 预算被丢掉的片段不在返回值里，引用编号和上下文里的 [文档 N] 保持一致；
+/// 截短的片段在引用里仍是完整内容。
+pub(crate) fn inject_context(
+    messages: &mut [ChatMessage],
+    chunks: Vec<RetrievedChunk>,
+    options: &ContextOptions,
+) -> Vec<RetrievedChunk> {
+    if chunks.is_empty() {
+        return chunks;
+    }
+    let Some(last_user) = messages.iter_mut().rev().find(|m| m.role == "user") else {
+        return Vec::new();
+    };
+    let built = build_context(&chunks, &last_user.content, options);
+    if built.included.len() < chunks.len() || !built.trimmed.is_empty() {
+        log::info!(
+            "[RAG] context budget kept {} of {} chunks ({} trimmed, ~{} tokens)",
+            built.included.len(),
+            chunks.len(),
+            built.trimmed.len(),
+            built.tokens
+        );
+    }
+    last_user.content = built.text;
+    let mut chunks: Vec<Option<RetrievedChunk>> = chunks.into_iter().map(Some).collect();
+    built.included.iter().filter_map(|&index| chunks[index].take()).collect()
+}
+
+/// 给会话绑定知识库
+#[tauri::command]
+pub async fn attach_session_kb(
+    session_id: String,
+    kb_id: String,
+    kb_state: State<'_, KbState>,
+    db_state: State<'_, DbState>,
+) -> Result<(), KnowledgeBaseError> {
+    let id = kb_id.clone();
+    let exists: bool = with_conn(&kb_state.db_path, move |conn| {
+        conn.query_row("SELECT COUNT(*) FROM knowledge_bases WHERE id = ?1", [&id], |row| row.get(0))
+            .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+    })
+    .await?;
+    if !exists {
+        return Err(KnowledgeBaseError::NotFound(format!("Knowledge base not found: {}", kb_id)));
+    }
+
+    db_state
+        .run(move |db| {
+            db.attach_session_kb(&session_id, &kb_id)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+        })
+        .await
+}
+
+/// 解除会话和知识库的绑定
+#[tauri::command]
+pub async fn detach_session_kb(
+    session_id: String,
+    kb_id: String,
+    db_state: State<'_, DbState>,
+) -> Result<(), KnowledgeBaseError> {
+    db_state
+        .run(move |db| {
+            db.detach_session_kb(&session_id, &kb_id)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+        })
+        .await
+}
+
+/// 获取会话绑定的知识库 ID
+#[tauri::command]
+pub async fn list_session_kbs(
+    session_id: String,
+    db_state: State<'_, DbState>,
+) -> Result<Vec<String>, KnowledgeBaseError> {
+    db_state
+        .run(move |db| {
+            db.get_session_kb_ids(&session_id)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &str, score: f32) -> RetrievedChunk {
+        RetrievedChunk {
+            chunk: Chunk {
+                id: id.to_string(),
+                document_id: "d1".to_string(),
+                kb_id: "kb".to_string(),
+                content: format!("内容 {}", id),
+                chunk_index: 0,
+                token_count: 3,
+                ..Default::default()
+            },
+            score,
+            vector_score: Some(score),
+            keyword_score: None,
+            document_filename: "手册.pdf".to_string(),
+            snippet: None,
+            match_offsets: Vec::new(),
+        }
+    }
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            id: String::new(),
+            role: role.to_string(),
+            content: content.to_string(),
+            timestamp: 0,
+            error: None,
+            images: vec![],
+            videos: vec![],
+            seed: None,
+            generation: None,
+        }
+    }
+
+    #[test]
+    fn results_from_several_knowledge_bases_are_merged_by_score() {
+        let merged = merge_ranked(vec![chunk("a", 0.4), chunk("b", 0.9), chunk("c", 0.7)], 2);
+        let ids: Vec<&str> = merged.iter().map(|c| c.chunk.id.as_str()).collect();
+        assert_eq!(ids, ["b", "c"]);
+    }
+
+    #[test]
+    fn context_goes_into_the_last_user_message_only() {
+        let mut messages = vec![message("user", "旧问题"), message("assistant", "旧回答"), message("user", "怎么退款")];
+        assert_eq!(last_user_query(&messages).as_deref(), Some("怎么退款"));
+
+        let options = ContextOptions::default();
+        let included = inject_context(&mut messages, vec![chunk("a", 0.9)], &options);
+        assert_eq!(included.len(), 1);
+        assert_eq!(messages[0].content, "旧问题");
+        assert!(messages[2].content.contains("[文档 1: 手册.pdf]\n内容 a"));
+        assert!(messages[2].content.ends_with("问题：怎么退款"));
+
+        let citations = citations_from(&[chunk("a", 0.9), chunk("b", 0.5)]);
+        assert_eq!(citations[1].index, 2);
+        assert_eq!(citations[1].chunk_id, "b");
+        assert_eq!(citations[1].document_filename, "手册.pdf");
+
+        let mut untouched = vec![message("user", "hi")];
+        assert!(inject_context(&mut untouched, Vec::new(), &options).is_empty());
+        assert_eq!(untouched[0].content, "hi");
+    }
+}
@@ -0,0 +1,410 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * embedding 模型迁移模块
+ *
+ * 功能说明:
+ * - reembed_knowledge_base 在后台用新的 embedding 服务商/模型为知识库的全部分块重新生成向量，
+ *   不需要删掉知识库重新导入文档
+ * - 新向量先写进 reembed_vectors 暂存表，迁移期间检索仍然使用旧向量和旧模型；
+ *   全部生成完之后在一个事务里替换 vectors 表并更新知识库的 embedding 配置
+ * - 迁移任务记在 reembed_jobs 表里，中断（出错、应用关闭）之后用同样的目标再次调用即可继续，
+ *   已经暂存的向量不会重新生成；换了目标模型则从头开始
+ * - 通过 kb-reembed-progress 事件上报进度；cancel_reembed 放弃迁移并删掉暂存的向量
+ *
+ * 迁移期间不能往知识库里导入、重新导入文档（见 ensure_not_reembedding），
+ * 否则新分块会按旧模型生成向量，切换之后和其它向量不在同一个空间里。
+ */
+
+use super::commands::{embedding_target, load_knowledge_base, KbState};
+use super::embedding_config::{load_embedding_config, read_api_key, EmbeddingTarget};
+use super::db::{bytes_to_vector, kb_quantization, vector_to_bytes};
+use super::embedding::embed_in_batches;
+use super::encryption::{encode_for_kb, kb_cipher, open_text, seal_vector};
+use super::types::*;
+use once_cell::sync::Lazy;
+use rusqlite::OptionalExtension;
+use std::collections::HashSet;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+/// 正在后台迁移的知识库，避免同一个知识库同时跑两个迁移任务
+static REEMBEDDING: Lazy<std::sync::Mutex<HashSet<String>>> = Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+
+fn is_running(kb_id: &str) -> bool {
+    REEMBEDDING.lock().unwrap_or_else(|e| e.into_inner()).contains(kb_id)
+}
+
+/// 读取知识库的迁移任务，连同已暂存的向量数和总分块数
+fn load_job(conn: &rusqlite::Connection, kb_id: &str) -> Result<Option<ReembedJob>, KnowledgeBaseError> {
+    conn.query_row(
+        "SELECT kb_id, embedding_api_config_id, embedding_provider, embedding_model, embedding_base_url,
+                error_message, started_at,
+                (SELECT COUNT(*) FROM reembed_vectors r JOIN chunks c ON c.id = r.chunk_id WHERE r.kb_id = j.kb_id),
+                (SELECT COUNT(*) FROM chunks WHERE kb_id = j.kb_id)
+         FROM reembed_jobs j WHERE kb_id = ?1",
+        [kb_id],
+        |row| {
+            Ok(ReembedJob {
+                kb_id: row.get(0)?,
+                embedding_api_config_id: row.get(1)?,
+                embedding_provider: row.get(2)?,
+                embedding_model: row.get(3)?,
+                embedding_base_url: row.get(4)?,
+                error: row.get(5)?,
+                started_at: row.get(6)?,
+                done: row.get::<_, i64>(7)? as usize,
+                total: row.get::<_, i64>(8)? as usize,
+                running: false,
+            })
+        },
+    )
+    .optional()
+    .map_err(db_error)
+    .map(|job| job.map(|job| ReembedJob { running: is_running(&job.kb_id), ..job }))
+}
+
+/// 知识库正在迁移 embedding 模型时拒绝写入新分块的操作
+pub(crate) fn ensure_not_reembedding(conn: &rusqlite::Connection, kb_id: &str) -> Result<(), KnowledgeBaseError> {
+    let migrating: bool = conn
+        .query_row("SELECT COUNT(*) > 0 FROM reembed_jobs WHERE kb_id = ?1", [kb_id], |row| row.get(0))
+        .map_err(db_error)?;
+    if migrating {
+        return Err(KnowledgeBaseError::InvalidConfig(
+            "知识库正在迁移 embedding 模型，请等迁移完成或取消迁移后再导入".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// 用新的 embedding 模型重新生成知识库的全部向量，完成后整体切换
+///
+/// 已经有同一目标的迁移任务时从中断处继续；目标不同则丢掉之前暂存的向量重新开始。
+#[tauri::command]
+pub async fn reembed_knowledge_base(
+    request: ReembedRequest,
+    app_handle: AppHandle,
+    kb_state: State<'_, KbState>,
+) -> Result<ReembedJob, KnowledgeBaseError> {
+    if is_running(&request.kb_id) {
+        return Err(KnowledgeBaseError::InvalidConfig("知识库正在迁移 embedding 模型".to_string()));
+    }
+
+    let conn = crate::db::open_connection(&kb_state.db_path).map_err(db_error)?;
+    let kb = load_knowledge_base(&conn, &request.kb_id)?;
+    let target = load_embedding_config(&conn, &request.embedding_api_config_id)?;
+    let processing: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM documents WHERE kb_id = ?1 AND status = 'processing'",
+            [&kb.id],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    if processing {
+        return Err(KnowledgeBaseError::InvalidConfig("知识库里还有正在导入的文档，请等导入完成后再迁移".to_string()));
+    }
+
+    let same_target = |provider: &str, model: &str, base_url: &str| {
+        provider == target.provider && model == target.model && base_url == target.base_url
+    };
+    match load_job(&conn, &kb.id)? {
+        Some(job) if same_target(&job.embedding_provider, &job.embedding_model, &job.embedding_base_url) => {
+            // 继续之前的迁移；API 配置可能换了一个同模型的
+            conn.execute(
+                "UPDATE reembed_jobs SET embedding_api_config_id = ?1, error_message = NULL WHERE kb_id = ?2",
+                rusqlite::params![&target.id, &kb.id],
+            )
+            .map_err(db_error)?;
+        }
+        existing => {
+            let (provider, model, base_url) = embedding_target(&kb);
+            if existing.is_none() && same_target(&provider, &model, &base_url) {
+                return Err(KnowledgeBaseError::InvalidConfig("知识库已经在使用这个 embedding 模型".to_string()));
+            }
+            conn.execute("DELETE FROM reembed_vectors WHERE kb_id = ?1", [&kb.id]).map_err(db_error)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO reembed_jobs
+                 (kb_id, embedding_api_config_id, embedding_provider, embedding_model, embedding_base_url, error_message, started_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, NULL, ?6)",
+                rusqlite::params![
+                    &kb.id,
+                    &target.id,
+                    &target.provider,
+                    &target.model,
+                    &target.base_url,
+                    chrono::Utc::now().timestamp_millis()
+                ],
+            )
+            .map_err(db_error)?;
+        }
+    }
+
+    let job = load_job(&conn, &kb.id)?
+        .ok_or_else(|| KnowledgeBaseError::NotFound(format!("Reembed job not found: {}", kb.id)))?;
+    if !REEMBEDDING.lock().unwrap_or_else(|e| e.into_inner()).insert(kb.id.clone()) {
+        return Err(KnowledgeBaseError::InvalidConfig("知识库正在迁移 embedding 模型".to_string()));
+    }
+    log::info!(
+        "[KB] Re-embedding {} with {}/{} ({} of {} chunks already done)",
+        kb.name, job.embedding_provider, job.embedding_model, job.done, job.total
+    );
+
+    let kb_id = kb.id.clone();
+    tauri::async_runtime::spawn(async move {
+        let _done = scopeguard::guard(kb_id.clone(), |kb_id| {
+            REEMBEDDING.lock().unwrap_or_else(|e| e.into_inner()).remove(&kb_id);
+        });
+        if let Err(e) = run_reembed(&app_handle, &kb_id).await {
+            let message = e.to_string();
+            log::error!("[KB] Re-embedding {} failed: {}", kb_id, message);
+            let db_path = app_handle.state::<KbState>().db_path.clone();
+            if let Err(mark_err) = crate::db::open_connection(&db_path).and_then(|conn| {
+                conn.execute("UPDATE reembed_jobs SET error_message = ?1 WHERE kb_id = ?2", rusqlite::params![&message, &kb_id])
+            }) {
+                log::warn!("[KB] Failed to record re-embedding error for {}: {}", kb_id, mark_err);
+            }
+            emit_progress(&app_handle, ReembedProgressEvent { kb_id, done: 0, total: 0, finished: true, error: Some(message) });
+        }
+    });
+
+    Ok(ReembedJob { running: true, ..job })
+}
+
+/// 查询知识库进行中或中断了的迁移任务；没有时返回 None
+#[tauri::command]
+pub async fn get_reembed_job(kb_id: String, kb_state: State<'_, KbState>) -> Result<Option<ReembedJob>, KnowledgeBaseError> {
+    let conn = crate::db::open_connection(&kb_state.db_path).map_err(db_error)?;
+    load_job(&conn, &kb_id)
+}
+
+/// 放弃迁移：删掉暂存的新向量，知识库继续使用原来的模型。正在运行的迁移在当前批次结束后停止
+#[tauri::command]
+pub async fn cancel_reembed(kb_id: String, kb_state: State<'_, KbState>) -> Result<(), KnowledgeBaseError> {
+    let conn = crate::db::open_connection(&kb_state.db_path).map_err(db_error)?;
+    conn.execute("DELETE FROM reembed_jobs WHERE kb_id = ?1", [&kb_id]).map_err(db_error)?;
+    conn.execute("DELETE FROM reembed_vectors WHERE kb_id = ?1", [&kb_id]).map_err(db_error)?;
+    log::info!("[KB] Cancelled re-embedding of {}", kb_id);
+    Ok(())
+}
+
+/// 还没有暂存新向量的分块 (chunk_id, 内容)
+fn pending_chunks(conn: &rusqlite::Connection, kb_id: &str) -> Result<Vec<(String, String)>, KnowledgeBaseError> {
+    let cipher = kb_cipher(conn, kb_id)?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT c.id, c.content FROM chunks c
+             LEFT JOIN reembed_vectors r ON r.chunk_id = c.id
+             WHERE c.kb_id = ?1 AND r.chunk_id IS NULL
+             ORDER BY c.document_id, c.chunk_index",
+        )
+        .map_err(db_error)?;
+    let rows = stmt
+        .query_map([kb_id], |row| Ok((row.get(0)?, open_text(cipher.as_deref(), row.get(1)?))))
+        .map_err(db_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(db_error)?;
+    Ok(rows)
+}
+
+/// 把一批新向量写进暂存表；任务已被取消时什么也不写，返回 false
+fn save_batch(
+    db_path: &str,
+    kb_id: &str,
+    batch: &[(String, String)],
+    embeddings: Vec<Vec<f32>>,
+) -> Result<bool, KnowledgeBaseError> {
+    let mut conn = crate::db::open_connection(db_path).map_err(db_error)?;
+    let tx = conn.transaction().map_err(db_error)?;
+    let cancelled: bool = tx
+        .query_row("SELECT COUNT(*) = 0 FROM reembed_jobs WHERE kb_id = ?1", [kb_id], |row| row.get(0))
+        .map_err(db_error)?;
+    if cancelled {
+        return Ok(false);
+    }
+    let cipher = kb_cipher(&tx, kb_id)?;
+    for ((chunk_id, _), embedding) in batch.iter().zip(embeddings) {
+        tx.execute(
+            "INSERT OR REPLACE INTO reembed_vectors (chunk_id, kb_id, vector) VALUES (?1, ?2, ?3)",
+            rusqlite::params![chunk_id, kb_id, seal_vector(cipher.as_deref(), vector_to_bytes(&embedding))],
+        )
+        .map_err(db_error)?;
+    }
+    tx.commit().map_err(db_error)?;
+    Ok(true)
+}
+
+/// 后台部分：分批生成新向量写进暂存表，全部完成后切换。任务被取消时安静地结束
+async fn run_reembed(app_handle: &AppHandle, kb_id: &str) -> Result<(), KnowledgeBaseError> {
+    let kb_state = app_handle.state::<KbState>();
+    let db_path = kb_state.db_path.clone();
+
+    // 迁移期间分块可能被删除或修改，处理完一轮之后再查一次，直到没有遗漏
+    loop {
+        let (job, pending, key_ref) = {
+            let conn = crate::db::open_connection(&db_path).map_err(db_error)?;
+            let Some(job) = load_job(&conn, kb_id)? else {
+                return Ok(());
+            };
+            let key_ref = load_embedding_config(&conn, &job.embedding_api_config_id)?.key_ref;
+            (job, pending_chunks(&conn, kb_id)?, key_ref)
+        };
+        if pending.is_empty() {
+            break;
+        }
+
+        let target = EmbeddingTarget {
+            provider: job.embedding_provider.clone(),
+            model: job.embedding_model.clone(),
+            base_url: job.embedding_base_url.clone(),
+            api_key: read_api_key(&key_ref)?,
+        };
+        let mut done = job.done;
+        let finished = embed_in_batches(&pending, |(_, content): &(String, String)| content.clone(), &target, |batch, embeddings| {
+            let saved = save_batch(&db_path, kb_id, batch, embeddings);
+            if let Ok(true) = saved {
+                done += batch.len();
+                emit_progress(
+                    app_handle,
+                    ReembedProgressEvent { kb_id: kb_id.to_string(), done, total: job.total, finished: false, error: None },
+                );
+            }
+            std::future::ready(saved)
+        })
+        .await?;
+        if !finished {
+            return Ok(());
+        }
+    }
+
+    let mut conn = crate::db::open_connection(&db_path).map_err(db_error)?;
+    let Some(job) = load_job(&conn, kb_id)? else {
+        return Ok(());
+    };
+    let count = swap_vectors(&mut conn, &job)?;
+    kb_state.vector_store.drop_ann_index(kb_id);
+    emit_progress(
+        app_handle,
+        ReembedProgressEvent { kb_id: kb_id.to_string(), done: count, total: count, finished: true, error: None },
+    );
+    log::info!("[KB] Re-embedded {} chunks of {} with {}", count, kb_id, job.embedding_model);
+    Ok(())
+}
+
+/// 在一个事务里用暂存的向量替换知识库的全部向量，并把知识库的 embedding 配置改成迁移目标。
+/// 返回切换的向量数
+fn swap_vectors(conn: &mut rusqlite::Connection, job: &ReembedJob) -> Result<usize, KnowledgeBaseError> {
+    let tx = conn.transaction().map_err(db_error)?;
+    let quantization = kb_quantization(&tx, &job.kb_id)?;
+    let cipher = kb_cipher(&tx, &job.kb_id)?;
+
+    let rows: Vec<(String, String, Vec<u8>)> = {
+        let mut stmt = tx
+            .prepare(
+                "SELECT r.chunk_id, c.document_id, r.vector FROM reembed_vectors r
+                 JOIN chunks c ON c.id = r.chunk_id WHERE r.kb_id = ?1",
+            )
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map([&job.kb_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(db_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_error)?;
+        rows
+    };
+
+    tx.execute("DELETE FROM vectors WHERE kb_id = ?1", [&job.kb_id]).map_err(db_error)?;
+    // 摘要向量是旧模型生成的，和新向量不可比；摘要文字保留，由 summarize_documents 补向量
+    tx.execute("UPDATE document_summaries SET vector = NULL WHERE kb_id = ?1", [&job.kb_id]).map_err(db_error)?;
+    let mut dim = 0;
+    {
+        let mut insert = tx
+            .prepare("INSERT INTO vectors (chunk_id, document_id, kb_id, vector, code) VALUES (?1, ?2, ?3, ?4, ?5)")
+            .map_err(db_error)?;
+        for (chunk_id, document_id, bytes) in &rows {
+            let vector = bytes_to_vector(bytes);
+            dim = vector.len();
+            let encoded = encode_for_kb(&vector, quantization, cipher.as_deref());
+            insert
+                .execute(rusqlite::params![chunk_id, document_id, &job.kb_id, encoded.vector, encoded.code])
+                .map_err(db_error)?;
+        }
+    }
+
+    tx.execute(
+        "UPDATE knowledge_bases SET embedding_api_config_id = ?1, embedding_provider = ?2, embedding_model = ?3,
+         embedding_base_url = ?4, embedding_dim = ?5, updated_at = ?6 WHERE id = ?7",
+        rusqlite::params![
+            &job.embedding_api_config_id,
+            &job.embedding_provider,
+            &job.embedding_model,
+            &job.embedding_base_url,
+            dim as i64,
+            chrono::Utc::now().timestamp_millis(),
+            &job.kb_id
+        ],
+    )
+    .map_err(db_error)?;
+    tx.execute("DELETE FROM reembed_vectors WHERE kb_id = ?1", [&job.kb_id]).map_err(db_error)?;
+    tx.execute("DELETE FROM reembed_jobs WHERE kb_id = ?1", [&job.kb_id]).map_err(db_error)?;
+    tx.commit().map_err(db_error)?;
+    Ok(rows.len())
+}
+
+fn emit_progress(app_handle: &AppHandle, progress: ReembedProgressEvent) {
+    if let Err(e) = app_handle.emit("kb-reembed-progress", progress) {
+        log::warn!("[KB] Failed to emit re-embedding progress: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_vectors_replace_the_old_ones_and_update_the_kb() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, embedding_dim, created_at, updated_at)
+             VALUES ('kb', 'kb', 'old-cfg', 'openai', 'old', 2, 0, 0);
+             INSERT INTO documents (id, kb_id, filename, file_type, created_at) VALUES ('d', 'kb', 'a.txt', 'txt', 0);
+             INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at) VALUES ('c1', 'd', 'kb', 'a', 0, 0);
+             INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at) VALUES ('c2', 'd', 'kb', 'b', 1, 0);
+             INSERT INTO reembed_jobs (kb_id, embedding_api_config_id, embedding_provider, embedding_model, embedding_base_url, started_at)
+             VALUES ('kb', 'cfg', 'voyage', 'new', '', 0);",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO vectors (chunk_id, document_id, kb_id, vector) VALUES ('c1', 'd', 'kb', ?1)",
+            [vector_to_bytes(&[1.0, 0.0])],
+        )
+        .unwrap();
+        for (chunk_id, vector) in [("c1", [0.0, 1.0, 0.0]), ("c2", [1.0, 1.0, 0.0]), ("gone", [0.0, 0.0, 1.0])] {
+            conn.execute(
+                "INSERT INTO reembed_vectors (chunk_id, kb_id, vector) VALUES (?1, 'kb', ?2)",
+                rusqlite::params![chunk_id, vector_to_bytes(&vector)],
+            )
+            .unwrap();
+        }
+
+        let job = load_job(&conn, "kb").unwrap().unwrap();
+        assert_eq!((job.done, job.total), (2, 2));
+        assert!(pending_chunks(&conn, "kb").unwrap().is_empty());
+        assert!(ensure_not_reembedding(&conn, "kb").is_err());
+
+        // 已经删掉的分块（gone）不会被切换进去
+        assert_eq!(swap_vectors(&mut conn, &job).unwrap(), 2);
+        let stored: Vec<u8> =
+            conn.query_row("SELECT vector FROM vectors WHERE chunk_id = 'c1'", [], |row| row.get(0)).unwrap();
+        assert_eq!(bytes_to_vector(&stored), vec![0.0, 1.0, 0.0]);
+        let (model, dim): (String, i64) = conn
+            .query_row("SELECT embedding_model, embedding_dim FROM knowledge_bases WHERE id = 'kb'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((model.as_str(), dim), ("new", 3));
+        assert!(load_job(&conn, "kb").unwrap().is_none());
+        assert!(ensure_not_reembedding(&conn, "kb").is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 知识库数据修复
+ *
+ * 功能说明:
+ * - 找出已经没有归属的数据并删除：知识库不存在的文档、文档不存在的分块和父块、
+ *   分块不存在的向量和全文索引条目，以及挂在已删除文档上的全文、摘要
+ * - 顺带清理已删除知识库留下的会话绑定、向量变更日志和待切换的新向量
+ * - 返回每一类删除了多少条，前端据此提示修复结果
+ *
+ * 旧版本删除知识库时依赖外键级联，连接没有开启 foreign_keys 时级联不会发生，
+ * chunks_fts 又是不受外键约束的虚拟表，这些数据会一直留在库里、占空间，
+ * 关键词检索还可能命中已删除的内容。修复在一个事务里完成，可以随时重复执行。
+ */
+
+use super::commands::{with_conn, KbState};
+use super::types::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use tauri::State;
+
+/// 一次修复删除的各类数据条数
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct RepairReport {
+    pub documents: usize,
+    pub chunks: usize,
+    pub parent_chunks: usize,
+    pub vectors: usize,
+    pub fts_rows: usize,
+    pub document_contents: usize,
+    pub document_summaries: usize,
+    pub reembed_vectors: usize,
+    pub session_bindings: usize,
+    /// 受影响的知识库，修复后需要重建它们的 ANN 索引和向量缓存
+    #[serde(skip)]
+    pub affected_kbs: Vec<String>,
+}
+
+impl RepairReport {
+    /// 删除的数据总条数
+    pub fn total(&self) -> usize {
+        self.documents
+            + self.chunks
+            + self.parent_chunks
+            + self.vectors
+            + self.fts_rows
+            + self.document_contents
+            + self.document_summaries
+            + self.reembed_vectors
+            + self.session_bindings
+    }
+}
+
+/// 删除没有归属的数据，返回各类删除的条数
+pub(crate) fn remove_orphans(conn: &rusqlite::Connection) -> Result<RepairReport, rusqlite::Error> {
+    let tx = conn.unchecked_transaction()?;
+
+    // 被删掉的向量所在的知识库（含已经不存在的知识库），之后要丢掉它们的 ANN 索引
+    let affected_kbs: HashSet<String> = {
+        let mut stmt = tx.prepare(
+            "SELECT DISTINCT kb_id FROM vectors WHERE chunk_id NOT IN (SELECT id FROM chunks)
+                OR kb_id NOT IN (SELECT id FROM knowledge_bases)
+             UNION SELECT DISTINCT kb_id FROM chunks WHERE kb_id NOT IN (SELECT id FROM knowledge_bases)
+                OR document_id NOT IN (SELECT id FROM documents)",
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let mut report = RepairReport {
+        documents: tx.execute("DELETE FROM documents WHERE kb_id NOT IN (SELECT id FROM knowledge_bases)", [])?,
+        ..Default::default()
+    };
+    // 全文索引按 rowid 对应分块，必须在删除分块之前删
+    report.fts_rows = tx.execute(
+        "DELETE FROM chunks_fts WHERE rowid IN (
+            SELECT rowid FROM chunks WHERE document_id NOT IN (SELECT id FROM documents)
+                OR kb_id NOT IN (SELECT id FROM knowledge_bases)
+         )",
+        [],
+    )?;
+    report.chunks = tx.execute(
+        "DELETE FROM chunks WHERE document_id NOT IN (SELECT id FROM documents)
+            OR kb_id NOT IN (SELECT id FROM knowledge_bases)",
+        [],
+    )?;
+    report.fts_rows += tx.execute("DELETE FROM chunks_fts WHERE rowid NOT IN (SELECT rowid FROM chunks)", [])?;
+    report.parent_chunks = tx.execute(
+        "DELETE FROM parent_chunks WHERE document_id NOT IN (SELECT id FROM documents)
+            OR kb_id NOT IN (SELECT id FROM knowledge_bases)",
+        [],
+    )?;
+    report.vectors = tx.execute(
+        "DELETE FROM vectors WHERE chunk_id NOT IN (SELECT id FROM chunks)
+            OR kb_id NOT IN (SELECT id FROM knowledge_bases)",
+        [],
+    )?;
+    report.document_contents = tx.execute(
+        "DELETE FROM document_contents WHERE document_id NOT IN (SELECT id FROM documents)",
+        [],
+    )?;
+    report.document_summaries = tx.execute(
+        "DELETE FROM document_summaries WHERE document_id NOT IN (SELECT id FROM documents)",
+        [],
+    )?;
+    report.reembed_vectors = tx.execute(
+        "DELETE FROM reembed_vectors WHERE chunk_id NOT IN (SELECT id FROM chunks)",
+        [],
+    )?;
+    tx.execute("DELETE FROM reembed_jobs WHERE kb_id NOT IN (SELECT id FROM knowledge_bases)", [])?;
+    report.session_bindings = tx.execute(
+        "DELETE FROM session_kbs WHERE kb_id NOT IN (SELECT id FROM knowledge_bases)",
+        [],
+    )?;
+    // 已删除知识库的向量变更日志和版本号（上面删向量时触发器又记了一遍）
+    tx.execute("DELETE FROM vector_log WHERE kb_id NOT IN (SELECT id FROM knowledge_bases)", [])?;
+    tx.execute("DELETE FROM vector_versions WHERE kb_id NOT IN (SELECT id FROM knowledge_bases)", [])?;
+    tx.commit()?;
+
+    report.affected_kbs = affected_kbs.into_iter().collect();
+    Ok(report)
+}
+
+/// 查找并删除没有归属的分块、向量、全文索引等数据，返回修复了什么
+#[tauri::command]
+pub async fn repair_knowledge_base(kb_state: State<'_, KbState>) -> Result<RepairReport, KnowledgeBaseError> {
+    let report = with_conn(&kb_state.db_path, |conn| {
+        remove_orphans(&conn).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))
+    })
+    .await?;
+    for kb_id in &report.affected_kbs {
+        kb_state.vector_store.drop_ann_index(kb_id);
+    }
+    log::info!("[KB] Repair removed {} orphaned rows: {:?}", report.total(), report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(conn: &rusqlite::Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn orphans_left_without_cascades_are_removed() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE session_kbs (session_id TEXT NOT NULL, kb_id TEXT NOT NULL, created_at INTEGER NOT NULL DEFAULT 0);
+             INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at)
+                VALUES ('kb', 'kb', 'cfg', 'openai', 'm', 0, 0), ('gone', 'gone', 'cfg', 'openai', 'm', 0, 0);
+             INSERT INTO documents (id, kb_id, filename, file_type, status, created_at)
+                VALUES ('d1', 'kb', 'a.md', 'md', 'completed', 0), ('d2', 'gone', 'b.md', 'md', 'completed', 0);
+             INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at)
+                VALUES ('c1', 'd1', 'kb', 'kept', 0, 0), ('c2', 'd2', 'gone', 'orphan', 0, 0);
+             INSERT INTO chunks_fts (rowid, kb_id, content) SELECT rowid, kb_id, content FROM chunks;
+             INSERT INTO vectors (chunk_id, document_id, kb_id, vector) VALUES ('c1', 'd1', 'kb', x'00'), ('c2', 'd2', 'gone', x'00');
+             INSERT INTO document_contents (document_id, content) VALUES ('d1', 'a'), ('d2', 'b');
+             INSERT INTO session_kbs (session_id, kb_id) VALUES ('s', 'kb'), ('s', 'gone');",
+        )
+        .unwrap();
+
+        // 没有开启外键时删除知识库，下面的数据都留了下来
+        conn.execute_batch("PRAGMA foreign_keys = OFF; DELETE FROM knowledge_bases WHERE id = 'gone';").unwrap();
+        let report = remove_orphans(&conn).unwrap();
+        assert_eq!(
+            (report.documents, report.chunks, report.vectors, report.fts_rows, report.document_contents, report.session_bindings),
+            (1, 1, 1, 1, 1, 1)
+        );
+        assert_eq!(report.affected_kbs, ["gone"]);
+        assert_eq!(count(&conn, "chunks"), 1);
+        assert_eq!(count(&conn, "chunks_fts"), 1);
+        assert_eq!(count(&conn, "vector_log WHERE kb_id = 'gone'"), 0);
+
+        assert_eq!(remove_orphans(&conn).unwrap().total(), 0);
+    }
+}
@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::types::{KnowledgeBaseError, RetrievedChunk};
+use crate::commands::http_client::{shared_client, ClientKind};
 
 /// 使用兼容 Cohere 接口的 reranker API 对检索结果重新排序。
 ///
@@ -27,9 +28,7 @@ pub async fn rerank_chunks(
 
     let url = format!("{}/v1/rerank", base_url.trim_end_matches('/'));
 
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
+    let client = shared_client(ClientKind::General, None, &url)
         .map_err(|e| KnowledgeBaseError::RetrievalError(format!("Failed to build HTTP client: {}", e)))?;
 
     let body = serde_json::json!({
@@ -41,6 +40,7 @@ pub async fn rerank_chunks(
 
     let response = client
         .post(&url)
+        .timeout(std::time::Duration::from_secs(30))
         .header("Authorization", format!("Bearer {}", api_key))
         .header("Content-Type", "application/json")
         .json(&body)
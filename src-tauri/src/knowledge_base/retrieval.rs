@@ -3,10 +3,117 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::types::*;
-use super::db::VectorStore;
+use super::db::{document_filter_clause, document_filter_param, VectorStore};
+use super::dedup::NOT_COLLAPSED;
+use super::trash::NOT_TRASHED;
 use super::embedding::generate_single_embedding;
+use super::document::{estimate_tokens, join_chunks};
+use super::fts::{build_match_query, build_snippet, match_ranges};
+use super::encryption::{kb_cipher, open_text};
+use super::summary::select_documents;
+use super::tokenizer::{count_tokens, truncate_to_tokens};
+use crate::commands::context_window::{context_window, input_budget};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+/// BM25 分数归一化到 0-1 时的半饱和点：原始分数等于它时归一化为 0.5。
+/// 用固定的饱和曲线而不是按本次结果的最大值缩放，分数在不同查询之间才可比
+const BM25_HALF_SCORE: f64 = 3.0;
+/// BM25 的词频饱和参数和文档长度归一化参数，和 FTS5 bm25() 的默认值一致
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+/// 把 BM25 原始分数（越大越相关）映射到 0-1
+fn normalize_bm25(raw: f64) -> f32 {
+    let raw = raw.max(0.0);
+    (raw / (raw + BM25_HALF_SCORE)) as f32
+}
+
+/// LIKE 回退时没有 FTS 的语料统计，按 IDF 为 1 计算 BM25：各个词在内容里的出现次数
+/// 按词频饱和、文档长度归一化后相加，avg_len 取本次命中结果的平均长度
+fn like_bm25(content: &str, terms: &[String], avg_len: f64) -> f64 {
+    let content = content.to_lowercase();
+    let len = content.chars().count() as f64;
+    let length_norm = 1.0 - BM25_B + BM25_B * len / avg_len.max(1.0);
+    terms
+        .iter()
+        .map(|term| {
+            let tf = content.matches(&term.to_lowercase()).count() as f64;
+            tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * length_norm)
+        })
+        .sum()
+}
+
+/// 把子块的内容换成父块（parents: chunk_id -> (父块 id, 父块内容)）。结果已经按分数排好序，
+/// 同一个父块下的子块只保留第一个；没有父块的结果原样保留
+fn replace_with_parents(
+    chunks: Vec<RetrievedChunk>,
+    parents: &HashMap<String, (String, String)>,
+) -> (Vec<RetrievedChunk>, HashSet<String>) {
+    let mut seen_parents = HashSet::new();
+    let mut replaced = HashSet::new();
+    let mut result = Vec::with_capacity(chunks.len());
+    for mut chunk in chunks {
+        if let Some((parent_id, content)) = parents.get(&chunk.chunk.id) {
+            if !seen_parents.insert(parent_id.clone()) {
+                continue;
+            }
+            chunk.chunk.content = content.clone();
+            chunk.chunk.token_count = estimate_tokens(content);
+            // 父块没有记录字符偏移，页码沿用命中的子块
+            chunk.chunk.start_offset = None;
+            chunk.chunk.end_offset = None;
+            replaced.insert(chunk.chunk.id.clone());
+        }
+        result.push(chunk);
+    }
+    (result, replaced)
+}
+
+/// 一条检索结果的窗口扩展方式
+#[derive(Debug, PartialEq)]
+enum WindowSpan {
+    /// 不扩展（父子分块已经换成了父块）
+    Unchanged,
+    /// 拼接这个 chunk_index 范围（含两端）
+    Range(i32, i32),
+    /// 并进了排名更靠前的结果，去掉
+    Merged,
+}
+
+/// 按排名顺序给每条结果算窗口：targets 是 (文档, chunk_index)，None 表示不扩展。
+/// 同一文档里窗口重叠或相连的两条结果，排名靠后的并进靠前的，范围取并集；
+/// 并完范围变大可能又碰到别的结果，所以反复合并到没有变化为止（结果最多几十条）
+fn plan_windows(targets: &[Option<(String, i32)>], window: i32) -> Vec<WindowSpan> {
+    let mut plan: Vec<WindowSpan> = targets
+        .iter()
+        .map(|target| match target {
+            Some((_, index)) => WindowSpan::Range(index - window, index + window),
+            None => WindowSpan::Unchanged,
+        })
+        .collect();
+    let same_doc = |i: usize, j: usize| matches!((&targets[i], &targets[j]), (Some((a, _)), Some((b, _))) if a == b);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..plan.len() {
+            for j in 0..i {
+                let (WindowSpan::Range(lo_j, hi_j), WindowSpan::Range(lo_i, hi_i)) = (&plan[j], &plan[i]) else {
+                    continue;
+                };
+                if same_doc(i, j) && *lo_j <= hi_i + 1 && *lo_i <= hi_j + 1 {
+                    plan[j] = WindowSpan::Range(*lo_j.min(lo_i), *hi_j.max(hi_i));
+                    plan[i] = WindowSpan::Merged;
+                    changed = true;
+                    break;
+                }
+            }
+        }
+    }
+    plan
+}
+
 pub struct Retriever {
     vector_store: Arc<VectorStore>,
     db_path: String,
@@ -28,6 +135,12 @@ impl Retriever {
         api_key: &str,
     ) -> Result<RetrievalResult, KnowledgeBaseError> {
         let window_size = request.window_size;
+        let mut request = request;
+        if request.summary_top_n > 0 {
+            request.document_ids = self
+                .select_by_summary(&request, embedding_provider, embedding_model, embedding_base_url, api_key)
+                .await?;
+        }
 
         let mut result = match request.retrieval_mode {
             RetrievalMode::Vector => {
@@ -37,59 +150,147 @@ impl Retriever {
                 self.keyword_search(&request).await
             }
             RetrievalMode::Hybrid => {
-                self.hybrid_search(&request, embedding_provider, embedding_model, embedding_base_url, api_key).await
+                self.hybrid_search(&request, &request.query, embedding_provider, embedding_model, embedding_base_url, api_key).await
+            }
+            RetrievalMode::Hyde => {
+                let vector_query = match &request.expanded_query {
+                    Some(expanded) => expanded.clone(),
+                    None => super::hyde::expand_query(request.query_llm.as_ref(), &request.query).await,
+                };
+                self.hybrid_search(&request, &vector_query, embedding_provider, embedding_model, embedding_base_url, api_key).await
             }
         }?;
 
+        // 父子分块的知识库：命中的子块换成父块，父块本身已经是完整的上下文，不再扩展窗口
+        let mut with_parents = HashSet::new();
+        if !result.chunks.is_empty() {
+            (result.chunks, with_parents) = self.attach_parents(&request.kb_id, result.chunks).await?;
+            result.total_chunks = result.chunks.len() as i32;
+        }
+
         if window_size > 0 && !result.chunks.is_empty() {
-            result.chunks = self.expand_windows(result.chunks, window_size).await?;
+            result.chunks = self.expand_windows(&request.kb_id, result.chunks, window_size, &with_parents).await?;
+            result.total_chunks = result.chunks.len() as i32;
+        }
+
+        // 内容已经换成父块或拼好窗口，摘录和命中位置按最终返回的内容计算
+        for chunk in &mut result.chunks {
+            chunk.match_offsets = match_ranges(&request.query, &chunk.chunk.content);
+            chunk.snippet = build_snippet(&chunk.chunk.content, &chunk.match_offsets);
         }
 
         Ok(result)
     }
 
+    /// 按文档摘要向量挑出和问题最相关的文档，知识库还没有摘要时返回 None（不限定文档）
+    async fn select_by_summary(
+        &self,
+        request: &RetrievalRequest,
+        embedding_provider: &str,
+        embedding_model: &str,
+        embedding_base_url: &str,
+        api_key: &str,
+    ) -> Result<Option<Vec<String>>, KnowledgeBaseError> {
+        let query_vector = generate_single_embedding(
+            &request.query,
+            embedding_provider,
+            api_key,
+            embedding_model,
+            embedding_base_url,
+        ).await?;
+
+        let db_path = self.db_path.clone();
+        let kb_id = request.kb_id.clone();
+        let top_n = request.summary_top_n as usize;
+        let selected = tokio::task::spawn_blocking(move || {
+            let conn = crate::db::open_connection(&db_path)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            select_documents(&conn, &kb_id, &query_vector, top_n)
+        }).await.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))??;
+
+        if let Some(documents) = &selected {
+            log::info!("[KB] Summary prefilter kept {} documents for retrieval in {}", documents.len(), request.kb_id);
+        }
+        Ok(selected)
+    }
+
     /// 为每个检索到的 chunk 扩展最多 `window` 个相邻 chunk（左右各取，同一文档内，
     /// 按 chunk_index 排序）。命中 chunk 的内容会被替换为拼接后的窗口内容，
     /// 让 LLM 获得更丰富的上下文，同时不影响任何分数或排名。
+    ///
+    /// 同一文档里窗口重叠或相连的几条结果合成一条（保留排名最靠前的那条），
+    /// 拼接时去掉相邻 chunk 之间因 chunk_overlap 重复的文字。
     async fn expand_windows(
         &self,
+        kb_id: &str,
         chunks: Vec<RetrievedChunk>,
         window: i32,
+        skip: &HashSet<String>,
     ) -> Result<Vec<RetrievedChunk>, KnowledgeBaseError> {
         let db_path = self.db_path.clone();
 
-        // 在移动 `chunks` 之前先收集好各项标识
-        let targets: Vec<(String, String, i32)> = chunks
+        // 在移动 `chunks` 之前先算好每条结果要拼接的范围
+        let targets: Vec<Option<(String, i32)>> = chunks
             .iter()
-            .map(|c| (c.chunk.id.clone(), c.chunk.document_id.clone(), c.chunk.chunk_index))
+            .map(|c| {
+                (!skip.contains(&c.chunk.id)).then(|| (c.chunk.document_id.clone(), c.chunk.chunk_index))
+            })
+            .collect();
+        let plan = plan_windows(&targets, window);
+        let spans: Vec<(String, String, i32, i32)> = chunks
+            .iter()
+            .zip(&plan)
+            .filter_map(|(c, span)| match span {
+                WindowSpan::Range(lo, hi) => Some((c.chunk.id.clone(), c.chunk.document_id.clone(), *lo, *hi)),
+                _ => None,
+            })
             .collect();
 
+        let kb_id = kb_id.to_string();
         let expanded = tokio::task::spawn_blocking(move || {
-            let conn = rusqlite::Connection::open(&db_path)
+            let conn = crate::db::open_connection(&db_path)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let cipher = kb_cipher(&conn, &kb_id)?;
 
             let mut stmt = conn
                 .prepare(
-                    "SELECT content FROM chunks \
+                    "SELECT content, page, start_offset, end_offset FROM chunks \
                      WHERE document_id = ?1 AND chunk_index BETWEEN ?2 AND ?3 \
                      ORDER BY chunk_index ASC",
                 )
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-            let mut map: std::collections::HashMap<String, String> =
+            // chunk_id -> 拼接后的窗口（内容，以及从第一块开头到最后一块结尾的位置）
+            let mut map: std::collections::HashMap<String, Chunk> =
                 std::collections::HashMap::new();
 
-            for (chunk_id, doc_id, chunk_index) in &targets {
-                let contents: Vec<String> = stmt
-                    .query_map(
-                        rusqlite::params![doc_id, chunk_index - window, chunk_index + window],
-                        |row| row.get(0),
-                    )
+            for (chunk_id, doc_id, lo, hi) in &spans {
+                let rows: Vec<Chunk> = stmt
+                    .query_map(rusqlite::params![doc_id, lo, hi], |row| {
+                        Ok(Chunk {
+                            content: open_text(cipher.as_deref(), row.get(0)?),
+                            page: row.get(1)?,
+                            start_offset: row.get(2)?,
+                            end_offset: row.get(3)?,
+                            ..Default::default()
+                        })
+                    })
                     .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
                     .filter_map(|r| r.ok())
                     .collect();
+                let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+                    continue;
+                };
 
-                map.insert(chunk_id.clone(), contents.join("\n"));
+                let contents: Vec<String> = rows.iter().map(|r| r.content.clone()).collect();
+                let window = Chunk {
+                    content: join_chunks(&contents, "\n"),
+                    page: first.page,
+                    start_offset: first.start_offset,
+                    end_offset: last.end_offset,
+                    ..Default::default()
+                };
+                map.insert(chunk_id.clone(), window);
             }
 
             Ok::<_, KnowledgeBaseError>(map)
@@ -99,9 +300,14 @@ impl Retriever {
 
         let result = chunks
             .into_iter()
-            .map(|mut c| {
-                if let Some(content) = expanded.get(&c.chunk.id) {
-                    c.chunk.content = content.clone();
+            .zip(plan)
+            .filter(|(_, span)| !matches!(span, WindowSpan::Merged))
+            .map(|(mut c, _)| {
+                if let Some(window) = expanded.get(&c.chunk.id) {
+                    c.chunk.content = window.content.clone();
+                    c.chunk.page = window.page;
+                    c.chunk.start_offset = window.start_offset;
+                    c.chunk.end_offset = window.end_offset;
                 }
                 c
             })
@@ -110,6 +316,48 @@ impl Retriever {
         Ok(result)
     }
 
+    /// 父子分块：查出命中的子块所属的父块，交给 replace_with_parents 替换和去重。
+    /// 返回处理后的结果，以及换成了父块的 chunk id
+    async fn attach_parents(
+        &self,
+        kb_id: &str,
+        chunks: Vec<RetrievedChunk>,
+    ) -> Result<(Vec<RetrievedChunk>, HashSet<String>), KnowledgeBaseError> {
+        let db_path = self.db_path.clone();
+        let kb_id = kb_id.to_string();
+        let chunk_ids: Vec<String> = chunks.iter().map(|c| c.chunk.id.clone()).collect();
+
+        let parents = tokio::task::spawn_blocking(move || {
+            let conn = crate::db::open_connection(&db_path)
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let cipher = kb_cipher(&conn, &kb_id)?;
+            let placeholders = vec!["?"; chunk_ids.len()].join(",");
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT c.id, p.id, p.content FROM chunks c
+                     JOIN parent_chunks p ON p.id = c.parent_chunk_id
+                     WHERE c.id IN ({})",
+                    placeholders
+                ))
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            let parents = stmt
+                .query_map(rusqlite::params_from_iter(&chunk_ids), |row| {
+                    Ok((row.get::<_, String>(0)?, (row.get(1)?, open_text(cipher.as_deref(), row.get(2)?))))
+                })
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
+                .collect::<Result<HashMap<String, (String, String)>, _>>()
+                .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            Ok::<_, KnowledgeBaseError>(parents)
+        })
+        .await
+        .map_err(|e| KnowledgeBaseError::DatabaseError(format!("spawn_blocking: {}", e)))??;
+
+        if parents.is_empty() {
+            return Ok((chunks, HashSet::new()));
+        }
+        Ok(replace_with_parents(chunks, &parents))
+    }
+
     /// 纯向量相似度检索
     async fn vector_search(
         &self,
@@ -130,7 +378,7 @@ impl Retriever {
 
         // 在向量存储中检索
         let results = self.vector_store
-            .search(&request.kb_id, query_vector, request.top_k)
+            .search(&request.kb_id, query_vector, request.top_k, &request.tags, request.document_ids.as_deref())
             .await?;
 
         // 转换为带完整元数据的 RetrievedChunk
@@ -158,15 +406,16 @@ impl Retriever {
         let kb_id = request.kb_id.clone();
         let query = request.query.clone();
         let top_k = request.top_k;
+        let tag_filter = document_filter_param(&request.tags, request.document_ids.as_deref());
         
         // 在阻塞任务中执行 SQLite 操作
         let chunks = tokio::task::spawn_blocking(move || {
-            let conn = rusqlite::Connection::open(&db_path)
+            let conn = crate::db::open_connection(&db_path)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
             // 优先尝试 FTS5，失败则回退到 LIKE 查询
-            Self::search_with_fts_blocking(&conn, &kb_id, &query, top_k)
-                .or_else(|_| Self::search_with_like_blocking(&conn, &kb_id, &query, top_k))
+            Self::search_with_fts_blocking(&conn, &kb_id, &query, top_k, &tag_filter)
+                .or_else(|_| Self::search_with_like_blocking(&conn, &kb_id, &query, top_k, &tag_filter))
         }).await.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))??;
 
         Ok(RetrievalResult {
@@ -177,9 +426,13 @@ impl Retriever {
     }
 
     /// 混合检索：结合向量与关键词
+    ///
+    /// `vector_query` 是生成查询向量用的文本，普通混合检索就是原问题，
+    /// HyDE 模式下是原问题加假设回答；关键词检索始终用原问题。
     async fn hybrid_search(
         &self,
         request: &RetrievalRequest,
+        vector_query: &str,
         embedding_provider: &str,
         embedding_model: &str,
         embedding_base_url: &str,
@@ -192,6 +445,7 @@ impl Retriever {
         // RRF 分数（约 0.001–0.033）和余弦相似度（0–1）不是同一量纲，无法直接比较，
         // 所以我们在合并后的输出上也跳过阈值过滤。
         let mut vector_request = request.clone();
+        vector_request.query = vector_query.to_string();
         vector_request.top_k = request.top_k * 2;
         vector_request.similarity_threshold = 0.0;
 
@@ -234,31 +488,13 @@ impl Retriever {
         let kb_id = kb_id.to_string();
         
         tokio::task::spawn_blocking(move || {
-            let conn = rusqlite::Connection::open(&db_path)
+            let conn = crate::db::open_connection(&db_path)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
             
             conn.query_row(
-                "SELECT id, name, description, embedding_api_config_id,
-                 chunk_size, chunk_overlap, created_at, updated_at, document_count,
-                 COALESCE(embedding_provider, ''), COALESCE(embedding_model, ''), COALESCE(embedding_base_url, '')
-                 FROM knowledge_bases WHERE id = ?1",
+                &format!("SELECT {} FROM knowledge_bases WHERE id = ?1", KnowledgeBase::COLUMNS),
                 [&kb_id],
-                |row| {
-                    Ok(KnowledgeBase {
-                        id: row.get(0)?,
-                        name: row.get(1)?,
-                        description: row.get(2)?,
-                        embedding_api_config_id: row.get(3)?,
-                        chunk_size: row.get(4)?,
-                        chunk_overlap: row.get(5)?,
-                        created_at: row.get(6)?,
-                        updated_at: row.get(7)?,
-                        document_count: row.get(8)?,
-                        embedding_provider: row.get(9)?,
-                        embedding_model: row.get(10)?,
-                        embedding_base_url: row.get(11)?,
-                    })
-                }
+                KnowledgeBase::from_row,
             ).map_err(|e| KnowledgeBaseError::NotFound(format!("Knowledge base not found: {}", e)))
         }).await.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
     }
@@ -274,7 +510,7 @@ impl Retriever {
         let kb_id = kb_id.to_string();
 
         tokio::task::spawn_blocking(move || {
-            let conn = rusqlite::Connection::open(&db_path)
+            let conn = crate::db::open_connection(&db_path)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
             if results.is_empty() {
@@ -294,7 +530,8 @@ impl Retriever {
             let query = format!(
                 r#"
                 SELECT c.id, c.chunk_index, c.token_count,
-                       COALESCE(d.filename, 'Unknown') as filename
+                       COALESCE(d.filename, 'Unknown') as filename, c.heading_path,
+                       c.page, c.start_offset, c.end_offset
                 FROM chunks c
                 LEFT JOIN documents d ON c.document_id = d.id
                 WHERE c.id IN ({})
@@ -305,13 +542,21 @@ impl Retriever {
             let mut stmt = conn.prepare(&query)
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
-            let metadata_rows: std::collections::HashMap<String, (i32, i32, String)> = stmt
+            // chunk_id -> (文件名, 除内容以外的分块元数据)
+            let metadata_rows: std::collections::HashMap<String, (String, Chunk)> = stmt
                 .query_map(rusqlite::params_from_iter(chunk_ids), |row| {
                     let id: String = row.get(0)?;
-                    let chunk_index: i32 = row.get(1)?;
-                    let token_count: i32 = row.get(2)?;
                     let filename: String = row.get(3)?;
-                    Ok((id, (chunk_index, token_count, filename)))
+                    let metadata = Chunk {
+                        chunk_index: row.get(1)?,
+                        token_count: row.get(2)?,
+                        heading_path: row.get(4)?,
+                        page: row.get(5)?,
+                        start_offset: row.get(6)?,
+                        end_offset: row.get(7)?,
+                        ..Default::default()
+                    };
+                    Ok((id, (filename, metadata)))
                 })
                 .map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?
                 .filter_map(|r| r.ok())
@@ -320,10 +565,10 @@ impl Retriever {
             let chunks: Vec<RetrievedChunk> = results
                 .into_iter()
                 .map(|(chunk_id, doc_id, content, score)| {
-                    let (chunk_index, token_count, filename) = metadata_rows
+                    let (filename, metadata) = metadata_rows
                         .get(&chunk_id)
                         .cloned()
-                        .unwrap_or((0, 0, "Unknown".to_string()));
+                        .unwrap_or_else(|| ("Unknown".to_string(), Chunk::default()));
 
                     RetrievedChunk {
                         chunk: Chunk {
@@ -331,13 +576,14 @@ impl Retriever {
                             document_id: doc_id.clone(),
                             kb_id: kb_id.clone(),
                             content,
-                            chunk_index,
-                            token_count,
+                            ..metadata
                         },
                         score,
                         vector_score: Some(score),
                         keyword_score: None,
                         document_filename: filename,
+                        snippet: None,
+                        match_offsets: Vec::new(),
                     }
                 })
                 .collect();
@@ -353,6 +599,7 @@ impl Retriever {
         kb_id: &str,
         query: &str,
         top_k: i32,
+        tag_filter: &Option<String>,
     ) -> Result<Vec<RetrievedChunk>, KnowledgeBaseError> {
         // 检查 FTS 表是否存在
         let fts_exists: bool = conn.query_row(
@@ -365,47 +612,52 @@ impl Retriever {
             return Err(KnowledgeBaseError::RetrievalError("FTS5 not available".to_string()));
         }
 
-        // 构建 FTS 查询：转义特殊字符，并把每个词用双引号包起来
-        // FTS5 的特殊字符包括：" * ( ) : ^ [ ] { } + - AND OR NOT NEAR
-        let fts_query: String = query
-            .split_whitespace()
-            .map(|term| {
-                // 转义词内部的双引号
-                let escaped = term.replace('"', "\"\"");
-                format!("\"{}\"", escaped)
-            })
-            .collect::<Vec<_>>()
-            .join(" ");
+        // 构建 FTS 查询：中日韩文字按二元词组成短语，其余每个词加引号并转义
+        let fts_query = build_match_query(query);
+        let cipher = kb_cipher(conn, kb_id)?;
 
-        let mut stmt = conn.prepare(
+        // bm25() 越相关越小（负数），kb_id 列权重为 0，只按正文打分
+        let mut stmt = conn.prepare(&format!(
             r#"
             SELECT c.id, c.document_id, c.content, c.chunk_index, c.token_count, d.filename,
-                   rank
+                   bm25(chunks_fts, 0.0, 1.0) AS bm25_score, c.heading_path,
+                   c.page, c.start_offset, c.end_offset
             FROM chunks_fts fts
             JOIN chunks c ON fts.rowid = c.rowid
             JOIN documents d ON c.document_id = d.id
-            WHERE fts.kb_id = ?1 AND fts MATCH ?2
-            ORDER BY rank
+            WHERE fts.kb_id = ?1 AND fts MATCH ?2 AND {} AND {} AND {}
+            ORDER BY bm25_score
             LIMIT ?3
-            "#
-        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            "#,
+            document_filter_clause(4),
+            NOT_COLLAPSED,
+            NOT_TRASHED
+        )).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
         let rows = stmt.query_map(
-            rusqlite::params![kb_id, &fts_query, top_k],
+            rusqlite::params![kb_id, &fts_query, top_k, tag_filter],
             |row| {
+                let score = normalize_bm25(-row.get::<_, f64>(6)?);
                 Ok(RetrievedChunk {
                     chunk: Chunk {
                         id: row.get(0)?,
                         document_id: row.get(1)?,
                         kb_id: kb_id.to_string(),
-                        content: row.get(2)?,
+                        content: open_text(cipher.as_deref(), row.get(2)?),
                         chunk_index: row.get(3)?,
                         token_count: row.get(4)?,
+                        heading_path: row.get(7)?,
+                        page: row.get(8)?,
+                        start_offset: row.get(9)?,
+                        end_offset: row.get(10)?,
+                        duplicate_of: None,
                     },
-                    score: 1.0, // FTS 不会直接给出 0-1 范围的分数
+                    score,
                     vector_score: None,
-                    keyword_score: Some(1.0),
+                    keyword_score: Some(score),
                     document_filename: row.get(5)?,
+                    snippet: None,
+                    match_offsets: Vec::new(),
                 })
             }
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
@@ -425,6 +677,7 @@ impl Retriever {
         kb_id: &str,
         query: &str,
         top_k: i32,
+        tag_filter: &Option<String>,
     ) -> Result<Vec<RetrievedChunk>, KnowledgeBaseError> {
         // 构建带通配符的 LIKE 模式，同时转义 LIKE 的特殊字符
         let escaped_terms: Vec<String> = query
@@ -437,33 +690,45 @@ impl Retriever {
             .collect();
 
         let pattern = format!("%{}%", escaped_terms.join("%"));
+        let cipher = kb_cipher(conn, kb_id)?;
 
-        let mut stmt = conn.prepare(
+        let mut stmt = conn.prepare(&format!(
             r#"
-            SELECT c.id, c.document_id, c.content, c.chunk_index, c.token_count, d.filename
+            SELECT c.id, c.document_id, c.content, c.chunk_index, c.token_count, d.filename,
+                   c.heading_path, c.page, c.start_offset, c.end_offset
             FROM chunks c
             JOIN documents d ON c.document_id = d.id
-            WHERE c.kb_id = ?1 AND c.content LIKE ?2 ESCAPE '\'
+            WHERE c.kb_id = ?1 AND c.content LIKE ?2 ESCAPE '\' AND {} AND {} AND {}
             LIMIT ?3
-            "#
-        ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
+            "#,
+            document_filter_clause(4),
+            NOT_COLLAPSED,
+            NOT_TRASHED
+        )).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
 
         let rows = stmt.query_map(
-            rusqlite::params![kb_id, &pattern, top_k],
+            rusqlite::params![kb_id, &pattern, top_k, tag_filter],
             |row| {
                 Ok(RetrievedChunk {
                     chunk: Chunk {
                         id: row.get(0)?,
                         document_id: row.get(1)?,
                         kb_id: kb_id.to_string(),
-                        content: row.get(2)?,
+                        content: open_text(cipher.as_deref(), row.get(2)?),
                         chunk_index: row.get(3)?,
                         token_count: row.get(4)?,
+                        heading_path: row.get(6)?,
+                        page: row.get(7)?,
+                        start_offset: row.get(8)?,
+                        end_offset: row.get(9)?,
+                        duplicate_of: None,
                     },
-                    score: 0.5, // LIKE 查询无法给出有意义的分数
+                    score: 0.0, // 下面按 like_bm25 算出
                     vector_score: None,
-                    keyword_score: Some(0.5),
+                    keyword_score: None,
                     document_filename: row.get(5)?,
+                    snippet: None,
+                    match_offsets: Vec::new(),
                 })
             }
         ).map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?;
@@ -473,6 +738,16 @@ impl Retriever {
             chunks.push(row.map_err(|e| KnowledgeBaseError::DatabaseError(e.to_string()))?);
         }
 
+        let terms: Vec<String> = query.split_whitespace().map(str::to_string).collect();
+        let avg_len = chunks.iter().map(|c| c.chunk.content.chars().count() as f64).sum::<f64>()
+            / chunks.len().max(1) as f64;
+        for c in &mut chunks {
+            let score = normalize_bm25(like_bm25(&c.chunk.content, &terms, avg_len));
+            c.score = score;
+            c.keyword_score = Some(score);
+        }
+        chunks.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+
         Ok(chunks)
     }
 
@@ -528,31 +803,302 @@ impl Retriever {
     }
 }
 
+/// 上下文模板里代表检索到的参考片段的占位符
+pub const SOURCES_PLACEHOLDER: &str = "{sources}";
+/// 上下文模板里代表用户问题的占位符
+pub const QUERY_PLACEHOLDER: &str = "{query}";
+
+/// 没有自定义模板时按语言使用的默认上下文模板
+pub fn default_context_template(language: ContextLanguage) -> &'static str {
+    match language {
+        ContextLanguage::Zh => "基于以下参考文档回答问题：\n\n{sources}\n\n---\n\n问题：{query}",
+        ContextLanguage::En => "Answer the question based on the following reference documents:\n\n{sources}\n\n---\n\nQuestion: {query}",
+    }
+}
+
+/// 自定义模板必须同时包含 {sources} 和 {query}，否则检索结果或问题会被丢掉
+pub fn validate_context_template(template: &str) -> Result<(), KnowledgeBaseError> {
+    for placeholder in [SOURCES_PLACEHOLDER, QUERY_PLACEHOLDER] {
+        if !template.contains(placeholder) {
+            return Err(KnowledgeBaseError::InvalidConfig(format!("上下文模板缺少占位符 {}", placeholder)));
+        }
+    }
+    Ok(())
+}
+
+/// 被截短的片段至少要保留这么多 token，剩下的预算更少时直接丢掉这个片段
+const MIN_TRIMMED_CHUNK_TOKENS: usize = 64;
+
+/// 拼接检索上下文的参数
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContextOptions<'a> {
+    /// 自定义模板，None 时用 language 对应的默认模板
+    pub template: Option<&'a str>,
+    pub language: ContextLanguage,
+    /// 整段上下文（含模板和问题）最多占多少 token；None 时按 model 的上下文窗口估算，
+    /// 窗口也查不到时不限制
+    pub token_budget: Option<usize>,
+    /// 对话用的模型，没有给出 token_budget 时用来查上下文窗口
+    pub model: &'a str,
+}
+
+impl ContextOptions<'_> {
+    /// 没有指定预算时，检索上下文最多占模型输入预算的一半，另一半留给对话历史
+    fn budget(&self) -> usize {
+        self.token_budget
+            .or_else(|| context_window(self.model).map(|window| input_budget(window, None) / 2))
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// build_context 的结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuiltContext {
+    pub text: String,
+    /// 实际拼进上下文的片段在输入里的下标，按拼接顺序排列（和 [文档 N] 的编号一致）
+    pub included: Vec<usize>,
+    /// included 里内容被截短了的片段下标
+    pub trimmed: Vec<usize>,
+    /// 整段上下文的估算 token 数
+    pub tokens: usize,
+}
+
+/// 按模板替换占位符；只替换模板里原有的，片段内容或问题里碰巧出现的 {query} 等不会被再次替换
+fn fill_context_template(template: &str, sources: &str, query: &str) -> String {
+    let mut context = String::with_capacity(template.len() + sources.len() + query.len());
+    let mut rest = template;
+    loop {
+        let next = [(SOURCES_PLACEHOLDER, sources), (QUERY_PLACEHOLDER, query)]
+            .into_iter()
+            .filter_map(|(placeholder, value)| rest.find(placeholder).map(|pos| (pos, placeholder, value)))
+            .min_by_key(|(pos, _, _)| *pos);
+        let Some((pos, placeholder, value)) = next else {
+            context.push_str(rest);
+            break;
+        };
+        context.push_str(&rest[..pos]);
+        context.push_str(value);
+        rest = &rest[pos + placeholder.len()..];
+    }
+    context
+}
+
+/// 片段的来源标注，如 "[文档 1: a.pdf 第 3 页]"
+fn source_label(chunk: &RetrievedChunk, number: usize, language: ContextLanguage) -> String {
+    // 幻灯片的标题路径里已经有页码，只有没有标题路径时才单独标页码
+    let source = match (&chunk.chunk.heading_path, chunk.chunk.page, language) {
+        (Some(path), _, _) => format!("{} > {}", chunk.document_filename, path),
+        (None, Some(page), ContextLanguage::Zh) => format!("{} 第 {} 页", chunk.document_filename, page),
+        (None, Some(page), ContextLanguage::En) => format!("{} p. {}", chunk.document_filename, page),
+        (None, None, _) => chunk.document_filename.clone(),
+    };
+    let label = match language {
+        ContextLanguage::Zh => "文档",
+        ContextLanguage::En => "Document",
+    };
+    format!("[{} {}: {}]", label, number, source)
+}
+
 /// 用检索到的 chunk 为 LLM 构建上下文
-#[allow(dead_code)]
-pub fn build_context(chunks: &[RetrievedChunk], query: &str) -> String {
-    if chunks.is_empty() {
-        return query.to_string();
+///
+/// 来源标注（文档序号、页码）按 options.language 本地化。整段上下文超出 token 预算时
+/// 从分数最低的片段开始丢弃；放不下的片段如果还能保留一段像样的开头就截短放入。
+/// 留下的片段保持原来的顺序，按留下后的顺序编号，调用方用 included 对齐引用。
+/// 一个片段都放不下时直接返回问题本身。
+pub fn build_context(chunks: &[RetrievedChunk], query: &str, options: &ContextOptions) -> BuiltContext {
+    let template = options.template.unwrap_or_else(|| default_context_template(options.language));
+    let separator_tokens = count_tokens("\n\n");
+    let mut remaining = options.budget().saturating_sub(count_tokens(&fill_context_template(template, "", query)));
+
+    let mut by_score: Vec<usize> = (0..chunks.len()).collect();
+    by_score.sort_by(|&a, &b| chunks[b].score.partial_cmp(&chunks[a].score).unwrap_or(std::cmp::Ordering::Equal));
+    let mut kept: Vec<(usize, Option<&str>)> = Vec::new();
+    for index in by_score {
+        let chunk = &chunks[index];
+        // 编号按原来的下标估算，和最终编号最多差一两位数字
+        let header_tokens = count_tokens(&source_label(chunk, index + 1, options.language)) + 1 + separator_tokens;
+        let content_tokens = count_tokens(&chunk.chunk.content);
+        if header_tokens + content_tokens <= remaining {
+            remaining -= header_tokens + content_tokens;
+            kept.push((index, None));
+            continue;
+        }
+        // 截短的内容后面加一个省略号
+        let room = remaining.saturating_sub(header_tokens + 1);
+        if room >= MIN_TRIMMED_CHUNK_TOKENS {
+            kept.push((index, Some(truncate_to_tokens(&chunk.chunk.content, room))));
+            remaining = 0;
+        }
+    }
+
+    if kept.is_empty() {
+        return BuiltContext { text: query.to_string(), included: Vec::new(), trimmed: Vec::new(), tokens: count_tokens(query) };
+    }
+    kept.sort_by_key(|(index, _)| *index);
+
+    let sources: Vec<String> = kept
+        .iter()
+        .enumerate()
+        .map(|(number, &(index, trimmed))| {
+            let label = source_label(&chunks[index], number + 1, options.language);
+            match trimmed {
+                Some(content) => format!("{}\n{}…", label, content),
+                None => format!("{}\n{}", label, chunks[index].chunk.content),
+            }
+        })
+        .collect();
+    let text = fill_context_template(template, &sources.join("\n\n"), query);
+    BuiltContext {
+        tokens: count_tokens(&text),
+        text,
+        included: kept.iter().map(|(index, _)| *index).collect(),
+        trimmed: kept.iter().filter(|(_, trimmed)| trimmed.is_some()).map(|(index, _)| *index).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bm25_scores_are_normalized_into_unit_range() {
+        assert_eq!(normalize_bm25(0.0), 0.0);
+        assert_eq!(normalize_bm25(-1.0), 0.0);
+        assert!((normalize_bm25(BM25_HALF_SCORE) - 0.5).abs() < 1e-6);
+        assert!(normalize_bm25(1e9) <= 1.0);
+        assert!(normalize_bm25(2.0) < normalize_bm25(8.0));
+
+        // 同样长度时出现次数多的更相关，同样次数时更短的更相关
+        let terms = vec!["Rust".to_string()];
+        let once = like_bm25("rust is fast and safe", &terms, 20.0);
+        let twice = like_bm25("rust rust fast safe!", &terms, 20.0);
+        let long = like_bm25("rust is fast and safe and also quite pleasant", &terms, 20.0);
+        assert!(twice > once);
+        assert!(once > long);
+        assert_eq!(like_bm25("nothing here", &terms, 20.0), 0.0);
+    }
+
+    #[test]
+    fn overlapping_windows_merge_into_the_higher_ranked_hit() {
+        let target = |doc: &str, index: i32| Some((doc.to_string(), index));
+        // 0 和 2 的窗口相连，并进 0；1 在另一个文档；3 换成了父块不扩展；
+        // 4 本来只和 2 相连，2 并进 0 之后 0 的范围变大，4 也并进 0
+        let plan = plan_windows(&[target("a", 5), target("b", 5), target("a", 8), None, target("a", 11)], 1);
+        assert_eq!(
+            plan,
+            [
+                WindowSpan::Range(4, 12),
+                WindowSpan::Range(4, 6),
+                WindowSpan::Merged,
+                WindowSpan::Unchanged,
+                WindowSpan::Merged,
+            ]
+        );
+        assert_eq!(plan_windows(&[target("a", 1), target("a", 9)], 1), [WindowSpan::Range(0, 2), WindowSpan::Range(8, 10)]);
+    }
+
+    #[test]
+    fn stitching_drops_text_repeated_by_chunk_overlap() {
+        let parts = vec![
+            "第一句话比较短。第二句话稍微长一点点。".to_string(),
+            "第二句话稍微长一点点。第三句。".to_string(),
+            "另起一段".to_string(),
+        ];
+        assert_eq!(join_chunks(&parts, "\n"), "第一句话比较短。第二句话稍微长一点点。第三句。\n另起一段");
+        // 碰巧相同的一两个字不算重叠
+        assert_eq!(join_chunks(&["结尾。".to_string(), "。开头".to_string()], "\n"), "结尾。\n。开头");
+    }
+
+    fn hit(id: &str, score: f32) -> RetrievedChunk {
+        RetrievedChunk {
+            chunk: Chunk {
+                id: id.to_string(),
+                document_id: "d".to_string(),
+                kb_id: "kb".to_string(),
+                content: format!("子块 {}", id),
+                chunk_index: 0,
+                token_count: 1,
+                ..Default::default()
+            },
+            score,
+            vector_score: Some(score),
+            keyword_score: None,
+            document_filename: "a.md".to_string(),
+            snippet: None,
+            match_offsets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn children_of_the_same_parent_collapse_into_one_result() {
+        let parents: HashMap<String, (String, String)> = [
+            ("c1".to_string(), ("p1".to_string(), "父块一的完整内容".to_string())),
+            ("c2".to_string(), ("p1".to_string(), "父块一的完整内容".to_string())),
+            ("c3".to_string(), ("p2".to_string(), "父块二".to_string())),
+        ]
+        .into_iter()
+        .collect();
+        let chunks = vec![hit("c1", 0.9), hit("c4", 0.8), hit("c2", 0.7), hit("c3", 0.6)];
+
+        let (result, replaced) = replace_with_parents(chunks, &parents);
+        let ids: Vec<&str> = result.iter().map(|c| c.chunk.id.as_str()).collect();
+        assert_eq!(ids, ["c1", "c4", "c3"]);
+        assert_eq!(result[0].chunk.content, "父块一的完整内容");
+        assert_eq!(result[1].chunk.content, "子块 c4");
+        assert!(replaced.contains("c1") && replaced.contains("c3") && !replaced.contains("c4"));
+    }
+
+    #[test]
+    fn context_templates_fill_placeholders_once_and_localize_sources() {
+        let mut paged = hit("c2", 0.5);
+        paged.chunk.page = Some(3);
+        let chunks = vec![hit("c1", 0.9), paged];
+        let zh_options = ContextOptions { language: ContextLanguage::Zh, ..Default::default() };
+        let en_options = ContextOptions { language: ContextLanguage::En, ..Default::default() };
+
+        let zh = build_context(&chunks, "问什么", &zh_options);
+        assert_eq!(
+            zh.text,
+            "基于以下参考文档回答问题：\n\n[文档 1: a.md]\n子块 c1\n\n[文档 2: a.md 第 3 页]\n子块 c2\n\n---\n\n问题：问什么"
+        );
+        assert_eq!(zh.included, [0, 1]);
+        let en = build_context(&chunks, "what", &en_options);
+        assert!(en.text.contains("[Document 2: a.md p. 3]") && en.text.ends_with("Question: what"));
+
+        // 问题里的占位符原样保留，模板里的占位符可以重复出现
+        let custom_options = ContextOptions { template: Some("Q: {query}\n{sources}\nQ again: {query}"), ..en_options };
+        let custom = build_context(&chunks[..1], "{sources}?", &custom_options);
+        assert_eq!(custom.text, "Q: {sources}?\n[Document 1: a.md]\n子块 c1\nQ again: {sources}?");
+        assert_eq!(build_context(&[], "q", &custom_options).text, "q");
+
+        assert!(validate_context_template("{sources} {query}").is_ok());
+        assert!(validate_context_template("only {query}").is_err());
     }
-    
-    let mut context_parts = vec![
-        "基于以下参考文档回答问题：".to_string(),
-        String::new(),
-    ];
-    
-    for (i, chunk) in chunks.iter().enumerate() {
-        context_parts.push(format!(
-            "[文档 {}: {}]\n{}",
-            i + 1,
-            chunk.document_filename,
-            chunk.chunk.content
-        ));
-        context_parts.push(String::new());
+
+    #[test]
+    fn token_budget_drops_lowest_scored_chunks_and_trims_the_last_one() {
+        let mut chunks = vec![hit("c1", 0.9), hit("c2", 0.2), hit("c3", 0.5)];
+        for chunk in &mut chunks {
+            chunk.chunk.content = "字".repeat(200);
+        }
+        let options = |budget| ContextOptions { token_budget: Some(budget), ..Default::default() };
+
+        // 放得下两个完整片段，第三个（分数最低的 c2）剩下的预算不够截短保留
+        let built = build_context(&chunks, "问题", &options(450));
+        assert_eq!(built.included, [0, 2]);
+        assert!(built.trimmed.is_empty());
+        assert!(built.tokens <= 450);
+        assert!(built.text.contains("[文档 2: a.md]") && !built.text.contains("[文档 3"));
+
+        // 预算只够一个半：分数最高的完整保留，次高的截短
+        let built = build_context(&chunks, "问题", &options(330));
+        assert_eq!(built.included, [0, 2]);
+        assert_eq!(built.trimmed, [2]);
+        assert!(built.tokens <= 330 && built.text.contains("…"));
+
+        // 一个片段都放不下时只发问题
+        let built = build_context(&chunks, "问题", &options(20));
+        assert!(built.included.is_empty());
+        assert_eq!(built.text, "问题");
     }
-    
-    context_parts.push("---".to_string());
-    context_parts.push(String::new());
-    context_parts.push(format!("问题：{}", query));
-    
-    context_parts.join("\n")
 }
@@ -0,0 +1,252 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 临时文件问答模块
+ *
+ * 功能说明:
+ * - chat_with_file 把拖进聊天的文件解析、分块、做 embedding，放进只属于这个
+ *   会话的内存索引，不用先建知识库（解析/分块/embedding 都复用知识库的管线）
+ * - 之后这个会话每一轮都会从这些文件里检索，结果和知识库的检索结果合在一起
+ *   拼进上下文（见 rag::retrieve_for_chat）
+ * - discard_session_files 在会话关闭（切走/删除）时丢掉索引
+ *
+ * 索引只在内存里，不写数据库，应用重启后就没了；需要长期使用的文件请导入知识库。
+ */
+
+use super::commands::get_embedding_api_key;
+use super::db::cosine_similarity;
+use super::document::{estimate_tokens, parse_document, split_text};
+use super::embedding::{generate_embeddings, generate_single_embedding};
+use super::types::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// 分块参数的默认值，和新建知识库的默认值一致
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+const DEFAULT_CHUNK_OVERLAP: usize = 200;
+
+// 各会话的临时文件索引，以 session_id 为键
+static SESSION_FILES: Lazy<Mutex<HashMap<String, Vec<ScratchFile>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 生成文件向量时用的 embedding 配置，检索时问题要用同一个模型编码
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct EmbeddingTarget {
+    api_config_id: String,
+    provider: String,
+    model: String,
+    base_url: String,
+}
+
+/// 会话里的一个临时文件：分块后的内容和对应的向量
+struct ScratchFile {
+    file_id: String,
+    filename: String,
+    embedding: EmbeddingTarget,
+    chunks: Vec<(String, Vec<f32>)>,
+}
+
+/// 临时文件问答请求
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatWithFileRequest {
+    pub session_id: String,
+    pub file_path: String,
+    pub embedding_api_config_id: String,
+    pub embedding_provider: String,
+    pub embedding_model: String,
+    #[serde(default)]
+    pub embedding_base_url: String,
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    #[serde(default)]
+    pub chunk_overlap: Option<usize>,
+}
+
+/// 已加入会话索引的文件
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScratchFileInfo {
+    pub file_id: String,
+    pub filename: String,
+    pub chunk_count: usize,
+}
+
+/// 解析文件并加入会话的临时索引
+#[tauri::command]
+pub async fn chat_with_file(request: ChatWithFileRequest) -> Result<ScratchFileInfo, KnowledgeBaseError> {
+    let filename = std::path::Path::new(&request.file_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown")
+        .to_string();
+
+    let text = parse_document(&request.file_path).await?;
+    let chunks = split_text(
+        &text,
+        request.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+        request.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP),
+    );
+    if chunks.is_empty() {
+        return Err(KnowledgeBaseError::DocumentParseError(format!("文件中没有可用的文本: {}", filename)));
+    }
+
+    let embedding = EmbeddingTarget {
+        api_config_id: request.embedding_api_config_id,
+        provider: request.embedding_provider,
+        model: request.embedding_model,
+        base_url: request.embedding_base_url,
+    };
+    let api_key = get_embedding_api_key(&embedding.api_config_id)?;
+    let vectors = generate_embeddings(chunks.clone(), &embedding.provider, &api_key, &embedding.model, &embedding.base_url).await?;
+    if vectors.len() != chunks.len() {
+        return Err(KnowledgeBaseError::EmbeddingError(format!(
+            "Embedding count mismatch: {} chunks, {} vectors",
+            chunks.len(),
+            vectors.len()
+        )));
+    }
+
+    let info = ScratchFileInfo {
+        file_id: Uuid::new_v4().to_string(),
+        filename: filename.clone(),
+        chunk_count: chunks.len(),
+    };
+    SESSION_FILES.lock().await.entry(request.session_id.clone()).or_default().push(ScratchFile {
+        file_id: info.file_id.clone(),
+        filename,
+        embedding,
+        chunks: chunks.into_iter().zip(vectors).collect(),
+    });
+
+    log::info!("[KB] Indexed {} ({} chunks) for session {}", info.filename, info.chunk_count, request.session_id);
+    Ok(info)
+}
+
+/// 丢掉会话的临时文件索引（会话关闭时调用）
+#[tauri::command]
+pub async fn discard_session_files(session_id: String) -> Result<(), KnowledgeBaseError> {
+    if let Some(files) = SESSION_FILES.lock().await.remove(&session_id) {
+        log::info!("[KB] Discarded {} temporary files for session {}", files.len(), session_id);
+    }
+    Ok(())
+}
+
+/// 会话里有没有临时文件
+pub(crate) async fn has_session_files(session_id: &str) -> bool {
+    SESSION_FILES.lock().await.get(session_id).is_some_and(|files| !files.is_empty())
+}
+
+/// 在会话的临时文件里检索，返回相似度最高的 top_k 个片段
+pub(crate) async fn retrieve_session_files(session_id: &str, query: &str, top_k: i32) -> Vec<RetrievedChunk> {
+    // 先取出要用到的 embedding 配置再放锁，编码问题要走网络
+    let targets: Vec<EmbeddingTarget> = {
+        let index = SESSION_FILES.lock().await;
+        let mut targets: Vec<EmbeddingTarget> = Vec::new();
+        for file in index.get(session_id).into_iter().flatten() {
+            if !targets.contains(&file.embedding) {
+                targets.push(file.embedding.clone());
+            }
+        }
+        targets
+    };
+
+    let mut query_vectors = HashMap::new();
+    for target in targets {
+        let encoded = match get_embedding_api_key(&target.api_config_id) {
+            Ok(api_key) => generate_single_embedding(query, &target.provider, &api_key, &target.model, &target.base_url).await,
+            Err(e) => Err(e),
+        };
+        match encoded {
+            Ok(vector) => {
+                query_vectors.insert(target, vector);
+            }
+            Err(e) => log::warn!("[RAG] failed to embed query for temporary files ({}): {}", target.model, e),
+        }
+    }
+
+    let index = SESSION_FILES.lock().await;
+    rank_chunks(index.get(session_id).map(Vec::as_slice).unwrap_or_default(), &query_vectors, top_k)
+}
+
+/// 按问题向量给所有文件片段打分，取前 top_k 个；问题没能编码的文件跳过
+fn rank_chunks(
+    files: &[ScratchFile],
+    query_vectors: &HashMap<EmbeddingTarget, Vec<f32>>,
+    top_k: i32,
+) -> Vec<RetrievedChunk> {
+    let mut hits: Vec<RetrievedChunk> = files
+        .iter()
+        .filter_map(|file| query_vectors.get(&file.embedding).map(|q| (file, q)))
+        .flat_map(|(file, query_vector)| {
+            file.chunks.iter().enumerate().map(move |(i, (content, vector))| {
+                let score = cosine_similarity(query_vector, vector);
+                RetrievedChunk {
+                    chunk: Chunk {
+                        id: format!("{}#{}", file.file_id, i),
+                        document_id: file.file_id.clone(),
+                        kb_id: String::new(),
+                        content: content.clone(),
+                        chunk_index: i as i32,
+                        token_count: estimate_tokens(content),
+                        ..Default::default()
+                    },
+                    score,
+                    vector_score: Some(score),
+                    keyword_score: None,
+                    document_filename: file.filename.clone(),
+                    snippet: None,
+                    match_offsets: Vec::new(),
+                }
+            })
+        })
+        .collect();
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    hits.truncate(top_k.max(0) as usize);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(model: &str) -> EmbeddingTarget {
+        EmbeddingTarget {
+            api_config_id: "cfg".to_string(),
+            provider: "openai".to_string(),
+            model: model.to_string(),
+            base_url: String::new(),
+        }
+    }
+
+    #[test]
+    fn chunks_are_ranked_across_files_and_unencoded_files_are_skipped() {
+        let files = vec![
+            ScratchFile {
+                file_id: "f1".to_string(),
+                filename: "合同.pdf".to_string(),
+                embedding: target("small"),
+                chunks: vec![("付款条款".to_string(), vec![1.0, 0.0]), ("违约责任".to_string(), vec![0.0, 1.0])],
+            },
+            ScratchFile {
+                file_id: "f2".to_string(),
+                filename: "附件.docx".to_string(),
+                embedding: target("large"),
+                chunks: vec![("不会被检索".to_string(), vec![1.0, 0.0])],
+            },
+        ];
+        let query_vectors = HashMap::from([(target("small"), vec![0.6, 0.8])]);
+
+        let hits = rank_chunks(&files, &query_vectors, 5);
+        let ids: Vec<&str> = hits.iter().map(|h| h.chunk.id.as_str()).collect();
+        assert_eq!(ids, ["f1#1", "f1#0"]);
+        assert_eq!(hits[0].document_filename, "合同.pdf");
+        assert_eq!(hits[0].chunk.chunk_index, 1);
+
+        assert_eq!(rank_chunks(&files, &query_vectors, 1).len(), 1);
+    }
+}
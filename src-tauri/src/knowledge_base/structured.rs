@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 结构化数据解析模块
+ *
+ * 功能说明:
+ * - 支持 JSON、JSONL（每行一个 JSON）和 YAML 文件
+ * - 把数据拆成一条条记录：顶层数组的每个元素、顶层对象的每个键各算一条，
+ *   对象数组会再展开成每个元素一条
+ * - 每条记录展平成 "键路径: 值" 的若干行，前面加一个以记录路径为名的一级标题，
+ *   分块时按 Markdown 处理，一条记录一个块，记录路径记在 heading_path 里
+ *
+ * YAML 用 serde_yaml 解析后转成 JSON 值，和 JSON 走同一套展平逻辑：锚点和 << 合并键会展开，
+ * 标签只保留被标注的值，非字符串的键转成文本。解析失败时整个文件报错，不会只导入前半部分。
+ */
+
+use super::types::KnowledgeBaseError;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// 结构化数据的具体格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StructuredKind {
+    Json,
+    JsonLines,
+    Yaml,
+}
+
+impl StructuredKind {
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "json" => Some(StructuredKind::Json),
+            "jsonl" | "ndjson" => Some(StructuredKind::JsonLines),
+            "yaml" | "yml" => Some(StructuredKind::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// 解析结构化数据，返回按记录分好标题的 Markdown 文本
+pub fn parse_structured(raw: &str, kind: StructuredKind) -> Result<String, KnowledgeBaseError> {
+    let parse_error = |e: String| KnowledgeBaseError::DocumentParseError(format!("结构化数据解析失败: {}", e));
+
+    let records = match kind {
+        StructuredKind::Json => {
+            let value: Value = serde_json::from_str(raw).map_err(|e| parse_error(e.to_string()))?;
+            split_records(&value)
+        }
+        StructuredKind::JsonLines => {
+            let mut records = Vec::new();
+            for (i, line) in raw.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                let value: Value = serde_json::from_str(line).map_err(|e| parse_error(format!("第 {} 行: {}", i + 1, e)))?;
+                records.push((format!("第 {} 行", i + 1), value));
+            }
+            records
+        }
+        StructuredKind::Yaml => {
+            let documents = parse_yaml(raw).map_err(parse_error)?;
+            if documents.len() == 1 {
+                split_records(&documents[0])
+            } else {
+                documents
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, doc)| (format!("文档 {}", i + 1), doc))
+                    .collect()
+            }
+        }
+    };
+
+    let mut result = String::new();
+    for (path, value) in records {
+        let mut lines = Vec::new();
+        flatten(&value, "", &mut lines);
+        if lines.is_empty() {
+            continue;
+        }
+        result.push_str(&format!("# {}\n\n{}\n\n", path, lines.join("\n")));
+    }
+    Ok(result)
+}
+
+/// 把顶层数据拆成记录：数组按元素、对象按键，值是对象数组时展开成每个元素一条
+fn split_records(value: &Value) -> Vec<(String, Value)> {
+    match value {
+        Value::Array(items) => items.iter().enumerate().map(|(i, v)| (format!("[{}]", i), v.clone())).collect(),
+        Value::Object(map) => {
+            let mut records = Vec::new();
+            for (key, v) in map {
+                match v {
+                    Value::Array(items) if items.iter().any(Value::is_object) => {
+                        records.extend(items.iter().enumerate().map(|(i, item)| (format!("{}[{}]", key, i), item.clone())));
+                    }
+                    _ => records.push((key.clone(), v.clone())),
+                }
+            }
+            records
+        }
+        scalar => vec![("值".to_string(), scalar.clone())],
+    }
+}
+
+/// 展平成 "键路径: 值" 的若干行，路径相对于记录本身
+fn flatten(value: &Value, path: &str, lines: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map {
+                let child = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                flatten(v, &child, lines);
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                flatten(v, &format!("{}[{}]", path, i), lines);
+            }
+        }
+        Value::Null => {}
+        scalar => {
+            let text = match scalar {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            if path.is_empty() {
+                lines.push(text);
+            } else {
+                lines.push(format!("{}: {}", path, text));
+            }
+        }
+    }
+}
+
+// ============ YAML ============
+
+/// 按 --- 拆成多个文档，逐个解析并转成 JSON 值
+fn parse_yaml(raw: &str) -> Result<Vec<Value>, String> {
+    let mut documents = Vec::new();
+    for (i, document) in serde_yaml::Deserializer::from_str(raw).enumerate() {
+        let mut value = serde_yaml::Value::deserialize(document).map_err(|e| format!("第 {} 个文档: {}", i + 1, e))?;
+        value.apply_merge().map_err(|e| format!("第 {} 个文档: {}", i + 1, e))?;
+        documents.push(yaml_to_json(value));
+    }
+    Ok(documents)
+}
+
+/// YAML 值转 JSON 值：标签去掉只留值，键统一转成文本，NaN/无穷大这类 JSON 表示不了的数字保留原文
+fn yaml_to_json(value: serde_yaml::Value) -> Value {
+    match value {
+        serde_yaml::Value::Null => Value::Null,
+        serde_yaml::Value::Bool(b) => Value::Bool(b),
+        serde_yaml::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Value::from(i)
+            } else if let Some(u) = n.as_u64() {
+                Value::from(u)
+            } else {
+                n.as_f64()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number)
+                    .unwrap_or_else(|| Value::String(n.to_string()))
+            }
+        }
+        serde_yaml::Value::String(s) => Value::String(s),
+        serde_yaml::Value::Sequence(items) => Value::Array(items.into_iter().map(yaml_to_json).collect()),
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut map = Map::new();
+            for (key, v) in mapping {
+                map.insert(yaml_key(key), yaml_to_json(v));
+            }
+            Value::Object(map)
+        }
+        serde_yaml::Value::Tagged(tagged) => yaml_to_json(tagged.value),
+    }
+}
+
+/// 映射的键转成文本：标量直接取值，序列、映射这类复杂键用 YAML 文本表示
+fn yaml_key(key: serde_yaml::Value) -> String {
+    match key {
+        serde_yaml::Value::String(s) => s,
+        serde_yaml::Value::Null => "null".to_string(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::Tagged(tagged) => yaml_key(tagged.value),
+        other => serde_yaml::to_string(&other).map(|s| s.trim().to_string()).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_arrays_become_one_record_per_element() {
+        let raw = r#"{"version": 2, "products": [{"name": "键盘", "price": 199, "tags": ["外设", "无线"]}, {"name": "鼠标", "spec": {"dpi": 1600}}]}"#;
+        let text = parse_structured(raw, StructuredKind::Json).unwrap();
+        assert_eq!(
+            text,
+            "# products[0]\n\nname: 键盘\nprice: 199\ntags[0]: 外设\ntags[1]: 无线\n\n# products[1]\n\nname: 鼠标\nspec.dpi: 1600\n\n# version\n\n2\n\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_lines_are_records_and_bad_lines_are_reported() {
+        let text = parse_structured("{\"q\": \"退货\"}\n\n{\"q\": \"换货\"}\n", StructuredKind::JsonLines).unwrap();
+        assert_eq!(text, "# 第 1 行\n\nq: 退货\n\n# 第 3 行\n\nq: 换货\n\n");
+        assert!(parse_structured("{\"q\": 1}\n{oops", StructuredKind::JsonLines).is_err());
+    }
+
+    #[test]
+    fn yaml_documents_keep_block_scalars_flow_collections_and_anchors() {
+        let raw = "# 服务配置\nservers:\n  - name: web # 前端\n    ports: [80, 443]\n  - name: db\n    note: |\n      # 不是注释\n      每天备份\n    <<: &limits {cpu: 2}\nlimits: *limits\n1: 数字键\nenabled: true\n";
+        let docs = parse_yaml(raw).unwrap();
+        assert_eq!(
+            docs,
+            vec![serde_json::json!({
+                "servers": [
+                    {"name": "web", "ports": [80, 443]},
+                    {"name": "db", "note": "# 不是注释\n每天备份\n", "cpu": 2}
+                ],
+                "limits": {"cpu": 2},
+                "1": "数字键",
+                "enabled": true
+            })]
+        );
+        assert_eq!(parse_yaml("a: 1\n---\na: 2\n").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn malformed_yaml_is_an_error_instead_of_a_truncated_document() {
+        assert!(parse_structured("a: 1\nb: [unclosed\nc: 3\n", StructuredKind::Yaml).is_err());
+        assert!(parse_structured("a: 1\n  b: 2\nc: 3\n", StructuredKind::Yaml).is_err());
+    }
+}
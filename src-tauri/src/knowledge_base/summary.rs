@@ -0,0 +1,291 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 文档摘要索引
+ *
+ * 功能说明:
+ * - 设置了摘要模型时（set_summary_llm），文档导入完成后让模型写一段摘要，
+ *   用知识库的 embedding 模型生成摘要向量，存进 document_summaries
+ * - 检索时可以先按摘要向量挑出最相关的几篇文档（summary_top_n），
+ *   再只在这些文档里做分块检索，适合"哪份报告提到了 X"这类问题
+ * - summarize_documents 为还没有摘要的文档补生成摘要；更换 embedding 模型后
+ *   摘要文字保留、向量清空，也由它重新生成向量
+ *
+ * 摘要是导入之外的附加步骤：没设置模型或者生成失败只记日志，不影响文档本身。
+ * 记录摘要时对应的正文哈希，重新导入而正文没变时不会重复调用模型。
+ */
+
+use super::commands::{load_knowledge_base, with_conn, KbState};
+use super::db::{bytes_to_vector, vector_to_bytes, CosineScorer};
+use super::document::calculate_text_hash;
+use super::embedding::generate_embeddings;
+use super::embedding_config::kb_embedding;
+use super::encryption::{cipher_for, document_cipher, open_text, seal_text, seal_vector};
+use super::hyde::complete;
+use super::types::*;
+use once_cell::sync::Lazy;
+use rusqlite::OptionalExtension;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, State};
+
+/// 写摘要时的系统提示词
+const SUMMARY_SYSTEM_PROMPT: &str = "请用 3 到 5 句话概括这份文档：讲的是什么、涉及哪些对象（人名、机构、产品、时间），\
+有哪些关键结论或数据。只输出摘要本身，不要加标题或评价。";
+
+/// 交给模型的正文最多取这么多字符，长文档只看开头
+const SUMMARY_INPUT_CHARS: usize = 12000;
+
+/// 摘要的 token 上限
+const SUMMARY_MAX_TOKENS: u32 = 512;
+
+/// 生成摘要用的模型，None 表示导入时不生成摘要
+static SUMMARY_LLM: Lazy<Mutex<Option<QueryLlm>>> = Lazy::new(|| Mutex::new(None));
+
+fn summary_llm() -> Option<QueryLlm> {
+    SUMMARY_LLM.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// 交给模型的内容：文件名加正文开头
+fn summary_input(filename: &str, content: &str) -> String {
+    let mut text: String = content.chars().take(SUMMARY_INPUT_CHARS).collect();
+    if text.len() < content.len() {
+        text.push_str("\n……（后文省略）");
+    }
+    format!("文件名：{}\n\n{}", filename, text)
+}
+
+/// 为一篇文档生成（或补全）摘要和摘要向量，返回是否调用了模型或 embedding
+///
+/// 正文没变、摘要和向量都在时什么都不做；只缺向量时（换过 embedding 模型）只补向量。
+pub(super) async fn summarize_document(db_path: &str, kb: &KnowledgeBase, doc_id: &str) -> Result<bool, KnowledgeBaseError> {
+    let cipher = cipher_for(kb)?;
+    let id = doc_id.to_string();
+    let (filename, content, existing) = with_conn(db_path, move |conn| {
+        let (filename, content): (String, Option<String>) = conn
+            .query_row(
+                "SELECT d.filename, c.content FROM documents d
+                 LEFT JOIN document_contents c ON c.document_id = d.id WHERE d.id = ?1",
+                [&id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(db_error)?;
+        let existing: Option<(String, String, bool)> = conn
+            .query_row(
+                "SELECT summary, content_hash, vector IS NOT NULL FROM document_summaries WHERE document_id = ?1",
+                [&id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(db_error)?;
+        Ok((filename, content, existing))
+    })
+    .await?;
+    let content = content.map(|c| open_text(cipher.as_deref(), c)).unwrap_or_default();
+    if content.trim().is_empty() {
+        return Ok(false);
+    }
+
+    let content_hash = calculate_text_hash(&content);
+    let summary = match existing {
+        Some((_, hash, true)) if hash == content_hash => return Ok(false),
+        Some((summary, hash, false)) if hash == content_hash => open_text(cipher.as_deref(), summary),
+        _ => {
+            let Some(llm) = summary_llm() else {
+                return Ok(false);
+            };
+            let reply = complete(&llm, SUMMARY_SYSTEM_PROMPT, &summary_input(&filename, &content), SUMMARY_MAX_TOKENS)
+                .await
+                .map_err(|e| KnowledgeBaseError::InvalidConfig(format!("生成摘要失败: {}", e)))?;
+            let reply = reply.trim().to_string();
+            if reply.is_empty() {
+                return Err(KnowledgeBaseError::InvalidConfig("模型返回了空摘要".to_string()));
+            }
+            reply
+        }
+    };
+
+    let target = {
+        let kb = kb.clone();
+        with_conn(db_path, move |conn| kb_embedding(&conn, &kb)).await?
+    };
+    let vector = generate_embeddings(vec![summary.clone()], &target.provider, &target.api_key, &target.model, &target.base_url)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| KnowledgeBaseError::EmbeddingError("Embedding count mismatch: 1 summary, 0 vectors".to_string()))?;
+
+    let (id, kb_id) = (doc_id.to_string(), kb.id.clone());
+    with_conn(db_path, move |conn| {
+        conn.execute(
+            "INSERT OR REPLACE INTO document_summaries (document_id, kb_id, summary, vector, content_hash, created_at)
+             SELECT ?1, ?2, ?3, ?4, ?5, ?6 WHERE EXISTS (SELECT 1 FROM documents WHERE id = ?1)",
+            rusqlite::params![
+                &id,
+                &kb_id,
+                seal_text(cipher.as_deref(), &summary),
+                seal_vector(cipher.as_deref(), vector_to_bytes(&vector)),
+                content_hash,
+                chrono::Utc::now().timestamp_millis()
+            ],
+        )
+        .map_err(db_error)
+    })
+    .await?;
+    Ok(true)
+}
+
+/// 导入完成后在后台生成摘要，失败只记日志
+pub(super) fn spawn_summary(app_handle: &AppHandle, kb: &KnowledgeBase, doc_id: &str) {
+    if summary_llm().is_none() {
+        return;
+    }
+    let db_path = app_handle.state::<KbState>().db_path.clone();
+    let (kb, doc_id) = (kb.clone(), doc_id.to_string());
+    tauri::async_runtime::spawn(async move {
+        match summarize_document(&db_path, &kb, &doc_id).await {
+            Ok(true) => log::info!("[KB] Summarized document {}", doc_id),
+            Ok(false) => {}
+            Err(e) => log::warn!("[KB] Summarizing document {} failed: {}", doc_id, e),
+        }
+    });
+}
+
+/// 按摘要向量挑出和问题最相关的 top_n 篇文档，知识库里还没有任何摘要向量时返回 None（不预筛选）
+///
+/// 没有摘要的文档（摘要生成失败、或者开启摘要之前导入的）总是保留在结果里，不会因此检索不到。
+pub(crate) fn select_documents(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    query_vector: &[f32],
+    top_n: usize,
+) -> Result<Option<Vec<String>>, KnowledgeBaseError> {
+    let rows: Vec<(String, Vec<u8>)> = conn
+        .prepare(
+            "SELECT s.document_id, s.vector FROM document_summaries s JOIN documents d ON d.id = s.document_id
+             WHERE s.kb_id = ?1 AND s.vector IS NOT NULL AND d.deleted_at IS NULL",
+        )
+        .and_then(|mut stmt| stmt.query_map([kb_id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect())
+        .map_err(db_error)?;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+
+    let summarized: HashSet<String> = rows.iter().map(|(doc_id, _)| doc_id.clone()).collect();
+    let scorer = CosineScorer::new(query_vector);
+    let mut scored: Vec<(f32, String)> = rows
+        .into_iter()
+        .map(|(doc_id, bytes)| (scorer.score(&bytes_to_vector(&bytes)), doc_id))
+        .collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    let mut selected: Vec<String> = scored.into_iter().take(top_n).map(|(_, doc_id)| doc_id).collect();
+
+    let unsummarized: Vec<String> = conn
+        .prepare("SELECT id FROM documents WHERE kb_id = ?1 AND status = 'completed' AND deleted_at IS NULL")
+        .and_then(|mut stmt| stmt.query_map([kb_id], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>())
+        .map_err(db_error)?
+        .into_iter()
+        .filter(|id| !summarized.contains(id))
+        .collect();
+    selected.extend(unsummarized);
+    Ok(Some(selected))
+}
+
+/// 设置导入后生成文档摘要用的模型，传 None 关闭
+#[tauri::command]
+pub fn set_summary_llm(llm: Option<QueryLlm>) {
+    log::info!(
+        "[KB] Document summaries {}",
+        llm.as_ref().map_or("disabled".to_string(), |l| format!("use {}/{}", l.provider, l.model))
+    );
+    *SUMMARY_LLM.lock().unwrap_or_else(|e| e.into_inner()) = llm;
+}
+
+/// 读取文档的摘要，还没有摘要时返回 None
+#[tauri::command]
+pub async fn get_document_summary(
+    document_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<Option<String>, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        let summary: Option<String> = conn
+            .query_row("SELECT summary FROM document_summaries WHERE document_id = ?1", [&document_id], |row| row.get(0))
+            .optional()
+            .map_err(db_error)?;
+        let cipher = document_cipher(&conn, &document_id)?;
+        Ok(summary.map(|s| open_text(cipher.as_deref(), s)))
+    })
+    .await
+}
+
+/// 为知识库里还没有摘要（或摘要向量）的已完成文档补生成，返回处理的文档数
+#[tauri::command]
+pub async fn summarize_documents(kb_id: String, kb_state: State<'_, KbState>) -> Result<usize, KnowledgeBaseError> {
+    let db_path = kb_state.db_path.clone();
+    let id = kb_id.clone();
+    let (kb, doc_ids) = with_conn(&db_path, move |conn| {
+        let kb = load_knowledge_base(&conn, &id)?;
+        let doc_ids: Vec<String> = conn
+            .prepare(
+                "SELECT d.id FROM documents d
+                 LEFT JOIN document_summaries s ON s.document_id = d.id
+                 WHERE d.kb_id = ?1 AND d.status = 'completed' AND d.deleted_at IS NULL AND s.vector IS NULL
+                 ORDER BY d.created_at",
+            )
+            .and_then(|mut stmt| stmt.query_map([&id], |row| row.get(0))?.collect())
+            .map_err(db_error)?;
+        Ok((kb, doc_ids))
+    })
+    .await?;
+
+    let mut count = 0;
+    for doc_id in &doc_ids {
+        match summarize_document(&db_path, &kb, doc_id).await {
+            Ok(true) => count += 1,
+            Ok(false) => {}
+            Err(e) => log::warn!("[KB] Summarizing document {} failed: {}", doc_id, e),
+        }
+    }
+    log::info!("[KB] Summarized {} of {} documents in {}", count, doc_ids.len(), kb_id);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_are_selected_by_summary_and_unsummarized_ones_kept() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at)
+             VALUES ('kb', 'kb', 'cfg', 'openai', 'm', 0, 0)",
+            [],
+        )
+        .unwrap();
+        for id in ["finance", "travel", "legacy"] {
+            conn.execute(
+                "INSERT INTO documents (id, kb_id, filename, file_type, file_size, status, created_at)
+                 VALUES (?1, 'kb', ?1, 'pdf', 10, 'completed', 0)",
+                [id],
+            )
+            .unwrap();
+        }
+        for (id, vector) in [("finance", [1.0f32, 0.0]), ("travel", [0.0, 1.0])] {
+            conn.execute(
+                "INSERT INTO document_summaries (document_id, kb_id, summary, vector, content_hash, created_at)
+                 VALUES (?1, 'kb', 's', ?2, 'h', 0)",
+                rusqlite::params![id, vector_to_bytes(&vector)],
+            )
+            .unwrap();
+        }
+
+        let selected = select_documents(&conn, "kb", &[0.9, 0.1], 1).unwrap();
+        assert_eq!(selected, Some(vec!["finance".to_string(), "legacy".to_string()]));
+        assert_eq!(select_documents(&conn, "other", &[0.9, 0.1], 1).unwrap(), None);
+        assert!(summary_input("a.txt", &"字".repeat(SUMMARY_INPUT_CHARS + 1)).ends_with("（后文省略）"));
+    }
+}
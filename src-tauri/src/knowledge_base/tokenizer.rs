@@ -0,0 +1,265 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * Token 计数模块
+ *
+ * 功能说明:
+ * - 知识库的 Embedding 模型有公开的 BPE 词表时（OpenAI 的 text-embedding-3-*、
+ *   ada-002 等），用 tiktoken 的词表精确分词，分块大小和模型实际看到的 token 数一致
+ * - 词表未知的模型（bge、各家中文 Embedding 等）回落到估算：按 BPE 预分词规则把文本
+ *   切成片段（英文单词连同前导空格、最多 3 位一组的数字、单个标点、单个中日韩字符、
+ *   换行），中日韩字符每字 1 个 token，英文约每 4 个字母 1 个 token
+ * - 两种方式切出的片段都首尾相接、覆盖整段文本，分块时可以直接按片段边界硬切或取末尾重叠
+ */
+
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Encoding};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, p50k_edit_singleton,
+    r50k_base_singleton, CoreBPE,
+};
+
+/// 按知识库的 Embedding 模型选定的分词方式
+#[derive(Clone, Copy)]
+pub enum Tokenizer {
+    /// 模型的 BPE 词表，计数精确
+    Bpe(&'static CoreBPE),
+    /// 词表未知，按预分词片段估算
+    Estimate,
+}
+
+impl std::fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bpe(_) => f.write_str("Bpe"),
+            Self::Estimate => f.write_str("Estimate"),
+        }
+    }
+}
+
+impl Tokenizer {
+    /// 按模型名选词表，兼容 "openai/text-embedding-3-small" 这类带服务商前缀的写法
+    pub fn for_model(model: &str) -> Self {
+        let name = model.rsplit('/').next().unwrap_or(model).trim();
+        let bpe = match get_tokenizer(name) {
+            Some(Encoding::O200kBase) => o200k_base_singleton(),
+            Some(Encoding::Cl100kBase) => cl100k_base_singleton(),
+            Some(Encoding::P50kBase) => p50k_base_singleton(),
+            Some(Encoding::P50kEdit) => p50k_edit_singleton(),
+            Some(Encoding::R50kBase | Encoding::Gpt2) => r50k_base_singleton(),
+            None => return Self::Estimate,
+        };
+        Self::Bpe(bpe)
+    }
+
+    /// 文本的 token 数
+    pub fn count(&self, text: &str) -> usize {
+        match self {
+            Self::Bpe(bpe) => bpe.encode_ordinary(text).len(),
+            Self::Estimate => count_tokens(text),
+        }
+    }
+
+    /// 把文本切成首尾相接的片段，返回 (片段, token 数)。
+    ///
+    /// BPE 的单个 token 可能只是一个多字节字符的一部分（生僻汉字、emoji），
+    /// 这种 token 会和后面的 token 攒到字符边界再一起输出。
+    pub fn pieces<'a>(&self, text: &'a str) -> Vec<(&'a str, usize)> {
+        let Self::Bpe(bpe) = self else {
+            return pieces(text);
+        };
+        let mut result = Vec::new();
+        let (mut start, mut end, mut tokens) = (0, 0, 0);
+        for bytes in bpe._decode_native_and_split(bpe.encode_ordinary(text)) {
+            end += bytes.len();
+            tokens += 1;
+            if text.is_char_boundary(end) {
+                result.push((&text[start..end], tokens));
+                start = end;
+                tokens = 0;
+            }
+        }
+        result
+    }
+}
+
+/// 中日韩文字：汉字、假名、谚文及 CJK 标点
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3000..=0x303F     // CJK 标点
+        | 0x3040..=0x30FF   // 平假名、片假名
+        | 0x3400..=0x4DBF   // 扩展 A
+        | 0x4E00..=0x9FFF   // 基本汉字
+        | 0xAC00..=0xD7AF   // 谚文音节
+        | 0xF900..=0xFAFF   // 兼容汉字
+        | 0xFF00..=0xFFEF   // 全角标点
+        | 0x20000..=0x2FA1F // 扩展 B 及之后
+    )
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+    Word,
+    Digit,
+    Cjk,
+    Space,
+    Newline,
+    Symbol,
+}
+
+fn classify(c: char) -> Class {
+    if is_cjk(c) {
+        Class::Cjk
+    } else if c == '\n' || c == '\r' {
+        Class::Newline
+    } else if c.is_whitespace() {
+        Class::Space
+    } else if c.is_ascii_digit() {
+        Class::Digit
+    } else if c.is_alphabetic() || c == '\'' {
+        Class::Word
+    } else {
+        Class::Symbol
+    }
+}
+
+/// 单个片段的 token 数估算
+fn piece_tokens(piece: &str, class: Class) -> usize {
+    match class {
+        Class::Word => {
+            let letters = piece.trim_start().chars().count();
+            if piece.trim_start().is_ascii() {
+                letters.div_ceil(4).max(1)
+            } else {
+                // 西里尔、带重音的拉丁字母等，词表覆盖得少，按每 2 个字母 1 个 token
+                letters.div_ceil(2).max(1)
+            }
+        }
+        Class::Digit | Class::Cjk | Class::Newline | Class::Symbol => 1,
+        // 单独的空格通常并进下一个单词，只有成串的缩进才单独算 token
+        Class::Space => usize::from(piece.chars().count() > 1),
+    }
+}
+
+/// 把文本切成首尾相接的预分词片段，返回 (片段, 估算的 token 数)
+pub fn pieces(text: &str) -> Vec<(&str, usize)> {
+    let mut result = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let mut class = classify(c);
+        let mut end = start + c.len_utf8();
+
+        // 单个空格后面紧跟单词时，和 BPE 一样把空格并进单词
+        if c == ' ' {
+            if let Some(&(_, next)) = chars.peek() {
+                if classify(next) == Class::Word {
+                    class = Class::Word;
+                }
+            }
+        }
+
+        match class {
+            Class::Word | Class::Space | Class::Newline => {
+                while let Some(&(i, next)) = chars.peek() {
+                    if classify(next) != class {
+                        break;
+                    }
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+            }
+            Class::Digit => {
+                // 数字最多 3 位一组
+                for _ in 0..2 {
+                    match chars.peek() {
+                        Some(&(i, next)) if next.is_ascii_digit() => {
+                            end = i + next.len_utf8();
+                            chars.next();
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            Class::Cjk | Class::Symbol => {}
+        }
+
+        let piece = &text[start..end];
+        result.push((piece, piece_tokens(piece, class)));
+    }
+
+    result
+}
+
+/// 估算文本的 token 数（不依赖具体模型，用于检索上下文预算这类只需要大致长度的场合）
+pub fn count_tokens(text: &str) -> usize {
+    pieces(text).iter().map(|(_, tokens)| tokens).sum()
+}
+
+/// 截取文本开头估算不超过 max_tokens 个 token 的部分，在片段边界处截断
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> &str {
+    let mut total = 0;
+    let mut end = 0;
+    for (piece, tokens) in pieces(text) {
+        if total + tokens > max_tokens {
+            break;
+        }
+        total += tokens;
+        end += piece.len();
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pieces_cover_the_whole_text() {
+        let text = "Hello world, 你好世界！ 2024年\n\n    indented";
+        let joined: String = pieces(text).iter().map(|(p, _)| *p).collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn counts_follow_bpe_granularity() {
+        assert_eq!(count_tokens("你好世界"), 4);
+        assert_eq!(count_tokens("the cat sat"), 3);
+        assert_eq!(count_tokens("12345"), 2);
+        assert_eq!(count_tokens("internationalization"), 5);
+        assert_eq!(count_tokens(""), 0);
+    }
+
+    #[test]
+    fn truncation_stops_at_piece_boundaries() {
+        assert_eq!(truncate_to_tokens("你好世界", 2), "你好");
+        assert_eq!(truncate_to_tokens("the cat sat", 2), "the cat");
+        assert_eq!(truncate_to_tokens("the cat sat", 10), "the cat sat");
+        assert_eq!(truncate_to_tokens("internationalization", 3), "");
+    }
+
+    #[test]
+    fn openai_models_count_with_their_bpe_vocabulary() {
+        let tokenizer = Tokenizer::for_model("text-embedding-3-small");
+        assert!(matches!(tokenizer, Tokenizer::Bpe(_)));
+        assert!(matches!(Tokenizer::for_model("openai/text-embedding-3-large"), Tokenizer::Bpe(_)));
+        // cl100k 里 "世" 占 2 个 token，估算按每字 1 个
+        assert_eq!(tokenizer.count("你好世界"), 5);
+        assert_eq!(tokenizer.count("internationalization"), 2);
+
+        // 拆开多字节字符的 token 攒到字符边界才输出，片段仍然覆盖整段文本
+        let text = "Hello world, 你好世界！ 2024年\n\n    indented 🦀";
+        let pieces = tokenizer.pieces(text);
+        assert_eq!(pieces.iter().map(|(p, _)| *p).collect::<String>(), text);
+        assert_eq!(pieces.iter().map(|(_, n)| n).sum::<usize>(), tokenizer.count(text));
+        assert!(pieces.contains(&("世", 2)));
+    }
+
+    #[test]
+    fn unknown_models_fall_back_to_the_estimate() {
+        let tokenizer = Tokenizer::for_model("bge-m3");
+        assert!(matches!(tokenizer, Tokenizer::Estimate));
+        assert_eq!(tokenizer.count("你好世界"), count_tokens("你好世界"));
+    }
+}
@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 音视频转写模块
+ *
+ * 功能说明:
+ * - 调用外部 whisper（pip install openai-whisper）把音频/视频转写成带时间戳的片段
+ * - 把片段按 TRANSCRIPT_WINDOW_SECS 归成一段段，每段一个以时间范围为名的一级标题，
+ *   分块时按 Markdown 处理，时间范围记在块的 heading_path 里，引用时能定位到原音视频的位置
+ *
+ * 和 pdftotext、tesseract 一样走外部程序，不把语音模型编进应用；whisper 依赖 ffmpeg 解码，
+ * 两者都需要在 PATH 中。
+ */
+
+use super::types::KnowledgeBaseError;
+use crate::commands::local_model::hide_console_window;
+use serde::Deserialize;
+use std::path::Path;
+use uuid::Uuid;
+
+/// 每段转写覆盖的时长（秒）：太短块太碎，太长时间戳就失去定位意义
+const TRANSCRIPT_WINDOW_SECS: f64 = 120.0;
+
+/// whisper 输出的一个片段
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TranscriptSegment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// whisper --output_format json 的输出
+#[derive(Debug, Deserialize)]
+struct WhisperOutput {
+    #[serde(default)]
+    segments: Vec<TranscriptSegment>,
+}
+
+/// 转写音频/视频文件
+pub async fn transcribe(file_path: &str) -> Result<Vec<TranscriptSegment>, KnowledgeBaseError> {
+    let work_dir = std::env::temp_dir().join(format!("baiyu-transcribe-{}", Uuid::new_v4()));
+    tokio::fs::create_dir_all(&work_dir)
+        .await
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
+    let _cleanup = scopeguard::guard(work_dir.clone(), |dir| {
+        let _ = std::fs::remove_dir_all(dir);
+    });
+
+    let mut cmd = tokio::process::Command::new("whisper");
+    cmd.arg(file_path)
+        .args(["--output_format", "json", "--verbose", "False", "--output_dir"])
+        .arg(&work_dir);
+    hide_console_window(&mut cmd);
+
+    let output = cmd.output().await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            KnowledgeBaseError::DocumentParseError(
+                "未找到 whisper，请先执行 pip install openai-whisper 并确认它和 ffmpeg 都在 PATH 中".to_string(),
+            )
+        } else {
+            KnowledgeBaseError::DocumentParseError(format!("whisper 启动失败: {}", e))
+        }
+    })?;
+    if !output.status.success() {
+        return Err(KnowledgeBaseError::DocumentParseError(format!(
+            "转写失败: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // 输出文件名跟输入文件同名（扩展名换成 .json），直接找目录里的 json 文件
+    let json_path = std::fs::read_dir(&work_dir)
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .find(|p| p.extension().and_then(|e| e.to_str()) == Some("json"))
+        .ok_or_else(|| KnowledgeBaseError::DocumentParseError("whisper 没有输出转写结果".to_string()))?;
+    let raw = tokio::fs::read_to_string(&json_path)
+        .await
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(e.to_string()))?;
+    parse_whisper_output(&raw)
+}
+
+fn parse_whisper_output(raw: &str) -> Result<Vec<TranscriptSegment>, KnowledgeBaseError> {
+    let output: WhisperOutput = serde_json::from_str(raw)
+        .map_err(|e| KnowledgeBaseError::DocumentParseError(format!("无法解析 whisper 输出: {}", e)))?;
+    Ok(output.segments)
+}
+
+/// 秒数格式化成 HH:MM:SS
+fn format_timestamp(seconds: f64) -> String {
+    let total = seconds.max(0.0) as u64;
+    format!("{:02}:{:02}:{:02}", total / 3600, total / 60 % 60, total % 60)
+}
+
+/// 把片段按时间窗口归段，整理成 Markdown：每段标题是时间范围，正文每个片段一行
+pub fn format_transcript(segments: &[TranscriptSegment]) -> String {
+    let mut result = String::new();
+    let mut window: Vec<&TranscriptSegment> = Vec::new();
+
+    let mut flush = |window: &mut Vec<&TranscriptSegment>| {
+        let (Some(first), Some(last)) = (window.first(), window.last()) else { return };
+        result.push_str(&format!("# {} - {}\n\n", format_timestamp(first.start), format_timestamp(last.end)));
+        for segment in window.iter() {
+            result.push_str(segment.text.trim());
+            result.push('\n');
+        }
+        result.push('\n');
+        window.clear();
+    };
+
+    for segment in segments.iter().filter(|s| !s.text.trim().is_empty()) {
+        if window.first().is_some_and(|first| segment.end - first.start > TRANSCRIPT_WINDOW_SECS) {
+            flush(&mut window);
+        }
+        window.push(segment);
+    }
+    flush(&mut window);
+
+    result
+}
+
+/// 转写并整理成 Markdown
+pub async fn parse_media(path: &Path) -> Result<String, KnowledgeBaseError> {
+    let segments = transcribe(&path.to_string_lossy()).await?;
+    let text = format_transcript(&segments);
+    if text.trim().is_empty() {
+        return Err(KnowledgeBaseError::DocumentParseError("没有转写出任何文字".to_string()));
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_are_grouped_into_timestamped_windows() {
+        let raw = r#"{"text": "...", "language": "zh", "segments": [
+            {"id": 0, "start": 0.0, "end": 4.5, "text": " 大家好。"},
+            {"id": 1, "start": 4.5, "end": 118.0, "text": " 今天讲检索。"},
+            {"id": 2, "start": 118.0, "end": 125.2, "text": " "},
+            {"id": 3, "start": 3600.0, "end": 3725.0, "text": " 总结一下。"}
+        ]}"#;
+        let segments = parse_whisper_output(raw).unwrap();
+        assert_eq!(segments.len(), 4);
+
+        assert_eq!(
+            format_transcript(&segments),
+            "# 00:00:00 - 00:01:58\n\n大家好。\n今天讲检索。\n\n# 01:00:00 - 01:02:05\n\n总结一下。\n\n"
+        );
+    }
+}
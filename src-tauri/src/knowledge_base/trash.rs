@@ -0,0 +1,269 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 知识库文档回收站
+ *
+ * 功能说明:
+ * - delete_document 只给文档（连同按“关联”导入、指向它的重复文档）打上 deleted_at
+ *   删除标记，分块、向量和全文索引原样保留，检索时跳过（见 NOT_TRASHED）
+ * - 回收站里的文档可以恢复，恢复后不用重新解析和 embedding
+ * - 彻底删除时才真正删除向量、分块、全文索引、正文和摘要；超过
+ *   回收站保留期限（默认 crate::db::TRASH_RETENTION_DAYS 天，可以在清理策略里修改）的文档
+ *   在启动时和后台维护任务里自动彻底删除
+ *
+ * ANN 索引里还留着回收站文档的向量，检索时候选向量回表打分会按 NOT_TRASHED 过滤掉；
+ * 向量缓存按参与检索的分块载入，移入和恢复时都要丢掉重新载入。
+ */
+
+use super::ann::prune_vector_log;
+use super::commands::{delete_document_rows, document_from_row, with_conn, KbState, DOCUMENT_COLUMNS};
+use super::encryption::kb_cipher;
+use super::types::*;
+use rusqlite::OptionalExtension;
+use tauri::State;
+
+/// 检索时排除回收站里文档的分块的 SQL 条件，分块表的别名需要是 c
+pub(crate) const NOT_TRASHED: &str =
+    "NOT EXISTS (SELECT 1 FROM documents trashed WHERE trashed.id = c.document_id AND trashed.deleted_at IS NOT NULL)";
+
+/// 把文档和指向它的重复文档移进回收站，返回移入的文档数
+pub(super) fn trash_document_rows(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    doc_id: &str,
+) -> Result<usize, rusqlite::Error> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let tx = conn.unchecked_transaction()?;
+    let trashed = tx.execute(
+        "UPDATE documents SET deleted_at = ?1
+         WHERE kb_id = ?2 AND (id = ?3 OR duplicate_of = ?3) AND deleted_at IS NULL",
+        rusqlite::params![now, kb_id, doc_id],
+    )?;
+    // 安全地更新知识库的文档计数（保证永远不会小于 0）
+    tx.execute(
+        "UPDATE knowledge_bases SET document_count = MAX(document_count - ?1, 0), updated_at = ?2 WHERE id = ?3",
+        rusqlite::params![trashed as i64, now, kb_id],
+    )?;
+    tx.commit()?;
+    Ok(trashed)
+}
+
+/// 从回收站恢复文档，和它一起移进回收站的重复文档一起恢复；返回恢复的文档数
+pub(super) fn restore_document_rows(
+    conn: &rusqlite::Connection,
+    kb_id: &str,
+    doc_id: &str,
+) -> Result<usize, rusqlite::Error> {
+    let tx = conn.unchecked_transaction()?;
+    let deleted_at: Option<i64> = tx
+        .query_row(
+            "SELECT deleted_at FROM documents WHERE id = ?1 AND kb_id = ?2",
+            rusqlite::params![doc_id, kb_id],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    let Some(deleted_at) = deleted_at else {
+        return Ok(0);
+    };
+    let restored = tx.execute(
+        "UPDATE documents SET deleted_at = NULL
+         WHERE kb_id = ?1 AND (id = ?2 OR (duplicate_of = ?2 AND deleted_at = ?3))",
+        rusqlite::params![kb_id, doc_id, deleted_at],
+    )?;
+    tx.execute(
+        "UPDATE knowledge_bases SET document_count = document_count + ?1, updated_at = ?2 WHERE id = ?3",
+        rusqlite::params![restored as i64, chrono::Utc::now().timestamp_millis(), kb_id],
+    )?;
+    tx.commit()?;
+    Ok(restored)
+}
+
+/// 彻底删除文档的全部数据，指向它的重复文档没有自己的内容，一起删除
+fn purge_document_data(conn: &rusqlite::Connection, kb_id: &str, doc_id: &str) -> Result<(), KnowledgeBaseError> {
+    // 向量删除由触发器记进 vector_log，ANN 索引下次检索时增量同步；知识库小到不用索引时清掉日志
+    conn.execute("DELETE FROM vectors WHERE document_id = ?1", [doc_id])
+        .map_err(db_error)?;
+    prune_vector_log(conn, kb_id).map_err(db_error)?;
+    delete_document_rows(conn, doc_id)?;
+
+    // 还没进回收站的重复文档（单独恢复过的）要从文档计数里减掉
+    let live_linked = conn
+        .execute("DELETE FROM documents WHERE duplicate_of = ?1 AND deleted_at IS NULL", [doc_id])
+        .map_err(db_error)?;
+    conn.execute("DELETE FROM documents WHERE duplicate_of = ?1", [doc_id])
+        .map_err(db_error)?;
+    if live_linked > 0 {
+        conn.execute(
+            "UPDATE knowledge_bases SET document_count = MAX(document_count - ?1, 0) WHERE id = ?2",
+            rusqlite::params![live_linked as i64, kb_id],
+        )
+        .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+/// 回收站里删除时间早于 cutoff (毫秒) 的文档数（清理策略的预演用）
+pub fn count_expired_documents(conn: &rusqlite::Connection, cutoff: i64) -> Result<usize, KnowledgeBaseError> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM documents WHERE deleted_at < ?1",
+        [cutoff],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count as usize)
+    .map_err(db_error)
+}
+
+/// 彻底删除回收站里超过保留期限的文档，返回删除的文档数
+pub fn purge_expired_documents(conn: &rusqlite::Connection) -> Result<usize, KnowledgeBaseError> {
+    let expired: Vec<(String, String)> = conn
+        .prepare("SELECT kb_id, id FROM documents WHERE deleted_at < ?1 AND duplicate_of IS NULL")
+        .and_then(|mut stmt| {
+            stmt.query_map([crate::db::trash_expiry_cutoff()], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .map_err(db_error)?;
+    for (kb_id, doc_id) in &expired {
+        purge_document_data(conn, kb_id, doc_id)?;
+    }
+    // 单独进回收站的重复文档没有分块，直接删记录
+    let linked = conn
+        .execute(
+            "DELETE FROM documents WHERE deleted_at < ?1 AND duplicate_of IS NOT NULL",
+            [crate::db::trash_expiry_cutoff()],
+        )
+        .map_err(db_error)?;
+    Ok(expired.len() + linked)
+}
+
+/// 列出知识库回收站里的文档（按删除时间倒序）
+#[tauri::command]
+pub async fn list_trashed_documents(
+    kb_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<Vec<Document>, KnowledgeBaseError> {
+    with_conn(&kb_state.db_path, move |conn| {
+        let cipher = kb_cipher(&conn, &kb_id)?;
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM documents WHERE kb_id = ?1 AND deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+                DOCUMENT_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let docs = stmt
+            .query_map([&kb_id], |row| document_from_row(row, cipher.as_deref()))
+            .map_err(db_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_error)?;
+        Ok(docs)
+    })
+    .await
+}
+
+/// 从回收站恢复文档
+#[tauri::command]
+pub async fn restore_document(
+    doc_id: String,
+    kb_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<(), KnowledgeBaseError> {
+    let (document_id, id) = (doc_id.clone(), kb_id.clone());
+    with_conn(&kb_state.db_path, move |conn| {
+        if restore_document_rows(&conn, &id, &document_id).map_err(db_error)? == 0 {
+            return Err(KnowledgeBaseError::NotFound(format!(
+                "Document not in trash: {} in knowledge base: {}",
+                document_id, id
+            )));
+        }
+        Ok(())
+    })
+    .await?;
+    kb_state.vector_store.drop_vector_cache(&kb_id);
+    log::info!("Restored document: {}", doc_id);
+    Ok(())
+}
+
+/// 彻底删除回收站里的文档，无法撤销
+#[tauri::command]
+pub async fn purge_document(
+    doc_id: String,
+    kb_id: String,
+    kb_state: State<'_, KbState>,
+) -> Result<(), KnowledgeBaseError> {
+    let (document_id, id) = (doc_id.clone(), kb_id.clone());
+    with_conn(&kb_state.db_path, move |conn| {
+        let kb_id = &id;
+        let in_trash: bool = conn
+            .query_row(
+                "SELECT COUNT(*) FROM documents WHERE id = ?1 AND kb_id = ?2 AND deleted_at IS NOT NULL",
+                rusqlite::params![&document_id, kb_id],
+                |row| row.get(0),
+            )
+            .map_err(db_error)?;
+        if !in_trash {
+            return Err(KnowledgeBaseError::NotFound(format!(
+                "Document not in trash: {} in knowledge base: {}",
+                document_id, kb_id
+            )));
+        }
+
+        purge_document_data(&conn, kb_id, &document_id)
+    })
+    .await?;
+    kb_state.vector_store.checkpoint_ann(&kb_id).await?;
+    log::info!("Purged document: {}", doc_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trashed_documents_are_hidden_until_restored_and_purged_when_expired() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        super::super::db::init_sqlite_tables(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO knowledge_bases (id, name, embedding_api_config_id, embedding_provider, embedding_model, created_at, updated_at, document_count)
+                VALUES ('kb', 'kb', 'cfg', 'openai', 'm', 0, 0, 3);
+             INSERT INTO documents (id, kb_id, filename, file_type, status, created_at, duplicate_of)
+                VALUES ('d1', 'kb', 'a.md', 'md', 'completed', 0, NULL), ('d2', 'kb', 'b.md', 'md', 'completed', 0, NULL),
+                       ('d3', 'kb', 'a-copy.md', 'md', 'completed', 0, 'd1');
+             INSERT INTO chunks (id, document_id, kb_id, content, chunk_index, created_at)
+                VALUES ('c1', 'd1', 'kb', 'a', 0, 0), ('c2', 'd2', 'kb', 'b', 0, 0);
+             INSERT INTO vectors (chunk_id, document_id, kb_id, vector) VALUES ('c1', 'd1', 'kb', x'00'), ('c2', 'd2', 'kb', x'00');",
+        )
+        .unwrap();
+        let visible = |conn: &rusqlite::Connection| -> Vec<String> {
+            let sql = format!("SELECT c.id FROM chunks c WHERE {} ORDER BY c.id", NOT_TRASHED);
+            let mut stmt = conn.prepare(&sql).unwrap();
+            let ids = stmt.query_map([], |row| row.get(0)).unwrap();
+            ids.collect::<Result<_, _>>().unwrap()
+        };
+        let document_count = |conn: &rusqlite::Connection| -> i64 {
+            conn.query_row("SELECT document_count FROM knowledge_bases WHERE id = 'kb'", [], |row| row.get(0)).unwrap()
+        };
+
+        // 关联的重复文档跟着一起进回收站
+        assert_eq!(trash_document_rows(&conn, "kb", "d1").unwrap(), 2);
+        assert_eq!(visible(&conn), ["c2"]);
+        assert_eq!(document_count(&conn), 1);
+
+        assert_eq!(restore_document_rows(&conn, "kb", "d1").unwrap(), 2);
+        assert_eq!(visible(&conn), ["c1", "c2"]);
+        assert_eq!(document_count(&conn), 3);
+        assert_eq!(restore_document_rows(&conn, "kb", "d1").unwrap(), 0);
+
+        // 还在保留期内的不会被清掉，过期的连同分块、向量一起彻底删除
+        trash_document_rows(&conn, "kb", "d1").unwrap();
+        assert_eq!(purge_expired_documents(&conn).unwrap(), 0);
+        conn.execute("UPDATE documents SET deleted_at = 1 WHERE deleted_at IS NOT NULL", []).unwrap();
+        assert_eq!(purge_expired_documents(&conn).unwrap(), 1);
+        let remaining: i64 = conn.query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0)).unwrap();
+        let vectors: i64 = conn.query_row("SELECT COUNT(*) FROM vectors", [], |row| row.get(0)).unwrap();
+        assert_eq!((remaining, vectors), (1, 1));
+        assert_eq!(visible(&conn), ["c2"]);
+    }
+}
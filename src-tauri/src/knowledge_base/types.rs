@@ -31,6 +31,11 @@ impl Serialize for KnowledgeBaseError {
     }
 }
 
+/// 把数据库及其周边操作的错误统一包装为 DatabaseError，供 map_err 直接使用
+pub(crate) fn db_error(e: impl std::fmt::Display) -> KnowledgeBaseError {
+    KnowledgeBaseError::DatabaseError(e.to_string())
+}
+
 /// 知识库配置
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KnowledgeBase {
@@ -43,11 +48,165 @@ pub struct KnowledgeBase {
     pub embedding_base_url: String,
     pub chunk_size: i32,
     pub chunk_overlap: i32,
+    /// chunk_size / chunk_overlap 的计量单位
+    #[serde(default)]
+    pub chunk_unit: ChunkUnit,
+    /// 自定义分块分隔符，从粗到细排列；为空时使用默认分隔符
+    #[serde(default)]
+    pub separators: Vec<String>,
+    /// 检索这个知识库时的默认参数，没有设置时用全局检索设置
+    #[serde(default)]
+    pub retrieval_defaults: Option<RetrievalDefaults>,
+    /// 向量的存储方式
+    #[serde(default)]
+    pub vector_quantization: VectorQuantization,
+    /// 父子分块的父块大小（单位同 chunk_unit）。大于 chunk_size 时按 chunk_size 切出的小块
+    /// 只用来匹配，检索结果换成它所在的父块；0 表示不开启
+    #[serde(default)]
+    pub parent_chunk_size: i32,
+    /// 分块内容和向量加密存储（见 encryption 模块），只能在创建时选择
+    #[serde(default)]
+    pub encrypted: bool,
+    /// 拼接检索上下文用的提示词模板，需要包含 {sources} 和 {query}；None 时用 context_language 对应的默认模板
+    #[serde(default)]
+    pub context_template: Option<String>,
+    /// 默认上下文模板和来源标注使用的语言
+    #[serde(default)]
+    pub context_language: ContextLanguage,
     pub created_at: i64,
     pub updated_at: i64,
     pub document_count: i32,
 }
 
+/// 知识库自己的默认检索参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrievalDefaults {
+    pub top_k: i32,
+    #[serde(default)]
+    pub retrieval_mode: RetrievalMode,
+    #[serde(default)]
+    pub similarity_threshold: f32,
+}
+
+/// 分块大小的计量单位：字符数，或（估算的）token 数
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkUnit {
+    #[default]
+    Chars,
+    Tokens,
+}
+
+/// 检索上下文提示词的语言
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextLanguage {
+    #[default]
+    Zh,
+    En,
+}
+
+/// 向量的量化存储方式：不量化（f32）、int8，或 int8 加符号位粗筛
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorQuantization {
+    #[default]
+    None,
+    Int8,
+    Binary,
+}
+
+impl KnowledgeBase {
+    /// knowledge_bases 表里组成 KnowledgeBase 的列，顺序和 from_row 一致
+    pub(crate) const COLUMNS: &'static str = "id, name, description, embedding_api_config_id,
+         chunk_size, chunk_overlap, created_at, updated_at, document_count,
+         COALESCE(embedding_provider, ''), COALESCE(embedding_model, ''), COALESCE(embedding_base_url, ''),
+         COALESCE(chunk_unit, 'chars'), separators, retrieval_defaults, COALESCE(vector_quantization, 'none'),
+         COALESCE(parent_chunk_size, 0), COALESCE(encrypted, 0), context_template, COALESCE(context_language, 'zh')";
+
+    pub(crate) fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(KnowledgeBase {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            description: row.get(2)?,
+            embedding_api_config_id: row.get(3)?,
+            chunk_size: row.get(4)?,
+            chunk_overlap: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+            document_count: row.get(8)?,
+            embedding_provider: row.get(9)?,
+            embedding_model: row.get(10)?,
+            embedding_base_url: row.get(11)?,
+            chunk_unit: ChunkUnit::parse(&row.get::<_, String>(12)?),
+            separators: KnowledgeBase::separators_from_column(row.get(13)?),
+            retrieval_defaults: row
+                .get::<_, Option<String>>(14)?
+                .and_then(|json| serde_json::from_str(&json).ok()),
+            vector_quantization: VectorQuantization::parse(&row.get::<_, String>(15)?),
+            parent_chunk_size: row.get(16)?,
+            encrypted: row.get(17)?,
+            context_template: row.get(18)?,
+            context_language: ContextLanguage::parse(&row.get::<_, String>(19)?),
+        })
+    }
+
+    /// 数据库 separators 列存的是 JSON 数组；NULL 或无法解析时视为没有自定义
+    pub(crate) fn separators_from_column(value: Option<String>) -> Vec<String> {
+        value.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_default()
+    }
+}
+
+impl VectorQuantization {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VectorQuantization::None => "none",
+            VectorQuantization::Int8 => "int8",
+            VectorQuantization::Binary => "binary",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "int8" => VectorQuantization::Int8,
+            "binary" => VectorQuantization::Binary,
+            _ => VectorQuantization::None,
+        }
+    }
+}
+
+impl ContextLanguage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContextLanguage::Zh => "zh",
+            ContextLanguage::En => "en",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "en" => ContextLanguage::En,
+            _ => ContextLanguage::Zh,
+        }
+    }
+}
+
+impl ChunkUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChunkUnit::Chars => "chars",
+            ChunkUnit::Tokens => "tokens",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "tokens" => ChunkUnit::Tokens,
+            _ => ChunkUnit::Chars,
+        }
+    }
+}
+
 /// 文档元数据
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
@@ -61,7 +220,40 @@ pub struct Document {
     pub chunk_count: i32,
     pub status: DocumentStatus,
     pub error_message: Option<String>,
+    /// 后台导入当前所处的阶段，导入结束（成功或失败）后为 None
+    #[serde(default)]
+    pub import_stage: Option<ImportStage>,
+    /// 导入来源：本地文件路径或网页地址（旧版本导入的文档为 None）
+    #[serde(default)]
+    pub source: Option<String>,
+    /// 导入时内容和已有文档相同、按“关联”处理的文档，指向那份已有文档；这类文档没有自己的分块
+    #[serde(default)]
+    pub duplicate_of: Option<String>,
+    /// 用户给文档加的标签，检索时可以按标签过滤
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub created_at: i64,
+    /// 移进回收站的时间，不在回收站里为 None
+    #[serde(default)]
+    pub deleted_at: Option<i64>,
+}
+
+impl Document {
+    /// tags 列存的是 JSON 字符串数组，旧文档为 NULL
+    pub fn tags_from_column(raw: Option<String>) -> Vec<String> {
+        raw.and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default()
+    }
+}
+
+/// 修改文档的显示名称和标签，不填的字段保持不变
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateDocumentRequest {
+    pub document_id: String,
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// 整体替换原有标签
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -72,8 +264,235 @@ pub enum DocumentStatus {
     Error,
 }
 
-/// 带元数据的文本块
+/// 后台导入的阶段
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStage {
+    Parsing,
+    Chunking,
+    Embedding,
+    Inserting,
+    Completed,
+    Failed,
+    /// 内容和已有文档重复，按“跳过”处理，文档记录已删除
+    Skipped,
+    /// 被 cancel_import 取消，文档已按所处阶段回滚
+    Cancelled,
+    /// 已有文档正在重新导入：新数据到最后一个事务才替换旧数据，中断时旧内容完好
+    Reimporting,
+}
+
+impl ImportStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportStage::Parsing => "parsing",
+            ImportStage::Chunking => "chunking",
+            ImportStage::Embedding => "embedding",
+            ImportStage::Inserting => "inserting",
+            ImportStage::Completed => "completed",
+            ImportStage::Failed => "failed",
+            ImportStage::Skipped => "skipped",
+            ImportStage::Cancelled => "cancelled",
+            ImportStage::Reimporting => "reimporting",
+        }
+    }
+
+    /// 在这些阶段失败的导入保留了分块和已生成的向量，可以用 resume_import 继续
+    pub fn is_resumable(&self) -> bool {
+        matches!(self, ImportStage::Embedding | ImportStage::Inserting)
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "parsing" => Some(ImportStage::Parsing),
+            "chunking" => Some(ImportStage::Chunking),
+            "embedding" => Some(ImportStage::Embedding),
+            "inserting" => Some(ImportStage::Inserting),
+            "completed" => Some(ImportStage::Completed),
+            "failed" => Some(ImportStage::Failed),
+            "skipped" => Some(ImportStage::Skipped),
+            "cancelled" => Some(ImportStage::Cancelled),
+            "reimporting" => Some(ImportStage::Reimporting),
+            _ => None,
+        }
+    }
+}
+
+/// 导入的内容和知识库里已有文档相同（文件哈希一致）时的处理方式
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// 不导入，删除刚创建的文档记录
+    #[default]
+    Skip,
+    /// 删除已有文档（分块和向量一起），导入新的
+    Replace,
+    /// 保留一条指向已有文档的记录，不重复分块和生成向量
+    Link,
+}
+
+/// import_document 立即返回的后台导入任务
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportTask {
+    pub task_id: String,
+    pub kb_id: String,
+    pub document_id: String,
+    pub filename: String,
+}
+
+/// kb-import-progress 事件：current/total 在分块之后才有意义（embedding 阶段为已完成的块数）
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgressEvent {
+    pub task_id: String,
+    pub kb_id: String,
+    pub document_id: String,
+    pub filename: String,
+    pub stage: ImportStage,
+    pub current: usize,
+    pub total: usize,
+    pub error: Option<String>,
+}
+
+/// 导入任务在处理队列里的状态
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportQueueState {
+    /// 已提交，还没开始排队
+    Pending,
+    /// 等待解析名额
+    WaitingParse,
+    /// 正在解析和分块
+    Parsing,
+    /// 等待 embedding 名额
+    WaitingEmbed,
+    /// 正在生成向量和写入
+    Embedding,
+}
+
+/// 导入队列里的一个任务
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedImport {
+    pub task_id: String,
+    pub kb_id: String,
+    pub document_id: String,
+    pub filename: String,
+    pub state: ImportQueueState,
+    /// 在等待的队伍里排第几（从 1 开始），不在等待时为 0
+    pub position: usize,
+}
+
+/// get_import_queue 的返回值：并发上限和按提交顺序排列的任务
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportQueueStatus {
+    pub parse_workers: u32,
+    pub embed_workers: u32,
+    pub tasks: Vec<QueuedImport>,
+}
+
+/// 爬取整站的请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrawlRequest {
+    pub kb_id: String,
+    /// 起始网页，只跟随和它同一主机名的链接
+    pub url: String,
+    /// 从起始页开始最多跟随几层链接（起始页为第 0 层），默认 2
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// 最多抓取多少个网页，默认 20
+    #[serde(default)]
+    pub max_pages: Option<usize>,
+}
+
+/// crawl_site 立即返回的后台爬取任务
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrawlTask {
+    pub crawl_id: String,
+    pub kb_id: String,
+    pub url: String,
+}
+
+/// kb-crawl-progress 事件：每抓取完一个网页上报一次，结束时 finished 为 true。
+/// 每个被导入的网页另外有自己的 kb-import-progress 事件
+#[derive(Debug, Clone, Serialize)]
+pub struct CrawlProgressEvent {
+    pub crawl_id: String,
+    pub kb_id: String,
+    /// 刚处理完的网页
+    pub url: String,
+    /// 已抓取的网页数
+    pub visited: usize,
+    /// 还在队列里等待抓取的网页数
+    pub queued: usize,
+    pub imported: usize,
+    /// 因为网址或内容和已有文档重复而跳过的网页数
+    pub skipped: usize,
+    pub failed: usize,
+    pub finished: bool,
+}
+
+/// 更换知识库 embedding 模型的请求；新的服务商/模型从 embedding_api_config_id 指向的配置里取
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReembedRequest {
+    pub kb_id: String,
+    pub embedding_api_config_id: String,
+}
+
+/// 进行中或中断了的 embedding 模型迁移任务
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReembedJob {
+    pub kb_id: String,
+    pub embedding_api_config_id: String,
+    pub embedding_provider: String,
+    pub embedding_model: String,
+    pub embedding_base_url: String,
+    /// 已经用新模型生成向量的分块数
+    pub done: usize,
+    pub total: usize,
+    /// 上次运行失败的原因；继续迁移时清空
+    pub error: Option<String>,
+    /// 是否正在后台运行；false 表示中断了，可以继续或取消
+    pub running: bool,
+    pub started_at: i64,
+}
+
+/// kb-reembed-progress 事件：每写完一批新向量上报一次，切换完成或失败时 finished 为 true
+#[derive(Debug, Clone, Serialize)]
+pub struct ReembedProgressEvent {
+    pub kb_id: String,
+    pub done: usize,
+    pub total: usize,
+    pub finished: bool,
+    pub error: Option<String>,
+}
+
+/// 知识库的统计信息
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KbStats {
+    pub kb_id: String,
+    pub document_count: i64,
+    pub chunk_count: i64,
+    /// 所有分块的 token 数之和
+    pub total_tokens: i64,
+    /// 向量占用的存储空间（字节）
+    pub vector_bytes: i64,
+    /// 按文件类型分组的统计，文档多的排在前面
+    pub file_types: Vec<FileTypeStats>,
+    /// 最近一次导入文档的时间，知识库为空时为 None
+    pub last_import_at: Option<i64>,
+}
+
+/// 某一种文件类型的文档统计
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTypeStats {
+    pub file_type: String,
+    pub document_count: i64,
+    /// 原始文件大小之和（字节）
+    pub file_size: i64,
+    pub chunk_count: i64,
+}
+
+/// 带元数据的文本块
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Chunk {
     pub id: String,
     pub document_id: String,
@@ -81,6 +500,28 @@ pub struct Chunk {
     pub content: String,
     pub chunk_index: i32,
     pub token_count: i32,
+    /// Markdown 文档里这个块所在的标题路径，如 "安装 > Windows"
+    #[serde(default)]
+    pub heading_path: Option<String>,
+    /// 所在页码（PDF、PPTX），其他格式为空
+    #[serde(default)]
+    pub page: Option<i32>,
+    /// 在文档解析出的正文里的起止字符偏移，[start, end)
+    #[serde(default)]
+    pub start_offset: Option<i32>,
+    #[serde(default)]
+    pub end_offset: Option<i32>,
+    /// 近似重复时保留的那一块的 id，这一块检索时被跳过（见 dedup 模块）
+    #[serde(default)]
+    pub duplicate_of: Option<String>,
+}
+
+/// list_chunks 返回的一页分块
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkPage {
+    pub chunks: Vec<Chunk>,
+    /// 文档的分块总数，用来计算页数
+    pub total: usize,
 }
 
 /// 检索请求
@@ -110,6 +551,22 @@ pub struct RetrievalRequest {
     /// 精排后保留的 chunk 数量。缺省时默认为 top_k。
     #[serde(default)]
     pub rerank_top_n: Option<i32>,
+    /// 只检索带有其中任一标签的文档，为空时不过滤
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// HyDE 模式下先写假设回答的 LLM，缺省时按普通混合检索处理
+    #[serde(default)]
+    pub query_llm: Option<QueryLlm>,
+    /// 已经生成好的向量检索问题（原问题 + 假设回答）。聊天同时检索多个知识库时
+    /// 只生成一次，不经过前端
+    #[serde(skip)]
+    pub expanded_query: Option<String>,
+    /// 先按文档摘要挑出最相关的这么多篇文档，只在其中检索分块。0 = 不预筛选（默认值）
+    #[serde(default)]
+    pub summary_top_n: i32,
+    /// 摘要预筛选挑出的文档，由检索过程填写
+    #[serde(skip)]
+    pub document_ids: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -118,6 +575,20 @@ pub enum RetrievalMode {
     Vector,      // 纯向量相似度
     Keyword,     // 纯关键词检索
     Hybrid,      // 向量 + 关键词（默认）
+    Hyde,        // 先让 LLM 写一段假设回答，拿它做向量检索，再和关键词结果融合
+}
+
+/// 检索前改写问题用的 LLM 配置（HyDE 模式）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryLlm {
+    pub provider: String,
+    pub model: String,
+    #[serde(default)]
+    pub base_url: String,
+    /// 为空时按 provider 从系统 keyring 读取
+    #[serde(default)]
+    pub api_key: String,
 }
 
 /// 带分数的检索结果块
@@ -128,6 +599,23 @@ pub struct RetrievedChunk {
     pub vector_score: Option<f32>,
     pub keyword_score: Option<f32>,
     pub document_filename: String,
+    /// 问题里的词在内容中命中的一段摘录，没有命中时为空
+    #[serde(default)]
+    pub snippet: Option<MatchSnippet>,
+    /// 问题里的词在内容中出现的所有位置（字符偏移，[start, end)）
+    #[serde(default)]
+    pub match_offsets: Vec<[usize; 2]>,
+}
+
+/// 检索结果里关键词命中处前后的一段摘录
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MatchSnippet {
+    /// 摘录的文字，前后有截断时带省略号
+    pub text: String,
+    /// 命中的词在 text 里的位置（字符偏移，[start, end)）
+    pub highlights: Vec<[usize; 2]>,
+    /// 摘录（不含省略号）在内容里的起始字符偏移
+    pub offset: usize,
 }
 
 /// 检索结果
@@ -138,17 +626,139 @@ pub struct RetrievalResult {
     pub total_chunks: i32,
 }
 
-/// 创建知识库的请求
+/// 聊天记录检索请求（跨所有会话）
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CreateKnowledgeBaseRequest {
-    pub name: String,
-    pub description: String,
+#[serde(rename_all = "camelCase")]
+pub struct HistorySearchRequest {
+    pub query: String,
+    pub top_k: i32,
+    #[serde(default)]
+    pub retrieval_mode: RetrievalMode,
+    /// 向量相似度阈值，只作用于纯向量模式
+    #[serde(default)]
+    pub similarity_threshold: f32,
+    /// 语义检索用的 Embedding API 配置（纯关键词模式可以不填）
+    #[serde(default)]
     pub embedding_api_config_id: String,
+    #[serde(default)]
     pub embedding_provider: String,
+    #[serde(default)]
     pub embedding_model: String,
+    #[serde(default)]
     pub embedding_base_url: String,
+}
+
+/// 聊天记录检索命中的一条消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistorySearchHit {
+    pub message_id: String,
+    pub session_id: String,
+    pub session_title: String,
+    pub role: String,
+    /// 消息开头的一段预览
+    pub preview: String,
+    pub timestamp: i64,
+    pub score: f32,
+    pub vector_score: Option<f32>,
+    pub keyword_score: Option<f32>,
+}
+
+/// Embedding API 配置：知识库通过 id 引用，服务商/模型/Base URL 以这里为准
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingConfig {
+    pub id: String,
+    pub name: String,
+    pub provider: String,
+    pub model: String,
+    pub base_url: String,
+    /// API Key 在系统 keyring 里的条目名（secure_storage 的 provider 参数），形如 emb_{id}
+    pub key_ref: String,
+    /// 引用这个配置的知识库数
+    #[serde(default)]
+    pub kb_count: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// 新建或修改 Embedding API 配置；id 由前端生成，API Key 由前端另外写进 keyring
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveEmbeddingConfigRequest {
+    pub id: String,
+    pub name: String,
+    pub provider: String,
+    pub model: String,
+    #[serde(default)]
+    pub base_url: String,
+}
+
+/// 创建知识库的请求；服务商/模型/Base URL 从 embedding_api_config_id 指向的配置里取
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateKnowledgeBaseRequest {
+    pub name: String,
+    pub description: String,
+    pub embedding_api_config_id: String,
     pub chunk_size: Option<i32>,     // 默认：1000
     pub chunk_overlap: Option<i32>,  // 默认：200
+    #[serde(default)]
+    pub chunk_unit: Option<ChunkUnit>,  // 默认：chars
+    #[serde(default)]
+    pub separators: Option<Vec<String>>,  // 默认：内置分隔符
+    #[serde(default)]
+    pub vector_quantization: Option<VectorQuantization>,  // 默认：不量化
+    #[serde(default)]
+    pub parent_chunk_size: Option<i32>,  // 默认：0（不开启父子分块）
+    #[serde(default)]
+    pub encrypted: Option<bool>,  // 默认：不加密
+    #[serde(default)]
+    pub context_template: Option<String>,  // 默认：按 context_language 使用内置模板
+    #[serde(default)]
+    pub context_language: Option<ContextLanguage>,  // 默认：zh
+}
+
+/// 修改知识库设置，不填的字段保持不变
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateKnowledgeBaseRequest {
+    pub kb_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub chunk_size: Option<i32>,
+    #[serde(default)]
+    pub chunk_overlap: Option<i32>,
+    #[serde(default)]
+    pub chunk_unit: Option<ChunkUnit>,
+    /// 空数组表示改回默认分隔符
+    #[serde(default)]
+    pub separators: Option<Vec<String>>,
+    /// 传了就整体替换；要清除知识库自己的默认值，把 clear_retrieval_defaults 设为 true
+    #[serde(default)]
+    pub retrieval_defaults: Option<RetrievalDefaults>,
+    #[serde(default)]
+    pub clear_retrieval_defaults: bool,
+    /// 改了就把已有向量按新方式重新编码（不需要重新请求 embedding）
+    #[serde(default)]
+    pub vector_quantization: Option<VectorQuantization>,
+    /// 0 表示关闭父子分块
+    #[serde(default)]
+    pub parent_chunk_size: Option<i32>,
+    /// 空字符串表示改回默认模板
+    #[serde(default)]
+    pub context_template: Option<String>,
+    #[serde(default)]
+    pub context_language: Option<ContextLanguage>,
+    /// 分块参数有变化时，是否在后台按新参数重新分块并生成向量
+    #[serde(default)]
+    pub rechunk: bool,
+}
+
+/// update_knowledge_base 的结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateKnowledgeBaseResult {
+    pub knowledge_base: KnowledgeBase,
+    /// 已在后台排队重新分块的文档数
+    pub rechunk_documents: usize,
 }
 
 impl Default for RetrievalMode {
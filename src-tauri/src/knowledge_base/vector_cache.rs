@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/**
+ * 向量内存缓存模块
+ *
+ * 功能说明:
+ * - 把最近检索过的知识库的向量（解码成 f32）留在内存里，重复检索时不再从 SQLite
+ *   逐行读取、反序列化 BLOB
+ * - 总占用受内存预算限制（set_vector_cache_budget，0 表示关闭缓存），
+ *   超出时按最近最少使用淘汰整个知识库
+ * - vectors 表上的触发器在每次写入/删除时给知识库的版本号加一（vector_versions），
+ *   检索时版本号对不上就整体重新加载，各种写入路径都不需要单独维护缓存
+ *
+ * 缓存只保存 chunk_id、document_id 和向量，分块内容只给最终的 top_k 读取；
+ * binary 量化的知识库不进缓存（选 binary 就是为了省内存和 I/O）。
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// 默认的缓存预算（MB）
+const DEFAULT_VECTOR_CACHE_MB: u64 = 256;
+
+static BUDGET_BYTES: AtomicU64 = AtomicU64::new(DEFAULT_VECTOR_CACHE_MB * 1024 * 1024);
+
+/// 缓存里的一个向量
+pub(crate) struct CachedVector {
+    pub chunk_id: String,
+    pub document_id: String,
+    pub vector: Vec<f32>,
+}
+
+impl CachedVector {
+    /// 估算占用的内存字节数
+    fn size(&self) -> usize {
+        self.chunk_id.len() + self.document_id.len() + self.vector.len() * 4 + std::mem::size_of::<Self>()
+    }
+}
+
+struct Entry {
+    /// 加载时知识库的向量版本号
+    version: i64,
+    vectors: Arc<Vec<CachedVector>>,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// 按知识库缓存向量，LRU 淘汰
+#[derive(Default)]
+pub(crate) struct VectorCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    tick: AtomicU64,
+}
+
+impl VectorCache {
+    /// 取缓存的向量；版本号对不上（期间有写入）时丢掉旧缓存并返回 None
+    pub(crate) fn get(&self, kb_id: &str, version: i64) -> Option<Arc<Vec<CachedVector>>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match entries.get_mut(kb_id) {
+            Some(entry) if entry.version == version => {
+                entry.last_used = self.tick.fetch_add(1, Ordering::Relaxed);
+                Some(entry.vectors.clone())
+            }
+            Some(_) => {
+                entries.remove(kb_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// 放入一个知识库的向量，超出预算时先淘汰最久没用的知识库；
+    /// 单个知识库就超出预算时不缓存
+    pub(crate) fn insert(&self, kb_id: &str, version: i64, vectors: Vec<CachedVector>) -> Arc<Vec<CachedVector>> {
+        let vectors = Arc::new(vectors);
+        let bytes: usize = vectors.iter().map(CachedVector::size).sum();
+        let budget = BUDGET_BYTES.load(Ordering::Relaxed) as usize;
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(kb_id);
+        if bytes > budget {
+            return vectors;
+        }
+        evict_to(&mut entries, budget - bytes);
+        entries.insert(
+            kb_id.to_string(),
+            Entry {
+                version,
+                vectors: vectors.clone(),
+                bytes,
+                last_used: self.tick.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+        vectors
+    }
+
+    /// 按向量数和维度估算，一个知识库能不能放进预算（预算为 0 时缓存关闭，什么都放不进）
+    pub(crate) fn fits(&self, count: usize, dim: usize) -> bool {
+        // chunk_id、document_id 都是 36 字节的 UUID
+        let estimated = count.saturating_mul(dim * 4 + 72 + std::mem::size_of::<CachedVector>());
+        estimated <= BUDGET_BYTES.load(Ordering::Relaxed) as usize
+    }
+
+    /// 丢掉某个知识库的缓存
+    pub(crate) fn remove(&self, kb_id: &str) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).remove(kb_id);
+    }
+
+    /// 按当前预算淘汰
+    fn shrink(&self) {
+        let budget = BUDGET_BYTES.load(Ordering::Relaxed) as usize;
+        evict_to(&mut self.entries.lock().unwrap_or_else(|e| e.into_inner()), budget);
+    }
+}
+
+/// 按最近使用时间从旧到新淘汰，直到总占用不超过 limit
+fn evict_to(entries: &mut HashMap<String, Entry>, limit: usize) {
+    let mut total: usize = entries.values().map(|e| e.bytes).sum();
+    while total > limit {
+        let Some(oldest) = entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone()) else {
+            break;
+        };
+        if let Some(entry) = entries.remove(&oldest) {
+            total -= entry.bytes;
+            log::info!("[KB] Evicted vectors of {} from cache ({} bytes)", oldest, entry.bytes);
+        }
+    }
+}
+
+/// 知识库的向量版本号，vectors 表每次写入/删除都会加一；从没写过时为 0
+pub(crate) fn vector_version(conn: &rusqlite::Connection, kb_id: &str) -> Result<i64, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COALESCE((SELECT version FROM vector_versions WHERE kb_id = ?1), 0)",
+        [kb_id],
+        |row| row.get(0),
+    )
+}
+
+/// 设置向量缓存的内存预算（MB，0 = 关闭缓存）
+#[tauri::command]
+pub fn set_vector_cache_budget(
+    megabytes: u64,
+    kb_state: tauri::State<'_, super::commands::KbState>,
+) {
+    BUDGET_BYTES.store(megabytes.saturating_mul(1024 * 1024), Ordering::Relaxed);
+    kb_state.vector_store.vector_cache().shrink();
+    log::info!("Vector cache budget set to {} MB", megabytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vectors(n: usize) -> Vec<CachedVector> {
+        (0..n)
+            .map(|i| CachedVector { chunk_id: format!("c{}", i), document_id: "d".to_string(), vector: vec![0.0; 64] })
+            .collect()
+    }
+
+    #[test]
+    fn stale_versions_miss_and_least_recently_used_kb_is_evicted() {
+        let cache = VectorCache::default();
+        let one_kb: usize = vectors(10).iter().map(CachedVector::size).sum();
+
+        cache.insert("a", 1, vectors(10));
+        assert!(cache.get("a", 1).is_some());
+        // 期间有写入，版本号变了
+        assert!(cache.get("a", 2).is_none());
+        assert!(cache.get("a", 1).is_none());
+
+        cache.insert("a", 2, vectors(10));
+        cache.insert("b", 1, vectors(10));
+        assert!(cache.get("a", 2).is_some());
+        // b 比 a 更久没用，预算只够一个知识库时淘汰 b
+        let mut entries = cache.entries.lock().unwrap();
+        evict_to(&mut entries, one_kb);
+        assert!(entries.contains_key("a"));
+        assert!(!entries.contains_key("b"));
+    }
+}
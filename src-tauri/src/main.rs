@@ -24,6 +24,7 @@
 // 引入模块
 mod commands;
 mod db;
+mod db_encryption;
 mod knowledge_base;
 mod scheduler;
 mod secure_storage;
@@ -32,9 +33,12 @@ mod workspace;
 mod workspace_smoke_test;
 
 // 引入类型和函数
-use commands::llm::{ChatMessage, ChatSession};
+use commands::llm::{ChatMessage, ChatSession, MessagePage, SessionFilter, SessionOverview, SessionTagCount};
 use db::{Database, DbState};
-use secure_storage::{delete_api_key, get_api_key, save_api_key};
+use secure_storage::{
+    delete_api_key, get_api_key, get_api_key_pool, list_api_key_profiles, save_api_key, save_api_key_pool,
+    select_api_key_profile,
+};
 use knowledge_base::commands::{KbState, init_knowledge_base};
 use workspace::commands::{
     WorkspaceState, PendingProposals, PendingSleepRequests, PendingRoundsRequests, PendingQuestions, PendingToolApprovals,
@@ -196,6 +200,42 @@ fn main() {
             // LLM 相关命令
             commands::llm::stream_message,
             commands::llm::cancel_stream,
+            commands::provider_models::list_provider_models,
+            // 角色预设
+            commands::personas::save_persona,
+            commands::personas::list_personas,
+            commands::personas::delete_persona,
+            commands::personas::set_session_persona,
+            // 用量与费用估算
+            commands::pricing::get_cost_summary,
+            // 语音合成
+            commands::tts::synthesize_speech,
+            commands::tts::cancel_speech,
+            // 网页抓取
+            commands::web_fetch::fetch_url,
+            commands::proxy::set_proxy_settings,
+            commands::proxy::get_proxy_settings,
+            commands::rate_limit::get_throttle_state,
+            commands::summarizer::get_session_summary,
+            commands::summarizer::delete_session_summary,
+            commands::trash::list_trash,
+            commands::trash::restore_session,
+            commands::trash::purge_session,
+            commands::trash::restore_message,
+            commands::trash::purge_message,
+            commands::trash::empty_trash,
+            commands::stats::get_chat_stats,
+            commands::bookmarks::bookmark_message,
+            commands::bookmarks::remove_bookmark,
+            commands::bookmarks::list_bookmarks,
+            commands::bookmarks::save_bookmark_collection,
+            commands::bookmarks::list_bookmark_collections,
+            commands::bookmarks::delete_bookmark_collection,
+            commands::arena::stream_message_multi,
+            commands::llm_debug::set_llm_debug_mode,
+            commands::llm_debug::get_llm_debug_log,
+            commands::llm_debug::clear_llm_debug_log,
+            commands::stream_queue::set_stream_concurrency,
             // 检测最新版本(设置页手动检测按钮)
             commands::app_update::check_latest_releases,
             // 检测并安装 Beta 版更新(独立于稳定版 updater 端点)
@@ -204,23 +244,91 @@ fn main() {
             save_session_cmd,
             save_message_cmd,
             get_sessions_cmd,
+            get_messages_cmd,
+            set_session_pinned_cmd,
+            set_session_tags_cmd,
+            list_session_tags_cmd,
+            duplicate_session_cmd,
+            set_session_archived_cmd,
             delete_session_cmd,
             delete_message_cmd,
-            export_text_file_cmd,
+            update_message_cmd,
+            get_message_sources_cmd,
+            commands::export::export_session,
+            commands::export::export_all_sessions,
+            commands::import::import_conversations,
+            commands::backup::backup_database,
+            commands::backup::restore_database,
+            db_encryption::get_database_encryption_status,
+            db_encryption::enable_database_encryption,
+            db_encryption::change_database_passphrase,
+            db_encryption::disable_database_encryption,
+            db_encryption::unlock_database,
+            commands::sync::get_sync_config,
+            commands::sync::save_sync_config,
+            commands::sync::test_sync_connection,
+            commands::sync::sync_now,
+            commands::retention::get_retention_policy,
+            commands::retention::save_retention_policy,
+            commands::retention::preview_retention,
+            commands::retention::run_retention_now,
             clear_database_cmd,
             // 安全存储相关命令
             save_api_key,
             get_api_key,
             delete_api_key,
+            save_api_key_pool,
+            get_api_key_pool,
+            list_api_key_profiles,
+            select_api_key_profile,
             // 知识库相关命令
             knowledge_base::commands::create_knowledge_base,
             knowledge_base::commands::list_knowledge_bases,
+            knowledge_base::commands::update_knowledge_base,
             knowledge_base::commands::delete_knowledge_base,
+            knowledge_base::reembed::reembed_knowledge_base,
+            knowledge_base::reembed::get_reembed_job,
+            knowledge_base::reembed::cancel_reembed,
+            knowledge_base::embedding_config::list_embedding_configs,
+            knowledge_base::embedding_config::save_embedding_config,
+            knowledge_base::embedding_config::delete_embedding_config,
+            knowledge_base::archive::export_knowledge_base,
+            knowledge_base::archive::import_knowledge_base,
             knowledge_base::commands::import_document,
+            knowledge_base::commands::resume_import,
+            knowledge_base::commands::reimport_document,
+            knowledge_base::import_tasks::cancel_import,
+            knowledge_base::repair::repair_knowledge_base,
+            knowledge_base::dedup::dedup_chunks,
+            knowledge_base::dedup::clear_chunk_duplicates,
+            knowledge_base::import_queue::get_import_queue,
+            knowledge_base::import_queue::set_import_concurrency,
+            knowledge_base::summary::set_summary_llm,
+            knowledge_base::summary::get_document_summary,
+            knowledge_base::summary::summarize_documents,
+            knowledge_base::commands::import_url,
+            knowledge_base::commands::add_note,
+            knowledge_base::crawler::crawl_site,
             knowledge_base::commands::list_documents,
+            knowledge_base::commands::list_chunks,
+            knowledge_base::commands::update_chunk,
+            knowledge_base::commands::get_document_content,
+            knowledge_base::commands::get_kb_stats,
+            knowledge_base::commands::update_document,
             knowledge_base::commands::delete_document,
+            knowledge_base::trash::list_trashed_documents,
+            knowledge_base::trash::restore_document,
+            knowledge_base::trash::purge_document,
             knowledge_base::commands::search_knowledge_base,
+            knowledge_base::vector_cache::set_vector_cache_budget,
             knowledge_base::commands::read_document_for_context,
+            knowledge_base::history::search_chat_history,
+            knowledge_base::rag::attach_session_kb,
+            knowledge_base::rag::detach_session_kb,
+            knowledge_base::rag::list_session_kbs,
+            knowledge_base::scratch::chat_with_file,
+            knowledge_base::scratch::discard_session_files,
+            knowledge_base::embedding::get_available_embedding_models,
             // MCP 相关命令
             commands::mcp::create_mcp_server,
             commands::mcp::list_mcp_servers,
@@ -301,27 +409,35 @@ fn main() {
         ])
         // 应用初始化设置
         .setup(move |app| {
+            // 数据库加密：导出并换上待生效的新数据库、读取密钥，必须在打开任何连接之前
+            if let Ok(dir) = app.handle().path().app_data_dir() {
+                db_encryption::prepare(&dir);
+                // 清理策略里的回收站保留天数，数据库初始化时的过期清理要用
+                commands::retention::load(&dir);
+            }
             let db = Database::new(app.handle());
             if let Err(e) = db.init() {
                 log::error!("Failed to initialize database: {}", e);
             }
             
-            let conn = match rusqlite::Connection::open(&db.path) {
+            // 这个连接除了做初始化，后面也被 Agent 循环自动恢复扫描复用，同样可能撞上并发写，
+            // busy_timeout 等设置见 db::open_connection
+            let conn = match crate::db::open_connection(&db.path) {
                 Ok(c) => c,
                 Err(e) => {
                     log::error!("Failed to open database: {}", e);
                     return Err(Box::new(e) as Box<dyn std::error::Error>);
                 }
             };
-            // 与 workspace::db::open_conn 保持一致的 busy_timeout 设置 -- 这个连接
-            // 除了做初始化，后面也被 Agent 循环自动恢复扫描复用，同样可能撞上并发写。
-            if let Err(e) = conn.busy_timeout(std::time::Duration::from_secs(5)) {
-                log::warn!("设置数据库 busy_timeout 失败: {}", e);
-            }
 
             if let Err(e) = init_knowledge_base(&conn) {
                 log::error!("Failed to initialize knowledge base tables: {}", e);
             }
+            match knowledge_base::trash::purge_expired_documents(&conn) {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {} expired documents from trash", purged),
+                Err(e) => log::warn!("Failed to purge expired documents: {}", e),
+            }
 
             if let Err(e) = init_workspace_tables(&conn) {
                 log::error!("Failed to initialize workspace tables: {}", e);
@@ -514,6 +630,14 @@ fn main() {
                 });
             }
 
+            // 启动旧数据自动清理的后台维护任务
+            {
+                let maintenance_handle = app.handle().clone();
+                tauri::async_runtime::spawn(async move {
+                    commands::retention::run_maintenance_loop(maintenance_handle).await;
+                });
+            }
+
             if std::env::var("BAIYU_WORKSPACE_SMOKE_TEST").is_ok() {
                 let smoke_handle = app.handle().clone();
                 tauri::async_runtime::spawn(async move {
@@ -545,8 +669,11 @@ async fn save_session_cmd(
     session: ChatSession,
     db_state: tauri::State<'_, DbState>,
 ) -> Result<(), String> {
-    let db = db_state.0.lock().await;
-    db.save_session(&session).map_err(|e| commands::local_model::friendly_err("保存会话失败，请重试", e))
+    db_state
+        .run(move |db| {
+            db.save_session(&session).map_err(|e| commands::local_model::friendly_err("保存会话失败，请重试", e))
+        })
+        .await
 }
 
 #[tauri::command]
@@ -555,42 +682,185 @@ async fn save_message_cmd(
     message: ChatMessage,
     db_state: tauri::State<'_, DbState>,
 ) -> Result<(), String> {
-    let db = db_state.0.lock().await;
-    db.save_message(&session_id, &message).map_err(|e| commands::local_model::friendly_err("保存消息失败，请重试", e))
+    db_state
+        .run(move |db| {
+            db.save_message(&session_id, &message).map_err(|e| commands::local_model::friendly_err("保存消息失败，请重试", e))
+        })
+        .await
 }
 
+/// 读取会话列表；可按服务商、模型、更新时间、是否有出错回复、标签、归档状态筛选并指定排序，不传时返回全部会话
 #[tauri::command]
 async fn get_sessions_cmd(
+    filter: Option<SessionFilter>,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<Vec<SessionOverview>, String> {
+    db_state
+        .run(move |db| {
+            db.get_sessions(&filter.unwrap_or_default())
+                .map_err(|e| commands::local_model::friendly_err("读取会话列表失败，请重试", e))
+        })
+        .await
+}
+
+/// 设置会话的标签（整体替换）；标签去掉首尾空白，空标签和重复的标签忽略
+#[tauri::command]
+async fn set_session_tags_cmd(
+    session_id: String,
+    tags: Vec<String>,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<(), String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        // 标签在查询里用换行拼接，标签内部的空白统一压成一个空格
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    db_state
+        .run(move |db| {
+            db.set_session_tags(&session_id, &normalized)
+                .map_err(|e| commands::local_model::friendly_err("设置会话标签失败，请确认会话已保存", e))
+        })
+        .await
+}
+
+/// 列出所有用过的会话标签及使用它的会话数
+#[tauri::command]
+async fn list_session_tags_cmd(db_state: tauri::State<'_, DbState>) -> Result<Vec<SessionTagCount>, String> {
+    db_state
+        .run(|db| {
+            db.get_session_tags()
+                .map_err(|e| commands::local_model::friendly_err("读取会话标签失败，请重试", e))
+        })
+        .await
+}
+
+/// 分页读取会话消息；不传 offset 时返回最新的一页
+#[tauri::command]
+async fn get_messages_cmd(
+    session_id: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<MessagePage, String> {
+    db_state
+        .run(move |db| {
+            db.get_messages_page(&session_id, offset, limit.unwrap_or(db::DEFAULT_MESSAGE_PAGE_SIZE))
+                .map_err(|e| commands::local_model::friendly_err("读取消息失败，请重试", e))
+        })
+        .await
+}
+
+/// 置顶或取消置顶会话
+#[tauri::command]
+async fn set_session_pinned_cmd(
+    session_id: String,
+    pinned: bool,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<(), String> {
+    db_state
+        .run(move |db| {
+            db.set_session_pinned(&session_id, pinned)
+                .map_err(|e| commands::local_model::friendly_err("置顶会话失败，请重试", e))
+        })
+        .await
+}
+
+/// 复制会话及其消息，返回新会话 ID；不传标题时用 “原标题 (副本)”
+#[tauri::command]
+async fn duplicate_session_cmd(
+    session_id: String,
+    title: Option<String>,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<String, String> {
+    db_state
+        .run(move |db| {
+            let title = title.filter(|t| !t.trim().is_empty());
+            db.duplicate_session(&session_id, title.as_deref())
+                .map_err(|e| commands::local_model::friendly_err("复制会话失败，请重试", e))
+        })
+        .await
+}
+
+/// 归档或取消归档会话
+#[tauri::command]
+async fn set_session_archived_cmd(
+    session_id: String,
+    archived: bool,
     db_state: tauri::State<'_, DbState>,
-) -> Result<Vec<ChatSession>, String> {
-    let db = db_state.0.lock().await;
-    db.get_sessions().map_err(|e| commands::local_model::friendly_err("读取会话列表失败，请重试", e))
+) -> Result<(), String> {
+    db_state
+        .run(move |db| {
+            db.set_session_archived(&session_id, archived)
+                .map_err(|e| commands::local_model::friendly_err("归档会话失败，请重试", e))
+        })
+        .await
 }
 
+/// 删除会话：移进回收站，可以用 restore_session 恢复
 #[tauri::command]
 async fn delete_session_cmd(
     session_id: String,
     db_state: tauri::State<'_, DbState>,
 ) -> Result<(), String> {
-    let db = db_state.0.lock().await;
-    db.delete_session(&session_id).map_err(|e| commands::local_model::friendly_err("删除会话失败，请重试", e))
+    db_state
+        .run(move |db| {
+            db.delete_session(&session_id).map_err(|e| commands::local_model::friendly_err("删除会话失败，请重试", e))
+        })
+        .await
 }
 
+/// 删除消息：移进回收站，可以用 restore_message 恢复
 #[tauri::command]
 async fn delete_message_cmd(
     message_id: String,
     db_state: tauri::State<'_, DbState>,
 ) -> Result<(), String> {
-    let db = db_state.0.lock().await;
-    db.delete_message(&message_id).map_err(|e| commands::local_model::friendly_err("删除消息失败，请重试", e))
+    db_state
+        .run(move |db| {
+            db.delete_message(&message_id).map_err(|e| commands::local_model::friendly_err("删除消息失败，请重试", e))
+        })
+        .await
+}
+
+/// 修改单条消息的内容（不重新生成回复）
+#[tauri::command]
+async fn update_message_cmd(
+    message_id: String,
+    content: String,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<(), String> {
+    db_state
+        .run(move |db| {
+            db.update_message(&message_id, &content)
+                .map_err(|e| commands::local_model::friendly_err("修改消息失败，请重试", e))
+        })
+        .await
 }
 
-/// 导出对话为文本文件（JSON/TXT）：前端已用 save() 对话框拿到用户选择的落盘路径，
-/// 这里只负责把拼好的文本写进去。跟 copy_log_file 一样直接用 std::fs，不引入
-/// tauri-plugin-fs——避免为这一个功能新增插件依赖和权限声明。
+/// 获取会话中各条回复的引用列表（消息 ID -> 引用），重新打开会话时用来恢复脚注
 #[tauri::command]
-fn export_text_file_cmd(file_path: String, content: String) -> Result<(), String> {
-    std::fs::write(&file_path, content).map_err(|e| format!("导出失败: {}", e))
+async fn get_message_sources_cmd(
+    session_id: String,
+    db_state: tauri::State<'_, DbState>,
+) -> Result<std::collections::HashMap<String, Vec<knowledge_base::rag::Citation>>, String> {
+    db_state
+        .run(move |db| {
+            let rows = db
+                .get_message_sources(&session_id)
+                .map_err(|e| commands::local_model::friendly_err("读取引用来源失败，请重试", e))?;
+            Ok(rows
+                .into_iter()
+                .filter_map(|(message_id, json)| {
+                    serde_json::from_str::<Vec<knowledge_base::types::RetrievedChunk>>(&json)
+                        .ok()
+                        .map(|chunks| (message_id, knowledge_base::rag::citations_from(&chunks)))
+                })
+                .collect())
+        })
+        .await
 }
 
 /// 清空数据库：删除全部会话、消息、MCP 服务器配置、Skill（设置页“危险操作”按钮对应的后端命令）
@@ -598,8 +868,7 @@ fn export_text_file_cmd(file_path: String, content: String) -> Result<(), String
 async fn clear_database_cmd(
     db_state: tauri::State<'_, DbState>,
 ) -> Result<(), String> {
-    let db = db_state.0.lock().await;
-    db.clear_all().map_err(|e| commands::local_model::friendly_err("清空数据库失败，请重启应用后重试", e))
+    db_state.run(|db| db.clear_all().map_err(|e| commands::local_model::friendly_err("清空数据库失败，请重启应用后重试", e))).await
 }
 
 #[tauri::command]
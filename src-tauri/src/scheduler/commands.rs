@@ -116,23 +116,19 @@ pub async fn run_scheduler_loop(app_handle: AppHandle, cancel: CancellationToken
 
         let now_ms = chrono::Utc::now().timestamp_millis();
         let db_state = app_handle.state::<DbState>();
-        let db_path = {
-            let db = db_state.0.lock().await;
-            db.path.clone()
-        };
-
-        let due = match rusqlite::Connection::open(&db_path) {
-            Ok(conn) => db::list_due_schedules(&conn, now_ms).unwrap_or_default(),
-            Err(e) => { log::error!("[scheduler] 打开数据库失败: {}", e); continue; }
-        };
+        let due = db_state.run(move |db| match crate::db::open_connection(&db.path) {
+            Ok(conn) => Some(db::list_due_schedules(&conn, now_ms).unwrap_or_default()),
+            Err(e) => { log::error!("[scheduler] 打开数据库失败: {}", e); None }
+        }).await;
+        let Some(due) = due else { continue };
 
         for schedule in due {
-            fire_schedule(&app_handle, &schedule, now_ms, &db_path).await;
+            fire_schedule(&app_handle, &schedule, now_ms).await;
         }
     }
 }
 
-async fn fire_schedule(app_handle: &AppHandle, schedule: &Schedule, now_ms: i64, db_path: &str) {
+async fn fire_schedule(app_handle: &AppHandle, schedule: &Schedule, now_ms: i64) {
     log::info!("[scheduler] 触发定时任务「{}」(id={})", schedule.name, schedule.id);
 
     // 1. 发消息到 workspace（如果有绑定）
@@ -157,9 +153,12 @@ async fn fire_schedule(app_handle: &AppHandle, schedule: &Schedule, now_ms: i64,
     // 3. 计算下次运行时间，更新 DB
     let next = compute_next_run_at(schedule, now_ms);
     let disable = schedule.kind == ScheduleKind::Once;
-    if let Ok(conn) = rusqlite::Connection::open(db_path) {
-        let _ = db::update_after_fire(&conn, &schedule.id, next, now_ms, disable);
-    }
+    let id = schedule.id.clone();
+    app_handle.state::<DbState>().run(move |db| {
+        if let Ok(conn) = crate::db::open_connection(&db.path) {
+            let _ = db::update_after_fire(&conn, &id, next, now_ms, disable);
+        }
+    }).await;
 }
 
 // ─── Tauri 命令 ─────────────────────────────────────────────────────────
@@ -191,10 +190,11 @@ pub async fn schedule_create(
         updated_at:       now,
     };
 
-    let db = db_state.0.lock().await;
-    let conn = rusqlite::Connection::open(&db.path).map_err(|e| e.to_string())?;
-    db::insert_schedule(&conn, &schedule).map_err(|e| e.to_string())?;
-    Ok(schedule)
+    db_state.run(move |db| {
+        let conn = crate::db::open_connection(&db.path).map_err(|e| e.to_string())?;
+        db::insert_schedule(&conn, &schedule).map_err(|e| e.to_string())?;
+        Ok(schedule)
+    }).await
 }
 
 #[tauri::command]
@@ -202,9 +202,10 @@ pub async fn schedule_list(
     workspace_id: Option<String>,
     db_state: State<'_, DbState>,
 ) -> Result<Vec<Schedule>, String> {
-    let db = db_state.0.lock().await;
-    let conn = rusqlite::Connection::open(&db.path).map_err(|e| e.to_string())?;
-    db::list_schedules(&conn, workspace_id.as_deref()).map_err(|e| e.to_string())
+    db_state.run(move |db| {
+        let conn = crate::db::open_connection(&db.path).map_err(|e| e.to_string())?;
+        db::list_schedules(&conn, workspace_id.as_deref()).map_err(|e| e.to_string())
+    }).await
 }
 
 #[tauri::command]
@@ -212,9 +213,10 @@ pub async fn schedule_delete(
     id: String,
     db_state: State<'_, DbState>,
 ) -> Result<(), String> {
-    let db = db_state.0.lock().await;
-    let conn = rusqlite::Connection::open(&db.path).map_err(|e| e.to_string())?;
-    db::delete_schedule(&conn, &id).map_err(|e| e.to_string())
+    db_state.run(move |db| {
+        let conn = crate::db::open_connection(&db.path).map_err(|e| e.to_string())?;
+        db::delete_schedule(&conn, &id).map_err(|e| e.to_string())
+    }).await
 }
 
 #[tauri::command]
@@ -222,9 +224,10 @@ pub async fn schedule_toggle(
     id: String,
     db_state: State<'_, DbState>,
 ) -> Result<Schedule, String> {
-    let db = db_state.0.lock().await;
-    let conn = rusqlite::Connection::open(&db.path).map_err(|e| e.to_string())?;
-    db::toggle_schedule(&conn, &id)
-        .map_err(|e| e.to_string())?
-        .ok_or_else(|| format!("定时任务 {} 不存在", id))
+    db_state.run(move |db| {
+        let conn = crate::db::open_connection(&db.path).map_err(|e| e.to_string())?;
+        db::toggle_schedule(&conn, &id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("定时任务 {} 不存在", id))
+    }).await
 }
@@ -9,6 +9,9 @@
  * - 使用系统密钥链 (Keyring) 安全存储 API 密钥
  * - 支持保存、获取、删除 API 密钥
  * - 支持检查密钥是否存在
+ * - 每个配置可额外保存一组备用密钥（多密钥轮换）
+ * - 同一提供商可以保存多个命名的密钥档案（如 openai:work、openai:personal），
+ *   并选一个作为默认档案：按提供商取密钥时优先返回默认档案的密钥
  * 
  * 使用方式:
  * - Windows: 使用 Windows Credential Manager
@@ -18,7 +21,7 @@
 
 // 引入依赖
 use keyring::Entry;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 /// 应用名称 (用于密钥链标识)
@@ -35,6 +38,9 @@ pub enum SecureStorageError {
     /// 序列化错误
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
+    /// 档案名不合法或档案不存在
+    #[error("Invalid key profile: {0}")]
+    InvalidProfile(String),
 }
 
 /// 实现 Serialize trait 用于 Tauri 命令返回
@@ -50,16 +56,27 @@ impl Serialize for SecureStorageError {
 /**
  * 保存 API 密钥到系统密钥链
  * 
- * @param provider: 提供商标识符 (如 openai, anthropic)
+ * @param provider: 提供商标识符 (如 openai, anthropic)；"提供商:档案名" 形式时保存为该档案的密钥，
+ *                  并把档案记入提供商的档案列表
  * @param api_key: API 密钥
  */
 #[tauri::command]
 pub fn save_api_key(provider: String, api_key: String) -> Result<(), SecureStorageError> {
+    let profile = split_profile(&provider)?;
     let entry = Entry::new(APP_NAME, &format!("{}_{}", SERVICE_NAME, provider))
         .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
     
     entry.set_password(&api_key)
         .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+
+    if let Some((base, name)) = profile {
+        let mut index = load_profile_index(base)?;
+        if !index.profiles.iter().any(|p| p == name) {
+            index.profiles.push(name.to_string());
+            index.profiles.sort();
+            save_profile_index(base, &index)?;
+        }
+    }
     
     log::info!("API key saved for provider: {}", provider);
     Ok(())
@@ -68,12 +85,26 @@ pub fn save_api_key(provider: String, api_key: String) -> Result<(), SecureStora
 /// 从系统密钥链获取 API 密钥
 /// 
 /// # 参数
-/// * `provider` - 提供商标识符 (如 openai, anthropic)
+/// * `provider` - 提供商标识符 (如 openai, anthropic)，或 "提供商:档案名" 指定某个档案
 /// 
 /// # 返回
-/// 找到则返回 `Some(api_key)`，未找到则返回 `None`
+/// 找到则返回 `Some(api_key)`，未找到则返回 `None`。
+/// 只给提供商、且选了默认档案时返回默认档案的密钥；默认档案的密钥已经没有时退回不带档案的密钥
 #[tauri::command]
 pub fn get_api_key(provider: String) -> Result<Option<String>, SecureStorageError> {
+    if split_profile(&provider)?.is_none() {
+        if let Some(default) = load_profile_index(&provider)?.default {
+            if let Some(key) = read_api_key(&format!("{}:{}", provider, default))? {
+                return Ok(Some(key));
+            }
+            log::warn!("Default key profile {}:{} has no key, using the provider key", provider, default);
+        }
+    }
+    read_api_key(&provider)
+}
+
+/// 读出一个密钥链条目，不做档案解析
+fn read_api_key(provider: &str) -> Result<Option<String>, SecureStorageError> {
     let entry = Entry::new(APP_NAME, &format!("{}_{}", SERVICE_NAME, provider))
         .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
     
@@ -93,15 +124,167 @@ pub fn get_api_key(provider: String) -> Result<Option<String>, SecureStorageErro
 /// 从系统密钥链删除 API 密钥
 /// 
 /// # 参数
-/// * `provider` - 提供商标识符
+/// * `provider` - 提供商标识符；"提供商:档案名" 时删除该档案，删除的是默认档案时取消默认
 #[tauri::command]
 pub fn delete_api_key(provider: String) -> Result<(), SecureStorageError> {
+    let profile = split_profile(&provider)?;
     let entry = Entry::new(APP_NAME, &format!("{}_{}", SERVICE_NAME, provider))
         .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
     
-    entry.delete_credential()
-        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+    match (entry.delete_credential(), profile) {
+        (Ok(()), _) | (Err(keyring::Error::NoEntry), Some(_)) => {}
+        (Err(e), _) => return Err(SecureStorageError::KeyringError(e.to_string())),
+    }
+
+    if let Some((base, name)) = profile {
+        let mut index = load_profile_index(base)?;
+        index.profiles.retain(|p| p != name);
+        if index.default.as_deref() == Some(name) {
+            index.default = None;
+        }
+        save_profile_index(base, &index)?;
+    }
     
     log::info!("API key deleted for provider: {}", provider);
     Ok(())
 }
+
+/// 某个提供商的密钥档案列表（不含密钥本身）
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ApiKeyProfiles {
+    /// 档案名，按名称排序
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    /// 默认档案名；None 时使用不带档案的密钥
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+/// 档案列表在密钥链里的标签（密钥链不能枚举条目，档案名单独记一份）
+fn profile_index_label(provider: &str) -> String {
+    format!("{}_{}_profiles", SERVICE_NAME, provider)
+}
+
+/// 拆出 "提供商:档案名" 里的两部分；不带档案时返回 None，档案名或提供商为空时报错
+fn split_profile(provider: &str) -> Result<Option<(&str, &str)>, SecureStorageError> {
+    match provider.split_once(':') {
+        None => Ok(None),
+        Some((base, name)) if !base.trim().is_empty() && !name.trim().is_empty() && !name.contains(':') => {
+            Ok(Some((base, name)))
+        }
+        Some(_) => Err(SecureStorageError::InvalidProfile(provider.to_string())),
+    }
+}
+
+fn load_profile_index(provider: &str) -> Result<ApiKeyProfiles, SecureStorageError> {
+    let entry = Entry::new(APP_NAME, &profile_index_label(provider))
+        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+    match entry.get_password() {
+        Ok(raw) => Ok(serde_json::from_str(&raw)?),
+        Err(keyring::Error::NoEntry) => Ok(ApiKeyProfiles::default()),
+        Err(e) => Err(SecureStorageError::KeyringError(e.to_string())),
+    }
+}
+
+fn save_profile_index(provider: &str, index: &ApiKeyProfiles) -> Result<(), SecureStorageError> {
+    let entry = Entry::new(APP_NAME, &profile_index_label(provider))
+        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+    if index.profiles.is_empty() {
+        return match entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(SecureStorageError::KeyringError(e.to_string())),
+        };
+    }
+    entry.set_password(&serde_json::to_string(index)?)
+        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))
+}
+
+/**
+ * 列出提供商的密钥档案和默认档案
+ *
+ * @param provider: 提供商标识符 (如 openai)
+ */
+#[tauri::command]
+pub fn list_api_key_profiles(provider: String) -> Result<ApiKeyProfiles, SecureStorageError> {
+    load_profile_index(&provider)
+}
+
+/**
+ * 选择提供商的默认密钥档案，之后按提供商取密钥和 stream_message 没有指定档案时都用这个档案
+ *
+ * @param provider: 提供商标识符 (如 openai)
+ * @param profile: 档案名，None 表示不用档案、改回不带档案的密钥
+ */
+#[tauri::command]
+pub fn select_api_key_profile(provider: String, profile: Option<String>) -> Result<(), SecureStorageError> {
+    let mut index = load_profile_index(&provider)?;
+    if let Some(name) = &profile {
+        if !index.profiles.contains(name) {
+            return Err(SecureStorageError::InvalidProfile(format!("{}:{}", provider, name)));
+        }
+    }
+    index.default = profile;
+    save_profile_index(&provider, &index)?;
+
+    log::info!("Default key profile for {} set to {:?}", provider, index.default);
+    Ok(())
+}
+
+/// 备用密钥池在密钥链里的标签（与单个密钥的标签区分开）
+fn pool_label(provider: &str) -> String {
+    format!("{}_{}_pool", SERVICE_NAME, provider)
+}
+
+/**
+ * 保存某个配置的备用 API 密钥列表（多密钥轮换用）
+ *
+ * 列表以 JSON 数组存成一个密钥链条目；传入空列表表示清空。
+ *
+ * @param provider: 配置标识符 (前端传 API 配置 ID)
+ * @param keys: 备用密钥（不含主密钥）
+ */
+#[tauri::command]
+pub fn save_api_key_pool(provider: String, keys: Vec<String>) -> Result<(), SecureStorageError> {
+    let entry = Entry::new(APP_NAME, &pool_label(&provider))
+        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+
+    let keys: Vec<String> = keys.into_iter().map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect();
+    if keys.is_empty() {
+        return match entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(SecureStorageError::KeyringError(e.to_string())),
+        };
+    }
+    entry.set_password(&serde_json::to_string(&keys)?)
+        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+
+    log::info!("API key pool saved for provider: {} ({} keys)", provider, keys.len());
+    Ok(())
+}
+
+/// 从系统密钥链获取备用 API 密钥列表，没有保存过时返回空列表
+#[tauri::command]
+pub fn get_api_key_pool(provider: String) -> Result<Vec<String>, SecureStorageError> {
+    let entry = Entry::new(APP_NAME, &pool_label(&provider))
+        .map_err(|e| SecureStorageError::KeyringError(e.to_string()))?;
+
+    match entry.get_password() {
+        Ok(raw) => Ok(serde_json::from_str(&raw)?),
+        Err(keyring::Error::NoEntry) => Ok(Vec::new()),
+        Err(e) => Err(SecureStorageError::KeyringError(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_is_the_part_after_the_colon() {
+        assert_eq!(split_profile("openai").unwrap(), None);
+        assert_eq!(split_profile("openai:work").unwrap(), Some(("openai", "work")));
+        assert!(split_profile("openai:").is_err());
+        assert!(split_profile(":work").is_err());
+        assert!(split_profile("openai:work:old").is_err());
+    }
+}
@@ -5,6 +5,13 @@
 // 这里重新导出共享的领域类型，让更底层的模块（例如 db.rs）可以从这个中立的
 // 位置导入，而不必反过来依赖 commands/ 目录。
 // 类型的权威定义仍然放在各自的 command 模块里；这里只做重新导出。
-pub use crate::commands::llm::{ChatMessage, ChatSession};
+pub use crate::commands::llm::{ChatMessage, ChatSession, GenerationInfo, MessagePage, SessionFilter, SessionOverview, SessionSort, SessionTagCount};
 pub use crate::commands::mcp::{MCPServer, MCPServerType};
 pub use crate::commands::skills::Skill;
+pub use crate::commands::pricing::MessageUsage;
+pub use crate::commands::personas::Persona;
+pub use crate::commands::bookmarks::{Bookmark, BookmarkCollection, BookmarkFilter};
+pub use crate::commands::summarizer::SessionSummary;
+pub use crate::commands::trash::{Trash, TrashedMessage, TrashedSession};
+pub use crate::commands::retention::ArchivedSession;
+pub use crate::commands::stats::{ChatStats, ChatStatsGroup};
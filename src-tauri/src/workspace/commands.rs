@@ -12,7 +12,8 @@ use crate::commands::llm::{
 use crate::commands::mcp::{call_mcp_tool, get_all_mcp_tools, MCPTool};
 use crate::db::DbState;
 use crate::knowledge_base::commands::{search_knowledge_base, KbState};
-use crate::knowledge_base::retrieval::build_context as build_rag_context;
+use crate::knowledge_base::rag::context_template_for;
+use crate::knowledge_base::retrieval::{build_context as build_rag_context, ContextOptions};
 use crate::knowledge_base::types::{RetrievalMode, RetrievalRequest};
 use crate::secure_storage;
 use chrono::Utc;
@@ -1295,6 +1296,8 @@ async fn process_agent_wake(
                     error: None,
                     images: vec![],
                     videos: vec![],
+                    seed: None,
+                    generation: None,
                 };
                 native_messages.extend(build_native_messages(&agent.provider, std::slice::from_ref(&rescue_hint)));
 
@@ -1478,6 +1481,8 @@ async fn process_agent_wake(
                         error: None,
                         images: vec![],
                         videos: vec![],
+                        seed: None,
+                        generation: None,
                     };
                     native_messages.extend(build_native_messages(&agent.provider, std::slice::from_ref(&warn)));
                 }
@@ -1506,6 +1511,8 @@ async fn process_agent_wake(
             error: None,
             images: vec![],
             videos: vec![],
+            seed: None,
+            generation: None,
         };
         native_messages.extend(build_native_messages(&agent.provider, std::slice::from_ref(&nudge)));
 
@@ -1647,6 +1654,8 @@ async fn build_chat_history(app_handle: &AppHandle, workspace_id: &str, agent: &
                 // （build_native_messages 只对 user 角色构造多模态块）
                 images: m.images,
                 videos: vec![],
+                seed: None,
+                generation: None,
             }
         })
         .collect()
@@ -1724,10 +1733,22 @@ async fn build_agent_system_prompt(app_handle: &AppHandle, agent: &WorkspaceAgen
                 reranker_base_url: agent.rag_reranker_base_url.clone(),
                 reranker_model: agent.rag_reranker_model.clone(),
                 rerank_top_n: agent.rag_rerank_top_n,
+                tags: Vec::new(),
+                query_llm: None,
+                expanded_query: None,
+                summary_top_n: 0,
+                document_ids: None,
             };
             match search_knowledge_base(request, kb_state.clone()).await {
                 Ok(result) if !result.chunks.is_empty() => {
-                    sections.push(build_rag_context(&result.chunks, &result.query));
+                    let (template, language) = context_template_for(&kb_state, std::slice::from_ref(kb_id)).await;
+                    let options = ContextOptions {
+                        template: template.as_deref(),
+                        language,
+                        token_budget: None,
+                        model: &agent.model,
+                    };
+                    sections.push(build_rag_context(&result.chunks, &result.query, &options).text);
                 }
                 Ok(_) => {}
                 Err(e) => log::warn!("Workspace agent {} 知识库 {} 检索失败: {}", agent.id, kb_id, e),
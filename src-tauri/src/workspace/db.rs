@@ -5,14 +5,12 @@
 use super::types::*;
 use rusqlite::Connection;
 
-/// 打开连接时设置 busy-timeout，这样一次写入如果撞上另一个连接短暂持有写锁
-/// 的窗口，会重试最多 5 秒，而不是立刻以 `SQLITE_BUSY` 失败——本模块里每个
-/// 业务操作都各自打开一个短生命周期的连接（见 `delete_workspace` 的文档注释），
+/// 打开连接，busy-timeout 等设置见 `crate::db::open_connection`：一次写入如果撞上
+/// 另一个连接短暂持有写锁的窗口，会重试最多 5 秒，而不是立刻以 `SQLITE_BUSY` 失败——
+/// 本模块里每个业务操作都各自打开一个短生命周期的连接（见 `delete_workspace` 的文档注释），
 /// 所以两个 Agent 的循环同时触发写入，是真实会发生的场景，不是纸上谈兵。
 pub fn open_conn(path: &str) -> Result<Connection, WorkspaceError> {
-    let conn = Connection::open(path)?;
-    conn.busy_timeout(std::time::Duration::from_secs(5))?;
-    Ok(conn)
+    Ok(crate::db::open_connection(path)?)
 }
 
 /// 若 Workspace 相关表不存在则创建，并对老版本安装做增量迁移（`ALTER TABLE
@@ -251,9 +249,8 @@ pub fn get_workspace(conn: &Connection, id: &str) -> Result<Option<Workspace>, W
 
 /// 删除一个工作组及其下所有内容。手动做级联删除、先删子表再删主表，而不是
 /// 单纯依赖上面表定义里的 `ON DELETE CASCADE`——`PRAGMA foreign_keys` 是按
-/// 连接（per-connection）生效的设置，而本模块每个业务操作都是新开一个
-/// `rusqlite::Connection`，并不复用 `Database::init` 那个长生命周期的连接，
-/// 所以级联到底会不会真的触发，本模块不应该指望它。
+/// 连接（per-connection）生效的设置，虽然 `open_conn` 会打开它，但用户库里
+/// 老版本建的表未必都带着完整的外键定义，级联到底会不会真的触发，本模块不应该指望它。
 pub fn delete_workspace(conn: &Connection, id: &str) -> Result<(), WorkspaceError> {
     conn.execute(
         "DELETE FROM workspace_agent_tasks WHERE agent_id IN (SELECT id FROM workspace_agents WHERE workspace_id = ?1)",
@@ -9,16 +9,18 @@
 
 import { ref } from "vue";
 import { defineStore } from "pinia";
+import type { MatchSnippet } from "@/stores/knowledgeBase";
 import { invoke } from "@tauri-apps/api/core";
 import { listen, type UnlistenFn } from "@tauri-apps/api/event";
-import { useSettingsStore } from "./settings";
-import { useKnowledgeBaseStore, type RetrievalResult } from "./knowledgeBase";
+import { useSettingsStore, toCustomAuth, toSafetySettings } from "./settings";
+import { useKnowledgeBaseStore, type RetrievalMode } from "./knowledgeBase";
 import { classifyError } from "@/utils/errorMessage";
 
 /** 图片附件（base64 编码，不含 data URL 前缀） */
 export interface ImageAttachment {
   data: string;       // 原始 base64 字符串
   mediaType: string;  // MIME 类型，如 "image/jpeg"
+  path?: string;      // 本地图片路径（data 为空时由后端读取）
 }
 
 /** 视频附件（base64 编码，不含 data URL 前缀，仅 Gemini provider 有效） */
@@ -46,6 +48,43 @@ export interface Message {
   images?: ImageAttachment[];     // 图片附件（已转 base64）
   videos?: VideoAttachment[];     // 视频附件（已转 base64，仅 Gemini）
   toolCalls?: ToolCallInfo[];     // 本轮回复中触发的工具调用（按发生顺序）
+  finishReason?: string;          // 生成停止的原因: stop/length/content_filter/tool_calls（入库）
+  usage?: MessageUsage;           // 本条回复的 token 用量（入库）
+  blocked?: BlockInfo;            // 被服务商安全策略拦截的详情（目前只有 Gemini 会给，仅内存态）
+  metrics?: StreamMetrics;        // 首字延迟、生成速度和总耗时（只有总耗时入库）
+  provider?: string;              // 实际应答的服务商（仅 assistant 消息，入库）
+  model?: string;                 // 实际应答的模型（仅 assistant 消息，入库）
+  logprobs?: TokenLogprob[];      // 各输出 token 的对数概率（开启 logprobs 时才有，仅内存态）
+  seed?: number;                  // 生成这条回复时使用的随机种子（仅 assistant 消息，入库，用于复现）
+  citations?: Citation[];         // 本条回复引用的知识库片段，用于渲染脚注
+}
+
+/** 一个输出 token 的对数概率，来自后端 stream-logprobs 事件 */
+export interface TokenLogprob {
+  token: string;                   // token 文本
+  logprob: number;                 // 对数概率（≤ 0，越接近 0 越确定）
+  top: Array<{ token: string; logprob: number }>;  // 该位置概率最高的几个候选
+}
+
+/** 单条回复的性能指标，来自后端 stream-metrics 事件 */
+export interface StreamMetrics {
+  ttftMs: number | null;           // 首个 token 延迟（毫秒）
+  tokensPerSec: number | null;     // 流式阶段的生成速度
+  totalMs: number;                 // 整条回复的总耗时（毫秒，含工具调用）
+}
+
+/** 安全拦截详情，来自后端 stream-blocked 事件 */
+export interface BlockInfo {
+  stage: "prompt" | "response";    // prompt = 提示词被拦，response = 回复中途被拦
+  reason: string;                  // 服务商给出的原因，如 SAFETY、PROHIBITED_CONTENT
+  categories: string[];            // 触发拦截的安全类别
+}
+
+/** 单条回复的 token 用量，来自后端 stream-usage 事件 */
+export interface MessageUsage {
+  inputTokens: number;             // 输入 token 数
+  outputTokens: number;            // 输出 token 数
+  estimated: boolean;              // 是否为估算值（false = 服务商报告的真实用量）
 }
 
 /** 单次工具调用的状态信息，用于在消息里展示"正在调用/已完成/失败" */
@@ -70,6 +109,32 @@ export interface ChatSession {
   apiConfigId: string;           // 关联的 API 配置 ID
   provider: string;               // LLM 提供商 (如 openai, anthropic)
   model: string;                  // 模型名称 (如 gpt-4, claude-3)
+  messageCount?: number;          // 消息条数（会话列表里 messages 为空，用它显示）
+  lastMessagePreview?: string;    // 最后一条消息的开头部分
+  pinned?: boolean;               // 是否置顶（列表里排在最前）
+  archived?: boolean;             // 是否已归档（不在历史记录主列表里显示）
+  tags?: string[];                // 会话标签
+}
+
+/** 会话列表的排序方式，置顶的会话总是排在最前 */
+export type SessionSort = "updated_desc" | "updated_asc" | "created_desc" | "created_asc" | "title" | "message_count";
+
+/** get_sessions_cmd 的筛选条件，在后端 SQL 里完成；不填的条件不筛选 */
+export interface SessionFilter {
+  provider?: string | null;
+  model?: string | null;
+  updated_from?: number | null;   // 最后更新时间不早于 (毫秒)
+  updated_to?: number | null;     // 最后更新时间早于 (毫秒)
+  has_errors?: boolean | null;    // true 只要有出错回复的会话，false 只要没有的
+  tag?: string | null;
+  archived?: boolean | null;      // true 只要已归档的，false 只要没归档的
+  sort?: SessionSort;
+}
+
+/** 用过的会话标签 (list_session_tags_cmd 返回) */
+export interface SessionTagCount {
+  tag: string;
+  sessions: number;               // 带这个标签的会话数
 }
 
 /**
@@ -79,9 +144,24 @@ export interface ChatSession {
 interface StreamChunk {
   session_id: string;             // 所属会话 ID
   message_id: string;             // 消息 ID
-  content: string;                // 增量内容
-  is_thinking?: boolean;          // 是否思考过程增量（归到 thinking 字段而非正文）
+  content: string;                // 正文增量（思考过程增量时为空串）
+  reasoning?: string | null;      // 思考过程增量（归到 thinking 字段而非正文）
+  is_thinking?: boolean;          // 是否思考过程增量（旧字段，等价于 reasoning 非空）
   done: boolean;                  // 是否完成
+  error?: string | null;          // 流异常中止的原因（只出现在 done 块上，如服务商长时间无响应）
+  finish_reason?: string | null;  // 生成停止的原因（只出现在 done 块上）
+}
+
+/**
+ * 用量事件类型
+ * 从后端接收的 stream-usage 事件数据结构（先于 done 块到达）
+ */
+interface UsageEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  input_tokens: number;           // 输入 token 数
+  output_tokens: number;          // 输出 token 数
+  estimated: boolean;             // token 数是否为估算值
 }
 
 /**
@@ -98,6 +178,151 @@ interface ToolCallEvent {
   result?: string;                 // 调用结果 (JSON 字符串)
 }
 
+/**
+ * 限流状态事件类型
+ * 从后端接收的 llm-throttle 事件数据结构
+ */
+interface ThrottleEvent {
+  session_id: string;             // 所属会话 ID
+  provider: string;               // 被限流的提供商
+  waiting: boolean;               // true = 正在排队等待，false = 等待结束
+  retry_after_secs: number;       // 预计还要等待的秒数
+  reason: string;                 // 触发限流的原因
+}
+
+/**
+ * 上下文裁剪事件类型
+ * 从后端接收的 context-truncated 事件数据结构
+ */
+interface ContextTruncatedEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  dropped_messages: number;       // 被裁掉的较早消息条数
+  estimated_tokens: number;       // 裁剪前的估算 token 数
+  kept_tokens: number;            // 裁剪后的估算 token 数
+  context_window: number;         // 模型上下文窗口
+}
+
+/**
+ * logprobs 事件类型
+ * 从后端接收的 stream-logprobs 事件数据结构
+ */
+interface LogprobsEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  tokens: TokenLogprob[];         // 本次增量各 token 的对数概率
+}
+
+/**
+ * 引用类型
+ * 回复引用的一条知识库片段，index 与发给模型的"[文档 N]"编号一致
+ */
+export interface Citation {
+  index: number;                  // 脚注编号（从 1 开始）
+  chunk_id: string;               // 片段 ID
+  document_id: string;            // 所属文档 ID
+  document_filename: string;      // 所属文档文件名
+  chunk_index: number;            // 片段在文档中的序号
+  score: number;                  // 检索得分
+  content: string;                // 片段内容
+  page?: number | null;           // 所在页码（PDF、PPTX）
+  start_offset?: number | null;   // 在文档原文里的起始字符偏移
+  end_offset?: number | null;     // 在文档原文里的结束字符偏移（不含）
+  snippet?: MatchSnippet | null;  // 问题里的词命中处的摘录，用来说明片段为什么被检索到
+}
+
+/**
+ * 引用事件类型
+ * 从后端接收的 citations 事件数据结构
+ */
+interface CitationsEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  citations: Citation[];          // 本条回复引用的片段
+}
+
+/**
+ * 性能指标事件类型
+ * 从后端接收的 stream-metrics 事件数据结构
+ */
+interface MetricsEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  provider: string;               // 实际应答的提供商
+  model: string;                  // 实际应答的模型
+  ttft_ms: number | null;         // 首个 token 延迟（毫秒）
+  tokens_per_sec: number | null;  // 生成速度
+  total_ms: number;               // 总耗时（毫秒）
+}
+
+/**
+ * 并发排队事件类型
+ * 从后端接收的 stream-queue 事件数据结构
+ */
+interface StreamQueueEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  provider: string;               // 提供商
+  queued: boolean;                // true = 正在排队，false = 已轮到
+  position: number;               // 队伍中的位置（从 1 开始）
+  limit: number;                  // 当前并发上限
+}
+
+/**
+ * 安全拦截事件类型
+ * 从后端接收的 stream-blocked 事件数据结构
+ */
+interface BlockedEvent {
+  session_id: string;             // 所属会话 ID
+  message_id: string;             // 消息 ID
+  stage: "prompt" | "response";   // 被拦截的阶段
+  reason: string;                 // 拦截原因
+  categories: string[];           // 触发拦截的安全类别
+}
+
+/**
+ * 多模型对比开始事件类型
+ * 从后端接收的 arena-started 事件数据结构
+ */
+interface ArenaStartedEvent {
+  session_id: string;             // 所属会话 ID
+  slots: Array<{
+    slot: number;                 // 在请求 targets 里的序号
+    message_id: string;           // 这一栏流式事件使用的消息 ID
+    provider: string;             // 提供商
+    model: string;                // 模型名称
+  }>;
+}
+
+/** 多模型对比中的一栏回复（仅内存态，用户采用其中一个后才写进会话） */
+export interface ArenaReply {
+  slot: number;                   // 在请求 targets 里的序号
+  configId: string;               // 对应的 API 配置 ID
+  label: string;                  // 栏目标题（配置名 + 模型）
+  backendMessageId?: string;      // 后端事件里的 message_id（arena-started 之后才有）
+  message: Message;               // 这一栏的回复内容
+}
+
+/** 聊天记录检索命中的一条消息（跨所有会话，字段与后端一致） */
+export interface HistorySearchHit {
+  message_id: string;
+  session_id: string;
+  session_title: string;
+  role: string;
+  preview: string;                // 消息开头的一段预览
+  timestamp: number;
+  score: number;
+  vector_score?: number;
+  keyword_score?: number;
+}
+
+/** 加入当前会话临时索引的文件（chat_with_file，会话关闭时丢弃） */
+export interface SessionFile {
+  fileId: string;
+  filename: string;
+  chunkCount: number;             // 分块数
+}
+
 /**
  * 数据库消息类型
  * 与后端数据库结构对应的消息类型 (snake_case 命名)
@@ -108,8 +333,84 @@ interface DbMessage {
   content: string;
   timestamp: number;
   error?: string;
+  seed?: number | null;
+  generation?: DbGenerationInfo | null;
+}
+
+/**
+ * 助手消息的生成信息
+ * 与后端 GenerationInfo 对应，记录这条回复由哪个模型生成、为何结束、耗时和 token 数
+ */
+interface DbGenerationInfo {
+  provider?: string | null;
+  model?: string | null;
+  finish_reason?: string | null;
+  latency_ms?: number | null;
+  input_tokens?: number | null;
+  output_tokens?: number | null;
+  tokens_estimated: boolean;
 }
 
+/**
+ * 从助手消息收集要入库的生成信息
+ * 不知道是哪个模型生成的（用户消息、中途失败没有收到 stream-metrics 的回复）时不记录
+ */
+const generationFromMessage = (message: Message): DbGenerationInfo | null => {
+  if (message.role !== "assistant" || !message.provider || !message.model) return null;
+  return {
+    provider: message.provider,
+    model: message.model,
+    finish_reason: message.finishReason ?? null,
+    latency_ms: message.metrics?.totalMs ?? null,
+    input_tokens: message.usage?.inputTokens ?? null,
+    output_tokens: message.usage?.outputTokens ?? null,
+    tokens_estimated: message.usage?.estimated ?? false,
+  };
+};
+
+/**
+ * 把库里的生成信息还原成消息字段
+ * 首字延迟和生成速度不入库，还原后为 null
+ */
+const messageFieldsFromGeneration = (generation?: DbGenerationInfo | null): Partial<Message> => {
+  if (!generation) return {};
+  return {
+    provider: generation.provider ?? undefined,
+    model: generation.model ?? undefined,
+    finishReason: generation.finish_reason ?? undefined,
+    usage: generation.input_tokens != null && generation.output_tokens != null
+      ? {
+          inputTokens: generation.input_tokens,
+          outputTokens: generation.output_tokens,
+          estimated: generation.tokens_estimated,
+        }
+      : undefined,
+    metrics: generation.latency_ms != null
+      ? { ttftMs: null, tokensPerSec: null, totalMs: generation.latency_ms }
+      : undefined,
+  };
+};
+
+/**
+ * 会话列表项转换为前端格式 (snake_case -> camelCase)；列表项不带消息，打开会话时再读
+ */
+const sessionFromOverview = (s: DbSessionOverview): ChatSession => ({
+  id: s.id,
+  title: s.title,
+  provider: s.provider,
+  model: s.model,
+  // 如果 api_config_id 为空，使用会话 ID 作为后备 (兼容旧数据)
+  apiConfigId: s.api_config_id || s.id,
+  createdAt: s.created_at,
+  updatedAt: s.updated_at,
+  messages: [],
+  messageCount: s.message_count,
+  lastMessagePreview: s.last_message_preview ?? undefined,
+  pinned: s.pinned,
+  archived: s.archived,
+  tags: s.tags,
+});
+
 /**
  * 数据库会话类型
  * 与后端数据库结构对应的会话类型 (snake_case 命名)
@@ -125,6 +426,92 @@ interface DbSession {
   messages: DbMessage[];
 }
 
+/**
+ * 会话列表项 (get_sessions_cmd 返回)
+ * 只有元信息和最后一条消息预览，消息正文用 get_messages_cmd 分页读取
+ */
+interface DbSessionOverview {
+  id: string;
+  title: string;
+  provider: string;
+  model: string;
+  api_config_id: string;
+  created_at: number;
+  updated_at: number;
+  message_count: number;
+  last_message_preview: string | null;
+  last_message_at: number | null;
+  pinned: boolean;
+  archived: boolean;
+  tags: string[];
+}
+
+/** get_messages_cmd 返回的一页消息 */
+interface DbMessagePage {
+  messages: DbMessage[];
+  offset: number;                  // 本页第一条消息的位置（从最早的消息算起）
+  total: number;                   // 会话的消息总条数
+}
+
+/** 回收站里的会话 (list_trash 返回) */
+export interface TrashedSession {
+  id: string;
+  title: string;
+  provider: string;
+  model: string;
+  message_count: number;
+  deleted_at: number;              // 移进回收站的时间 (毫秒)
+}
+
+/** 回收站里单独删除的消息 */
+export interface TrashedMessage {
+  id: string;
+  session_id: string;
+  session_title: string;
+  role: string;
+  preview: string;                 // 消息开头部分
+  timestamp: number;
+  deleted_at: number;
+}
+
+/** 回收站内容 */
+export interface Trash {
+  sessions: TrashedSession[];
+  messages: TrashedMessage[];
+  retention_days: number;          // 保留天数，过期后自动彻底删除
+}
+
+/** 收藏夹 (list_bookmark_collections 返回) */
+export interface BookmarkCollection {
+  id: string;                      // 新建时为空字符串
+  name: string;
+  bookmarkCount: number;           // 收藏夹里的收藏数
+  createdAt: number;
+  updatedAt: number;
+}
+
+/** 收藏的消息 (list_bookmarks 返回) */
+export interface Bookmark {
+  messageId: string;
+  sessionId: string;
+  sessionTitle: string;
+  role: string;
+  preview: string;                 // 消息开头部分
+  collectionId: string | null;     // 所在收藏夹，未分组时为 null
+  note: string | null;             // 备注
+  timestamp: number;               // 消息本身的时间
+  createdAt: number;               // 收藏的时间
+}
+
+/** list_bookmarks 的筛选条件：全部、未分组或指定收藏夹 */
+export type BookmarkFilter =
+  | { kind: "all" }
+  | { kind: "ungrouped" }
+  | { kind: "collection"; id: string };
+
+/** 打开会话时每次向后端读取的消息条数 */
+const MESSAGE_PAGE_SIZE = 500;
+
 /**
  * 聊天 Store
  * 使用 Pinia 管理聊天状态和业务逻辑
@@ -159,6 +546,43 @@ export const useChatStore = defineStore("chat", () => {
   /** 工具调用状态事件监听器取消函数 */
   let unlistenToolCallFn: UnlistenFn | null = null;
 
+  /** 限流 / 并发排队提示（为空表示没有在等待），由输入框上方展示 */
+  const throttleNotice = ref<string | null>(null);
+
+  /** 并发排队事件监听器取消函数 */
+  let unlistenQueueFn: UnlistenFn | null = null;
+
+  /** 限流状态事件监听器取消函数 */
+  let unlistenThrottleFn: UnlistenFn | null = null;
+
+  /** 上下文超长、较早消息被裁掉的一次性提醒队列，由 Layout.vue watch 后弹出 */
+  const contextNotices = ref<string[]>([]);
+
+  /** 上下文裁剪事件监听器取消函数 */
+  let unlistenContextFn: UnlistenFn | null = null;
+
+  /** 引用事件监听器取消函数 */
+  let unlistenCitationsFn: UnlistenFn | null = null;
+
+  /** 用量事件监听器取消函数 */
+  let unlistenUsageFn: UnlistenFn | null = null;
+
+  /** logprobs 事件监听器取消函数 */
+  let unlistenLogprobsFn: UnlistenFn | null = null;
+  /** 安全拦截事件监听器取消函数 */
+  let unlistenBlockedFn: UnlistenFn | null = null;
+  /** 性能指标事件监听器取消函数 */
+  let unlistenMetricsFn: UnlistenFn | null = null;
+
+  /** 多模型对比：除当前配置外一起参与对比的 API 配置 ID（为空表示普通单模型模式） */
+  const arenaConfigIds = ref<string[]>([]);
+
+  /** 多模型对比：当前这一轮各模型的回复，由 ChatView 分栏并排展示 */
+  const arenaReplies = ref<ArenaReply[]>([]);
+
+  /** 多模型对比开始事件监听器取消函数 */
+  let unlistenArenaFn: UnlistenFn | null = null;
+
   /** RAG (检索增强生成) 是否启用 */
   const ragEnabled = ref(false);
   
@@ -166,7 +590,13 @@ export const useChatStore = defineStore("chat", () => {
   const selectedKnowledgeBaseId = ref<string | null>(null);
   
   /** 上一次检索结果 */
-  const lastRetrievalResult = ref<RetrievalResult | null>(null);
+  const lastCitations = ref<Citation[]>([]);
+
+  /** 当前会话绑定的知识库 ID（每一轮由后端自动检索，不用随请求传） */
+  const sessionKbIds = ref<string[]>([]);
+
+  /** 当前会话的临时文件（每一轮由后端自动检索，切换或删除会话时丢弃） */
+  const sessionFiles = ref<SessionFile[]>([]);
   
   /** MCP (Model Context Protocol) 是否启用 */
   const mcpEnabled = ref(false);
@@ -174,6 +604,9 @@ export const useChatStore = defineStore("chat", () => {
   /** 手动激活的 Skill ID 列表 */
   const activeSkillIds = ref<string[]>([]);
 
+  // 已收藏的消息 ID，消息上的星标按它显示
+  const bookmarkedMessageIds = ref<string[]>([]);
+
   /** 是否允许模型自主判断调用其它已启用的 Skill */
   const skillAutonomyEnabled = ref(false);
 
@@ -191,36 +624,18 @@ export const useChatStore = defineStore("chat", () => {
   const loadSessionsFromDb = async () => {
     try {
       // 从后端获取会话列表
-      const dbSessions = await invoke<DbSession[]>("get_sessions_cmd");
+      const dbSessions = await invoke<DbSessionOverview[]>("get_sessions_cmd");
       console.log("[Chat] get_sessions_cmd returned:", dbSessions.length, "sessions");
       
-      // 转换为前端格式 (snake_case -> camelCase)
-      sessions.value = dbSessions.map(s => ({
-        id: s.id,
-        title: s.title,
-        provider: s.provider,
-        model: s.model,
-        // 如果 api_config_id 为空，使用会话 ID 作为后备 (兼容旧数据)
-        apiConfigId: s.api_config_id || s.id,
-        createdAt: s.created_at,
-        updatedAt: s.updated_at,
-        messages: s.messages.map(m => ({
-          id: m.id,
-          role: m.role as "user" | "assistant" | "system",
-          content: m.content,
-          timestamp: m.timestamp,
-          error: m.error,
-        })),
-      }));
-      console.log("[Chat] sessions.value updated, first session messages:", sessions.value[0]?.messages?.length);
+      sessions.value = dbSessions.map(sessionFromOverview);
+      void loadBookmarkedIds();
       
-      // 如果有当前会话，同步更新当前会话的数据
+      // 如果有当前会话，同步更新当前会话的元信息（消息仍用内存里已加载的）
       if (currentSession.value) {
         const currentId = String(currentSession.value.id);
         const freshCurrent = sessions.value.find(s => String(s.id) === currentId);
         if (freshCurrent) {
-          currentSession.value = { ...freshCurrent };
-          console.log("[Chat] Updated currentSession with fresh data, messages:", freshCurrent.messages.length);
+          currentSession.value = { ...freshCurrent, messages: currentSession.value.messages };
         }
       }
     } catch (error) {
@@ -228,6 +643,34 @@ export const useChatStore = defineStore("chat", () => {
     }
   };
 
+  /**
+   * 读取指定会话的全部消息
+   * 按页调用 get_messages_cmd，避免一次 IPC 传回过大的消息列表
+   *
+   * @param sessionId - 会话 ID
+   * @returns 按时间升序排列的消息
+   */
+  const fetchSessionMessages = async (sessionId: string): Promise<Message[]> => {
+    const messages: Message[] = [];
+    for (;;) {
+      const page = await invoke<DbMessagePage>("get_messages_cmd", {
+        sessionId,
+        offset: messages.length,
+        limit: MESSAGE_PAGE_SIZE,
+      });
+      messages.push(...page.messages.map(m => ({
+        id: m.id,
+        role: m.role as "user" | "assistant" | "system",
+        content: m.content,
+        timestamp: m.timestamp,
+        error: m.error,
+        seed: m.seed ?? undefined,
+        ...messageFieldsFromGeneration(m.generation),
+      })));
+      if (page.messages.length === 0 || messages.length >= page.total) return messages;
+    }
+  };
+
   /**
    * 设置流式响应监听器
    * 监听后端发送的 stream-chunk 事件
@@ -254,17 +697,32 @@ export const useChatStore = defineStore("chat", () => {
       const currentId = String(currentSession.value.id);
       const chunkId = String(chunk.session_id);
 
+      // 多模型对比的数据块按 message_id 分到各自的栏目
+      const arenaReply = arenaReplies.value.find(r => r.backendMessageId === chunk.message_id);
+      if (arenaReply) {
+        applyArenaChunk(arenaReply, chunk);
+        return;
+      }
+
       // 处理流结束信号
       if (chunk.done) {
         console.log("[Stream] Stream done for session:", chunkId);
         
         // 无论消息是否存在，都要重置加载状态
         isLoading.value = false;
+        throttleNotice.value = null;
         currentStreamContent.value = "";
         
         const lastMessage = currentSession.value.messages[currentSession.value.messages.length - 1];
         if (lastMessage && lastMessage.role === "assistant") {
           lastMessage.streaming = false;
+          // 已输出的部分内容保留，同时标出中止原因
+          if (chunk.error) {
+            lastMessage.error = chunk.error;
+          }
+          if (chunk.finish_reason) {
+            lastMessage.finishReason = chunk.finish_reason;
+          }
           console.log("[Stream] Saving message to DB:", lastMessage.id, "content length:", lastMessage.content.length);
           await saveMessageToDb(lastMessage);
           await saveSessionToDb();
@@ -293,8 +751,8 @@ export const useChatStore = defineStore("chat", () => {
 
       // 累加内容 (打字机效果)。思考型模型的思考增量单独归到 thinking 字段，
       // 由 ChatMessage.vue 的"思考过程"折叠区展示，不混入正文、也不入库
-      if (chunk.is_thinking) {
-        lastMessage.thinking = (lastMessage.thinking ?? "") + chunk.content;
+      if (chunk.reasoning != null || chunk.is_thinking) {
+        lastMessage.thinking = (lastMessage.thinking ?? "") + (chunk.reasoning ?? chunk.content);
       } else {
         lastMessage.content += chunk.content;
         currentStreamContent.value = lastMessage.content;
@@ -319,11 +777,10 @@ export const useChatStore = defineStore("chat", () => {
       if (!currentSession.value) return;
       if (String(evt.session_id) !== String(currentSession.value.id)) return;
 
-      // 后端在 stream_message 里自行生成 message_id，与前端占位 assistant
-      // 消息的 id 并不相同（文本流的 stream-chunk 监听器同样只按 session
-      // 匹配，所以文本不受影响）。按 id 找不到时回退到最后一条 assistant
-      // 消息——否则工具调用状态永远挂不上任何消息，工具明明执行了，界面
-      // 上却看不到"调用中/已完成"。
+      // stream_message 沿用前端占位 assistant 消息的 id（replyMessageId），
+      // 但多模型对比等路径的 message_id 是后端生成的。按 id 找不到时回退到
+      // 最后一条 assistant 消息——否则工具调用状态永远挂不上任何消息，工具
+      // 明明执行了，界面上却看不到"调用中/已完成"。
       const message = currentSession.value.messages.find(m => m.id === evt.message_id)
         ?? [...currentSession.value.messages].reverse().find(m => m.role === "assistant");
       if (!message) return;
@@ -348,104 +805,369 @@ export const useChatStore = defineStore("chat", () => {
   };
 
   /**
-   * 保存当前会话到数据库
-   * 包含会话基本信息，不包含消息内容
-   * 
+   * 设置限流状态监听器
+   * 监听后端发送的 llm-throttle 事件：服务商返回 429/Retry-After 时请求会在
+   * 后端排队，这里把"正在等待"展示出来，而不是让用户对着没反应的界面干等
+   *
    * @returns void
    */
-  const saveSessionToDb = async () => {
-    if (!currentSession.value) return;
-    
-    try {
-      const dbSession: DbSession = {
-        id: currentSession.value.id,
-        title: currentSession.value.title,
-        provider: currentSession.value.provider,
-        model: currentSession.value.model,
-        api_config_id: currentSession.value.apiConfigId,  // 保存 API 配置关联
-        created_at: currentSession.value.createdAt,
-        updated_at: Date.now(),
-        messages: [],
-      };
-      await invoke("save_session_cmd", { session: dbSession });
-    } catch (error) {
-      console.error("Failed to save session:", error);
-      dbSaveErrorNotices.value.push(`会话保存失败：${classifyError(error).message}`);
+  const setupThrottleListener = async () => {
+    if (unlistenThrottleFn) {
+      unlistenThrottleFn();
     }
+
+    unlistenThrottleFn = await listen<ThrottleEvent>("llm-throttle", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      throttleNotice.value = evt.waiting
+        ? `${evt.provider} 暂时限流，约 ${evt.retry_after_secs} 秒后自动重试…`
+        : null;
+    });
   };
 
   /**
-   * 保存单条消息到数据库
-   * 
-   * @param message - 要保存的消息对象
+   * 设置并发排队监听器
+   * 同一服务商同时进行的回复数达到上限时后端会让新请求排队，这里显示排在第几位
+   *
    * @returns void
    */
-  const saveMessageToDb = async (message: Message) => {
-    if (!currentSession.value) return;
-    
-    try {
-      const dbMessage: DbMessage = {
-        id: message.id,
-        role: message.role,
-        content: message.content,
-        timestamp: message.timestamp,
-        error: message.error,
-      };
-      await invoke("save_message_cmd", {
-        sessionId: currentSession.value.id,
-        message: dbMessage
-      });
-    } catch (error) {
-      console.error("Failed to save message:", error);
-      dbSaveErrorNotices.value.push(`消息保存失败：${classifyError(error).message}`);
+  const setupQueueListener = async () => {
+    if (unlistenQueueFn) {
+      unlistenQueueFn();
     }
+
+    unlistenQueueFn = await listen<StreamQueueEvent>("stream-queue", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      throttleNotice.value = evt.queued
+        ? `${evt.provider} 同时进行的回复已达上限（${evt.limit} 个），正在排队，前面还有 ${evt.position - 1} 个…`
+        : null;
+    });
   };
 
   /**
-   * 创建新会话
-   * 
-   * @param apiConfigId - API 配置 ID
-   * @returns 新创建的会话对象，失败返回 null
+   * 设置上下文裁剪监听器
+   * 会话超出模型上下文窗口时后端会裁掉最早的消息再发送，这里提醒用户
+   * 模型"看不到"那部分内容了
+   *
+   * @returns void
    */
-  const createSession = async (apiConfigId: string): Promise<ChatSession | null> => {
-    // 查找对应的 API 配置
-    const config = settings.apiConfigs.find(c => c.id === apiConfigId);
-    if (!config) {
-      console.error("API config not found:", apiConfigId);
-      return null;
+  const setupContextListener = async () => {
+    if (unlistenContextFn) {
+      unlistenContextFn();
     }
 
-    // 如果有正在进行的流，先停止它
-    if (isLoading.value) {
-      console.log("[Chat] Stopping existing stream before creating new session");
-      isLoading.value = false;
-      currentStreamContent.value = "";
-    }
-    
-    // 构建新会话对象
-    const session: ChatSession = {
-      id: crypto.randomUUID(),
-      title: "新对话",
-      messages: [],
-      createdAt: Date.now(),
-      updatedAt: Date.now(),
-      apiConfigId,
-      provider: config.provider,
-      model: config.model,
-    };
-    
-    // 设置为当前会话
-    currentSession.value = session;
-    lastRetrievalResult.value = null;
-    
-    // 设置流式监听
-    // 注意：这里不写库。空会话在发出第一条消息前只存在于内存里，
-    // sendMessage() 里已经会在追加第一条用户消息后调用 saveSessionToDb()，
-    // 否则每次点"新建对话"都会在历史记录里留下一条"新对话/0条消息"的僵尸记录
-    await setupStreamListener();
-    await setupToolCallListener();
+    unlistenContextFn = await listen<ContextTruncatedEvent>("context-truncated", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
 
-    return session;
+      contextNotices.value.push(
+        `对话已超出模型上下文窗口（约 ${evt.context_window} token），最早的 ${evt.dropped_messages} 条消息本次未发送给模型。`
+      );
+    });
+  };
+
+  /**
+   * 设置用量监听器
+   * 把后端报告的 token 用量挂到当前流式消息上，ChatMessage.vue 据此显示
+   * 服务商给出的真实 token 数（没有时仍显示前端估算值）
+   *
+   * @returns void
+   */
+  const setupUsageListener = async () => {
+    if (unlistenUsageFn) {
+      unlistenUsageFn();
+    }
+
+    unlistenUsageFn = await listen<UsageEvent>("stream-usage", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      const arenaReply = arenaReplies.value.find(r => r.backendMessageId === evt.message_id);
+      const lastMessage = arenaReply?.message
+        ?? currentSession.value.messages[currentSession.value.messages.length - 1];
+      if (!lastMessage || lastMessage.role !== "assistant") return;
+      lastMessage.usage = {
+        inputTokens: evt.input_tokens,
+        outputTokens: evt.output_tokens,
+        estimated: evt.estimated,
+      };
+    });
+  };
+
+  /**
+   * 设置 logprobs 监听器
+   * 把后端随正文增量发来的 token 对数概率累积到对应消息上，
+   * ChatMessage.vue 据此提供按置信度着色的视图
+   *
+   * @returns void
+   */
+  const setupLogprobsListener = async () => {
+    if (unlistenLogprobsFn) {
+      unlistenLogprobsFn();
+    }
+
+    unlistenLogprobsFn = await listen<LogprobsEvent>("stream-logprobs", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      const arenaReply = arenaReplies.value.find(r => r.backendMessageId === evt.message_id);
+      const lastMessage = arenaReply?.message
+        ?? currentSession.value.messages[currentSession.value.messages.length - 1];
+      if (!lastMessage || lastMessage.role !== "assistant") return;
+      lastMessage.logprobs = [...(lastMessage.logprobs ?? []), ...evt.tokens];
+    });
+  };
+
+  /**
+   * 设置安全拦截监听器
+   * 服务商（目前是 Gemini）因安全策略拦截提示词或回复时，把原因和类别记到
+   * 对应消息上，ChatMessage.vue 据此给出比"内容被拦截"更具体的提示
+   *
+   * @returns void
+   */
+  const setupBlockedListener = async () => {
+    if (unlistenBlockedFn) {
+      unlistenBlockedFn();
+    }
+
+    unlistenBlockedFn = await listen<BlockedEvent>("stream-blocked", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      const arenaReply = arenaReplies.value.find(r => r.backendMessageId === evt.message_id);
+      const lastMessage = arenaReply?.message
+        ?? currentSession.value.messages[currentSession.value.messages.length - 1];
+      if (!lastMessage || lastMessage.role !== "assistant") return;
+      lastMessage.blocked = { stage: evt.stage, reason: evt.reason, categories: evt.categories };
+    });
+  };
+
+  /**
+   * 设置引用监听器
+   * 后端用知识库内容回答时，在流式输出开始前发出命中的片段，挂到对应回复上渲染脚注
+   *
+   * @returns void
+   */
+  const setupCitationsListener = async () => {
+    if (unlistenCitationsFn) {
+      unlistenCitationsFn();
+    }
+
+    unlistenCitationsFn = await listen<CitationsEvent>("citations", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      lastCitations.value = evt.citations;
+      const arenaReply = arenaReplies.value.find(r => r.backendMessageId === evt.message_id);
+      const lastMessage = arenaReply?.message
+        ?? currentSession.value.messages[currentSession.value.messages.length - 1];
+      if (!lastMessage || lastMessage.role !== "assistant") return;
+      lastMessage.citations = evt.citations;
+    });
+  };
+
+  /**
+   * 设置性能指标监听器
+   * 每条回复结束时后端报告首字延迟、生成速度和总耗时，便于比较不同服务商
+   *
+   * @returns void
+   */
+  const setupMetricsListener = async () => {
+    if (unlistenMetricsFn) {
+      unlistenMetricsFn();
+    }
+
+    unlistenMetricsFn = await listen<MetricsEvent>("stream-metrics", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      const arenaReply = arenaReplies.value.find(r => r.backendMessageId === evt.message_id);
+      const lastMessage = arenaReply?.message
+        ?? currentSession.value.messages[currentSession.value.messages.length - 1];
+      if (!lastMessage || lastMessage.role !== "assistant") return;
+      lastMessage.metrics = {
+        ttftMs: evt.ttft_ms,
+        tokensPerSec: evt.tokens_per_sec,
+        totalMs: evt.total_ms,
+      };
+      lastMessage.provider = evt.provider;
+      lastMessage.model = evt.model;
+    });
+  };
+
+  /**
+   * 设置多模型对比监听器
+   * arena-started 事件给出每一栏对应的后端 message_id，之后的 stream-chunk
+   * 据此分流到各自的栏目
+   *
+   * @returns void
+   */
+  const setupArenaListener = async () => {
+    if (unlistenArenaFn) {
+      unlistenArenaFn();
+    }
+
+    unlistenArenaFn = await listen<ArenaStartedEvent>("arena-started", (event) => {
+      const evt = event.payload;
+      if (!currentSession.value) return;
+      if (String(evt.session_id) !== String(currentSession.value.id)) return;
+
+      for (const slot of evt.slots) {
+        const reply = arenaReplies.value.find(r => r.slot === slot.slot);
+        if (reply) reply.backendMessageId = slot.message_id;
+      }
+    });
+  };
+
+  /**
+   * 把一个数据块写进对比栏目；所有栏目都结束后退出加载状态
+   *
+   * @param reply - 目标栏目
+   * @param chunk - 后端数据块
+   * @returns void
+   */
+  const applyArenaChunk = (reply: ArenaReply, chunk: StreamChunk) => {
+    const message = reply.message;
+    if (chunk.done) {
+      message.streaming = false;
+      if (chunk.error) message.error = chunk.error;
+      if (chunk.finish_reason) message.finishReason = chunk.finish_reason;
+      if (arenaReplies.value.every(r => !r.message.streaming)) {
+        isLoading.value = false;
+        throttleNotice.value = null;
+      }
+      return;
+    }
+    if (chunk.reasoning != null || chunk.is_thinking) {
+      message.thinking = (message.thinking ?? "") + (chunk.reasoning ?? chunk.content);
+    } else {
+      message.content += chunk.content;
+    }
+  };
+
+  /**
+   * 保存当前会话到数据库
+   * 包含会话基本信息，不包含消息内容
+   * 
+   * @returns void
+   */
+  const saveSessionToDb = async () => {
+    if (!currentSession.value) return;
+    
+    try {
+      const dbSession: DbSession = {
+        id: currentSession.value.id,
+        title: currentSession.value.title,
+        provider: currentSession.value.provider,
+        model: currentSession.value.model,
+        api_config_id: currentSession.value.apiConfigId,  // 保存 API 配置关联
+        created_at: currentSession.value.createdAt,
+        updated_at: Date.now(),
+        messages: [],
+      };
+      await invoke("save_session_cmd", { session: dbSession });
+    } catch (error) {
+      console.error("Failed to save session:", error);
+      dbSaveErrorNotices.value.push(`会话保存失败：${classifyError(error).message}`);
+    }
+  };
+
+  /**
+   * 保存单条消息到数据库
+   * 
+   * @param message - 要保存的消息对象
+   * @returns void
+   */
+  const saveMessageToDb = async (message: Message) => {
+    if (!currentSession.value) return;
+    
+    try {
+      const dbMessage: DbMessage = {
+        id: message.id,
+        role: message.role,
+        content: message.content,
+        timestamp: message.timestamp,
+        error: message.error,
+        seed: message.seed ?? null,
+        generation: generationFromMessage(message),
+      };
+      await invoke("save_message_cmd", {
+        sessionId: currentSession.value.id,
+        message: dbMessage
+      });
+    } catch (error) {
+      console.error("Failed to save message:", error);
+      dbSaveErrorNotices.value.push(`消息保存失败：${classifyError(error).message}`);
+    }
+  };
+
+  /**
+   * 创建新会话
+   * 
+   * @param apiConfigId - API 配置 ID
+   * @returns 新创建的会话对象，失败返回 null
+   */
+  const createSession = async (apiConfigId: string): Promise<ChatSession | null> => {
+    // 查找对应的 API 配置
+    const config = settings.apiConfigs.find(c => c.id === apiConfigId);
+    if (!config) {
+      console.error("API config not found:", apiConfigId);
+      return null;
+    }
+
+    // 如果有正在进行的流，先停止它
+    if (isLoading.value) {
+      console.log("[Chat] Stopping existing stream before creating new session");
+      isLoading.value = false;
+      currentStreamContent.value = "";
+    }
+    
+    // 构建新会话对象
+    const session: ChatSession = {
+      id: crypto.randomUUID(),
+      title: "新对话",
+      messages: [],
+      createdAt: Date.now(),
+      updatedAt: Date.now(),
+      apiConfigId,
+      provider: config.provider,
+      model: config.model,
+    };
+    
+    // 设置为当前会话
+    discardSessionFiles();
+    currentSession.value = session;
+    lastCitations.value = [];
+    sessionKbIds.value = [];
+    
+    // 设置流式监听
+    // 注意：这里不写库。空会话在发出第一条消息前只存在于内存里，
+    // sendMessage() 里已经会在追加第一条用户消息后调用 saveSessionToDb()，
+    // 否则每次点"新建对话"都会在历史记录里留下一条"新对话/0条消息"的僵尸记录
+    await setupStreamListener();
+    await setupToolCallListener();
+    await setupThrottleListener();
+    await setupQueueListener();
+    await setupContextListener();
+    await setupUsageListener();
+    await setupCitationsListener();
+    await setupArenaListener();
+    await setupLogprobsListener();
+    await setupBlockedListener();
+    await setupMetricsListener();
+
+    return session;
   };
 
   /**
@@ -460,44 +1182,49 @@ export const useChatStore = defineStore("chat", () => {
     // 清理之前的状态
     isLoading.value = false;
     currentStreamContent.value = "";
+    arenaReplies.value = [];
     
-    // 尝试从数据库重新加载会话数据（确保消息最新）
+    // 会话列表项不带消息，从数据库读取这个会话的消息
     let sessionWithMessages = session;
     try {
-      const dbSessions = await invoke<DbSession[]>("get_sessions_cmd");
-      console.log("[Chat] Loaded sessions from DB:", dbSessions.length);
-      const freshSession = dbSessions.find(s => String(s.id) === String(session.id));
-      console.log("[Chat] Found fresh session:", freshSession?.id, "messages:", freshSession?.messages?.length);
-      if (freshSession) {
-        // 创建新对象确保响应式更新，使用数据库中的最新数据
-        sessionWithMessages = {
-          id: freshSession.id,
-          title: freshSession.title,
-          provider: freshSession.provider,
-          model: freshSession.model,
-          apiConfigId: freshSession.api_config_id || freshSession.id,
-          createdAt: freshSession.created_at,
-          updatedAt: freshSession.updated_at,
-          messages: freshSession.messages.map(m => ({
-            id: m.id,
-            role: m.role as "user" | "assistant" | "system",
-            content: m.content,
-            timestamp: m.timestamp,
-            error: m.error,
-          }))
-        };
-        console.log("[Chat] Created new session object with messages:", sessionWithMessages.messages.length);
+      const messages = await fetchSessionMessages(session.id);
+      // 创建新对象确保响应式更新，使用数据库中的最新数据
+      sessionWithMessages = { ...session, messages, messageCount: messages.length };
+      console.log("[Chat] Created new session object with messages:", sessionWithMessages.messages.length);
+
+      // 把回复引用过的知识库片段挂回对应消息
+      const citations = await invoke<Record<string, Citation[]>>("get_message_sources_cmd", { sessionId: session.id });
+      for (const message of sessionWithMessages.messages) {
+        if (citations[message.id]) message.citations = citations[message.id];
       }
     } catch (error) {
       console.warn("Failed to reload session from DB, using cached data:", error);
     }
     
     // 设置当前会话并设置流式监听器
+    if (currentSession.value?.id !== sessionWithMessages.id) {
+      discardSessionFiles();
+    }
     currentSession.value = sessionWithMessages;
-    lastRetrievalResult.value = null;
+    lastCitations.value = [];
+    try {
+      sessionKbIds.value = await invoke<string[]>("list_session_kbs", { sessionId: sessionWithMessages.id });
+    } catch (error) {
+      console.warn("Failed to load session knowledge bases:", error);
+      sessionKbIds.value = [];
+    }
     console.log("[Chat] currentSession set, messages:", currentSession.value?.messages?.length);
     await setupStreamListener();
     await setupToolCallListener();
+    await setupThrottleListener();
+    await setupQueueListener();
+    await setupContextListener();
+    await setupUsageListener();
+    await setupCitationsListener();
+    await setupArenaListener();
+    await setupLogprobsListener();
+    await setupBlockedListener();
+    await setupMetricsListener();
   };
 
   /**
@@ -526,7 +1253,7 @@ export const useChatStore = defineStore("chat", () => {
 
     // 检查 API 密钥是否已加载
     // Local models don't require API keys
-    if (config.provider !== "local" && !config.apiKey) {
+    if (config.provider !== "local" && !config.apiKey && !config.keyProfile) {
       console.error("API key not loaded for config:", config.id);
       alert("API 密钥未加载，请重启应用或重新设置");
       return null;
@@ -535,6 +1262,62 @@ export const useChatStore = defineStore("chat", () => {
     return config;
   };
 
+  /**
+   * 本次生成使用的随机种子：设置里开启后优先用固定 seed，否则每次随机生成一个，
+   * 随回复一起入库，之后把它填进固定 seed 就能复现这条回复
+   *
+   * @returns 种子；未开启时为 undefined（不传给后端）
+   */
+  const pickSeed = (): number | undefined => {
+    if (!settings.seedEnabled) return undefined;
+    return settings.fixedSeed ?? Math.floor(Math.random() * 2 ** 31);
+  };
+
+  /**
+   * 构建发给后端的消息列表：去掉流式中/出错的消息，替换 RAG 增强后的提问，
+   * 并把全局 System Prompt 并入开头
+   *
+   * @param contentOverride - 只替换发给模型的某条消息内容（RAG/文档增强）
+   * @returns 后端 ChatMessage 结构的消息列表
+   */
+  const buildApiMessages = (contentOverride?: { messageId: string; content: string }) => {
+    const apiMessages = (currentSession.value?.messages ?? [])
+      // 过滤掉流式中和有错误的消息
+      .filter(m => !m.streaming && !m.error)
+      .map((m) => ({
+        id: m.id,
+        role: m.role,
+        content: (contentOverride && m.id === contentOverride.messageId) ? contentOverride.content : m.content,
+        timestamp: m.timestamp,
+        error: m.error,
+        images: m.images ?? [],
+        videos: m.videos ?? [],
+      }));
+
+    // ============ 全局 System Prompt ============
+    const globalSystemPrompt = settings.systemPrompt.trim();
+    if (globalSystemPrompt) {
+      if (apiMessages.length > 0 && apiMessages[0].role === "system") {
+        apiMessages[0] = {
+          ...apiMessages[0],
+          content: globalSystemPrompt + "\n\n" + apiMessages[0].content,
+        };
+      } else {
+        apiMessages.unshift({
+          id: crypto.randomUUID(),
+          role: "system",
+          content: globalSystemPrompt,
+          timestamp: Date.now(),
+          error: undefined,
+          images: [],
+          videos: [],
+        });
+      }
+    }
+
+    return apiMessages;
+  };
+
   /**
    * 基于当前会话已有的消息列表向 LLM 请求一次新回复 (核心生成函数)
    * 发送新消息、编辑用户消息后重新生成、点击"重新生成"共用这一段逻辑——
@@ -564,43 +1347,11 @@ export const useChatStore = defineStore("chat", () => {
         content: "",
         timestamp: Date.now(),
         streaming: true,
+        seed: pickSeed(),
       };
       currentSession.value.messages.push(assistantMessage);
 
-      // ============ 构建 API 消息列表 ============
-      const apiMessages = currentSession.value.messages
-        // 过滤掉流式中和有错误的消息
-        .filter(m => !m.streaming && !m.error)
-        .map((m) => ({
-          id: m.id,
-          role: m.role,
-          content: (contentOverride && m.id === contentOverride.messageId) ? contentOverride.content : m.content,
-          timestamp: m.timestamp,
-          error: m.error,
-          images: m.images ?? [],
-          videos: m.videos ?? [],
-        }));
-
-      // ============ 全局 System Prompt ============
-      const globalSystemPrompt = settings.systemPrompt.trim();
-      if (globalSystemPrompt) {
-        if (apiMessages.length > 0 && apiMessages[0].role === "system") {
-          apiMessages[0] = {
-            ...apiMessages[0],
-            content: globalSystemPrompt + "\n\n" + apiMessages[0].content,
-          };
-        } else {
-          apiMessages.unshift({
-            id: crypto.randomUUID(),
-            role: "system",
-            content: globalSystemPrompt,
-            timestamp: Date.now(),
-            error: undefined,
-            images: [],
-            videos: [],
-          });
-        }
-      }
+      const apiMessages = buildApiMessages(contentOverride);
 
       // ============ 构建请求 payload ============
       // MCP 工具不再以文本形式塞进 system prompt——后端会在 enableMcp 开启时
@@ -614,15 +1365,31 @@ export const useChatStore = defineStore("chat", () => {
         // 持久化会剥掉 apiKey，重启后 keyring 里没有条目的配置（本地模型）读到
         // 的是 undefined——必须兜底成空串，否则 invoke 参数反序列化直接报
         // "missing field apiKey"。空串对后端是合法值（本地免鉴权/keyring 兜底）。
+        // 后端先用档案（配置选的，其次该 provider 的默认档案），档案没有密钥时才用这里的 apiKey。
         apiKey: config.apiKey ?? "",
         baseUrl: config.baseUrl,
+        customAuth: toCustomAuth(config),
+        keyPool: config.keyPoolSize ? config.id : null,
+        keyRotation: config.keyRotation ?? "round_robin",
+        apiKeyProfile: config.keyProfile ?? null,
+        safetySettings: toSafetySettings(config),
         enableMcp: mcpEnabled.value,
         activeSkillIds: activeSkillIds.value,
         enableSkillAutonomy: skillAutonomyEnabled.value,
         enableThinking: thinkingEnabled.value,
         maxTokens: config.maxTokens ?? null,
+        thinkingBudget: config.thinkingBudget ?? null,
+        contextWindow: config.contextWindow ?? null,
         retryCount: settings.retryCount,
         retryIntervalSecs: settings.retryIntervalSecs,
+        logprobs: settings.logprobsEnabled,
+        topLogprobs: settings.topLogprobs,
+        seed: assistantMessage.seed ?? null,
+        summarizeHistory: settings.summarizeHistory,
+        // 后端据此在生成过程中定期把已输出的内容存进数据库，应用中途关闭也不丢
+        replyMessageId: assistantMessage.id,
+        replyTimestamp: assistantMessage.timestamp,
+        ...buildRagPayload(),
       };
 
       // 开发模式下打印调试日志 (隐藏 API 密钥)
@@ -694,10 +1461,114 @@ export const useChatStore = defineStore("chat", () => {
 
       console.error(`[${errorInfo.type}] ${error}`);
       isLoading.value = false;
+      throttleNotice.value = null;
       currentStreamContent.value = "";
     }
   };
 
+  /**
+   * 多模型对比：把同一轮提问同时发给当前配置和 arenaConfigIds 里的配置
+   * 各栏回复先只放在 arenaReplies 里，用户采用其中一个（adoptArenaReply）后
+   * 才作为正式回复写进会话，会话历史因此始终是一问一答
+   *
+   * @param contentOverride - 只替换发给模型的某条消息内容（RAG/文档增强）
+   * @returns void
+   */
+  const generateArenaReplies = async (contentOverride?: { messageId: string; content: string }) => {
+    if (!currentSession.value) return;
+
+    const primary = resolveActiveConfig();
+    if (!primary) return;
+    const configs = [primary, ...arenaConfigIds.value
+      .filter(id => id !== primary.id)
+      .map(id => settings.apiConfigs.find(c => c.id === id))
+      .filter((c): c is NonNullable<typeof c> => Boolean(c))]
+      .slice(0, 4);
+
+    const seed = pickSeed();
+    arenaReplies.value = configs.map((config, slot) => ({
+      slot,
+      configId: config.id,
+      label: `${config.name} · ${config.model}`,
+      message: {
+        id: crypto.randomUUID(),
+        role: "assistant",
+        content: "",
+        timestamp: Date.now(),
+        streaming: true,
+        seed,
+      },
+    }));
+    isLoading.value = true;
+
+    const requestPayload = {
+      sessionId: currentSession.value.id,
+      messages: buildApiMessages(contentOverride),
+      provider: primary.provider,
+      model: primary.model,
+      apiKey: primary.apiKey ?? "",
+      baseUrl: primary.baseUrl,
+      customAuth: toCustomAuth(primary),
+      keyPool: primary.keyPoolSize ? primary.id : null,
+      keyRotation: primary.keyRotation ?? "round_robin",
+      safetySettings: toSafetySettings(primary),
+      enableMcp: mcpEnabled.value,
+      activeSkillIds: activeSkillIds.value,
+      enableSkillAutonomy: skillAutonomyEnabled.value,
+      enableThinking: thinkingEnabled.value,
+      maxTokens: primary.maxTokens ?? null,
+      thinkingBudget: primary.thinkingBudget ?? null,
+      retryCount: settings.retryCount,
+      retryIntervalSecs: settings.retryIntervalSecs,
+      logprobs: settings.logprobsEnabled,
+      topLogprobs: settings.topLogprobs,
+      seed: seed ?? null,
+      ...buildRagPayload(),
+      targets: configs.map(c => ({
+        provider: c.provider,
+        model: c.model,
+        baseUrl: c.baseUrl,
+        apiKey: c.apiKey ?? "",
+        apiConfigId: c.id,
+        customAuth: toCustomAuth(c),
+      })),
+    };
+
+    try {
+      await invoke("stream_message_multi", { request: requestPayload });
+    } catch (error) {
+      const errorInfo = classifyError(error);
+      for (const reply of arenaReplies.value) {
+        if (reply.message.streaming) {
+          reply.message.streaming = false;
+          reply.message.error = errorInfo.message;
+        }
+      }
+      console.error(`[${errorInfo.type}] ${error}`);
+    } finally {
+      isLoading.value = false;
+      throttleNotice.value = null;
+    }
+  };
+
+  /**
+   * 采用多模型对比中的某一栏回复：写进会话并保存，其余栏目丢弃
+   *
+   * @param slot - 栏目序号
+   * @returns void
+   */
+  const adoptArenaReply = async (slot: number) => {
+    if (!currentSession.value || isLoading.value) return;
+    const reply = arenaReplies.value.find(r => r.slot === slot);
+    if (!reply || reply.message.error) return;
+
+    currentSession.value.messages.push(reply.message);
+    currentSession.value.updatedAt = Date.now();
+    arenaReplies.value = [];
+    await saveMessageToDb(reply.message);
+    await saveSessionToDb();
+  };
+
   /**
    * 发送消息 (核心函数)
    * 处理用户消息发送、LLM 调用、流式响应等完整流程
@@ -718,6 +1589,9 @@ export const useChatStore = defineStore("chat", () => {
     if (!currentSession.value) return;
     if (!resolveActiveConfig()) return;
 
+    // 上一轮没有被采用的对比回复作废
+    arenaReplies.value = [];
+
     // 初始化内容变量
     let enhancedContent = content;
 
@@ -728,31 +1602,12 @@ export const useChatStore = defineStore("chat", () => {
       docContext = `[用户附加文档]\n${docParts.join('\n---\n')}`;
     }
 
-    // ============ RAG 检索增强 ============
-    let retrievalContext = "";
-    if (ragEnabled.value && selectedKnowledgeBaseId.value) {
-      const kb = kbStore.knowledgeBases.find(k => k.id === selectedKnowledgeBaseId.value);
-      if (kb) {
-        const result = await kbStore.searchKnowledgeBase(
-          selectedKnowledgeBaseId.value,
-          content
-        );
-        if (result && result.chunks.length > 0) {
-          lastRetrievalResult.value = result;
-          retrievalContext = buildRagContext(result);
-        }
-      }
-    }
-
-    // 合并上下文，构建最终发送内容
-    const contextParts: string[] = [];
-    if (retrievalContext) contextParts.push(retrievalContext);
-    if (docContext) contextParts.push(docContext);
-    if (contextParts.length > 0) {
-      enhancedContent = `${contextParts.join('\n\n')}\n\n问题：${content}`;
+    // 知识库检索（RAG）由后端在发请求前完成，见 buildRagPayload
+    if (docContext) {
+      enhancedContent = `${docContext}\n\n问题：${content}`;
     }
 
-    // 构建用户消息对象——聊天气泡展示原始输入，RAG/文档增强内容只通过
+    // 构建用户消息对象——聊天气泡展示原始输入，文档增强内容只通过
     // generateReply 的 contentOverride 参数注入发给模型的那份拷贝，不写进
     // 消息本身（写进去用户编辑这条消息时会看到一堆检索上下文，体验很差）
     const userMessage: Message = {
@@ -773,9 +1628,13 @@ export const useChatStore = defineStore("chat", () => {
     await saveSessionToDb();
     await saveMessageToDb(userMessage);
 
-    await generateReply(
-      enhancedContent !== content ? { messageId: userMessage.id, content: enhancedContent } : undefined
-    );
+    const contentOverride =
+      enhancedContent !== content ? { messageId: userMessage.id, content: enhancedContent } : undefined;
+    if (arenaConfigIds.value.length > 0) {
+      await generateArenaReplies(contentOverride);
+    } else {
+      await generateReply(contentOverride);
+    }
   };
 
   /**
@@ -834,6 +1693,57 @@ export const useChatStore = defineStore("chat", () => {
     await generateReply();
   };
 
+  /**
+   * 只修改一条消息的文字，不截断后面的消息、不重新生成
+   * 用于修正 AI 回复里的错误、精简用户消息等，改好再继续对话
+   *
+   * @param messageId - 要修改的消息 ID
+   * @param newContent - 修改后的内容
+   * @returns 是否成功
+   */
+  const updateMessage = async (messageId: string, newContent: string): Promise<boolean> => {
+    if (!currentSession.value || isLoading.value) return false;
+    const target = currentSession.value.messages.find(m => m.id === messageId);
+    const trimmed = newContent.trim();
+    if (!target || !trimmed) return false;
+
+    try {
+      await invoke("update_message_cmd", { messageId, content: trimmed });
+    } catch (error) {
+      console.error("Failed to update message:", error);
+      dbSaveErrorNotices.value.push(`消息修改失败：${classifyError(error).message}`);
+      return false;
+    }
+    target.content = trimmed;
+    target.logprobs = undefined;     // 改过的文字和原来的 token 概率对不上了
+    currentSession.value.updatedAt = Date.now();
+    return true;
+  };
+
+  /**
+   * 删除单条消息（移进回收站，可以在历史记录页的回收站里恢复）
+   * 只删这一条，前后的消息保持不变——用来剪掉答偏的回合再继续对话
+   *
+   * @param messageId - 要删除的消息 ID
+   * @returns 是否成功
+   */
+  const deleteMessage = async (messageId: string): Promise<boolean> => {
+    if (!currentSession.value || isLoading.value) return false;
+    const idx = currentSession.value.messages.findIndex(m => m.id === messageId);
+    if (idx === -1) return false;
+
+    try {
+      await invoke("delete_message_cmd", { messageId });
+    } catch (error) {
+      console.error("Failed to delete message:", error);
+      dbSaveErrorNotices.value.push(`消息删除失败：${classifyError(error).message}`);
+      return false;
+    }
+    currentSession.value.messages.splice(idx, 1);
+    currentSession.value.updatedAt = Date.now();
+    return true;
+  };
+
   /**
    * 重新生成指定的 AI 回复
    * 删除该回复（及其后的所有消息，理论上只会有它自己），基于剩余上下文重新请求一次生成
@@ -857,23 +1767,54 @@ export const useChatStore = defineStore("chat", () => {
   };
 
   /**
-   * 构建 RAG 上下文
-   * 将检索到的文档片段格式化为提示上下文
-   * 
-   * @param result - 检索结果
-   * @returns 格式化的上下文字符串
+   * 知识库检索参数：开启 RAG 并选了知识库时，后端在开始流式输出前检索并把
+   * 结果拼进发给模型的用户消息。检索问题用用户原话，不带附件文档等增强内容。
+   *
+   * @returns 请求 payload 里的 kbIds / ragSettings / ragQuery
    */
-  const buildRagContext = (result: RetrievalResult): string => {
-    if (result.chunks.length === 0) return "";
-    
-    const contextParts = ["基于以下参考文档回答问题："];
-    
-    result.chunks.forEach((chunk, index) => {
-      contextParts.push(`\n[文档 ${index + 1}: ${chunk.document_filename}]\n${chunk.chunk.content}`);
-    });
-    
-    contextParts.push("\n---");
-    return contextParts.join("\n");
+  const buildRagPayload = () => {
+    const kbIds = ragEnabled.value && selectedKnowledgeBaseId.value ? [selectedKnowledgeBaseId.value] : [];
+    const lastUser = [...(currentSession.value?.messages ?? [])].reverse().find(m => m.role === "user");
+    return {
+      kbIds,
+      ragSettings: kbStore.buildRagSettings(),
+      ragQuery: kbIds.length > 0 ? lastUser?.content ?? null : null,
+    };
+  };
+
+  /**
+   * 跨所有会话检索聊天记录
+   * 语义/混合检索使用当前激活的 Embedding 配置；没有配置时只能用关键词检索。
+   * 第一次语义检索时后端会先给历史消息生成向量，可能比较慢。
+   *
+   * @param query - 检索内容
+   * @param mode - 检索模式
+   * @param topK - 返回条数
+   * @returns 命中的消息，检索失败时返回空数组
+   */
+  const searchChatHistory = async (
+    query: string,
+    mode: RetrievalMode = "hybrid",
+    topK = 20,
+  ): Promise<HistorySearchHit[]> => {
+    const embedding = settings.activeEmbeddingApiConfig;
+    const effectiveMode: RetrievalMode = embedding ? mode : "keyword";
+    try {
+      return await invoke<HistorySearchHit[]>("search_chat_history", {
+        request: {
+          query,
+          topK,
+          retrievalMode: effectiveMode,
+          embeddingApiConfigId: embedding?.id ?? "",
+          embeddingProvider: embedding?.provider ?? "",
+          embeddingModel: embedding?.model ?? "",
+          embeddingBaseUrl: embedding?.baseUrl ?? "",
+        },
+      });
+    } catch (error) {
+      console.error("Failed to search chat history:", error);
+      return [];
+    }
   };
 
   /**
@@ -887,7 +1828,7 @@ export const useChatStore = defineStore("chat", () => {
     // 如果关闭 RAG，清除相关状态
     if (!enabled) {
       selectedKnowledgeBaseId.value = null;
-      lastRetrievalResult.value = null;
+      lastCitations.value = [];
     }
   };
 
@@ -901,6 +1842,76 @@ export const useChatStore = defineStore("chat", () => {
     selectedKnowledgeBaseId.value = kbId;
   };
 
+  /**
+   * 给当前会话绑定知识库，之后每一轮都会自动检索它
+   * 空会话还没写库，先保存再绑定
+   *
+   * @param kbId - 知识库 ID
+   * @returns void
+   */
+  const attachSessionKb = async (kbId: string) => {
+    if (!currentSession.value) return;
+    await saveSessionToDb();
+    const sessionId = currentSession.value.id;
+    await invoke("attach_session_kb", { sessionId, kbId });
+    sessionKbIds.value = await invoke<string[]>("list_session_kbs", { sessionId });
+  };
+
+  /**
+   * 解除当前会话和知识库的绑定
+   *
+   * @param kbId - 知识库 ID
+   * @returns void
+   */
+  const detachSessionKb = async (kbId: string) => {
+    if (!currentSession.value) return;
+    await invoke("detach_session_kb", { sessionId: currentSession.value.id, kbId });
+    sessionKbIds.value = sessionKbIds.value.filter(id => id !== kbId);
+  };
+
+  /**
+   * 把文件解析、分块、向量化后加入当前会话的临时索引，之后每一轮都从中检索，
+   * 不用先建知识库。需要在设置里配置 Embedding API
+   *
+   * @param filePath - 文件路径
+   * @returns 是否加入成功（没有会话、没有 Embedding 配置或处理失败时为 false）
+   */
+  const chatWithFile = async (filePath: string): Promise<boolean> => {
+    const embedding = settings.activeEmbeddingApiConfig;
+    if (!currentSession.value || !embedding) return false;
+    try {
+      const file = await invoke<SessionFile>("chat_with_file", {
+        request: {
+          sessionId: currentSession.value.id,
+          filePath,
+          embeddingApiConfigId: embedding.id,
+          embeddingProvider: embedding.provider,
+          embeddingModel: embedding.model,
+          embeddingBaseUrl: embedding.baseUrl ?? "",
+        },
+      });
+      sessionFiles.value.push(file);
+      return true;
+    } catch (error) {
+      console.error("Failed to index file for chat:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 丢弃当前会话的临时文件索引（会话关闭时调用）
+   *
+   * @returns void
+   */
+  const discardSessionFiles = () => {
+    const sessionId = currentSession.value?.id;
+    if (!sessionId || sessionFiles.value.length === 0) return;
+    sessionFiles.value = [];
+    invoke("discard_session_files", { sessionId }).catch(error => {
+      console.warn("Failed to discard session files:", error);
+    });
+  };
+
   /**
    * 切换某个 Skill 的手动激活状态
    */
@@ -913,6 +1924,23 @@ export const useChatStore = defineStore("chat", () => {
     }
   };
 
+  /**
+   * 复制会话：消息、绑定的知识库等复制到新会话下，原会话不受影响
+   *
+   * @param sessionId - 要复制的会话 ID
+   * @returns 新会话 ID，失败返回 null
+   */
+  const duplicateSession = async (sessionId: string): Promise<string | null> => {
+    try {
+      const newId = await invoke<string>("duplicate_session_cmd", { sessionId });
+      await loadSessionsFromDb();
+      return newId;
+    } catch (error) {
+      console.error("Failed to duplicate session:", error);
+      return null;
+    }
+  };
+
   /**
    * 删除会话
    * 
@@ -924,6 +1952,7 @@ export const useChatStore = defineStore("chat", () => {
       await invoke("delete_session_cmd", { sessionId });
       // 如果删除的是当前会话，清空当前会话
       if (currentSession.value?.id === sessionId) {
+        discardSessionFiles();
         currentSession.value = null;
       }
       // 刷新会话列表
@@ -933,6 +1962,286 @@ export const useChatStore = defineStore("chat", () => {
     }
   };
 
+  /**
+   * 读取回收站里的会话和消息
+   *
+   * @returns 回收站内容，失败返回 null
+   */
+  const listTrash = async (): Promise<Trash | null> => {
+    try {
+      return await invoke<Trash>("list_trash");
+    } catch (error) {
+      console.error("Failed to load trash:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 从回收站恢复会话
+   *
+   * @param sessionId - 会话 ID
+   * @returns 是否成功
+   */
+  const restoreSession = async (sessionId: string): Promise<boolean> => {
+    try {
+      await invoke("restore_session", { sessionId });
+      await loadSessionsFromDb();
+      return true;
+    } catch (error) {
+      console.error("Failed to restore session:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 从回收站恢复单条消息；消息属于当前会话时重新读取当前会话的消息
+   *
+   * @param message - 回收站里的消息
+   * @returns 是否成功
+   */
+  const restoreMessage = async (message: TrashedMessage): Promise<boolean> => {
+    try {
+      await invoke("restore_message", { messageId: message.id });
+      if (currentSession.value?.id === message.session_id) {
+        currentSession.value.messages = await fetchSessionMessages(message.session_id);
+      }
+      await loadSessionsFromDb();
+      return true;
+    } catch (error) {
+      console.error("Failed to restore message:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 彻底删除回收站里的会话或消息，无法撤销
+   *
+   * @param kind - "session" 或 "message"
+   * @param id - 会话或消息 ID
+   * @returns 是否成功
+   */
+  const purgeTrashItem = async (kind: "session" | "message", id: string): Promise<boolean> => {
+    try {
+      if (kind === "session") {
+        await invoke("purge_session", { sessionId: id });
+      } else {
+        await invoke("purge_message", { messageId: id });
+      }
+      return true;
+    } catch (error) {
+      console.error("Failed to purge trash item:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 清空回收站
+   *
+   * @returns 彻底删除的会话和消息条数，失败返回 null
+   */
+  const emptyTrash = async (): Promise<number | null> => {
+    try {
+      return await invoke<number>("empty_trash");
+    } catch (error) {
+      console.error("Failed to empty trash:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 重新读取已收藏的消息 ID（会话列表刷新时一起刷新，回收站里恢复的消息星标随之回来）
+   *
+   * @returns void
+   */
+  const loadBookmarkedIds = async () => {
+    try {
+      const bookmarks = await invoke<Bookmark[]>("list_bookmarks", { filter: { kind: "all" } });
+      bookmarkedMessageIds.value = bookmarks.map(b => b.messageId);
+    } catch (error) {
+      console.error("Failed to load bookmarks:", error);
+    }
+  };
+
+  /**
+   * 收藏消息；已经收藏过时改为放进指定收藏夹、更新备注
+   *
+   * @param messageId - 消息 ID（消息要已经保存过）
+   * @param collectionId - 收藏夹 ID，不传则未分组
+   * @param note - 备注
+   * @returns 是否成功
+   */
+  const bookmarkMessage = async (messageId: string, collectionId?: string | null, note?: string | null): Promise<boolean> => {
+    try {
+      await invoke("bookmark_message", { messageId, collectionId: collectionId ?? null, note: note ?? null });
+      if (!bookmarkedMessageIds.value.includes(messageId)) {
+        bookmarkedMessageIds.value.push(messageId);
+      }
+      return true;
+    } catch (error) {
+      console.error("Failed to bookmark message:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 取消收藏
+   *
+   * @param messageId - 消息 ID
+   * @returns 是否成功
+   */
+  const removeBookmark = async (messageId: string): Promise<boolean> => {
+    try {
+      await invoke("remove_bookmark", { messageId });
+      bookmarkedMessageIds.value = bookmarkedMessageIds.value.filter(id => id !== messageId);
+      return true;
+    } catch (error) {
+      console.error("Failed to remove bookmark:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 按收藏时间倒序读取收藏的消息
+   *
+   * @param filter - 全部、未分组或指定收藏夹
+   * @returns 收藏列表，失败返回 null
+   */
+  const listBookmarks = async (filter: BookmarkFilter = { kind: "all" }): Promise<Bookmark[] | null> => {
+    try {
+      return await invoke<Bookmark[]>("list_bookmarks", { filter });
+    } catch (error) {
+      console.error("Failed to load bookmarks:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 读取所有收藏夹
+   *
+   * @returns 收藏夹列表，失败返回 null
+   */
+  const listBookmarkCollections = async (): Promise<BookmarkCollection[] | null> => {
+    try {
+      return await invoke<BookmarkCollection[]>("list_bookmark_collections");
+    } catch (error) {
+      console.error("Failed to load bookmark collections:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 新建或重命名收藏夹
+   *
+   * @param name - 收藏夹名称
+   * @param id - 要重命名的收藏夹 ID，不传则新建
+   * @returns 保存后的收藏夹，失败返回 null
+   */
+  const saveBookmarkCollection = async (name: string, id = ""): Promise<BookmarkCollection | null> => {
+    try {
+      return await invoke<BookmarkCollection>("save_bookmark_collection", {
+        collection: { id, name, bookmarkCount: 0, createdAt: 0, updatedAt: 0 },
+      });
+    } catch (error) {
+      console.error("Failed to save bookmark collection:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 删除收藏夹，里面的收藏变回未分组
+   *
+   * @param collectionId - 收藏夹 ID
+   * @returns 是否成功
+   */
+  const deleteBookmarkCollection = async (collectionId: string): Promise<boolean> => {
+    try {
+      await invoke("delete_bookmark_collection", { collectionId });
+      return true;
+    } catch (error) {
+      console.error("Failed to delete bookmark collection:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 置顶或取消置顶会话
+   *
+   * @param sessionId - 会话 ID
+   * @param pinned - true 置顶，false 取消置顶
+   */
+  const setSessionPinned = async (sessionId: string, pinned: boolean) => {
+    try {
+      await invoke("set_session_pinned_cmd", { sessionId, pinned });
+      // 刷新会话列表，置顶的会话由后端排到最前
+      await loadSessionsFromDb();
+    } catch (error) {
+      console.error("Failed to pin session:", error);
+    }
+  };
+
+  /**
+   * 按条件读取会话列表，筛选和排序都在后端完成；不影响 sessions（侧边栏等处用的完整列表）
+   *
+   * @param filter - 筛选和排序条件
+   * @returns 符合条件的会话，失败返回 null
+   */
+  const querySessions = async (filter: SessionFilter): Promise<ChatSession[] | null> => {
+    try {
+      const dbSessions = await invoke<DbSessionOverview[]>("get_sessions_cmd", { filter });
+      return dbSessions.map(sessionFromOverview);
+    } catch (error) {
+      console.error("Failed to query sessions:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 设置会话的标签（整体替换）
+   *
+   * @param sessionId - 会话 ID
+   * @param tags - 新的标签
+   * @returns 是否成功
+   */
+  const setSessionTags = async (sessionId: string, tags: string[]): Promise<boolean> => {
+    try {
+      await invoke("set_session_tags_cmd", { sessionId, tags });
+      await loadSessionsFromDb();
+      return true;
+    } catch (error) {
+      console.error("Failed to set session tags:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 读取所有用过的会话标签
+   *
+   * @returns 标签及使用它的会话数，失败返回空数组
+   */
+  const listSessionTags = async (): Promise<SessionTagCount[]> => {
+    try {
+      return await invoke<SessionTagCount[]>("list_session_tags_cmd");
+    } catch (error) {
+      console.error("Failed to load session tags:", error);
+      return [];
+    }
+  };
+
+  /**
+   * 归档或取消归档会话
+   *
+   * @param sessionId - 会话 ID
+   * @param archived - true 归档，false 取消归档
+   */
+  const setSessionArchived = async (sessionId: string, archived: boolean) => {
+    try {
+      await invoke("set_session_archived_cmd", { sessionId, archived });
+      await loadSessionsFromDb();
+    } catch (error) {
+      console.error("Failed to archive session:", error);
+    }
+  };
+
   /**
    * 清除当前会话
    * 取消事件监听器，清空当前会话状态
@@ -944,6 +2253,7 @@ export const useChatStore = defineStore("chat", () => {
       unlistenFn();
       unlistenFn = null;
     }
+    discardSessionFiles();
     currentSession.value = null;
     currentStreamContent.value = "";
   };
@@ -982,27 +2292,59 @@ export const useChatStore = defineStore("chat", () => {
     sessions,
     isLoading,
     currentStreamContent,
+    throttleNotice,
+    contextNotices,
     ragEnabled,
     dbSaveErrorNotices,
     selectedKnowledgeBaseId,
-    lastRetrievalResult,
+    lastCitations,
+    sessionKbIds,
+    sessionFiles,
     mcpEnabled,
     activeSkillIds,
     skillAutonomyEnabled,
     thinkingEnabled,
+    arenaConfigIds,
+    arenaReplies,
+    bookmarkedMessageIds,
 
     // 方法
     createSession,           // 创建新会话
     loadSession,             // 加载会话
     sendMessage,             // 发送消息
+    adoptArenaReply,         // 采用多模型对比中的一栏回复
     editUserMessage,         // 编辑用户消息并重新生成
     regenerateMessage,       // 重新生成 AI 回复
+    updateMessage,           // 只修改消息文字，不重新生成
+    deleteMessage,           // 删除单条消息（移进回收站）
+    duplicateSession,        // 复制会话
     deleteSession,           // 删除会话
+    setSessionPinned,        // 置顶/取消置顶会话
+    setSessionArchived,      // 归档/取消归档会话
+    querySessions,           // 按条件筛选会话列表
+    setSessionTags,          // 设置会话标签
+    listSessionTags,         // 读取用过的会话标签
+    listTrash,               // 读取回收站
+    restoreSession,          // 从回收站恢复会话
+    restoreMessage,          // 从回收站恢复消息
+    purgeTrashItem,          // 彻底删除回收站里的会话/消息
+    emptyTrash,              // 清空回收站
+    bookmarkMessage,         // 收藏消息 / 移动到收藏夹
+    removeBookmark,          // 取消收藏
+    listBookmarks,           // 读取收藏的消息
+    listBookmarkCollections, // 读取收藏夹
+    saveBookmarkCollection,  // 新建/重命名收藏夹
+    deleteBookmarkCollection,  // 删除收藏夹
     clearSession,            // 清除当前会话
     toggleSkillActive,       // 切换 Skill 手动激活状态
     loadSessionsFromDb,      // 加载会话列表
+    fetchSessionMessages,    // 分页读取会话消息
     toggleRag,               // 切换 RAG
+    searchChatHistory,       // 跨会话检索聊天记录
     selectKnowledgeBaseForRag,  // 选择知识库
+    attachSessionKb,         // 给会话绑定知识库
+    detachSessionKb,         // 解除会话绑定的知识库
+    chatWithFile,            // 把文件加入会话的临时索引
     classifyError,           // 错误分类
     stopStream,              // 停止流式输出
   };
@@ -19,7 +19,8 @@
 import { ref, computed } from "vue";
 import { defineStore } from "pinia";
 import { invoke } from "@tauri-apps/api/core";
-import { open } from "@tauri-apps/plugin-dialog";
+import { listen, type UnlistenFn } from "@tauri-apps/api/event";
+import { open, save } from "@tauri-apps/plugin-dialog";
 import { useSettingsStore } from "./settings";
 
 // ============ 类型定义 (与 Rust 后端对应) ============
@@ -36,13 +37,80 @@ export interface KnowledgeBase {
   embedding_provider: string;      // Embedding 服务商 (创建时从配置中快照)
   embedding_model: string;         // Embedding 模型名称 (创建时从配置中快照)
   embedding_base_url: string;      // Embedding API Base URL (创建时从配置中快照)
-  chunk_size: number;              // 文本分块大小 (字符数)
+  chunk_size: number;              // 文本分块大小 (单位见 chunk_unit)
   chunk_overlap: number;           // 分块重叠大小
+  chunk_unit: ChunkUnit;           // 分块大小按字符数还是 token 数计
+  separators: string[];            // 自定义分块分隔符 (从粗到细，空数组表示默认)
+  retrieval_defaults?: KbRetrievalDefaults | null;  // 知识库自己的默认检索参数 (为空时用全局设置)
+  vector_quantization: VectorQuantization;  // 向量的量化存储方式
+  parent_chunk_size: number;       // 父子分块的父块大小 (0 表示不开启)
+  encrypted?: boolean;             // 分块内容和向量是否加密存储
+  context_template?: string | null; // 检索上下文的提示词模板 (含 {sources} 和 {query}，为空时用默认模板)
+  context_language?: ContextLanguage; // 默认上下文模板和来源标注的语言
   created_at: number;              // 创建时间戳
   updated_at: number;              // 更新时间戳
   document_count: number;          // 包含的文档数量
 }
 
+/**
+ * 知识库自己的默认检索参数
+ */
+export interface KbRetrievalDefaults {
+  top_k: number;
+  retrieval_mode: RetrievalMode;
+  similarity_threshold: number;
+}
+
+/**
+ * 修改知识库设置的请求，不填的字段保持不变
+ * rechunk 为 true 且分块参数有变化时，已有文档会在后台重新分块
+ */
+export interface UpdateKnowledgeBaseRequest {
+  kb_id: string;
+  name?: string;
+  description?: string;
+  chunk_size?: number;
+  chunk_overlap?: number;
+  chunk_unit?: ChunkUnit;
+  separators?: string[];
+  retrieval_defaults?: KbRetrievalDefaults;
+  clear_retrieval_defaults?: boolean;
+  vector_quantization?: VectorQuantization;
+  parent_chunk_size?: number;
+  context_template?: string;      // 空字符串表示改回默认模板
+  context_language?: ContextLanguage;
+  rechunk?: boolean;
+}
+
+/**
+ * 检索上下文提示词的语言
+ */
+export type ContextLanguage = "zh" | "en";
+
+/**
+ * 分块大小的计量单位
+ */
+export type ChunkUnit = "chars" | "tokens";
+
+/**
+ * 向量的量化存储方式
+ * none 存 f32；int8 约省 3/4 空间；binary 在 int8 基础上用符号位先粗筛再精确重排
+ */
+export type VectorQuantization = "none" | "int8" | "binary";
+
+/**
+ * 内置的分块分隔符，从粗到细排列
+ * 与后端 document.rs 的 DEFAULT_SEPARATORS 保持一致
+ */
+export const DEFAULT_CHUNK_SEPARATORS: string[] = [
+  "\n# ", "\n## ", "\n### ", "\n#### ", "\n##### ",
+  "\n\n", "\n",
+  "。", "！", "？", "；",
+  ". ", "! ", "? ", "; ",
+  "，", ", ",
+  " ",
+];
+
 /**
  * 文档类型
  * 表示知识库中的一个文档
@@ -58,7 +126,151 @@ export interface Document {
   chunk_count: number;            // 分块数量
   status: "processing" | "completed" | "error";  // 处理状态
   error_message?: string;         // 错误信息 (如果有)
+  import_stage?: ImportStage | null;  // 后台导入所处阶段 (导入结束后为空)
+  source?: string | null;         // 导入来源 (文件路径或网页地址)
+  duplicate_of?: string | null;   // 按“关联”导入的重复文档指向的已有文档 ID
+  tags: string[];                 // 用户给文档加的标签 (检索时可按标签过滤)
   created_at: number;             // 创建时间戳
+  deleted_at?: number | null;     // 移进回收站的时间 (不在回收站里为空)
+}
+
+/**
+ * 后台导入阶段
+ */
+export type ImportStage = "parsing" | "chunking" | "embedding" | "inserting" | "completed" | "failed" | "skipped" | "cancelled" | "reimporting";
+
+/**
+ * 导入内容和已有文档相同时的处理方式
+ * skip: 不导入；replace: 删除已有文档后导入；link: 只记一条指向已有文档的记录
+ */
+export type DuplicatePolicy = "skip" | "replace" | "link";
+
+/**
+ * import_document 返回的后台导入任务
+ */
+export interface ImportTask {
+  task_id: string;
+  kb_id: string;
+  document_id: string;
+  filename: string;
+}
+
+/**
+ * kb-import-progress 事件
+ * current/total 在 embedding 阶段为已完成/总分块数
+ */
+export interface ImportProgressEvent extends ImportTask {
+  stage: ImportStage;
+  current: number;
+  total: number;
+  error: string | null;
+}
+
+/** 导入任务在处理队列里的状态 */
+export type ImportQueueState = "pending" | "waiting_parse" | "parsing" | "waiting_embed" | "embedding";
+
+/** 导入队列里的一个任务，position 为排队位置（不在等待时为 0） */
+export interface QueuedImport extends ImportTask {
+  state: ImportQueueState;
+  position: number;
+}
+
+/** get_import_queue 的返回值 */
+export interface ImportQueueStatus {
+  parse_workers: number;
+  embed_workers: number;
+  tasks: QueuedImport[];
+}
+
+export interface CrawlTask {
+  crawl_id: string;
+  kb_id: string;
+  url: string;
+}
+
+/**
+ * kb-crawl-progress 事件：每抓取完一个网页一次，结束时 finished 为 true
+ * skipped 是网址或内容和已有文档重复而跳过的网页数
+ */
+export interface CrawlProgressEvent {
+  crawl_id: string;
+  kb_id: string;
+  url: string;
+  visited: number;
+  queued: number;
+  imported: number;
+  skipped: number;
+  failed: number;
+  finished: boolean;
+}
+
+/**
+ * 更换 embedding 模型的迁移任务；running 为 false 表示中断了，可以继续或取消
+ */
+export interface ReembedJob {
+  kb_id: string;
+  embedding_api_config_id: string;
+  embedding_provider: string;
+  embedding_model: string;
+  embedding_base_url: string;
+  done: number;
+  total: number;
+  error: string | null;
+  running: boolean;
+  started_at: number;
+}
+
+/**
+ * kb-reembed-progress 事件：每写完一批新向量一次，切换完成或失败时 finished 为 true
+ */
+export interface ReembedProgressEvent {
+  kb_id: string;
+  done: number;
+  total: number;
+  finished: boolean;
+  error: string | null;
+}
+
+/**
+ * repair_knowledge_base 删除的没有归属的数据条数
+ */
+export interface RepairReport {
+  documents: number;
+  chunks: number;
+  parent_chunks: number;
+  vectors: number;
+  fts_rows: number;
+  document_contents: number;
+  document_summaries: number;
+  reembed_vectors: number;
+  session_bindings: number;
+}
+
+/**
+ * dedup_chunks 的结果
+ */
+export interface DedupReport {
+  scanned: number;     // 参与比较的分块数
+  duplicates: number;  // 被标记为重复的分块数
+  groups: number;      // 含有重复分块的组数
+}
+
+/**
+ * 知识库统计信息
+ */
+export interface KbStats {
+  kb_id: string;
+  document_count: number;
+  chunk_count: number;
+  total_tokens: number;           // 所有分块的 token 数之和
+  vector_bytes: number;           // 向量占用的存储空间（字节）
+  file_types: {
+    file_type: string;
+    document_count: number;
+    file_size: number;
+    chunk_count: number;
+  }[];
+  last_import_at: number | null;  // 最近一次导入文档的时间
 }
 
 /**
@@ -72,6 +284,19 @@ export interface Chunk {
   content: string;                // 分块内容
   chunk_index: number;            // 分块索引
   token_count: number;            // token 数量
+  heading_path?: string | null;   // Markdown 分块所在的标题路径
+  page?: number | null;           // 所在页码（PDF、PPTX）
+  start_offset?: number | null;   // 在文档原文里的起始字符偏移
+  end_offset?: number | null;     // 在文档原文里的结束字符偏移（不含）
+  duplicate_of?: string | null;   // 近似重复时保留的那一块的 ID（检索时跳过这一块）
+}
+
+/**
+ * list_chunks 返回的一页分块
+ */
+export interface ChunkPage {
+  chunks: Chunk[];
+  total: number;                  // 文档的分块总数
 }
 
 /**
@@ -84,6 +309,17 @@ export interface RetrievedChunk {
   vector_score?: number;          // 向量相似度分数
   keyword_score?: number;         // 关键词匹配分数
   document_filename: string;      // 来源文档文件名
+  snippet?: MatchSnippet | null;  // 问题里的词命中处的摘录（没有命中时为空）
+  match_offsets?: [number, number][]; // 问题里的词在内容中的位置（字符偏移，[start, end)）
+}
+
+/**
+ * 关键词命中摘录类型
+ */
+export interface MatchSnippet {
+  text: string;                   // 摘录文字（前后截断时带省略号）
+  highlights: [number, number][]; // 命中的词在 text 里的位置（字符偏移，[start, end)）
+  offset: number;                 // 摘录在内容里的起始字符偏移
 }
 
 /**
@@ -100,8 +336,9 @@ export interface RetrievalResult {
  * - vector: 向量检索 (语义相似度)
  * - keyword: 关键词检索
  * - hybrid: 混合检索 (向量 + 关键词)
+ * - hyde: 先让当前对话模型写一段假设回答，用它做向量检索，再和关键词结果融合
  */
-export type RetrievalMode = "vector" | "keyword" | "hybrid";
+export type RetrievalMode = "vector" | "keyword" | "hybrid" | "hyde";
 
 /**
  * 创建知识库请求类型
@@ -109,12 +346,16 @@ export type RetrievalMode = "vector" | "keyword" | "hybrid";
 export interface CreateKnowledgeBaseRequest {
   name: string;                   // 知识库名称
   description: string;            // 知识库描述
-  embedding_api_config_id: string; // Embedding API 配置 ID
-  embedding_provider: string;     // Embedding 服务商 (从选中的配置中取出)
-  embedding_model: string;        // Embedding 模型名称 (从选中的配置中取出)
-  embedding_base_url: string;     // Embedding API Base URL (从选中的配置中取出)
+  embedding_api_config_id: string; // Embedding API 配置 ID (服务商/模型由后端从配置中取出)
   chunk_size?: number;           // 分块大小 (可选)
   chunk_overlap?: number;        // 分块重叠 (可选)
+  chunk_unit?: ChunkUnit;        // 分块单位 (可选，默认按字符)
+  separators?: string[];         // 分块分隔符 (可选，默认使用内置分隔符)
+  vector_quantization?: VectorQuantization; // 向量量化方式 (可选，默认不量化)
+  parent_chunk_size?: number;    // 父块大小 (可选，默认 0 不开启父子分块)
+  encrypted?: boolean;           // 加密存储 (可选，只能在创建时选择)
+  context_template?: string;     // 上下文模板 (可选，默认按 context_language 使用内置模板)
+  context_language?: ContextLanguage; // 上下文语言 (可选，默认中文)
 }
 
 /**
@@ -127,6 +368,10 @@ export interface RetrievalSettings {
   enableReranker: boolean;        // 是否启用 Reranker 精排
   rerankerConfigId?: string;      // 选用的 Reranker 配置 ID
   rerankTopN?: number;            // 精排后保留条数（默认等于 topK）
+  compressContext?: boolean;      // 聊天时只把片段里和问题相关的句子拼进上下文
+  summaryTopN?: number;           // 先按文档摘要挑出这么多篇文档再检索分块（0 表示不预筛选）
+  contextTokenBudget?: number;    // 聊天时检索上下文最多占多少 token（0 或不填表示按模型上下文窗口估算）
+  windowSize?: number;            // 每条结果前后各拼接几个相邻分块（默认 1，0 表示不拼接）
 }
 
 export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
@@ -144,8 +389,23 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
   // 是否正在加载
   const loading = ref(false);
   
-  // 文档导入进度
-  const importProgress = ref<{ current: number; total: number } | null>(null);
+  // 文档导入进度，以 document_id 为键，导入结束后移除
+  const importProgress = ref<Record<string, ImportProgressEvent>>({});
+  let unlistenImportProgressFn: UnlistenFn | null = null;
+
+  // 导入队列里还没结束的任务，以 document_id 为键，收到导入进度时刷新
+  const importQueue = ref<Record<string, QueuedImport>>({});
+
+  // 导入重复内容时的处理方式
+  const duplicatePolicy = ref<DuplicatePolicy>("skip");
+
+  // 整站爬取进度，以 crawl_id 为键，爬取结束后移除
+  const crawlProgress = ref<Record<string, CrawlProgressEvent>>({});
+  let unlistenCrawlProgressFn: UnlistenFn | null = null;
+
+  // embedding 模型迁移进度，以 kb_id 为键，迁移结束后移除
+  const reembedProgress = ref<Record<string, ReembedProgressEvent>>({});
+  let unlistenReembedProgressFn: UnlistenFn | null = null;
   
   // 检索设置
   const retrievalSettings = ref<RetrievalSettings>({
@@ -153,6 +413,7 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     topK: 5,
     similarityThreshold: 0.7,
     enableReranker: false,
+    windowSize: 1,
   });
 
   // ============ 计算属性 ============
@@ -194,6 +455,72 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     }
   };
 
+  /**
+   * 修改知识库设置，返回后台重新分块的文档数；失败时返回 null
+   */
+  const updateKnowledgeBase = async (
+    request: UpdateKnowledgeBaseRequest,
+  ): Promise<number | null> => {
+    try {
+      if (request.rechunk) {
+        await setupImportProgressListener();
+      }
+      const result = await invoke<{ knowledge_base: KnowledgeBase; rechunk_documents: number }>(
+        "update_knowledge_base",
+        { request },
+      );
+      const updated = result.knowledge_base;
+      knowledgeBases.value = knowledgeBases.value.map((kb) => (kb.id === updated.id ? updated : kb));
+      if (currentKb.value?.id === updated.id) {
+        currentKb.value = updated;
+        if (result.rechunk_documents > 0) {
+          await loadDocuments(updated.id);
+        }
+      }
+      return result.rechunk_documents;
+    } catch (error) {
+      console.error("Failed to update knowledge base:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 把知识库（含分块和向量）导出成 zip 归档，返回导出的文档数；取消或失败时返回 null
+   */
+  const exportKnowledgeBase = async (kb: KnowledgeBase): Promise<number | null> => {
+    try {
+      const path = await save({
+        defaultPath: `${kb.name}.zip`,
+        filters: [{ name: "Knowledge Base", extensions: ["zip"] }],
+      });
+      if (!path) return null;
+      return await invoke<number>("export_knowledge_base", { kbId: kb.id, path });
+    } catch (error) {
+      console.error("Failed to export knowledge base:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 选择导出的 zip 归档，导入成一个新的知识库（不需要重新生成向量）
+   * embeddingApiConfigId 用来取 API Key，需要和归档里的 embedding 模型一致
+   */
+  const importKnowledgeBase = async (embeddingApiConfigId: string): Promise<KnowledgeBase | null> => {
+    try {
+      const path = await open({
+        multiple: false,
+        filters: [{ name: "Knowledge Base", extensions: ["zip"] }],
+      });
+      if (!path || typeof path !== "string") return null;
+      const kb = await invoke<KnowledgeBase>("import_knowledge_base", { path, embeddingApiConfigId });
+      knowledgeBases.value.unshift(kb);
+      return kb;
+    } catch (error) {
+      console.error("Failed to import knowledge base:", error);
+      throw error;
+    }
+  };
+
   const deleteKnowledgeBase = async (kbId: string): Promise<boolean> => {
     try {
       await invoke("delete_knowledge_base", { kbId });
@@ -221,35 +548,192 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     try {
       const result = await invoke<Document[]>("list_documents", { kbId });
       documents.value = result;
+      if (result.some(d => d.status === "processing")) await loadImportQueue();
     } catch (error) {
       console.error("Failed to load documents:", error);
     }
   };
 
+  /**
+   * 监听后台导入进度；导入结束（完成、失败或因重复跳过）时刷新文档列表和文档数
+   */
+  const setupImportProgressListener = async () => {
+    if (unlistenImportProgressFn) return;
+    unlistenImportProgressFn = await listen<ImportProgressEvent>("kb-import-progress", async (event) => {
+      const progress = event.payload;
+      await loadImportQueue();
+      if (progress.stage === "skipped") {
+        console.info("Skipped duplicate document:", progress.error);
+      }
+      if (["completed", "failed", "skipped", "cancelled"].includes(progress.stage)) {
+        delete importProgress.value[progress.document_id];
+        // 失败时后端会把文档行写成 status='error' + error_message（方便定位原因，
+        // 比如 embedding 模型的单次输入长度限制），刷新后这条失败记录才会出现在 UI 里
+        if (currentKb.value?.id === progress.kb_id) {
+          await loadDocuments(progress.kb_id);
+        }
+        await loadKnowledgeBases(); // Refresh document count
+      } else {
+        importProgress.value[progress.document_id] = progress;
+      }
+    });
+  };
+
   /**
    * Import document to knowledge base
    * Note: API key is no longer passed from frontend (#32).
    * Backend retrieves it from secure storage using the KB's embedding_api_config_id.
+   * 后端只创建文档记录就返回，解析/分块/embedding 在后台进行，进度见 importProgress
    */
   const importDocument = async (
     kbId: string,
     filePath: string,
   ): Promise<boolean> => {
     try {
-      await invoke("import_document", {
+      await setupImportProgressListener();
+      await invoke<ImportTask>("import_document", {
         kbId,
         filePath,
+        duplicatePolicy: duplicatePolicy.value,
       });
       await loadDocuments(kbId);
-      await loadKnowledgeBases(); // Refresh document count
       return true;
     } catch (error) {
       console.error("Failed to import document:", error);
-      // 后端在失败时仍会把文档行写成 status='error' + error_message（方便定位原因，
-      // 比如 embedding 模型的单次输入长度限制），所以这里也要刷新列表，
-      // 否则这条失败记录永远不会出现在 UI 里，用户只能看到一个空泛的"导入失败"提示
+      return false;
+    }
+  };
+
+  /**
+   * 把网页导入知识库：后端抓取并提取正文，之后和文件导入走同一条后台管线
+   */
+  const importUrl = async (kbId: string, url: string): Promise<boolean> => {
+    try {
+      await setupImportProgressListener();
+      await invoke<ImportTask>("import_url", { kbId, url, duplicatePolicy: duplicatePolicy.value });
+      await loadDocuments(kbId);
+      return true;
+    } catch (error) {
+      console.error("Failed to import url:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 把一段文字（纯文本或 Markdown）作为笔记加入知识库，标题留空时取正文第一行
+   */
+  const addNote = async (kbId: string, content: string, title?: string): Promise<boolean> => {
+    try {
+      await setupImportProgressListener();
+      await invoke<ImportTask>("add_note", {
+        kbId,
+        title: title || null,
+        content,
+        duplicatePolicy: duplicatePolicy.value,
+      });
       await loadDocuments(kbId);
-      await loadKnowledgeBases();
+      return true;
+    } catch (error) {
+      console.error("Failed to add note:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 监听整站爬取进度；每个网页自己的导入进度仍然走 kb-import-progress
+   */
+  const setupCrawlProgressListener = async () => {
+    if (unlistenCrawlProgressFn) return;
+    unlistenCrawlProgressFn = await listen<CrawlProgressEvent>("kb-crawl-progress", (event) => {
+      const progress = event.payload;
+      if (progress.finished) {
+        delete crawlProgress.value[progress.crawl_id];
+      } else {
+        crawlProgress.value[progress.crawl_id] = progress;
+      }
+    });
+  };
+
+  /**
+   * 从一个网页开始爬取同一站点，每个网页作为单独的文档导入
+   * maxDepth/maxPages 不传时使用后端默认值
+   */
+  const crawlSite = async (
+    kbId: string,
+    url: string,
+    maxDepth?: number,
+    maxPages?: number,
+  ): Promise<boolean> => {
+    try {
+      await setupImportProgressListener();
+      await setupCrawlProgressListener();
+      await invoke<CrawlTask>("crawl_site", {
+        request: { kb_id: kbId, url, max_depth: maxDepth, max_pages: maxPages },
+      });
+      return true;
+    } catch (error) {
+      console.error("Failed to crawl site:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 监听 embedding 模型迁移进度；切换完成后重新加载知识库列表（模型和维度变了）
+   */
+  const setupReembedProgressListener = async () => {
+    if (unlistenReembedProgressFn) return;
+    unlistenReembedProgressFn = await listen<ReembedProgressEvent>("kb-reembed-progress", async (event) => {
+      const progress = event.payload;
+      if (progress.finished) {
+        delete reembedProgress.value[progress.kb_id];
+        if (!progress.error) {
+          await loadKnowledgeBases();
+          const updated = knowledgeBases.value.find((kb) => kb.id === progress.kb_id);
+          if (updated && currentKb.value?.id === updated.id) {
+            currentKb.value = updated;
+          }
+        }
+      } else {
+        reembedProgress.value[progress.kb_id] = progress;
+      }
+    });
+  };
+
+  /**
+   * 用另一个 embedding 配置重新生成知识库的全部向量；同一目标的中断任务会接着做
+   */
+  const reembedKnowledgeBase = async (kbId: string, embeddingApiConfigId: string): Promise<ReembedJob> => {
+    await setupReembedProgressListener();
+    const job = await invoke<ReembedJob>("reembed_knowledge_base", {
+      request: { kb_id: kbId, embedding_api_config_id: embeddingApiConfigId },
+    });
+    reembedProgress.value[kbId] = { kb_id: kbId, done: job.done, total: job.total, finished: false, error: null };
+    return job;
+  };
+
+  /**
+   * 查询知识库进行中或中断了的迁移任务
+   */
+  const getReembedJob = async (kbId: string): Promise<ReembedJob | null> => {
+    try {
+      await setupReembedProgressListener();
+      return await invoke<ReembedJob | null>("get_reembed_job", { kbId });
+    } catch (error) {
+      console.error("Failed to get re-embedding job:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 放弃迁移，知识库继续使用原来的模型
+   */
+  const cancelReembed = async (kbId: string): Promise<boolean> => {
+    try {
+      await invoke("cancel_reembed", { kbId });
+      delete reembedProgress.value[kbId];
+      return true;
+    } catch (error) {
+      console.error("Failed to cancel re-embedding:", error);
       return false;
     }
   };
@@ -275,6 +759,12 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
               "markdown",
               "html",
               "htm",
+              "epub",
+              "json",
+              "jsonl",
+              "ndjson",
+              "yaml",
+              "yml",
               "txt",
               "rs",
               "js",
@@ -285,6 +775,22 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
               "cpp",
               "h",
               "go",
+              "png",
+              "jpg",
+              "jpeg",
+              "bmp",
+              "tif",
+              "tiff",
+              "webp",
+              "mp3",
+              "wav",
+              "m4a",
+              "flac",
+              "ogg",
+              "mp4",
+              "mkv",
+              "mov",
+              "webm",
             ],
           },
         ],
@@ -300,10 +806,193 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     }
   };
 
+  /**
+   * 继续导入中途失败的文档（跳过已经生成过向量的分块）
+   */
+  const resumeImport = async (documentId: string, kbId: string): Promise<boolean> => {
+    try {
+      await setupImportProgressListener();
+      await invoke<ImportTask>("resume_import", { documentId });
+      await loadDocuments(kbId);
+      return true;
+    } catch (error) {
+      console.error("Failed to resume import:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 刷新导入队列（排队位置只在任务开始或结束时变化，收到进度事件时刷新即可）
+   */
+  const loadImportQueue = async () => {
+    try {
+      const status = await invoke<ImportQueueStatus>("get_import_queue");
+      importQueue.value = Object.fromEntries(status.tasks.map(t => [t.document_id, t]));
+    } catch (error) {
+      console.error("Failed to load import queue:", error);
+    }
+  };
+
+  /**
+   * 取消正在进行的导入；后端按所处阶段回滚，结束后会收到 cancelled 进度事件
+   */
+  const cancelImport = async (taskId: string): Promise<boolean> => {
+    try {
+      await invoke("cancel_import", { taskId });
+      return true;
+    } catch (error) {
+      console.error("Failed to cancel import:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 从原来的文件或网址重新导入文档，内容有变化时原地替换分块和向量（文档 ID 不变）
+   */
+  const reimportDocument = async (documentId: string, kbId: string): Promise<boolean> => {
+    try {
+      await setupImportProgressListener();
+      await invoke<ImportTask>("reimport_document", { documentId });
+      await loadDocuments(kbId);
+      return true;
+    } catch (error) {
+      console.error("Failed to reimport document:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 按顺序分页获取文档的分块，用来查看文档被切成了什么样
+   */
+  const listChunks = async (
+    kbId: string,
+    docId: string,
+    offset: number,
+    limit: number,
+  ): Promise<ChunkPage | null> => {
+    try {
+      return await invoke<ChunkPage>("list_chunks", { kbId, docId, offset, limit });
+    } catch (error) {
+      console.error("Failed to list chunks:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 修改单个分块的内容并重新生成向量，返回修改后的分块
+   */
+  const updateChunk = async (chunkId: string, content: string): Promise<Chunk> => {
+    return await invoke<Chunk>("update_chunk", { chunkId, content });
+  };
+
+  /**
+   * 读取文档全文（导入时清洗后的文本）
+   */
+  const getDocumentContent = async (docId: string): Promise<string | null> => {
+    try {
+      return await invoke<string>("get_document_content", { docId });
+    } catch (error) {
+      console.error("Failed to load document content:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 读取文档摘要，还没有摘要时为 null
+   */
+  const getDocumentSummary = async (docId: string): Promise<string | null> => {
+    try {
+      return await invoke<string | null>("get_document_summary", { documentId: docId });
+    } catch (error) {
+      console.error("Failed to load document summary:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 为知识库里还没有摘要的文档补生成摘要，返回生成的数量（失败为 null）
+   */
+  const summarizeDocuments = async (kbId: string): Promise<number | null> => {
+    try {
+      return await invoke<number>("summarize_documents", { kbId });
+    } catch (error) {
+      console.error("Failed to summarize documents:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 找出知识库里近似重复的分块（页眉页脚等），检索时每组只保留最早导入的一块
+   */
+  const dedupChunks = async (kbId: string, threshold?: number): Promise<DedupReport | null> => {
+    try {
+      return await invoke<DedupReport>("dedup_chunks", { kbId, threshold });
+    } catch (error) {
+      console.error("Failed to dedup chunks:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 撤销知识库里全部的重复分块标记，返回撤销的分块数
+   */
+  const clearChunkDuplicates = async (kbId: string): Promise<number | null> => {
+    try {
+      return await invoke<number>("clear_chunk_duplicates", { kbId });
+    } catch (error) {
+      console.error("Failed to clear chunk duplicates:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 清理已删除的知识库和文档留下的分块、向量、全文索引等数据
+   */
+  const repairKnowledgeBase = async (): Promise<RepairReport | null> => {
+    try {
+      return await invoke<RepairReport>("repair_knowledge_base");
+    } catch (error) {
+      console.error("Failed to repair knowledge base:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 获取知识库统计信息（分块数、token 数、向量占用空间、文件类型分布）
+   */
+  const getKbStats = async (kbId: string): Promise<KbStats | null> => {
+    try {
+      return await invoke<KbStats>("get_kb_stats", { kbId });
+    } catch (error) {
+      console.error("Failed to load knowledge base stats:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 修改文档的显示名称和标签（只改记录，不影响分块和向量）
+   */
+  const updateDocument = async (
+    documentId: string,
+    changes: { filename?: string; tags?: string[] },
+  ): Promise<boolean> => {
+    try {
+      const updated = await invoke<Document>("update_document", {
+        request: { document_id: documentId, ...changes },
+      });
+      documents.value = documents.value.map((d) => (d.id === updated.id ? updated : d));
+      return true;
+    } catch (error) {
+      console.error("Failed to update document:", error);
+      return false;
+    }
+  };
+
   const deleteDocument = async (docId: string, kbId: string): Promise<boolean> => {
     try {
       await invoke("delete_document", { docId, kbId });
-      documents.value = documents.value.filter((d) => d.id !== docId);
+      // 关联到这份文档的重复记录也一起进了回收站
+      documents.value = documents.value.filter((d) => d.id !== docId && d.duplicate_of !== docId);
       await loadKnowledgeBases(); // Refresh document count
       return true;
     } catch (error) {
@@ -312,6 +1001,77 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     }
   };
 
+  /**
+   * 列出知识库回收站里的文档
+   */
+  const listTrashedDocuments = async (kbId: string): Promise<Document[] | null> => {
+    try {
+      return await invoke<Document[]>("list_trashed_documents", { kbId });
+    } catch (error) {
+      console.error("Failed to load trashed documents:", error);
+      return null;
+    }
+  };
+
+  /**
+   * 从回收站恢复文档（不用重新导入）
+   */
+  const restoreDocument = async (docId: string, kbId: string): Promise<boolean> => {
+    try {
+      await invoke("restore_document", { docId, kbId });
+      await loadDocuments(kbId);
+      await loadKnowledgeBases(); // Refresh document count
+      return true;
+    } catch (error) {
+      console.error("Failed to restore document:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 彻底删除回收站里的文档，无法撤销
+   */
+  const purgeDocument = async (docId: string, kbId: string): Promise<boolean> => {
+    try {
+      await invoke("purge_document", { docId, kbId });
+      return true;
+    } catch (error) {
+      console.error("Failed to purge document:", error);
+      return false;
+    }
+  };
+
+  /**
+   * 当前检索设置对应的后端参数（知识库页检索和聊天时的 RAG 共用）
+   */
+  const buildRagSettings = (): Record<string, unknown> => {
+    const params: Record<string, unknown> = {
+      topK: retrievalSettings.value.topK,
+      retrievalMode: retrievalSettings.value.mode,
+      similarityThreshold: retrievalSettings.value.similarityThreshold,
+      // 前后各拼接几个相邻分块，窗口重叠的结果在后端合并成一条
+      windowSize: retrievalSettings.value.windowSize ?? 1,
+      compressContext: retrievalSettings.value.compressContext ?? false,
+      summaryTopN: retrievalSettings.value.summaryTopN ?? 0,
+    };
+    if (retrievalSettings.value.contextTokenBudget) {
+      params.contextTokenBudget = retrievalSettings.value.contextTokenBudget;
+    }
+    if (retrievalSettings.value.enableReranker && retrievalSettings.value.rerankerConfigId) {
+      const settingsStore = useSettingsStore();
+      const cfg = settingsStore.rerankerApiConfigs.find(
+        (c) => c.id === retrievalSettings.value.rerankerConfigId
+      );
+      if (cfg) {
+        params.rerankerConfigId = cfg.id;
+        params.rerankerBaseUrl = cfg.baseUrl;
+        params.rerankerModel = cfg.model;
+        params.rerankTopN = retrievalSettings.value.rerankTopN ?? retrievalSettings.value.topK;
+      }
+    }
+    return params;
+  };
+
   /**
    * Search knowledge base
    * Note: API key is no longer passed from frontend (#32).
@@ -320,32 +1080,37 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
   const searchKnowledgeBase = async (
     kbId: string,
     query: string,
+    tags: string[] = [],
   ): Promise<RetrievalResult | null> => {
     try {
-      // Build optional reranker params
-      const rerankerParams: Record<string, unknown> = {};
-      if (retrievalSettings.value.enableReranker && retrievalSettings.value.rerankerConfigId) {
-        const settingsStore = useSettingsStore();
-        const cfg = settingsStore.rerankerApiConfigs.find(
-          (c) => c.id === retrievalSettings.value.rerankerConfigId
-        );
-        if (cfg) {
-          rerankerParams.rerankerConfigId = cfg.id;
-          rerankerParams.rerankerBaseUrl = cfg.baseUrl;
-          rerankerParams.rerankerModel = cfg.model;
-          rerankerParams.rerankTopN = retrievalSettings.value.rerankTopN ?? retrievalSettings.value.topK;
-        }
-      }
-
+      // 知识库设置了自己的默认检索参数时，覆盖全局设置里的对应项
+      const defaults = knowledgeBases.value.find((kb) => kb.id === kbId)?.retrieval_defaults;
+      const mode = defaults?.retrieval_mode ?? retrievalSettings.value.mode;
+      // HyDE 用当前激活的对话模型写假设回答（聊天时后端直接用本轮的模型，不需要传）
+      const llm = mode === "hyde" ? useSettingsStore().activeConfig : null;
       const result = await invoke<RetrievalResult>("search_knowledge_base", {
         request: {
           kbId,
           query,
-          topK: retrievalSettings.value.topK,
-          retrievalMode: retrievalSettings.value.mode,
-          similarityThreshold: retrievalSettings.value.similarityThreshold,
-          windowSize: 1, // fetch ±1 adjacent chunks to give LLM richer context
-          ...rerankerParams,
+          tags,
+          ...buildRagSettings(),
+          ...(defaults
+            ? {
+                topK: defaults.top_k,
+                retrievalMode: defaults.retrieval_mode,
+                similarityThreshold: defaults.similarity_threshold,
+              }
+            : {}),
+          ...(llm
+            ? {
+                queryLlm: {
+                  provider: llm.provider,
+                  model: llm.model,
+                  baseUrl: llm.baseUrl,
+                  apiKey: llm.apiKey ?? "",
+                },
+              }
+            : {}),
         },
       });
       return result;
@@ -387,6 +1152,11 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     documents,
     loading,
     importProgress,
+    importQueue,
+    loadImportQueue,
+    crawlProgress,
+    reembedProgress,
+    duplicatePolicy,
     retrievalSettings,
     
     // Getters
@@ -395,19 +1165,45 @@ export const useKnowledgeBaseStore = defineStore("knowledgeBase", () => {
     // Actions
     loadKnowledgeBases,
     createKnowledgeBase,
+    updateKnowledgeBase,
+    exportKnowledgeBase,
+    importKnowledgeBase,
     deleteKnowledgeBase,
+    reembedKnowledgeBase,
+    getReembedJob,
+    cancelReembed,
     setCurrentKb,
     loadDocuments,
     importDocument,
+    resumeImport,
+    cancelImport,
+    reimportDocument,
+    updateDocument,
+    listChunks,
+    updateChunk,
+    getDocumentContent,
+    getDocumentSummary,
+    summarizeDocuments,
+    repairKnowledgeBase,
+    dedupChunks,
+    clearChunkDuplicates,
+    getKbStats,
+    importUrl,
+    addNote,
+    crawlSite,
     selectAndImportDocument,
     deleteDocument,
+    listTrashedDocuments,
+    restoreDocument,
+    purgeDocument,
     searchKnowledgeBase,
     updateRetrievalSettings,
+    buildRagSettings,
     formatFileSize,
     formatDate,
   };
 }, {
   persist: {
-    paths: ["retrievalSettings"],
+    paths: ["retrievalSettings", "duplicatePolicy"],
   },
 });
@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-import { ref, computed } from "vue";
+import { ref, computed, watch } from "vue";
 import { defineStore } from "pinia";
 import { invoke } from "@tauri-apps/api/core";
 
@@ -94,6 +94,28 @@ export const PRESET_PROVIDERS: Record<string, { name: string; baseUrl: string }>
   },
 };
 
+// 只提供 Embedding 接口的服务商，不出现在 LLM 提供商列表里
+export const EMBEDDING_ONLY_PROVIDERS: Record<string, { name: string; baseUrl: string }> = {
+  jina: {
+    name: "Jina AI",
+    baseUrl: "https://api.jina.ai/v1",
+  },
+  voyage: {
+    name: "Voyage AI",
+    baseUrl: "https://api.voyageai.com/v1",
+  },
+  cohere: {
+    name: "Cohere",
+    baseUrl: "https://api.cohere.com/v2",
+  },
+};
+
+// Embedding 配置可选的全部服务商
+export const EMBEDDING_PROVIDERS: Record<string, { name: string; baseUrl: string }> = {
+  ...PRESET_PROVIDERS,
+  ...EMBEDDING_ONLY_PROVIDERS,
+};
+
 /**
  * LLM API 配置接口
  * 用于配置各种大语言模型的 API 连接信息
@@ -105,10 +127,51 @@ export interface ApiConfig {
   baseUrl: string;                 // API 基础 URL
   model: string;                   // 模型名称 (如 gpt-4, claude-3-opus)
   apiKey: string;                  // API 密钥 (会存储到系统安全存储)
-  maxTokens?: number;              // 最大输出 token 数（不填则后端默认 4096）
+  maxTokens?: number;              // 最大输出 token 数（不填则大多数服务商不限制，Anthropic 按模型取默认上限）
+  contextWindow?: number;          // 上下文窗口 token 数（不填按模型名查表；查不到的本地/自定义模型不裁剪历史）
+  thinkingBudget?: number;         // 思考 token 预算（不填则后端默认 8000，仅 budget 式思考生效）
+  customHeaders?: Array<{ key: string; value: string }>;  // 附加请求头（自建网关用，值里的 {apiKey} 替换为密钥）
+  apiKeyQueryParam?: string;       // 密钥改放到这个 query 参数里（如 key），不再发 Authorization 头
+  safetySettings?: Record<string, string>;  // Gemini 安全过滤阈值：类别 -> 阈值（未设置的类别用服务商默认）
+  keyPoolSize?: number;            // 系统安全存储里备用密钥的个数（密钥本身不落盘）
+  keyRotation?: "round_robin" | "failover";  // 多密钥选择策略：轮询 / 429 时才切换
+  keyProfile?: string;             // 使用该提供商的哪个密钥档案（档案里有密钥时优先于 apiKey）
   createdAt: number;               // 创建时间戳
 }
 
+/** 某个提供商的密钥档案 (list_api_key_profiles 返回，不含密钥本身) */
+export interface ApiKeyProfiles {
+  profiles: string[];              // 档案名，按名称排序
+  default: string | null;          // 默认档案：配置没选档案时优先用它，其次才是配置的 apiKey
+}
+
+/** Gemini 可配置的安全类别 */
+export const GEMINI_SAFETY_CATEGORIES: Array<{ category: string; label: string }> = [
+  { category: "HARM_CATEGORY_HARASSMENT", label: "骚扰" },
+  { category: "HARM_CATEGORY_HATE_SPEECH", label: "仇恨言论" },
+  { category: "HARM_CATEGORY_SEXUALLY_EXPLICIT", label: "色情内容" },
+  { category: "HARM_CATEGORY_DANGEROUS_CONTENT", label: "危险内容" },
+  { category: "HARM_CATEGORY_CIVIC_INTEGRITY", label: "公民诚信" },
+];
+
+/** 把 API 配置里的 Gemini 安全阈值转成后端 safetySettings 参数 */
+export const toSafetySettings = (config: ApiConfig) =>
+  config.provider === "google"
+    ? Object.entries(config.safetySettings ?? {})
+        .filter(([, threshold]) => threshold)
+        .map(([category, threshold]) => ({ category, threshold }))
+    : [];
+
+/**
+ * 把 API 配置里的附加请求头 / query 鉴权转成后端 customAuth 参数
+ */
+export const toCustomAuth = (config: ApiConfig) => ({
+  headers: (config.customHeaders ?? [])
+    .filter(h => h.key.trim())
+    .map(h => ({ name: h.key.trim(), value: h.value })),
+  queryParam: config.apiKeyQueryParam?.trim() || null,
+});
+
 /**
  * Embedding API 配置接口
  * 用于配置文本嵌入模型的 API (知识库向量化用)
@@ -218,6 +281,54 @@ export const useSettingsStore = defineStore(
       }
     };
 
+    // LLM 请求调试模式：开启后后端在内存里记录最近若干次请求的请求体、打码后的
+    // 请求头和原始 SSE 事件，用于排查服务商对接问题
+    const llmDebugEnabled = ref(false);
+
+    const setLlmDebugMode = async (enabled: boolean) => {
+      llmDebugEnabled.value = enabled;
+      await syncLlmDebugMode();
+    };
+
+    // 将调试模式开关同步给后端（应用启动时调用一次，之后每次修改再调用）
+    const syncLlmDebugMode = async () => {
+      try {
+        await invoke("set_llm_debug_mode", { enabled: llmDebugEnabled.value });
+      } catch (error) {
+        console.error("Failed to sync LLM debug mode:", error);
+      }
+    };
+
+    // 网络代理：全局代理 + 按 provider（嵌入按嵌入 provider，MCP 用 "mcp"）单独指定；
+    // 单独指定为空串表示该 provider 直连。地址支持 http:// / https:// / socks5:// / socks5h://
+    const proxyEnabled = ref(false);
+    const globalProxy = ref("");
+    const providerProxies = ref<Record<string, string>>({});
+
+    // 修改代理设置并同步给后端
+    const setProxySettings = async (enabled: boolean, global: string, perProvider: Record<string, string>) => {
+      proxyEnabled.value = enabled;
+      globalProxy.value = global.trim();
+      providerProxies.value = { ...perProvider };
+      await syncProxySettings();
+    };
+
+    // 将当前代理设置同步给后端（应用启动时调用一次，之后每次修改再调用）
+    const syncProxySettings = async () => {
+      try {
+        await invoke("set_proxy_settings", {
+          settings: {
+            enabled: proxyEnabled.value,
+            globalProxy: globalProxy.value || null,
+            providerProxies: providerProxies.value,
+          },
+        });
+      } catch (error) {
+        console.error("Failed to sync proxy settings:", error);
+        syncErrorNotices.value.push(`代理设置未能同步生效：${error}`);
+      }
+    };
+
     // 从托盘唤起主窗口的全局快捷键（Tauri accelerator 格式，如 "Ctrl+Alt+Space"）
     const showHotkey = ref("Ctrl+Alt+Space");
 
@@ -262,6 +373,106 @@ export const useSettingsStore = defineStore(
     const retryCount = ref(3);
     const retryIntervalSecs = ref(2);
 
+    // 每个服务商同时进行的流式回复上限，超出的请求在后端排队（0 = 不限制）；
+    // 默认值需与 src-tauri/src/commands/stream_queue.rs 的 DEFAULT_STREAM_CONCURRENCY 一致
+    const streamConcurrency = ref(3);
+
+    const setStreamConcurrency = async (limit: number | null) => {
+      streamConcurrency.value = Math.max(0, Math.floor(limit ?? 0));
+      await syncStreamConcurrency();
+    };
+
+    // 将并发上限同步给后端（应用启动时调用一次，之后每次修改再调用）
+    const syncStreamConcurrency = async () => {
+      try {
+        await invoke("set_stream_concurrency", { limit: streamConcurrency.value });
+      } catch (error) {
+        console.error("Failed to sync stream concurrency:", error);
+      }
+    };
+
+    // 知识库导入队列：同时解析（CPU 密集）和同时生成向量（等网络）的文档数，最少 1 个；
+    // 默认值需与 src-tauri/src/knowledge_base/import_queue.rs 的 DEFAULT_PARSE_WORKERS / DEFAULT_EMBED_WORKERS 一致
+    const importParseWorkers = ref(2);
+    const importEmbedWorkers = ref(2);
+
+    const setImportParseWorkers = async (workers: number | null) => {
+      importParseWorkers.value = Math.max(1, Math.floor(workers ?? 1));
+      await syncImportConcurrency();
+    };
+
+    const setImportEmbedWorkers = async (workers: number | null) => {
+      importEmbedWorkers.value = Math.max(1, Math.floor(workers ?? 1));
+      await syncImportConcurrency();
+    };
+
+    // 将导入并发数同步给后端（应用启动时调用一次，之后每次修改再调用）
+    const syncImportConcurrency = async () => {
+      try {
+        await invoke("set_import_concurrency", {
+          parseWorkers: importParseWorkers.value,
+          embedWorkers: importEmbedWorkers.value,
+        });
+      } catch (error) {
+        console.error("Failed to sync import concurrency:", error);
+      }
+    };
+
+    // 知识库向量内存缓存的预算（MB，0 = 关闭），超出时淘汰最久没检索的知识库；
+    // 默认值需与 src-tauri/src/knowledge_base/vector_cache.rs 的 DEFAULT_VECTOR_CACHE_MB 一致
+    const vectorCacheBudgetMb = ref(256);
+
+    const setVectorCacheBudget = async (megabytes: number | null) => {
+      vectorCacheBudgetMb.value = Math.max(0, Math.floor(megabytes ?? 0));
+      await syncVectorCacheBudget();
+    };
+
+    // 将缓存预算同步给后端（应用启动时调用一次，之后每次修改再调用）
+    const syncVectorCacheBudget = async () => {
+      try {
+        await invoke("set_vector_cache_budget", { megabytes: vectorCacheBudgetMb.value });
+      } catch (error) {
+        console.error("Failed to sync vector cache budget:", error);
+      }
+    };
+
+    // 知识库导入文档后生成摘要所用的对话模型配置 ID（null = 不生成摘要）；
+    // 摘要用于检索时先按摘要挑出相关文档
+    const kbSummaryConfigId = ref<string | null>(null);
+
+    const setKbSummaryConfigId = async (configId: string | null) => {
+      kbSummaryConfigId.value = configId;
+      await syncKbSummaryModel();
+    };
+
+    // 将摘要模型同步给后端（应用启动时调用一次，之后每次修改或模型配置变化时再调用）
+    const syncKbSummaryModel = async () => {
+      const config = apiConfigs.value.find((c) => c.id === kbSummaryConfigId.value);
+      try {
+        await invoke("set_summary_llm", {
+          llm: config
+            ? { provider: config.provider, model: config.model, baseUrl: config.baseUrl, apiKey: config.apiKey ?? "" }
+            : null,
+        });
+      } catch (error) {
+        console.error("Failed to sync document summary model:", error);
+      }
+    };
+
+    // 请求每个输出 token 的对数概率，消息里可以切换成按置信度着色的视图；
+    // 只有 OpenAI 兼容接口和 Gemini 支持，topLogprobs 为每个位置的候选数
+    const logprobsEnabled = ref(false);
+    const topLogprobs = ref(3);
+
+    // 可复现生成：开启后每次请求都带上 seed 并记录在回复上；fixedSeed 为空时
+    // 每次随机生成，填了则始终使用它（把某条回复的 seed 填进来即可复现那条回复）
+    const seedEnabled = ref(false);
+    const fixedSeed = ref<number | null>(null);
+
+    // 滚动摘要：长会话在后台把较早的几轮压缩成摘要，之后用摘要代替原文发送。
+    // 摘要请求按当前模型计费，所以默认关闭
+    const summarizeHistory = ref(false);
+
     // ============ API 配置状态 ============
     
     // LLM API 配置列表 (支持多配置)
@@ -287,6 +498,13 @@ export const useSettingsStore = defineStore(
       return apiConfigs.value.find((c) => c.id === activeConfigId.value) || null;
     });
 
+    // 文档摘要所用配置的模型、地址或密钥改了（或者配置被删除）时重新同步
+    watch(
+      () => apiConfigs.value.find((c) => c.id === kbSummaryConfigId.value),
+      () => syncKbSummaryModel(),
+      { deep: true },
+    );
+
     // 获取当前激活的 Embedding 配置
     const activeEmbeddingApiConfig = computed(() => {
       if (!activeEmbeddingApiConfigId.value) return null;
@@ -317,6 +535,14 @@ export const useSettingsStore = defineStore(
       }));
     });
 
+    // 获取 Embedding 服务商下拉选项
+    const embeddingProviderOptions = computed(() => {
+      return Object.entries(EMBEDDING_PROVIDERS).map(([key, value]) => ({
+        label: value.name,
+        value: key,
+      }));
+    });
+
     // 获取 API 配置下拉选项 (聊天页面使用)
     const apiConfigOptions = computed(() => {
       return apiConfigs.value.map((config) => ({
@@ -341,7 +567,10 @@ export const useSettingsStore = defineStore(
       model: string,
       apiKey: string,
       customBaseUrl?: string,
-      maxTokens?: number
+      maxTokens?: number,
+      thinkingBudget?: number,
+      customHeaders?: Array<{ key: string; value: string }>,
+      apiKeyQueryParam?: string
     ): ApiConfig => {
       const preset = PRESET_PROVIDERS[provider];
       const config: ApiConfig = {
@@ -352,6 +581,9 @@ export const useSettingsStore = defineStore(
         model,
         apiKey,
         maxTokens,
+        thinkingBudget,
+        customHeaders,
+        apiKeyQueryParam,
         createdAt: Date.now(),
       };
       apiConfigs.value.push(config);
@@ -386,6 +618,77 @@ export const useSettingsStore = defineStore(
       }
     };
 
+    /**
+     * 保存某个 LLM 配置的备用 API 密钥（多密钥轮换）
+     * 密钥只写入系统安全存储，配置里只记录个数；传空数组表示清空
+     */
+    const setApiKeyPool = async (configId: string, keys: string[]) => {
+      const cleaned = [...new Set(keys.map(k => k.trim()).filter(Boolean))];
+      try {
+        await invoke("save_api_key_pool", { provider: configId, keys: cleaned });
+        const idx = apiConfigs.value.findIndex((c) => c.id === configId);
+        if (idx !== -1) apiConfigs.value[idx].keyPoolSize = cleaned.length;
+      } catch (error) {
+        console.error("Failed to save API key pool:", error);
+      }
+    };
+
+    /**
+     * 读取提供商的密钥档案列表
+     * 失败时返回空列表
+     */
+    const listApiKeyProfiles = async (provider: string): Promise<ApiKeyProfiles> => {
+      try {
+        return await invoke<ApiKeyProfiles>("list_api_key_profiles", { provider });
+      } catch (error) {
+        console.error("Failed to load API key profiles:", error);
+        return { profiles: [], default: null };
+      }
+    };
+
+    /**
+     * 把密钥保存为提供商的一个命名档案（如 openai:work），同名档案直接覆盖
+     * 密钥只写入系统安全存储
+     */
+    const saveApiKeyProfile = async (provider: string, profile: string, apiKey: string): Promise<boolean> => {
+      try {
+        await invoke("save_api_key", { provider: `${provider}:${profile}`, apiKey });
+        return true;
+      } catch (error) {
+        console.error("Failed to save API key profile:", error);
+        return false;
+      }
+    };
+
+    /**
+     * 删除提供商的密钥档案；使用该档案的配置改回用自己的 API Key
+     */
+    const deleteApiKeyProfile = async (provider: string, profile: string): Promise<boolean> => {
+      try {
+        await invoke("delete_api_key", { provider: `${provider}:${profile}` });
+        apiConfigs.value = apiConfigs.value.map((c) =>
+          c.provider === provider && c.keyProfile === profile ? { ...c, keyProfile: undefined } : c
+        );
+        return true;
+      } catch (error) {
+        console.error("Failed to delete API key profile:", error);
+        return false;
+      }
+    };
+
+    /**
+     * 选择提供商的默认密钥档案，null 表示不用档案
+     */
+    const selectDefaultApiKeyProfile = async (provider: string, profile: string | null): Promise<boolean> => {
+      try {
+        await invoke("select_api_key_profile", { provider, profile });
+        return true;
+      } catch (error) {
+        console.error("Failed to select API key profile:", error);
+        return false;
+      }
+    };
+
     // 删除 LLM API 配置
     const deleteApiConfig = (configId: string) => {
       apiConfigs.value = apiConfigs.value.filter((c) => c.id !== configId);
@@ -397,6 +700,7 @@ export const useSettingsStore = defineStore(
       
       // 删除安全存储中的密钥
       deleteApiKeyFromSecureStorage(configId);
+      invoke("save_api_key_pool", { provider: configId, keys: [] }).catch(() => {});
     };
 
     // 设置当前激活的配置
@@ -426,6 +730,10 @@ export const useSettingsStore = defineStore(
       
       // Save API key to secure storage with prefix
       saveApiKeyToSecureStorage(`emb_${config.id}`, apiKey);
+      // 后端的 embedding_configs 表是知识库引用配置的依据
+      saveEmbeddingConfigToBackend(config).catch((error) => {
+        console.error("Failed to save embedding config:", error);
+      });
       
       // If first config, set as active
       if (embeddingApiConfigs.value.length === 1) {
@@ -435,13 +743,26 @@ export const useSettingsStore = defineStore(
       return config;
     };
 
-    // 更新现有 Embedding API 配置
-    const updateEmbeddingApiConfig = (configId: string, updates: Partial<EmbeddingApiConfig>) => {
+    // 把 Embedding API 配置写进后端（不含 API Key，API Key 只在安全存储里）
+    const saveEmbeddingConfigToBackend = (config: EmbeddingApiConfig) =>
+      invoke("save_embedding_config", {
+        request: {
+          id: config.id,
+          name: config.name,
+          provider: config.provider,
+          model: config.model,
+          base_url: config.baseUrl,
+        },
+      });
+
+    // 更新现有 Embedding API 配置；被知识库使用的配置不能改模型，后端拒绝时抛出错误
+    const updateEmbeddingApiConfig = async (configId: string, updates: Partial<EmbeddingApiConfig>) => {
       const idx = embeddingApiConfigs.value.findIndex((c) => c.id === configId);
       if (idx === -1) return;
 
       const config = embeddingApiConfigs.value[idx];
       const { apiKey, ...safeUpdates } = updates;
+      await saveEmbeddingConfigToBackend({ ...config, ...safeUpdates });
 
       if (typeof apiKey === "string" && apiKey.trim()) {
         if (apiKey !== config.apiKey) {
@@ -453,8 +774,9 @@ export const useSettingsStore = defineStore(
       }
     };
 
-    // 删除 Embedding API 配置
-    const deleteEmbeddingApiConfig = (configId: string) => {
+    // 删除 Embedding API 配置；还有知识库在使用时后端拒绝并抛出错误
+    const deleteEmbeddingApiConfig = async (configId: string) => {
+      await invoke("delete_embedding_config", { configId });
       embeddingApiConfigs.value = embeddingApiConfigs.value.filter((c) => c.id !== configId);
       
       // If active config is deleted, switch to another
@@ -555,8 +877,45 @@ export const useSettingsStore = defineStore(
       }
     };
 
+    /**
+     * 和后端的 embedding_configs 表对齐：本地有、后端没有（或后端是从旧知识库迁移出来、没有名称）的写进后端；
+     * 后端有、本地没有的（例如在别处创建的知识库引用的配置）加到本地列表，API Key 需要在设置里补填
+     */
+    const syncEmbeddingApiConfigs = async () => {
+      try {
+        const remote = await invoke<{ id: string; name: string; provider: string; model: string; base_url: string; created_at: number }[]>(
+          "list_embedding_configs",
+        );
+        const remoteById = new Map(remote.map((c) => [c.id, c]));
+        for (const config of embeddingApiConfigs.value) {
+          const existing = remoteById.get(config.id);
+          if (!existing || !existing.name) {
+            await saveEmbeddingConfigToBackend(config).catch((error) => {
+              console.error(`Failed to sync embedding config ${config.name}:`, error);
+            });
+          }
+        }
+        for (const config of remote) {
+          if (!embeddingApiConfigs.value.some((c) => c.id === config.id)) {
+            embeddingApiConfigs.value.push({
+              id: config.id,
+              name: config.name || config.model,
+              provider: config.provider,
+              baseUrl: config.base_url,
+              model: config.model,
+              apiKey: "",
+              createdAt: config.created_at,
+            });
+          }
+        }
+      } catch (error) {
+        console.error("Failed to sync embedding configs:", error);
+      }
+    };
+
     // 加载所有 Embedding API 密钥
     const loadAllEmbeddingApiKeys = async () => {
+      await syncEmbeddingApiConfigs();
       for (const config of embeddingApiConfigs.value) {
         await loadEmbeddingApiKeyForConfig(config.id);
       }
@@ -620,6 +979,14 @@ export const useSettingsStore = defineStore(
       errorSoundLevel,
       setCloseToTray,
       syncCloseToTray,
+      proxyEnabled,
+      globalProxy,
+      providerProxies,
+      setProxySettings,
+      syncProxySettings,
+      llmDebugEnabled,
+      setLlmDebugMode,
+      syncLlmDebugMode,
       showHotkey,
       setShowHotkey,
       syncShowHotkey,
@@ -630,13 +997,38 @@ export const useSettingsStore = defineStore(
       systemPrompt,
       retryCount,
       retryIntervalSecs,
+      streamConcurrency,
+      setStreamConcurrency,
+      syncStreamConcurrency,
+      importParseWorkers,
+      importEmbedWorkers,
+      setImportParseWorkers,
+      setImportEmbedWorkers,
+      syncImportConcurrency,
+      vectorCacheBudgetMb,
+      setVectorCacheBudget,
+      syncVectorCacheBudget,
+      kbSummaryConfigId,
+      setKbSummaryConfigId,
+      syncKbSummaryModel,
+      logprobsEnabled,
+      topLogprobs,
+      seedEnabled,
+      fixedSeed,
+      summarizeHistory,
       apiConfigs,
       activeConfigId,
       activeConfig,
       presetProviderOptions,
+      embeddingProviderOptions,
       apiConfigOptions,
       createApiConfig,
       updateApiConfig,
+      setApiKeyPool,
+      listApiKeyProfiles,
+      saveApiKeyProfile,
+      deleteApiKeyProfile,
+      selectDefaultApiKeyProfile,
       deleteApiConfig,
       setActiveConfig,
       loadAllApiKeys,
@@ -649,6 +1041,7 @@ export const useSettingsStore = defineStore(
       createEmbeddingApiConfig,
       updateEmbeddingApiConfig,
       deleteEmbeddingApiConfig,
+      syncEmbeddingApiConfigs,
       setActiveEmbeddingApiConfig,
       loadEmbeddingApiKeyForConfig,
       loadAllEmbeddingApiKeys,
@@ -665,7 +1058,7 @@ export const useSettingsStore = defineStore(
   {
     persist: {
       key: "baiyu-aispace-settings",
-      paths: ["darkMode", "closeToTray", "errorSoundLevel", "showHotkey", "newSessionHotkey", "fullscreenHotkey", "systemPrompt", "retryCount", "retryIntervalSecs", "apiConfigs", "activeConfigId", "embeddingApiConfigs", "activeEmbeddingApiConfigId", "rerankerApiConfigs"],
+      paths: ["darkMode", "closeToTray", "proxyEnabled", "globalProxy", "providerProxies", "errorSoundLevel", "showHotkey", "newSessionHotkey", "fullscreenHotkey", "systemPrompt", "retryCount", "retryIntervalSecs", "streamConcurrency", "importParseWorkers", "importEmbedWorkers", "vectorCacheBudgetMb", "kbSummaryConfigId", "logprobsEnabled", "topLogprobs", "seedEnabled", "fixedSeed", "summarizeHistory", "llmDebugEnabled", "apiConfigs", "activeConfigId", "embeddingApiConfigs", "activeEmbeddingApiConfigId", "rerankerApiConfigs"],
       // apiKey lives in secure storage (see saveApiKeyToSecureStorage) and is
       // only kept in these arrays in-memory for request building. Without
       // this serializer it would otherwise round-trip into plaintext